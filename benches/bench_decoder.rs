@@ -0,0 +1,55 @@
+//! Decoder throughput, without the decode cache of the CPU in front of it.
+
+use criterion::{Criterion, Throughput, black_box, criterion_group, criterion_main};
+
+use riscv_emulator::isa::riscv::{
+    RawInstr, decoder::Decoder, isa_builder::ISABuilder, random_test::RandomProgram,
+};
+
+/// A compressed instruction of each common kind: `c.addi`, `c.li`, `c.mv`, `c.add`, `c.lw`,
+/// `c.sw`, `c.ld`, `c.sd`, `c.ldsp`, `c.sdsp`, `c.addi16sp`, `c.slli`, `c.andi`, `c.beqz`,
+/// `c.j` and `c.jr`.
+const COMPRESSED: [u32; 16] = [
+    0x0505, 0x4595, 0x862a, 0x962e, 0x41c8, 0xc588, 0x6b14, 0xef14, 0x60a2, 0xe406, 0x713d, 0x050e,
+    0x899d, 0xc501, 0xa801, 0x8082,
+];
+
+fn decode_all(decoder: &Decoder, instrs: &[RawInstr]) {
+    for &raw in instrs {
+        black_box(decoder.decode(black_box(raw)));
+    }
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+
+    // The integer, `M` and load/store mix of the random tests.
+    let random: Vec<RawInstr> = RandomProgram::new(0, 4096)
+        .encode()
+        .into_iter()
+        .map(RawInstr::from)
+        .collect();
+    let compressed: Vec<RawInstr> = COMPRESSED.iter().copied().map(RawInstr::from).collect();
+
+    for (name, decoder) in [
+        ("all", Decoder::new()),
+        (
+            "rv64gc",
+            Decoder::from_builder("rv64gc".parse::<ISABuilder>().unwrap()),
+        ),
+    ] {
+        group.throughput(Throughput::Elements(random.len() as u64));
+        group.bench_function(format!("random_{name}"), |b| {
+            b.iter(|| decode_all(&decoder, &random))
+        });
+        group.throughput(Throughput::Elements(compressed.len() as u64));
+        group.bench_function(format!("compressed_{name}"), |b| {
+            b.iter(|| decode_all(&decoder, &compressed))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode);
+criterion_main!(benches);
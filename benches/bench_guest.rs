@@ -0,0 +1,116 @@
+//! Whole guest programs: Dhrystone and CoreMark, built for the bare-metal environment of
+//! riscv-tests (`riscv-tests/benchmarks`, and riscv-coremark for CoreMark), which report through
+//! `tohost`.
+//!
+//! The pre-built images are looked up in `benches/images` (or `$RVEMU_BENCH_IMAGES`) as
+//! `dhrystone.riscv` and `coremark.bare.riscv`, `benches/fetch.sh` builds them. Missing ones are
+//! skipped with a warning. Their console output is dropped.
+
+use std::path::PathBuf;
+
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+
+use riscv_emulator::board::virt::{SerialDestination, VirtBoard, VirtBoardBuilder};
+use riscv_emulator::board::{Board, BoardStatus};
+use riscv_emulator::config::arch_config::WordType;
+use riscv_emulator::isa::DebugTarget;
+use riscv_emulator::isa::riscv::debugger::Address;
+
+const IMAGES: [(&str, &str); 2] = [
+    ("dhrystone", "dhrystone.riscv"),
+    ("coremark", "coremark.bare.riscv"),
+];
+
+/// Cycles between two looks at `tohost`.
+const POLL_INTERVAL: u64 = 0x1000;
+const MAX_CYCLES: u64 = 1 << 34;
+/// The syscall number of `write` in the `tohost` protocol.
+const SYS_WRITE: u64 = 64;
+
+fn images_dir() -> PathBuf {
+    std::env::var_os("RVEMU_BENCH_IMAGES")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("benches/images"))
+}
+
+fn board(elf: &[u8]) -> VirtBoard {
+    VirtBoardBuilder::new()
+        .serial(SerialDestination::Buffer)
+        .elf(elf.to_vec())
+        .build()
+        .unwrap()
+}
+
+fn symbol(board: &VirtBoard, name: &str) -> WordType {
+    board
+        .loader()
+        .and_then(|loader| loader.get_symbol_table())
+        .and_then(|symtab| symtab.func_addr_by_name(name))
+        .unwrap_or_else(|| panic!("the image has no {name} symbol"))
+}
+
+/// Runs the image to its exit through `tohost`, answering its syscalls. Returns the cycles it
+/// took.
+fn run(mut board: VirtBoard) -> u64 {
+    let tohost = symbol(&board, "tohost");
+    let fromhost = symbol(&board, "fromhost");
+    let read =
+        |board: &mut VirtBoard, addr| board.cpu.read_memory::<u64>(Address::Phys(addr)).unwrap();
+
+    while board.status() != BoardStatus::Halt {
+        board.step().unwrap();
+        let now = board.clock.now();
+        if now % POLL_INTERVAL != 0 {
+            continue;
+        }
+        assert!(now < MAX_CYCLES, "the guest did not exit");
+
+        match read(&mut board, tohost) {
+            0 => {}
+            1 => return now,
+            code if code & 1 == 1 => panic!("the guest failed with {}", code >> 1),
+            // A syscall, its arguments are in the `magic_mem` array `code` points to.
+            magic => {
+                let result = match read(&mut board, magic) {
+                    SYS_WRITE => read(&mut board, magic + 24),
+                    _ => 0,
+                };
+                let cpu = &mut board.cpu;
+                cpu.write_memory(Address::Phys(magic), result).unwrap();
+                cpu.write_memory(Address::Phys(tohost), 0u64).unwrap();
+                cpu.write_memory(Address::Phys(fromhost), 1u64).unwrap();
+            }
+        }
+    }
+    board.clock.now()
+}
+
+fn bench_guest(c: &mut Criterion) {
+    let mut group = c.benchmark_group("guest");
+    group.sample_size(10);
+
+    let dir = images_dir();
+    for (name, file) in IMAGES {
+        let path = dir.join(file);
+        let Ok(elf) = std::fs::read(&path) else {
+            eprintln!(
+                "\nWARNING: skipping the {name} benchmark, {} not found.\n\
+                 WARNING: run benches/fetch.sh to build the guest images.\n",
+                path.display()
+            );
+            continue;
+        };
+
+        // Report the throughput in guest instructions.
+        let cycles = run(board(&elf));
+        group.throughput(Throughput::Elements(cycles));
+        group.bench_function(name, |b| {
+            b.iter_batched(|| board(&elf), run, BatchSize::PerIteration)
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_guest);
+criterion_main!(benches);
@@ -0,0 +1,64 @@
+//! MMIO dispatch: a guest loop loading the same register of a device, against the same loop
+//! loading from RAM.
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+
+use riscv_emulator::board::Board;
+use riscv_emulator::board::memory_map::MemoryMap;
+use riscv_emulator::board::virt::{SerialDestination, VirtBoard, VirtBoardBuilder};
+use riscv_emulator::config::arch_config::WordType;
+use riscv_emulator::isa::DebugTarget;
+use riscv_emulator::ram_config::BASE_ADDR;
+
+const LBU: u32 = 0x00054303; // lbu t1, 0(a0)
+const LW: u32 = 0x00052303; // lw t1, 0(a0)
+const LD: u32 = 0x00053303; // ld t1, 0(a0)
+const LOOP: u32 = 0xffdff06f; // j .-4
+
+const STEPS: u64 = 10_000;
+
+/// A board running `load` from `addr` in a loop.
+fn board(load: u32, addr: WordType) -> VirtBoard {
+    let program = [load, LOOP]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+    let mut board = VirtBoardBuilder::new()
+        .serial(SerialDestination::Buffer)
+        .binary(program)
+        .build()
+        .unwrap();
+    board.cpu.write_reg(10, addr);
+    board
+}
+
+fn bench_mmio(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mmio");
+    group.throughput(Throughput::Elements(STEPS));
+
+    let map = MemoryMap::default();
+    for (name, load, addr) in [
+        ("ram", LD, BASE_ADDR + 0x10_0000),
+        // LSR
+        ("uart", LBU, map.uart.base + 5),
+        // mtime
+        ("clint", LD, map.clint.base + 0xbff8),
+        // The priority of source 1.
+        ("plic", LW, map.plic.base + 4),
+    ] {
+        let mut board = board(load, addr);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for _ in 0..STEPS {
+                    board.step().unwrap();
+                }
+            })
+        });
+        assert!(board.cpu.recent_traps().next().is_none());
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_mmio);
+criterion_main!(benches);
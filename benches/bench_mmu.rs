@@ -0,0 +1,132 @@
+//! Address translation on the load path: a guest in S-mode loads a word from each page of a
+//! buffer in turn, without translation, with the pages in the TLB and with more pages than the
+//! TLB holds, so every load walks the Sv39 page tables.
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+
+use riscv_emulator::board::Board;
+use riscv_emulator::board::virt::{SerialDestination, VirtBoard, VirtBoardBuilder};
+use riscv_emulator::config::arch_config::WordType;
+use riscv_emulator::isa::DebugTarget;
+use riscv_emulator::isa::riscv::csr_reg::PrivilegeLevel;
+use riscv_emulator::isa::riscv::debugger::Address;
+use riscv_emulator::ram_config::BASE_ADDR;
+
+const PROGRAM: [u32; 17] = [
+    0x18039073, // csrw satp, t2
+    0x12000073, // sfence.vma
+    0x00000297, // auipc t0, 0
+    0x02828293, // addi t0, t0, 40
+    0x34129073, // csrw mepc, t0
+    0x00002337, // lui t1, 2
+    0x8003031b, // addiw t1, t1, -2048
+    0x30033073, // csrc mstatus, t1
+    0x00001337, // lui t1, 1
+    0x8003031b, // addiw t1, t1, -2048
+    0x30032073, // csrs mstatus, t1
+    0x30200073, // mret
+    0x00050293, // loop: mv t0, a0
+    0x0002b303, // inner: ld t1, 0(t0)
+    0x00c282b3, // add t0, t0, a2
+    0xfeb2ece3, // bltu t0, a1, inner
+    0xff1ff06f, // j loop
+];
+/// The instructions before the loop, in M-mode.
+const PROLOGUE_LEN: usize = 12;
+
+const PAGE_SIZE: WordType = 0x1000;
+const ROOT: WordType = BASE_ADDR + 0x10_0000;
+const L1: WordType = ROOT + PAGE_SIZE;
+const L0: WordType = L1 + PAGE_SIZE;
+/// The physical pages of the buffer.
+const DATA: WordType = BASE_ADDR + 0x20_0000;
+/// The buffer is mapped at this virtual address.
+const DATA_VADDR: WordType = 0x4000_0000;
+/// Twice the entries of the TLB.
+const MAX_PAGES: WordType = 1024;
+
+const STEPS: u64 = 10_000;
+
+const PTE_V: WordType = 1 << 0;
+const PTE_R: WordType = 1 << 1;
+const PTE_W: WordType = 1 << 2;
+const PTE_X: WordType = 1 << 3;
+const PTE_A: WordType = 1 << 6;
+const PTE_D: WordType = 1 << 7;
+const SATP_SV39: WordType = 8 << 60;
+
+fn pte(paddr: WordType, flags: WordType) -> WordType {
+    (paddr >> 12) << 10 | flags
+}
+
+fn write_pte(board: &mut VirtBoard, table: WordType, idx: WordType, pte: WordType) {
+    board
+        .cpu
+        .write_memory(Address::Phys(table + 8 * idx), pte)
+        .unwrap();
+}
+
+/// Maps the RAM the code runs from to itself, with a gigapage, and the buffer at
+/// [`DATA_VADDR`] with 4 KiB pages.
+fn map_pages(board: &mut VirtBoard) {
+    let leaf = PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+    write_pte(board, ROOT, BASE_ADDR >> 30, pte(BASE_ADDR, leaf | PTE_X));
+    write_pte(board, ROOT, DATA_VADDR >> 30, pte(L1, PTE_V));
+    for table in 0..MAX_PAGES / 512 {
+        let l0 = L0 + table * PAGE_SIZE;
+        write_pte(board, L1, table, pte(l0, PTE_V));
+        for idx in 0..512 {
+            let page = DATA + (table * 512 + idx) * PAGE_SIZE;
+            write_pte(board, l0, idx, pte(page, leaf));
+        }
+    }
+}
+
+/// A board running the loop in S-mode over `pages` pages, from `start`.
+fn board(satp: WordType, start: WordType, pages: WordType) -> VirtBoard {
+    let program = PROGRAM.iter().flat_map(|word| word.to_le_bytes()).collect();
+    let mut board = VirtBoardBuilder::new()
+        .serial(SerialDestination::Buffer)
+        .binary(program)
+        .build()
+        .unwrap();
+    map_pages(&mut board);
+    board.cpu.write_reg(10, start);
+    board.cpu.write_reg(11, start + pages * PAGE_SIZE);
+    board.cpu.write_reg(12, PAGE_SIZE);
+    board.cpu.write_reg(7, satp);
+
+    for _ in 0..PROLOGUE_LEN + 4 * MAX_PAGES as usize {
+        board.step().unwrap();
+    }
+    assert_eq!(board.cpu.get_current_privilege(), PrivilegeLevel::S);
+    assert!(board.cpu.recent_traps().next().is_none());
+    board
+}
+
+fn bench_translate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mmu");
+    group.throughput(Throughput::Elements(STEPS));
+
+    let satp = SATP_SV39 | ROOT >> 12;
+    for (name, satp, start, pages) in [
+        ("bare", 0, DATA, MAX_PAGES),
+        ("tlb_hit", satp, DATA_VADDR, 16),
+        ("tlb_miss", satp, DATA_VADDR, MAX_PAGES),
+    ] {
+        let mut board = board(satp, start, pages);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for _ in 0..STEPS {
+                    board.step().unwrap();
+                }
+            })
+        });
+        assert!(board.cpu.recent_traps().next().is_none());
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_translate);
+criterion_main!(benches);
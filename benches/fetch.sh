@@ -0,0 +1,47 @@
+#!/usr/bin/env bash
+# Fill the images directory of the guest benchmarks (benches/images, or $RVEMU_BENCH_IMAGES).
+#
+# - dhrystone: built in the riscv-tests submodule (riscv-tests/benchmarks).
+# - coremark: cloned from $COREMARK_REPO (at $COREMARK_REV) and built with its build-coremark.sh,
+#   or taken from $COREMARK_DIR.
+#
+# Images which can not be produced are reported and skipped, bench_guest skips them too.
+
+set -euo pipefail
+
+ROOT="$(cd "$(dirname "${BASH_SOURCE[0]}")/.." && pwd)"
+IMAGES="${RVEMU_BENCH_IMAGES:-$ROOT/benches/images}"
+CROSS_COMPILE="${CROSS_COMPILE:-riscv64-unknown-elf-}"
+COREMARK_REPO="${COREMARK_REPO:-https://github.com/riscv-boom/riscv-coremark.git}"
+COREMARK_REV="${COREMARK_REV:-master}"
+
+skip() {
+    echo "skip $1: $2" >&2
+}
+
+mkdir -p "$IMAGES"
+
+# dhrystone
+if [ ! -d "$ROOT/riscv-tests/benchmarks" ]; then
+    skip dhrystone "the riscv-tests submodule is not checked out"
+elif command -v "${CROSS_COMPILE}gcc" > /dev/null; then
+    make -C "$ROOT/riscv-tests/benchmarks" dhrystone.riscv RISCV_PREFIX="$CROSS_COMPILE"
+    cp "$ROOT/riscv-tests/benchmarks/dhrystone.riscv" "$IMAGES/"
+else
+    skip dhrystone "${CROSS_COMPILE}gcc not found"
+fi
+
+# coremark
+if [ -z "${COREMARK_DIR:-}" ] && command -v "${CROSS_COMPILE}gcc" > /dev/null; then
+    COREMARK_DIR="$IMAGES/coremark-src"
+    if [ ! -d "$COREMARK_DIR" ]; then
+        git clone --depth 1 --branch "$COREMARK_REV" --recurse-submodules \
+            "$COREMARK_REPO" "$COREMARK_DIR"
+    fi
+    (cd "$COREMARK_DIR" && ./build-coremark.sh)
+fi
+if [ -n "${COREMARK_DIR:-}" ] && [ -f "$COREMARK_DIR/coremark.bare.riscv" ]; then
+    cp "$COREMARK_DIR/coremark.bare.riscv" "$IMAGES/"
+else
+    skip coremark "set COREMARK_DIR or install ${CROSS_COMPILE}gcc"
+fi
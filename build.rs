@@ -290,6 +290,8 @@ fn main() {
         // data/instr_dict_illegal.json), kept separate from the auto-generated
         // instr_dict.json.
         m.insert("rv_illegal", "RVIllegal");
+        // Stands for every instruction of the `InstructionExtension`s, never in a decoding table.
+        m.insert("rv_extension", "RVExtension");
 
         #[cfg(feature = "custom-instr")]
         m.insert("rv_custom0", "RVCustom0");
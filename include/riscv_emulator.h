@@ -0,0 +1,115 @@
+#ifndef RISCV_EMULATOR_H
+#define RISCV_EMULATOR_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// An emulator created by [`rvemu_create`].
+typedef struct RvEmu RvEmu;
+
+// Read `size` bytes at `offset` in the region, called on every guest load.
+typedef uint64_t (*RvemuMmioRead)(void *opaque, uint64_t offset, uint32_t size);
+
+// Write the low `size` bytes of `value` at `offset` in the region, called on every guest store.
+typedef void (*RvemuMmioWrite)(void *opaque, uint64_t offset, uint32_t size, uint64_t value);
+
+// Create a virt board without an image, its UART output is kept for
+// [`rvemu_take_uart_output`]. Returns null if the board cannot be built.
+RvEmu *rvemu_create(void);
+
+// # Safety
+// `emu` must come from [`rvemu_create`] and not be used afterwards, null is ignored.
+void rvemu_destroy(RvEmu *emu);
+
+// The last failure of `emu`, valid until the next failing call.
+//
+// # Safety
+// `emu` must come from [`rvemu_create`].
+const char *rvemu_last_error(const RvEmu *emu);
+
+// Load the ELF image `data` and reset the board to run it.
+//
+// # Safety
+// `emu` must come from [`rvemu_create`], `data` must point to `len` bytes.
+int rvemu_load_elf(RvEmu *emu, const uint8_t *data, size_t len);
+
+// Load the raw image `data` at the start of the RAM and reset the board to run it.
+//
+// # Safety
+// `emu` must come from [`rvemu_create`], `data` must point to `len` bytes.
+int rvemu_load_binary(RvEmu *emu, const uint8_t *data, size_t len);
+
+// Run up to `steps` instructions, stopping early when the board halts. The number of steps
+// run is stored in `executed` unless it is null.
+//
+// # Safety
+// `emu` must come from [`rvemu_create`], `executed` must be null or writable.
+int rvemu_step(RvEmu *emu, uint64_t steps, uint64_t *executed);
+
+// # Safety
+// `emu` must come from [`rvemu_create`].
+bool rvemu_is_halted(const RvEmu *emu);
+
+// # Safety
+// `emu` must come from [`rvemu_create`].
+uint64_t rvemu_read_pc(const RvEmu *emu);
+
+// # Safety
+// `emu` must come from [`rvemu_create`].
+void rvemu_write_pc(RvEmu *emu, uint64_t pc);
+
+// Read the integer register `x<idx>` into `value`.
+//
+// # Safety
+// `emu` must come from [`rvemu_create`], `value` must be writable.
+int rvemu_read_reg(RvEmu *emu, uint32_t idx, uint64_t *value);
+
+// Write `value` to the integer register `x<idx>`, writes to `x0` are ignored.
+//
+// # Safety
+// `emu` must come from [`rvemu_create`].
+int rvemu_write_reg(RvEmu *emu, uint32_t idx, uint64_t value);
+
+// Copy `len` bytes of physical memory at `paddr` into `buf`, one byte access at a time.
+//
+// # Safety
+// `emu` must come from [`rvemu_create`], `buf` must point to `len` writable bytes.
+int rvemu_read_mem(RvEmu *emu, uint64_t paddr, uint8_t *buf, size_t len);
+
+// Copy the `len` bytes of `data` to physical memory at `paddr`, one byte access at a time.
+//
+// # Safety
+// `emu` must come from [`rvemu_create`], `data` must point to `len` bytes.
+int rvemu_write_mem(RvEmu *emu, uint64_t paddr, const uint8_t *data, size_t len);
+
+// Map `size` bytes at `base` to the callbacks, which get `opaque` back. A null callback makes
+// the accesses of that kind fault. The region must be free and below the RAM.
+//
+// # Safety
+// `emu` must come from [`rvemu_create`], `name` must be a NUL-terminated string and `opaque`
+// must stay valid for as long as `emu`.
+int rvemu_register_mmio(RvEmu *emu,
+                        const char *name,
+                        uint64_t base,
+                        uint64_t size,
+                        RvemuMmioRead read,
+                        RvemuMmioWrite write,
+                        void *opaque);
+
+// Send the `len` bytes of `data` to the UART.
+//
+// # Safety
+// `emu` must come from [`rvemu_create`], `data` must point to `len` bytes.
+void rvemu_push_uart_input(RvEmu *emu, const uint8_t *data, size_t len);
+
+// Move up to `capacity` bytes of UART output into `buf`, returns how many were moved. Output
+// which does not fit stays queued for the next call.
+//
+// # Safety
+// `emu` must come from [`rvemu_create`], `buf` must point to `capacity` writable bytes.
+size_t rvemu_take_uart_output(RvEmu *emu, uint8_t *buf, size_t capacity);
+
+#endif  /* RISCV_EMULATOR_H */
@@ -27,6 +27,10 @@ mod imp {
         Running {
             poll_worker: thread::JoinHandle<Vec<ExecTask>>,
         },
+        /// Started without a worker, tasks run on the caller of `poll_once`.
+        Inline {
+            poll_tasks: Vec<ExecTask>,
+        },
         Stopped {
             poll_tasks: Vec<ExecTask>,
         },
@@ -86,13 +90,41 @@ mod imp {
             };
         }
 
-        pub fn poll_once(&mut self) {}
+        /// Start without a worker thread, so the tasks run at well defined points on the
+        /// calling thread (used by deterministic mode).
+        pub fn start_inline(&mut self) {
+            let ExecContext::Stopped { poll_tasks } = std::mem::take(&mut self.context) else {
+                log::warn!("executor has started");
+                return;
+            };
+            self.context = ExecContext::Inline { poll_tasks };
+        }
+
+        /// Whether the tasks only run on [`Self::poll_once`].
+        pub fn runs_inline(&self) -> bool {
+            matches!(self.context, ExecContext::Inline { .. })
+        }
+
+        /// Run every polling task once if started inline, no-op with a worker thread.
+        pub fn poll_once(&mut self) {
+            if let ExecContext::Inline { poll_tasks } = &mut self.context {
+                poll_round(poll_tasks);
+            }
+        }
 
         /// Signal threads to stop and join the worker.
         pub fn shutdown(&mut self) {
-            let ExecContext::Running { poll_worker } = std::mem::take(&mut self.context) else {
-                log::warn!("try to shutdown executor when not running");
-                return;
+            let poll_worker = match std::mem::take(&mut self.context) {
+                ExecContext::Running { poll_worker } => poll_worker,
+                ExecContext::Inline { poll_tasks } => {
+                    self.context = ExecContext::Stopped { poll_tasks };
+                    return;
+                }
+                ExecContext::Stopped { poll_tasks } => {
+                    self.context = ExecContext::Stopped { poll_tasks };
+                    log::warn!("try to shutdown executor when not running");
+                    return;
+                }
             };
 
             // let the worker thread exit.
@@ -138,6 +170,13 @@ mod imp {
         /// No worker thread to start.
         pub fn start(&mut self) {}
 
+        pub fn start_inline(&mut self) {}
+
+        /// Always, there is no worker thread.
+        pub fn runs_inline(&self) -> bool {
+            true
+        }
+
         /// Run every polling task once on the calling thread
         pub fn poll_once(&mut self) {
             for task in self.poll_tasks.iter_mut() {
@@ -0,0 +1,95 @@
+//! Device tree source of the virt board.
+//!
+//! `dts/virt.dts` describes the board, the ISA properties of its `cpu` node are rewritten from
+//! the configured [`ISABuilder`], so the guest is told exactly what the decoder and the CSRs
+//! implement. The checked-in file matches [`ISABuilder::all`]. The `bootargs` of the `chosen`
+//! node can be replaced by the kernel command line of `--append`.
+
+use crate::{config::arch_config::XLEN, isa::riscv::isa_builder::ISABuilder};
+
+const VIRT_DTS: &str = include_str!("../../dts/virt.dts");
+
+/// Prefix shared by the `riscv,isa`, `riscv,isa-base` and `riscv,isa-extensions` properties.
+const ISA_PROPERTY: &str = "riscv,isa";
+const BOOTARGS_PROPERTY: &str = "bootargs";
+
+/// The device tree source of the virt board with a hart implementing `isa`, passing `bootargs`
+/// to the kernel instead of the default command line.
+pub fn virt_dts(isa: &ISABuilder, bootargs: Option<&str>) -> String {
+    let mut dts = String::with_capacity(VIRT_DTS.len());
+    let mut written = false;
+    for line in VIRT_DTS.split_inclusive('\n') {
+        let property = line.trim_start();
+        let indent = &line[..line.len() - property.len()];
+        if let Some(bootargs) = bootargs
+            && property.starts_with(BOOTARGS_PROPERTY)
+        {
+            let escaped = bootargs.replace('\\', "\\\\").replace('"', "\\\"");
+            dts.push_str(&format!("{indent}{BOOTARGS_PROPERTY} = \"{escaped}\";\n"));
+            continue;
+        }
+        if !property.starts_with(ISA_PROPERTY) {
+            dts.push_str(line);
+            continue;
+        }
+        if written {
+            continue;
+        }
+
+        for property in isa_properties(isa) {
+            dts.push_str(indent);
+            dts.push_str(&property);
+            dts.push('\n');
+        }
+        written = true;
+    }
+    dts
+}
+
+fn isa_properties(isa: &ISABuilder) -> [String; 3] {
+    let names = isa.extension_names();
+    let extensions = names
+        .iter()
+        .map(|name| format!("\"{name}\""))
+        .collect::<Vec<_>>()
+        .join(", ");
+    [
+        format!("riscv,isa = \"{}\";", isa.isa_string()),
+        format!("riscv,isa-base = \"rv{XLEN}{}\";", names[0]),
+        format!("riscv,isa-extensions = {extensions};"),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::riscv::isa_builder::Extension;
+
+    #[test]
+    #[cfg(feature = "riscv64")]
+    fn test_checked_in_dts_is_up_to_date() {
+        assert_eq!(virt_dts(&ISABuilder::all(), None), VIRT_DTS);
+    }
+
+    #[test]
+    fn test_isa_properties() {
+        let isa = ISABuilder::new().add(Extension::M).add(Extension::Zicntr);
+        let dts = virt_dts(&isa, None);
+        assert!(dts.contains(&format!("\t\t\triscv,isa = \"rv{XLEN}im_zicntr_zicsr\";\n")));
+        assert!(dts.contains(&format!("\t\t\triscv,isa-base = \"rv{XLEN}i\";\n")));
+        assert!(
+            dts.contains("\t\t\triscv,isa-extensions = \"i\", \"m\", \"zicntr\", \"zicsr\";\n")
+        );
+        assert_eq!(dts.matches(ISA_PROPERTY).count(), 3);
+    }
+
+    #[test]
+    fn test_bootargs() {
+        let dts = virt_dts(
+            &ISABuilder::all(),
+            Some(r#"root=/dev/vda init="/bin/sh" a\b"#),
+        );
+        assert!(dts.contains("\t\tbootargs = \"root=/dev/vda init=\\\"/bin/sh\\\" a\\\\b\";\n"));
+        assert_eq!(dts.matches("bootargs").count(), 1);
+    }
+}
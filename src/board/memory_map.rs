@@ -0,0 +1,383 @@
+//! Addresses of the UART, PLIC, CLINT and VirtIO devices of the virt board.
+//!
+//! The defaults are the constants of [`device::config`](crate::device::config), a board
+//! description file moves the devices to mimic the layout of another SoC:
+//!
+//! ```toml
+//! [uart]
+//! base = 0x10010000
+//! size = 0x100       # optional, every key keeps its default when left out
+//! irq = 12           # PLIC source
+//!
+//! [plic]
+//! base = 0xc000000
+//!
+//! [clint]
+//! base = 0x2000000
+//!
+//! [virtio]
+//! base = 0x10008000
+//! size = 0x1000      # size of a slot, the slots follow each other
+//! irq = 20           # PLIC source of the first slot, the n-th one uses `irq + n`
+//!
+//! # Physical memory attributes of the devices mapped in a range, see `device::pma`
+//! [[pma]]
+//! base = 0x20000000
+//! size = 0x4000000
+//! idempotent = true  # optional, like the other attributes
+//! cacheable = true
+//! widths = [1, 2, 4, 8]
+//! amo = "arithmetic" # "none", "swap", "logical" or "arithmetic"
+//! reservable = false
+//! ```
+//!
+//! The guest must be given a device tree describing the same map.
+
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::{
+    config::arch_config::WordType,
+    device::{
+        AccessWidths, IdAllocator, MemMappedDeviceTrait,
+        config::{
+            CLINT_BASE, CLINT_NAME, CLINT_SIZE, PLIC_BASE, PLIC_NAME, PLIC_SIZE, UART_BASE,
+            UART_IRQ, UART_NAME, UART_SIZE, VIRTIO_IRQ_BASE, VIRTIO_MMIO_BASE, VIRTIO_MMIO_NAME,
+            VIRTIO_MMIO_SIZE, VIRTIO_MMIO_SLOTS,
+        },
+        plic::ExternalInterrupt,
+        pma::PmaOverride,
+    },
+    ram_config,
+};
+
+/// PLIC sources are `1..PLIC_SOURCES`.
+const PLIC_SOURCES: ExternalInterrupt = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub base: WordType,
+    pub size: WordType,
+    /// The PLIC source of the device, `None` for the interrupt controllers.
+    pub irq: Option<ExternalInterrupt>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub uart: Region,
+    pub plic: Region,
+    pub clint: Region,
+    /// The first of the [`VIRTIO_MMIO_SLOTS`] VirtIO slots.
+    pub virtio: Region,
+    /// Attributes of the devices in some ranges, in order: a later entry wins.
+    pub pma: Vec<PmaOverride>,
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self {
+            uart: Region {
+                base: UART_BASE,
+                size: UART_SIZE,
+                irq: Some(UART_IRQ),
+            },
+            plic: Region {
+                base: PLIC_BASE,
+                size: PLIC_SIZE,
+                irq: None,
+            },
+            clint: Region {
+                base: CLINT_BASE,
+                size: CLINT_SIZE,
+                irq: None,
+            },
+            virtio: Region {
+                base: VIRTIO_MMIO_BASE,
+                size: VIRTIO_MMIO_SIZE,
+                irq: Some(VIRTIO_IRQ_BASE),
+            },
+            pma: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MemoryMapError {
+    #[error("failed to read board description: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid board description: {0}")]
+    Parse(#[from] toml::de::Error),
+
+    #[error("{0}: size must not be zero")]
+    EmptyRegion(&'static str),
+
+    #[error("{name}: irq {irq} is not a PLIC source (1..{PLIC_SOURCES})")]
+    InvalidIrq {
+        name: &'static str,
+        irq: ExternalInterrupt,
+    },
+
+    #[error("{0} overlaps {1}")]
+    Overlap(&'static str, &'static str),
+
+    #[error("pma {base:#x}: {reason}")]
+    InvalidPma {
+        base: WordType,
+        reason: &'static str,
+    },
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RegionFile {
+    base: Option<WordType>,
+    size: Option<WordType>,
+    irq: Option<ExternalInterrupt>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ControllerFile {
+    base: Option<WordType>,
+    size: Option<WordType>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemoryMapFile {
+    #[serde(default)]
+    uart: RegionFile,
+    #[serde(default)]
+    plic: ControllerFile,
+    #[serde(default)]
+    clint: ControllerFile,
+    #[serde(default)]
+    virtio: RegionFile,
+    #[serde(default)]
+    pma: Vec<PmaOverride>,
+}
+
+impl Region {
+    fn merge(self, base: Option<WordType>, size: Option<WordType>) -> Self {
+        Self {
+            base: base.unwrap_or(self.base),
+            size: size.unwrap_or(self.size),
+            ..self
+        }
+    }
+
+    fn end(&self) -> WordType {
+        self.base.saturating_add(self.size)
+    }
+}
+
+impl MemoryMap {
+    /// Parse a board description and check that the regions fit together.
+    pub fn parse(text: &str) -> Result<Self, MemoryMapError> {
+        let file = toml::from_str::<MemoryMapFile>(text)?;
+        let default = Self::default();
+        let map = Self {
+            uart: Region {
+                irq: file.uart.irq.or(default.uart.irq),
+                ..default.uart.merge(file.uart.base, file.uart.size)
+            },
+            plic: default.plic.merge(file.plic.base, file.plic.size),
+            clint: default.clint.merge(file.clint.base, file.clint.size),
+            virtio: Region {
+                irq: file.virtio.irq.or(default.virtio.irq),
+                ..default.virtio.merge(file.virtio.base, file.virtio.size)
+            },
+            pma: file.pma,
+        };
+        map.validate()?;
+        Ok(map)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, MemoryMapError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn validate(&self) -> Result<(), MemoryMapError> {
+        let ram = Region {
+            base: ram_config::BASE_ADDR,
+            size: ram_config::SIZE as WordType,
+            irq: None,
+        };
+        let virtio_slots = Region {
+            size: self
+                .virtio
+                .size
+                .saturating_mul(VIRTIO_MMIO_SLOTS as WordType),
+            ..self.virtio
+        };
+        let regions = [
+            (UART_NAME, self.uart),
+            (PLIC_NAME, self.plic),
+            (CLINT_NAME, self.clint),
+            ("virtio", virtio_slots),
+            ("ram", ram),
+        ];
+
+        for (i, (name, region)) in regions.iter().enumerate() {
+            if region.size == 0 {
+                return Err(MemoryMapError::EmptyRegion(name));
+            }
+            if let Some(irq) = region.irq {
+                let last = match *name {
+                    "virtio" => irq.saturating_add(VIRTIO_MMIO_SLOTS as ExternalInterrupt - 1),
+                    _ => irq,
+                };
+                if irq == 0 || last >= PLIC_SOURCES {
+                    return Err(MemoryMapError::InvalidIrq { name, irq });
+                }
+            }
+            for (other, other_region) in &regions[..i] {
+                if region.base < other_region.end() && other_region.base < region.end() {
+                    return Err(MemoryMapError::Overlap(name, other));
+                }
+            }
+        }
+
+        let virtio_irqs = virtio_slots
+            .irq
+            .map(|irq| irq..irq + VIRTIO_MMIO_SLOTS as ExternalInterrupt);
+        if let (Some(uart_irq), Some(virtio_irqs)) = (self.uart.irq, virtio_irqs)
+            && virtio_irqs.contains(&uart_irq)
+        {
+            return Err(MemoryMapError::Overlap("uart irq", "virtio irqs"));
+        }
+
+        for entry in &self.pma {
+            let reason = if entry.size == 0 {
+                "size must not be zero"
+            } else if entry
+                .widths
+                .as_ref()
+                .is_some_and(|widths| widths.is_empty() || !AccessWidths::valid(widths))
+            {
+                "widths must be some of 1, 2, 4 and 8"
+            } else {
+                continue;
+            };
+            return Err(MemoryMapError::InvalidPma {
+                base: entry.base,
+                reason,
+            });
+        }
+        Ok(())
+    }
+
+    /// The region of devices of type `D`, if the map moves them.
+    pub(crate) fn region_of<D: MemMappedDeviceTrait>(&self) -> Option<&Region> {
+        match D::name() {
+            UART_NAME => Some(&self.uart),
+            VIRTIO_MMIO_NAME => Some(&self.virtio),
+            _ => None,
+        }
+    }
+
+    /// Hands out the address ranges of devices of type `D`, starting with the `start_id`-th one.
+    pub(crate) fn allocator<D: MemMappedDeviceTrait>(
+        &self,
+        start_id: WordType,
+        name: String,
+    ) -> IdAllocator {
+        match self.region_of::<D>() {
+            Some(region) => IdAllocator::with_range(start_id, name, region.base, region.size),
+            None => IdAllocator::new::<D>(start_id, name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::pma::{AmoClass, Pma};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(MemoryMap::parse("").unwrap(), MemoryMap::default());
+
+        let map = MemoryMap::parse(
+            r#"
+            [uart]
+            base = 0x10010000
+            irq = 12
+
+            [virtio]
+            size = 0x200
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            map.uart,
+            Region {
+                base: 0x1001_0000,
+                size: UART_SIZE,
+                irq: Some(12),
+            }
+        );
+        assert_eq!(map.virtio.size, 0x200);
+        assert_eq!(map.virtio.base, VIRTIO_MMIO_BASE);
+        assert_eq!(map.plic, MemoryMap::default().plic);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(
+            MemoryMap::parse("[plic]\nirq = 3"),
+            Err(MemoryMapError::Parse(_))
+        ));
+        assert!(matches!(
+            MemoryMap::parse("[uart]\nsize = 0"),
+            Err(MemoryMapError::EmptyRegion("uart"))
+        ));
+        assert!(matches!(
+            MemoryMap::parse("[uart]\nbase = 0xc000100"),
+            Err(MemoryMapError::Overlap("plic", "uart"))
+        ));
+        assert!(matches!(
+            MemoryMap::parse("[clint]\nbase = 0x80001000"),
+            Err(MemoryMapError::Overlap("ram", "clint"))
+        ));
+        assert!(matches!(
+            MemoryMap::parse("[virtio]\nirq = 60"),
+            Err(MemoryMapError::InvalidIrq { .. })
+        ));
+        assert!(matches!(
+            MemoryMap::parse("[uart]\nirq = 3"),
+            Err(MemoryMapError::Overlap(..))
+        ));
+        assert!(matches!(
+            MemoryMap::parse("[[pma]]\nbase = 0x1000\nsize = 0x100\nwidths = [3]"),
+            Err(MemoryMapError::InvalidPma { base: 0x1000, .. })
+        ));
+        assert!(matches!(
+            MemoryMap::parse("[[pma]]\nbase = 0x1000\nsize = 0x100\namo = \"all\""),
+            Err(MemoryMapError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_pma() {
+        let map = MemoryMap::parse(
+            r#"
+            [[pma]]
+            base = 0x20000000
+            size = 0x4000000
+            idempotent = true
+            amo = "swap"
+            "#,
+        )
+        .unwrap();
+        let [entry] = &map.pma[..] else {
+            panic!("expected one entry");
+        };
+        assert!(entry.covers(0x2200_0000));
+        let pma = entry.apply(Pma::IO);
+        assert!(pma.idempotent && !pma.cacheable);
+        assert_eq!(pma.amo, AmoClass::Swap);
+    }
+}
@@ -1,27 +1,205 @@
-use crate::isa::riscv::{executor::RVCPU, trap::Exception};
+use std::{cell::Cell, io, ops::Range, rc::Rc};
 
+use crate::{
+    DeviceConfig,
+    config::arch_config::WordType,
+    device::{
+        FaultConfig, plic::ExternalInterrupt, sifive_gpio::GpioState,
+        virtio::virtio_mmio::VirtIODeviceID,
+    },
+    doorbell::Doorbell,
+    isa::riscv::{executor::RVCPU, trap::Exception},
+};
+
+pub mod dts;
+pub mod memory_map;
+pub mod serial_scanner;
 pub mod virt;
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum BoardStatus {
     Running,
+    /// A reset was requested, the run loop calls [`Board::reset`] before the next step.
+    Resetting,
     Halt,
 }
 
+/// What a device asks the board to do, see [`BoardControl`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BoardRequest {
+    /// Reset the harts and devices, the memory is kept.
+    Reset,
+    /// Stop the board, with the reason to report.
+    Halt(String),
+    /// Print the hart state to the host console, the guest keeps running.
+    DumpState,
+    /// Hand control to the user, with the reason to report, see [`Board::take_break`].
+    Break(String),
+}
+
+/// Handle for devices to reset or stop the board. Requests are served between instructions,
+/// a later request replaces an unserved one.
+#[derive(Clone, Default)]
+pub struct BoardControl {
+    request: Rc<Cell<Option<BoardRequest>>>,
+    /// Rung with every request so the board serves it.
+    doorbell: Doorbell,
+}
+
+impl BoardControl {
+    pub(crate) fn new(doorbell: Doorbell) -> Self {
+        Self {
+            request: Rc::default(),
+            doorbell,
+        }
+    }
+
+    pub fn request(&self, request: BoardRequest) {
+        self.request.set(Some(request));
+        self.doorbell.ring();
+    }
+
+    pub(crate) fn take(&self) -> Option<BoardRequest> {
+        self.request.take()
+    }
+}
+
+/// Where a hot-plugged device ended up, see [`Board::hotplug_device`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HotplugInfo {
+    pub slot: usize,
+    pub base: WordType,
+    pub irq: ExternalInterrupt,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum HotplugError {
+    #[error("the board does not support hot-plug")]
+    Unsupported,
+
+    #[error("device type {0:?} can not be hot-plugged")]
+    UnsupportedDevice(VirtIODeviceID),
+
+    #[error("can not open {path}: {source}")]
+    Backend { path: String, source: io::Error },
+
+    #[error("no free VirtIO slot")]
+    NoFreeSlot,
+
+    #[error("VirtIO slot {0} is empty")]
+    EmptySlot(usize),
+
+    #[error("the device in VirtIO slot {0} has no disk")]
+    NoDisk(usize),
+
+    #[error(
+        "shared memory region of {len:#x} bytes does not fit in the window of VirtIO slot {slot}"
+    )]
+    SharedMemory { slot: usize, len: u64 },
+
+    #[error("{name} at {base:#x} overlaps another device")]
+    Overlap { name: String, base: u64 },
+}
+
 pub trait Board {
     fn step(&mut self) -> Result<(), Exception>;
     fn status(&self) -> BoardStatus;
 
+    /// Reset the harts and devices as at power-on, and return to [`BoardStatus::Running`].
+    fn reset(&mut self);
+
+    /// Write the images the board booted from to RAM again, undoing what the guest changed in
+    /// them. Returns `false` if the board keeps no images.
+    fn reload_images(&mut self) -> bool {
+        false
+    }
+
     fn cpu(&self) -> &RVCPU;
     fn cpu_mut(&mut self) -> &mut RVCPU;
 
+    /// Number of harts, their IDs are `0..hart_count()`.
+    fn hart_count(&self) -> usize {
+        1
+    }
+
+    /// Hart `id`, [`Self::cpu`] is hart 0.
+    fn hart(&self, id: usize) -> Option<&RVCPU> {
+        (id == 0).then(|| self.cpu())
+    }
+
+    fn hart_mut(&mut self, id: usize) -> Option<&mut RVCPU> {
+        (id == 0).then(|| self.cpu_mut())
+    }
+
     fn loader(&self) -> Option<&crate::load::ELFLoader>;
 
+    /// The reason of a [`BoardRequest::Break`] served since the last call. The board keeps
+    /// running, a debugger stops at the prompt and a batch run gives up.
+    fn take_break(&mut self) -> Option<String> {
+        None
+    }
+
+    /// Set an external interrupt source pending, as if a device raised it.
+    /// Returns `false` if the board has no such source.
+    fn raise_external_interrupt(&mut self, _id: ExternalInterrupt) -> bool {
+        false
+    }
+
+    /// Plug a device into a free slot while the guest runs, and notify the driver with a
+    /// configuration change interrupt.
+    fn hotplug_device(&mut self, _cfg: &DeviceConfig) -> Result<HotplugInfo, HotplugError> {
+        Err(HotplugError::Unsupported)
+    }
+
+    /// Remove the device in `slot`. The guest should have released it, as its registers and
+    /// interrupt source disappear right away.
+    fn unplug_device(&mut self, _slot: usize) -> Result<(), HotplugError> {
+        Err(HotplugError::Unsupported)
+    }
+
+    /// The faults injected into the disk of the device in `slot`.
+    fn block_faults(&self, _slot: usize) -> Result<FaultConfig, HotplugError> {
+        Err(HotplugError::Unsupported)
+    }
+
+    /// Inject `faults` into the disk of the device in `slot`, from its next request on.
+    fn set_block_faults(&mut self, _slot: usize, _faults: FaultConfig) -> Result<(), HotplugError> {
+        Err(HotplugError::Unsupported)
+    }
+
+    /// Taint what the hart reads from the UART, see [`RVCPU::enable_taint`].
+    /// Returns `false` if the board has no UART.
+    fn taint_uart(&mut self) -> bool {
+        false
+    }
+
+    /// Taint what the disk in `slot` reads from the byte `region` to memory.
+    fn taint_disk(&mut self, _slot: usize, _region: Range<u64>) -> Result<(), HotplugError> {
+        Err(HotplugError::Unsupported)
+    }
+
+    /// The pins of the GPIO controller, `None` if the board has none.
+    fn gpio_state(&self) -> Option<GpioState> {
+        None
+    }
+
+    /// Drive GPIO `pin` from the host, or stop driving it with `None`.
+    /// Returns `false` if the board has no GPIO controller.
+    fn drive_gpio(&mut self, _pin: u32, _level: Option<bool>) -> bool {
+        false
+    }
+
     fn run(&mut self) {
-        while self.status() == BoardStatus::Running {
-            if let Err(e) = self.step() {
-                eprintln!("Board encountered an exception: {:?}", e);
-                break;
+        loop {
+            match self.status() {
+                BoardStatus::Running => {
+                    if let Err(e) = self.step() {
+                        eprintln!("Board encountered an exception: {:?}", e);
+                        break;
+                    }
+                }
+                BoardStatus::Resetting => self.reset(),
+                BoardStatus::Halt => break,
             }
         }
     }
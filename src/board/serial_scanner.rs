@@ -0,0 +1,131 @@
+//! Watch the serial output for patterns such as `Kernel panic`, and stop the board when one
+//! shows up: rvdb breaks into the prompt, a batch run dumps the hart state and fails.
+//!
+//! The UART may be drained on the event loop thread, so matches are sent to the board through a
+//! channel and ring its [`Doorbell`], the board turns them into a
+//! [`BoardRequest::Break`](super::BoardRequest::Break).
+
+use std::collections::VecDeque;
+
+use crossbeam::channel::Sender;
+
+use crate::{byte_io::ByteSink, doorbell::Doorbell};
+
+/// Patterns of `--detect-panic`.
+pub const DEFAULT_PANIC_PATTERNS: [&str; 2] = ["Kernel panic", "Oops"];
+
+pub struct SerialScanner {
+    patterns: Vec<String>,
+    /// The last bytes, as many as the longest pattern.
+    window: VecDeque<u8>,
+    window_len: usize,
+    matches: Sender<String>,
+    doorbell: Doorbell,
+}
+
+impl SerialScanner {
+    pub fn new(patterns: Vec<String>, matches: Sender<String>, doorbell: Doorbell) -> Self {
+        let window_len = patterns.iter().map(String::len).max().unwrap_or(0);
+        Self {
+            patterns,
+            window: VecDeque::with_capacity(window_len),
+            window_len,
+            matches,
+            doorbell,
+        }
+    }
+
+    pub fn scan(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.feed(byte);
+        }
+    }
+
+    fn feed(&mut self, byte: u8) {
+        if self.window.len() == self.window_len {
+            self.window.pop_front();
+        }
+        self.window.push_back(byte);
+
+        let window = self.window.make_contiguous();
+        if let Some(pattern) = self
+            .patterns
+            .iter()
+            .find(|pattern| window.ends_with(pattern.as_bytes()))
+        {
+            let _ = self
+                .matches
+                .send(format!("serial output matched {pattern:?}"));
+            self.doorbell.ring();
+        }
+    }
+
+    /// Scan the bytes on their way to `sink`.
+    pub fn forward_to<'a, S: ByteSink + ?Sized>(
+        &'a mut self,
+        sink: &'a mut S,
+    ) -> ScanningSink<'a, S> {
+        ScanningSink {
+            scanner: self,
+            sink,
+        }
+    }
+}
+
+pub struct ScanningSink<'a, S: ByteSink + ?Sized> {
+    scanner: &'a mut SerialScanner,
+    sink: &'a mut S,
+}
+
+impl<S: ByteSink + ?Sized> ByteSink for ScanningSink<'_, S> {
+    fn do_receive(&mut self, byte: u8) {
+        self.sink.do_receive(byte);
+        self.scanner.feed(byte);
+    }
+
+    fn before_receive(&mut self) {
+        self.sink.before_receive();
+    }
+
+    fn after_receive(&mut self, has_received: bool) {
+        self.sink.after_receive(has_received);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::byte_io::ByteSinkExt;
+    use crossbeam::channel::{self, Receiver};
+
+    fn scanner() -> (SerialScanner, Receiver<String>) {
+        let (tx, rx) = channel::unbounded();
+        let patterns = DEFAULT_PANIC_PATTERNS.map(String::from).to_vec();
+        (SerialScanner::new(patterns, tx, Doorbell::new()), rx)
+    }
+
+    #[test]
+    fn test_match_across_chunks() {
+        let (mut scanner, matches) = scanner();
+
+        scanner.scan(b"[    1.000000] Kernel pa");
+        assert!(matches.try_recv().is_err());
+        scanner.scan(b"nic - not syncing: VFS\n");
+        assert_eq!(
+            matches.try_recv().unwrap(),
+            "serial output matched \"Kernel panic\""
+        );
+        scanner.scan(b"Kernel is fine\n");
+        assert!(matches.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_forward_to() {
+        let (mut scanner, matches) = scanner();
+        let mut output = Vec::new();
+
+        scanner.forward_to(&mut output).receive_bytes(*b"Oops [#1]");
+        assert_eq!(output, b"Oops [#1]");
+        assert!(matches.try_recv().is_ok());
+    }
+}
@@ -3,50 +3,101 @@ use std::{
     cell::{RefCell, UnsafeCell},
     collections::HashMap,
     hint::cold_path,
+    io::Write,
+    ops::Range,
+    path::Path,
     pin::Pin,
     rc::Rc,
-    sync::atomic::Ordering,
+    sync::{Arc, atomic::Ordering},
 };
 
 use crossbeam::channel;
 
 use crate::{
-    DeviceConfig, EMULATOR_CONFIG,
+    BusDeviceKind, DeviceConfig, EMULATOR_CONFIG,
     background::BackgroundExecutor,
-    board::{Board, BoardStatus},
-    byte_io::{ByteSinkExt, ByteSource},
+    board::{
+        Board, BoardControl, BoardRequest, BoardStatus, HotplugError, HotplugInfo,
+        memory_map::MemoryMap, serial_scanner::SerialScanner,
+    },
+    byte_io::{ByteSink, ByteSinkExt, ByteSource, WriterSink},
+    config::arch_config::{REG_NAME, WordType},
     device::{
-        self, DeviceTrait, IdAllocator,
+        self, DeviceTrait, FaultConfig, IdAllocator, MemMapInfo, MemMappedDeviceTrait,
         aclint::Clint,
+        cfi_flash::CfiFlash,
         config::{
-            CLINT_BASE, CLINT_SIZE, PLIC_BASE, PLIC_SIZE, POWER_MANAGER_BASE, POWER_MANAGER_SIZE,
+            CLINT_NAME, DMA_DEVICE_ID, FLASH_NAME, PLIC_NAME, POWER_MANAGER_BASE,
+            POWER_MANAGER_NAME, POWER_MANAGER_SIZE, VIRTIO_MMIO_SLOTS, VIRTIO_SHM_BASE,
+            VIRTIO_SHM_WINDOW,
         },
+        dma_engine::DmaEngine,
+        eeprom::{Eeprom, I2cEeprom, SpiEeprom},
+        entropy::{self, EntropySource},
         fast_uart::{FastUart16550, UartBytePort},
+        hypercall::{Checkpoint, Hypercall},
+        lm75::Lm75,
         mmio::{MemoryMapIO, MemoryMapItem},
+        ocores_i2c::{I2cSlave, OcoresI2c},
         plic::{
-            PLIC,
-            irq_line::{PlicIRQLine, PlicIRQSource},
+            ExternalInterrupt, PLIC,
+            irq_line::{IrqDescriptor, IrqPin, PlicIRQLine, PlicIRQSource},
+            storm::{Storm, StormConfig, StormDetector},
         },
         power_manager::{POWER_OFF_CODE, POWER_STATUS, PowerManager},
+        riscv_iommu::RiscvIommu,
+        scripted::{ScriptError, ScriptedDevice},
+        sd_card::SdCard,
+        sifive_gpio::{GpioScript, GpioState, SifiveGpio},
+        sifive_pwm::SifivePwm,
+        sifive_spi::{SPI_CS_COUNT, SifiveSpi, SpiSlave},
         virtio::{
+            block_backend::{self, FaultControl},
+            dma::GuestRam,
+            shared_memory::SharedMemory,
             virtio_blk::VirtIOBlkDeviceBuilder,
-            virtio_mmio::{VirtIODeviceID, VirtIOMMIO},
+            virtio_mmio::{EmptyVirtIOSlot, VirtIODeviceID, VirtIOMMIO},
         },
+        watchdog::{Watchdog, WatchdogAction},
     },
-    device_poller::DevicePoller,
-    isa::riscv::{
-        executor::RVCPU,
-        mmu::VirtAddrManager,
-        trap::{Exception, Interrupt},
+    device_poller::{DevicePoller, PollEventId},
+    doorbell::Doorbell,
+    isa::{
+        DebugTarget,
+        riscv::{
+            csr_reg::{
+                CsrRegFile, HartIdentity, NamedCsrReg,
+                csr_macro::{
+                    CSR_REG_TABLE, Mcause, Mepc, Minstret, Mstatus, Mtval, Satp, Scause, Sepc,
+                    Stval,
+                },
+                custom::CustomCsr,
+            },
+            decoder::Decoder,
+            executor::RVCPU,
+            extension::InstructionExtension,
+            isa_builder::ISABuilder,
+            mmu::VirtAddrManager,
+            taint::DiskTaint,
+            trap::{Exception, Interrupt, Trap},
+        },
     },
-    load::{ELFLoader, load_bin},
-    ram::Ram,
-    vclock::{Timer, VirtualClockRef},
+    load::{ELFLoader, load_bin, load_cmdline},
+    ram::{Ram, RamInit},
+    vclock::{self, Timer, VirtualClockRef},
+    work_queue::WorkQueue,
 };
 
 #[cfg(feature = "test-device")]
 use crate::device::test_device::TestDevice;
 
+#[cfg(target_arch = "wasm32")]
+use crate::device_poller::PollingFnWrapper;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::event_loop::{EventLoop, EventLoopThread, Interest};
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::TcpStream;
+
 pub trait RiscvIRQHandler {
     fn handle_irq(&mut self, interrupt: Interrupt, level: bool);
 }
@@ -74,31 +125,406 @@ impl IRQLine {
     }
 }
 
-const PLIC_FREQUENCY_DIVISION: usize = 128;
+/// How often, in ticks, the guest time is published to the device threads and the background
+/// tasks run when they have no thread.
+const DEVICE_POLL_PERIOD: u64 = 128;
+
+/// How often the terminal is checked for input where it can not be watched by the event loop.
+#[cfg(all(feature = "native-cli", not(unix)))]
+const TERMINAL_INPUT_PERIOD: std::time::Duration = std::time::Duration::from_millis(10);
 
 pub struct RVBoardBuilder {
     extra_plic_devices: Vec<Rc<RefCell<dyn DeviceTrait>>>,
     virtio_devices: Vec<DeviceConfig>,
     mmio_items: Vec<MemoryMapItem>,
     id_allocators: HashMap<TypeId, IdAllocator>,
+    /// Shared by everything that needs the board to service the devices, see [`crate::doorbell`].
+    doorbell: Doorbell,
     device_poller: DevicePoller,
     background: BackgroundExecutor,
+    #[cfg(not(target_arch = "wasm32"))]
+    event_loop: EventLoop,
+    work_queue: WorkQueue,
+    isa: Option<ISABuilder>,
+    custom_csrs: Vec<CustomCsr>,
+    extensions: Vec<Arc<dyn InstructionExtension>>,
+    identity: HartIdentity,
+    irq_pins: Vec<IrqPin>,
+    control: BoardControl,
+    watchdog: Option<WatchdogAction>,
+    irq_storm: Option<StormConfig>,
+    iommu: bool,
+    dma: bool,
+    gpio: Option<GpioScript>,
+    pwm: bool,
+    rng_seed: Option<u64>,
+    spi_devices: Vec<(usize, Box<dyn SpiSlave>)>,
+    i2c_devices: Vec<(u8, Box<dyn I2cSlave>)>,
+    serial: SerialDestination,
+    panic_patterns: Vec<String>,
+    memory_map: MemoryMap,
+}
+
+/// Where the output of the UART goes, and its input comes from.
+pub enum SerialDestination {
+    /// The host terminal, with the `native-cli` feature, otherwise like [`Self::Buffer`].
+    Terminal,
+    /// Kept for [`VirtBoard::take_uart_output`], input is given with [`VirtBoard::push_uart_input`].
+    Buffer,
+    /// Written to a file or any other writer, the UART gets no input.
+    Writer(Box<dyn Write + Send>),
+    /// A TCP connection, e.g. accepted for `--serial-tcp`.
+    #[cfg(not(target_arch = "wasm32"))]
+    Tcp(TcpStream),
+}
+
+/// Create the interrupt output of the `index`-th device of type `D` as described by
+/// [`MemMappedDeviceTrait::irq`] or moved by `map`, the caller connects the returned pin to the PLIC.
+fn connect_irq<D: device::MemMappedDeviceTrait>(
+    device: &mut D,
+    index: WordType,
+    map: &MemoryMap,
+) -> Option<IrqPin> {
+    let desc = D::irq()?;
+    let first = map
+        .region_of::<D>()
+        .and_then(|region| region.irq)
+        .unwrap_or(desc.id);
+    let pin = IrqPin::new(IrqDescriptor {
+        id: first + index as ExternalInterrupt,
+        ..desc
+    });
+    device.connect_irq(pin.clone());
+    Some(pin)
+}
+
+/// An EEPROM of `size` bytes, starting from the content of `image` if given.
+fn open_eeprom(size: usize, image: Option<&Path>) -> Eeprom {
+    let eeprom = Eeprom::new(size);
+    match image {
+        Some(path) => {
+            eeprom.with_image(&std::fs::read(path).unwrap_or_else(|err| {
+                panic!("failed to read EEPROM image {}: {err}", path.display())
+            }))
+        }
+        None => eeprom,
+    }
+}
+
+/// The address range of VirtIO MMIO slot `index`.
+fn virtio_slot_info(map: &MemoryMap, index: usize) -> MemMapInfo {
+    map.allocator::<VirtIOMMIO>(index as WordType, "virtio".into())
+        .get()
+}
+
+/// The placeholder mapped at VirtIO MMIO slot `index` while it has no device.
+fn empty_virtio_slot(map: &MemoryMap, index: usize) -> MemoryMapItem {
+    let info = virtio_slot_info(map, index);
+    MemoryMapItem::new(
+        info.name,
+        info.base,
+        info.size,
+        Rc::new(RefCell::new(EmptyVirtIOSlot)),
+    )
+}
+
+/// Create the VirtIO device described by `cfg` for the slot `slot`, which accesses the guest
+/// memory in `ram` directly, or through `iommu` as the device `slot`, and serves its requests
+/// on `work_queue`.
+fn create_virtio_device(
+    ram: &Rc<UnsafeCell<Ram>>,
+    iommu: Option<&Rc<RefCell<RiscvIommu>>>,
+    work_queue: &WorkQueue,
+    slot: usize,
+    cfg: &DeviceConfig,
+) -> Result<VirtIOMMIO, HotplugError> {
+    let virtio_device = match cfg.dev_type {
+        VirtIODeviceID::Block => {
+            let path = cfg.path.to_string_lossy().into_owned();
+            // TODO: Use raw pointer instead of Ram::write will break atomicity of `RVCPU`.
+            let mut builder =
+                VirtIOBlkDeviceBuilder::new(unsafe { ram.as_mut_unchecked() }, path.clone())
+                    .host_feature(crate::device::virtio::virtio_blk::VirtIOBlockFeature::BlockSize)
+                    .read_only(cfg.read_only)
+                    .cache(cfg.cache)
+                    .work_queue(work_queue);
+            if let Some(iommu) = iommu {
+                builder = builder.iommu(iommu.clone(), slot as u32);
+            }
+            builder
+                .try_get()
+                .map_err(|source| HotplugError::Backend { path, source })?
+        }
+        dev_type => return Err(HotplugError::UnsupportedDevice(dev_type)),
+    };
+    Ok(VirtIOMMIO::new(Box::new(UnsafeCell::new(virtio_device))))
+}
+
+/// Map the shared memory regions of `device`, in slot `slot`, into the window of the slot.
+fn map_shared_memory(
+    device: &mut VirtIOMMIO,
+    slot: usize,
+) -> Result<Vec<MemoryMapItem>, HotplugError> {
+    let end = VIRTIO_SHM_BASE + (slot as WordType + 1) * VIRTIO_SHM_WINDOW;
+    let mut base = end - VIRTIO_SHM_WINDOW;
+    let mut items = Vec::new();
+    for (id, len) in device.shared_memory_regions() {
+        let len = len.next_multiple_of(0x1000);
+        if len > end - base {
+            return Err(HotplugError::SharedMemory { slot, len });
+        }
+        let region = Rc::new(RefCell::new(SharedMemory::new(len as usize)));
+        device.attach_shared_memory(id, base, region.clone());
+        items.push(MemoryMapItem::new(
+            format!("virtio-shm{slot}.{id}"),
+            base,
+            len,
+            region,
+        ));
+        base += len;
+    }
+    Ok(items)
+}
+
+/// Register the timer task completing the requests `device` holds back, see
+/// [`FaultConfig::latency`].
+fn connect_timer(device: &Rc<RefCell<VirtIOMMIO>>, timer: &Rc<UnsafeCell<Timer>>) {
+    let weak = Rc::downgrade(device);
+    let task = unsafe { timer.as_mut_unchecked() }.register(move || {
+        if let Some(device) = weak.upgrade() {
+            device.borrow_mut().timer_expired();
+        }
+    });
+    device.borrow_mut().attach_timer(timer.clone(), task);
+}
+
+/// Publish the guest time every [`DEVICE_POLL_PERIOD`] ticks, and with `inline` background tasks
+/// ring `doorbell` too, so the board polls them.
+fn schedule_device_poll(
+    timer: &Rc<UnsafeCell<Timer>>,
+    clock: &VirtualClockRef,
+    doorbell: &Doorbell,
+    inline: bool,
+) {
+    let clock = clock.clone();
+    let doorbell = doorbell.clone();
+    let mut timer = unsafe { timer.as_mut_unchecked() }.guard();
+    let task = timer.register_repeating(move || {
+        let now = clock.now();
+        vclock::publish_guest_time(now);
+        if inline {
+            doorbell.ring();
+        }
+        Some(now + DEVICE_POLL_PERIOD)
+    });
+    timer.set_delay(task, DEVICE_POLL_PERIOD);
+}
+
+/// Wait for the client of the serial console on localhost:`port`.
+#[cfg(not(target_arch = "wasm32"))]
+fn accept_serial_client(port: u16) -> TcpStream {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", port))
+        .unwrap_or_else(|err| panic!("failed to listen on port {port}: {err}"));
+    log::info!("waiting for the serial console client on localhost:{port}");
+    let (stream, addr) = listener
+        .accept()
+        .unwrap_or_else(|err| panic!("failed to accept the serial console client: {err}"));
+    log::info!("serial console connected to {addr}");
+    stream
+}
+
+/// A populated VirtIO MMIO slot.
+struct VirtIOSlot {
+    device: Rc<RefCell<VirtIOMMIO>>,
+    irq: ExternalInterrupt,
+    /// Bases of the shared memory regions of the device.
+    shared_memory: Vec<WordType>,
+    poll_event: Option<PollEventId>,
 }
 
 impl RVBoardBuilder {
     pub fn new() -> Self {
         let (plic_irq_tx, plic_irq_rx) = channel::unbounded();
+        let doorbell = Doorbell::new();
 
         Self {
             extra_plic_devices: Vec::new(),
             virtio_devices: Vec::new(),
             mmio_items: Vec::new(),
             id_allocators: HashMap::new(),
-            device_poller: DevicePoller::new(plic_irq_tx, plic_irq_rx),
+            device_poller: DevicePoller::new(plic_irq_tx, plic_irq_rx, doorbell.clone()),
             background: BackgroundExecutor::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            event_loop: EventLoop::new().expect("failed to create the host event loop"),
+            work_queue: WorkQueue::default().with_doorbell(doorbell.clone()),
+            isa: None,
+            custom_csrs: Vec::new(),
+            extensions: Vec::new(),
+            identity: HartIdentity::default(),
+            irq_pins: Vec::new(),
+            control: BoardControl::new(doorbell.clone()),
+            doorbell,
+            watchdog: None,
+            irq_storm: Some(StormConfig::default()),
+            iommu: false,
+            dma: false,
+            gpio: None,
+            pwm: false,
+            rng_seed: None,
+            spi_devices: Vec::new(),
+            i2c_devices: Vec::new(),
+            serial: SerialDestination::Terminal,
+            panic_patterns: Vec::new(),
+            memory_map: MemoryMap::default(),
+        }
+    }
+
+    /// The host event loop device backends register their file descriptors and timers on.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn event_loop(&mut self) -> &mut EventLoop {
+        &mut self.event_loop
+    }
+
+    /// The worker threads devices offload long operations to, see [`crate::work_queue`].
+    pub fn work_queue(&self) -> &WorkQueue {
+        &self.work_queue
+    }
+
+    /// The handle devices use to reset or stop the board.
+    pub fn board_control(&self) -> BoardControl {
+        self.control.clone()
+    }
+
+    /// Add a watchdog which takes `action` when the guest stops kicking it.
+    pub fn watchdog(mut self, action: WatchdogAction) -> Self {
+        self.watchdog = Some(action);
+        self
+    }
+
+    /// Warn about interrupt storms as `config` describes, on by default, `None` turns it off.
+    pub fn irq_storm(mut self, config: Option<StormConfig>) -> Self {
+        self.irq_storm = config;
+        self
+    }
+
+    /// Add a RISC-V IOMMU translating the DMA of the VirtIO devices.
+    pub fn iommu(mut self, enabled: bool) -> Self {
+        self.iommu = enabled;
+        self
+    }
+
+    /// Add a memory-to-memory DMA engine, behind the IOMMU if there is one.
+    pub fn dma(mut self, enabled: bool) -> Self {
+        self.dma = enabled;
+        self
+    }
+
+    /// Add a GPIO controller whose inputs follow the events of `script`.
+    pub fn gpio(mut self, script: GpioScript) -> Self {
+        self.gpio = Some(script);
+        self
+    }
+
+    /// Add a SiFive PWM, which also serves as a periodic timer.
+    pub fn pwm(mut self, enabled: bool) -> Self {
+        self.pwm = enabled;
+        self
+    }
+
+    /// Add an entropy source whose stream is derived from `seed`.
+    pub fn rng(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    /// Connect the UART to the host terminal (the default), otherwise its output is only
+    /// available through [`VirtBoard::take_uart_output`].
+    pub fn serial_console(self, enabled: bool) -> Self {
+        self.serial(match enabled {
+            true => SerialDestination::Terminal,
+            false => SerialDestination::Buffer,
+        })
+    }
+
+    /// Connect the UART to `destination`.
+    pub fn serial(mut self, destination: SerialDestination) -> Self {
+        self.serial = destination;
+        self
+    }
+
+    /// Forward what the guest writes to `uart` from `port` to `sink`, on the host event loop
+    /// once the UART queued something.
+    fn forward_uart_output(
+        &mut self,
+        uart: &Rc<RefCell<FastUart16550>>,
+        port: &UartBytePort,
+        mut sink: impl ByteSink + Send + 'static,
+        mut scanner: Option<SerialScanner>,
+    ) {
+        let mut port = port.clone();
+        let forward = move || {
+            match &mut scanner {
+                Some(scanner) => port.drain_to(&mut scanner.forward_to(&mut sink)),
+                None => port.drain_to(&mut sink),
+            };
+        };
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let (_, notifier) = self.event_loop.add_notifier(forward);
+            uart.borrow_mut().on_output(move || notifier.notify());
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            let _ = uart;
+            let mut forward = forward;
+            self.device_poller
+                .add_event(Box::new(PollingFnWrapper::new(move || {
+                    forward();
+                    None
+                })));
         }
     }
 
+    /// Break when the serial output contains one of `patterns`, see [`SerialScanner`].
+    pub fn panic_patterns(mut self, patterns: Vec<String>) -> Self {
+        self.panic_patterns.extend(patterns);
+        self
+    }
+
+    /// Place the UART, PLIC, CLINT and VirtIO devices as described by `map`. Must be set
+    /// before devices are added.
+    pub fn memory_map(mut self, map: MemoryMap) -> Self {
+        self.memory_map = map;
+        self
+    }
+
+    /// Set the IDs read from `mvendorid`, `marchid`, `mimpid` and `mhartid`.
+    pub fn identity(mut self, identity: HartIdentity) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Add user-defined CSRs, they must not conflict with the builtin ones
+    /// (as checked by [`parse_custom_csrs`](crate::isa::riscv::csr_reg::custom::parse_custom_csrs)).
+    pub fn custom_csrs(mut self, csrs: Vec<CustomCsr>) -> Self {
+        self.custom_csrs.extend(csrs);
+        self
+    }
+
+    /// Decode and execute the instructions of `extension`, with its CSRs.
+    pub fn extension(mut self, extension: Arc<dyn InstructionExtension>) -> Self {
+        self.custom_csrs.extend(extension.csrs());
+        self.extensions.push(extension);
+        self
+    }
+
+    /// Build the CPU for `isa` instead of the default decoder ISA.
+    pub fn isa(mut self, isa: ISABuilder) -> Self {
+        self.isa = Some(isa);
+        self
+    }
+
     pub fn add_plic_device<D: device::MemMappedDeviceTrait + 'static>(
         mut self,
         device: Rc<RefCell<D>>,
@@ -107,11 +533,17 @@ impl RVBoardBuilder {
         let allocator = self
             .id_allocators
             .entry(type_id)
-            .or_insert_with(|| device::IdAllocator::new::<D>(0, stringify!(D).to_string()));
+            .or_insert_with(|| self.memory_map.allocator::<D>(0, D::name().to_string()));
 
         let info = allocator.get();
-        self.mmio_items
-            .push(MemoryMapItem::new(info.base, info.size, device.clone()));
+        self.mmio_items.push(
+            MemoryMapItem::new(info.name, info.base, info.size, device.clone())
+                .widths(D::access_widths()),
+        );
+
+        if let Some(pin) = connect_irq(&mut *device.borrow_mut(), info.index, &self.memory_map) {
+            self.irq_pins.push(pin);
+        }
 
         if let Some(event) = device.borrow_mut().get_poll_event() {
             self.device_poller.add_event(event);
@@ -122,6 +554,60 @@ impl RVBoardBuilder {
         self
     }
 
+    /// Map `flash` as bank 0 of the virt flash range, the firmware.
+    pub(crate) fn flash(self, flash: CfiFlash) -> Self {
+        self.flash_bank(0, flash)
+    }
+
+    /// Map `flash` as bank 1 of the virt flash range, the variable store of UEFI firmware.
+    pub(crate) fn flash_vars(self, flash: CfiFlash) -> Self {
+        self.flash_bank(1, flash)
+    }
+
+    fn flash_bank(mut self, bank: WordType, flash: CfiFlash) -> Self {
+        // Either bank can be mapped alone, so its range does not depend on the order.
+        let allocator = self
+            .memory_map
+            .allocator::<CfiFlash>(bank, FLASH_NAME.to_string());
+        self.id_allocators
+            .insert(TypeId::of::<CfiFlash>(), allocator);
+        self.add_plic_device(Rc::new(RefCell::new(flash)))
+    }
+
+    /// Map `device` at its base, halting the board when the guest writes another value than the
+    /// script expects.
+    pub fn scripted_device(self, device: Rc<RefCell<ScriptedDevice>>) -> Self {
+        device.borrow_mut().halt_on_mismatch(self.control.clone());
+        self.add_plic_device(device)
+    }
+
+    /// Put `card` on the first chip select of the SPI controller.
+    pub(crate) fn sd_card(self, card: SdCard) -> Self {
+        self.spi_device(0, Box::new(card))
+    }
+
+    /// Put `device` on chip select `cs` of a SiFive SPI controller, which is added with the first
+    /// device.
+    pub fn spi_device(mut self, cs: usize, device: Box<dyn SpiSlave>) -> Self {
+        assert!(
+            cs < SPI_CS_COUNT && self.spi_devices.iter().all(|(used, _)| *used != cs),
+            "SPI chip select {cs} is not available"
+        );
+        self.spi_devices.push((cs, device));
+        self
+    }
+
+    /// Put `device` at the 7-bit address `addr` of an OpenCores I2C controller, which is added
+    /// with the first device.
+    pub fn i2c_device(mut self, addr: u8, device: Box<dyn I2cSlave>) -> Self {
+        assert!(
+            addr < 0x80 && self.i2c_devices.iter().all(|(used, _)| *used != addr),
+            "I2C address {addr:#x} is not available"
+        );
+        self.i2c_devices.push((addr, device));
+        self
+    }
+
     pub fn add_virtio_devices(mut self, devices: &mut Vec<DeviceConfig>) -> Self {
         self.virtio_devices.append(devices);
         self
@@ -133,42 +619,185 @@ impl RVBoardBuilder {
         let ram_ref = Rc::new(UnsafeCell::new(ram));
 
         // Construct devices
-        let (uart1, uart_port1) = FastUart16550::new();
-        let uart1 = Rc::new(RefCell::new(uart1));
-        self = self.add_plic_device(uart1);
-
-        #[cfg(feature = "native-cli")]
-        {
-            use crate::byte_io::ByteSource;
-            use std::io::IsTerminal;
-
-            // TODO: make this configurable
-            // uart <-> std I/O
-            use crate::{byte_io::TerminalIOContext, device_poller::PollingFnWrapper};
+        if let Some(action) = self.watchdog {
+            let watchdog =
+                Watchdog::new(action, clock.clone(), timer.clone(), self.control.clone());
+            self = self.add_plic_device(Rc::new(RefCell::new(watchdog)));
+        }
 
-            let mut ctx = TerminalIOContext::new();
-            let mut uart_port1 = uart_port1.clone();
+        let hypercall = Rc::new(RefCell::new(Hypercall::new(
+            clock.clone(),
+            self.control.clone(),
+        )));
+        self = self.add_plic_device(hypercall.clone());
+
+        let iommu = self.iommu.then(|| {
+            let ram = GuestRam::new(unsafe { ram_ref.as_mut_unchecked() });
+            Rc::new(RefCell::new(RiscvIommu::new(ram)))
+        });
+        if let Some(iommu) = &iommu {
+            self = self.add_plic_device(iommu.clone());
+        }
 
-            let input_term = std::io::stdin().is_terminal();
+        if self.dma {
+            let mut ram = GuestRam::new(unsafe { ram_ref.as_mut_unchecked() });
+            if let Some(iommu) = &iommu {
+                ram = ram.behind_iommu(iommu.clone(), DMA_DEVICE_ID);
+            }
+            let dma = Rc::new(RefCell::new(DmaEngine::new(ram)));
+            let weak = Rc::downgrade(&dma);
+            let task = unsafe { timer.as_mut_unchecked() }.register(move || {
+                if let Some(dma) = weak.upgrade() {
+                    dma.borrow_mut().timer_expired();
+                }
+            });
+            dma.borrow_mut().attach_timer(timer.clone(), task);
+            self = self.add_plic_device(dma);
+        }
 
-            self.device_poller
-                .add_event(Box::new(PollingFnWrapper::new(move || {
-                    // stdin -> uart
-                    if input_term {
-                        ctx.drain_to(&mut uart_port1);
+        let gpio = self.gpio.take().map(|script| {
+            let gpio = Rc::new(RefCell::new(SifiveGpio::new()));
+            // One task per event, a task cannot schedule itself again from its callback.
+            let mut timer = unsafe { timer.as_mut_unchecked() }.guard();
+            for event in script.events {
+                let weak = Rc::downgrade(&gpio);
+                let task = timer.register(move || {
+                    if let Some(gpio) = weak.upgrade() {
+                        gpio.borrow_mut().drive(event.pin, event.level);
                     }
+                });
+                timer.set_due(task, event.at);
+            }
+            gpio
+        });
+        if let Some(gpio) = &gpio {
+            self = self.add_plic_device(gpio.clone());
+        }
 
-                    // uart -> stdout
-                    uart_port1.drain_to(&mut ctx);
+        if self.pwm {
+            let pwm = Rc::new(RefCell::new(SifivePwm::new(clock.clone())));
+            let weak = Rc::downgrade(&pwm);
+            let task = unsafe { timer.as_mut_unchecked() }.register_repeating(move || {
+                weak.upgrade()
+                    .and_then(|pwm| pwm.borrow_mut().timer_expired())
+            });
+            pwm.borrow_mut().attach_timer(timer.clone(), task);
+            self = self.add_plic_device(pwm);
+        }
 
-                    None
-                })));
+        if let Some(seed) = self.rng_seed {
+            self = self.add_plic_device(Rc::new(RefCell::new(EntropySource::new(seed))));
         }
 
+        if !self.spi_devices.is_empty() {
+            let spi = std::mem::take(&mut self.spi_devices)
+                .into_iter()
+                .fold(SifiveSpi::new(), |spi, (cs, device)| {
+                    spi.with_slave(cs, device)
+                });
+            self = self.add_plic_device(Rc::new(RefCell::new(spi)));
+        }
+        if !self.i2c_devices.is_empty() {
+            let i2c = std::mem::take(&mut self.i2c_devices)
+                .into_iter()
+                .fold(OcoresI2c::new(), |i2c, (addr, device)| {
+                    i2c.with_slave(addr, device)
+                });
+            self = self.add_plic_device(Rc::new(RefCell::new(i2c)));
+        }
+
+        let (uart1, uart_port1) = FastUart16550::new();
+        let uart1 = Rc::new(RefCell::new(uart1));
+        self = self.add_plic_device(uart1.clone());
+
+        let (serial_match_tx, serial_matches) = channel::unbounded();
+        let scanner = (!self.panic_patterns.is_empty()).then(|| {
+            SerialScanner::new(
+                self.panic_patterns.clone(),
+                serial_match_tx,
+                self.doorbell.clone(),
+            )
+        });
+
+        let scanner = match std::mem::replace(&mut self.serial, SerialDestination::Buffer) {
+            #[cfg(feature = "native-cli")]
+            SerialDestination::Terminal => {
+                use std::io::IsTerminal;
+
+                // uart <-> std I/O
+                use crate::byte_io::TerminalIOContext;
+
+                // stdin -> uart
+                if std::io::stdin().is_terminal() {
+                    let mut input = TerminalIOContext::new();
+                    let mut uart_port1 = uart_port1.clone();
+                    let mut drain_input = move || {
+                        while input.has_input() {
+                            input.drain_to(&mut uart_port1);
+                        }
+                    };
+                    #[cfg(unix)]
+                    self.event_loop
+                        .register(std::io::stdin(), Interest::Readable, move |_| {
+                            drain_input();
+                            true
+                        })
+                        .expect("failed to watch the terminal input");
+                    #[cfg(not(unix))]
+                    self.event_loop
+                        .add_timer(TERMINAL_INPUT_PERIOD, drain_input);
+                }
+
+                // uart -> stdout
+                self.forward_uart_output(&uart1, &uart_port1, TerminalIOContext::new(), scanner);
+                None
+            }
+            SerialDestination::Writer(writer) => {
+                self.forward_uart_output(&uart1, &uart_port1, WriterSink(writer), scanner);
+                None
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            SerialDestination::Tcp(stream) => {
+                use std::io::{BufWriter, ErrorKind, Read};
+
+                let clone = |stream: &TcpStream| {
+                    stream
+                        .try_clone()
+                        .expect("failed to share the serial connection")
+                };
+                let mut reader = clone(&stream);
+                let mut uart_port = uart_port1.clone();
+                self.event_loop
+                    .register(clone(&stream), Interest::Readable, move |_| {
+                        let mut buf = [0u8; 256];
+                        match reader.read(&mut buf) {
+                            Ok(0) => {
+                                log::info!("serial connection closed");
+                                false
+                            }
+                            Ok(len) => {
+                                uart_port.receive_bytes(buf[..len].iter().copied());
+                                true
+                            }
+                            Err(err) if err.kind() == ErrorKind::Interrupted => true,
+                            Err(err) => {
+                                log::warn!("serial connection failed: {err}");
+                                false
+                            }
+                        }
+                    })
+                    .expect("failed to watch the serial connection");
+                let sink = WriterSink(BufWriter::new(stream));
+                self.forward_uart_output(&uart1, &uart_port1, sink, scanner);
+                None
+            }
+            _ => scanner,
+        };
+
         const MTIME_OFFSET: u64 = 0xbff8;
         const MTIMECMP_OFFSET: u64 = 0x4000;
 
-        let power_manager = Rc::new(RefCell::new(PowerManager::new()));
+        let power_manager = Rc::new(RefCell::new(PowerManager::new(self.control.clone())));
         let clint = Rc::new(RefCell::new(Clint::new(
             1,
             0,
@@ -180,47 +809,103 @@ impl RVBoardBuilder {
 
         // PLIC init.
         let plic = Rc::new(RefCell::new(PLIC::new()));
+        plic.borrow_mut().set_doorbell(self.doorbell.clone());
+        if self.irq_storm.is_some() {
+            plic.borrow_mut().track_claims();
+        }
         let poller_plic_irq_line = PlicIRQLine::new(&mut *plic.borrow_mut());
         self.device_poller.set_irq_line(poller_plic_irq_line, 0);
 
         self.mmio_items.append(&mut vec![
-            MemoryMapItem::new(POWER_MANAGER_BASE, POWER_MANAGER_SIZE, power_manager),
-            MemoryMapItem::new(CLINT_BASE, CLINT_SIZE, clint.clone()),
-            MemoryMapItem::new(PLIC_BASE, PLIC_SIZE, plic.clone()),
+            MemoryMapItem::new(
+                POWER_MANAGER_NAME,
+                POWER_MANAGER_BASE,
+                POWER_MANAGER_SIZE,
+                power_manager,
+            )
+            .widths(PowerManager::access_widths()),
+            MemoryMapItem::new(
+                CLINT_NAME,
+                self.memory_map.clint.base,
+                self.memory_map.clint.size,
+                clint.clone(),
+            )
+            .widths(Clint::access_widths()),
+            MemoryMapItem::new(
+                PLIC_NAME,
+                self.memory_map.plic.base,
+                self.memory_map.plic.size,
+                plic.clone(),
+            )
+            .widths(PLIC::ACCESS_WIDTHS),
         ]);
 
         // Add VirtIO device.
-        let mut virtio_allocator =
-            device::IdAllocator::new::<VirtIOMMIO>(0, String::from("virtio"));
-        for virtio_device_cfg in self.virtio_devices.iter() {
-            let virtio_device = match virtio_device_cfg.dev_type {
-                VirtIODeviceID::Block => {
-                    // TODO: Use raw pointer instead of Ram::write will break atomicity of `RVCPU`.
-                    let ram_raw_base = unsafe { &mut ram_ref.as_mut_unchecked()[0] as *mut u8 };
-                    VirtIOBlkDeviceBuilder::new(
-                        ram_raw_base,
-                        String::from(virtio_device_cfg.path.to_str().unwrap()),
-                    )
-                    .host_feature(crate::device::virtio::virtio_blk::VirtIOBlockFeature::BlockSize)
-                    .get()
-                }
-                dev_type => {
-                    panic!("unsupport device: {:#?}", dev_type);
-                }
-            };
-            let virtio_mmio_device = VirtIOMMIO::new(Box::new(UnsafeCell::new(virtio_device)));
-            let virtio_info = virtio_allocator.get();
+        assert!(
+            self.virtio_devices.len() <= VIRTIO_MMIO_SLOTS,
+            "at most {VIRTIO_MMIO_SLOTS} VirtIO devices are supported"
+        );
+        let mut virtio_slots: Vec<_> = (0..VIRTIO_MMIO_SLOTS).map(|_| None).collect();
+        for (slot, virtio_device_cfg) in self.virtio_devices.iter().enumerate() {
+            let mut virtio_mmio_device = create_virtio_device(
+                &ram_ref,
+                iommu.as_ref(),
+                &self.work_queue,
+                slot,
+                virtio_device_cfg,
+            )
+            .unwrap_or_else(|err| panic!("failed to create VirtIO device: {err}"));
+            let virtio_info = virtio_slot_info(&self.memory_map, slot);
+            let pin = connect_irq(&mut virtio_mmio_device, virtio_info.index, &self.memory_map)
+                .expect("VirtIO devices have an interrupt");
+            let shared_memory = map_shared_memory(&mut virtio_mmio_device, slot)
+                .unwrap_or_else(|err| panic!("failed to create VirtIO device: {err}"));
+            let virtio_mmio_device = Rc::new(RefCell::new(virtio_mmio_device));
+            connect_timer(&virtio_mmio_device, &timer);
+            let poll_event = virtio_mmio_device
+                .borrow_mut()
+                .get_poll_event()
+                .map(|event| self.device_poller.add_event(event));
+            virtio_slots[slot] = Some(VirtIOSlot {
+                device: virtio_mmio_device.clone(),
+                irq: pin.id(),
+                shared_memory: shared_memory.iter().map(|item| item.start).collect(),
+                poll_event,
+            });
+            self.mmio_items.extend(shared_memory);
+            self.irq_pins.push(pin);
             self.mmio_items.push(MemoryMapItem::new(
+                virtio_info.name,
                 virtio_info.base,
                 virtio_info.size,
-                Rc::new(RefCell::new(virtio_mmio_device)),
+                virtio_mmio_device,
             ));
         }
 
-        let mmio = MemoryMapIO::from_mmio_items(ram_ref.clone(), self.mmio_items);
+        for slot in self.virtio_devices.len()..VIRTIO_MMIO_SLOTS {
+            self.mmio_items
+                .push(empty_virtio_slot(&self.memory_map, slot));
+        }
+
+        for pin in self.irq_pins {
+            plic.borrow_mut().connect_pin(pin);
+        }
+
+        let devices = self
+            .mmio_items
+            .iter()
+            .map(|item| item.device.clone())
+            .collect();
+        let mut mmio = MemoryMapIO::from_mmio_items(ram_ref.clone(), self.mmio_items);
+        mmio.set_pma_overrides(self.memory_map.pma.clone());
         let vaddr_manager = VirtAddrManager::from_ram_and_mmio(ram_ref.clone(), mmio);
 
-        let mut cpu = Box::pin(RVCPU::from_vaddr_manager(vaddr_manager));
+        let isa = self.isa.unwrap_or_else(ISABuilder::all);
+        let decoder = Decoder::new_with_extensions(isa, self.extensions);
+        let mut csr = CsrRegFile::from(CSR_REG_TABLE, &self.custom_csrs)
+            .expect("custom CSRs conflict with the builtin ones");
+        csr.set_identity(&self.identity);
+        let mut cpu = Box::pin(RVCPU::from_parts(decoder, csr, vaddr_manager));
 
         // register irq line for timer.
         clint.borrow_mut().set_irq_line(
@@ -238,7 +923,7 @@ impl RVBoardBuilder {
             1,
         );
 
-        cpu.time_addr = Some(CLINT_BASE + MTIME_OFFSET);
+        cpu.time_addr = Some(self.memory_map.clint.base + MTIME_OFFSET);
 
         // register irq line for plic.
         let plic_mathine_irq_line = IRQLine::new(
@@ -254,25 +939,293 @@ impl RVBoardBuilder {
         plic.borrow_mut().set_irq_line(plic_supervisor_irq_line, 1);
 
         // Hand the device poller's tick to the background executor and start the worker thread.
+        // The event loop gets a thread of its own, or is polled inline in deterministic mode.
         let mut background = self.background;
         background.add_polling_task(self.device_poller.poll_task());
-        background.start();
+        #[cfg(not(target_arch = "wasm32"))]
+        let event_loop = if vclock::is_deterministic() {
+            background.add_polling_task(self.event_loop.into_task());
+            None
+        } else {
+            Some(
+                self.event_loop
+                    .spawn()
+                    .expect("failed to start the host event loop"),
+            )
+        };
+        if vclock::is_deterministic() {
+            background.start_inline();
+        } else {
+            background.start();
+        }
+        schedule_device_poll(&timer, &clock, &self.doorbell, background.runs_inline());
 
         VirtBoard {
             background,
+            #[cfg(not(target_arch = "wasm32"))]
+            event_loop,
             loader: None,
+            binary: None,
+            bootargs: None,
             cpu,
             clock,
             timer,
 
+            doorbell: self.doorbell,
             device_poller: self.device_poller,
             clint,
             plic,
-            plic_freq_counter: 0,
+            irq_storm: self.irq_storm.map(StormDetector::new),
             uart_port: uart_port1,
+            scanner,
+            serial_matches,
+
+            work_queue: self.work_queue,
+            devices,
 
+            ram: ram_ref,
+            iommu,
+            virtio_slots,
+            memory_map: self.memory_map,
+
+            hypercall,
+            gpio,
+            control: self.control,
             status: BoardStatus::Running,
+            break_reason: None,
+        }
+    }
+}
+
+/// The image loaded into RAM before the board starts.
+enum BootImage {
+    Elf(Vec<u8>),
+    Binary(Vec<u8>),
+}
+
+/// Assembles a [`VirtBoard`] in code, for embedders which want other devices or images than the
+/// command line offers. [`VirtBoard::from_elf`] and friends use it with [`EMULATOR_CONFIG`].
+///
+/// The RAM is [`ram_config::SIZE`](crate::ram_config::SIZE) bytes at
+/// [`ram_config::BASE_ADDR`](crate::ram_config::BASE_ADDR), fixed at compile time.
+pub struct VirtBoardBuilder {
+    board: RVBoardBuilder,
+    ram: Option<Ram>,
+    image: Option<BootImage>,
+    bootargs: Option<String>,
+}
+
+impl Default for VirtBoardBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtBoardBuilder {
+    /// A virt board without any optional device, with the UART on the host terminal.
+    pub fn new() -> Self {
+        Self {
+            board: RVBoardBuilder::new(),
+            ram: None,
+            image: None,
+            bootargs: None,
+        }
+    }
+
+    /// The board described by [`EMULATOR_CONFIG`].
+    pub fn from_config() -> Self {
+        let mut config = EMULATOR_CONFIG.lock().unwrap();
+        let mut board = RVBoardBuilder::new()
+            .memory_map(config.memory_map.clone())
+            .add_virtio_devices(&mut config.devices);
+        if let Some(isa) = config.isa.clone() {
+            board = board.isa(isa);
+        }
+        board = board
+            .custom_csrs(config.custom_csrs.clone())
+            .identity(config.identity)
+            .serial_console(config.serial_console)
+            .panic_patterns(config.panic_patterns.clone());
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(port) = config.serial_tcp {
+            board = board.serial(SerialDestination::Tcp(accept_serial_client(port)));
+        }
+        if let Some(action) = config.watchdog {
+            board = board.watchdog(action);
+        }
+        board = board.irq_storm(config.irq_storm);
+        board = board.iommu(config.iommu).dma(config.dma).pwm(config.pwm);
+        let rng_seed = config.rng_seed.or_else(|| {
+            config.rng.then(|| match vclock::is_deterministic() {
+                true => 0,
+                false => entropy::host_seed(),
+            })
+        });
+        if let Some(seed) = rng_seed {
+            log::info!("RNG seed {seed}, pass --rng-seed={seed} to replay it");
+            board = board.rng(seed);
+        }
+        if let Some((path, read_only)) = &config.flash {
+            let flash = CfiFlash::open(path, *read_only).unwrap_or_else(|err| {
+                panic!("failed to open flash image {}: {err}", path.display())
+            });
+            board = board.flash(flash);
+        }
+        if let Some((path, read_only)) = &config.flash_vars {
+            let flash = match read_only {
+                true => CfiFlash::open(path, true),
+                false => CfiFlash::create(path),
+            }
+            .unwrap_or_else(|err| {
+                panic!(
+                    "failed to open flash variable store {}: {err}",
+                    path.display()
+                )
+            });
+            board = board.flash_vars(flash);
+        }
+        if let Some((path, read_only)) = &config.sd_card {
+            let path = path.to_string_lossy();
+            let backend = block_backend::open(&path, *read_only)
+                .unwrap_or_else(|err| panic!("failed to open SD card image {path}: {err}"));
+            board = board.sd_card(SdCard::new(backend));
+        }
+        for cfg in &config.spi_devices {
+            let device: Box<dyn SpiSlave> = match &cfg.kind {
+                BusDeviceKind::Eeprom { size, image } => {
+                    Box::new(SpiEeprom::new(open_eeprom(*size, image.as_deref())))
+                }
+                BusDeviceKind::Lm75 { .. } => panic!("the LM75 is not an SPI device"),
+            };
+            board = board.spi_device(cfg.addr as usize, device);
+        }
+        for cfg in &config.i2c_devices {
+            let device: Box<dyn I2cSlave> = match &cfg.kind {
+                BusDeviceKind::Eeprom { size, image } => {
+                    Box::new(I2cEeprom::new(open_eeprom(*size, image.as_deref())))
+                }
+                BusDeviceKind::Lm75 { millicelsius } => Box::new(Lm75::new(*millicelsius)),
+            };
+            board = board.i2c_device(cfg.addr, device);
+        }
+        if let Some(path) = &config.gpio_script {
+            let script = std::fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| text.parse().map_err(|err: ScriptError| err.to_string()))
+                .unwrap_or_else(|err| panic!("failed to load script {}: {err}", path.display()));
+            board = board.gpio(script);
+        } else if config.gpio {
+            board = board.gpio(GpioScript::default());
+        }
+        if let Some(path) = &config.scripted_device {
+            let script = std::fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| text.parse().map_err(|err: ScriptError| err.to_string()))
+                .unwrap_or_else(|err| panic!("failed to load script {}: {err}", path.display()));
+            board = board.scripted_device(Rc::new(RefCell::new(ScriptedDevice::new(script))));
+        }
+
+        #[cfg(feature = "test-device")]
+        let board = board.add_plic_device(Rc::new(RefCell::new(TestDevice::new())));
+
+        let ram = config.ram_init.map(|init| match init {
+            RamInit::Byte(byte) => Ram::with_init(byte),
+            RamInit::Random(seed) => {
+                let seed = seed.unwrap_or_else(|| match vclock::is_deterministic() {
+                    true => 0,
+                    false => entropy::host_seed(),
+                });
+                log::info!("RAM seed {seed}, pass --ram-init=random:{seed} to replay it");
+                Ram::with_random(seed)
+            }
+        });
+
+        Self {
+            board,
+            ram,
+            image: None,
+            bootargs: config.bootargs.clone(),
+        }
+    }
+
+    /// Start from the content of `ram` instead of zeroed memory, the image is loaded on top.
+    pub fn ram(mut self, ram: Ram) -> Self {
+        self.ram = Some(ram);
+        self
+    }
+
+    /// Load an ELF executable and start at its entry point.
+    pub fn elf(mut self, bytes: Vec<u8>) -> Self {
+        self.image = Some(BootImage::Elf(bytes));
+        self
+    }
+
+    /// Load a raw binary at the start of RAM.
+    pub fn binary(mut self, bytes: Vec<u8>) -> Self {
+        self.image = Some(BootImage::Binary(bytes));
+        self
+    }
+
+    /// Write the kernel command line to [`CMDLINE_ADDR`](crate::ram_config::CMDLINE_ADDR).
+    pub fn bootargs(mut self, bootargs: String) -> Self {
+        self.bootargs = Some(bootargs);
+        self
+    }
+
+    /// Map `device` on the board, at the address and PLIC source of
+    /// [`MemMappedDeviceTrait`](device::MemMappedDeviceTrait), or of the memory map.
+    pub fn device<D: device::MemMappedDeviceTrait + 'static>(
+        mut self,
+        device: Rc<RefCell<D>>,
+    ) -> Self {
+        self.board = self.board.add_plic_device(device);
+        self
+    }
+
+    /// Add a VirtIO device in the next free slot.
+    pub fn virtio_device(mut self, device: DeviceConfig) -> Self {
+        self.board = self.board.add_virtio_devices(&mut vec![device]);
+        self
+    }
+
+    /// Connect the UART to `destination`.
+    pub fn serial(mut self, destination: SerialDestination) -> Self {
+        self.board = self.board.serial(destination);
+        self
+    }
+
+    /// Configure the CPU, interrupt controllers and system devices, see [`RVBoardBuilder`].
+    pub fn machine(mut self, f: impl FnOnce(RVBoardBuilder) -> RVBoardBuilder) -> Self {
+        self.board = f(self.board);
+        self
+    }
+
+    pub fn build(self) -> Result<VirtBoard, String> {
+        let mut ram = self.ram.unwrap_or_else(Ram::new);
+        if let Some(bootargs) = &self.bootargs {
+            load_cmdline(&mut ram, bootargs)?;
+        }
+        let mut loader = None;
+        let mut binary = None;
+        match self.image {
+            Some(BootImage::Elf(bytes)) => {
+                let elf =
+                    ELFLoader::try_new(bytes).ok_or_else(|| "Invalid ELF file".to_string())?;
+                elf.load_to_ram(&mut ram);
+                loader = Some(elf);
+            }
+            Some(BootImage::Binary(bytes)) => {
+                load_bin(&mut ram, &bytes);
+                binary = Some(bytes);
+            }
+            None => {}
         }
+
+        let mut board = self.board.build(ram);
+        board.loader = loader;
+        board.binary = binary;
+        board.bootargs = self.bootargs;
+        Ok(board)
     }
 }
 
@@ -280,10 +1233,19 @@ pub struct VirtBoard {
     // Background threads must stop before the poller / devices they touch are dropped, so this is
     // the first field (in rust, "fields of a struct are dropped in declaration order").
     pub background: BackgroundExecutor,
+    /// Runs the host side of the device backends, `None` when polled by [`Self::background`].
+    #[cfg(not(target_arch = "wasm32"))]
+    event_loop: Option<EventLoopThread>,
 
+    /// Rung when the devices need servicing, see [`crate::doorbell`].
+    doorbell: Doorbell,
     pub device_poller: DevicePoller,
 
     loader: Option<ELFLoader>,
+    /// The raw image the board booted from, ELF images are kept by `loader`.
+    binary: Option<Vec<u8>>,
+    /// The kernel command line written to RAM at boot.
+    bootargs: Option<String>,
 
     pub cpu: Pin<Box<RVCPU>>,
     pub clock: VirtualClockRef,
@@ -292,18 +1254,43 @@ pub struct VirtBoard {
     // interrupt manager.
     pub clint: Rc<RefCell<Clint>>,
     pub plic: Rc<RefCell<PLIC>>,
-    pub plic_freq_counter: usize,
+    /// Fed with the claims of the PLIC, see [`RVBoardBuilder::irq_storm`].
+    irq_storm: Option<StormDetector>,
 
     pub uart_port: UartBytePort,
-
+    /// Scans [`Self::take_uart_output`] when the UART is not on the terminal.
+    scanner: Option<SerialScanner>,
+    /// Patterns the [`SerialScanner`] found.
+    serial_matches: channel::Receiver<String>,
+
+    work_queue: WorkQueue,
+    /// Every memory-mapped device, to deliver work queue completions to.
+    devices: Vec<Rc<RefCell<dyn DeviceTrait>>>,
+
+    ram: Rc<UnsafeCell<Ram>>,
+    /// Translates the DMA of the VirtIO devices, hot-plugged ones included.
+    iommu: Option<Rc<RefCell<RiscvIommu>>>,
+    /// `None` for the free slots, see [`Board::hotplug_device`].
+    virtio_slots: Vec<Option<VirtIOSlot>>,
+    /// Where the VirtIO slots of hot-plugged devices are.
+    memory_map: MemoryMap,
+
+    hypercall: Rc<RefCell<Hypercall>>,
+    gpio: Option<Rc<RefCell<SifiveGpio>>>,
+
+    /// Reset and halt requests from devices.
+    control: BoardControl,
     status: BoardStatus,
+    /// See [`Board::take_break`].
+    break_reason: Option<String>,
 }
 
 impl VirtBoard {
     pub fn from_binary(bytes: &[u8]) -> Self {
-        let mut ram = Ram::new();
-        load_bin(&mut ram, bytes);
-        Self::from_ram(ram)
+        VirtBoardBuilder::from_config()
+            .binary(bytes.to_vec())
+            .build()
+            .unwrap_or_else(|err| panic!("{err}"))
     }
 
     pub fn from_elf(bytes: Vec<u8>) -> Self {
@@ -311,22 +1298,180 @@ impl VirtBoard {
     }
 
     pub fn try_from_elf(bytes: Vec<u8>) -> Result<Self, String> {
-        let mut ram = Ram::new();
-        let loader = ELFLoader::try_new(bytes).ok_or_else(|| "Invalid ELF file".to_string())?;
-        loader.load_to_ram(&mut ram);
-        let mut board = Self::from_ram(ram);
-        board.loader = Some(loader);
-        Ok(board)
+        VirtBoardBuilder::from_config().elf(bytes).build()
     }
 
     pub fn from_ram(ram: Ram) -> Self {
-        let builder =
-            RVBoardBuilder::new().add_virtio_devices(&mut EMULATOR_CONFIG.lock().unwrap().devices);
+        VirtBoardBuilder::from_config()
+            .ram(ram)
+            .build()
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
 
-        #[cfg(feature = "test-device")]
-        let builder = builder.add_plic_device(Rc::new(RefCell::new(TestDevice::new())));
+    fn handle_request(&mut self, request: BoardRequest) -> Result<(), Exception> {
+        match request {
+            BoardRequest::Reset => {
+                log::info!(
+                    "Reset requested: pc = {:#x}, cycle {}",
+                    self.cpu.read_pc(),
+                    self.clock.now()
+                );
+                self.status = BoardStatus::Resetting;
+            }
+            BoardRequest::Halt(reason) => {
+                log::error!(
+                    "{reason}, halting: pc = {:#x}, cycle {}",
+                    self.cpu.read_pc(),
+                    self.clock.now()
+                );
+                self.cpu.power_off()?;
+                self.status = BoardStatus::Halt;
+            }
+            BoardRequest::DumpState => {
+                let state = self.dump_state();
+                log::info!("Guest requested a state dump:\n{state}");
+                eprint!("{}\r\n", state.replace('\n', "\r\n"));
+            }
+            BoardRequest::Break(reason) => {
+                log::warn!(
+                    "{reason}, breaking: pc = {:#x}, cycle {}",
+                    self.cpu.read_pc(),
+                    self.clock.now()
+                );
+                self.break_reason = Some(reason);
+            }
+        }
+        Ok(())
+    }
 
-        builder.build(ram)
+    /// The handle devices use to reset or stop the board.
+    pub fn board_control(&self) -> BoardControl {
+        self.control.clone()
+    }
+
+    /// The last interrupt storm found, see [`RVBoardBuilder::irq_storm`].
+    pub fn last_irq_storm(&self) -> Option<Storm> {
+        self.irq_storm.as_ref().and_then(StormDetector::last)
+    }
+
+    /// Bring in what the device threads left on their queues once the doorbell was rung: deliver
+    /// the interrupts and work queue completions, let the PLIC arbitrate and serve the requests.
+    fn service_devices(&mut self) -> Result<(), Exception> {
+        // Runs the background tasks when they have no thread, a no-op otherwise.
+        self.background.poll_once();
+        self.device_poller.trigger_external_interrupt();
+        if self.work_queue.take_ready() {
+            for device in self.devices.iter() {
+                device.borrow_mut().complete_work();
+            }
+        }
+        self.plic.borrow_mut().sample_pins();
+
+        self.plic.borrow_mut().try_get_interrupt(0);
+        self.plic.borrow_mut().try_get_interrupt(1);
+        if self.irq_storm.is_some() {
+            self.check_irq_storm();
+        }
+
+        if let Ok(reason) = self.serial_matches.try_recv() {
+            self.handle_request(BoardRequest::Break(reason))?;
+            // One break at a time, the next match is served on the following instruction.
+            if !self.serial_matches.is_empty() {
+                self.doorbell.ring();
+            }
+        }
+        if let Some(request) = self.control.take() {
+            self.handle_request(request)?;
+        }
+        Ok(())
+    }
+
+    /// Give the claims since the last poll to the storm detector, each with the pc of the
+    /// last external interrupt the hart took.
+    fn check_irq_storm(&mut self) {
+        let claims = self.plic.borrow_mut().take_claims();
+        if claims.is_empty() {
+            return;
+        }
+        let Some(pc) = self
+            .cpu
+            .recent_traps()
+            .find(|trap| {
+                matches!(
+                    trap.cause,
+                    Trap::Interrupt(Interrupt::MachineExternal | Interrupt::SupervisorExternal)
+                )
+            })
+            .map(|trap| trap.pc)
+        else {
+            // Polled, not interrupted.
+            return;
+        };
+
+        let now = self.clock.now();
+        let detector = self.irq_storm.as_mut().unwrap();
+        for source in claims {
+            if let Some(storm) = detector.on_claim(source, now, pc) {
+                cold_path();
+                log::warn!("{storm}\n{}", self.plic.borrow().dump_state());
+            }
+        }
+    }
+
+    /// The pc, privilege level, general-purpose registers and trap CSRs, one per line.
+    pub fn dump_state(&mut self) -> String {
+        let mut lines = vec![format!(
+            "pc = {:#x}, {:?}, cycle {}",
+            self.cpu.read_pc(),
+            self.cpu.get_current_privilege(),
+            self.clock.now()
+        )];
+        for (i, names) in REG_NAME.chunks(4).enumerate() {
+            let regs: Vec<_> = names
+                .iter()
+                .enumerate()
+                .map(|(j, name)| {
+                    let value = self.cpu.read_reg((i * 4 + j) as u8);
+                    format!("{name:>4} = {value:#018x}")
+                })
+                .collect();
+            lines.push(regs.join("  "));
+        }
+        for (name, addr) in [
+            ("mstatus", Mstatus::get_index()),
+            ("mcause", Mcause::get_index()),
+            ("mepc", Mepc::get_index()),
+            ("mtval", Mtval::get_index()),
+            ("scause", Scause::get_index()),
+            ("sepc", Sepc::get_index()),
+            ("stval", Stval::get_index()),
+            ("satp", Satp::get_index()),
+        ] {
+            if let Some(value) = self.cpu.debug_csr(addr, None) {
+                lines.push(format!("{name:>7} = {value:#018x}"));
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Instructions retired, guest time and pc, to show that a long run is making progress.
+    pub fn progress(&mut self) -> String {
+        let instret = self
+            .cpu
+            .debug_csr(Minstret::get_index(), None)
+            .unwrap_or_default();
+        let guest_secs = self.clock.now() as f64 / (vclock::TICKS_PER_MICRO * 1_000_000) as f64;
+        format!(
+            "{} instructions, guest time {:.3}s, pc {:#x}",
+            instret,
+            guest_secs,
+            self.cpu.read_pc()
+        )
+    }
+
+    /// Every checkpoint the guest reported through the hypercall window.
+    pub fn guest_checkpoints(&self) -> Vec<Checkpoint> {
+        self.hypercall.borrow().checkpoints().to_vec()
     }
 
     pub fn push_uart_input(&mut self, bytes: &[u8]) {
@@ -336,22 +1481,80 @@ impl VirtBoard {
     pub fn take_uart_output(&mut self) -> Vec<u8> {
         let mut vec = Vec::new();
         self.uart_port.drain_to(&mut vec);
+        if let Some(scanner) = &mut self.scanner {
+            scanner.scan(&vec);
+        }
         vec
     }
+
+    /// Boot the ELF `bytes` instead of the current image: load it and reset the board.
+    pub fn load_elf(&mut self, bytes: Vec<u8>) -> Result<(), String> {
+        let elf = ELFLoader::try_new(bytes).ok_or_else(|| "Invalid ELF file".to_string())?;
+        self.loader = Some(elf);
+        self.binary = None;
+        self.reload_images();
+        self.reset();
+        Ok(())
+    }
+
+    /// Boot the raw image `bytes` instead of the current image: load it and reset the board.
+    pub fn load_binary(&mut self, bytes: Vec<u8>) {
+        self.loader = None;
+        self.binary = Some(bytes);
+        self.reload_images();
+        self.reset();
+    }
+
+    /// Map `device` at `base..base + size` of the physical address space, which must be free
+    /// and below the RAM.
+    pub fn map_device(
+        &mut self,
+        name: &str,
+        base: WordType,
+        size: WordType,
+        device: Rc<RefCell<dyn DeviceTrait>>,
+    ) -> Result<(), String> {
+        if size == 0
+            || base
+                .checked_add(size)
+                .is_none_or(|end| end > crate::ram_config::BASE_ADDR)
+        {
+            return Err(format!("{name}: {base:#x}+{size:#x} is not below the RAM"));
+        }
+        self.cpu
+            .mmio_mut()
+            .add_item(MemoryMapItem::new(name, base, size, device.clone()))
+            .map_err(|_| format!("{name}: {base:#x}+{size:#x} overlaps another device"))?;
+        if let Some(event) = device.borrow_mut().get_poll_event() {
+            self.device_poller.add_event(event);
+        }
+        self.devices.push(device);
+        Ok(())
+    }
+
+    /// The fault injection of the disk of the device in `slot`.
+    fn fault_control(&self, slot: usize) -> Result<FaultControl, HotplugError> {
+        let device = self
+            .virtio_slots
+            .get(slot)
+            .and_then(Option::as_ref)
+            .ok_or(HotplugError::EmptySlot(slot))?;
+        device
+            .device
+            .borrow()
+            .faults()
+            .ok_or(HotplugError::NoDisk(slot))
+    }
 }
 
 impl Board for VirtBoard {
     fn step(&mut self) -> Result<(), Exception> {
-        self.plic_freq_counter += 1;
-        if self.plic_freq_counter >= PLIC_FREQUENCY_DIVISION {
-            self.plic_freq_counter = 0;
-
-            // TODO: use external irq lines to trigger plic interrupts.
-            self.background.poll_once();
-            self.device_poller.trigger_external_interrupt();
-
-            self.plic.borrow_mut().try_get_interrupt(0);
-            self.plic.borrow_mut().try_get_interrupt(1);
+        if self.doorbell.take() {
+            cold_path();
+            self.service_devices()?;
+            if self.status != BoardStatus::Running {
+                return Ok(());
+            }
         }
         self.cpu.step()?;
         self.clock.advance(1);
@@ -379,6 +1582,35 @@ impl Board for VirtBoard {
         self.status
     }
 
+    /// The guest boots again from the reset vector, RAM keeps its content.
+    fn reset(&mut self) {
+        self.cpu.reset();
+        for device in self.devices.iter() {
+            device.borrow_mut().reset();
+        }
+        if let Some(detector) = self.irq_storm.as_mut() {
+            detector.reset();
+        }
+        self.status = BoardStatus::Running;
+        log::info!("Board reset at cycle {}", self.clock.now());
+    }
+
+    fn reload_images(&mut self) -> bool {
+        let ram = unsafe { self.ram.as_mut_unchecked() };
+        if let Some(loader) = &self.loader {
+            loader.load_to_ram(ram);
+        } else if let Some(bytes) = &self.binary {
+            load_bin(ram, bytes);
+        } else {
+            return false;
+        }
+        if let Some(bootargs) = &self.bootargs {
+            let _ = load_cmdline(ram, bootargs);
+        }
+        self.cpu.flush_icache();
+        true
+    }
+
     fn cpu(&self) -> &RVCPU {
         &self.cpu
     }
@@ -390,6 +1622,165 @@ impl Board for VirtBoard {
     fn loader(&self) -> Option<&crate::load::ELFLoader> {
         self.loader.as_ref()
     }
+
+    fn take_break(&mut self) -> Option<String> {
+        self.break_reason.take()
+    }
+
+    fn raise_external_interrupt(&mut self, id: ExternalInterrupt) -> bool {
+        if !PLIC::is_valid_source(id) {
+            return false;
+        }
+
+        let mut plic = self.plic.borrow_mut();
+        plic.trigger_interrupt(id);
+        // Deliver right away instead of ringing the doorbell.
+        plic.try_get_interrupt(0);
+        plic.try_get_interrupt(1);
+        true
+    }
+
+    fn hotplug_device(&mut self, cfg: &DeviceConfig) -> Result<HotplugInfo, HotplugError> {
+        let slot = self
+            .virtio_slots
+            .iter()
+            .position(Option::is_none)
+            .ok_or(HotplugError::NoFreeSlot)?;
+        let mut virtio_mmio_device =
+            create_virtio_device(&self.ram, self.iommu.as_ref(), &self.work_queue, slot, cfg)?;
+        let info = virtio_slot_info(&self.memory_map, slot);
+        let shared_memory = map_shared_memory(&mut virtio_mmio_device, slot)?;
+        let pin = connect_irq(&mut virtio_mmio_device, info.index, &self.memory_map)
+            .expect("VirtIO devices have an interrupt");
+        let irq = pin.id();
+        let device = Rc::new(RefCell::new(virtio_mmio_device));
+
+        // The device takes the place of the empty slot, which is put back if anything overlaps.
+        let empty = self.cpu.mmio_mut().remove_item(info.base);
+        let items = std::iter::once(MemoryMapItem::new(
+            info.name,
+            info.base,
+            info.size,
+            device.clone(),
+        ))
+        .chain(shared_memory);
+        let mut mapped = Vec::new();
+        for item in items {
+            let (name, base) = (item.name.clone(), item.start);
+            if self.cpu.mmio_mut().add_item(item).is_err() {
+                for base in mapped {
+                    self.cpu.mmio_mut().remove_item(base);
+                }
+                if let Some(empty) = empty {
+                    let _ = self.cpu.mmio_mut().add_item(empty);
+                }
+                return Err(HotplugError::Overlap { name, base });
+            }
+            mapped.push(base);
+        }
+        let shared_memory = mapped.split_off(1);
+
+        connect_timer(&device, &self.timer);
+        let poll_event = device
+            .borrow_mut()
+            .get_poll_event()
+            .map(|event| self.device_poller.add_event(event));
+        self.plic.borrow_mut().connect_pin(pin);
+        self.devices.push(device.clone());
+
+        device.borrow_mut().notify_config_change();
+        self.virtio_slots[slot] = Some(VirtIOSlot {
+            device,
+            irq,
+            shared_memory,
+            poll_event,
+        });
+
+        log::info!("{cfg:?} plugged into VirtIO slot {slot}");
+        Ok(HotplugInfo {
+            slot,
+            base: info.base,
+            irq,
+        })
+    }
+
+    fn unplug_device(&mut self, slot: usize) -> Result<(), HotplugError> {
+        let VirtIOSlot {
+            device,
+            irq,
+            shared_memory,
+            poll_event,
+        } = self
+            .virtio_slots
+            .get_mut(slot)
+            .and_then(Option::take)
+            .ok_or(HotplugError::EmptySlot(slot))?;
+
+        let empty = empty_virtio_slot(&self.memory_map, slot);
+        self.cpu.mmio_mut().remove_item(empty.start);
+        let _ = self.cpu.mmio_mut().add_item(empty);
+        for base in shared_memory {
+            self.cpu.mmio_mut().remove_item(base);
+        }
+        if let Some(id) = poll_event {
+            self.device_poller.remove_event(id);
+        }
+        self.plic.borrow_mut().disconnect_pin(irq);
+        self.devices
+            .retain(|d| Rc::as_ptr(d) as *const () != Rc::as_ptr(&device) as *const ());
+        device.borrow_mut().sync();
+
+        log::info!("VirtIO slot {slot} unplugged");
+        Ok(())
+    }
+
+    fn block_faults(&self, slot: usize) -> Result<FaultConfig, HotplugError> {
+        Ok(self.fault_control(slot)?.get())
+    }
+
+    fn set_block_faults(&mut self, slot: usize, faults: FaultConfig) -> Result<(), HotplugError> {
+        self.fault_control(slot)?.set(faults);
+        log::info!("VirtIO slot {slot}: injecting {faults:?}");
+        Ok(())
+    }
+
+    fn taint_uart(&mut self) -> bool {
+        // The receive buffer register.
+        let rbr = self.memory_map.uart.base;
+        self.cpu.enable_taint().add_mmio_source(rbr..rbr + 1);
+        true
+    }
+
+    fn taint_disk(&mut self, slot: usize, region: Range<u64>) -> Result<(), HotplugError> {
+        let device = self
+            .virtio_slots
+            .get(slot)
+            .and_then(Option::as_ref)
+            .ok_or(HotplugError::EmptySlot(slot))?;
+        let memory = self.cpu.enable_taint().memory().clone();
+        match device
+            .device
+            .borrow_mut()
+            .attach_taint(DiskTaint::new(region, memory))
+        {
+            true => Ok(()),
+            false => Err(HotplugError::NoDisk(slot)),
+        }
+    }
+
+    fn gpio_state(&self) -> Option<GpioState> {
+        self.gpio.as_ref().map(|gpio| gpio.borrow().state())
+    }
+
+    fn drive_gpio(&mut self, pin: u32, level: Option<bool>) -> bool {
+        match &self.gpio {
+            Some(gpio) => {
+                gpio.borrow_mut().drive(pin, level);
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -447,6 +1838,31 @@ mod tests {
         assert_eq!(read_timecmp, timecmp_value, "mtimecmp write/read mismatch");
     }
 
+    #[test]
+    fn test_device_access_widths() {
+        use crate::device::{
+            MemError,
+            config::{PLIC_BASE, UART_BASE},
+        };
+
+        let mut board = create_test_board();
+        let mmio = board.cpu.mmio_mut();
+        assert!(mmio.read_by_type::<u32>(PLIC_BASE + 4).is_ok());
+        assert_eq!(
+            mmio.read_by_type::<u8>(PLIC_BASE + 4),
+            Err(MemError::LoadFault)
+        );
+        assert_eq!(
+            mmio.write_by_type::<u64>(PLIC_BASE + 8, 0),
+            Err(MemError::StoreFault)
+        );
+        assert!(mmio.read_by_type::<u8>(UART_BASE + 5).is_ok());
+        assert_eq!(
+            mmio.write_by_type::<u32>(UART_BASE, 0),
+            Err(MemError::StoreFault)
+        );
+    }
+
     #[test]
     fn test_clint_timer_interrupt() {
         let mut board = create_test_board();
@@ -514,6 +1930,283 @@ mod tests {
         assert_eq!(mcause, (1u64 << (XLEN - 1)) | 0b11)
     }
 
+    #[test]
+    fn test_identity() {
+        let identity = HartIdentity {
+            vendor_id: 0x489,
+            arch_id: 5,
+            imp_id: 1,
+            hart_id: 0,
+        };
+        let mut board = RVBoardBuilder::new().identity(identity).build(Ram::new());
+        assert_eq!(board.cpu.debug_csr(csr_index::mvendorid, None), Some(0x489));
+        assert_eq!(board.cpu.debug_csr(csr_index::marchid, None), Some(5));
+        assert_eq!(board.cpu.debug_csr(csr_index::mimpid, None), Some(1));
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut ram = Ram::new();
+        // addi x0, x0, 0
+        ram.insert_section(&0x0000_0013u32.to_le_bytes().repeat(8), 0);
+        let mut board = RVBoardBuilder::new().build(ram);
+        for _ in 0..5 {
+            board.step().unwrap();
+        }
+        assert_eq!(
+            board.progress(),
+            format!(
+                "5 instructions, guest time 0.000s, pc {:#x}",
+                ram_config::BASE_ADDR + 20
+            )
+        );
+    }
+
+    #[test]
+    fn test_virt_board_builder() {
+        // addi x0, x0, 0
+        let nops = 0x0000_0013u32.to_le_bytes().repeat(4);
+        let mut board = VirtBoardBuilder::new()
+            .serial(SerialDestination::Buffer)
+            .binary(nops.clone())
+            .bootargs("console=ttyS0".into())
+            .machine(|board| {
+                board.identity(HartIdentity {
+                    hart_id: 3,
+                    ..Default::default()
+                })
+            })
+            .build()
+            .unwrap();
+        assert_eq!(board.binary.as_deref(), Some(&nops[..]));
+        assert_eq!(board.cpu.debug_csr(csr_index::mhartid, None), Some(3));
+        let cmdline = ram_config::CMDLINE_ADDR - ram_config::BASE_ADDR;
+        let ram = unsafe { board.ram.as_mut_unchecked() };
+        assert_eq!(ram.read::<u8>(cmdline + 12), Ok(b'0'));
+        assert_eq!(ram.read::<u8>(cmdline + 13), Ok(0));
+        board.step().unwrap();
+        assert_eq!(board.cpu.read_pc(), ram_config::BASE_ADDR + 4);
+
+        assert!(VirtBoardBuilder::new().elf(nops).build().is_err());
+    }
+
+    #[test]
+    fn test_work_queue_completion() {
+        use std::{cell::Cell, time::Instant};
+
+        use crate::work_queue::CompletionQueue;
+
+        struct WorkDevice {
+            completions: CompletionQueue<u32>,
+            done: Rc<Cell<u32>>,
+        }
+
+        impl DeviceTrait for WorkDevice {
+            fn read(&mut self, _addr: WordType, _len: u32) -> Result<u64, device::MemError> {
+                Ok(0)
+            }
+            fn write(
+                &mut self,
+                _addr: WordType,
+                _len: u32,
+                _data: u64,
+            ) -> Result<(), device::MemError> {
+                Ok(())
+            }
+            fn sync(&mut self) {}
+            fn get_poll_event(
+                &mut self,
+            ) -> Option<Box<dyn crate::device_poller::PollingEventTrait>> {
+                None
+            }
+            fn complete_work(&mut self) {
+                for v in self.completions.drain() {
+                    self.done.set(self.done.get() + v);
+                }
+            }
+        }
+
+        impl device::MemMappedDeviceTrait for WorkDevice {
+            fn name() -> &'static str {
+                "work-device"
+            }
+            fn base() -> WordType {
+                0x10_2000
+            }
+            fn size() -> WordType {
+                0x10
+            }
+        }
+
+        let builder = RVBoardBuilder::new();
+        let (submitter, completions) = builder.work_queue().channel();
+        let done = Rc::new(Cell::new(0));
+        let device = WorkDevice {
+            completions,
+            done: done.clone(),
+        };
+        let mut board = builder
+            .add_plic_device(Rc::new(RefCell::new(device)))
+            .build(Ram::new());
+
+        submitter.submit(|| 42);
+        let start = Instant::now();
+        while done.get() == 0 {
+            assert!(start.elapsed().as_secs() < 5, "completion never delivered");
+            let _ = board.step();
+        }
+        assert_eq!(done.get(), 42);
+    }
+
+    #[test]
+    fn test_virtio_hotplug() {
+        use crate::device::virtio::{config::VIRT_MAGIC, virtio_blk::init_block_file};
+        use crate::isa::riscv::debugger::Address;
+
+        let file_name = "./tmp/test_virtio_hotplug.img";
+        init_block_file(file_name, 1, |_| &[0u8; 512]);
+        let cfg: DeviceConfig = format!("virtio-block:{file_name}").parse().unwrap();
+
+        let mut board = RVBoardBuilder::new().build(Ram::new());
+        let info = board.hotplug_device(&cfg).unwrap();
+        assert_eq!(info.slot, 0);
+        assert_eq!(
+            board.cpu.read_memory::<u32>(Address::Phys(info.base)),
+            Ok(VIRT_MAGIC)
+        );
+        // Configuration change interrupt.
+        let pending = |board: &VirtBoard| board.plic.borrow_mut().read_u32(0x1000).unwrap();
+        board.plic.borrow_mut().sample_pins();
+        assert_ne!(pending(&board) & (1 << info.irq), 0);
+
+        assert_eq!(board.hotplug_device(&cfg).unwrap().slot, 1);
+        board.unplug_device(0).unwrap();
+        assert_eq!(
+            board.cpu.read_memory::<u32>(Address::Phys(info.base + 8)),
+            Ok(0)
+        );
+        assert_eq!(pending(&board) & (1 << info.irq), 0);
+        assert!(matches!(
+            board.unplug_device(0),
+            Err(HotplugError::EmptySlot(0))
+        ));
+        // The freed slot is reused.
+        assert_eq!(board.hotplug_device(&cfg).unwrap(), info);
+
+        let read_only: DeviceConfig = format!("virtio-block:{file_name}:ro").parse().unwrap();
+        assert!(read_only.read_only);
+        assert_eq!(board.hotplug_device(&read_only).unwrap().slot, 2);
+        assert!(
+            format!("virtio-block:{file_name}:rw")
+                .parse::<DeviceConfig>()
+                .is_err()
+        );
+
+        let missing: DeviceConfig = "virtio-block:./tmp/no-such-disk.img".parse().unwrap();
+        assert!(matches!(
+            board.hotplug_device(&missing),
+            Err(HotplugError::Backend { .. })
+        ));
+
+        // A device overlapping the window of the free slot makes hot-plug fail cleanly.
+        let mut blocker = empty_virtio_slot(&board.memory_map, 3);
+        blocker.start = virtio_slot_info(&board.memory_map, 3).base + 0x100;
+        blocker.size = 0x100;
+        let empty = board.cpu.mmio_mut().remove_item(blocker.start - 0x100);
+        assert!(board.cpu.mmio_mut().add_item(blocker).is_ok());
+        assert!(board.cpu.mmio_mut().add_item(empty.unwrap()).is_err());
+        assert!(matches!(
+            board.hotplug_device(&cfg),
+            Err(HotplugError::Overlap { .. })
+        ));
+        assert!(board.virtio_slots[3].is_none());
+    }
+
+    #[test]
+    fn test_raise_external_interrupt() {
+        const IRQ: ExternalInterrupt = 5;
+        let mut board = create_test_board();
+        board.cpu.debug_csr(csr_index::mie, Some(1 << 11)); // enable MEIE
+
+        {
+            let mut plic = board.plic.borrow_mut();
+            // source priority
+            plic.write_u32(IRQ as WordType * 4, 1).unwrap();
+            // context 0 enable
+            plic.write_u32(0x2000, 1 << IRQ).unwrap();
+        }
+
+        assert!(!board.raise_external_interrupt(0));
+        assert!(board.raise_external_interrupt(IRQ));
+
+        let meip = 1 << 11;
+        assert_eq!(
+            board.cpu.debug_csr(csr_index::mip, None).unwrap() & meip,
+            meip
+        );
+
+        // claim
+        let claimed_id = board.plic.borrow_mut().read_u32(0x200004).unwrap();
+        assert_eq!(claimed_id, IRQ);
+    }
+
+    #[test]
+    fn test_irq_storm() {
+        const IRQ: ExternalInterrupt = 5;
+        // Takes the interrupt in `loop`, claims and completes it at the PLIC and returns.
+        let program: Vec<u8> = [
+            0x00000297u32, // auipc t0, 0
+            0x02028293,    // addi t0, t0, 32
+            0x30529073,    // csrw mtvec, t0
+            0x40000293,    // li t0, 0x400
+            0x40028293,    // addi t0, t0, 0x400
+            0x3042a073,    // csrs mie, t0
+            0x30046073,    // csrsi mstatus, MIE
+            0x0000006f,    // loop: j loop
+            0x0c200337,    // lui t1, 0xc200
+            0x00432383,    // lw t2, 4(t1)
+            0x00732223,    // sw t2, 4(t1)
+            0x30200073,    // mret
+        ]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+        let config = StormConfig {
+            claims: 20,
+            window: 10_000,
+        };
+        let mut board = VirtBoardBuilder::new()
+            .serial(SerialDestination::Buffer)
+            .machine(|machine| machine.irq_storm(Some(config)))
+            .binary(program)
+            .build()
+            .unwrap();
+        {
+            let mut plic = board.plic.borrow_mut();
+            plic.write_u32(IRQ as WordType * 4, 1).unwrap();
+            plic.write_u32(0x2000, 1 << IRQ).unwrap();
+        }
+
+        // The source fires now and then, the guest gets on with its work.
+        for i in 0..5000 {
+            if i % 1000 == 0 {
+                board.raise_external_interrupt(IRQ);
+            }
+            board.step().unwrap();
+        }
+        assert_eq!(board.last_irq_storm(), None);
+
+        // Never cleared, as if level-triggered and left asserted by the device.
+        for _ in 0..5000 {
+            board.raise_external_interrupt(IRQ);
+            board.step().unwrap();
+        }
+        let storm = board.last_irq_storm().unwrap();
+        assert_eq!(storm.source, IRQ);
+        assert_eq!(storm.pc, ram_config::BASE_ADDR + 0x1c);
+        assert!(storm.claims > config.claims);
+    }
+
     #[cfg(feature = "test-device")]
     #[test]
     fn test_plic() {
@@ -521,8 +2214,8 @@ mod tests {
 
         use crate::device::config::TEST_DEVICE_BASE;
         use crate::device::test_device::TEST_DEVICE_INTERRUPT_ID;
+        use crate::isa::riscv::debugger::Address;
         use crate::ram_config;
-        use crate::{config::arch_config::WordType, isa::riscv::debugger::Address};
         const PRIORITY_OFFSET: WordType = 0;
         const PENDING_BIT_OFFSET: WordType = 0x001000;
         const CONTEXT_ENABLE_BIT_OFFSET: WordType = 0x002000;
@@ -596,4 +2289,493 @@ mod tests {
         let mepc = board.cpu.debug_csr(csr_index::mepc, None).unwrap();
         assert!(mepc >= ram_config::BASE_ADDR);
     }
+
+    /// Step like [`Board::run`] does, serving the reset requests.
+    fn run_steps(board: &mut VirtBoard, steps: usize) {
+        for _ in 0..steps {
+            match board.status() {
+                BoardStatus::Running => board.step().unwrap(),
+                BoardStatus::Resetting => board.reset(),
+                BoardStatus::Halt => break,
+            }
+        }
+    }
+
+    #[test]
+    fn test_watchdog_reset() {
+        use crate::device::{
+            config::WATCHDOG_BASE,
+            watchdog::{WATCHDOG_KICK_KEY, WatchdogAction},
+        };
+        use crate::isa::riscv::debugger::Address;
+
+        let mut ram = Ram::new();
+        for i in 0..0x1000 {
+            ram.write::<u32>(4 * i, 0x13).unwrap(); // NOP
+        }
+        let mut board = RVBoardBuilder::new()
+            .watchdog(WatchdogAction::Reset)
+            .build(ram);
+        let watchdog = |offset| Address::Phys(WATCHDOG_BASE + offset);
+
+        board.cpu.write_reg(5, 123);
+        board.cpu.debug_csr(csr_index::mscratch, Some(0x55));
+        board.cpu.write_memory(watchdog(0x04), 200u32).unwrap();
+        board.cpu.write_memory(watchdog(0x00), 1u32).unwrap();
+        for _ in 0..150 {
+            board.step().unwrap();
+        }
+        board
+            .cpu
+            .write_memory(watchdog(0x08), WATCHDOG_KICK_KEY)
+            .unwrap();
+        for _ in 0..150 {
+            board.step().unwrap();
+        }
+        assert_eq!(board.cpu.read_reg(5), 123);
+
+        run_steps(&mut board, 300);
+        assert_eq!(board.status(), BoardStatus::Running);
+        assert_eq!(board.cpu.read_reg(5), 0);
+        assert_eq!(board.cpu.debug_csr(csr_index::mscratch, None), Some(0));
+        assert!(board.cpu.read_pc() < ram_config::BASE_ADDR + 4 * 300);
+        // Disabled by the reset, which it reports.
+        assert_eq!(board.cpu.read_memory::<u32>(watchdog(0x00)), Ok(0));
+        assert_eq!(board.cpu.read_memory::<u32>(watchdog(0x10)), Ok(1));
+    }
+
+    #[test]
+    fn test_dma_engine() {
+        use crate::device::config::DMA_BASE;
+        use crate::isa::riscv::debugger::Address;
+
+        let mut ram = Ram::new();
+        for i in 0..0x1000 {
+            ram.write::<u32>(4 * i, 0x13).unwrap(); // NOP
+        }
+        ram.write::<u64>(0x8000, 0x1122_3344_5566_7788).unwrap();
+        let mut board = RVBoardBuilder::new().dma(true).build(ram);
+        let dma = |offset| Address::Phys(DMA_BASE + offset);
+
+        let src = ram_config::BASE_ADDR + 0x8000;
+        board.cpu.write_memory(dma(0x00), src).unwrap();
+        board.cpu.write_memory(dma(0x08), src + 0x100).unwrap();
+        board.cpu.write_memory(dma(0x10), 8u32).unwrap();
+        board.cpu.write_memory(dma(0x14), 0b11u32).unwrap();
+        assert_eq!(board.cpu.read_memory::<u32>(dma(0x18)), Ok(0));
+
+        run_steps(&mut board, 10);
+        assert_eq!(board.cpu.read_memory::<u32>(dma(0x18)), Ok(1));
+        assert_eq!(
+            board.cpu.read_memory::<u64>(Address::Phys(src + 0x100)),
+            Ok(0x1122_3344_5566_7788)
+        );
+        let stats = board.cpu.mmio_mut().device_stats();
+        let dma_stats = stats.iter().find(|d| d.name.starts_with("dma")).unwrap();
+        assert_eq!(dma_stats.irqs_raised, 1);
+    }
+
+    #[test]
+    fn test_flash_vars() {
+        use crate::device::config::{FLASH_BASE, FLASH_SIZE};
+        use crate::isa::riscv::debugger::Address;
+
+        let path = "./tmp/test_flash_vars.img";
+        std::fs::create_dir_all("./tmp").unwrap();
+        let _ = std::fs::remove_file(path);
+
+        // The variable store is bank 1 even without a firmware bank.
+        let mut board = RVBoardBuilder::new()
+            .flash_vars(CfiFlash::create(path).unwrap())
+            .build(Ram::new());
+        let vars = Address::Phys(FLASH_BASE + FLASH_SIZE);
+        assert!(
+            board
+                .cpu
+                .read_memory::<u32>(Address::Phys(FLASH_BASE))
+                .is_err()
+        );
+        assert_eq!(board.cpu.read_memory::<u32>(vars), Ok(0xffff_ffff));
+
+        // Program a word, it lands in the image.
+        board.cpu.write_memory(vars, 0x40u32).unwrap();
+        board.cpu.write_memory(vars, 0x5641_5253u32).unwrap();
+        board.cpu.write_memory(vars, 0xffu32).unwrap();
+        assert_eq!(board.cpu.read_memory::<u32>(vars), Ok(0x5641_5253));
+        assert_eq!(std::fs::read(path).unwrap(), b"SRAV");
+    }
+
+    #[test]
+    fn test_pwm_timer() {
+        use crate::device::config::PWM_BASE;
+        use crate::isa::riscv::debugger::Address;
+
+        let mut ram = Ram::new();
+        for i in 0..0x1000 {
+            ram.write::<u32>(4 * i, 0x13).unwrap(); // NOP
+        }
+        let mut board = RVBoardBuilder::new().pwm(true).build(ram);
+        // A period of 50 cycles: sticky, zerocmp, enalways.
+        let pwm = |offset| Address::Phys(PWM_BASE + offset);
+        board.cpu.write_memory(pwm(0x20), 49u32).unwrap();
+        board
+            .cpu
+            .write_memory(pwm(0x00), (1u32 << 8) | (1 << 9) | (1 << 12))
+            .unwrap();
+
+        run_steps(&mut board, 49);
+        assert_eq!(board.cpu.read_memory::<u32>(pwm(0x00)).unwrap() >> 28, 1);
+        assert_eq!(board.cpu.read_memory::<u32>(pwm(0x08)), Ok(49));
+        run_steps(&mut board, 1);
+        assert_eq!(board.cpu.read_memory::<u32>(pwm(0x08)), Ok(0));
+    }
+
+    #[test]
+    fn test_gpio_script() {
+        use crate::device::config::GPIO_BASE;
+        use crate::isa::riscv::debugger::Address;
+
+        let mut ram = Ram::new();
+        for i in 0..0x1000 {
+            ram.write::<u32>(4 * i, 0x13).unwrap(); // NOP
+        }
+        let script: GpioScript = "10 2 high\n+10 2 low\n".parse().unwrap();
+        let mut board = RVBoardBuilder::new().gpio(script).build(ram);
+        let input_val = Address::Phys(GPIO_BASE);
+        // Enable the input and its rising edge interrupt.
+        board
+            .cpu
+            .write_memory(Address::Phys(GPIO_BASE + 0x04), 1u32 << 2)
+            .unwrap();
+        board
+            .cpu
+            .write_memory(Address::Phys(GPIO_BASE + 0x18), 1u32 << 2)
+            .unwrap();
+
+        run_steps(&mut board, 5);
+        assert_eq!(board.cpu.read_memory::<u32>(input_val), Ok(0));
+        run_steps(&mut board, 10);
+        assert_eq!(board.cpu.read_memory::<u32>(input_val), Ok(1 << 2));
+        run_steps(&mut board, 10);
+        assert_eq!(board.cpu.read_memory::<u32>(input_val), Ok(0));
+
+        let state = board.gpio_state().unwrap();
+        assert_eq!(state.pending, 1 << 2);
+        assert!(board.drive_gpio(3, Some(true)));
+    }
+
+    #[test]
+    fn test_amo_on_device() {
+        use crate::board::memory_map::MemoryMap;
+        use crate::device::config::GPIO_BASE;
+        use crate::isa::riscv::debugger::Address;
+
+        const AMOOR_W: u32 = 0x4053_a32f; // amoor.w x6, x5, (x7)
+        let output_val = GPIO_BASE + 0x0c;
+        let run = |map: MemoryMap| {
+            let mut ram = Ram::new();
+            ram.write::<u32>(0, AMOOR_W).unwrap();
+            let mut board = RVBoardBuilder::new()
+                .memory_map(map)
+                .gpio(GpioScript::default())
+                .build(ram);
+            board.cpu.write_reg(5, 0b101);
+            board.cpu.write_reg(7, output_val);
+            board
+                .cpu
+                .write_memory(Address::Phys(output_val), 0b010u32)
+                .unwrap();
+            run_steps(&mut board, 1);
+            board
+        };
+
+        // Devices take no AMOs by default.
+        let mut board = run(MemoryMap::default());
+        assert_eq!(
+            board.cpu.debug_csr(csr_index::mcause, None),
+            Some(Exception::StoreFault.into())
+        );
+
+        let map = MemoryMap::parse(&format!(
+            "[[pma]]\nbase = {GPIO_BASE}\nsize = 0x1000\namo = \"logical\""
+        ))
+        .unwrap();
+        let mut board = run(map);
+        assert_eq!(board.cpu.read_reg(6), 0b010);
+        assert_eq!(
+            board.cpu.read_memory::<u32>(Address::Phys(output_val)),
+            Ok(0b111)
+        );
+    }
+
+    #[test]
+    fn test_bus_devices() {
+        use crate::BusDeviceConfig;
+        use crate::device::config::{I2C_BASE, SPI_BASE};
+        use crate::isa::riscv::debugger::Address;
+
+        assert_eq!(
+            "lm75@0x48:temp=36.5".parse(),
+            Ok(BusDeviceConfig {
+                kind: BusDeviceKind::Lm75 {
+                    millicelsius: 36_500
+                },
+                addr: 0x48,
+            })
+        );
+        assert!("eeprom@0x50:size=1000".parse::<BusDeviceConfig>().is_err());
+        assert!("eeprom@0x80".parse::<BusDeviceConfig>().is_err());
+
+        let mut board = RVBoardBuilder::new()
+            .i2c_device(0x48, Box::new(Lm75::new(36_500)))
+            .spi_device(
+                1,
+                Box::new(SpiEeprom::new(Eeprom::new(0x100).with_image(b"rv"))),
+            )
+            .build(Ram::new());
+        let i2c = |offset| Address::Phys(I2C_BASE + offset);
+        let spi = |offset| Address::Phys(SPI_BASE + offset);
+
+        // Read the temperature: enable the core, address the sensor for reading, read 2 bytes.
+        board.cpu.write_memory(i2c(0x08), 0x80u8).unwrap();
+        board.cpu.write_memory(i2c(0x0c), 0x48u8 << 1 | 1).unwrap();
+        board.cpu.write_memory(i2c(0x10), 0x90u8).unwrap();
+        let mut temp = [0; 2];
+        for (byte, cmd) in temp.iter_mut().zip([0x20u8, 0x68]) {
+            board.cpu.write_memory(i2c(0x10), cmd).unwrap();
+            *byte = board.cpu.read_memory::<u8>(i2c(0x0c)).unwrap();
+        }
+        assert_eq!(temp, [36, 0x80]);
+
+        // Read the EEPROM on chip select 1, holding it across the instruction and the data.
+        board.cpu.write_memory(spi(0x10), 1u32).unwrap();
+        board.cpu.write_memory(spi(0x18), 2u32).unwrap();
+        let mut rx = Vec::new();
+        for byte in [0x03u32, 0, 0, 0] {
+            board.cpu.write_memory(spi(0x48), byte).unwrap();
+            rx.push(board.cpu.read_memory::<u32>(spi(0x4c)).unwrap());
+        }
+        board.cpu.write_memory(spi(0x18), 0u32).unwrap();
+        assert_eq!(rx[2..], [b'r' as u32, b'v' as u32]);
+    }
+
+    #[test]
+    fn test_syscon_reset_and_fail() {
+        use crate::device::config::POWER_MANAGER_BASE;
+        use crate::isa::riscv::debugger::Address;
+
+        let mut ram = Ram::new();
+        for i in 0..0x1000 {
+            ram.write::<u32>(4 * i, 0x13).unwrap(); // NOP
+        }
+        let mut board = RVBoardBuilder::new().build(ram);
+        let syscon = Address::Phys(POWER_MANAGER_BASE);
+
+        board.cpu.write_reg(5, 123);
+        board.cpu.write_memory(syscon, 0x7777u32).unwrap();
+        // Served before the next instruction.
+        run_steps(&mut board, 1);
+        assert_eq!(board.status(), BoardStatus::Resetting);
+        run_steps(&mut board, 1);
+        assert_eq!(board.status(), BoardStatus::Running);
+        assert_eq!(board.cpu.read_reg(5), 0);
+
+        board
+            .cpu
+            .write_memory(syscon, (3u32 << 16) | 0x3333)
+            .unwrap();
+        run_steps(&mut board, 1);
+        assert_eq!(board.status(), BoardStatus::Halt);
+    }
+
+    #[test]
+    fn test_break_on_panic_pattern() {
+        use crate::device::config::UART_BASE;
+        use crate::isa::riscv::debugger::Address;
+
+        let mut ram = Ram::new();
+        for i in 0..0x1000 {
+            ram.write::<u32>(4 * i, 0x13).unwrap(); // NOP
+        }
+        let mut board = RVBoardBuilder::new()
+            .serial_console(false)
+            .panic_patterns(vec!["Oops".to_string()])
+            .build(ram);
+
+        for byte in *b"Oops: 0000" {
+            board
+                .cpu
+                .write_memory(Address::Phys(UART_BASE), byte)
+                .unwrap();
+        }
+        assert_eq!(board.take_uart_output(), b"Oops: 0000");
+        run_steps(&mut board, 1);
+        assert_eq!(
+            board.take_break().as_deref(),
+            Some("serial output matched \"Oops\"")
+        );
+        assert_eq!(board.status(), BoardStatus::Running);
+        assert_eq!(board.take_break(), None);
+    }
+
+    #[test]
+    fn test_serial_tcp() {
+        use std::{
+            io::Read,
+            net::TcpListener,
+            time::{Duration, Instant},
+        };
+
+        use crate::device::config::UART_BASE;
+        use crate::isa::riscv::debugger::Address;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut board = RVBoardBuilder::new()
+            .serial(SerialDestination::Tcp(stream))
+            .build(Ram::new());
+
+        // uart -> client
+        for byte in *b"hi" {
+            board
+                .cpu
+                .write_memory(Address::Phys(UART_BASE), byte)
+                .unwrap();
+        }
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+
+        // client -> uart
+        client.write_all(b"x").unwrap();
+        let start = Instant::now();
+        while board
+            .cpu
+            .read_memory::<u8>(Address::Phys(UART_BASE + 5))
+            .unwrap()
+            & 1
+            == 0
+        {
+            assert!(start.elapsed() < Duration::from_secs(5), "no serial input");
+            std::thread::yield_now();
+        }
+        assert_eq!(
+            board
+                .cpu
+                .read_memory::<u8>(Address::Phys(UART_BASE))
+                .unwrap(),
+            b'x'
+        );
+    }
+
+    #[test]
+    fn test_virtio_slots() {
+        use crate::device::config::{VIRTIO_IRQ_BASE, VIRTIO_MMIO_BASE, VIRTIO_MMIO_SIZE};
+        use crate::device::virtio::{config::VIRT_MAGIC, virtio_blk::init_block_file};
+        use crate::isa::riscv::debugger::Address;
+
+        let file_name = "./tmp/test_virtio_slots.img";
+        init_block_file(file_name, 1, |_| &[0u8; 512]);
+        let cfg: DeviceConfig = format!("virtio-block:{file_name}").parse().unwrap();
+        let mut board = RVBoardBuilder::new()
+            .add_virtio_devices(&mut vec![cfg.clone(), cfg])
+            .build(Ram::new());
+
+        let slot = |n: WordType| VIRTIO_MMIO_BASE + n * VIRTIO_MMIO_SIZE;
+        for n in 0..3 {
+            assert_eq!(
+                board.cpu.read_memory::<u32>(Address::Phys(slot(n))),
+                Ok(VIRT_MAGIC)
+            );
+        }
+        // Device ID: block devices in the first two slots, nothing in the third one.
+        let device_id = |board: &mut VirtBoard, n| {
+            board
+                .cpu
+                .read_memory::<u32>(Address::Phys(slot(n) + 8))
+                .unwrap()
+        };
+        assert_eq!(device_id(&mut board, 0), 2);
+        assert_eq!(device_id(&mut board, 1), 2);
+        assert_eq!(device_id(&mut board, 2), 0);
+
+        let irqs: Vec<_> = board
+            .cpu
+            .mmio_mut()
+            .device_stats()
+            .into_iter()
+            .filter(|d| d.base >= slot(0) && d.base < slot(8))
+            .map(|d| (d.base, d.irq))
+            .collect();
+        assert_eq!(irqs.len(), 8);
+        assert!(irqs.contains(&(slot(0), Some(VIRTIO_IRQ_BASE))));
+        assert!(irqs.contains(&(slot(1), Some(VIRTIO_IRQ_BASE + 1))));
+    }
+
+    #[test]
+    fn test_memory_map() {
+        use crate::board::memory_map::MemoryMap;
+        use crate::device::config::UART_BASE;
+        use crate::isa::riscv::debugger::Address;
+
+        let map =
+            MemoryMap::parse("[uart]\nbase = 0x10010000\nirq = 12\n[clint]\nbase = 0x2100000")
+                .unwrap();
+        let mut board = RVBoardBuilder::new()
+            .memory_map(map)
+            .serial_console(false)
+            .build(Ram::new());
+
+        board
+            .cpu
+            .write_memory(Address::Phys(0x1001_0000), b'x')
+            .unwrap();
+        assert_eq!(board.take_uart_output(), b"x");
+        assert!(
+            board
+                .cpu
+                .write_memory(Address::Phys(UART_BASE), b'x')
+                .is_err()
+        );
+        assert_eq!(board.cpu.time_addr, Some(0x210_0000 + 0xbff8));
+        let stats = board.cpu.mmio_mut().device_stats();
+        let uart = stats.iter().find(|d| d.name.starts_with("uart")).unwrap();
+        assert_eq!((uart.base, uart.irq), (0x1001_0000, Some(12)));
+    }
+
+    #[test]
+    fn test_hypercall_window() {
+        use crate::device::config::HYPERCALL_BASE;
+        use crate::isa::riscv::debugger::Address;
+
+        let mut ram = Ram::new();
+        for i in 0..0x100 {
+            ram.write::<u32>(4 * i, 0x13).unwrap(); // NOP
+        }
+        let mut board = RVBoardBuilder::new().build(ram);
+
+        run_steps(&mut board, 10);
+        board
+            .cpu
+            .write_memory(Address::Phys(HYPERCALL_BASE + 0x04), 0xabu32)
+            .unwrap();
+        board
+            .cpu
+            .write_memory(Address::Phys(HYPERCALL_BASE + 0x08), 1u32)
+            .unwrap();
+        run_steps(&mut board, 1);
+
+        assert_eq!(board.status(), BoardStatus::Running);
+        assert_eq!(
+            board.guest_checkpoints(),
+            vec![Checkpoint {
+                value: 0xab,
+                cycle: 10
+            }]
+        );
+    }
 }
@@ -1,7 +1,7 @@
 use super::*;
 
 use crossbeam::channel::{self, Receiver, Sender};
-use std::collections::VecDeque;
+use std::{collections::VecDeque, io::Write};
 
 impl ByteSink for VecDeque<u8> {
     #[inline]
@@ -23,6 +23,25 @@ impl ByteSink for Vec<u8> {
     fn after_receive(&mut self, _received: bool) {}
 }
 
+/// Writes the bytes it receives to `W`, e.g. the UART output to a file.
+pub struct WriterSink<W: Write>(pub W);
+
+impl<W: Write> ByteSink for WriterSink<W> {
+    fn do_receive(&mut self, byte: u8) {
+        if let Err(e) = self.0.write_all(&[byte]) {
+            log::warn!("Failed to write serial output: {}", e);
+        }
+    }
+
+    fn before_receive(&mut self) {}
+
+    fn after_receive(&mut self, received: bool) {
+        if received {
+            let _ = self.0.flush();
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ChannelIOContext {
     pub output_sender: Sender<u8>,
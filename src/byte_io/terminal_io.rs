@@ -16,14 +16,22 @@ use std::{io::Write, time::Duration};
 pub struct TerminalIOContext {
     /// True after `Ctrl+A` has been seen, awaiting the command key.
     escape_pending: bool,
+    /// Bytes of the guest received since `before_receive`, written out at once.
+    output: Vec<u8>,
 }
 
 impl TerminalIOContext {
     pub fn new() -> Self {
         Self {
             escape_pending: false,
+            output: Vec::new(),
         }
     }
+
+    /// Whether a key is waiting for [`ByteSource::drain_to`].
+    pub fn has_input(&self) -> bool {
+        event::poll(Duration::ZERO).unwrap_or(false)
+    }
 }
 
 impl ByteSink for TerminalIOContext {
@@ -39,13 +47,20 @@ impl ByteSink for TerminalIOContext {
             byte,
             byte as char
         );
-        // do not use `print!` because we need to output the raw byte sequence.
-        std::io::stdout().write_all(&[byte]).unwrap();
+        self.output.push(byte);
     }
 
     #[inline]
     fn after_receive(&mut self, _received: bool) {
-        std::io::stdout().flush().unwrap();
+        if self.output.is_empty() {
+            return;
+        }
+        // do not use `print!` because we need to output the raw byte sequence.
+        let _console = CliCoordinator::global().lock_console();
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&self.output).unwrap();
+        stdout.flush().unwrap();
+        self.output.clear();
     }
 }
 
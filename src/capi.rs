@@ -0,0 +1,419 @@
+//! C bindings of the emulator, to drive a virt board from C/C++ test benches and cocotb-style
+//! verification flows.
+//!
+//! The declarations are in `include/riscv_emulator.h`, generated by `make gen-header`. Functions
+//! returning an `int` return 0 on success and -1 on failure, [`rvemu_last_error`] then tells
+//! what failed.
+//! An emulator must only be used from the thread which created it.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    ffi::{CString, c_char, c_int, c_void},
+    ptr,
+    rc::Rc,
+    slice,
+};
+
+use crate::{
+    Emulator,
+    board::{
+        Board, BoardStatus,
+        virt::{SerialDestination, VirtBoardBuilder},
+    },
+    config::arch_config::WordType,
+    device::{DeviceTrait, MemError},
+    device_poller::PollingEventTrait,
+    isa::{DebugTarget, riscv::debugger::Address},
+};
+
+/// Read `size` bytes at `offset` in the region, called on every guest load.
+pub type RvemuMmioRead = Option<extern "C" fn(opaque: *mut c_void, offset: u64, size: u32) -> u64>;
+/// Write the low `size` bytes of `value` at `offset` in the region, called on every guest store.
+pub type RvemuMmioWrite =
+    Option<extern "C" fn(opaque: *mut c_void, offset: u64, size: u32, value: u64)>;
+
+/// An emulator created by [`rvemu_create`].
+pub struct RvEmu {
+    emulator: Emulator,
+    last_error: CString,
+    /// UART output which did not fit in the buffer of [`rvemu_take_uart_output`].
+    uart_output: VecDeque<u8>,
+}
+
+impl RvEmu {
+    fn check(&mut self, result: Result<(), String>) -> c_int {
+        match result {
+            Ok(()) => 0,
+            Err(err) => {
+                self.last_error = CString::new(err).unwrap_or_default();
+                -1
+            }
+        }
+    }
+}
+
+/// A memory-mapped region whose accesses are forwarded to the callbacks of the C side.
+struct CallbackDevice {
+    read: RvemuMmioRead,
+    write: RvemuMmioWrite,
+    opaque: *mut c_void,
+}
+
+impl DeviceTrait for CallbackDevice {
+    fn read(&mut self, addr: WordType, len: u32) -> Result<u64, MemError> {
+        let read = self.read.ok_or(MemError::LoadFault)?;
+        Ok(read(self.opaque, addr, len))
+    }
+
+    fn write(&mut self, addr: WordType, len: u32, data: u64) -> Result<(), MemError> {
+        let write = self.write.ok_or(MemError::StoreFault)?;
+        write(self.opaque, addr, len, data);
+        Ok(())
+    }
+
+    fn sync(&mut self) {}
+
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+}
+
+/// Check that the `len` bytes at `paddr` do not wrap around the address space.
+fn check_range(paddr: u64, len: usize) -> Result<(), String> {
+    match paddr.checked_add(len as u64) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "{paddr:#x}+{len:#x} wraps around the address space"
+        )),
+    }
+}
+
+/// # Safety
+/// `data` must point to `len` readable bytes, or be null with `len` 0.
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    match data.is_null() {
+        true => &[],
+        false => unsafe { slice::from_raw_parts(data, len) },
+    }
+}
+
+/// Create a virt board without an image, its UART output is kept for
+/// [`rvemu_take_uart_output`]. Returns null if the board cannot be built.
+#[unsafe(no_mangle)]
+pub extern "C" fn rvemu_create() -> *mut RvEmu {
+    match VirtBoardBuilder::new()
+        .serial(SerialDestination::Buffer)
+        .build()
+    {
+        Ok(board) => Box::into_raw(Box::new(RvEmu {
+            emulator: Emulator::from_board(board),
+            last_error: CString::default(),
+            uart_output: VecDeque::new(),
+        })),
+        Err(err) => {
+            log::error!("{err}");
+            ptr::null_mut()
+        }
+    }
+}
+
+/// # Safety
+/// `emu` must come from [`rvemu_create`] and not be used afterwards, null is ignored.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_destroy(emu: *mut RvEmu) {
+    if !emu.is_null() {
+        drop(unsafe { Box::from_raw(emu) });
+    }
+}
+
+/// The last failure of `emu`, valid until the next failing call.
+///
+/// # Safety
+/// `emu` must come from [`rvemu_create`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_last_error(emu: *const RvEmu) -> *const c_char {
+    unsafe { (*emu).last_error.as_ptr() }
+}
+
+/// Load the ELF image `data` and reset the board to run it.
+///
+/// # Safety
+/// `emu` must come from [`rvemu_create`], `data` must point to `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_load_elf(emu: *mut RvEmu, data: *const u8, len: usize) -> c_int {
+    let emu = unsafe { &mut *emu };
+    let image = unsafe { bytes(data, len) }.to_vec();
+    let result = emu.emulator.board_mut().load_elf(image);
+    emu.check(result)
+}
+
+/// Load the raw image `data` at the start of the RAM and reset the board to run it.
+///
+/// # Safety
+/// `emu` must come from [`rvemu_create`], `data` must point to `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_load_binary(emu: *mut RvEmu, data: *const u8, len: usize) -> c_int {
+    let emu = unsafe { &mut *emu };
+    let image = unsafe { bytes(data, len) }.to_vec();
+    emu.emulator.board_mut().load_binary(image);
+    0
+}
+
+/// Run up to `steps` instructions, stopping early when the board halts. The number of steps
+/// run is stored in `executed` unless it is null.
+///
+/// # Safety
+/// `emu` must come from [`rvemu_create`], `executed` must be null or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_step(emu: *mut RvEmu, steps: u64, executed: *mut u64) -> c_int {
+    let emu = unsafe { &mut *emu };
+    let result = emu.emulator.run_steps(steps);
+    if let (Ok(done), false) = (&result, executed.is_null()) {
+        unsafe { *executed = *done };
+    }
+    emu.check(result.map(|_| ()).map_err(|err| format!("{err:?}")))
+}
+
+/// # Safety
+/// `emu` must come from [`rvemu_create`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_is_halted(emu: *const RvEmu) -> bool {
+    unsafe { (*emu).emulator.board().status() == BoardStatus::Halt }
+}
+
+/// # Safety
+/// `emu` must come from [`rvemu_create`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_read_pc(emu: *const RvEmu) -> u64 {
+    unsafe { (*emu).emulator.board().cpu().read_pc() }
+}
+
+/// # Safety
+/// `emu` must come from [`rvemu_create`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_write_pc(emu: *mut RvEmu, pc: u64) {
+    unsafe { (*emu).emulator.board_mut().cpu_mut().write_pc(pc) }
+}
+
+/// Read the integer register `x<idx>` into `value`.
+///
+/// # Safety
+/// `emu` must come from [`rvemu_create`], `value` must be writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_read_reg(emu: *mut RvEmu, idx: u32, value: *mut u64) -> c_int {
+    let emu = unsafe { &mut *emu };
+    if idx >= 32 {
+        return emu.check(Err(format!("x{idx}: no such register")));
+    }
+    unsafe { *value = emu.emulator.board().cpu().read_reg(idx as u8) };
+    0
+}
+
+/// Write `value` to the integer register `x<idx>`, writes to `x0` are ignored.
+///
+/// # Safety
+/// `emu` must come from [`rvemu_create`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_write_reg(emu: *mut RvEmu, idx: u32, value: u64) -> c_int {
+    let emu = unsafe { &mut *emu };
+    if idx >= 32 {
+        return emu.check(Err(format!("x{idx}: no such register")));
+    }
+    let cpu = emu.emulator.board_mut().cpu_mut();
+    cpu.write_reg(idx as u8, value);
+    0
+}
+
+/// Copy `len` bytes of physical memory at `paddr` into `buf`, one byte access at a time.
+///
+/// # Safety
+/// `emu` must come from [`rvemu_create`], `buf` must point to `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_read_mem(
+    emu: *mut RvEmu,
+    paddr: u64,
+    buf: *mut u8,
+    len: usize,
+) -> c_int {
+    let emu = unsafe { &mut *emu };
+    if let Err(err) = check_range(paddr, len) {
+        return emu.check(Err(err));
+    }
+    let cpu = emu.emulator.board_mut().cpu_mut();
+    for i in 0..len {
+        let addr = paddr + i as u64;
+        match cpu.read_memory::<u8>(Address::Phys(addr)) {
+            Ok(byte) => unsafe { *buf.add(i) = byte },
+            Err(err) => return emu.check(Err(format!("{addr:#x}: {err:?}"))),
+        }
+    }
+    0
+}
+
+/// Copy the `len` bytes of `data` to physical memory at `paddr`, one byte access at a time.
+///
+/// # Safety
+/// `emu` must come from [`rvemu_create`], `data` must point to `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_write_mem(
+    emu: *mut RvEmu,
+    paddr: u64,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    let emu = unsafe { &mut *emu };
+    if let Err(err) = check_range(paddr, len) {
+        return emu.check(Err(err));
+    }
+    let cpu = emu.emulator.board_mut().cpu_mut();
+    for (i, &byte) in unsafe { bytes(data, len) }.iter().enumerate() {
+        let addr = paddr + i as u64;
+        if let Err(err) = cpu.write_memory::<u8>(Address::Phys(addr), byte) {
+            return emu.check(Err(format!("{addr:#x}: {err:?}")));
+        }
+    }
+    cpu.flush_icache();
+    0
+}
+
+/// Map `size` bytes at `base` to the callbacks, which get `opaque` back. A null callback makes
+/// the accesses of that kind fault. The region must be free and below the RAM.
+///
+/// # Safety
+/// `emu` must come from [`rvemu_create`], `name` must be a NUL-terminated string and `opaque`
+/// must stay valid for as long as `emu`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_register_mmio(
+    emu: *mut RvEmu,
+    name: *const c_char,
+    base: u64,
+    size: u64,
+    read: RvemuMmioRead,
+    write: RvemuMmioWrite,
+    opaque: *mut c_void,
+) -> c_int {
+    let emu = unsafe { &mut *emu };
+    let name = match name.is_null() {
+        true => "mmio".into(),
+        false => unsafe { std::ffi::CStr::from_ptr(name) }.to_string_lossy(),
+    };
+    let device = Rc::new(RefCell::new(CallbackDevice {
+        read,
+        write,
+        opaque,
+    }));
+    let result =
+        emu.emulator
+            .board_mut()
+            .map_device(&name, base as WordType, size as WordType, device);
+    emu.check(result)
+}
+
+/// Send the `len` bytes of `data` to the UART.
+///
+/// # Safety
+/// `emu` must come from [`rvemu_create`], `data` must point to `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_push_uart_input(emu: *mut RvEmu, data: *const u8, len: usize) {
+    let emu = unsafe { &mut *emu };
+    emu.emulator
+        .push_uart_input_bytes(unsafe { bytes(data, len) });
+}
+
+/// Move up to `capacity` bytes of UART output into `buf`, returns how many were moved. Output
+/// which does not fit stays queued for the next call.
+///
+/// # Safety
+/// `emu` must come from [`rvemu_create`], `buf` must point to `capacity` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rvemu_take_uart_output(
+    emu: *mut RvEmu,
+    buf: *mut u8,
+    capacity: usize,
+) -> usize {
+    let emu = unsafe { &mut *emu };
+    let output = emu.emulator.take_uart_output_bytes();
+    emu.uart_output.extend(output);
+    let len = emu.uart_output.len().min(capacity);
+    for (i, byte) in emu.uart_output.drain(..len).enumerate() {
+        unsafe { buf.add(i).write(byte) };
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn read_register(_opaque: *mut c_void, offset: u64, _size: u32) -> u64 {
+        0x1234_0000 + offset
+    }
+
+    extern "C" fn record_write(opaque: *mut c_void, offset: u64, size: u32, value: u64) {
+        let writes = unsafe { &mut *(opaque as *mut Vec<(u64, u32, u64)>) };
+        writes.push((offset, size, value));
+    }
+
+    #[test]
+    fn test_capi() {
+        let program: Vec<u8> = [
+            0x2000_02b7u32, // lui t0, 0x20000
+            0x0042_a303,    // lw t1, 4(t0)
+            0x0062_a423,    // sw t1, 8(t0)
+        ]
+        .iter()
+        .flat_map(|instr| instr.to_le_bytes())
+        .collect();
+        let mut writes: Vec<(u64, u32, u64)> = Vec::new();
+
+        unsafe {
+            let emu = rvemu_create();
+            assert!(!emu.is_null());
+            assert_eq!(rvemu_load_binary(emu, program.as_ptr(), program.len()), 0);
+            let opaque = &mut writes as *mut _ as *mut c_void;
+            let name = c"bench".as_ptr();
+            let (read, write): (RvemuMmioRead, RvemuMmioWrite) =
+                (Some(read_register), Some(record_write));
+            assert_eq!(
+                rvemu_register_mmio(emu, name, 0x2000_0000, 0x1000, read, write, opaque),
+                0
+            );
+            // Overlaps the first one.
+            assert_eq!(
+                rvemu_register_mmio(emu, name, 0x2000_0800, 0x1000, read, write, opaque),
+                -1
+            );
+
+            let mut executed = 0;
+            assert_eq!(rvemu_step(emu, 3, &mut executed), 0);
+            assert_eq!(executed, 3);
+            let mut t1 = 0;
+            assert_eq!(rvemu_read_reg(emu, 6, &mut t1), 0);
+            assert_eq!(t1, 0x1234_0004);
+            assert_eq!(rvemu_read_reg(emu, 32, &mut t1), -1);
+
+            let mut word = [0u8; 4];
+            let ram = crate::ram_config::BASE_ADDR;
+            assert_eq!(rvemu_read_mem(emu, ram + 4, word.as_mut_ptr(), 4), 0);
+            assert_eq!(u32::from_le_bytes(word), 0x0042_a303);
+            assert_eq!(rvemu_read_pc(emu), ram + 12);
+            assert_eq!(rvemu_read_mem(emu, u64::MAX, word.as_mut_ptr(), 4), -1);
+            assert_eq!(rvemu_write_mem(emu, u64::MAX - 1, word.as_ptr(), 4), -1);
+
+            // Output beyond the buffer is kept for the next call.
+            let uart = crate::device::config::UART_BASE;
+            for byte in b"hi" {
+                assert_eq!(rvemu_write_mem(emu, uart, byte, 1), 0);
+            }
+            let mut out = [0u8; 1];
+            assert_eq!(rvemu_take_uart_output(emu, out.as_mut_ptr(), 1), 1);
+            assert_eq!(out, *b"h");
+            assert_eq!(rvemu_take_uart_output(emu, out.as_mut_ptr(), 1), 1);
+            assert_eq!(out, *b"i");
+            assert_eq!(rvemu_take_uart_output(emu, out.as_mut_ptr(), 1), 0);
+            rvemu_destroy(emu);
+        }
+        assert_eq!(writes, [(8, 4, 0x1234_0004)]);
+    }
+}
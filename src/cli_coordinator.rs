@@ -86,7 +86,7 @@ mod imp {
     }
 }
 
-#[cfg(not(feature = "multithreading"))]
+#[cfg(not(all(feature = "native-cli", feature = "multithreading")))]
 mod imp {
     #[derive(Clone)]
     pub struct CliCoordinator;
@@ -109,3 +109,15 @@ mod imp {
 }
 
 pub use imp::CliCoordinator;
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+static CONSOLE: Mutex<()> = Mutex::new(());
+
+impl CliCoordinator {
+    /// Hold the console while writing to stdout or stderr, so that the guest serial output and
+    /// the log messages shown on the terminal never interleave.
+    pub fn lock_console(&self) -> MutexGuard<'static, ()> {
+        CONSOLE.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
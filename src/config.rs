@@ -11,6 +11,11 @@ pub mod ram_config {
 
     #[cfg(not(target_arch = "wasm32"))]
     pub const SIZE: usize = 0x80_000_000;
+
+    /// The last page of RAM holds the kernel command line of `--append`, NUL-terminated, for
+    /// loaders which do not read it from the device tree.
+    pub const CMDLINE_SIZE: usize = 0x1000;
+    pub const CMDLINE_ADDR: WordType = BASE_ADDR + (SIZE - CMDLINE_SIZE) as WordType;
 }
 
 pub mod arch_config {
@@ -23,21 +28,16 @@ pub mod arch_config {
         RISCV64,
     }
 
-    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+    /// Byte order of data accesses. It is not fixed per build: harts come out of reset
+    /// little-endian and `mstatus.MBE`/`SBE`/`UBE` switch the byte order of each privilege
+    /// level. Instruction fetches are always little-endian.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
     pub enum Endianness {
+        #[default]
         Little,
         Big,
     }
 
-    macro_rules! mem_order_list {
-        ($endian: path) => {
-            match $endian {
-                Endianness::Little => [0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7],
-                Endianness::Big => [0x7, 0x6, 0x5, 0x4, 0x3, 0x2, 0x1, 0x0],
-            }
-        };
-    }
-
     macro_rules! arch_config {
         (
             $(
@@ -46,7 +46,6 @@ pub mod arch_config {
                     arch: $arch:path,
                     word: $word:ty,
                     signed_word: $signed_word:ty,
-                    endian: $endian:path,
                     reg_name: $reg_name: expr,
                     float_reg_name: $float_reg_name: expr,
                     vector_reg_name: $vector_reg_name: expr,
@@ -63,12 +62,6 @@ pub mod arch_config {
                 #[cfg(feature = $feature)]
                 pub type SignedWordType = $signed_word;
 
-                #[cfg(feature = $feature)]
-                pub const MEM_ORDER: $crate::config::arch_config::Endianness = $endian;
-
-                #[cfg(feature = $feature)]
-                pub const MEM_ORDER_LIST: [usize; 8] = mem_order_list!($endian);
-
                 #[cfg(feature = $feature)]
                 pub const REGFILE_CNT: usize = $reg_name.len();
 
@@ -96,7 +89,6 @@ pub mod arch_config {
             arch: Arch::RISCV32,
             word: u32,
             signed_word: i32,
-            endian: Endianness::Little,
             reg_name: gen_reg_name_list!(   "zero";     "ra";           "sp";       "gp";
                                             "tp";       "t", 0, 2;      "s0/fp";    "s1";
                                             "a", 0, 7;  "s", 2, 11;     "t", 3, 6),
@@ -107,7 +99,6 @@ pub mod arch_config {
             arch: Arch::RISCV64,
             word: u64,
             signed_word: i64,
-            endian: Endianness::Little,
             reg_name: gen_reg_name_list!(   "zero";     "ra";   "sp";   "gp";
                                             "tp";       "t", 0, 2;      "s0/fp";    "s1";
                                             "a", 0, 7;  "s", 2, 11;     "t", 3, 6),
@@ -1,7 +1,4 @@
-use std::{
-    fmt::Debug,
-    ops::{Index, IndexMut},
-};
+use std::{fmt::Debug, ops::Index};
 
 use crate::config::arch_config::{REG_NAME, REGFILE_CNT, WordType};
 
@@ -17,12 +14,6 @@ impl Index<usize> for RegFile {
     }
 }
 
-impl IndexMut<usize> for RegFile {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.data[index]
-    }
-}
-
 impl Debug for RegFile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let byte_len = size_of::<WordType>();
@@ -64,12 +55,18 @@ impl RegFile {
         }
     }
 
+    pub fn as_slice(&self) -> &[WordType] {
+        &self.data
+    }
+
     pub fn read(&self, id1: u8, id2: u8) -> (WordType, WordType) {
         (self.data[id1 as usize], self.data[id2 as usize])
     }
 
     #[cfg(any(feature = "riscv64", feature = "riscv32"))]
     /// id == 0 will be ignored, if an instruction do not need to WriteBack, set id = 0.
+    ///
+    /// This is the only way to change a register, so `x0` always reads as zero.
     pub fn write(&mut self, id: u8, data: WordType) {
         if id == 0u8 {
             return;
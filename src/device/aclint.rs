@@ -4,8 +4,8 @@ use crate::{
     board::virt::{IRQLine, RiscvIRQSource},
     config::arch_config::WordType,
     device::{
-        DeviceTrait, MemError, MemMappedDeviceTrait,
-        config::{CLINT_BASE, CLINT_SIZE},
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{CLINT_BASE, CLINT_NAME, CLINT_SIZE},
     },
     utils::{concat_to_u64, negative_of},
     vclock::{Timer, VirtualClockRef},
@@ -202,15 +202,35 @@ impl DeviceTrait for Clint {
     fn get_poll_event(&mut self) -> Option<Box<dyn crate::device_poller::PollingEventTrait>> {
         None
     }
+
+    fn reset(&mut self) {
+        self.time_offset = negative_of(self.clock.now());
+        self.msip.fill(0);
+        self.time_cmp.fill(0);
+        // As after power-on, the timer interrupt stays low until `mtimecmp` is written.
+        if let Some(irq) = &mut self.timer_irq_line {
+            irq.set_irq(false);
+            unsafe { self.timer.as_mut_unchecked() }.set_due(self.timer_cb_id, u64::MAX);
+        }
+        if let Some(irq) = &mut self.software_irq_line {
+            irq.set_irq(false);
+        }
+    }
 }
 
 impl MemMappedDeviceTrait for Clint {
+    fn name() -> &'static str {
+        CLINT_NAME
+    }
     fn base() -> WordType {
         CLINT_BASE
     }
     fn size() -> WordType {
         CLINT_SIZE
     }
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[4, 8])
+    }
 }
 
 #[cfg(test)]
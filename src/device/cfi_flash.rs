@@ -0,0 +1,406 @@
+//! CFI NOR flash with the Intel/Sharp command set (as `pflash_cfi01` in QEMU), mapped at the
+//! virt flash range as 32-bit wide chips: bank 0 holds the firmware, bank 1 the variable store of
+//! UEFI firmware, like `pflash0` and `pflash1` of QEMU.
+//!
+//! The whole flash is kept in memory, programs and erases are written through to the backing
+//! file. An image smaller than the flash reads as erased (`0xff`) past its end, and grows as the
+//! guest programs it.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    config::arch_config::WordType,
+    device::{
+        DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{FLASH_BASE, FLASH_NAME, FLASH_SECTOR_SIZE, FLASH_SIZE},
+    },
+    device_poller::PollingEventTrait,
+};
+
+/// Bytes per bus cycle, the CFI query and ID registers are one per bank-width word.
+const BANK_WIDTH: WordType = 4;
+
+/// Intel, 32 MiB.
+const MANUFACTURER_ID: u8 = 0x89;
+const DEVICE_ID: u8 = 0x18;
+
+const CMD_READ_ARRAY: u8 = 0xff;
+const CMD_READ_ID: u8 = 0x90;
+const CMD_READ_QUERY: u8 = 0x98;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+const CMD_PROGRAM: u8 = 0x40;
+const CMD_PROGRAM_ALT: u8 = 0x10;
+const CMD_BUFFERED_PROGRAM: u8 = 0xe8;
+const CMD_BLOCK_ERASE: u8 = 0x20;
+const CMD_LOCK_SETUP: u8 = 0x60;
+const CMD_CONFIRM: u8 = 0xd0;
+
+const STATUS_READY: u8 = 0x80;
+const STATUS_ERASE_ERROR: u8 = 0x20;
+const STATUS_PROGRAM_ERROR: u8 = 0x10;
+const STATUS_LOCKED: u8 = 0x02;
+
+/// Words the write buffer holds, as advertised in the query table.
+const WRITE_BUFFER_WORDS: usize = 16;
+
+/// The CFI query table, indexed by word address.
+fn query(index: WordType) -> u8 {
+    let blocks = FLASH_SIZE / FLASH_SECTOR_SIZE - 1;
+    let block_size = FLASH_SECTOR_SIZE / 256;
+    match index {
+        0x10 => b'Q',
+        0x11 => b'R',
+        0x12 => b'Y',
+        // Intel/Sharp extended command set, primary extended table at 0x31.
+        0x13 => 0x01,
+        0x15 => 0x31,
+        // Vcc and Vpp ranges.
+        0x1b => 0x45,
+        0x1c => 0x55,
+        // Typical and maximum timeouts, as powers of two.
+        0x1f => 0x07,
+        0x20 => 0x07,
+        0x21 => 0x0a,
+        0x23 => 0x04,
+        0x24 => 0x04,
+        0x25 => 0x04,
+        0x27 => FLASH_SIZE.trailing_zeros() as u8,
+        // x32 interface.
+        0x28 => 0x03,
+        0x2a => (WRITE_BUFFER_WORDS * BANK_WIDTH as usize).trailing_zeros() as u8,
+        // One erase region of uniform blocks.
+        0x2c => 0x01,
+        0x2d => blocks as u8,
+        0x2e => (blocks >> 8) as u8,
+        0x2f => block_size as u8,
+        0x30 => (block_size >> 8) as u8,
+        0x31 => b'P',
+        0x32 => b'R',
+        0x33 => b'I',
+        0x34 => b'1',
+        0x35 => b'0',
+        _ => 0,
+    }
+}
+
+enum Mode {
+    ReadArray,
+    ReadStatus,
+    ReadId,
+    Query,
+    /// The next write is programmed.
+    Program,
+    /// Waiting for the confirm command of a block erase.
+    Erase,
+    /// Waiting for the word count of a buffered program.
+    BufferCount,
+    BufferData {
+        words_left: usize,
+        writes: Vec<(WordType, Vec<u8>)>,
+    },
+    /// Waiting for the confirm command of a buffered program.
+    BufferConfirm {
+        writes: Vec<(WordType, Vec<u8>)>,
+    },
+    LockSetup,
+}
+
+pub struct CfiFlash {
+    file: File,
+    file_len: u64,
+    read_only: bool,
+
+    data: Vec<u8>,
+    mode: Mode,
+    status: u8,
+}
+
+impl CfiFlash {
+    pub fn open(path: impl AsRef<Path>, read_only: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        Self::from_file(file, read_only)
+    }
+
+    /// Open the image at `path` for writing, an empty one is created when it does not exist, so
+    /// a variable store starts out erased.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Self::from_file(file, false)
+    }
+
+    fn from_file(mut file: File, read_only: bool) -> io::Result<Self> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        if file_len > FLASH_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("flash image is larger than the flash ({FLASH_SIZE:#x} bytes)"),
+            ));
+        }
+
+        let mut data = vec![0xff; FLASH_SIZE as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut data[..file_len as usize])?;
+
+        Ok(Self {
+            file,
+            file_len,
+            read_only,
+            data,
+            mode: Mode::ReadArray,
+            status: STATUS_READY,
+        })
+    }
+
+    /// Write `data[offset..offset + len]` back to the file, growing it if needed.
+    fn persist(&mut self, offset: WordType, len: usize) -> io::Result<()> {
+        let start = offset.min(self.file_len);
+        let end = offset + len as u64;
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file
+            .write_all(&self.data[start as usize..end as usize])?;
+        self.file.flush()?;
+        self.file_len = self.file_len.max(end);
+        Ok(())
+    }
+
+    /// NOR programming can only clear bits.
+    fn program(&mut self, offset: WordType, bytes: &[u8]) {
+        if self.read_only {
+            self.status |= STATUS_PROGRAM_ERROR | STATUS_LOCKED;
+            return;
+        }
+        let start = offset as usize;
+        for (dst, src) in self.data[start..start + bytes.len()].iter_mut().zip(bytes) {
+            *dst &= src;
+        }
+        if let Err(err) = self.persist(offset, bytes.len()) {
+            log::warn!("flash: failed to write {offset:#x}: {err}");
+            self.status |= STATUS_PROGRAM_ERROR;
+        }
+    }
+
+    fn erase(&mut self, offset: WordType) {
+        if self.read_only {
+            self.status |= STATUS_ERASE_ERROR | STATUS_LOCKED;
+            return;
+        }
+        let start = offset & !(FLASH_SECTOR_SIZE - 1);
+        self.data[start as usize..(start + FLASH_SECTOR_SIZE) as usize].fill(0xff);
+        if let Err(err) = self.persist(start, FLASH_SECTOR_SIZE as usize) {
+            log::warn!("flash: failed to erase {start:#x}: {err}");
+            self.status |= STATUS_ERASE_ERROR;
+        }
+    }
+
+    fn command(&mut self, offset: WordType, cmd: u8) {
+        self.mode = match cmd {
+            CMD_READ_ARRAY => Mode::ReadArray,
+            CMD_READ_ID => Mode::ReadId,
+            CMD_READ_QUERY => Mode::Query,
+            CMD_READ_STATUS => Mode::ReadStatus,
+            CMD_CLEAR_STATUS => {
+                self.status = STATUS_READY;
+                Mode::ReadArray
+            }
+            CMD_PROGRAM | CMD_PROGRAM_ALT => Mode::Program,
+            CMD_BUFFERED_PROGRAM => Mode::BufferCount,
+            CMD_BLOCK_ERASE => Mode::Erase,
+            CMD_LOCK_SETUP => Mode::LockSetup,
+            _ => {
+                log::debug!("flash: unsupported command {cmd:#x} at {offset:#x}");
+                Mode::ReadArray
+            }
+        };
+    }
+
+    fn read_impl<T>(&mut self, addr: WordType) -> Result<T, MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let len = size_of::<T>();
+        if addr + len as WordType > FLASH_SIZE {
+            return Err(MemError::LoadFault);
+        }
+
+        let value = match self.mode {
+            Mode::ReadArray => {
+                let mut bytes = [0u8; 8];
+                bytes[..len].copy_from_slice(&self.data[addr as usize..addr as usize + len]);
+                return Ok(T::truncate_from(u64::from_le_bytes(bytes)));
+            }
+            Mode::ReadId => match (addr / BANK_WIDTH) & (FLASH_SECTOR_SIZE / BANK_WIDTH - 1) {
+                0 => MANUFACTURER_ID,
+                1 => DEVICE_ID,
+                // Block lock status, blocks are never locked.
+                _ => 0,
+            },
+            Mode::Query => query(addr / BANK_WIDTH),
+            _ => self.status,
+        };
+        Ok(T::from(value))
+    }
+
+    fn write_impl<T>(&mut self, addr: WordType, data: T) -> Result<(), MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let len = size_of::<T>();
+        if addr + len as WordType > FLASH_SIZE {
+            return Err(MemError::StoreFault);
+        }
+
+        let data: u64 = data.into();
+        let cmd = data as u8;
+        match std::mem::replace(&mut self.mode, Mode::ReadStatus) {
+            Mode::Program => self.program(addr, &data.to_le_bytes()[..len]),
+            Mode::Erase if cmd == CMD_CONFIRM => self.erase(addr),
+            Mode::Erase => self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR,
+            Mode::BufferCount => {
+                let words = data as usize + 1;
+                if words > WRITE_BUFFER_WORDS {
+                    self.status |= STATUS_PROGRAM_ERROR;
+                } else {
+                    self.mode = Mode::BufferData {
+                        words_left: words,
+                        writes: Vec::with_capacity(words),
+                    };
+                }
+            }
+            Mode::BufferData {
+                words_left,
+                mut writes,
+            } => {
+                writes.push((addr, data.to_le_bytes()[..len].to_vec()));
+                self.mode = if words_left > 1 {
+                    Mode::BufferData {
+                        words_left: words_left - 1,
+                        writes,
+                    }
+                } else {
+                    Mode::BufferConfirm { writes }
+                };
+            }
+            Mode::BufferConfirm { writes } if cmd == CMD_CONFIRM => {
+                for (offset, bytes) in writes {
+                    self.program(offset, &bytes);
+                }
+            }
+            Mode::BufferConfirm { .. } => self.status |= STATUS_PROGRAM_ERROR,
+            // Lock, unlock and lock-down, blocks are never locked.
+            Mode::LockSetup => {}
+            Mode::ReadArray | Mode::ReadStatus | Mode::ReadId | Mode::Query => {
+                self.command(addr, cmd)
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DeviceTrait for CfiFlash {
+    dispatch_read_write! { read_impl, write_impl }
+
+    fn sync(&mut self) {}
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+}
+
+impl MemMappedDeviceTrait for CfiFlash {
+    fn name() -> &'static str {
+        FLASH_NAME
+    }
+    fn base() -> WordType {
+        FLASH_BASE
+    }
+    fn size() -> WordType {
+        FLASH_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cfi_flash() {
+        let path = "./tmp/test_cfi_flash.img";
+        std::fs::create_dir_all("./tmp").unwrap();
+        std::fs::write(path, [0x12u8, 0x34, 0x56, 0x78]).unwrap();
+
+        let mut flash = CfiFlash::open(path, false).unwrap();
+        assert_eq!(flash.read_u32(0).unwrap(), 0x7856_3412);
+        assert_eq!(flash.read_u8(4).unwrap(), 0xff);
+
+        flash.write_u32(0, CMD_READ_QUERY as u32).unwrap();
+        let qry: Vec<u8> = (0x10..0x13)
+            .map(|i| flash.read_u32(i * BANK_WIDTH).unwrap() as u8)
+            .collect();
+        assert_eq!(qry, b"QRY");
+        flash.write_u32(0, CMD_READ_ID as u32).unwrap();
+        assert_eq!(flash.read_u32(0).unwrap(), MANUFACTURER_ID as u32);
+
+        // Programming only clears bits, the status is read back afterwards.
+        flash.write_u32(0, CMD_PROGRAM as u32).unwrap();
+        flash.write_u32(0, 0x0f0f_0f0f).unwrap();
+        assert_eq!(flash.read_u32(0).unwrap(), STATUS_READY as u32);
+        flash.write_u32(0, CMD_READ_ARRAY as u32).unwrap();
+        assert_eq!(flash.read_u32(0).unwrap(), 0x0806_0402);
+
+        // Buffered program of two words past the end of the image.
+        flash.write_u32(0x100, CMD_BUFFERED_PROGRAM as u32).unwrap();
+        flash.write_u32(0x100, 1).unwrap();
+        flash.write_u32(0x100, 0xaabb_ccdd).unwrap();
+        flash.write_u32(0x104, 0x1122_3344).unwrap();
+        flash.write_u32(0x100, CMD_CONFIRM as u32).unwrap();
+        flash.write_u32(0, CMD_READ_ARRAY as u32).unwrap();
+        assert_eq!(flash.read_u64(0x100).unwrap(), 0x1122_3344_aabb_ccdd);
+
+        let image = std::fs::read(path).unwrap();
+        assert_eq!(image.len(), 0x108);
+        assert_eq!(image[..4], [0x02, 0x04, 0x06, 0x08]);
+        assert_eq!(image[4..0x100], [0xff; 0xfc]);
+        assert_eq!(
+            image[0x100..],
+            [0xdd, 0xcc, 0xbb, 0xaa, 0x44, 0x33, 0x22, 0x11]
+        );
+
+        flash.write_u32(0x10, CMD_BLOCK_ERASE as u32).unwrap();
+        flash.write_u32(0x10, CMD_CONFIRM as u32).unwrap();
+        flash.write_u32(0, CMD_READ_ARRAY as u32).unwrap();
+        assert_eq!(flash.read_u32(0).unwrap(), 0xffff_ffff);
+
+        let mut flash = CfiFlash::open(path, true).unwrap();
+        flash.write_u32(0, CMD_PROGRAM as u32).unwrap();
+        flash.write_u32(0, 0).unwrap();
+        assert_ne!(flash.read_u32(0).unwrap() as u8 & STATUS_PROGRAM_ERROR, 0);
+        flash.write_u32(0, CMD_CLEAR_STATUS as u32).unwrap();
+        assert_eq!(flash.read_u32(0).unwrap(), 0xffff_ffff);
+    }
+
+    #[test]
+    fn test_cfi_flash_create() {
+        let path = "./tmp/test_cfi_flash_create.img";
+        std::fs::create_dir_all("./tmp").unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let mut flash = CfiFlash::create(path).unwrap();
+        assert_eq!(flash.read_u32(0).unwrap(), 0xffff_ffff);
+        flash.write_u32(8, CMD_PROGRAM as u32).unwrap();
+        flash.write_u32(8, 0x1234_5678).unwrap();
+        assert_eq!(std::fs::read(path).unwrap()[8..], [0x78, 0x56, 0x34, 0x12]);
+
+        // An existing store is kept.
+        let mut flash = CfiFlash::create(path).unwrap();
+        assert_eq!(flash.read_u32(8).unwrap(), 0x1234_5678);
+    }
+}
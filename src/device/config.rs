@@ -5,11 +5,43 @@ pub const POWER_MANAGER_BASE: WordType = 0x10_0000;
 // Cannot be too small - the OpenSBI disallow.
 pub const POWER_MANAGER_SIZE: WordType = 0x1000;
 
+#[cfg(feature = "test-device")]
+pub const TEST_DEVICE_NAME: &'static str = "test-device";
 #[cfg(feature = "test-device")]
 pub const TEST_DEVICE_BASE: WordType = 0x10_1000;
 #[cfg(feature = "test-device")]
 pub const TEST_DEVICE_SIZE: WordType = 0x10;
 
+pub const WATCHDOG_NAME: &'static str = "watchdog";
+pub const WATCHDOG_BASE: WordType = 0x10_2000;
+pub const WATCHDOG_SIZE: WordType = 0x1000;
+
+pub const HYPERCALL_NAME: &'static str = "hypercall";
+pub const HYPERCALL_BASE: WordType = 0x10_3000;
+pub const HYPERCALL_SIZE: WordType = 0x1000;
+
+pub const SCRIPTED_DEVICE_NAME: &str = "scripted";
+pub const SCRIPTED_DEVICE_BASE: WordType = 0x10_4000;
+pub const SCRIPTED_DEVICE_SIZE: WordType = 0x1000;
+
+pub const DMA_NAME: &str = "dma";
+pub const DMA_BASE: WordType = 0x10_5000;
+pub const DMA_SIZE: WordType = 0x1000;
+/// DMA engine PLIC interrupt source ID.
+pub const DMA_IRQ: u32 = 14;
+/// Device ID of the DMA engine in the IOMMU, the one after the VirtIO slots.
+pub const DMA_DEVICE_ID: u32 = VIRTIO_MMIO_SLOTS as u32;
+
+pub const RNG_NAME: &str = "rng";
+pub const RNG_BASE: WordType = 0x10_6000;
+pub const RNG_SIZE: WordType = 0x1000;
+
+pub const IOMMU_NAME: &str = "iommu";
+pub const IOMMU_BASE: WordType = 0x301_0000;
+pub const IOMMU_SIZE: WordType = 0x1000;
+/// IOMMU PLIC interrupt source ID, for the command and the fault queue.
+pub const IOMMU_IRQ: u32 = 13;
+
 pub const CLINT_NAME: &'static str = "clint";
 pub const CLINT_BASE: WordType = 0x200_0000;
 pub const CLINT_SIZE: WordType = 0x10000;
@@ -28,5 +60,44 @@ pub const UART_IRQ: u32 = 10;
 pub const VIRTIO_MMIO_NAME: &'static str = "virtio-mmio-device";
 pub const VIRTIO_MMIO_BASE: WordType = 0x1000_1000;
 pub const VIRTIO_MMIO_SIZE: WordType = 0x1000;
+/// Number of VirtIO MMIO slots, both boot-time and hot-plugged devices use them.
+pub const VIRTIO_MMIO_SLOTS: usize = 8;
+/// PLIC interrupt source ID of the first VirtIO device, the n-th one uses `VIRTIO_IRQ_BASE + n`.
+pub const VIRTIO_IRQ_BASE: u32 = 1;
+/// Shared memory regions of the VirtIO devices, the n-th slot maps its regions in the
+/// `VIRTIO_SHM_WINDOW` bytes at `VIRTIO_SHM_BASE + n * VIRTIO_SHM_WINDOW`.
+pub const VIRTIO_SHM_BASE: WordType = 0x4000_0000;
+pub const VIRTIO_SHM_WINDOW: WordType = 0x800_0000;
+
+pub const FLASH_NAME: &'static str = "flash";
+pub const FLASH_BASE: WordType = 0x2000_0000;
+/// Size of a bank, bank 1 (the variable store) follows bank 0.
+pub const FLASH_SIZE: WordType = 0x200_0000;
+/// Erase block size of the flash.
+pub const FLASH_SECTOR_SIZE: WordType = 0x4_0000;
+
+pub const SPI_NAME: &'static str = "spi";
+pub const SPI_BASE: WordType = 0x1005_0000;
+pub const SPI_SIZE: WordType = 0x1000;
+/// SPI PLIC interrupt source ID, must match DTS `interrupts = <0xb>`
+pub const SPI_IRQ: u32 = 11;
+
+pub const I2C_NAME: &str = "i2c";
+pub const I2C_BASE: WordType = 0x1003_0000;
+pub const I2C_SIZE: WordType = 0x1000;
+/// I2C PLIC interrupt source ID.
+pub const I2C_IRQ: u32 = 16;
+
+pub const PWM_NAME: &str = "pwm";
+pub const PWM_BASE: WordType = 0x1002_0000;
+pub const PWM_SIZE: WordType = 0x1000;
+/// PWM PLIC interrupt source ID, shared by the four comparators.
+pub const PWM_IRQ: u32 = 17;
+
+pub const GPIO_NAME: &str = "gpio";
+pub const GPIO_BASE: WordType = 0x1006_0000;
+pub const GPIO_SIZE: WordType = 0x1000;
+/// GPIO PLIC interrupt source ID, shared by all pins.
+pub const GPIO_IRQ: u32 = 15;
 
 // pub const MMIO_FREQ_DIV: usize = 32;
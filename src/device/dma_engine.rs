@@ -0,0 +1,324 @@
+//! Memory-to-memory DMA controller: the guest programs a source, a destination and a length, sets
+//! `START` and gets an interrupt once the copy is done.
+//!
+//! The copy takes [`DMA_BYTES_PER_CYCLE`] bytes per cycle of the virtual clock and lands in
+//! memory when the transfer completes, so a driver reading the destination early sees stale data.
+//! Behind the IOMMU (`--iommu`) the addresses are I/O virtual addresses of device
+//! [`DMA_DEVICE_ID`](crate::device::config::DMA_DEVICE_ID).
+//!
+//! All registers are 32-bit, `SRC` and `DST` can also be accessed as one 64-bit register:
+//!
+//! | Offset | Name    | Description                                                             |
+//! |--------|---------|-------------------------------------------------------------------------|
+//! | 0x00   | SRC     | Source address, low then high half                                      |
+//! | 0x08   | DST     | Destination address, low then high half                                 |
+//! | 0x10   | LEN     | Number of bytes to copy, overlapping ranges are copied as by `memmove`  |
+//! | 0x14   | CTRL    | Bit 0 `START` starts a transfer and reads as 1 until it completes, bit 1 enables the interrupt |
+//! | 0x18   | STATUS  | Bit 0 `DONE`, bit 1 `ERROR` if an address was outside RAM or faulted in the IOMMU (write 1 to clear) |
+//!
+//! The registers cannot be written while a transfer is in progress. The interrupt is raised
+//! while `STATUS` is not zero and the interrupt is enabled.
+
+use std::{cell::UnsafeCell, rc::Rc};
+
+use crate::{
+    config::arch_config::WordType,
+    device::{
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{DMA_BASE, DMA_IRQ, DMA_NAME, DMA_SIZE},
+        plic::irq_line::{IrqDescriptor, IrqPin},
+        stats::DeviceStats,
+        virtio::dma::{DmaAccess, DmaError, GuestRam},
+    },
+    device_poller::PollingEventTrait,
+    vclock::Timer,
+};
+
+/// Copy throughput, in bytes per cycle of the virtual clock.
+pub const DMA_BYTES_PER_CYCLE: u64 = 8;
+
+const SRC: WordType = 0x00;
+const SRC_HI: WordType = 0x04;
+const DST: WordType = 0x08;
+const DST_HI: WordType = 0x0c;
+const LEN: WordType = 0x10;
+const CTRL: WordType = 0x14;
+const STATUS: WordType = 0x18;
+
+const CTRL_START: u32 = 1 << 0;
+const CTRL_IRQ_ENABLE: u32 = 1 << 1;
+
+const STATUS_DONE: u32 = 1 << 0;
+const STATUS_ERROR: u32 = 1 << 1;
+
+pub struct DmaEngine {
+    ram: GuestRam,
+    timer: Option<(Rc<UnsafeCell<Timer>>, u64)>,
+
+    src: u64,
+    dst: u64,
+    len: u32,
+    irq_enabled: bool,
+    busy: bool,
+    status: u32,
+
+    irq: Option<IrqPin>,
+}
+
+impl DmaEngine {
+    pub(crate) fn new(ram: GuestRam) -> Self {
+        Self {
+            ram,
+            timer: None,
+            src: 0,
+            dst: 0,
+            len: 0,
+            irq_enabled: false,
+            busy: false,
+            status: 0,
+            irq: None,
+        }
+    }
+
+    /// Complete the transfers with `task` of `timer`, which must call [`Self::timer_expired`].
+    pub(crate) fn attach_timer(&mut self, timer: Rc<UnsafeCell<Timer>>, task: u64) {
+        self.timer = Some((timer, task));
+    }
+
+    /// The transfer in progress is due.
+    pub(crate) fn timer_expired(&mut self) {
+        if !self.busy {
+            return;
+        }
+        self.busy = false;
+        match self.copy() {
+            Ok(()) => self.status |= STATUS_DONE,
+            Err(err) => {
+                log::warn!(
+                    "[DMA] {:#x} -> {:#x} ({:#x} bytes) failed: {}",
+                    self.src,
+                    self.dst,
+                    self.len,
+                    err
+                );
+                self.status |= STATUS_ERROR;
+            }
+        }
+        self.update_irq();
+    }
+
+    fn copy(&self) -> Result<(), DmaError> {
+        let len = self.len as usize;
+        // Read everything first, the ranges may overlap.
+        let data = self.ram.buffer(self.src, len, DmaAccess::Read)?.to_vec();
+        self.ram
+            .buffer(self.dst, len, DmaAccess::Write)?
+            .copy_from_slice(&data);
+        Ok(())
+    }
+
+    fn start(&mut self) {
+        self.busy = true;
+        let cycles = (self.len as u64).div_ceil(DMA_BYTES_PER_CYCLE).max(1);
+        match &self.timer {
+            Some((timer, task)) => unsafe { timer.as_mut_unchecked() }.set_delay(*task, cycles),
+            None => self.timer_expired(),
+        }
+    }
+
+    fn update_irq(&self) {
+        if let Some(pin) = &self.irq {
+            pin.set_level(self.irq_enabled && self.status != 0);
+        }
+    }
+
+    fn ctrl(&self) -> u32 {
+        let mut ctrl = if self.busy { CTRL_START } else { 0 };
+        if self.irq_enabled {
+            ctrl |= CTRL_IRQ_ENABLE;
+        }
+        ctrl
+    }
+
+    fn read_impl<T>(&mut self, addr: WordType) -> Result<T, MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        if size_of::<T>() == 8 && !matches!(addr, SRC | DST) {
+            return Err(MemError::LoadFault);
+        }
+
+        let value = match addr {
+            SRC => self.src,
+            SRC_HI => self.src >> 32,
+            DST => self.dst,
+            DST_HI => self.dst >> 32,
+            LEN => self.len as u64,
+            CTRL => self.ctrl() as u64,
+            STATUS => self.status as u64,
+            _ => return Err(MemError::LoadFault),
+        };
+        Ok(T::truncate_from(value))
+    }
+
+    fn write_impl<T>(&mut self, addr: WordType, data: T) -> Result<(), MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let data: u64 = data.into();
+        if size_of::<T>() == 8 && !matches!(addr, SRC | DST) {
+            return Err(MemError::StoreFault);
+        }
+
+        match addr {
+            STATUS => self.status &= !(data as u32),
+            SRC | SRC_HI | DST | DST_HI | LEN | CTRL if self.busy => {
+                log::warn!("[DMA] register {:#x} written during a transfer", addr);
+            }
+            SRC if size_of::<T>() == 8 => self.src = data,
+            SRC => self.src = (self.src & !0xffff_ffff) | data,
+            SRC_HI => self.src = (self.src & 0xffff_ffff) | data << 32,
+            DST if size_of::<T>() == 8 => self.dst = data,
+            DST => self.dst = (self.dst & !0xffff_ffff) | data,
+            DST_HI => self.dst = (self.dst & 0xffff_ffff) | data << 32,
+            LEN => self.len = data as u32,
+            CTRL => {
+                self.irq_enabled = data as u32 & CTRL_IRQ_ENABLE != 0;
+                if data as u32 & CTRL_START != 0 {
+                    self.start();
+                }
+            }
+            _ => return Err(MemError::StoreFault),
+        }
+        self.update_irq();
+        Ok(())
+    }
+}
+
+impl DeviceTrait for DmaEngine {
+    dispatch_read_write! { read_impl, write_impl }
+
+    fn sync(&mut self) {}
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+
+    fn connect_irq(&mut self, pin: IrqPin) {
+        self.irq = Some(pin);
+    }
+
+    fn report_stats(&mut self, stats: &mut DeviceStats) {
+        if let Some(pin) = &self.irq {
+            stats.record_irq(pin);
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some((timer, task)) = &self.timer
+            && self.busy
+        {
+            unsafe { timer.as_mut_unchecked() }.set_due(*task, u64::MAX);
+        }
+        self.src = 0;
+        self.dst = 0;
+        self.len = 0;
+        self.irq_enabled = false;
+        self.busy = false;
+        self.status = 0;
+        self.update_irq();
+    }
+}
+
+impl MemMappedDeviceTrait for DmaEngine {
+    fn name() -> &'static str {
+        DMA_NAME
+    }
+    fn base() -> WordType {
+        DMA_BASE
+    }
+    fn size() -> WordType {
+        DMA_SIZE
+    }
+    fn irq() -> Option<IrqDescriptor> {
+        Some(IrqDescriptor::level(DMA_IRQ))
+    }
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[4, 8])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{ram::Ram, ram_config::BASE_ADDR, vclock::VirtualClockRef};
+
+    #[test]
+    fn test_dma_copy() {
+        let mut ram = Ram::new();
+        for i in 0..0x100u64 {
+            ram.write::<u8>(0x1000 + i, i as u8).unwrap();
+        }
+        let clock = VirtualClockRef::new();
+        let timer = Rc::new(UnsafeCell::new(Timer::new(clock.clone())));
+        let dma = Rc::new(RefCell::new(DmaEngine::new(GuestRam::new(&mut ram))));
+        let task = unsafe { timer.as_mut_unchecked() }.register({
+            let dma = dma.clone();
+            move || dma.borrow_mut().timer_expired()
+        });
+        dma.borrow_mut().attach_timer(timer.clone(), task);
+        let pin = IrqPin::new(IrqDescriptor::level(DMA_IRQ));
+        dma.borrow_mut().connect_irq(pin.clone());
+
+        {
+            let mut dma = dma.borrow_mut();
+            dma.write_u64(SRC, BASE_ADDR + 0x1000).unwrap();
+            dma.write_u32(DST, (BASE_ADDR + 0x2000) as u32).unwrap();
+            dma.write_u32(DST_HI, 0).unwrap();
+            dma.write_u32(LEN, 0x100).unwrap();
+            dma.write_u32(CTRL, CTRL_START | CTRL_IRQ_ENABLE).unwrap();
+            assert_eq!(dma.read_u32(CTRL).unwrap() & CTRL_START, CTRL_START);
+            // Ignored during the transfer.
+            dma.write_u32(LEN, 0x10).unwrap();
+            assert_eq!(dma.read_u32(LEN).unwrap(), 0x100);
+            assert!(dma.read_u64(LEN).is_err());
+        }
+
+        // 0x100 bytes take 0x20 cycles.
+        clock.advance(0x1f);
+        unsafe { timer.as_mut_unchecked() }.tick();
+        assert_eq!(ram.read::<u8>(0x2001).unwrap(), 0);
+        assert!(!pin.level());
+        clock.advance(1);
+        unsafe { timer.as_mut_unchecked() }.tick();
+        assert_eq!(ram.read::<u64>(0x2008).unwrap(), 0x0f0e_0d0c_0b0a_0908);
+        assert_eq!(ram.read::<u8>(0x20ff).unwrap(), 0xff);
+        assert!(pin.level());
+
+        let mut dma = dma.borrow_mut();
+        assert_eq!(dma.read_u32(STATUS).unwrap(), STATUS_DONE);
+        assert_eq!(dma.read_u32(CTRL).unwrap(), CTRL_IRQ_ENABLE);
+        dma.write_u32(STATUS, STATUS_DONE).unwrap();
+        assert!(!pin.level());
+    }
+
+    #[test]
+    fn test_dma_overlap_and_error() {
+        let mut ram = Ram::new();
+        ram.write::<u64>(0, 0x0807_0605_0403_0201).unwrap();
+        let mut dma = DmaEngine::new(GuestRam::new(&mut ram));
+
+        // Without a timer the copy is immediate.
+        dma.write_u64(SRC, BASE_ADDR).unwrap();
+        dma.write_u64(DST, BASE_ADDR + 2).unwrap();
+        dma.write_u32(LEN, 6).unwrap();
+        dma.write_u32(CTRL, CTRL_START).unwrap();
+        assert_eq!(ram.read::<u64>(0).unwrap(), 0x0605_0403_0201_0201);
+        assert_eq!(dma.read_u32(STATUS).unwrap(), STATUS_DONE);
+
+        dma.write_u64(SRC, 0x1000).unwrap();
+        dma.write_u32(CTRL, CTRL_START).unwrap();
+        assert_eq!(dma.read_u32(STATUS).unwrap(), STATUS_DONE | STATUS_ERROR);
+    }
+}
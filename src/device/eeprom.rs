@@ -0,0 +1,288 @@
+//! Serial EEPROMs: [`SpiEeprom`] speaks the 25xx command set on a
+//! [`SifiveSpi`](crate::device::sifive_spi::SifiveSpi) chip select, [`I2cEeprom`] is a 24xx
+//! device on an [`OcoresI2c`](crate::device::ocores_i2c::OcoresI2c) bus.
+//!
+//! Both take their memory address in 1 to 3 bytes depending on their size, most significant byte
+//! first, and writes complete instantly. A write wraps around within its page, reads stream
+//! through the whole memory. The content lives in host memory only.
+
+use crate::device::{ocores_i2c::I2cSlave, sifive_spi::SpiSlave};
+
+/// The memory of an EEPROM and its address pointer.
+pub struct Eeprom {
+    data: Vec<u8>,
+    addr: usize,
+}
+
+impl Eeprom {
+    /// An erased EEPROM of `size` bytes, a power of two up to 16 MiB.
+    pub fn new(size: usize) -> Self {
+        assert!(
+            size.is_power_of_two() && size <= 1 << 24,
+            "invalid EEPROM size {size}"
+        );
+        Self {
+            data: vec![0xff; size],
+            addr: 0,
+        }
+    }
+
+    /// Start from `image`, which is truncated or padded with erased bytes to the size.
+    pub fn with_image(mut self, image: &[u8]) -> Self {
+        let len = image.len().min(self.data.len());
+        self.data[..len].copy_from_slice(&image[..len]);
+        self
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Number of bytes of a memory address.
+    fn addr_bytes(&self) -> usize {
+        let size = self.data.len();
+        if size <= 0x100 {
+            1
+        } else if size <= 0x1_0000 {
+            2
+        } else {
+            3
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        let size = self.data.len();
+        if size <= 0x800 {
+            16
+        } else if size <= 0x2000 {
+            32
+        } else {
+            64
+        }
+    }
+
+    fn read_next(&mut self) -> u8 {
+        let byte = self.data[self.addr];
+        self.addr = (self.addr + 1) % self.data.len();
+        byte
+    }
+
+    fn write_next(&mut self, byte: u8) {
+        self.data[self.addr] = byte;
+        let page = self.page_size();
+        self.addr = self.addr / page * page + (self.addr + 1) % page;
+    }
+}
+
+const SPI_WRSR: u8 = 0x01;
+const SPI_WRITE: u8 = 0x02;
+const SPI_READ: u8 = 0x03;
+const SPI_WRDI: u8 = 0x04;
+const SPI_RDSR: u8 = 0x05;
+const SPI_WREN: u8 = 0x06;
+
+/// Write enable latch of the status register.
+const STATUS_WEL: u8 = 1 << 1;
+
+enum SpiState {
+    /// Waiting for the instruction, the first byte after the chip select.
+    Command,
+    /// Receiving the address of a read or write, `left` bytes to go.
+    Address {
+        write: bool,
+        left: usize,
+    },
+    Read,
+    Write,
+    ReadStatus,
+    /// Ignoring the rest of the transfer.
+    Done,
+}
+
+/// A 25xx SPI EEPROM, such as the AT25256.
+pub struct SpiEeprom {
+    memory: Eeprom,
+    state: SpiState,
+    write_enabled: bool,
+}
+
+impl SpiEeprom {
+    pub fn new(memory: Eeprom) -> Self {
+        Self {
+            memory,
+            state: SpiState::Done,
+            write_enabled: false,
+        }
+    }
+
+    pub fn memory(&self) -> &Eeprom {
+        &self.memory
+    }
+
+    fn command(&mut self, cmd: u8) -> SpiState {
+        let addr_bytes = self.memory.addr_bytes();
+        match cmd {
+            SPI_READ => SpiState::Address {
+                write: false,
+                left: addr_bytes,
+            },
+            SPI_WRITE if self.write_enabled => SpiState::Address {
+                write: true,
+                left: addr_bytes,
+            },
+            SPI_RDSR => SpiState::ReadStatus,
+            SPI_WREN => {
+                self.write_enabled = true;
+                SpiState::Done
+            }
+            SPI_WRDI => {
+                self.write_enabled = false;
+                SpiState::Done
+            }
+            SPI_WRITE | SPI_WRSR => SpiState::Done,
+            _ => {
+                log::debug!("[EEPROM] unknown SPI instruction {:#04x}", cmd);
+                SpiState::Done
+            }
+        }
+    }
+}
+
+impl SpiSlave for SpiEeprom {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let (next, out) = match std::mem::replace(&mut self.state, SpiState::Done) {
+            SpiState::Command => (self.command(byte), 0xff),
+            SpiState::Address { write, left } => {
+                let addr = (self.memory.addr << 8 | byte as usize) % self.memory.data.len();
+                self.memory.addr = addr;
+                match (left, write) {
+                    (1, false) => (SpiState::Read, 0xff),
+                    (1, true) => (SpiState::Write, 0xff),
+                    _ => (
+                        SpiState::Address {
+                            write,
+                            left: left - 1,
+                        },
+                        0xff,
+                    ),
+                }
+            }
+            SpiState::Read => (SpiState::Read, self.memory.read_next()),
+            SpiState::Write => {
+                self.memory.write_next(byte);
+                (SpiState::Write, 0xff)
+            }
+            SpiState::ReadStatus => {
+                let status = if self.write_enabled { STATUS_WEL } else { 0 };
+                (SpiState::ReadStatus, status)
+            }
+            SpiState::Done => (SpiState::Done, 0xff),
+        };
+        self.state = next;
+        out
+    }
+
+    fn select(&mut self, selected: bool) {
+        if selected {
+            self.memory.addr = 0;
+            self.state = SpiState::Command;
+        } else if matches!(self.state, SpiState::Write) {
+            // The write cycle ends with the chip select and resets the latch.
+            self.write_enabled = false;
+        }
+    }
+}
+
+/// A 24xx I2C EEPROM, such as the 24C02.
+pub struct I2cEeprom {
+    memory: Eeprom,
+    /// Address bytes still expected in the current write.
+    addr_left: usize,
+}
+
+impl I2cEeprom {
+    pub fn new(memory: Eeprom) -> Self {
+        Self {
+            memory,
+            addr_left: 0,
+        }
+    }
+
+    pub fn memory(&self) -> &Eeprom {
+        &self.memory
+    }
+}
+
+impl I2cSlave for I2cEeprom {
+    fn start(&mut self, read: bool) -> bool {
+        // A read starts at the current address, a write with a new one.
+        if !read {
+            self.addr_left = self.memory.addr_bytes();
+            self.memory.addr = 0;
+        }
+        true
+    }
+
+    fn write(&mut self, byte: u8) -> bool {
+        if self.addr_left > 0 {
+            self.addr_left -= 1;
+            self.memory.addr = (self.memory.addr << 8 | byte as usize) % self.memory.data.len();
+        } else {
+            self.memory.write_next(byte);
+        }
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        self.memory.read_next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spi_transfer(eeprom: &mut SpiEeprom, bytes: &[u8]) -> Vec<u8> {
+        eeprom.select(true);
+        let out = bytes.iter().map(|&byte| eeprom.transfer(byte)).collect();
+        eeprom.select(false);
+        out
+    }
+
+    #[test]
+    fn test_spi_eeprom() {
+        let mut eeprom = SpiEeprom::new(Eeprom::new(0x8000).with_image(b"hello"));
+        assert_eq!(
+            spi_transfer(&mut eeprom, &[SPI_READ, 0, 1, 0, 0, 0, 0])[3..],
+            *b"ello"
+        );
+
+        // Writes need the latch, which the write resets.
+        spi_transfer(&mut eeprom, &[SPI_WRITE, 0, 0, b'j']);
+        assert_eq!(eeprom.memory().data()[0], b'h');
+        spi_transfer(&mut eeprom, &[SPI_WREN]);
+        assert_eq!(spi_transfer(&mut eeprom, &[SPI_RDSR, 0])[1], STATUS_WEL);
+        // The last byte of a 64-byte page wraps to its start.
+        spi_transfer(&mut eeprom, &[SPI_WRITE, 0, 0x3f, b'!', b'j']);
+        assert_eq!(eeprom.memory().data()[..2], *b"je");
+        assert_eq!(eeprom.memory().data()[0x3f], b'!');
+        assert_eq!(spi_transfer(&mut eeprom, &[SPI_RDSR, 0])[1], 0);
+    }
+
+    #[test]
+    fn test_i2c_eeprom() {
+        let mut eeprom = I2cEeprom::new(Eeprom::new(0x100));
+        eeprom.start(false);
+        for byte in [0x10, 0xaa, 0xbb] {
+            assert!(eeprom.write(byte));
+        }
+        eeprom.stop();
+
+        // A random read: set the address, then read from there.
+        eeprom.start(false);
+        eeprom.write(0x0f);
+        eeprom.start(true);
+        let bytes: Vec<_> = (0..4).map(|_| eeprom.read()).collect();
+        assert_eq!(bytes, [0xff, 0xaa, 0xbb, 0xff]);
+    }
+}
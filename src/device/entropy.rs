@@ -0,0 +1,116 @@
+//! Entropy source: every read of `DATA` returns fresh random bits, for guests which seed their
+//! random number generator from hardware.
+//!
+//! The bits come from ChaCha12 seeded with a 64-bit seed: `--rng-seed` makes the stream, and so
+//! the run, reproducible. Otherwise the seed is random, or 0 with `--deterministic`, and logged.
+//!
+//! | Offset | Name    | Description                                                             |
+//! |--------|---------|-------------------------------------------------------------------------|
+//! | 0x00   | DATA    | 32 random bits per 32-bit read, 64 per 64-bit read (read-only)          |
+//! | 0x08   | STATUS  | Bit 0 `READY`, always set (read-only)                                   |
+
+use std::hash::{BuildHasher, RandomState};
+
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+
+use crate::{
+    config::arch_config::WordType,
+    device::{
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{RNG_BASE, RNG_NAME, RNG_SIZE},
+    },
+    device_poller::PollingEventTrait,
+};
+
+const DATA: WordType = 0x00;
+const STATUS: WordType = 0x08;
+
+const STATUS_READY: u32 = 1 << 0;
+
+/// A seed from the host's randomness.
+pub fn host_seed() -> u64 {
+    RandomState::new().hash_one(std::process::id())
+}
+
+pub struct EntropySource {
+    rng: ChaCha12Rng,
+}
+
+impl EntropySource {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: ChaCha12Rng::seed_from_u64(seed),
+        }
+    }
+
+    fn read_impl<T>(&mut self, addr: WordType) -> Result<T, MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let value = match addr {
+            DATA if size_of::<T>() == 8 => self.rng.next_u64(),
+            DATA => self.rng.next_u32() as u64,
+            STATUS => STATUS_READY as u64,
+            _ => return Err(MemError::LoadFault),
+        };
+        Ok(T::truncate_from(value))
+    }
+
+    fn write_impl<T>(&mut self, addr: WordType, _data: T) -> Result<(), MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        match addr {
+            DATA | STATUS => Ok(()),
+            _ => Err(MemError::StoreFault),
+        }
+    }
+}
+
+impl DeviceTrait for EntropySource {
+    dispatch_read_write! { read_impl, write_impl }
+
+    fn sync(&mut self) {}
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+}
+
+impl MemMappedDeviceTrait for EntropySource {
+    fn name() -> &'static str {
+        RNG_NAME
+    }
+    fn base() -> WordType {
+        RNG_BASE
+    }
+    fn size() -> WordType {
+        RNG_SIZE
+    }
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[4, 8])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entropy_seed() {
+        let stream = |seed| {
+            let mut rng = EntropySource::new(seed);
+            [
+                rng.read_u64(DATA).unwrap(),
+                rng.read_u32(DATA).unwrap() as u64,
+            ]
+        };
+        assert_eq!(stream(42), stream(42));
+        assert_ne!(stream(42), stream(43));
+
+        let mut rng = EntropySource::new(0);
+        assert_eq!(rng.read_u32(STATUS).unwrap(), STATUS_READY);
+        rng.write_u32(DATA, 0).unwrap();
+        assert!(rng.read_u32(0x10).is_err());
+    }
+}
@@ -4,7 +4,7 @@
 use std::{
     cell::RefCell,
     sync::{
-        Arc,
+        Arc, OnceLock,
         atomic::{AtomicBool, AtomicU8, Ordering},
     },
     u8,
@@ -16,9 +16,13 @@ use crate::{
     byte_io::{ByteSink, ByteSource, ChannelIOContext},
     config::arch_config::WordType,
     device::{
-        DeviceTrait, MemError, MemMappedDeviceTrait,
-        config::{UART_BASE, UART_DEFAULT_DIV, UART_IRQ, UART_SIZE},
-        plic::ExternalInterrupt,
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{UART_BASE, UART_DEFAULT_DIV, UART_IRQ, UART_NAME, UART_SIZE},
+        plic::{
+            ExternalInterrupt,
+            irq_line::{IrqDescriptor, IrqPin},
+        },
+        stats::DeviceStats,
     },
     device_poller::{PollingEventTrait, PollingFnWrapper},
     utils::{clear_bit, read_bit, set_bit},
@@ -26,12 +30,50 @@ use crate::{
 
 const UART_DATA_LENGTH: u8 = 8;
 
+/// Drive `irq` from the interrupt state. The UART and its port both call it after changing the
+/// state. A call racing with the other side may set a stale level, so the state is evaluated
+/// again after setting it: whichever call sets the pin last sees every change before it.
+fn update_irq(
+    irq: &OnceLock<IrqPin>,
+    ier: &AtomicU8,
+    thre_pending: &AtomicBool,
+    rx_pending: &AtomicBool,
+) {
+    let Some(pin) = irq.get() else {
+        return;
+    };
+    let eval = || {
+        FastUart16550::eval_irq(
+            ier.load(Ordering::Acquire),
+            thre_pending.load(Ordering::Acquire),
+            rx_pending.load(Ordering::Acquire),
+        )
+        .is_some()
+    };
+    let mut active = eval();
+    loop {
+        pin.set_level(active);
+        let now = eval();
+        if now == active {
+            break;
+        }
+        active = now;
+    }
+}
+
 #[derive(Clone)]
 pub struct UartBytePort {
     uart_io: ChannelIOContext,
     ier: Arc<AtomicU8>,
     thre_pending: Arc<AtomicBool>,
     rx_pending: Arc<AtomicBool>,
+    irq: Arc<OnceLock<IrqPin>>,
+}
+
+impl UartBytePort {
+    fn update_irq(&self) {
+        update_irq(&self.irq, &self.ier, &self.thre_pending, &self.rx_pending);
+    }
 }
 
 impl ByteSink for UartBytePort {
@@ -48,6 +90,7 @@ impl ByteSink for UartBytePort {
         self.uart_io.after_receive(received);
         if received {
             self.rx_pending.store(true, Ordering::Release);
+            self.update_irq();
         }
     }
 }
@@ -149,14 +192,19 @@ pub struct FastUart16550 {
     input_rx: Receiver<u8>,
     output_tx: Sender<u8>,
 
-    /// Shared IER value for the polling thread to check interrupt conditions.
+    /// Shared IER value for the port to check interrupt conditions.
     ier_shared: Arc<AtomicU8>,
     /// THRE event latch for simplified ETBEI behavior.
     /// Cleared when IIR reports THRE as the identified interrupt source.
     thre_pending: Arc<AtomicBool>,
-    /// RX-data-pending latch (mirrors LSR[0] plus any queued input) for the
-    /// interrupt poll. Set when bytes arrive, cleared once all input is read.
+    /// RX-data-pending latch (mirrors LSR[0] plus any queued input). Set when
+    /// bytes arrive, cleared once all input is read.
     rx_pending: Arc<AtomicBool>,
+    /// Updated on every change of the interrupt state once the board wires the UART to the PLIC,
+    /// by the port too when bytes arrive.
+    irq: Arc<OnceLock<IrqPin>>,
+    /// Called once a byte is queued for the [`UartBytePort`], see [`Self::on_output`].
+    on_output: Option<Box<dyn Fn()>>,
 }
 
 impl FastUart16550 {
@@ -167,6 +215,7 @@ impl FastUart16550 {
         let ier = uart.ier_shared.clone();
         let thre_pending = uart.thre_pending.clone();
         let rx_pending = uart.rx_pending.clone();
+        let irq = uart.irq.clone();
 
         (
             uart,
@@ -175,6 +224,7 @@ impl FastUart16550 {
                 ier,
                 thre_pending,
                 rx_pending,
+                irq,
             },
         )
     }
@@ -228,9 +278,17 @@ impl FastUart16550 {
             ier_shared,
             thre_pending,
             rx_pending,
+            irq: Arc::new(OnceLock::new()),
+            on_output: None,
         }
     }
 
+    /// Call `callback` after the guest wrote a byte, so the host side drains the
+    /// [`UartBytePort`] instead of polling it.
+    pub fn on_output(&mut self, callback: impl Fn() + 'static) {
+        self.on_output = Some(Box::new(callback));
+    }
+
     /// Compute a simplified IIR (Interrupt Identification Register) view based on current IER/LSR/FCR state.
     fn compute_iir(&mut self) -> u8 {
         let reg = self.reg.borrow();
@@ -282,6 +340,15 @@ impl FastUart16550 {
         (rda || thre).then_some(UART_IRQ)
     }
 
+    fn update_irq(&self) {
+        update_irq(
+            &self.irq,
+            &self.ier_shared,
+            &self.thre_pending,
+            &self.rx_pending,
+        );
+    }
+
     /// Snapshot the current interrupt state.
     #[cfg(test)]
     pub fn poll_interrupt(&self) -> Option<ExternalInterrupt> {
@@ -332,6 +399,8 @@ impl FastUart16550 {
             }
         }
 
+        // IIR and RBR reads clear the THRE and RDA conditions.
+        self.update_irq();
         Ok(data)
     }
 
@@ -366,6 +435,9 @@ impl FastUart16550 {
                         }
                     );
                     let _ = self.output_tx.send(byte);
+                    if let Some(on_output) = &self.on_output {
+                        on_output();
+                    }
                     // In a real 16550, writing THR clears LSR[5] (THRE) momentarily,
                     // then sets it again when the shift register accepts the byte.
                     // Since fast_uart sends instantly, we just re-arm the THRE event.
@@ -396,6 +468,7 @@ impl FastUart16550 {
             }
         }
 
+        self.update_irq();
         Ok(())
     }
 
@@ -423,9 +496,13 @@ impl DeviceTrait for FastUart16550 {
     fn sync(&mut self) {}
 
     fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
-        // Evaluate the UART's interrupt conditions on the device poller's
-        // cadence — independent of terminal input — so THRE and RDA are
-        // delivered even when no bytes are flowing.
+        // The pin follows the state on its own, nothing to poll.
+        if self.irq.get().is_some() {
+            return None;
+        }
+
+        // Without a pin, evaluate the interrupt conditions on the device poller's cadence and
+        // report through it instead.
         let ier = self.ier_shared.clone();
         let thre_pending = self.thre_pending.clone();
         let rx_pending = self.rx_pending.clone();
@@ -437,15 +514,39 @@ impl DeviceTrait for FastUart16550 {
             )
         })))
     }
+
+    fn connect_irq(&mut self, pin: IrqPin) {
+        if self.irq.set(pin).is_err() {
+            log::warn!("[uart] the interrupt pin is already connected");
+        }
+        self.update_irq();
+    }
+
+    fn report_stats(&mut self, stats: &mut DeviceStats) {
+        if let Some(pin) = self.irq.get() {
+            stats.record_irq(pin);
+        }
+    }
 }
 
 impl MemMappedDeviceTrait for FastUart16550 {
+    fn name() -> &'static str {
+        UART_NAME
+    }
     fn base() -> WordType {
         UART_BASE
     }
     fn size() -> WordType {
         UART_SIZE
     }
+    fn irq() -> Option<IrqDescriptor> {
+        Some(IrqDescriptor::level(UART_IRQ))
+    }
+
+    /// The registers are a byte wide, as the device tree leaves `reg-io-width` at 1.
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[1])
+    }
 }
 
 #[cfg(test)]
@@ -467,6 +568,12 @@ mod test {
 
         assert_eq!(deque.len(), 1);
         assert_eq!(deque[0], 'a' as u8);
+
+        let written = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counter = written.clone();
+        uart.on_output(move || counter.set(counter.get() + 1));
+        uart.write_impl(0, 'b' as u8).unwrap();
+        assert_eq!(written.get(), 1);
     }
 
     #[test]
@@ -539,4 +646,20 @@ mod test {
         assert_eq!(uart.read_impl::<u8>(2).unwrap() & 0x0f, 0x02); // IIR: THR empty
         assert_eq!(uart.poll_interrupt(), None); // cleared, no storm
     }
+
+    /// With a pin, the state is pushed on every change instead of being polled.
+    #[test]
+    fn pin_follows_interrupt_state() {
+        let (mut uart, mut port) = FastUart16550::new();
+        let pin = IrqPin::new(IrqDescriptor::level(UART_IRQ));
+        uart.connect_irq(pin.clone());
+        assert!(uart.get_poll_event().is_none());
+        uart.write_impl::<u8>(1, 0x01).unwrap(); // enable RDA
+        assert!(!pin.level());
+
+        port.receive_bytes([b'x']);
+        assert!(pin.level());
+        assert_eq!(uart.read_impl::<u8>(0).unwrap(), b'x');
+        assert!(!pin.level());
+    }
 }
@@ -0,0 +1,163 @@
+//! Hypercall window for bare-metal guest tests: instead of printing to the UART and parsing the
+//! output, a test writes to these registers to talk to the host directly.
+//!
+//! All registers are 32-bit and write-only, reads return 0:
+//!
+//! | Offset | Name       | Description                                                        |
+//! |--------|------------|--------------------------------------------------------------------|
+//! | 0x00   | PUTCHAR    | Append a byte to the message, `\n` prints it to the host console    |
+//! | 0x04   | CHECKPOINT | Record the value as a [`Checkpoint`], with the current cycle       |
+//! | 0x08   | DUMP       | Print the hart state (pc, registers and trap CSRs) to the console  |
+
+use crate::{
+    board::{BoardControl, BoardRequest},
+    config::arch_config::WordType,
+    device::{
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{HYPERCALL_BASE, HYPERCALL_NAME, HYPERCALL_SIZE},
+    },
+    device_poller::PollingEventTrait,
+    vclock::VirtualClockRef,
+};
+
+const PUTCHAR: WordType = 0x00;
+const CHECKPOINT: WordType = 0x04;
+const DUMP: WordType = 0x08;
+
+/// Longest message kept, the rest of the line is dropped.
+const MAX_MESSAGE_LEN: usize = 1024;
+
+/// A value the guest reported through `CHECKPOINT`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub value: u32,
+    pub cycle: u64,
+}
+
+pub struct Hypercall {
+    clock: VirtualClockRef,
+    control: BoardControl,
+
+    message: Vec<u8>,
+    checkpoints: Vec<Checkpoint>,
+}
+
+impl Hypercall {
+    pub fn new(clock: VirtualClockRef, control: BoardControl) -> Self {
+        Self {
+            clock,
+            control,
+            message: Vec::new(),
+            checkpoints: Vec::new(),
+        }
+    }
+
+    /// Every checkpoint the guest reported, oldest first.
+    pub fn checkpoints(&self) -> &[Checkpoint] {
+        &self.checkpoints
+    }
+
+    fn putchar(&mut self, byte: u8) {
+        if byte != b'\n' {
+            if self.message.len() < MAX_MESSAGE_LEN {
+                self.message.push(byte);
+            }
+            return;
+        }
+
+        let message = String::from_utf8_lossy(&self.message);
+        log::info!("Guest message: {message}");
+        eprint!("[guest] {message}\r\n");
+        self.message.clear();
+    }
+
+    fn read_impl<T>(&mut self, addr: WordType) -> Result<T, MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        if !matches!(addr, PUTCHAR | CHECKPOINT | DUMP) {
+            return Err(MemError::LoadFault);
+        }
+        Ok(T::truncate_from(0u32))
+    }
+
+    fn write_impl<T>(&mut self, addr: WordType, data: T) -> Result<(), MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let data: u32 = data.truncate_to();
+        match addr {
+            PUTCHAR => self.putchar(data as u8),
+            CHECKPOINT => {
+                let cycle = self.clock.now();
+                log::info!("Guest checkpoint {data:#x} at cycle {cycle}");
+                self.checkpoints.push(Checkpoint { value: data, cycle });
+            }
+            DUMP => self.control.request(BoardRequest::DumpState),
+            _ => return Err(MemError::StoreFault),
+        }
+        Ok(())
+    }
+}
+
+impl DeviceTrait for Hypercall {
+    dispatch_read_write! { read_impl, write_impl }
+
+    fn sync(&mut self) {}
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+
+    fn reset(&mut self) {
+        self.message.clear();
+    }
+}
+
+impl MemMappedDeviceTrait for Hypercall {
+    fn name() -> &'static str {
+        HYPERCALL_NAME
+    }
+    fn base() -> WordType {
+        HYPERCALL_BASE
+    }
+    fn size() -> WordType {
+        HYPERCALL_SIZE
+    }
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[4])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hypercall() {
+        let clock = VirtualClockRef::new();
+        let control = BoardControl::default();
+        let mut hypercall = Hypercall::new(clock.clone(), control.clone());
+
+        for byte in b"hello\n" {
+            hypercall.write_u32(PUTCHAR, *byte as u32).unwrap();
+        }
+        assert!(hypercall.message.is_empty());
+
+        clock.advance(42);
+        hypercall.write_u32(CHECKPOINT, 7).unwrap();
+        assert_eq!(
+            hypercall.checkpoints(),
+            &[Checkpoint {
+                value: 7,
+                cycle: 42
+            }]
+        );
+
+        hypercall.write_u32(DUMP, 1).unwrap();
+        assert_eq!(control.take(), Some(BoardRequest::DumpState));
+
+        assert_eq!(hypercall.read_u32(CHECKPOINT).unwrap(), 0);
+        assert!(!Hypercall::access_widths().allows(1));
+        assert!(hypercall.write_u32(0x0c, 0).is_err());
+    }
+}
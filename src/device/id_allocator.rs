@@ -1,6 +1,8 @@
 use crate::{config::arch_config::WordType, device::MemMappedDeviceTrait};
 
 pub(crate) struct MemMapInfo {
+    /// The n-th device of its type, starting from `start_id`.
+    pub(crate) index: WordType,
     pub(crate) name: String,
     pub(crate) base: WordType,
     pub(crate) size: WordType,
@@ -18,19 +20,31 @@ impl IdAllocator {
     where
         T: MemMappedDeviceTrait,
     {
+        Self::with_range(start_id, device_name, T::base(), T::size())
+    }
+
+    /// Hand out the ranges of `mem_size` bytes following `mem_base`.
+    pub(crate) fn with_range(
+        start_id: WordType,
+        device_name: String,
+        mem_base: WordType,
+        mem_size: WordType,
+    ) -> Self {
         Self {
             id: start_id,
             device_name,
-            mem_base: T::base(),
-            mem_size: T::size(),
+            mem_base,
+            mem_size,
         }
     }
 
     pub(crate) fn get(&mut self) -> MemMapInfo {
-        let name = self.id.to_string() + &self.device_name;
+        let name = format!("{}{}", self.device_name, self.id);
         let mem = self.mem_base + self.id * self.mem_size;
+        let index = self.id;
         self.id += 1;
         MemMapInfo {
+            index,
             name,
             base: mem,
             size: self.mem_size,
@@ -0,0 +1,133 @@
+//! LM75 temperature sensor on an [`OcoresI2c`](crate::device::ocores_i2c::OcoresI2c) bus.
+//!
+//! The first byte of a write selects the register, reads return the selected register over and
+//! over. The temperature is fixed by the host, with [`Lm75::set_temperature`]; the OS output and
+//! shutdown mode are not modelled, the configuration register only keeps its value.
+
+use crate::device::ocores_i2c::I2cSlave;
+
+const REG_TEMP: u8 = 0;
+const REG_CONF: u8 = 1;
+const REG_THYST: u8 = 2;
+const REG_TOS: u8 = 3;
+
+/// Default address with A2..A0 tied low.
+pub const LM75_ADDR: u8 = 0x48;
+
+/// A temperature register value, from millidegrees Celsius: 9 bits of half degrees, left aligned.
+fn to_register(millicelsius: i32) -> u16 {
+    (((millicelsius / 500).clamp(-256, 255) as i16) << 7) as u16
+}
+
+pub struct Lm75 {
+    temp: u16,
+    conf: u8,
+    thyst: u16,
+    tos: u16,
+
+    pointer: u8,
+    /// Bytes of the current transfer so far.
+    index: usize,
+}
+
+impl Lm75 {
+    pub fn new(millicelsius: i32) -> Self {
+        Self {
+            temp: to_register(millicelsius),
+            conf: 0,
+            thyst: to_register(75_000),
+            tos: to_register(80_000),
+            pointer: REG_TEMP,
+            index: 0,
+        }
+    }
+
+    pub fn set_temperature(&mut self, millicelsius: i32) {
+        self.temp = to_register(millicelsius);
+    }
+
+    fn register(&mut self) -> Option<&mut u16> {
+        match self.pointer {
+            REG_TEMP => Some(&mut self.temp),
+            REG_THYST => Some(&mut self.thyst),
+            REG_TOS => Some(&mut self.tos),
+            _ => None,
+        }
+    }
+}
+
+impl I2cSlave for Lm75 {
+    fn start(&mut self, _read: bool) -> bool {
+        self.index = 0;
+        true
+    }
+
+    fn write(&mut self, byte: u8) -> bool {
+        let index = self.index;
+        self.index += 1;
+        match (index, self.pointer) {
+            (0, _) => self.pointer = byte & 0b11,
+            (1, REG_CONF) => self.conf = byte,
+            // The temperature register is read-only.
+            (_, REG_TEMP) => {}
+            (1, _) => {
+                let reg = self.register().unwrap();
+                *reg = (*reg & 0x00ff) | (byte as u16) << 8;
+            }
+            (2, _) => {
+                let reg = self.register().unwrap();
+                *reg = (*reg & 0xff00) | (byte & 0x80) as u16;
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        let index = self.index;
+        self.index += 1;
+        if self.pointer == REG_CONF {
+            return self.conf;
+        }
+        let value = *self.register().unwrap();
+        match index % 2 {
+            0 => (value >> 8) as u8,
+            _ => value as u8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_register(lm75: &mut Lm75, reg: u8) -> u16 {
+        lm75.start(false);
+        lm75.write(reg);
+        lm75.start(true);
+        u16::from_be_bytes([lm75.read(), lm75.read()])
+    }
+
+    #[test]
+    fn test_lm75() {
+        let mut lm75 = Lm75::new(25_500);
+        assert_eq!(read_register(&mut lm75, REG_TEMP), 0x1980);
+        lm75.set_temperature(-500);
+        // The pointer stays on the temperature.
+        lm75.start(true);
+        assert_eq!(u16::from_be_bytes([lm75.read(), lm75.read()]), 0xff80);
+
+        assert_eq!(read_register(&mut lm75, REG_TOS), 0x5000);
+        lm75.start(false);
+        for byte in [REG_TOS, 0x3c, 0x80] {
+            lm75.write(byte);
+        }
+        assert_eq!(read_register(&mut lm75, REG_TOS), 0x3c80);
+
+        lm75.start(false);
+        lm75.write(REG_CONF);
+        lm75.write(0x02);
+        lm75.start(true);
+        assert_eq!(lm75.read(), 0x02);
+    }
+}
@@ -4,18 +4,76 @@ use std::{
     rc::Rc,
 };
 
+use serde::{Serialize, Serializer};
+
 use crate::{
     config::arch_config::WordType,
-    device::{DeviceTrait, MemError},
+    device::mmio_trace::{MmioAccess, MmioAccessKind, MmioTracer},
+    device::{
+        DeviceTrait, MemError,
+        pma::{Pma, PmaOverride},
+        stats::{AccessCounters, DeviceStats},
+    },
     ram::Ram,
     ram_config,
     utils::{TruncateTo, UnsignedInteger, check_align},
 };
 
+/// The access widths a device accepts, as a set of sizes in bytes. [`MemoryMapIO`] faults the
+/// other widths before they reach the device; every access must also be naturally aligned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessWidths(u8);
+
+impl AccessWidths {
+    pub const ANY: Self = Self::of(&[1, 2, 4, 8]);
+
+    /// Accepts the accesses of `sizes` bytes, each one of 1, 2, 4 or 8.
+    pub const fn of(sizes: &[u32]) -> Self {
+        let mut mask = 0;
+        let mut i = 0;
+        while i < sizes.len() {
+            assert!(matches!(sizes[i], 1 | 2 | 4 | 8));
+            // The sizes are the bits 0 to 3 themselves.
+            mask |= sizes[i] as u8;
+            i += 1;
+        }
+        Self(mask)
+    }
+
+    pub fn allows(self, size: u32) -> bool {
+        size.is_power_of_two() && size <= 8 && self.0 & size as u8 != 0
+    }
+
+    /// Whether every one of `sizes` is a valid access size, as [`Self::of`] requires.
+    pub fn valid(sizes: &[u32]) -> bool {
+        sizes.iter().all(|size| matches!(size, 1 | 2 | 4 | 8))
+    }
+}
+
+/// As the list of sizes.
+impl Serialize for AccessWidths {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let sizes: Vec<u32> = [1, 2, 4, 8]
+            .into_iter()
+            .filter(|&size| self.allows(size))
+            .collect();
+        sizes.serialize(serializer)
+    }
+}
+
+impl Default for AccessWidths {
+    fn default() -> Self {
+        Self::ANY
+    }
+}
+
 pub struct MemoryMapItem {
+    pub(crate) name: String,
     pub(crate) start: WordType,
     pub(crate) size: WordType,
     pub(crate) device: Rc<RefCell<dyn DeviceTrait>>,
+    pub(crate) pma: Pma,
+    pub(crate) accesses: AccessCounters,
 }
 
 impl PartialEq for MemoryMapItem {
@@ -37,16 +95,44 @@ impl Ord for MemoryMapItem {
 
 impl MemoryMapItem {
     pub(crate) fn new(
+        name: impl Into<String>,
         start: WordType,
         size: WordType,
         device: Rc<RefCell<dyn DeviceTrait>>,
     ) -> Self {
         Self {
+            name: name.into(),
             start,
             size,
             device,
+            pma: Pma::IO,
+            accesses: AccessCounters::default(),
         }
     }
+
+    /// Fault the accesses of other widths than `widths`, see
+    /// [`MemMappedDeviceTrait::access_widths`](crate::device::MemMappedDeviceTrait::access_widths).
+    pub(crate) fn widths(mut self, widths: AccessWidths) -> Self {
+        self.pma.widths = widths;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionKind {
+    Ram,
+    Device,
+}
+
+/// A range of the guest physical address space, as shown by `info mtree`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub start: WordType,
+    pub size: WordType,
+    pub kind: RegionKind,
+    pub pma: Pma,
 }
 
 /// # mmio
@@ -62,6 +148,9 @@ impl MemoryMapItem {
 pub struct MemoryMapIO {
     map: Vec<MemoryMapItem>,
     ram: Rc<UnsafeCell<Ram>>,
+    /// Attributes of the board description, given to the devices mapped later as well.
+    pma_overrides: Vec<PmaOverride>,
+    pub(crate) tracer: Option<Box<MmioTracer>>,
 }
 
 impl MemoryMapIO {
@@ -142,8 +231,11 @@ impl MemoryMapIO {
                     .load_reserved(p_addr - ram_config::BASE_ADDR)
             };
         }
-        // Fallback for MMIO: treat as normal read, no reservation
-        self.read_by_type(p_addr)
+        // No reservation is kept outside of RAM, a reservable device only sees the read.
+        match self.pma(p_addr) {
+            Some(pma) if pma.reservable => self.read_by_type(p_addr),
+            _ => Err(MemError::LoadFault),
+        }
     }
 
     pub fn store_conditional<T>(&mut self, p_addr: WordType, data: T) -> Result<bool, MemError>
@@ -157,32 +249,178 @@ impl MemoryMapIO {
                     .store_conditional(p_addr - ram_config::BASE_ADDR, data)
             };
         }
-        // Fallback for MMIO: always fail SC
-        Ok(false)
+        // Outside of RAM, an SC always fails.
+        match self.pma(p_addr) {
+            Some(pma) if pma.reservable => Ok(false),
+            _ => Err(MemError::StoreFault),
+        }
+    }
+
+    /// The attributes at `p_addr`, `None` if nothing is mapped there.
+    pub fn pma(&self, p_addr: WordType) -> Option<Pma> {
+        if p_addr >= ram_config::BASE_ADDR {
+            return Some(Pma::MAIN_MEMORY);
+        }
+        let i = self.map.partition_point(|item| item.start <= p_addr);
+        let item = &self.map[i.checked_sub(1)?];
+        (p_addr - item.start < item.size).then_some(item.pma)
+    }
+
+    /// Apply the attributes of a board description to the devices in their ranges.
+    pub(crate) fn set_pma_overrides(&mut self, overrides: Vec<PmaOverride>) {
+        for item in self.map.iter_mut() {
+            apply_overrides(item, &overrides);
+        }
+        self.pma_overrides = overrides;
     }
 
     pub fn from_mmio_items(ram: Rc<UnsafeCell<Ram>>, mut map: Vec<MemoryMapItem>) -> Self {
         map.sort();
-        Self { map, ram }
+        Self {
+            map,
+            ram,
+            pma_overrides: Vec::new(),
+            tracer: None,
+        }
+    }
+
+    /// Map a device at runtime. Returns the item back if it overlaps an existing one.
+    pub(crate) fn add_item(&mut self, mut item: MemoryMapItem) -> Result<(), MemoryMapItem> {
+        apply_overrides(&mut item, &self.pma_overrides);
+        let i = self.map.partition_point(|it| it.start < item.start);
+        let overlaps_prev = i > 0 && {
+            let prev = &self.map[i - 1];
+            prev.start + prev.size > item.start
+        };
+        let overlaps_next = self
+            .map
+            .get(i)
+            .is_some_and(|next| item.start + item.size > next.start);
+        if overlaps_prev || overlaps_next {
+            return Err(item);
+        }
+
+        self.map.insert(i, item);
+        Ok(())
+    }
+
+    /// Unmap the device starting at `start`.
+    pub(crate) fn remove_item(&mut self, start: WordType) -> Option<MemoryMapItem> {
+        let i = self.map.binary_search_by_key(&start, |it| it.start).ok()?;
+        Some(self.map.remove(i))
+    }
+
+    /// The physical address map: every mapped device, then the RAM.
+    pub fn regions(&self) -> Vec<MemoryRegion> {
+        let devices = self.map.iter().map(|item| MemoryRegion {
+            name: item.name.clone(),
+            start: item.start,
+            size: item.size,
+            kind: RegionKind::Device,
+            pma: item.pma,
+        });
+        let ram = MemoryRegion {
+            name: "ram".to_string(),
+            start: ram_config::BASE_ADDR,
+            size: ram_config::SIZE as WordType,
+            kind: RegionKind::Ram,
+            pma: Pma::MAIN_MEMORY,
+        };
+        devices.chain(std::iter::once(ram)).collect()
+    }
+
+    /// Counters of every mapped device, in address order.
+    pub fn device_stats(&self) -> Vec<DeviceStats> {
+        self.map
+            .iter()
+            .map(|item| {
+                let mut stats = DeviceStats {
+                    name: item.name.clone(),
+                    base: item.start,
+                    size: item.size,
+                    reads: item.accesses.reads,
+                    writes: item.accesses.writes,
+                    bytes_read: item.accesses.bytes_read,
+                    bytes_written: item.accesses.bytes_written,
+                    ..Default::default()
+                };
+                item.device.borrow_mut().report_stats(&mut stats);
+                stats
+            })
+            .collect()
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<MmioTracer>) {
+        self.tracer = tracer.map(Box::new);
+    }
+
+    #[cold]
+    fn trace(
+        &mut self,
+        kind: MmioAccessKind,
+        device_index: usize,
+        p_addr: WordType,
+        size: u32,
+        value: u64,
+        fault: Option<MemError>,
+    ) {
+        let item = &self.map[device_index];
+        let Some(tracer) = self.tracer.as_mut() else {
+            return;
+        };
+        if !tracer.is_traced(&item.name) {
+            return;
+        }
+
+        let pc = tracer.pc;
+        tracer.record(&MmioAccess {
+            kind,
+            device: &item.name,
+            offset: p_addr.wrapping_sub(item.start),
+            size,
+            value,
+            pc,
+            fault,
+        });
     }
 
     fn read_from_device<T>(&mut self, device_index: usize, p_addr: WordType) -> Result<T, MemError>
     where
         T: UnsignedInteger,
     {
-        if !check_align::<T>(p_addr) {
-            return Err(MemError::LoadMisaligned);
-        }
-        if !self.can_access::<T>(device_index, p_addr) {
-            return Err(MemError::LoadFault);
+        let rst = if !check_align::<T>(p_addr) {
+            match self.map[device_index].pma.idempotent {
+                true => Err(MemError::LoadMisaligned),
+                false => Err(MemError::LoadFault),
+            }
+        } else if !self.can_access::<T>(device_index, p_addr) {
+            Err(MemError::LoadFault)
+        } else {
+            let item = &mut self.map[device_index];
+            item.accesses.reads += 1;
+            item.accesses.bytes_read += size_of::<T>() as u64;
+            item.device
+                .borrow_mut()
+                .read(p_addr - item.start, size_of::<T>() as u32)
+        };
+
+        if self.tracer.is_some() {
+            let (value, fault) = match &rst {
+                Ok(value) => (*value, None),
+                Err(err) => (0, Some(*err)),
+            };
+            let size = size_of::<T>() as u32;
+            self.trace(
+                MmioAccessKind::Read,
+                device_index,
+                p_addr,
+                size,
+                value,
+                fault,
+            );
         }
 
-        let start = self.map[device_index].start;
-        let device = &mut self.map[device_index].device;
-        device
-            .borrow_mut()
-            .read(p_addr - start, size_of::<T>() as u32)
-            .map(|x| x.truncate_to())
+        rst.map(|x| x.truncate_to())
     }
 
     // write data to specific device.
@@ -195,22 +433,98 @@ impl MemoryMapIO {
     where
         T: UnsignedInteger,
     {
-        if !check_align::<T>(p_addr) {
-            return Err(MemError::StoreMisaligned);
+        let rst = if !check_align::<T>(p_addr) {
+            match self.map[device_index].pma.idempotent {
+                true => Err(MemError::StoreMisaligned),
+                false => Err(MemError::StoreFault),
+            }
+        } else if !self.can_access::<T>(device_index, p_addr) {
+            Err(MemError::StoreFault)
+        } else {
+            let item = &mut self.map[device_index];
+            item.accesses.writes += 1;
+            item.accesses.bytes_written += size_of::<T>() as u64;
+            item.device.borrow_mut().write(
+                p_addr - item.start,
+                size_of::<T>() as u32,
+                data.truncate_to(),
+            )
+        };
+
+        if self.tracer.is_some() {
+            let size = size_of::<T>() as u32;
+            let fault = rst.as_ref().err().copied();
+            self.trace(
+                MmioAccessKind::Write,
+                device_index,
+                p_addr,
+                size,
+                data.truncate_to(),
+                fault,
+            );
         }
-        if !self.can_access::<T>(device_index, p_addr) {
+
+        rst
+    }
+
+    /// An AMO on the device at `p_addr`, which replaces the value with `f` of it. Returns the old
+    /// value. The caller checks the PMAs.
+    pub fn atomic_rmw<T>(
+        &mut self,
+        p_addr: WordType,
+        mut f: impl FnMut(T) -> T,
+    ) -> Result<T, MemError>
+    where
+        T: UnsignedInteger,
+    {
+        let Some(device_index) = self
+            .map
+            .partition_point(|item| item.start <= p_addr)
+            .checked_sub(1)
+        else {
             return Err(MemError::StoreFault);
+        };
+
+        let size = size_of::<T>() as u32;
+        let mut stored = 0;
+        let rst = if !check_align::<T>(p_addr) {
+            Err(MemError::StoreMisaligned)
+        } else if !self.can_access::<T>(device_index, p_addr) {
+            Err(MemError::StoreFault)
+        } else {
+            let item = &mut self.map[device_index];
+            item.accesses.reads += 1;
+            item.accesses.bytes_read += size as u64;
+            item.accesses.writes += 1;
+            item.accesses.bytes_written += size as u64;
+            item.device
+                .borrow_mut()
+                .atomic_rmw(p_addr - item.start, size, &mut |old| {
+                    stored = f(old.truncate_to()).into();
+                    stored
+                })
+        };
+
+        if self.tracer.is_some() {
+            let fault = rst.as_ref().err().copied();
+            self.trace(
+                MmioAccessKind::Amo,
+                device_index,
+                p_addr,
+                size,
+                stored,
+                fault,
+            );
         }
 
-        let start = self.map[device_index].start;
-        let device = &mut self.map[device_index].device;
-        device
-            .borrow_mut()
-            .write(p_addr - start, size_of::<T>() as u32, data.truncate_to())
+        rst.map(|x| x.truncate_to())
     }
 
     fn can_access<T>(&self, device_index: usize, p_addr: WordType) -> bool {
         let item = &self.map[device_index];
+        if !item.pma.widths.allows(size_of::<T>() as u32) {
+            return false;
+        }
         let Some(offset) = p_addr.checked_sub(item.start) else {
             return false;
         };
@@ -220,6 +534,12 @@ impl MemoryMapIO {
     }
 }
 
+fn apply_overrides(item: &mut MemoryMapItem, overrides: &[PmaOverride]) {
+    for entry in overrides.iter().filter(|entry| entry.covers(item.start)) {
+        item.pma = entry.apply(item.pma);
+    }
+}
+
 impl DeviceTrait for MemoryMapIO {
     dispatch_read_write! { read_by_type, write_by_type }
 
@@ -237,6 +557,7 @@ impl DeviceTrait for MemoryMapIO {
 #[cfg(test)]
 mod test {
     use crate::{
+        board::BoardControl,
         byte_io::ByteSource,
         device::{
             config::{POWER_MANAGER_BASE, POWER_MANAGER_SIZE, UART_BASE, UART_SIZE},
@@ -252,14 +573,15 @@ mod test {
         let ram = Rc::new(UnsafeCell::new(Ram::new()));
 
         let (uart1, _port) = FastUart16550::new();
-        let power_manager = PowerManager::new();
+        let power_manager = PowerManager::new(BoardControl::default());
         let table = vec![
             MemoryMapItem::new(
+                "power",
                 POWER_MANAGER_BASE,
                 POWER_MANAGER_SIZE,
                 Rc::new(RefCell::new(power_manager)),
             ),
-            MemoryMapItem::new(UART_BASE, UART_SIZE, Rc::new(RefCell::new(uart1))),
+            MemoryMapItem::new("uart0", UART_BASE, UART_SIZE, Rc::new(RefCell::new(uart1))),
         ];
 
         let mut mmio = MemoryMapIO::from_mmio_items(ram, table);
@@ -271,8 +593,10 @@ mod test {
         for i in 0 as WordType..100 {
             assert_eq!(
                 i,
-                mmio.read_by_type(ram_config::BASE_ADDR + i * (1 << size_of::<WordType>()))
-                    .unwrap()
+                mmio.read_by_type::<WordType>(
+                    ram_config::BASE_ADDR + i * (1 << size_of::<WordType>())
+                )
+                .unwrap()
             );
         }
     }
@@ -281,14 +605,15 @@ mod test {
     fn mmio_stdout_test() {
         let ram = Rc::new(UnsafeCell::new(Ram::new()));
         let (uart1, mut port1) = FastUart16550::new();
-        let power_manager = PowerManager::new();
+        let power_manager = PowerManager::new(BoardControl::default());
         let table = vec![
             MemoryMapItem::new(
+                "power",
                 POWER_MANAGER_BASE,
                 POWER_MANAGER_SIZE,
                 Rc::new(RefCell::new(power_manager)),
             ),
-            MemoryMapItem::new(UART_BASE, UART_SIZE, Rc::new(RefCell::new(uart1))),
+            MemoryMapItem::new("uart0", UART_BASE, UART_SIZE, Rc::new(RefCell::new(uart1))),
         ];
 
         let mut mmio = MemoryMapIO::from_mmio_items(ram, table);
@@ -319,10 +644,57 @@ mod test {
         }
     }
 
+    struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+    impl std::io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mmio_trace_test() {
+        let ram = Rc::new(UnsafeCell::new(Ram::new()));
+        let table = vec![
+            MemoryMapItem::new("mock0", 0x1000, 0x10, Rc::new(RefCell::new(MockDevice))),
+            MemoryMapItem::new("other0", 0x2000, 0x10, Rc::new(RefCell::new(MockDevice))),
+        ];
+        let mut mmio = MemoryMapIO::from_mmio_items(ram, table);
+
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut tracer = MmioTracer::to_writer(Box::new(SharedWriter(output.clone())))
+            .filter(vec!["mock".to_string()]);
+        tracer.pc = 0x8000_0000;
+        mmio.set_tracer(Some(tracer));
+
+        mmio.write_by_type::<u32>(0x1004, 0x55).unwrap();
+        mmio.read_by_type::<u8>(0x1008).unwrap();
+        mmio.read_by_type::<u32>(0x2000).unwrap();
+        assert!(mmio.read_by_type::<u32>(0x1002).is_err());
+        // RAM is never traced.
+        mmio.write_by_type::<u32>(ram_config::BASE_ADDR, 1).unwrap();
+
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            vec![
+                "W mock0+0x4 [4] = 0x55 @ pc = 0x80000000",
+                "R mock0+0x8 [1] = 0x0 @ pc = 0x80000000",
+                "R mock0+0x2 [4] = 0x0 @ pc = 0x80000000 (LoadFault)",
+            ]
+        );
+    }
+
     #[test]
     fn mmio_rejects_accesses_crossing_device_end() {
         let ram = Rc::new(UnsafeCell::new(Ram::new()));
         let table = vec![MemoryMapItem::new(
+            "mock",
             0x1000,
             4,
             Rc::new(RefCell::new(MockDevice)),
@@ -338,4 +710,202 @@ mod test {
             Err(MemError::StoreFault)
         );
     }
+
+    #[test]
+    fn mmio_rejects_undeclared_widths() {
+        let ram = Rc::new(UnsafeCell::new(Ram::new()));
+        let table = vec![
+            MemoryMapItem::new("mock0", 0x1000, 0x10, Rc::new(RefCell::new(MockDevice)))
+                .widths(AccessWidths::of(&[4, 8])),
+        ];
+        let mut mmio = MemoryMapIO::from_mmio_items(ram, table);
+
+        assert_eq!(mmio.read_by_type::<u32>(0x1004), Ok(0));
+        assert_eq!(mmio.write_by_type::<u64>(0x1008, 0), Ok(()));
+        assert_eq!(mmio.read_by_type::<u8>(0x1004), Err(MemError::LoadFault));
+        assert_eq!(
+            mmio.write_by_type::<u16>(0x1004, 0),
+            Err(MemError::StoreFault)
+        );
+        // Alignment is checked first, a misaligned access to I/O faults.
+        assert_eq!(mmio.read_by_type::<u32>(0x1002), Err(MemError::LoadFault));
+
+        let stats = mmio.device_stats();
+        assert_eq!((stats[0].reads, stats[0].writes), (1, 1));
+    }
+
+    #[test]
+    fn mmio_pma_test() {
+        use crate::device::pma::AmoClass;
+
+        let ram = Rc::new(UnsafeCell::new(Ram::new()));
+        let mock =
+            |start| MemoryMapItem::new("mock", start, 0x10, Rc::new(RefCell::new(MockDevice)));
+        let mut mmio = MemoryMapIO::from_mmio_items(ram, vec![mock(0x1000), mock(0x2000)]);
+        mmio.set_pma_overrides(vec![PmaOverride {
+            base: 0x2000,
+            size: 0x1000,
+            cacheable: None,
+            idempotent: Some(true),
+            widths: None,
+            amo: Some(AmoClass::Swap),
+            reservable: Some(true),
+        }]);
+
+        assert_eq!(mmio.pma(0x1000), Some(Pma::IO));
+        assert_eq!(mmio.pma(0x1010), None);
+        assert_eq!(mmio.pma(ram_config::BASE_ADDR), Some(Pma::MAIN_MEMORY));
+        assert_eq!(mmio.pma(0x2008).unwrap().amo, AmoClass::Swap);
+
+        // Misaligned accesses may be emulated in idempotent regions only.
+        assert_eq!(
+            mmio.write_by_type::<u16>(0x1001, 0),
+            Err(MemError::StoreFault)
+        );
+        assert_eq!(
+            mmio.read_by_type::<u16>(0x2001),
+            Err(MemError::LoadMisaligned)
+        );
+
+        assert_eq!(mmio.load_reserved::<u32>(0x1000), Err(MemError::LoadFault));
+        assert_eq!(
+            mmio.store_conditional::<u32>(0x1000, 0),
+            Err(MemError::StoreFault)
+        );
+        assert_eq!(mmio.load_reserved::<u32>(0x2000), Ok(0));
+        assert_eq!(mmio.store_conditional::<u32>(0x2000, 0), Ok(false));
+
+        // Devices mapped later get the attributes of their range too.
+        assert!(mmio.add_item(mock(0x2800)).is_ok());
+        assert!(mmio.pma(0x2800).unwrap().idempotent);
+    }
+
+    #[test]
+    fn mmio_atomic_rmw_test() {
+        let ram = Rc::new(UnsafeCell::new(Ram::new()));
+        let table = vec![
+            MemoryMapItem::new(
+                "uart0",
+                UART_BASE,
+                UART_SIZE,
+                Rc::new(RefCell::new(MockDevice)),
+            )
+            .widths(AccessWidths::of(&[4])),
+        ];
+        let mut mmio = MemoryMapIO::from_mmio_items(ram, table);
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut tracer = MmioTracer::to_writer(Box::new(SharedWriter(output.clone())));
+        tracer.pc = 0x8000_0000;
+        mmio.set_tracer(Some(tracer));
+
+        // The default is a read, then a write of the new value.
+        assert_eq!(mmio.atomic_rmw(UART_BASE, |old: u32| old + 7), Ok(0));
+        assert_eq!(
+            mmio.atomic_rmw(UART_BASE, |old: u64| old),
+            Err(MemError::StoreFault)
+        );
+        assert_eq!(
+            mmio.atomic_rmw(0x10, |old: u32| old),
+            Err(MemError::StoreFault)
+        );
+
+        let stats = mmio.device_stats();
+        assert_eq!((stats[0].reads, stats[0].writes), (1, 1));
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        assert_eq!(
+            output.lines().next(),
+            Some("A uart0+0x0 [4] = 0x7 @ pc = 0x80000000")
+        );
+    }
+
+    #[test]
+    fn access_widths_test() {
+        assert!((1..=8).all(|size| AccessWidths::ANY.allows(size) == size.is_power_of_two()));
+        let word = AccessWidths::of(&[4]);
+        assert!(word.allows(4));
+        assert!(!word.allows(1) && !word.allows(8) && !word.allows(12));
+    }
+
+    #[test]
+    fn mmio_hotplug_test() {
+        let ram = Rc::new(UnsafeCell::new(Ram::new()));
+        let table = vec![MemoryMapItem::new(
+            "mock0",
+            0x1000,
+            0x10,
+            Rc::new(RefCell::new(MockDevice)),
+        )];
+        let mut mmio = MemoryMapIO::from_mmio_items(ram, table);
+        assert_eq!(mmio.read_by_type::<u32>(0x2000), Err(MemError::LoadFault));
+
+        let mock =
+            |start| MemoryMapItem::new("mock", start, 0x10, Rc::new(RefCell::new(MockDevice)));
+        assert!(mmio.add_item(mock(0x1008)).is_err());
+        assert!(mmio.add_item(mock(0x0ff8)).is_err());
+        assert!(mmio.add_item(mock(0x2000)).is_ok());
+        assert!(mmio.add_item(mock(0x0ff0)).is_ok());
+        assert_eq!(mmio.read_by_type::<u32>(0x2000), Ok(0));
+
+        assert!(mmio.remove_item(0x2004).is_none());
+        assert_eq!(mmio.remove_item(0x2000).unwrap().start, 0x2000);
+        assert_eq!(mmio.read_by_type::<u32>(0x2000), Err(MemError::LoadFault));
+        assert_eq!(mmio.read_by_type::<u32>(0x1000), Ok(0));
+    }
+
+    #[test]
+    fn mmio_stats_test() {
+        let ram = Rc::new(UnsafeCell::new(Ram::new()));
+        let table = vec![
+            MemoryMapItem::new("mock0", 0x1000, 0x10, Rc::new(RefCell::new(MockDevice))),
+            MemoryMapItem::new("other0", 0x2000, 0x10, Rc::new(RefCell::new(MockDevice))),
+        ];
+        let mut mmio = MemoryMapIO::from_mmio_items(ram, table);
+
+        mmio.write_by_type::<u32>(0x1004, 0x55).unwrap();
+        mmio.read_by_type::<u8>(0x1008).unwrap();
+        mmio.read_by_type::<u16>(0x1008).unwrap();
+        // Faulting accesses never reach the device.
+        assert!(mmio.read_by_type::<u32>(0x1002).is_err());
+
+        let stats = mmio.device_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].name, "mock0");
+        assert_eq!((stats[0].reads, stats[0].bytes_read), (2, 3));
+        assert_eq!((stats[0].writes, stats[0].bytes_written), (1, 4));
+        assert_eq!(stats[0].irq, None);
+        assert_eq!((stats[1].reads, stats[1].writes), (0, 0));
+    }
+
+    #[test]
+    fn mmio_regions_test() {
+        let ram = Rc::new(UnsafeCell::new(Ram::new()));
+        let table = vec![
+            MemoryMapItem::new("other0", 0x2000, 0x10, Rc::new(RefCell::new(MockDevice))),
+            MemoryMapItem::new("mock0", 0x1000, 0x8, Rc::new(RefCell::new(MockDevice))),
+        ];
+        let mut mmio = MemoryMapIO::from_mmio_items(ram, table);
+        let hotplug =
+            MemoryMapItem::new("hotplug0", 0x3000, 0x100, Rc::new(RefCell::new(MockDevice)));
+        assert!(mmio.add_item(hotplug).is_ok());
+
+        let regions = mmio.regions();
+        let summary: Vec<_> = regions
+            .iter()
+            .map(|r| (r.name.as_str(), r.start, r.size, r.kind))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("mock0", 0x1000, 0x8, RegionKind::Device),
+                ("other0", 0x2000, 0x10, RegionKind::Device),
+                ("hotplug0", 0x3000, 0x100, RegionKind::Device),
+                (
+                    "ram",
+                    ram_config::BASE_ADDR,
+                    ram_config::SIZE as WordType,
+                    RegionKind::Ram
+                ),
+            ]
+        );
+    }
 }
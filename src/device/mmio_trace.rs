@@ -0,0 +1,118 @@
+//! Tracing of guest accesses to memory-mapped devices.
+//!
+//! Attach a [`MmioTracer`] to the CPU with
+//! [`RVCPU::set_mmio_tracer`](crate::isa::riscv::executor::RVCPU::set_mmio_tracer),
+//! then every device read/write going through [`MemoryMapIO`](super::mmio::MemoryMapIO)
+//! is reported with the device name, offset, size, value and the `pc` of the access.
+//! RAM accesses are never traced.
+
+use std::{fs::File, io::BufWriter, io::Write, path::Path};
+
+use crate::{config::arch_config::WordType, device::MemError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioAccessKind {
+    Read,
+    Write,
+    /// An AMO, the value is the one stored.
+    Amo,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MmioAccess<'a> {
+    pub kind: MmioAccessKind,
+    pub device: &'a str,
+    pub offset: WordType,
+    pub size: u32,
+    /// The value read or written, zero for failed reads.
+    pub value: u64,
+    pub pc: WordType,
+    pub fault: Option<MemError>,
+}
+
+impl std::fmt::Display for MmioAccess<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            MmioAccessKind::Read => 'R',
+            MmioAccessKind::Write => 'W',
+            MmioAccessKind::Amo => 'A',
+        };
+        write!(
+            f,
+            "{} {}+{:#x} [{}] = {:#x} @ pc = {:#x}",
+            kind, self.device, self.offset, self.size, self.value, self.pc
+        )?;
+        if let Some(fault) = &self.fault {
+            write!(f, " ({:?})", fault)?;
+        }
+        Ok(())
+    }
+}
+
+enum MmioTraceSink {
+    Log,
+    Writer(Box<dyn Write>),
+}
+
+pub struct MmioTracer {
+    sink: MmioTraceSink,
+    /// Device name prefixes to trace, trace every device when empty.
+    filter: Vec<String>,
+    /// The `pc` of the instruction currently executing, updated by the CPU.
+    pub(crate) pc: WordType,
+}
+
+impl MmioTracer {
+    /// Trace to the log, with target `mmio`.
+    pub fn to_log() -> Self {
+        Self::new(MmioTraceSink::Log)
+    }
+
+    /// Trace to a dedicated file, one access per line.
+    pub fn to_file(path: &Path) -> std::io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Self::to_writer(Box::new(file)))
+    }
+
+    pub fn to_writer(writer: Box<dyn Write>) -> Self {
+        Self::new(MmioTraceSink::Writer(writer))
+    }
+
+    fn new(sink: MmioTraceSink) -> Self {
+        Self {
+            sink,
+            filter: Vec::new(),
+            pc: 0,
+        }
+    }
+
+    /// Only trace devices whose name starts with one of `devices`,
+    /// e.g. `"virtio"` matches `virtio0` and `virtio1`.
+    pub fn filter(mut self, devices: Vec<String>) -> Self {
+        self.filter = devices;
+        self
+    }
+
+    pub(crate) fn is_traced(&self, device: &str) -> bool {
+        self.filter.is_empty() || self.filter.iter().any(|f| device.starts_with(f.as_str()))
+    }
+
+    pub(crate) fn record(&mut self, access: &MmioAccess) {
+        match &mut self.sink {
+            MmioTraceSink::Log => log::info!(target: "mmio", "{}", access),
+            MmioTraceSink::Writer(w) => {
+                if let Err(e) = writeln!(w, "{}", access) {
+                    log::warn!("Failed to write MMIO trace: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for MmioTracer {
+    fn drop(&mut self) {
+        if let MmioTraceSink::Writer(w) = &mut self.sink {
+            let _ = w.flush();
+        }
+    }
+}
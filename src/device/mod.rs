@@ -1,4 +1,8 @@
-use crate::{config::arch_config::WordType, device_poller::PollingEventTrait};
+use crate::{
+    config::arch_config::WordType,
+    device::plic::irq_line::{IrqDescriptor, IrqPin},
+    device_poller::PollingEventTrait,
+};
 
 macro_rules! dispatch_read_write {
     ($read_impl: ident, $write_impl: ident) => {
@@ -38,17 +42,38 @@ macro_rules! dispatch_read_write {
 }
 
 pub(crate) mod aclint;
+pub(crate) mod cfi_flash;
 pub(crate) mod config;
+pub mod dma_engine;
+pub mod eeprom;
+pub mod entropy;
 pub mod fast_uart;
+pub mod hypercall;
 mod id_allocator;
+pub mod lm75;
 pub(crate) use id_allocator::*;
 pub(crate) mod mmio;
+pub mod mmio_trace;
+pub mod ocores_i2c;
 pub(crate) mod plic;
+pub mod pma;
 pub(crate) mod power_manager;
+pub(crate) mod riscv_iommu;
+pub mod scripted;
+pub(crate) mod sd_card;
+pub mod sifive_gpio;
+pub mod sifive_pwm;
+pub mod sifive_spi;
+pub mod stats;
 pub(crate) mod test_device;
 pub(crate) mod virtio;
+pub mod watchdog;
 
-#[derive(Debug, PartialEq, Eq)]
+pub use mmio::{AccessWidths, MemoryRegion, RegionKind};
+pub use plic::storm::{Storm, StormConfig};
+pub use virtio::block_backend::FaultConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MemError {
     LoadPageFault,
     LoadMisaligned,
@@ -56,6 +81,25 @@ pub enum MemError {
     StorePageFault,
     StoreMisaligned,
     StoreFault,
+    /// Instruction address misaligned, at the virtual address of the fetch.
+    ExecMisaligned(WordType),
+    /// Instruction access fault, at the virtual address of the fetch.
+    ExecFault(WordType),
+    /// Instruction page fault, at the virtual address of the fetch.
+    ExecPageFault(WordType),
+}
+
+impl MemError {
+    /// The faulting address, which instruction fetches report: the half of an instruction
+    /// crossing a page boundary that faulted, not the `pc`, goes to `xtval`.
+    pub fn addr(&self) -> Option<WordType> {
+        match self {
+            MemError::ExecMisaligned(addr)
+            | MemError::ExecFault(addr)
+            | MemError::ExecPageFault(addr) => Some(*addr),
+            _ => None,
+        }
+    }
 }
 
 macro_rules! impl_read_for_type {
@@ -94,11 +138,53 @@ pub trait DeviceTrait {
     impl_write_for_type! { u32 }
     impl_write_for_type! { u64 }
 
+    /// Replace the `len` bytes at `addr` with `f` of their value and return the old value, for
+    /// an AMO the PMAs allow on the device. A read then a write by default: the hart is the only
+    /// one accessing the device while it runs, so nothing comes in between. A device whose reads
+    /// have side effects overrides it when the AMO must not trigger them.
+    fn atomic_rmw(
+        &mut self,
+        addr: WordType,
+        len: u32,
+        f: &mut dyn FnMut(u64) -> u64,
+    ) -> Result<u64, MemError> {
+        let old = self.read(addr, len)?;
+        self.write(addr, len, f(old))?;
+        Ok(old)
+    }
+
     fn sync(&mut self);
     fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>>;
+
+    /// Hand the device its interrupt output, see [`MemMappedDeviceTrait::irq`].
+    fn connect_irq(&mut self, _pin: IrqPin) {}
+
+    /// Apply the results of jobs offloaded to the [`WorkQueue`](crate::work_queue::WorkQueue).
+    /// Called between instructions once any job completed.
+    fn complete_work(&mut self) {}
+
+    /// Fill in the device specific counters, the memory map ones are already set.
+    fn report_stats(&mut self, _stats: &mut stats::DeviceStats) {}
+
+    /// Return to the power-on state, called when the board resets. Backing files and host
+    /// connections are kept.
+    fn reset(&mut self) {}
 }
 
 pub trait MemMappedDeviceTrait: DeviceTrait {
+    fn name() -> &'static str;
     fn base() -> WordType;
     fn size() -> WordType;
+
+    /// The access widths of the registers. The memory map faults the others, so the device does
+    /// not have to check them.
+    fn access_widths() -> AccessWidths {
+        AccessWidths::ANY
+    }
+
+    /// The PLIC source the device drives, if any. The board wires it up through
+    /// [`DeviceTrait::connect_irq`].
+    fn irq() -> Option<IrqDescriptor> {
+        None
+    }
 }
@@ -0,0 +1,361 @@
+//! OpenCores I2C master, the controller of the SiFive FU540 (`sifive,i2c0`, driven by Linux
+//! `i2c-ocores`), with devices such as an [`I2cEeprom`](crate::device::eeprom::I2cEeprom) or an
+//! [`Lm75`](crate::device::lm75::Lm75) at their 7-bit addresses.
+//!
+//! The registers are spaced by 4 bytes (`reg-shift = <2>`), only their low byte is used:
+//!
+//! | Offset | Name      | Description                                                        |
+//! |--------|-----------|--------------------------------------------------------------------|
+//! | 0x00   | PRERlo    | Clock prescale, low byte                                           |
+//! | 0x04   | PRERhi    | Clock prescale, high byte                                          |
+//! | 0x08   | CTR       | Bit 7 enables the core, bit 6 the interrupt                        |
+//! | 0x0c   | TXR / RXR | Byte to transmit (write), byte received (read)                     |
+//! | 0x10   | CR / SR   | Command (write): `STA`, `STO`, `RD`, `WR`, `ACK`, `IACK`; status (read) |
+//!
+//! Commands complete instantly: `TIP` never reads as set, and `IF` is set right away.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    config::arch_config::WordType,
+    device::{
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{I2C_BASE, I2C_IRQ, I2C_NAME, I2C_SIZE},
+        plic::irq_line::{IrqDescriptor, IrqPin},
+        stats::DeviceStats,
+    },
+    device_poller::PollingEventTrait,
+};
+
+/// A device on the I2C bus.
+pub trait I2cSlave {
+    /// Addressed after a (repeated) start condition, in read or write direction. Returns whether
+    /// the device acknowledges.
+    fn start(&mut self, _read: bool) -> bool {
+        true
+    }
+
+    /// Receive `byte` from the master, returns whether the device acknowledges it.
+    fn write(&mut self, byte: u8) -> bool;
+
+    /// Send the next byte to the master.
+    fn read(&mut self) -> u8;
+
+    /// Called on a stop condition ending a transfer to this device.
+    fn stop(&mut self) {}
+}
+
+const PRERLO: WordType = 0x00;
+const PRERHI: WordType = 0x04;
+const CTR: WordType = 0x08;
+const TXR: WordType = 0x0c;
+const CR: WordType = 0x10;
+
+const CTR_EN: u8 = 1 << 7;
+const CTR_IEN: u8 = 1 << 6;
+
+const CR_STA: u8 = 1 << 7;
+const CR_STO: u8 = 1 << 6;
+const CR_RD: u8 = 1 << 5;
+const CR_WR: u8 = 1 << 4;
+/// Answer the byte read with a NACK, it is the last one.
+const CR_ACK: u8 = 1 << 3;
+const CR_IACK: u8 = 1 << 0;
+
+/// No acknowledge from the device.
+const SR_RXACK: u8 = 1 << 7;
+const SR_BUSY: u8 = 1 << 6;
+const SR_IF: u8 = 1 << 0;
+
+pub struct OcoresI2c {
+    slaves: BTreeMap<u8, Box<dyn I2cSlave>>,
+    /// The address of the device taking part in the current transfer.
+    target: Option<u8>,
+    /// A start condition was sent, the next byte written is an address.
+    addressing: bool,
+    busy: bool,
+
+    prescale: u16,
+    ctr: u8,
+    txr: u8,
+    rxr: u8,
+    rx_nack: bool,
+    irq_flag: bool,
+
+    irq: Option<IrqPin>,
+}
+
+impl OcoresI2c {
+    pub fn new() -> Self {
+        Self {
+            slaves: BTreeMap::new(),
+            target: None,
+            addressing: false,
+            busy: false,
+            prescale: 0xffff,
+            ctr: 0,
+            txr: 0,
+            rxr: 0,
+            rx_nack: false,
+            irq_flag: false,
+            irq: None,
+        }
+    }
+
+    /// Attach `slave` at the 7-bit address `addr`.
+    pub fn with_slave(mut self, addr: u8, slave: Box<dyn I2cSlave>) -> Self {
+        self.slaves.insert(addr, slave);
+        self
+    }
+
+    fn slave(&mut self) -> Option<&mut Box<dyn I2cSlave>> {
+        self.slaves.get_mut(&self.target?)
+    }
+
+    fn status(&self) -> u8 {
+        let mut sr = if self.irq_flag { SR_IF } else { 0 };
+        if self.rx_nack {
+            sr |= SR_RXACK;
+        }
+        if self.busy {
+            sr |= SR_BUSY;
+        }
+        sr
+    }
+
+    fn update_irq(&self) {
+        if let Some(pin) = &self.irq {
+            pin.set_level(self.ctr & CTR_IEN != 0 && self.irq_flag);
+        }
+    }
+
+    fn command(&mut self, cmd: u8) {
+        if cmd & CR_IACK != 0 {
+            self.irq_flag = false;
+        }
+        if self.ctr & CTR_EN == 0 || cmd & (CR_STA | CR_STO | CR_RD | CR_WR) == 0 {
+            return;
+        }
+
+        if cmd & CR_STA != 0 {
+            self.busy = true;
+            self.addressing = true;
+        }
+        if cmd & CR_WR != 0 {
+            self.rx_nack = !self.transmit(self.txr);
+        }
+        if cmd & CR_RD != 0 {
+            // `CR_ACK` only tells the device whether more bytes are wanted.
+            self.rxr = self.slave().map_or(0xff, |slave| slave.read());
+        }
+        if cmd & CR_STO != 0 {
+            if let Some(slave) = self.slave() {
+                slave.stop();
+            }
+            self.target = None;
+            self.addressing = false;
+            self.busy = false;
+        }
+        self.irq_flag = true;
+    }
+
+    /// Shift out `byte`, returns whether it was acknowledged.
+    fn transmit(&mut self, byte: u8) -> bool {
+        if !self.busy {
+            return false;
+        }
+        if self.addressing {
+            self.addressing = false;
+            let addr = byte >> 1;
+            let read = byte & 1 != 0;
+            let ack = match self.slaves.get_mut(&addr) {
+                Some(slave) => slave.start(read),
+                None => {
+                    log::debug!("[I2C] no device at {:#04x}", addr);
+                    false
+                }
+            };
+            self.target = ack.then_some(addr);
+            return ack;
+        }
+        self.slave().is_some_and(|slave| slave.write(byte))
+    }
+
+    fn read_impl<T>(&mut self, addr: WordType) -> Result<T, MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let value = match addr {
+            PRERLO => self.prescale as u8,
+            PRERHI => (self.prescale >> 8) as u8,
+            CTR => self.ctr,
+            TXR => self.rxr,
+            CR => self.status(),
+            _ => return Err(MemError::LoadFault),
+        };
+        Ok(T::truncate_from(value as u64))
+    }
+
+    fn write_impl<T>(&mut self, addr: WordType, data: T) -> Result<(), MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let data: u8 = data.truncate_to();
+        match addr {
+            PRERLO => self.prescale = (self.prescale & 0xff00) | data as u16,
+            PRERHI => self.prescale = (self.prescale & 0x00ff) | (data as u16) << 8,
+            CTR => self.ctr = data & (CTR_EN | CTR_IEN),
+            TXR => self.txr = data,
+            CR => self.command(data),
+            _ => return Err(MemError::StoreFault),
+        }
+        self.update_irq();
+        Ok(())
+    }
+}
+
+impl Default for OcoresI2c {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceTrait for OcoresI2c {
+    dispatch_read_write! { read_impl, write_impl }
+
+    fn sync(&mut self) {}
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+
+    fn connect_irq(&mut self, pin: IrqPin) {
+        self.irq = Some(pin);
+    }
+
+    fn report_stats(&mut self, stats: &mut DeviceStats) {
+        if let Some(pin) = &self.irq {
+            stats.record_irq(pin);
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(slave) = self.slave() {
+            slave.stop();
+        }
+        self.target = None;
+        self.addressing = false;
+        self.busy = false;
+        self.prescale = 0xffff;
+        self.ctr = 0;
+        self.txr = 0;
+        self.rxr = 0;
+        self.rx_nack = false;
+        self.irq_flag = false;
+        self.update_irq();
+    }
+}
+
+impl MemMappedDeviceTrait for OcoresI2c {
+    fn name() -> &'static str {
+        I2C_NAME
+    }
+    fn base() -> WordType {
+        I2C_BASE
+    }
+    fn size() -> WordType {
+        I2C_SIZE
+    }
+    fn irq() -> Option<IrqDescriptor> {
+        Some(IrqDescriptor::level(I2C_IRQ))
+    }
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[1, 4])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    /// Records what it receives, and sends back a counter.
+    struct Recorder {
+        log: Rc<RefCell<Vec<String>>>,
+        next: u8,
+    }
+
+    impl I2cSlave for Recorder {
+        fn start(&mut self, read: bool) -> bool {
+            self.log.borrow_mut().push(format!("start {read}"));
+            true
+        }
+        fn write(&mut self, byte: u8) -> bool {
+            self.log.borrow_mut().push(format!("write {byte:#x}"));
+            true
+        }
+        fn read(&mut self) -> u8 {
+            self.next += 1;
+            self.next
+        }
+        fn stop(&mut self) {
+            self.log.borrow_mut().push("stop".to_string());
+        }
+    }
+
+    fn send(i2c: &mut OcoresI2c, byte: u8, cmd: u8) -> u8 {
+        i2c.write_u8(TXR, byte).unwrap();
+        i2c.write_u8(CR, cmd | CR_WR).unwrap();
+        i2c.read_u8(CR).unwrap()
+    }
+
+    #[test]
+    fn test_i2c_transfers() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut i2c = OcoresI2c::new().with_slave(
+            0x50,
+            Box::new(Recorder {
+                log: log.clone(),
+                next: 0,
+            }),
+        );
+        let pin = IrqPin::new(IrqDescriptor::level(I2C_IRQ));
+        i2c.connect_irq(pin.clone());
+        i2c.write_u8(CTR, CTR_EN | CTR_IEN).unwrap();
+
+        // Write one byte, then read two after a repeated start.
+        assert_eq!(send(&mut i2c, 0x50 << 1, CR_STA), SR_BUSY | SR_IF);
+        assert!(pin.level());
+        i2c.write_u8(CR, CR_IACK).unwrap();
+        assert!(!pin.level());
+        assert_eq!(send(&mut i2c, 0x12, 0), SR_BUSY | SR_IF);
+        send(&mut i2c, 0x50 << 1 | 1, CR_STA);
+        i2c.write_u8(CR, CR_RD).unwrap();
+        assert_eq!(i2c.read_u8(TXR).unwrap(), 1);
+        i2c.write_u8(CR, CR_RD | CR_STO | CR_ACK).unwrap();
+        assert_eq!(i2c.read_u8(TXR).unwrap(), 2);
+        assert_eq!(i2c.read_u8(CR).unwrap(), SR_IF);
+
+        assert_eq!(
+            *log.borrow(),
+            ["start false", "write 0x12", "start true", "stop"]
+        );
+
+        // Nobody acknowledges another address.
+        assert_eq!(send(&mut i2c, 0x51 << 1, CR_STA | CR_STO), SR_RXACK | SR_IF);
+        assert_eq!(log.borrow().len(), 4);
+    }
+
+    #[test]
+    fn test_i2c_disabled() {
+        let mut i2c = OcoresI2c::new();
+        i2c.write_u32(PRERLO, 0x34).unwrap();
+        i2c.write_u32(PRERHI, 0x12).unwrap();
+        assert_eq!(i2c.read_u32(PRERLO).unwrap(), 0x34);
+        assert_eq!(i2c.read_u32(PRERHI).unwrap(), 0x12);
+
+        // Commands are ignored until the core is enabled.
+        assert_eq!(send(&mut i2c, 0x50 << 1, CR_STA), 0);
+        assert!(i2c.read_u32(0x14).is_err());
+    }
+}
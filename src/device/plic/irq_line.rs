@@ -1,4 +1,9 @@
-use crate::device::plic::ExternalInterrupt;
+use std::sync::{
+    Arc, OnceLock,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{device::plic::ExternalInterrupt, doorbell::Doorbell};
 
 pub trait PlicIRQHandler {
     fn handle_irq(&mut self, interrupt: ExternalInterrupt, level: bool);
@@ -22,3 +27,126 @@ impl PlicIRQLine {
         unsafe { &mut *self.target }.handle_irq(interrupt, level);
     }
 }
+
+/// How the PLIC gateway turns the signal of an [`IrqPin`] into interrupt requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqTrigger {
+    /// A request is forwarded for every rising edge (or pulse), even if the device drops the
+    /// signal before the request is claimed.
+    Edge,
+    /// A request is pending for as long as the signal is asserted, so the interrupt fires again
+    /// after completion unless the device deasserts it.
+    Level,
+}
+
+/// Describes the PLIC source a device is wired to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqDescriptor {
+    pub id: ExternalInterrupt,
+    pub trigger: IrqTrigger,
+}
+
+impl IrqDescriptor {
+    pub const fn level(id: ExternalInterrupt) -> Self {
+        Self {
+            id,
+            trigger: IrqTrigger::Level,
+        }
+    }
+
+    pub const fn edge(id: ExternalInterrupt) -> Self {
+        Self {
+            id,
+            trigger: IrqTrigger::Edge,
+        }
+    }
+}
+
+/// Interrupt output of a device, one end held by the device and the other by the PLIC.
+///
+/// The pin can be driven from any thread, the PLIC samples it on the main thread once a change
+/// rang the doorbell of the board.
+#[derive(Clone)]
+pub struct IrqPin {
+    desc: IrqDescriptor,
+    level: Arc<AtomicBool>,
+    edge: Arc<AtomicBool>,
+    /// Number of rising edges and pulses.
+    raised: Arc<AtomicU64>,
+    /// Set once connected to the PLIC.
+    doorbell: Arc<OnceLock<Doorbell>>,
+}
+
+impl IrqPin {
+    pub fn new(desc: IrqDescriptor) -> Self {
+        Self {
+            desc,
+            level: Arc::new(AtomicBool::new(false)),
+            edge: Arc::new(AtomicBool::new(false)),
+            raised: Arc::new(AtomicU64::new(0)),
+            doorbell: Arc::new(OnceLock::new()),
+        }
+    }
+
+    pub fn id(&self) -> ExternalInterrupt {
+        self.desc.id
+    }
+
+    pub fn trigger(&self) -> IrqTrigger {
+        self.desc.trigger
+    }
+
+    pub fn level(&self) -> bool {
+        self.level.load(Ordering::Acquire)
+    }
+
+    pub fn set_level(&self, level: bool) {
+        let old = self.level.swap(level, Ordering::AcqRel);
+        if level && !old {
+            self.edge.store(true, Ordering::Release);
+            self.raised.fetch_add(1, Ordering::Relaxed);
+        }
+        if level != old {
+            self.ring();
+        }
+    }
+
+    pub fn raise(&self) {
+        self.set_level(true);
+    }
+
+    pub fn lower(&self) {
+        self.set_level(false);
+    }
+
+    /// Raise and immediately lower the signal. Only meaningful for edge-triggered pins.
+    pub fn pulse(&self) {
+        self.edge.store(true, Ordering::Release);
+        self.raised.fetch_add(1, Ordering::Relaxed);
+        self.ring();
+    }
+
+    /// How many times the interrupt was raised so far.
+    pub fn raised(&self) -> u64 {
+        self.raised.load(Ordering::Relaxed)
+    }
+
+    /// Ring `doorbell` whenever the signal changes from now on.
+    pub(super) fn attach(&self, doorbell: &Doorbell) {
+        let _ = self.doorbell.set(doorbell.clone());
+    }
+
+    fn ring(&self) {
+        if let Some(doorbell) = self.doorbell.get() {
+            doorbell.ring();
+        }
+    }
+
+    /// Sample the pin from the PLIC gateway, consuming the latched edge if any.
+    pub(super) fn take_request(&self) -> bool {
+        match self.desc.trigger {
+            IrqTrigger::Level => self.level(),
+            IrqTrigger::Edge => self.edge.swap(false, Ordering::AcqRel),
+        }
+    }
+}
@@ -1,4 +1,5 @@
 pub mod irq_line;
+pub mod storm;
 
 use std::{
     collections::BTreeSet,
@@ -11,7 +12,12 @@ use bit_set::BitSet;
 use crate::{
     board::virt::RiscvIRQSource,
     config::arch_config::WordType,
-    device::{DeviceTrait, MemError, config::PLIC_SIZE, plic::irq_line::PlicIRQHandler},
+    device::{
+        AccessWidths, DeviceTrait, MemError,
+        config::PLIC_SIZE,
+        plic::irq_line::{IrqPin, IrqTrigger, PlicIRQHandler},
+    },
+    doorbell::Doorbell,
 };
 
 const PLIC_MAX_INTERRUPTS: usize = 1024;
@@ -235,13 +241,85 @@ impl PLICLayout {
 pub struct PLIC {
     layout: PLICLayout,
     irq_line: [Option<crate::board::virt::IRQLine>; VIRT_MAX_CONTEXTS],
+    pins: Vec<IrqPin>,
+    /// The sources the guest claimed since the last [`Self::take_claims`], once enabled.
+    claims: Option<Vec<ExternalInterrupt>>,
+    /// Rung when the arbitration must run again: a pin changed or the guest wrote a register.
+    doorbell: Option<Doorbell>,
 }
 
 impl PLIC {
+    /// Every register is 32 bits wide.
+    pub(crate) const ACCESS_WIDTHS: AccessWidths = AccessWidths::of(&[4]);
+
     pub fn new() -> Self {
         PLIC {
             layout: PLICLayout::new(),
             irq_line: core::array::from_fn(|_| None),
+            pins: Vec::new(),
+            claims: None,
+            doorbell: None,
+        }
+    }
+
+    /// Ring `doorbell` when the arbitration must run again, see [`crate::doorbell`].
+    pub fn set_doorbell(&mut self, doorbell: Doorbell) {
+        for pin in self.pins.iter() {
+            pin.attach(&doorbell);
+        }
+        doorbell.ring();
+        self.doorbell = Some(doorbell);
+    }
+
+    fn ring(&self) {
+        if let Some(doorbell) = &self.doorbell {
+            doorbell.ring();
+        }
+    }
+
+    /// Wire a device's interrupt output to its source, see [`Self::sample_pins`].
+    pub fn connect_pin(&mut self, pin: IrqPin) {
+        assert!(
+            Self::is_valid_source(pin.id()),
+            "invalid PLIC source {}",
+            pin.id()
+        );
+        assert!(
+            self.pins.iter().all(|p| p.id() != pin.id()),
+            "PLIC source {} is already connected",
+            pin.id()
+        );
+        if let Some(doorbell) = &self.doorbell {
+            pin.attach(doorbell);
+        }
+        self.pins.push(pin);
+        // The pin may be asserted already.
+        self.ring();
+    }
+
+    /// Unwire the pin of source `id`, dropping its pending request.
+    pub fn disconnect_pin(&mut self, id: ExternalInterrupt) -> Option<IrqPin> {
+        let i = self.pins.iter().position(|p| p.id() == id)?;
+        self.layout.pending.clear_bit(id);
+        self.ring();
+        Some(self.pins.remove(i))
+    }
+
+    /// The gateways: turn the signal of every connected pin into pending bits.
+    ///
+    /// A source being serviced is skipped, so a level-triggered one only fires again after
+    /// completion if it is still asserted, and an edge is held until then.
+    pub fn sample_pins(&mut self) {
+        for pin in self.pins.iter() {
+            let id = pin.id();
+            if self.layout.interrupt_sources_busy.contains(id as usize) {
+                continue;
+            }
+            if pin.take_request() {
+                self.layout.pending.set_bit(id);
+            } else if pin.trigger() == IrqTrigger::Level {
+                self.layout.pending.clear_bit(id);
+            }
         }
     }
 
@@ -249,10 +327,8 @@ impl PLIC {
     where
         T: crate::utils::UnsignedInteger,
     {
-        // check align in MMIO.
-        if core::mem::size_of::<T>() != 4 {
-            return Err(MemError::LoadFault);
-        }
+        // The width is checked in MMIO, see `PLIC::ACCESS_WIDTHS`.
+        debug_assert_eq!(size_of::<T>(), 4);
 
         if inner_addr < PENDING_BIT_OFFSET {
             // priority
@@ -291,6 +367,12 @@ impl PLIC {
                     // Claim/Complete
                     let data = self.layout.contexts[context_id].claim;
                     log::trace!("[PLIC] claim read ctx={} => id={}", context_id, data);
+                    if let Some(claims) = self.claims.as_mut()
+                        && data != 0
+                    {
+                        claims.push(data);
+                        self.ring();
+                    }
                     Ok(unsafe { core::mem::transmute_copy(&data) })
                 } else {
                     Err(MemError::LoadFault)
@@ -307,10 +389,10 @@ impl PLIC {
     where
         T: crate::utils::UnsignedInteger,
     {
-        // check align in MMIO.
-        if core::mem::size_of::<T>() != 4 {
-            return Err(MemError::StoreFault);
-        }
+        // The width is checked in MMIO, see `PLIC::ACCESS_WIDTHS`.
+        debug_assert_eq!(size_of::<T>(), 4);
+        // Priorities, enables, thresholds and completions all change the arbitration.
+        self.ring();
 
         if inner_addr < 0x1000 {
             // priority
@@ -369,8 +451,60 @@ impl PLIC {
         }
     }
 
+    /// Keep the sources the guest claims for [`Self::take_claims`].
+    pub fn track_claims(&mut self) {
+        self.claims.get_or_insert_default();
+    }
+
+    /// The sources claimed since the last call, in order.
+    pub fn take_claims(&mut self) -> Vec<ExternalInterrupt> {
+        self.claims.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// The configured sources and the contexts with an interrupt line, one per line.
+    pub fn dump_state(&self) -> String {
+        let layout = &self.layout;
+        let mut lines = Vec::new();
+        for id in 1..VIRT_MAX_INTERRUPTS as ExternalInterrupt {
+            let contexts: Vec<String> = (0..VIRT_MAX_CONTEXTS)
+                .filter(|&context| layout.get_enable_bit(context, id))
+                .map(|context| context.to_string())
+                .collect();
+            let priority = layout.get_priority(id);
+            let pending = layout.pending.get_bit(id);
+            if priority == 0 && contexts.is_empty() && !pending {
+                continue;
+            }
+            lines.push(format!(
+                "source {id}: priority {priority}, enabled for [{}]{}{}",
+                contexts.join(", "),
+                if pending { ", pending" } else { "" },
+                if layout.interrupt_sources_busy.contains(id as usize) {
+                    ", in service"
+                } else {
+                    ""
+                },
+            ));
+        }
+        for (nr, context) in layout.contexts.iter().enumerate() {
+            if self.irq_line[nr].is_none() {
+                continue;
+            }
+            lines.push(format!(
+                "context {nr}: threshold {}, claimed {}",
+                context.priority_threshold, context.claim
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Source 0 is reserved to mean "no interrupt".
+    pub fn is_valid_source(interrupt_id: ExternalInterrupt) -> bool {
+        interrupt_id != 0 && (interrupt_id as usize) < VIRT_MAX_INTERRUPTS
+    }
+
     pub fn trigger_interrupt(&mut self, interrupt_id: ExternalInterrupt) {
-        if unlikely(interrupt_id > VIRT_MAX_INTERRUPTS as ExternalInterrupt) {
+        if unlikely(interrupt_id >= VIRT_MAX_INTERRUPTS as ExternalInterrupt) {
             return;
         }
         self.layout.pending.set_bit(interrupt_id);
@@ -474,6 +608,14 @@ impl DeviceTrait for PLIC {
     fn sync(&mut self) {
         // nothing to do.
     }
+
+    fn reset(&mut self) {
+        self.layout = PLICLayout::new();
+        for irq_line in self.irq_line.iter_mut().flatten() {
+            irq_line.set_irq(false);
+        }
+        self.ring();
+    }
 }
 
 // Send the external interrupt resulting from the arbitration to the CPU through the IRQLine.
@@ -676,4 +818,80 @@ mod test {
         // context 0 <- None
         assert!(plic.try_get_interrupt(0).is_none());
     }
+
+    #[test]
+    fn source_range_test() {
+        assert!(!PLIC::is_valid_source(0));
+        assert!(PLIC::is_valid_source(1));
+        assert!(PLIC::is_valid_source(VIRT_MAX_INTERRUPTS as u32 - 1));
+        assert!(!PLIC::is_valid_source(VIRT_MAX_INTERRUPTS as u32));
+
+        // Out-of-range sources are ignored instead of panicking.
+        let mut plic = PLIC::new();
+        plic.trigger_interrupt(VIRT_MAX_INTERRUPTS as u32);
+    }
+
+    #[test]
+    fn pin_test() {
+        use crate::device::plic::irq_line::IrqDescriptor;
+
+        const LEVEL: u32 = 3;
+        const EDGE: u32 = 4;
+
+        let mut plic = PLIC::new();
+        let doorbell = Doorbell::new();
+        let level = IrqPin::new(IrqDescriptor::level(LEVEL));
+        let edge = IrqPin::new(IrqDescriptor::edge(EDGE));
+        plic.connect_pin(level.clone());
+        plic.set_doorbell(doorbell.clone());
+        plic.connect_pin(edge.clone());
+        plic.set_priority(LEVEL as WordType, 2).unwrap();
+        plic.set_priority(EDGE as WordType, 1).unwrap();
+        plic.set_enable_word(0, 0, (1 << LEVEL) | (1 << EDGE))
+            .unwrap();
+        assert!(doorbell.take());
+
+        // Level: fires again after completion while still asserted.
+        level.raise();
+        assert!(doorbell.take());
+        level.raise();
+        assert!(!doorbell.take());
+        plic.sample_pins();
+        assert_eq!(plic.try_get_interrupt(0), Some(LEVEL));
+        plic.sample_pins();
+        assert!(!plic.get_pending_bit(LEVEL as WordType).unwrap());
+        plic.set_claim_complete(0, LEVEL).unwrap();
+        plic.sample_pins();
+        assert!(plic.get_pending_bit(LEVEL as WordType).unwrap());
+        level.lower();
+        plic.sample_pins();
+        assert!(!plic.get_pending_bit(LEVEL as WordType).unwrap());
+
+        // Edge: latched even if the signal drops before the gateway samples it.
+        edge.raise();
+        edge.lower();
+        plic.sample_pins();
+        assert_eq!(plic.try_get_interrupt(0), Some(EDGE));
+        assert!(doorbell.take());
+        edge.pulse();
+        assert!(doorbell.take());
+        plic.sample_pins();
+        assert!(!plic.get_pending_bit(EDGE as WordType).unwrap());
+        plic.set_claim_complete(0, EDGE).unwrap();
+        plic.sample_pins();
+        assert_eq!(plic.try_get_interrupt(0), Some(EDGE));
+        plic.set_claim_complete(0, EDGE).unwrap();
+        plic.sample_pins();
+        assert_eq!(plic.try_get_interrupt(0), None);
+
+        // A disconnected pin no longer drives its source, which can be connected again.
+        level.raise();
+        plic.sample_pins();
+        assert!(plic.disconnect_pin(LEVEL).is_some());
+        assert!(plic.disconnect_pin(LEVEL).is_none());
+        assert!(!plic.get_pending_bit(LEVEL as WordType).unwrap());
+        plic.sample_pins();
+        assert!(!plic.get_pending_bit(LEVEL as WordType).unwrap());
+        plic.connect_pin(level);
+    }
 }
@@ -0,0 +1,214 @@
+//! Interrupt storm detection: a source claimed over and over while the guest makes no progress,
+//! the usual sign of a driver which completes the interrupt at the PLIC but never clears the
+//! condition in its device, so a level-triggered source fires again as soon as the handler
+//! returns.
+//!
+//! The board feeds every claim to a [`StormDetector`], with the guest time and the `pc` the
+//! interrupt was taken at. A storm is a source claimed more than [`StormConfig::claims`] times
+//! within [`StormConfig::window`] instructions, with every one of those interrupts taken at the
+//! same `pc`: the guest returns from the handler and is interrupted again before it retires
+//! a single instruction of its own.
+
+use std::{collections::HashMap, str::FromStr};
+
+use crate::{config::arch_config::WordType, device::plic::ExternalInterrupt};
+
+/// When a source is considered storming, see the [module documentation](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StormConfig {
+    pub claims: u32,
+    /// In instructions, i.e. cycles of the virtual clock.
+    pub window: u64,
+}
+
+impl Default for StormConfig {
+    fn default() -> Self {
+        Self {
+            claims: 1000,
+            window: 1_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("expected CLAIMS/INSTRUCTIONS with both above zero, e.g. 1000/1000000, got \"{0}\"")]
+pub struct StormConfigParseError(String);
+
+impl FromStr for StormConfig {
+    type Err = StormConfigParseError;
+
+    /// `CLAIMS/INSTRUCTIONS`, e.g. `1000/1000000`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || StormConfigParseError(s.to_string());
+        let (claims, window) = s.split_once('/').ok_or_else(err)?;
+        let config = StormConfig {
+            claims: claims.trim().parse().map_err(|_| err())?,
+            window: window.trim().parse().map_err(|_| err())?,
+        };
+        if config.claims == 0 || config.window == 0 {
+            return Err(err());
+        }
+        Ok(config)
+    }
+}
+
+/// A storm found by [`StormDetector::on_claim`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Storm {
+    pub source: ExternalInterrupt,
+    pub claims: u32,
+    /// Cycles since the first of the `claims`.
+    pub cycles: u64,
+    /// Where every one of the interrupts was taken.
+    pub pc: WordType,
+}
+
+impl std::fmt::Display for Storm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "interrupt storm: PLIC source {} claimed {} times in {} cycles, always interrupting \
+             pc = {:#x}; does the driver clear the interrupt in its device before completing it?",
+            self.source, self.claims, self.cycles, self.pc
+        )
+    }
+}
+
+/// The claims of one source in the current window.
+struct Window {
+    start: u64,
+    claims: u32,
+    /// The `pc` of the first claim, `None` once a claim interrupted another `pc`.
+    pc: Option<WordType>,
+    reported: bool,
+}
+
+pub struct StormDetector {
+    config: StormConfig,
+    windows: HashMap<ExternalInterrupt, Window>,
+    last: Option<Storm>,
+}
+
+impl StormDetector {
+    pub fn new(config: StormConfig) -> Self {
+        Self {
+            config,
+            windows: HashMap::new(),
+            last: None,
+        }
+    }
+
+    /// The last storm found.
+    pub fn last(&self) -> Option<Storm> {
+        self.last
+    }
+
+    /// Record a claim of `source` at cycle `now`, of an interrupt taken at `pc`. Returns the
+    /// storm once per window when it is found.
+    pub fn on_claim(&mut self, source: ExternalInterrupt, now: u64, pc: WordType) -> Option<Storm> {
+        let window = self.windows.entry(source).or_insert(Window {
+            start: now,
+            claims: 0,
+            pc: Some(pc),
+            reported: false,
+        });
+        if now - window.start > self.config.window {
+            *window = Window {
+                start: now,
+                claims: 0,
+                pc: Some(pc),
+                reported: false,
+            };
+        }
+
+        window.claims += 1;
+        if window.pc != Some(pc) {
+            window.pc = None;
+        }
+        if window.claims <= self.config.claims || window.reported {
+            return None;
+        }
+        let pc = window.pc?;
+        window.reported = true;
+        self.last = Some(Storm {
+            source,
+            claims: window.claims,
+            cycles: now - window.start,
+            pc,
+        });
+        self.last
+    }
+
+    pub fn reset(&mut self) {
+        self.windows.clear();
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: StormConfig = StormConfig {
+        claims: 10,
+        window: 1000,
+    };
+
+    #[test]
+    fn test_storm() {
+        let mut detector = StormDetector::new(CONFIG);
+        for i in 0..10 {
+            assert_eq!(detector.on_claim(3, i * 20, 0x8000_0100), None);
+        }
+        assert_eq!(
+            detector.on_claim(3, 200, 0x8000_0100),
+            Some(Storm {
+                source: 3,
+                claims: 11,
+                cycles: 200,
+                pc: 0x8000_0100,
+            })
+        );
+        // Once per window.
+        assert_eq!(detector.on_claim(3, 220, 0x8000_0100), None);
+        assert!(detector.on_claim(3, 1300, 0x8000_0100).is_none());
+    }
+
+    #[test]
+    fn test_progress_is_no_storm() {
+        let mut detector = StormDetector::new(CONFIG);
+        // Busy, but the guest moves on between interrupts.
+        for i in 0..100 {
+            assert_eq!(detector.on_claim(3, i, 0x8000_0100 + i % 2 * 4), None);
+        }
+
+        // Slow enough to fall in different windows.
+        let mut detector = StormDetector::new(CONFIG);
+        for i in 0..100 {
+            assert_eq!(detector.on_claim(3, i * 200, 0x8000_0100), None);
+        }
+
+        // Other sources are counted apart.
+        let mut detector = StormDetector::new(CONFIG);
+        for i in 0..20 {
+            assert_eq!(
+                detector.on_claim(1 + i as ExternalInterrupt % 2, i, 0x8000_0100),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(
+            "10/1000".parse::<StormConfig>(),
+            Ok(StormConfig {
+                claims: 10,
+                window: 1000
+            })
+        );
+        assert!("10".parse::<StormConfig>().is_err());
+        assert!("0/1000".parse::<StormConfig>().is_err());
+        assert!("10/x".parse::<StormConfig>().is_err());
+    }
+}
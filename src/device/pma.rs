@@ -0,0 +1,123 @@
+//! Physical memory attributes: what the memory system allows at an address, checked on every
+//! access by [`MemoryMapIO`](crate::device::mmio::MemoryMapIO).
+//!
+//! The RAM is main memory, every device is an I/O region with the access widths it declares. A
+//! board description can change the attributes of the devices in an address range, see
+//! [`MemoryMap`](crate::board::memory_map::MemoryMap).
+//!
+//! - An access of another width faults.
+//! - A misaligned access to a non-idempotent region faults instead of raising an
+//!   address-misaligned exception, so the trap handler does not emulate it with several accesses.
+//! - An AMO beyond the [`AmoClass`] of the region, or an LR/SC to a region which is not
+//!   reservable, faults.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::arch_config::WordType, device::AccessWidths};
+
+/// The atomic memory operations a region supports, each class includes the previous ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AmoClass {
+    None,
+    /// `amoswap`.
+    Swap,
+    /// And `amoand`, `amoor` and `amoxor`.
+    Logical,
+    /// And `amoadd`, `amomin[u]` and `amomax[u]`.
+    Arithmetic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Pma {
+    /// Not modelled, there are no caches; reported for the guest's device tree.
+    pub cacheable: bool,
+    /// Reads have no side effect and writes can be repeated.
+    pub idempotent: bool,
+    pub widths: AccessWidths,
+    pub amo: AmoClass,
+    /// Whether `lr` and `sc` are supported.
+    pub reservable: bool,
+}
+
+impl Pma {
+    pub const MAIN_MEMORY: Self = Self {
+        cacheable: true,
+        idempotent: true,
+        widths: AccessWidths::ANY,
+        amo: AmoClass::Arithmetic,
+        reservable: true,
+    };
+
+    pub const IO: Self = Self {
+        cacheable: false,
+        idempotent: false,
+        widths: AccessWidths::ANY,
+        amo: AmoClass::None,
+        reservable: false,
+    };
+
+    pub fn allows_amo(&self, class: AmoClass) -> bool {
+        class <= self.amo
+    }
+}
+
+/// Attributes a board description gives the devices mapped at `base..base + size`, the others
+/// keep their defaults.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PmaOverride {
+    pub base: WordType,
+    pub size: WordType,
+    pub cacheable: Option<bool>,
+    pub idempotent: Option<bool>,
+    /// Access sizes in bytes.
+    pub widths: Option<Vec<u32>>,
+    pub amo: Option<AmoClass>,
+    pub reservable: Option<bool>,
+}
+
+impl PmaOverride {
+    /// Whether the device mapped at `start` is in the range.
+    pub fn covers(&self, start: WordType) -> bool {
+        start >= self.base && start - self.base < self.size
+    }
+
+    pub fn apply(&self, pma: Pma) -> Pma {
+        Pma {
+            cacheable: self.cacheable.unwrap_or(pma.cacheable),
+            idempotent: self.idempotent.unwrap_or(pma.idempotent),
+            widths: self.widths.as_deref().map_or(pma.widths, AccessWidths::of),
+            amo: self.amo.unwrap_or(pma.amo),
+            reservable: self.reservable.unwrap_or(pma.reservable),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pma_override() {
+        assert!(Pma::MAIN_MEMORY.allows_amo(AmoClass::Arithmetic));
+        assert!(!Pma::IO.allows_amo(AmoClass::Swap));
+
+        let entry = PmaOverride {
+            base: 0x1000,
+            size: 0x100,
+            cacheable: None,
+            idempotent: Some(true),
+            widths: Some(vec![4]),
+            amo: Some(AmoClass::Logical),
+            reservable: None,
+        };
+        assert!(entry.covers(0x1000) && entry.covers(0x10ff));
+        assert!(!entry.covers(0xfff) && !entry.covers(0x1100));
+
+        let pma = entry.apply(Pma::IO);
+        assert!(pma.idempotent && !pma.cacheable && !pma.reservable);
+        assert_eq!(pma.widths, AccessWidths::of(&[4]));
+        assert!(pma.allows_amo(AmoClass::Swap) && !pma.allows_amo(AmoClass::Arithmetic));
+    }
+}
@@ -1,17 +1,32 @@
+//! SiFive test finisher, the `syscon` the guest writes to to power off or reboot the board:
+//!
+//! | Value                 | Effect                                        |
+//! |-----------------------|-----------------------------------------------|
+//! | `0x5555`              | Power off                                     |
+//! | `0x7777`              | Reset the board                               |
+//! | `code << 16 \| 0x3333` | Halt the board, reporting a failure with `code` |
+//!
+//! The SBI system reset extension of the firmware (`sbi_system_reset`) ends up here through the
+//! `syscon-poweroff` / `syscon-reboot` nodes of the device tree.
+
 use crate::{
+    board::{BoardControl, BoardRequest},
     device::{
-        DeviceTrait, MemError, MemMappedDeviceTrait,
-        config::{POWER_MANAGER_BASE, POWER_MANAGER_SIZE},
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{POWER_MANAGER_BASE, POWER_MANAGER_NAME, POWER_MANAGER_SIZE},
     },
     device_poller::PollingEventTrait,
 };
 use std::sync::atomic::AtomicU16;
 
 pub(crate) const POWER_OFF_CODE: u16 = 0x5555;
+pub(crate) const RESET_CODE: u16 = 0x7777;
+pub(crate) const FAIL_CODE: u16 = 0x3333;
 pub static POWER_STATUS: AtomicU16 = AtomicU16::new(0);
 
 pub struct PowerManager {
     reg: u16,
+    control: BoardControl,
 }
 
 impl PowerManager {
@@ -20,7 +35,6 @@ impl PowerManager {
         T: crate::utils::UnsignedInteger,
     {
         debug_assert!(addr == 0x00);
-        debug_assert!(size_of::<T>() >= 2);
         let mut ret: T = ((self.reg >> 8) as u8).into();
         ret <<= 8;
         ret |= (self.reg as u8).into();
@@ -39,8 +53,16 @@ impl PowerManager {
         let data: u64 = data.into();
         self.reg = data as u16;
 
-        if self.reg == POWER_OFF_CODE {
-            POWER_STATUS.store(0x5555, std::sync::atomic::Ordering::Release);
+        match self.reg {
+            POWER_OFF_CODE => POWER_STATUS.store(0x5555, std::sync::atomic::Ordering::Release),
+            RESET_CODE => self.control.request(BoardRequest::Reset),
+            FAIL_CODE => {
+                let code = (data >> 16) as u16;
+                self.control.request(BoardRequest::Halt(format!(
+                    "Guest reported a failure (code {code})"
+                )));
+            }
+            _ => {}
         }
         Ok(())
     }
@@ -53,9 +75,18 @@ impl DeviceTrait for PowerManager {
     fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
         None
     }
+
+    fn reset(&mut self) {
+        self.reg = 0;
+        POWER_STATUS.store(0, std::sync::atomic::Ordering::Release);
+    }
 }
 
 impl MemMappedDeviceTrait for PowerManager {
+    fn name() -> &'static str {
+        POWER_MANAGER_NAME
+    }
+
     fn base() -> crate::config::arch_config::WordType {
         POWER_MANAGER_BASE
     }
@@ -63,11 +94,16 @@ impl MemMappedDeviceTrait for PowerManager {
     fn size() -> crate::config::arch_config::WordType {
         POWER_MANAGER_SIZE
     }
+
+    /// The register is 16 bits wide.
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[2, 4, 8])
+    }
 }
 
 impl PowerManager {
-    pub fn new() -> Self {
+    pub fn new(control: BoardControl) -> Self {
         POWER_STATUS.store(0, std::sync::atomic::Ordering::Release);
-        Self { reg: 0 }
+        Self { reg: 0, control }
     }
 }
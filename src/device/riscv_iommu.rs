@@ -0,0 +1,731 @@
+//! RISC-V IOMMU (version 1.0) translating the DMA of the VirtIO devices, the device ID of a
+//! device is the index of its VirtIO MMIO slot.
+//!
+//! Supported: the device directory table in `1LVL` and `2LVL` mode with base-format device
+//! contexts, first-stage Sv39 translation (the G-stage must be `Bare`), the command queue and the
+//! fault queue with a wired interrupt. Nothing is cached, the invalidation commands complete
+//! right away. Not supported: process directories, ATS, page requests, MSI translation, the
+//! hardware update of the A and D bits and the performance monitor. Device contexts asking for
+//! any of them are misconfigured.
+//!
+//! The registers are at the offsets of the specification, 64-bit ones can be accessed as two
+//! 32-bit halves:
+//!
+//! | Offset | Name         | Description                                                    |
+//! |--------|--------------|----------------------------------------------------------------|
+//! | 0x00   | capabilities | Sv39, wired interrupts (read-only)                             |
+//! | 0x08   | fctl         | Only `WSI` is set (read-only)                                  |
+//! | 0x10   | ddtp         | Mode (`Off`, `Bare`, `1LVL`, `2LVL`) and PPN of the directory  |
+//! | 0x18   | cqb          | Command queue base and size                                    |
+//! | 0x20   | cqh          | Command queue head (read-only)                                 |
+//! | 0x24   | cqt          | Command queue tail, writing it runs the new commands           |
+//! | 0x28   | fqb          | Fault queue base and size                                      |
+//! | 0x30   | fqh          | Fault queue head                                               |
+//! | 0x34   | fqt          | Fault queue tail (read-only)                                   |
+//! | 0x48   | cqcsr        | Command queue control and status                               |
+//! | 0x4c   | fqcsr        | Fault queue control and status                                 |
+//! | 0x54   | ipsr         | Interrupt pending status (write 1 to clear)                    |
+
+use log::warn;
+
+use crate::{
+    config::arch_config::WordType,
+    device::{
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{IOMMU_BASE, IOMMU_IRQ, IOMMU_NAME, IOMMU_SIZE},
+        plic::irq_line::{IrqDescriptor, IrqPin},
+        stats::DeviceStats,
+        virtio::dma::{DmaAccess, DmaError, GuestRam},
+    },
+    device_poller::PollingEventTrait,
+};
+
+const CAPABILITIES: WordType = 0x00;
+const FCTL: WordType = 0x08;
+const DDTP: WordType = 0x10;
+const CQB: WordType = 0x18;
+const CQH: WordType = 0x20;
+const CQT: WordType = 0x24;
+const FQB: WordType = 0x28;
+const FQH: WordType = 0x30;
+const FQT: WordType = 0x34;
+const CQCSR: WordType = 0x48;
+const FQCSR: WordType = 0x4c;
+const IPSR: WordType = 0x54;
+
+const CAP_VERSION_1_0: u64 = 0x10;
+const CAP_SV39: u64 = 1 << 9;
+const CAP_IGS_WSI: u64 = 1 << 28;
+const CAP_PAS_56: u64 = 56 << 32;
+
+const FCTL_WSI: u32 = 1 << 1;
+
+const DDTP_MODE: u64 = 0xf;
+const DDTP_MODE_OFF: u64 = 0;
+const DDTP_MODE_BARE: u64 = 1;
+const DDTP_MODE_1LVL: u64 = 2;
+const DDTP_MODE_2LVL: u64 = 3;
+
+/// `PPN` of `ddtp`, the queue bases and the page table entries.
+const PPN: u64 = ((1 << 44) - 1) << 10;
+/// `LOG2SZ-1` of the queue bases.
+const QUEUE_LOG2SZ: u64 = 0x1f;
+
+const CQCSR_CQEN: u32 = 1 << 0;
+const CQCSR_CIE: u32 = 1 << 1;
+const CQCSR_CQMF: u32 = 1 << 8;
+const CQCSR_CMD_TO: u32 = 1 << 9;
+const CQCSR_CMD_ILL: u32 = 1 << 10;
+const CQCSR_FENCE_W_IP: u32 = 1 << 11;
+const CQCSR_ERRORS: u32 = CQCSR_CQMF | CQCSR_CMD_TO | CQCSR_CMD_ILL | CQCSR_FENCE_W_IP;
+const CQCSR_CQON: u32 = 1 << 16;
+
+const FQCSR_FQEN: u32 = 1 << 0;
+const FQCSR_FIE: u32 = 1 << 1;
+const FQCSR_FQMF: u32 = 1 << 8;
+const FQCSR_FQOF: u32 = 1 << 9;
+const FQCSR_ERRORS: u32 = FQCSR_FQMF | FQCSR_FQOF;
+const FQCSR_FQON: u32 = 1 << 16;
+
+const IPSR_CIP: u32 = 1 << 0;
+const IPSR_FIP: u32 = 1 << 1;
+
+const CMD_IOTINVAL: u64 = 1;
+const CMD_IOFENCE: u64 = 2;
+const CMD_IODIR: u64 = 3;
+const IOFENCE_AV: u64 = 1 << 10;
+const IOFENCE_WSI: u64 = 1 << 11;
+
+const DC_TC_V: u64 = 1 << 0;
+const DC_TC_DTF: u64 = 1 << 4;
+/// Everything in `tc` but `V` and `DTF` asks for a feature which is not supported.
+const DC_TC_UNSUPPORTED: u64 = !(DC_TC_V | DC_TC_DTF);
+const DC_ATP_MODE_SHIFT: u32 = 60;
+const FSC_MODE_BARE: u64 = 0;
+const FSC_MODE_SV39: u64 = 8;
+const FSC_PPN: u64 = (1 << 44) - 1;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+/// `N`, `PBMT` and the reserved bits.
+const PTE_RESERVED: u64 = !((1 << 54) - 1);
+
+const CAUSE_READ_ACCESS_FAULT: u16 = 5;
+const CAUSE_WRITE_ACCESS_FAULT: u16 = 7;
+const CAUSE_READ_PAGE_FAULT: u16 = 13;
+const CAUSE_WRITE_PAGE_FAULT: u16 = 15;
+const CAUSE_ALL_INBOUND_DISALLOWED: u16 = 256;
+const CAUSE_DDT_LOAD_FAULT: u16 = 257;
+const CAUSE_DDT_INVALID: u16 = 258;
+const CAUSE_DDT_MISCONFIGURED: u16 = 259;
+const CAUSE_TRANSACTION_DISALLOWED: u16 = 260;
+
+const TTYP_UNTRANSLATED_READ: u64 = 2;
+const TTYP_UNTRANSLATED_WRITE: u64 = 3;
+
+const PAGE_SHIFT: u32 = 12;
+
+/// Address of the page `ppn`.
+fn ppn_addr(reg: u64) -> u64 {
+    ((reg & PPN) >> 10) << PAGE_SHIFT
+}
+
+/// Number of entries of the queue `base` describes.
+fn queue_entries(base: u64) -> u32 {
+    2 << (base & QUEUE_LOG2SZ).min(30)
+}
+
+/// Device context in the base format.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DeviceContext {
+    tc: u64,
+    iohgatp: u64,
+    ta: u64,
+    fsc: u64,
+}
+
+pub(crate) struct RiscvIommu {
+    /// The directory, the page tables and the queues are in guest-physical memory.
+    ram: GuestRam,
+
+    ddtp: u64,
+    cqb: u64,
+    cqh: u32,
+    cqt: u32,
+    cqcsr: u32,
+    fqb: u64,
+    fqh: u32,
+    fqt: u32,
+    fqcsr: u32,
+    ipsr: u32,
+
+    irq: Option<IrqPin>,
+}
+
+impl RiscvIommu {
+    pub(crate) fn new(ram: GuestRam) -> Self {
+        Self {
+            ram,
+            ddtp: DDTP_MODE_BARE,
+            cqb: 0,
+            cqh: 0,
+            cqt: 0,
+            cqcsr: 0,
+            fqb: 0,
+            fqh: 0,
+            fqt: 0,
+            fqcsr: 0,
+            ipsr: 0,
+            irq: None,
+        }
+    }
+
+    /// Translate the I/O virtual address `iova` of the device `device_id`, the fault is
+    /// reported in the fault queue.
+    pub(crate) fn translate(
+        &mut self,
+        device_id: u32,
+        iova: u64,
+        access: DmaAccess,
+    ) -> Result<u64, DmaError> {
+        let (cause, report) = match self.try_translate(device_id, iova, access) {
+            Ok(paddr) => return Ok(paddr),
+            Err(fault) => fault,
+        };
+        warn!("IOMMU: fault {cause} of device {device_id} at {iova:#x}");
+        if report {
+            self.report_fault(cause, device_id, iova, access);
+        }
+        Err(DmaError::IommuFault { iova, cause })
+    }
+
+    /// The translation, or the fault cause and whether it is reported.
+    fn try_translate(
+        &self,
+        device_id: u32,
+        iova: u64,
+        access: DmaAccess,
+    ) -> Result<u64, (u16, bool)> {
+        let dc = match self.ddtp & DDTP_MODE {
+            DDTP_MODE_OFF => return Err((CAUSE_ALL_INBOUND_DISALLOWED, true)),
+            DDTP_MODE_BARE => return Ok(iova),
+            _ => self
+                .device_context(device_id)
+                .map_err(|cause| (cause, true))?,
+        };
+        let report = dc.tc & DC_TC_DTF == 0;
+
+        let mode = dc.fsc >> DC_ATP_MODE_SHIFT;
+        let reserved_fsc = dc.fsc & !(FSC_PPN | (0xf << DC_ATP_MODE_SHIFT)) != 0;
+        if dc.tc & DC_TC_UNSUPPORTED != 0
+            || dc.iohgatp != 0
+            || reserved_fsc
+            || (mode != FSC_MODE_BARE && mode != FSC_MODE_SV39)
+        {
+            return Err((CAUSE_DDT_MISCONFIGURED, report));
+        }
+
+        if mode == FSC_MODE_BARE {
+            return Ok(iova);
+        }
+        self.walk_sv39((dc.fsc & FSC_PPN) << PAGE_SHIFT, iova, access)
+            .map_err(|cause| (cause, report))
+    }
+
+    /// Load the device context of `device_id` from the device directory table.
+    fn device_context(&self, device_id: u32) -> Result<DeviceContext, u16> {
+        let device_id = device_id as u64;
+        // The base format uses 7 bits in the leaf table and 9 in the non-leaf one.
+        let (levels, width) = match self.ddtp & DDTP_MODE {
+            DDTP_MODE_1LVL => (1, 7),
+            DDTP_MODE_2LVL => (2, 16),
+            _ => unreachable!(),
+        };
+        if device_id >> width != 0 {
+            return Err(CAUSE_TRANSACTION_DISALLOWED);
+        }
+
+        let mut table = ppn_addr(self.ddtp);
+        if levels == 2 {
+            let entry = *self
+                .ram
+                .get_mut::<u64>(table + (device_id >> 7) * 8, DmaAccess::Read)
+                .map_err(|_| CAUSE_DDT_LOAD_FAULT)?;
+            if entry & PTE_V == 0 {
+                return Err(CAUSE_DDT_INVALID);
+            }
+            table = ppn_addr(entry);
+        }
+
+        let dc = *self
+            .ram
+            .get_mut::<DeviceContext>(
+                table + (device_id & 0x7f) * size_of::<DeviceContext>() as u64,
+                DmaAccess::Read,
+            )
+            .map_err(|_| CAUSE_DDT_LOAD_FAULT)?;
+        if dc.tc & DC_TC_V == 0 {
+            return Err(CAUSE_DDT_INVALID);
+        }
+        Ok(dc)
+    }
+
+    /// Walk the Sv39 page table at `root` as a user-mode access.
+    fn walk_sv39(&self, root: u64, iova: u64, access: DmaAccess) -> Result<u64, u16> {
+        let (page_fault, access_fault, permission) = match access {
+            DmaAccess::Read => (CAUSE_READ_PAGE_FAULT, CAUSE_READ_ACCESS_FAULT, PTE_R),
+            DmaAccess::Write => (
+                CAUSE_WRITE_PAGE_FAULT,
+                CAUSE_WRITE_ACCESS_FAULT,
+                PTE_W | PTE_D,
+            ),
+        };
+        // Bits 63:39 must be copies of bit 38.
+        if ((iova as i64) << 25 >> 25) as u64 != iova {
+            return Err(page_fault);
+        }
+
+        let mut table = root;
+        for level in (0..3).rev() {
+            let vpn = (iova >> (PAGE_SHIFT + 9 * level)) & 0x1ff;
+            let pte = *self
+                .ram
+                .get_mut::<u64>(table + vpn * 8, DmaAccess::Read)
+                .map_err(|_| access_fault)?;
+            if pte & PTE_V == 0 || pte & (PTE_R | PTE_W) == PTE_W || pte & PTE_RESERVED != 0 {
+                return Err(page_fault);
+            }
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = ppn_addr(pte);
+                continue;
+            }
+
+            let required = permission | PTE_U | PTE_A;
+            let offset_mask = (1 << (PAGE_SHIFT + 9 * level)) - 1;
+            let paddr = ppn_addr(pte);
+            if pte & required != required || paddr & offset_mask != 0 {
+                return Err(page_fault);
+            }
+            return Ok(paddr | (iova & offset_mask));
+        }
+        Err(page_fault)
+    }
+
+    fn report_fault(&mut self, cause: u16, device_id: u32, iova: u64, access: DmaAccess) {
+        if self.fqcsr & FQCSR_FQON == 0 || self.fqcsr & FQCSR_ERRORS != 0 {
+            return;
+        }
+        let entries = queue_entries(self.fqb);
+        if (self.fqt + 1) % entries == self.fqh {
+            self.fqcsr |= FQCSR_FQOF;
+        } else {
+            let ttyp = match access {
+                DmaAccess::Read => TTYP_UNTRANSLATED_READ,
+                DmaAccess::Write => TTYP_UNTRANSLATED_WRITE,
+            };
+            let record = [
+                cause as u64 | ttyp << 34 | (device_id as u64) << 40,
+                0,
+                iova,
+                0,
+            ];
+            let addr = ppn_addr(self.fqb) + self.fqt as u64 * size_of_val(&record) as u64;
+            match self.ram.get_mut::<[u64; 4]>(addr, DmaAccess::Write) {
+                Ok(entry) => {
+                    *entry = record;
+                    self.fqt = (self.fqt + 1) % entries;
+                }
+                Err(_) => self.fqcsr |= FQCSR_FQMF,
+            }
+        }
+        self.ipsr |= IPSR_FIP;
+        self.update_irq();
+    }
+
+    /// Run the commands up to the tail, the queue stops at an error until it is cleared.
+    fn process_commands(&mut self) {
+        if self.cqcsr & CQCSR_CQON == 0 || self.cqcsr & CQCSR_ERRORS != 0 {
+            return;
+        }
+        let entries = queue_entries(self.cqb);
+        while self.cqh != self.cqt {
+            let addr = ppn_addr(self.cqb) + self.cqh as u64 * 16;
+            let Ok(&mut command) = self.ram.get_mut::<[u64; 2]>(addr, DmaAccess::Read) else {
+                self.command_error(CQCSR_CQMF);
+                return;
+            };
+            if let Err(error) = self.execute(command) {
+                self.command_error(error);
+                return;
+            }
+            self.cqh = (self.cqh + 1) % entries;
+        }
+    }
+
+    fn execute(&mut self, [dword0, dword1]: [u64; 2]) -> Result<(), u32> {
+        let opcode = dword0 & 0x7f;
+        let func3 = (dword0 >> 7) & 0x7;
+        match (opcode, func3) {
+            // IOTINVAL.VMA, IOTINVAL.GVMA, IODIR.INVAL_DDT and IODIR.INVAL_PDT.
+            (CMD_IOTINVAL, 0 | 1) | (CMD_IODIR, 0 | 1) => Ok(()),
+            // IOFENCE.C
+            (CMD_IOFENCE, 0) => {
+                if dword0 & IOFENCE_AV != 0 {
+                    let data = (dword0 >> 32) as u32;
+                    *self
+                        .ram
+                        .get_mut::<u32>(dword1 << 2, DmaAccess::Write)
+                        .map_err(|_| CQCSR_CQMF)? = data;
+                }
+                if dword0 & IOFENCE_WSI != 0 {
+                    self.ipsr |= IPSR_CIP;
+                }
+                Ok(())
+            }
+            _ => Err(CQCSR_CMD_ILL),
+        }
+    }
+
+    fn command_error(&mut self, error: u32) {
+        warn!("IOMMU: command queue error {error:#x} at {}", self.cqh);
+        self.cqcsr |= error;
+        self.ipsr |= IPSR_CIP;
+    }
+
+    fn update_irq(&self) {
+        if let Some(pin) = &self.irq {
+            let command = self.ipsr & IPSR_CIP != 0 && self.cqcsr & CQCSR_CIE != 0;
+            let fault = self.ipsr & IPSR_FIP != 0 && self.fqcsr & FQCSR_FIE != 0;
+            pin.set_level(command || fault);
+        }
+    }
+
+    fn capabilities() -> u64 {
+        CAP_VERSION_1_0 | CAP_SV39 | CAP_IGS_WSI | CAP_PAS_56
+    }
+
+    fn read_u64_reg(&self, reg: WordType) -> u64 {
+        match reg {
+            CAPABILITIES => Self::capabilities(),
+            FCTL => FCTL_WSI as u64,
+            DDTP => self.ddtp,
+            CQB => self.cqb,
+            CQH => self.cqh as u64 | (self.cqt as u64) << 32,
+            FQB => self.fqb,
+            FQH => self.fqh as u64 | (self.fqt as u64) << 32,
+            CQCSR => self.cqcsr as u64 | (self.fqcsr as u64) << 32,
+            0x50 => (self.ipsr as u64) << 32,
+            _ => 0,
+        }
+    }
+
+    fn write_u64_reg(&mut self, reg: WordType, value: u64) {
+        match reg {
+            DDTP => {
+                if value & DDTP_MODE <= DDTP_MODE_2LVL {
+                    self.ddtp = value & (DDTP_MODE | PPN);
+                }
+            }
+            CQB if self.cqcsr & CQCSR_CQON == 0 => self.cqb = value & (QUEUE_LOG2SZ | PPN),
+            FQB if self.fqcsr & FQCSR_FQON == 0 => self.fqb = value & (QUEUE_LOG2SZ | PPN),
+            _ => {}
+        }
+    }
+
+    fn write_u32_reg(&mut self, reg: WordType, value: u32) {
+        match reg {
+            CQT => {
+                self.cqt = value & (queue_entries(self.cqb) - 1);
+                self.process_commands();
+            }
+            FQH => self.fqh = value & (queue_entries(self.fqb) - 1),
+            CQCSR => {
+                if value & CQCSR_CQEN != 0 && self.cqcsr & CQCSR_CQON == 0 {
+                    self.cqh = 0;
+                    self.cqcsr |= CQCSR_CQON;
+                } else if value & CQCSR_CQEN == 0 {
+                    self.cqcsr &= !CQCSR_CQON;
+                }
+                self.cqcsr &= !(CQCSR_CQEN | CQCSR_CIE | (value & CQCSR_ERRORS));
+                self.cqcsr |= value & (CQCSR_CQEN | CQCSR_CIE);
+                self.process_commands();
+            }
+            FQCSR => {
+                if value & FQCSR_FQEN != 0 && self.fqcsr & FQCSR_FQON == 0 {
+                    self.fqt = 0;
+                    self.fqcsr |= FQCSR_FQON;
+                } else if value & FQCSR_FQEN == 0 {
+                    self.fqcsr &= !FQCSR_FQON;
+                }
+                self.fqcsr &= !(FQCSR_FQEN | FQCSR_FIE | (value & FQCSR_ERRORS));
+                self.fqcsr |= value & (FQCSR_FQEN | FQCSR_FIE);
+            }
+            IPSR => self.ipsr &= !(value & (IPSR_CIP | IPSR_FIP)),
+            _ => {}
+        }
+    }
+
+    fn read_impl<T>(&mut self, addr: WordType) -> Result<T, MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let value = self.read_u64_reg(addr & !7) >> ((addr & 4) * 8);
+        Ok(T::truncate_from(value))
+    }
+
+    fn write_impl<T>(&mut self, addr: WordType, data: T) -> Result<(), MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let len = size_of::<T>();
+        let data: u64 = data.into();
+        match addr & !7 {
+            reg @ (CAPABILITIES | FCTL | DDTP | CQB | FQB) => {
+                let shift = (addr & 4) * 8;
+                let mask = if len == 8 {
+                    u64::MAX
+                } else {
+                    (u32::MAX as u64) << shift
+                };
+                let value = (self.read_u64_reg(reg) & !mask) | ((data << shift) & mask);
+                self.write_u64_reg(reg, value);
+            }
+            _ => {
+                self.write_u32_reg(addr, data as u32);
+                if len == 8 {
+                    self.write_u32_reg(addr + 4, (data >> 32) as u32);
+                }
+            }
+        }
+        self.update_irq();
+        Ok(())
+    }
+}
+
+impl DeviceTrait for RiscvIommu {
+    dispatch_read_write! { read_impl, write_impl }
+
+    fn sync(&mut self) {}
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+
+    fn reset(&mut self) {
+        let ram = self.ram.clone();
+        let irq = self.irq.take();
+        *self = Self::new(ram);
+        self.irq = irq;
+        self.update_irq();
+    }
+
+    fn connect_irq(&mut self, pin: IrqPin) {
+        self.irq = Some(pin);
+    }
+
+    fn report_stats(&mut self, stats: &mut DeviceStats) {
+        if let Some(pin) = &self.irq {
+            stats.record_irq(pin);
+        }
+    }
+}
+
+impl MemMappedDeviceTrait for RiscvIommu {
+    fn name() -> &'static str {
+        IOMMU_NAME
+    }
+    fn base() -> WordType {
+        IOMMU_BASE
+    }
+    fn size() -> WordType {
+        IOMMU_SIZE
+    }
+    fn irq() -> Option<IrqDescriptor> {
+        Some(IrqDescriptor::level(IOMMU_IRQ))
+    }
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[4, 8])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{ram::Ram, ram_config::BASE_ADDR};
+
+    const DDT: u64 = BASE_ADDR + 0x1_0000;
+    const ROOT: u64 = BASE_ADDR + 0x2_0000;
+    const FQ: u64 = BASE_ADDR + 0x3_0000;
+    const CQ: u64 = BASE_ADDR + 0x4_0000;
+    const IOVA: u64 = 0x40_0000_0000 - 0x1000;
+
+    fn write(ram: &mut Ram, paddr: u64, value: u64) {
+        ram.write(paddr - BASE_ADDR, value).unwrap();
+    }
+
+    fn read(ram: &mut Ram, paddr: u64) -> u64 {
+        ram.read(paddr - BASE_ADDR).unwrap()
+    }
+
+    fn pte(paddr: u64, flags: u64) -> u64 {
+        (paddr >> PAGE_SHIFT) << 10 | flags | PTE_V
+    }
+
+    /// Device 3 translates through the Sv39 table at `ROOT`, which maps `IOVA` to 0x8008_0000
+    /// read-only and the page after it to 0x8009_0000 read-write, with 4 KiB pages.
+    fn setup(ram: &mut Ram) -> RiscvIommu {
+        let mut iommu = RiscvIommu::new(GuestRam::new(ram));
+        write(ram, DDT + 3 * 32, DC_TC_V);
+        write(
+            ram,
+            DDT + 3 * 32 + 24,
+            FSC_MODE_SV39 << 60 | ROOT >> PAGE_SHIFT,
+        );
+
+        // IOVA is the last page of the lower half: VPN[2] = 0xff, VPN[1] = VPN[0] = 0x1ff.
+        let (l1, l0) = (ROOT + 0x1000, ROOT + 0x2000);
+        write(ram, ROOT + 0xff * 8, pte(l1, 0));
+        write(ram, l1 + 0x1ff * 8, pte(l0, 0));
+        write(ram, l0 + 0x1ff * 8, pte(0x8008_0000, PTE_R | PTE_U | PTE_A));
+
+        iommu
+            .write_u64(DDTP, DDT >> PAGE_SHIFT << 10 | DDTP_MODE_1LVL)
+            .unwrap();
+        iommu.write_u64(FQB, FQ >> PAGE_SHIFT << 10 | 2).unwrap();
+        iommu.write_u32(FQCSR, FQCSR_FQEN | FQCSR_FIE).unwrap();
+        iommu
+    }
+
+    #[test]
+    fn test_iommu_modes() {
+        let mut ram = Ram::new();
+        let mut iommu = RiscvIommu::new(GuestRam::new(&mut ram));
+        assert_eq!(iommu.read_u64(DDTP).unwrap(), DDTP_MODE_BARE);
+        assert_eq!(
+            iommu.translate(0, 0x8000_1234, DmaAccess::Write),
+            Ok(0x8000_1234)
+        );
+
+        iommu.write_u32(DDTP, DDTP_MODE_OFF as u32).unwrap();
+        assert_eq!(
+            iommu.translate(0, 0x8000_1234, DmaAccess::Read),
+            Err(DmaError::IommuFault {
+                iova: 0x8000_1234,
+                cause: CAUSE_ALL_INBOUND_DISALLOWED
+            })
+        );
+
+        // Unsupported modes are ignored.
+        iommu.write_u32(DDTP, 4).unwrap();
+        assert_eq!(iommu.read_u32(DDTP).unwrap(), DDTP_MODE_OFF as u32);
+        assert_eq!(iommu.read_u32(CAPABILITIES).unwrap() & 0xff, 0x10);
+    }
+
+    #[test]
+    fn test_iommu_translation_and_faults() {
+        let mut ram = Ram::new();
+        let mut iommu = setup(&mut ram);
+
+        assert_eq!(
+            iommu.translate(3, IOVA + 0x10, DmaAccess::Read),
+            Ok(0x8008_0010)
+        );
+        // Not writable, not mapped and not in the directory.
+        let faults = [
+            (3, IOVA, DmaAccess::Write, CAUSE_WRITE_PAGE_FAULT),
+            (3, 0x1000, DmaAccess::Read, CAUSE_READ_PAGE_FAULT),
+            (4, IOVA, DmaAccess::Read, CAUSE_DDT_INVALID),
+            (0x80, IOVA, DmaAccess::Read, CAUSE_TRANSACTION_DISALLOWED),
+        ];
+        for (i, &(device_id, iova, access, cause)) in faults.iter().enumerate() {
+            assert_eq!(
+                iommu.translate(device_id, iova, access),
+                Err(DmaError::IommuFault { iova, cause })
+            );
+            let record = FQ + i as u64 * 32;
+            let ttyp = match access {
+                DmaAccess::Read => TTYP_UNTRANSLATED_READ,
+                DmaAccess::Write => TTYP_UNTRANSLATED_WRITE,
+            };
+            assert_eq!(
+                read(&mut ram, record),
+                cause as u64 | ttyp << 34 | (device_id as u64) << 40
+            );
+            assert_eq!(read(&mut ram, record + 16), iova);
+        }
+        assert_eq!(iommu.read_u32(FQT).unwrap(), 4);
+        assert_eq!(iommu.read_u32(IPSR).unwrap(), IPSR_FIP);
+
+        // The queue of 8 records overflows.
+        for _ in 0..4 {
+            let _ = iommu.translate(3, 0x1000, DmaAccess::Read);
+        }
+        assert_eq!(iommu.read_u32(FQT).unwrap(), 7);
+        assert!(iommu.read_u32(FQCSR).unwrap() & FQCSR_FQOF != 0);
+        iommu.write_u32(FQH, 7).unwrap();
+        iommu
+            .write_u32(FQCSR, FQCSR_FQEN | FQCSR_FIE | FQCSR_FQOF)
+            .unwrap();
+        iommu.write_u32(IPSR, IPSR_FIP).unwrap();
+        assert_eq!(iommu.read_u32(FQCSR).unwrap() & FQCSR_FQOF, 0);
+        assert_eq!(iommu.read_u32(IPSR).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_iommu_dma_bounce() {
+        let mut ram = Ram::new();
+        let iommu = Rc::new(RefCell::new(setup(&mut ram)));
+        let flags = PTE_R | PTE_W | PTE_U | PTE_A | PTE_D;
+        let l0 = ROOT + 0x2000;
+        write(&mut ram, l0 + 0x1fe * 8, pte(0x8009_0000, flags));
+        write(&mut ram, l0 + 0x1ff * 8, pte(0x8008_0000, flags));
+        let guest = GuestRam::new(&mut ram).behind_iommu(iommu, 3);
+
+        // The two pages are not contiguous in guest memory.
+        assert!(matches!(
+            guest.get_mut::<[u64; 2]>(IOVA - 8, DmaAccess::Read),
+            Err(DmaError::Discontiguous { .. })
+        ));
+        let mut buf = guest.buffer(IOVA - 8, 16, DmaAccess::Write).unwrap();
+        buf.copy_from_slice(&[0xaa; 16]);
+        drop(buf);
+        assert_eq!(read(&mut ram, 0x8009_0ff8), u64::MAX / 0xff * 0xaa);
+        assert_eq!(read(&mut ram, 0x8008_0000), u64::MAX / 0xff * 0xaa);
+    }
+
+    #[test]
+    fn test_iommu_command_queue() {
+        let mut ram = Ram::new();
+        let mut iommu = setup(&mut ram);
+        iommu.write_u64(CQB, CQ >> PAGE_SHIFT << 10 | 2).unwrap();
+        iommu.write_u32(CQCSR, CQCSR_CQEN | CQCSR_CIE).unwrap();
+        assert!(iommu.read_u32(CQCSR).unwrap() & CQCSR_CQON != 0);
+
+        // IODIR.INVAL_DDT, then IOFENCE.C writing 0x1234 and raising the interrupt.
+        let done = BASE_ADDR + 0x5_0000;
+        write(&mut ram, CQ, CMD_IODIR);
+        write(
+            &mut ram,
+            CQ + 16,
+            CMD_IOFENCE | IOFENCE_AV | IOFENCE_WSI | 0x1234 << 32,
+        );
+        write(&mut ram, CQ + 24, done >> 2);
+        iommu.write_u32(CQT, 2).unwrap();
+        assert_eq!(iommu.read_u32(CQH).unwrap(), 2);
+        assert_eq!(read(&mut ram, done), 0x1234);
+        assert_eq!(iommu.read_u32(IPSR).unwrap(), IPSR_CIP);
+
+        // An unknown command stops the queue.
+        write(&mut ram, CQ + 32, 0x7f);
+        iommu.write_u32(CQT, 3).unwrap();
+        assert_eq!(iommu.read_u32(CQH).unwrap(), 2);
+        assert!(iommu.read_u32(CQCSR).unwrap() & CQCSR_CMD_ILL != 0);
+    }
+}
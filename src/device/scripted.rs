@@ -0,0 +1,375 @@
+//! Scripted device, standing in for hardware in guest driver tests: reads return the values of
+//! a script, and writes are logged and checked against the values the script expects.
+//!
+//! The script has one register per line, offsets and values in decimal or `0x` hex:
+//!
+//! ```text
+//! # Reads of 0x00 return 1, then 2, then 3 forever.
+//! read  0x00 1 2 3
+//! # Reads of 0x08 return 0xa, 0xb, 0xa, 0xb...
+//! read  0x08 0xa 0xb repeat
+//! # The guest must write 0xdead then 0xbeef to 0x04.
+//! write 0x04 0xdead 0xbeef
+//! ```
+//!
+//! Reads of other offsets return 0, writes to offsets without expectations are only logged.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    str::FromStr,
+};
+
+use crate::{
+    board::{BoardControl, BoardRequest},
+    config::arch_config::WordType,
+    device::{
+        DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{SCRIPTED_DEVICE_BASE, SCRIPTED_DEVICE_NAME, SCRIPTED_DEVICE_SIZE},
+    },
+    device_poller::PollingEventTrait,
+};
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("line {line}: {msg}")]
+pub struct ScriptError {
+    pub line: usize,
+    pub msg: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ReadSequence {
+    values: Vec<u64>,
+    /// Start again after the last value instead of repeating it.
+    repeat: bool,
+}
+
+/// The parsed script of a [`ScriptedDevice`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    reads: BTreeMap<WordType, ReadSequence>,
+    writes: BTreeMap<WordType, Vec<u64>>,
+}
+
+impl FromStr for Script {
+    type Err = ScriptError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut script = Script::default();
+        for (idx, line) in s.lines().enumerate() {
+            let error = |msg: String| ScriptError { line: idx + 1, msg };
+            let line = line.split('#').next().unwrap().trim();
+            let mut words = line.split_whitespace();
+            let Some(kind) = words.next() else {
+                continue;
+            };
+            let offset = words
+                .next()
+                .ok_or_else(|| error("missing offset".into()))
+                .and_then(|word| parse_value(word).map_err(error))?;
+            if offset >= SCRIPTED_DEVICE_SIZE {
+                return Err(error(format!("offset {offset:#x} outside the device")));
+            }
+
+            let mut values = Vec::new();
+            let mut repeat = false;
+            for word in words {
+                match word {
+                    "repeat" if kind == "read" => repeat = true,
+                    _ if repeat => return Err(error("values after `repeat`".into())),
+                    _ => values.push(parse_value(word).map_err(error)?),
+                }
+            }
+            if values.is_empty() {
+                return Err(error(format!("no value for {offset:#x}")));
+            }
+
+            let duplicate = match kind {
+                "read" => script
+                    .reads
+                    .insert(offset, ReadSequence { values, repeat })
+                    .is_some(),
+                "write" => script.writes.insert(offset, values).is_some(),
+                other => return Err(error(format!("unknown access `{other}`"))),
+            };
+            if duplicate {
+                return Err(error(format!("{kind} of {offset:#x} scripted twice")));
+            }
+        }
+        Ok(script)
+    }
+}
+
+pub(crate) fn parse_value(word: &str) -> Result<u64, String> {
+    crate::parse_u64(word).map_err(|_| format!("invalid number `{word}`"))
+}
+
+/// A write of the guest, with the value the script expected if it did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptedWrite {
+    pub offset: WordType,
+    pub len: u32,
+    pub value: u64,
+    pub expected: Option<u64>,
+}
+
+impl ScriptedWrite {
+    pub fn is_mismatch(&self) -> bool {
+        self.expected.is_some_and(|expected| expected != self.value)
+    }
+}
+
+pub struct ScriptedDevice {
+    script: Script,
+    /// Index of the next value of each read sequence.
+    read_pos: BTreeMap<WordType, usize>,
+    /// The writes still expected at each offset.
+    expected: BTreeMap<WordType, VecDeque<u64>>,
+    log: Vec<ScriptedWrite>,
+    /// Halts the board on the first unexpected write when set.
+    control: Option<BoardControl>,
+}
+
+impl ScriptedDevice {
+    pub fn new(script: Script) -> Self {
+        let mut device = Self {
+            script,
+            read_pos: BTreeMap::new(),
+            expected: BTreeMap::new(),
+            log: Vec::new(),
+            control: None,
+        };
+        device.restart();
+        device
+    }
+
+    /// Halt the board through `control` when the guest writes another value than expected.
+    pub fn halt_on_mismatch(&mut self, control: BoardControl) {
+        self.control = Some(control);
+    }
+
+    /// Every write of the guest, oldest first.
+    pub fn writes(&self) -> &[ScriptedWrite] {
+        &self.log
+    }
+
+    /// The expected writes the guest did not make yet, as `(offset, value)`.
+    pub fn pending_writes(&self) -> Vec<(WordType, u64)> {
+        self.expected
+            .iter()
+            .flat_map(|(&offset, values)| values.iter().map(move |&value| (offset, value)))
+            .collect()
+    }
+
+    /// Whether the guest made exactly the expected writes, with the first difference otherwise.
+    pub fn check(&self) -> Result<(), String> {
+        if let Some(write) = self.log.iter().find(|write| write.is_mismatch()) {
+            return Err(format!(
+                "wrote {:#x} to {:#x}, expected {:#x}",
+                write.value,
+                write.offset,
+                write.expected.unwrap()
+            ));
+        }
+        match self.pending_writes().first() {
+            Some((offset, value)) => Err(format!("no write of {value:#x} to {offset:#x}")),
+            None => Ok(()),
+        }
+    }
+
+    /// Go back to the start of the script, the log is kept.
+    fn restart(&mut self) {
+        self.read_pos = self
+            .script
+            .reads
+            .keys()
+            .map(|&offset| (offset, 0))
+            .collect();
+        self.expected = self
+            .script
+            .writes
+            .iter()
+            .map(|(&offset, values)| (offset, values.iter().copied().collect()))
+            .collect();
+    }
+}
+
+impl DeviceTrait for ScriptedDevice {
+    fn read(&mut self, addr: WordType, _len: u32) -> Result<u64, MemError> {
+        let Some(sequence) = self.script.reads.get(&addr) else {
+            return Ok(0);
+        };
+        let pos = self.read_pos.get_mut(&addr).unwrap();
+        let value = sequence.values[*pos];
+        *pos += 1;
+        if *pos == sequence.values.len() {
+            *pos = if sequence.repeat { 0 } else { *pos - 1 };
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, addr: WordType, len: u32, data: u64) -> Result<(), MemError> {
+        let expected = self
+            .expected
+            .get_mut(&addr)
+            .and_then(|values| values.pop_front());
+        let write = ScriptedWrite {
+            offset: addr,
+            len,
+            value: data,
+            expected,
+        };
+        if write.is_mismatch() {
+            let reason = format!(
+                "scripted device: wrote {data:#x} to {addr:#x}, expected {:#x}",
+                expected.unwrap()
+            );
+            log::error!("{reason}");
+            if let Some(control) = &self.control {
+                control.request(BoardRequest::Halt(reason));
+            }
+        }
+        self.log.push(write);
+        Ok(())
+    }
+
+    fn sync(&mut self) {}
+
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+
+    fn reset(&mut self) {
+        self.restart();
+    }
+}
+
+impl MemMappedDeviceTrait for ScriptedDevice {
+    fn name() -> &'static str {
+        SCRIPTED_DEVICE_NAME
+    }
+
+    fn base() -> WordType {
+        SCRIPTED_DEVICE_BASE
+    }
+
+    fn size() -> WordType {
+        SCRIPTED_DEVICE_SIZE
+    }
+}
+
+impl Drop for ScriptedDevice {
+    fn drop(&mut self) {
+        for (offset, value) in self.pending_writes() {
+            log::warn!("scripted device: expected write of {value:#x} to {offset:#x} never came");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{
+        board::{
+            Board, BoardStatus,
+            virt::{SerialDestination, VirtBoardBuilder},
+        },
+        isa::DebugTarget,
+    };
+
+    use super::*;
+
+    const SCRIPT: &str = "
+        # status, then data
+        read  0x00 0 0 1
+        read  0x08 0xa 0xb repeat
+        write 0x04 0xdead 0xbeef
+    ";
+
+    #[test]
+    fn test_parse_script() {
+        let script: Script = SCRIPT.parse().unwrap();
+        assert_eq!(script.reads[&0].values, [0, 0, 1]);
+        assert!(script.reads[&8].repeat);
+        assert_eq!(script.writes[&4], [0xdead, 0xbeef]);
+
+        let error = |script: &str| script.parse::<Script>().unwrap_err();
+        assert_eq!(error("read").msg, "missing offset");
+        assert_eq!(error("\nread 0x0").line, 2);
+        assert_eq!(error("poke 0x0 1").msg, "unknown access `poke`");
+        assert_eq!(error("write 0x0 1 repeat").msg, "invalid number `repeat`");
+        assert_eq!(
+            error("read 0x0 1\nread 0x0 2").msg,
+            "read of 0x0 scripted twice"
+        );
+        assert!(error("read 0x1000 1").msg.contains("outside"));
+    }
+
+    #[test]
+    fn test_scripted_device() {
+        let mut device = ScriptedDevice::new(SCRIPT.parse().unwrap());
+        let reads: Vec<u64> = (0..4).map(|_| device.read(0x00, 4).unwrap()).collect();
+        assert_eq!(reads, [0, 0, 1, 1]);
+        let reads: Vec<u64> = (0..3).map(|_| device.read(0x08, 4).unwrap()).collect();
+        assert_eq!(reads, [0xa, 0xb, 0xa]);
+        assert_eq!(device.read(0x10, 4), Ok(0));
+
+        device.write(0x04, 4, 0xdead).unwrap();
+        assert_eq!(device.check(), Err("no write of 0xbeef to 0x4".into()));
+        device.write(0x04, 4, 0xbeee).unwrap();
+        device.write(0x0c, 1, 7).unwrap();
+        assert_eq!(
+            device.check(),
+            Err("wrote 0xbeee to 0x4, expected 0xbeef".into())
+        );
+        assert_eq!(device.writes().len(), 3);
+        assert_eq!(device.writes()[2].expected, None);
+
+        device.reset();
+        assert_eq!(device.read(0x00, 4), Ok(0));
+        assert_eq!(device.pending_writes(), [(0x04, 0xdead), (0x04, 0xbeef)]);
+    }
+
+    #[test]
+    fn test_scripted_device_halts() {
+        let program: Vec<u8> = [
+            0x0010_42b7u32, // lui x5, 0x104
+            0x0002_a303,    // lw x6, 0(x5)
+            0x0062_a223,    // sw x6, 4(x5)
+        ]
+        .iter()
+        .flat_map(|instr| instr.to_le_bytes())
+        .collect();
+        let device = Rc::new(RefCell::new(ScriptedDevice::new(
+            "read 0 5\nwrite 4 6".parse().unwrap(),
+        )));
+        let mut board = VirtBoardBuilder::new()
+            .serial(SerialDestination::Buffer)
+            .binary(program)
+            .machine(|board| board.scripted_device(device.clone()))
+            .build()
+            .unwrap();
+
+        for _ in 0..3 {
+            board.step().unwrap();
+        }
+        assert_eq!(board.cpu.read_reg(6), 5);
+        assert_eq!(
+            device.borrow().writes(),
+            [ScriptedWrite {
+                offset: 4,
+                len: 4,
+                value: 5,
+                expected: Some(6),
+            }]
+        );
+        // The board serves the halt request within a few steps.
+        for _ in 0..1000 {
+            if board.status() == BoardStatus::Halt {
+                break;
+            }
+            board.step().unwrap();
+        }
+        assert_eq!(board.status(), BoardStatus::Halt);
+    }
+}
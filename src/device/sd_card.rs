@@ -0,0 +1,452 @@
+//! SD card in SPI mode, attached to a [`SifiveSpi`](crate::device::sifive_spi::SifiveSpi)
+//! controller.
+//!
+//! The card is an SDHC card (block addressing, 512-byte blocks) that leaves the idle state on
+//! the first `ACMD41`. Commands and data are exchanged byte by byte: the response to a command
+//! is queued when its last byte arrives, and shifted out by the following transfers.
+
+use std::collections::VecDeque;
+
+use crate::device::{sifive_spi::SpiSlave, virtio::block_backend::BlockBackend};
+
+pub const SD_BLOCK_SIZE: usize = 512;
+
+const R1_READY: u8 = 0x00;
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_ADDRESS_ERROR: u8 = 0x20;
+const R1_PARAMETER_ERROR: u8 = 0x40;
+
+const TOKEN_START_BLOCK: u8 = 0xfe;
+const TOKEN_START_MULTI_WRITE: u8 = 0xfc;
+const TOKEN_STOP_TRAN: u8 = 0xfd;
+/// Data error token: the read failed.
+const TOKEN_ERROR: u8 = 0x01;
+const TOKEN_OUT_OF_RANGE: u8 = 0x08;
+
+const DATA_ACCEPTED: u8 = 0x05;
+const DATA_WRITE_ERROR: u8 = 0x0d;
+
+/// Voltage window 2.7-3.6V, power-up done and card capacity status (SDHC).
+const OCR: u32 = 0xc0ff_8000;
+
+/// SD spec 2.0, 1 and 4 bit bus.
+const SCR: [u8; 8] = [0x02, 0x35, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+const CID: [u8; 16] = [
+    0x00, b'R', b'V', b'R', b'V', b'E', b'M', b'U', 0x10, 0x00, 0x00, 0x00, 0x01, 0x01, 0x9a, 0x01,
+];
+
+/// CRC16-CCITT of a data block, as sent after it.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+enum WriteState {
+    /// Waiting for the start token of the next block.
+    Token { block: u64, multi: bool },
+    Data {
+        block: u64,
+        multi: bool,
+        buf: Vec<u8>,
+    },
+}
+
+pub struct SdCard {
+    backend: Box<dyn BlockBackend>,
+    idle: bool,
+    /// The previous command was `CMD55`, this one is an application command.
+    app_cmd: bool,
+
+    command: Vec<u8>,
+    output: VecDeque<u8>,
+
+    /// Next block of a `CMD18` multiple block read.
+    multi_read: Option<u64>,
+    write: Option<WriteState>,
+}
+
+impl SdCard {
+    pub fn new(backend: Box<dyn BlockBackend>) -> Self {
+        Self {
+            backend,
+            idle: true,
+            app_cmd: false,
+            command: Vec::with_capacity(6),
+            output: VecDeque::new(),
+            multi_read: None,
+            write: None,
+        }
+    }
+
+    fn blocks(&self) -> u64 {
+        self.backend.size() / SD_BLOCK_SIZE as u64
+    }
+
+    fn csd(&self) -> [u8; 16] {
+        // CSD version 2.0, the capacity is (C_SIZE + 1) * 512KiB.
+        let c_size = (self.blocks() / 1024).saturating_sub(1).min(0x3f_ffff) as u32;
+        [
+            0x40,
+            0x0e,
+            0x00,
+            0x32,
+            0x5b,
+            0x59,
+            0x00,
+            (c_size >> 16) as u8 & 0x3f,
+            (c_size >> 8) as u8,
+            c_size as u8,
+            0x7f,
+            0x80,
+            0x0a,
+            0x40,
+            0x00,
+            0x01,
+        ]
+    }
+
+    fn r1(&self, flags: u8) -> u8 {
+        flags | if self.idle { R1_IDLE } else { R1_READY }
+    }
+
+    fn respond(&mut self, bytes: &[u8]) {
+        // One byte of N_cr before the response.
+        self.output.push_back(0xff);
+        self.output.extend(bytes);
+    }
+
+    /// Queue a data block: start token, data and CRC.
+    fn send_data(&mut self, data: &[u8]) {
+        self.output.push_back(0xff);
+        self.output.push_back(TOKEN_START_BLOCK);
+        self.output.extend(data);
+        self.output.extend(crc16(data).to_be_bytes());
+    }
+
+    /// Queue block `block`, or an error token. Returns whether the block was sent.
+    fn send_block(&mut self, block: u64) -> bool {
+        if block >= self.blocks() {
+            self.output.extend([0xff, TOKEN_OUT_OF_RANGE]);
+            return false;
+        }
+
+        let mut buf = [0u8; SD_BLOCK_SIZE];
+        match self.backend.read_at(&mut buf, block * SD_BLOCK_SIZE as u64) {
+            Ok(_) => {
+                self.send_data(&buf);
+                true
+            }
+            Err(err) => {
+                log::warn!("sd: failed to read block {block}: {err}");
+                self.output.extend([0xff, TOKEN_ERROR]);
+                false
+            }
+        }
+    }
+
+    fn execute(&mut self) {
+        let cmd = self.command[0] & 0x3f;
+        let arg = u32::from_be_bytes(self.command[1..5].try_into().unwrap());
+        let app_cmd = std::mem::take(&mut self.app_cmd);
+        self.command.clear();
+        self.output.clear();
+
+        if app_cmd {
+            self.execute_app(cmd, arg);
+            return;
+        }
+
+        match cmd {
+            // GO_IDLE_STATE
+            0 => {
+                self.idle = true;
+                self.multi_read = None;
+                self.write = None;
+                self.respond(&[R1_IDLE]);
+            }
+            // SEND_OP_COND (MMC)
+            1 => {
+                self.idle = false;
+                self.respond(&[R1_READY]);
+            }
+            // SEND_IF_COND: echo the voltage and check pattern.
+            8 => self.respond(&[self.r1(0), 0, 0, (arg >> 8) as u8 & 0xf, arg as u8]),
+            // SEND_CSD / SEND_CID
+            9 | 10 => {
+                let data = if cmd == 9 { self.csd() } else { CID };
+                self.respond(&[self.r1(0)]);
+                self.send_data(&data);
+            }
+            // STOP_TRANSMISSION, a stuff byte is skipped before the response.
+            12 => {
+                self.multi_read = None;
+                self.respond(&[0xff, self.r1(0)]);
+            }
+            // SEND_STATUS (R2)
+            13 => self.respond(&[self.r1(0), 0]),
+            // SET_BLOCKLEN, fixed on SDHC cards.
+            16 => {
+                let flags = if arg as usize == SD_BLOCK_SIZE {
+                    0
+                } else {
+                    R1_PARAMETER_ERROR
+                };
+                self.respond(&[self.r1(flags)]);
+            }
+            // READ_SINGLE_BLOCK / READ_MULTIPLE_BLOCK
+            17 | 18 if !self.idle => {
+                let block = arg as u64;
+                if block >= self.blocks() {
+                    self.respond(&[self.r1(R1_ADDRESS_ERROR)]);
+                    return;
+                }
+                self.respond(&[self.r1(0)]);
+                if self.send_block(block) && cmd == 18 {
+                    self.multi_read = Some(block + 1);
+                }
+            }
+            // WRITE_BLOCK / WRITE_MULTIPLE_BLOCK
+            24 | 25 if !self.idle => {
+                let block = arg as u64;
+                if block >= self.blocks() {
+                    self.respond(&[self.r1(R1_ADDRESS_ERROR)]);
+                    return;
+                }
+                self.respond(&[self.r1(0)]);
+                self.write = Some(WriteState::Token {
+                    block,
+                    multi: cmd == 25,
+                });
+            }
+            // APP_CMD
+            55 => {
+                self.app_cmd = true;
+                self.respond(&[self.r1(0)]);
+            }
+            // READ_OCR
+            58 => {
+                let ocr = OCR.to_be_bytes();
+                self.respond(&[self.r1(0), ocr[0], ocr[1], ocr[2], ocr[3]]);
+            }
+            // CRC_ON_OFF, CRCs are never checked.
+            59 => self.respond(&[self.r1(0)]),
+            _ => {
+                log::debug!("sd: unsupported command CMD{cmd}({arg:#x})");
+                self.respond(&[self.r1(R1_ILLEGAL_COMMAND)]);
+            }
+        }
+    }
+
+    fn execute_app(&mut self, cmd: u8, arg: u32) {
+        match cmd {
+            // SD_SEND_OP_COND, initialization completes immediately.
+            41 => {
+                self.idle = false;
+                self.respond(&[R1_READY]);
+            }
+            // SEND_SCR
+            51 if !self.idle => {
+                self.respond(&[self.r1(0)]);
+                self.send_data(&SCR);
+            }
+            _ => {
+                log::debug!("sd: unsupported command ACMD{cmd}({arg:#x})");
+                self.respond(&[self.r1(R1_ILLEGAL_COMMAND)]);
+            }
+        }
+    }
+
+    fn receive_write(&mut self, state: WriteState, byte: u8) -> Option<WriteState> {
+        match state {
+            WriteState::Token { block, multi } => match byte {
+                TOKEN_START_BLOCK if !multi => Some(WriteState::Data {
+                    block,
+                    multi,
+                    buf: Vec::with_capacity(SD_BLOCK_SIZE + 2),
+                }),
+                TOKEN_START_MULTI_WRITE if multi => Some(WriteState::Data {
+                    block,
+                    multi,
+                    buf: Vec::with_capacity(SD_BLOCK_SIZE + 2),
+                }),
+                TOKEN_STOP_TRAN if multi => {
+                    // Busy for a byte.
+                    self.output.push_back(0x00);
+                    None
+                }
+                _ => Some(WriteState::Token { block, multi }),
+            },
+            WriteState::Data {
+                block,
+                multi,
+                mut buf,
+            } => {
+                buf.push(byte);
+                // Data followed by the (ignored) CRC.
+                if buf.len() < SD_BLOCK_SIZE + 2 {
+                    return Some(WriteState::Data { block, multi, buf });
+                }
+
+                let result = if block >= self.blocks() {
+                    Err(std::io::ErrorKind::InvalidInput.into())
+                } else {
+                    self.backend
+                        .write_at(&buf[..SD_BLOCK_SIZE], block * SD_BLOCK_SIZE as u64)
+                        .and_then(|()| self.backend.flush())
+                };
+                match result {
+                    Ok(()) => {
+                        self.output.extend([DATA_ACCEPTED, 0x00]);
+                        multi.then_some(WriteState::Token {
+                            block: block + 1,
+                            multi,
+                        })
+                    }
+                    Err(err) => {
+                        log::warn!("sd: failed to write block {block}: {err}");
+                        self.output.extend([DATA_WRITE_ERROR, 0x00]);
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl SpiSlave for SdCard {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        if self.output.is_empty()
+            && let Some(block) = self.multi_read
+        {
+            self.multi_read = self.send_block(block).then_some(block + 1);
+        }
+        let out = self.output.pop_front().unwrap_or(0xff);
+
+        // A command aborts a pending write, its first byte never looks like a token.
+        let is_command = self.command.is_empty() && byte & 0xc0 == 0x40;
+        if let Some(state) = self.write.take()
+            && !(is_command && matches!(state, WriteState::Token { .. }))
+        {
+            self.write = self.receive_write(state, byte);
+            return out;
+        }
+
+        if !self.command.is_empty() || is_command {
+            self.command.push(byte);
+            if self.command.len() == 6 {
+                self.execute();
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::virtio::block_backend;
+
+    /// Send a command and return its R1 response.
+    fn command(card: &mut SdCard, cmd: u8, arg: u32) -> u8 {
+        let mut frame = vec![0x40 | cmd];
+        frame.extend(arg.to_be_bytes());
+        frame.push(0x01);
+        for byte in frame {
+            card.transfer(byte);
+        }
+        (0..8)
+            .map(|_| card.transfer(0xff))
+            .find(|&r| r != 0xff)
+            .expect("no response")
+    }
+
+    /// Wait for a start token and read `len` bytes of data plus the CRC.
+    fn read_data(card: &mut SdCard, len: usize) -> Vec<u8> {
+        assert!((0..8).any(|_| card.transfer(0xff) == TOKEN_START_BLOCK));
+        let data: Vec<u8> = (0..len).map(|_| card.transfer(0xff)).collect();
+        let crc = u16::from_be_bytes([card.transfer(0xff), card.transfer(0xff)]);
+        assert_eq!(crc, crc16(&data));
+        data
+    }
+
+    fn write_data(card: &mut SdCard, token: u8, data: &[u8]) -> u8 {
+        card.transfer(0xff);
+        card.transfer(token);
+        for &byte in data {
+            card.transfer(byte);
+        }
+        card.transfer(0xff);
+        card.transfer(0xff);
+        let response = card.transfer(0xff);
+        while card.transfer(0xff) != 0xff {}
+        response
+    }
+
+    #[test]
+    fn test_sd_card() {
+        let path = "./tmp/test_sd_card.img";
+        std::fs::create_dir_all("./tmp").unwrap();
+        let mut image = vec![0u8; 4 * SD_BLOCK_SIZE];
+        image[SD_BLOCK_SIZE..2 * SD_BLOCK_SIZE].fill(0x11);
+        image[2 * SD_BLOCK_SIZE..3 * SD_BLOCK_SIZE].fill(0x22);
+        std::fs::write(path, image).unwrap();
+
+        let mut card = SdCard::new(block_backend::open(path, false).unwrap());
+
+        assert_eq!(command(&mut card, 0, 0), R1_IDLE);
+        assert_eq!(command(&mut card, 8, 0x1aa), R1_IDLE);
+        let r7: Vec<u8> = (0..4).map(|_| card.transfer(0xff)).collect();
+        assert_eq!(r7, [0, 0, 0x1, 0xaa]);
+        assert_eq!(command(&mut card, 17, 0), R1_IDLE | R1_ILLEGAL_COMMAND);
+
+        assert_eq!(command(&mut card, 55, 0), R1_IDLE);
+        assert_eq!(command(&mut card, 41, 1 << 30), R1_READY);
+        assert_eq!(command(&mut card, 58, 0), R1_READY);
+        let ocr: Vec<u8> = (0..4).map(|_| card.transfer(0xff)).collect();
+        assert_eq!(u32::from_be_bytes(ocr.try_into().unwrap()), OCR);
+
+        assert_eq!(command(&mut card, 9, 0), R1_READY);
+        assert_eq!(read_data(&mut card, 16)[0] >> 6, 1);
+
+        assert_eq!(command(&mut card, 17, 1), R1_READY);
+        assert_eq!(read_data(&mut card, SD_BLOCK_SIZE), [0x11; SD_BLOCK_SIZE]);
+
+        assert_eq!(command(&mut card, 18, 1), R1_READY);
+        assert_eq!(read_data(&mut card, SD_BLOCK_SIZE), [0x11; SD_BLOCK_SIZE]);
+        assert_eq!(read_data(&mut card, SD_BLOCK_SIZE), [0x22; SD_BLOCK_SIZE]);
+        assert_eq!(command(&mut card, 12, 0), R1_READY);
+
+        assert_eq!(command(&mut card, 24, 3), R1_READY);
+        let block = [0x33u8; SD_BLOCK_SIZE];
+        assert_eq!(
+            write_data(&mut card, TOKEN_START_BLOCK, &block) & 0x1f,
+            DATA_ACCEPTED
+        );
+
+        assert_eq!(command(&mut card, 25, 0), R1_READY);
+        let block = [0x44u8; SD_BLOCK_SIZE];
+        assert_eq!(
+            write_data(&mut card, TOKEN_START_MULTI_WRITE, &block) & 0x1f,
+            DATA_ACCEPTED
+        );
+        card.transfer(TOKEN_STOP_TRAN);
+        while card.transfer(0xff) != 0xff {}
+
+        assert_eq!(command(&mut card, 17, 4), R1_ADDRESS_ERROR);
+
+        let image = std::fs::read(path).unwrap();
+        assert!(image[..SD_BLOCK_SIZE].iter().all(|&b| b == 0x44));
+        assert!(image[3 * SD_BLOCK_SIZE..].iter().all(|&b| b == 0x33));
+    }
+}
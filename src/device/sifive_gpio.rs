@@ -0,0 +1,390 @@
+//! SiFive GPIO controller, as in the FU540, with its pins driven from the host: the debugger
+//! (`gpio set`) or a script of timed events stand in for buttons, and the outputs the guest drives
+//! (LEDs) are logged with target `gpio`.
+//!
+//! A pin reads as the output when the guest enables it, otherwise as the level the host drives,
+//! or as the pull-up when the host does not drive it. All 32 pins share one PLIC source.
+//!
+//! The script has one event per line, at a cycle of the virtual clock, in decimal or `0x` hex:
+//!
+//! ```text
+//! # Press the button on pin 3 at cycle 1000 and release it 500 cycles later.
+//! 1000 3 1
+//! +500 3 0
+//! # Stop driving pin 4, it floats to its pull-up.
+//! 2000 4 release
+//! ```
+
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::{
+    config::arch_config::WordType,
+    device::{
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{GPIO_BASE, GPIO_IRQ, GPIO_NAME, GPIO_SIZE},
+        plic::irq_line::{IrqDescriptor, IrqPin},
+        scripted::{ScriptError, parse_value},
+        stats::DeviceStats,
+    },
+    device_poller::PollingEventTrait,
+};
+
+pub const GPIO_PINS: u32 = 32;
+
+const INPUT_VAL: WordType = 0x00;
+const INPUT_EN: WordType = 0x04;
+const OUTPUT_EN: WordType = 0x08;
+const OUTPUT_VAL: WordType = 0x0c;
+const PUE: WordType = 0x10;
+const DS: WordType = 0x14;
+const RISE_IE: WordType = 0x18;
+const RISE_IP: WordType = 0x1c;
+const FALL_IE: WordType = 0x20;
+const FALL_IP: WordType = 0x24;
+const HIGH_IE: WordType = 0x28;
+const HIGH_IP: WordType = 0x2c;
+const LOW_IE: WordType = 0x30;
+const LOW_IP: WordType = 0x34;
+const IOF_EN: WordType = 0x38;
+const IOF_SEL: WordType = 0x3c;
+const OUT_XOR: WordType = 0x40;
+
+/// A change of a pin scheduled by a [`GpioScript`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpioEvent {
+    /// Cycle of the virtual clock.
+    pub at: u64,
+    pub pin: u32,
+    /// The level the host drives, `None` to stop driving the pin.
+    pub level: Option<bool>,
+}
+
+/// The events of a GPIO script, in time order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GpioScript {
+    pub events: Vec<GpioEvent>,
+}
+
+impl FromStr for GpioScript {
+    type Err = ScriptError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut events: Vec<GpioEvent> = Vec::new();
+        for (idx, line) in s.lines().enumerate() {
+            let error = |msg: String| ScriptError { line: idx + 1, msg };
+            let line = line.split('#').next().unwrap().trim();
+            let words: Vec<_> = line.split_whitespace().collect();
+            let [at, pin, level] = words[..] else {
+                if words.is_empty() {
+                    continue;
+                }
+                return Err(error("expected `CYCLE PIN LEVEL`".into()));
+            };
+
+            let last = events.last().map_or(0, |event| event.at);
+            let at = match at.strip_prefix('+') {
+                Some(delay) => last + parse_value(delay).map_err(error)?,
+                None => parse_value(at).map_err(error)?,
+            };
+            if at < last {
+                return Err(error(format!("cycle {at} is before the previous event")));
+            }
+            let pin = parse_value(pin).map_err(error)? as u32;
+            if pin >= GPIO_PINS {
+                return Err(error(format!("no pin {pin}")));
+            }
+            let level = match level {
+                "0" | "low" => Some(false),
+                "1" | "high" => Some(true),
+                "release" => None,
+                other => return Err(error(format!("invalid level `{other}`"))),
+            };
+            events.push(GpioEvent { at, pin, level });
+        }
+        Ok(GpioScript { events })
+    }
+}
+
+/// The pins as seen from the host, one bit per pin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct GpioState {
+    pub input_en: u32,
+    pub output_en: u32,
+    /// The level of each pin.
+    pub pins: u32,
+    /// The pins the host drives.
+    pub driven: u32,
+    /// Pending interrupts of any kind.
+    pub pending: u32,
+}
+
+#[derive(Default)]
+pub struct SifiveGpio {
+    input_en: u32,
+    output_en: u32,
+    output_val: u32,
+    pue: u32,
+    ds: u32,
+    rise_ie: u32,
+    rise_ip: u32,
+    fall_ie: u32,
+    fall_ip: u32,
+    high_ie: u32,
+    high_ip: u32,
+    low_ie: u32,
+    low_ip: u32,
+    iof_en: u32,
+    iof_sel: u32,
+    out_xor: u32,
+
+    /// Levels of the pins the host drives, in `driven`.
+    external: u32,
+    driven: u32,
+    /// The levels at the last update, to find the edges.
+    pins: u32,
+
+    irq: Option<IrqPin>,
+}
+
+impl SifiveGpio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drive `pin` to `level` from the host, or stop driving it with `None`.
+    pub fn drive(&mut self, pin: u32, level: Option<bool>) {
+        let bit = 1 << pin;
+        match level {
+            Some(level) => {
+                self.driven |= bit;
+                self.external = (self.external & !bit) | (level as u32) << pin;
+            }
+            None => self.driven &= !bit,
+        }
+        self.update();
+    }
+
+    pub fn state(&self) -> GpioState {
+        GpioState {
+            input_en: self.input_en,
+            output_en: self.output_en,
+            pins: self.pins,
+            driven: self.driven,
+            pending: self.rise_ip | self.fall_ip | self.high_ip | self.low_ip,
+        }
+    }
+
+    fn levels(&self) -> u32 {
+        let outputs = (self.output_val ^ self.out_xor) & self.output_en;
+        let inputs = (self.external & self.driven) | (self.pue & !self.driven);
+        outputs | (inputs & !self.output_en)
+    }
+
+    /// Latch the edges and levels of the inputs, log the outputs which changed and update the
+    /// interrupt.
+    fn update(&mut self) {
+        let old = self.pins;
+        let new = self.levels();
+        self.pins = new;
+
+        let changed_outputs = (old ^ new) & self.output_en;
+        for pin in (0..GPIO_PINS).filter(|pin| changed_outputs & 1 << pin != 0) {
+            log::info!(target: "gpio", "pin {} -> {}", pin, new >> pin & 1);
+        }
+
+        let (old_in, new_in) = (old & self.input_en, new & self.input_en);
+        self.rise_ip |= new_in & !old_in;
+        self.fall_ip |= old_in & !new_in;
+        self.high_ip |= new_in;
+        self.low_ip |= self.input_en & !new_in;
+
+        if let Some(irq) = &self.irq {
+            let pending = (self.rise_ip & self.rise_ie)
+                | (self.fall_ip & self.fall_ie)
+                | (self.high_ip & self.high_ie)
+                | (self.low_ip & self.low_ie);
+            irq.set_level(pending != 0);
+        }
+    }
+
+    fn read_impl<T>(&mut self, addr: WordType) -> Result<T, MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let value = match addr {
+            INPUT_VAL => self.pins & self.input_en,
+            INPUT_EN => self.input_en,
+            OUTPUT_EN => self.output_en,
+            OUTPUT_VAL => self.output_val,
+            PUE => self.pue,
+            DS => self.ds,
+            RISE_IE => self.rise_ie,
+            RISE_IP => self.rise_ip,
+            FALL_IE => self.fall_ie,
+            FALL_IP => self.fall_ip,
+            HIGH_IE => self.high_ie,
+            HIGH_IP => self.high_ip,
+            LOW_IE => self.low_ie,
+            LOW_IP => self.low_ip,
+            IOF_EN => self.iof_en,
+            IOF_SEL => self.iof_sel,
+            OUT_XOR => self.out_xor,
+            _ => return Err(MemError::LoadFault),
+        };
+        Ok(T::truncate_from(value))
+    }
+
+    fn write_impl<T>(&mut self, addr: WordType, data: T) -> Result<(), MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let data: u32 = data.truncate_to();
+        match addr {
+            INPUT_VAL => {}
+            INPUT_EN => {
+                // Enabled inputs start from their current level, without an edge.
+                let enabled = data & !self.input_en;
+                self.input_en = data;
+                self.pins = (self.pins & !enabled) | (self.levels() & enabled);
+            }
+            OUTPUT_EN => self.output_en = data,
+            OUTPUT_VAL => self.output_val = data,
+            PUE => self.pue = data,
+            DS => self.ds = data,
+            RISE_IE => self.rise_ie = data,
+            RISE_IP => self.rise_ip &= !data,
+            FALL_IE => self.fall_ie = data,
+            FALL_IP => self.fall_ip &= !data,
+            HIGH_IE => self.high_ie = data,
+            HIGH_IP => self.high_ip &= !data,
+            LOW_IE => self.low_ie = data,
+            LOW_IP => self.low_ip &= !data,
+            IOF_EN => self.iof_en = data,
+            IOF_SEL => self.iof_sel = data,
+            OUT_XOR => self.out_xor = data,
+            _ => return Err(MemError::StoreFault),
+        }
+        self.update();
+        Ok(())
+    }
+}
+
+impl DeviceTrait for SifiveGpio {
+    dispatch_read_write! { read_impl, write_impl }
+
+    fn sync(&mut self) {}
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+
+    fn connect_irq(&mut self, pin: IrqPin) {
+        self.irq = Some(pin);
+    }
+
+    fn report_stats(&mut self, stats: &mut DeviceStats) {
+        if let Some(pin) = &self.irq {
+            stats.record_irq(pin);
+        }
+    }
+
+    /// The host keeps driving its pins.
+    fn reset(&mut self) {
+        *self = Self {
+            external: self.external,
+            driven: self.driven,
+            irq: self.irq.take(),
+            ..Self::default()
+        };
+        self.pins = self.levels();
+        self.update();
+    }
+}
+
+impl MemMappedDeviceTrait for SifiveGpio {
+    fn name() -> &'static str {
+        GPIO_NAME
+    }
+    fn base() -> WordType {
+        GPIO_BASE
+    }
+    fn size() -> WordType {
+        GPIO_SIZE
+    }
+    fn irq() -> Option<IrqDescriptor> {
+        Some(IrqDescriptor::level(GPIO_IRQ))
+    }
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[4])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gpio_pins() {
+        let mut gpio = SifiveGpio::new();
+        let irq = IrqPin::new(IrqDescriptor::level(GPIO_IRQ));
+        gpio.connect_irq(irq.clone());
+
+        // Pin 0 is an output (LED), pin 1 an input with a pull-up (button).
+        gpio.write_u32(OUTPUT_EN, 0b01).unwrap();
+        gpio.write_u32(PUE, 0b10).unwrap();
+        gpio.write_u32(INPUT_EN, 0b10).unwrap();
+        gpio.write_u32(FALL_IE, 0b10).unwrap();
+        assert_eq!(gpio.read_u32(INPUT_VAL).unwrap(), 0b10);
+        assert_eq!(gpio.read_u32(FALL_IP).unwrap(), 0);
+
+        gpio.write_u32(OUTPUT_VAL, 1).unwrap();
+        assert_eq!(gpio.state().pins, 0b11);
+
+        // Pressing the button pulls the pin low.
+        gpio.drive(1, Some(false));
+        assert_eq!(gpio.read_u32(INPUT_VAL).unwrap(), 0);
+        assert_eq!(gpio.read_u32(FALL_IP).unwrap(), 0b10);
+        assert!(irq.level());
+        gpio.write_u32(FALL_IP, 0b10).unwrap();
+        assert!(!irq.level());
+
+        // Releasing it floats back to the pull-up.
+        gpio.drive(1, None);
+        assert_eq!(gpio.read_u32(RISE_IP).unwrap(), 0b10);
+        assert_eq!(gpio.read_u32(INPUT_VAL).unwrap(), 0b10);
+        assert_eq!(gpio.state().driven, 0);
+    }
+
+    #[test]
+    fn test_gpio_script() {
+        let script: GpioScript = "# button\n1000 3 1\n+0x10 3 low\n2000 4 release"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            script.events,
+            [
+                GpioEvent {
+                    at: 1000,
+                    pin: 3,
+                    level: Some(true)
+                },
+                GpioEvent {
+                    at: 1016,
+                    pin: 3,
+                    level: Some(false)
+                },
+                GpioEvent {
+                    at: 2000,
+                    pin: 4,
+                    level: None
+                },
+            ]
+        );
+
+        let err = "10 1 1\n5 1 0".parse::<GpioScript>().unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!("10 32 1".parse::<GpioScript>().is_err());
+        assert!("10 1".parse::<GpioScript>().is_err());
+    }
+}
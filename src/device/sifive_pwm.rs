@@ -0,0 +1,417 @@
+//! SiFive PWM (as found on the FE310 and FU540), which bare-metal programs also use as a periodic
+//! timer: with `pwmzerocmp` the counter restarts whenever `pwms` reaches `pwmcmp0`.
+//!
+//! | Offset | Name     | Description                                                          |
+//! |--------|----------|----------------------------------------------------------------------|
+//! | 0x00   | pwmcfg   | Bits 0-3 `pwmscale`, 8 `pwmsticky`, 9 `pwmzerocmp`, 12 `pwmenalways`, 13 `pwmenoneshot`, 28-31 `pwmcmpXip` |
+//! | 0x08   | pwmcount | The 31-bit counter                                                   |
+//! | 0x10   | pwms     | `pwmcount >> pwmscale`, 16 bits (read-only)                          |
+//! | 0x20   | pwmcmp0  | Comparators 0 to 3, 16 bits, 4 bytes apart                           |
+//!
+//! The counter runs at the virtual clock. A comparator fires when `pwms` reaches its value,
+//! setting its `pwmcmpXip` bit; without `pwmsticky` the bit falls again when the counter wraps.
+//! The four comparators share one edge-triggered PLIC source, raised on every firing. The PWM
+//! outputs are not modelled, so `pwmdeglitch`, `pwmcmpXcenter` and `pwmcmpXgang` only keep their
+//! value.
+
+use std::{cell::UnsafeCell, rc::Rc};
+
+use crate::{
+    config::arch_config::WordType,
+    device::{
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{PWM_BASE, PWM_IRQ, PWM_NAME, PWM_SIZE},
+        plic::irq_line::{IrqDescriptor, IrqPin},
+        stats::DeviceStats,
+    },
+    device_poller::PollingEventTrait,
+    vclock::{Timer, VirtualClockRef},
+};
+
+const PWMCFG: WordType = 0x00;
+const PWMCOUNT: WordType = 0x08;
+const PWMS: WordType = 0x10;
+const PWMCMP0: WordType = 0x20;
+const PWMCMP1: WordType = 0x24;
+const PWMCMP2: WordType = 0x28;
+const PWMCMP3: WordType = 0x2c;
+
+const CFG_SCALE: u32 = 0xf;
+const CFG_STICKY: u32 = 1 << 8;
+const CFG_ZEROCMP: u32 = 1 << 9;
+const CFG_ENALWAYS: u32 = 1 << 12;
+const CFG_ENONESHOT: u32 = 1 << 13;
+const CFG_IP_SHIFT: u32 = 28;
+/// The bits of `pwmcfg` kept as written, besides `pwmcmpXip`.
+const CFG_MASK: u32 = 0x0f0f_37ff;
+
+const COUNT_BITS: u32 = 31;
+const CMP_BITS: u32 = 16;
+
+pub struct SifivePwm {
+    clock: VirtualClockRef,
+    timer: Option<(Rc<UnsafeCell<Timer>>, u64)>,
+
+    cfg: u32,
+    /// `pwmcmpXip`, one bit per comparator.
+    ip: u32,
+    cmp: [u32; 4],
+    count: u64,
+    /// Clock time `count` was taken at.
+    counted_at: u64,
+
+    irq: Option<IrqPin>,
+}
+
+impl SifivePwm {
+    pub(crate) fn new(clock: VirtualClockRef) -> Self {
+        let now = clock.now();
+        Self {
+            clock,
+            timer: None,
+            cfg: 0,
+            ip: 0,
+            cmp: [0; 4],
+            count: 0,
+            counted_at: now,
+            irq: None,
+        }
+    }
+
+    /// Fire the comparators with `task` of `timer`, which must be registered with
+    /// [`Timer::register_repeating`] and return [`Self::timer_expired`].
+    pub(crate) fn attach_timer(&mut self, timer: Rc<UnsafeCell<Timer>>, task: u64) {
+        self.timer = Some((timer, task));
+    }
+
+    /// Catch up with the clock, returns when to do it again.
+    pub(crate) fn timer_expired(&mut self) -> Option<u64> {
+        self.catch_up();
+        self.next_event()
+    }
+
+    fn running(&self) -> bool {
+        self.cfg & (CFG_ENALWAYS | CFG_ENONESHOT) != 0
+    }
+
+    fn scale(&self) -> u32 {
+        self.cfg & CFG_SCALE
+    }
+
+    /// Counts between two restarts of `pwms`, the comparators fire once in each.
+    fn period(&self) -> u64 {
+        match self.cfg & CFG_ZEROCMP != 0 {
+            true => ((self.cmp[0] as u64) << self.scale()) + 1,
+            false => 1 << (CMP_BITS + self.scale()),
+        }
+    }
+
+    /// Counts until the counter wraps to zero, which ends a one-shot run.
+    fn wrap(&self) -> u64 {
+        match self.cfg & CFG_ZEROCMP != 0 {
+            true => self.period(),
+            false => 1 << COUNT_BITS,
+        }
+    }
+
+    fn pwms(&self) -> u32 {
+        ((self.count % self.period()) >> self.scale()) as u32
+    }
+
+    /// The count at which comparator `i` fires in each period, `None` if it never does.
+    fn firing_point(&self, i: usize) -> Option<u64> {
+        let point = (self.cmp[i] as u64) << self.scale();
+        (self.cmp[i] != 0 && point < self.period()).then_some(point)
+    }
+
+    /// The comparators whose output is high.
+    fn levels(&self) -> u32 {
+        let pwms = self.pwms();
+        (0..4)
+            .filter(|&i| pwms >= self.cmp[i])
+            .fold(0, |levels, i| levels | 1 << i)
+    }
+
+    /// Advance the counter to the current time and fire the comparators on the way.
+    fn catch_up(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.saturating_sub(self.counted_at);
+        self.counted_at = now;
+        if !self.running() || elapsed == 0 {
+            return;
+        }
+
+        let one_shot = self.cfg & CFG_ENALWAYS == 0;
+        let wrap = self.wrap();
+        let mut end = self.count + elapsed;
+        if one_shot {
+            end = end.min(wrap);
+        }
+
+        let period = self.period();
+        let start = self.count;
+        let fired = (0..4)
+            .filter(|&i| {
+                self.firing_point(i).is_some_and(|point| {
+                    let next = start / period * period + point;
+                    let next = if next > start { next } else { next + period };
+                    next <= end
+                })
+            })
+            .fold(0, |fired, i| fired | 1 << i);
+
+        if end >= wrap && one_shot {
+            self.cfg &= !CFG_ENONESHOT;
+        }
+        self.count = end % wrap;
+        self.ip = match self.cfg & CFG_STICKY != 0 {
+            true => self.ip | fired,
+            false => self.levels(),
+        };
+        self.update_irq(fired != 0);
+    }
+
+    /// Clock time of the next firing or wrap.
+    fn next_event(&self) -> Option<u64> {
+        if !self.running() {
+            return None;
+        }
+        let period = self.period();
+        let offset = self.count % period;
+        let to_wrap = self.wrap() - self.count;
+        let to_fire = (0..4).filter_map(|i| {
+            let point = self.firing_point(i)?;
+            Some(match point > offset {
+                true => point - offset,
+                false => period - offset + point,
+            })
+        });
+        to_fire
+            .chain([to_wrap])
+            .min()
+            .map(|delay| self.counted_at + delay)
+    }
+
+    fn reschedule(&self) {
+        if let Some((timer, task)) = &self.timer {
+            let due = self.next_event().unwrap_or(u64::MAX);
+            unsafe { timer.as_mut_unchecked() }.set_due(*task, due);
+        }
+    }
+
+    /// `fired`: a comparator fired since the last update, which must raise an interrupt even if
+    /// the level does not change.
+    fn update_irq(&self, fired: bool) {
+        if let Some(pin) = &self.irq {
+            let level = self.ip != 0;
+            if fired && (pin.level() || !level) {
+                pin.pulse();
+            }
+            pin.set_level(level);
+        }
+    }
+
+    fn read_impl<T>(&mut self, addr: WordType) -> Result<T, MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        self.catch_up();
+        let value = match addr {
+            PWMCFG => self.cfg | self.ip << CFG_IP_SHIFT,
+            PWMCOUNT => self.count as u32,
+            PWMS => self.pwms() & 0xffff,
+            PWMCMP0..=PWMCMP3 if addr.is_multiple_of(4) => self.cmp[(addr - PWMCMP0) as usize / 4],
+            _ => return Err(MemError::LoadFault),
+        };
+        Ok(T::truncate_from(value))
+    }
+
+    fn write_impl<T>(&mut self, addr: WordType, data: T) -> Result<(), MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let data: u32 = data.truncate_to();
+        self.catch_up();
+        match addr {
+            PWMCFG => {
+                self.cfg = data & CFG_MASK;
+                self.ip = data >> CFG_IP_SHIFT;
+            }
+            PWMCOUNT => self.count = (data & ((1 << COUNT_BITS) - 1)) as u64 % self.wrap(),
+            PWMS => {}
+            PWMCMP0..=PWMCMP3 if addr.is_multiple_of(4) => {
+                self.cmp[(addr - PWMCMP0) as usize / 4] = data & ((1 << CMP_BITS) - 1);
+                self.count %= self.wrap();
+            }
+            _ => return Err(MemError::StoreFault),
+        }
+        if self.cfg & CFG_STICKY == 0 {
+            self.ip = self.levels();
+        }
+        self.update_irq(false);
+        self.reschedule();
+        Ok(())
+    }
+}
+
+impl DeviceTrait for SifivePwm {
+    dispatch_read_write! { read_impl, write_impl }
+
+    fn sync(&mut self) {}
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+
+    fn connect_irq(&mut self, pin: IrqPin) {
+        self.irq = Some(pin);
+    }
+
+    fn report_stats(&mut self, stats: &mut DeviceStats) {
+        if let Some(pin) = &self.irq {
+            stats.record_irq(pin);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.cfg = 0;
+        self.ip = 0;
+        self.cmp = [0; 4];
+        self.count = 0;
+        self.counted_at = self.clock.now();
+        self.update_irq(false);
+        self.reschedule();
+    }
+}
+
+impl MemMappedDeviceTrait for SifivePwm {
+    fn name() -> &'static str {
+        PWM_NAME
+    }
+    fn base() -> WordType {
+        PWM_BASE
+    }
+    fn size() -> WordType {
+        PWM_SIZE
+    }
+    fn irq() -> Option<IrqDescriptor> {
+        Some(IrqDescriptor::edge(PWM_IRQ))
+    }
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[4])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    struct Bench {
+        clock: VirtualClockRef,
+        timer: Rc<UnsafeCell<Timer>>,
+        pwm: Rc<RefCell<SifivePwm>>,
+        pin: IrqPin,
+    }
+
+    impl Bench {
+        fn new() -> Self {
+            let clock = VirtualClockRef::new();
+            let timer = Rc::new(UnsafeCell::new(Timer::new(clock.clone())));
+            let pwm = Rc::new(RefCell::new(SifivePwm::new(clock.clone())));
+            let task = unsafe { timer.as_mut_unchecked() }.register_repeating({
+                let pwm = pwm.clone();
+                move || pwm.borrow_mut().timer_expired()
+            });
+            pwm.borrow_mut().attach_timer(timer.clone(), task);
+            let pin = IrqPin::new(IrqDescriptor::edge(PWM_IRQ));
+            pwm.borrow_mut().connect_irq(pin.clone());
+            Self {
+                clock,
+                timer,
+                pwm,
+                pin,
+            }
+        }
+
+        fn advance(&self, cycles: u64) {
+            for _ in 0..cycles {
+                self.clock.advance(1);
+                unsafe { self.timer.as_mut_unchecked() }.tick();
+            }
+        }
+    }
+
+    #[test]
+    fn test_pwm_periodic_interrupt() {
+        let bench = Bench::new();
+        {
+            let mut pwm = bench.pwm.borrow_mut();
+            pwm.write_u32(PWMCMP0, 99).unwrap();
+            pwm.write_u32(PWMCFG, CFG_ENALWAYS | CFG_ZEROCMP | CFG_STICKY)
+                .unwrap();
+        }
+        // Comparators at 0 are high, until `pwmcfg` clears their bits.
+        let raised = bench.pin.raised();
+        assert!(!bench.pin.level());
+
+        // The counter goes from 0 to 99 and restarts, the comparator fires at 99.
+        bench.advance(98);
+        assert_eq!(bench.pin.raised(), raised);
+        bench.advance(1);
+        assert_eq!(bench.pin.raised(), raised + 1);
+        assert!(bench.pin.level());
+        let mut pwm = bench.pwm.borrow_mut();
+        assert_eq!(pwm.read_u32(PWMCFG).unwrap() >> CFG_IP_SHIFT, 1);
+        assert_eq!(pwm.read_u32(PWMCOUNT).unwrap(), 99);
+
+        // Clear the sticky bit, as an interrupt handler does.
+        let cfg = pwm.read_u32(PWMCFG).unwrap();
+        pwm.write_u32(PWMCFG, cfg & !(0xf << CFG_IP_SHIFT)).unwrap();
+        assert!(!bench.pin.level());
+        drop(pwm);
+
+        bench.advance(100);
+        assert_eq!(bench.pin.raised(), raised + 2);
+        assert_eq!(bench.pwm.borrow_mut().read_u32(PWMCOUNT).unwrap(), 99);
+        // Firings between two reads are not lost.
+        bench.clock.advance(1000);
+        let mut pwm = bench.pwm.borrow_mut();
+        assert_eq!(pwm.read_u32(PWMCOUNT).unwrap(), 99);
+        assert_eq!(pwm.read_u32(PWMCFG).unwrap() >> CFG_IP_SHIFT, 1);
+    }
+
+    #[test]
+    fn test_pwm_scale_and_one_shot() {
+        let bench = Bench::new();
+        {
+            let mut pwm = bench.pwm.borrow_mut();
+            for (i, cmp) in [0x20, 0x10, 0x30, 0x40].into_iter().enumerate() {
+                pwm.write_u32(PWMCMP0 + 4 * i as WordType, cmp).unwrap();
+            }
+            pwm.write_u32(PWMCFG, CFG_ENONESHOT | 4).unwrap();
+        }
+        let raised = bench.pin.raised();
+        // Comparator 1 fires first, at 0x10 << 4.
+        bench.advance(0xff);
+        assert_eq!(bench.pin.raised(), raised);
+        bench.advance(1);
+        assert_eq!(bench.pin.raised(), raised + 1);
+        let mut pwm = bench.pwm.borrow_mut();
+        assert_eq!(pwm.read_u32(PWMS).unwrap(), 0x10);
+        assert_eq!(pwm.read_u32(PWMCFG).unwrap() >> CFG_IP_SHIFT, 0b0010);
+        // A comparator at 0 is always high.
+        pwm.write_u32(PWMCMP2, 0).unwrap();
+        assert_eq!(pwm.read_u32(PWMCFG).unwrap() >> CFG_IP_SHIFT, 0b0110);
+        drop(pwm);
+
+        // The one-shot run ends when the 31-bit counter wraps, without `pwmsticky` the bits
+        // follow the comparators.
+        bench.clock.advance(1 << COUNT_BITS);
+        let mut pwm = bench.pwm.borrow_mut();
+        assert_eq!(pwm.read_u32(PWMCOUNT).unwrap(), 0);
+        assert_eq!(pwm.read_u32(PWMCFG).unwrap() & CFG_ENONESHOT, 0);
+        assert_eq!(pwm.read_u32(PWMCFG).unwrap() >> CFG_IP_SHIFT, 0b0100);
+        assert!(pwm.read_u32(0x30).is_err());
+    }
+}
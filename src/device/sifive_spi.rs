@@ -0,0 +1,358 @@
+//! SiFive SPI controller (as found on the FU540), with up to [`SPI_CS_COUNT`] devices such as an
+//! [`SdCard`](crate::device::sd_card::SdCard) or an [`SpiEeprom`](crate::device::eeprom::SpiEeprom).
+//!
+//! Transfers complete instantly: every byte written to `txdata` is shifted out and the byte
+//! shifted in lands in the receive FIFO right away.
+
+use std::collections::VecDeque;
+
+use crate::{
+    config::arch_config::WordType,
+    device::{
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{SPI_BASE, SPI_IRQ, SPI_NAME, SPI_SIZE},
+        plic::irq_line::{IrqDescriptor, IrqPin},
+        stats::DeviceStats,
+    },
+    device_poller::PollingEventTrait,
+};
+
+/// A device on the SPI bus.
+pub trait SpiSlave {
+    /// Shift `byte` out to the device and return the byte it shifted back.
+    fn transfer(&mut self, byte: u8) -> u8;
+
+    /// Called when the chip select line changes.
+    fn select(&mut self, _selected: bool) {}
+}
+
+/// Number of chip select lines.
+pub const SPI_CS_COUNT: usize = 4;
+
+const FIFO_DEPTH: usize = 8;
+
+const SCKDIV: WordType = 0x00;
+const SCKMODE: WordType = 0x04;
+const CSID: WordType = 0x10;
+const CSDEF: WordType = 0x14;
+const CSMODE: WordType = 0x18;
+const DELAY0: WordType = 0x28;
+const DELAY1: WordType = 0x2c;
+const FMT: WordType = 0x40;
+const TXDATA: WordType = 0x48;
+const RXDATA: WordType = 0x4c;
+const TXMARK: WordType = 0x50;
+const RXMARK: WordType = 0x54;
+const FCTRL: WordType = 0x60;
+const FFMT: WordType = 0x64;
+const IE: WordType = 0x70;
+const IP: WordType = 0x74;
+
+/// `txdata.full` / `rxdata.empty`.
+const FIFO_FLAG: u32 = 1 << 31;
+
+const IP_TXWM: u32 = 1 << 0;
+const IP_RXWM: u32 = 1 << 1;
+
+/// `csmode` values.
+const CSMODE_AUTO: u32 = 0;
+const CSMODE_OFF: u32 = 3;
+
+/// `fmt.dir`: the receive FIFO is not filled in TX direction.
+const FMT_DIR_TX: u32 = 1 << 3;
+
+pub struct SifiveSpi {
+    slaves: [Option<Box<dyn SpiSlave>>; SPI_CS_COUNT],
+    /// Whether the device on chip select `csid` is selected.
+    selected: bool,
+
+    sckdiv: u32,
+    sckmode: u32,
+    csid: u32,
+    csdef: u32,
+    csmode: u32,
+    delay0: u32,
+    delay1: u32,
+    fmt: u32,
+    txmark: u32,
+    rxmark: u32,
+    fctrl: u32,
+    ffmt: u32,
+    ie: u32,
+
+    rx_fifo: VecDeque<u8>,
+
+    irq: Option<IrqPin>,
+}
+
+impl SifiveSpi {
+    pub fn new() -> Self {
+        Self {
+            slaves: Default::default(),
+            selected: false,
+
+            // Reset values from the FU540 manual.
+            sckdiv: 0x3,
+            sckmode: 0,
+            csid: 0,
+            csdef: 0x1,
+            csmode: CSMODE_AUTO,
+            delay0: 0x0001_0001,
+            delay1: 0x0000_0001,
+            fmt: 0x0008_0000,
+            txmark: 0,
+            rxmark: 0,
+            fctrl: 0,
+            ffmt: 0,
+            ie: 0,
+
+            rx_fifo: VecDeque::with_capacity(FIFO_DEPTH),
+
+            irq: None,
+        }
+    }
+
+    /// Attach `slave` to chip select `cs`, which must be below [`SPI_CS_COUNT`].
+    pub fn with_slave(mut self, cs: usize, slave: Box<dyn SpiSlave>) -> Self {
+        self.slaves[cs] = Some(slave);
+        self
+    }
+
+    fn slave(&mut self) -> Option<&mut Box<dyn SpiSlave>> {
+        self.slaves.get_mut(self.csid as usize)?.as_mut()
+    }
+
+    fn ip(&self) -> u32 {
+        // The transmit FIFO is always drained immediately.
+        let mut ip = if self.txmark > 0 { IP_TXWM } else { 0 };
+        if self.rx_fifo.len() > self.rxmark as usize {
+            ip |= IP_RXWM;
+        }
+        ip
+    }
+
+    fn update_irq(&self) {
+        if let Some(pin) = &self.irq {
+            pin.set_level(self.ip() & self.ie != 0);
+        }
+    }
+
+    fn set_selected(&mut self, selected: bool) {
+        if self.selected != selected {
+            self.selected = selected;
+            if let Some(slave) = self.slave() {
+                slave.select(selected);
+            }
+        }
+    }
+
+    /// The chip selects are active-low by default, `csdef` holds the inactive levels.
+    fn update_cs(&mut self) {
+        let hold = self.csmode != CSMODE_AUTO && self.csmode != CSMODE_OFF;
+        self.set_selected(hold);
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        let auto = self.csmode == CSMODE_AUTO;
+        if auto {
+            self.set_selected(true);
+        }
+
+        let selected = self.selected;
+        let rx = match self.slave() {
+            Some(slave) if selected => slave.transfer(byte),
+            _ => 0xff,
+        };
+        if self.fmt & FMT_DIR_TX == 0 && self.rx_fifo.len() < FIFO_DEPTH {
+            self.rx_fifo.push_back(rx);
+        }
+
+        if auto {
+            self.set_selected(false);
+        }
+    }
+
+    fn read_impl<T>(&mut self, addr: WordType) -> Result<T, MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let value = match addr {
+            SCKDIV => self.sckdiv,
+            SCKMODE => self.sckmode,
+            CSID => self.csid,
+            CSDEF => self.csdef,
+            CSMODE => self.csmode,
+            DELAY0 => self.delay0,
+            DELAY1 => self.delay1,
+            FMT => self.fmt,
+            TXDATA => 0,
+            RXDATA => {
+                let value = self.rx_fifo.pop_front().map_or(FIFO_FLAG, u32::from);
+                self.update_irq();
+                value
+            }
+            TXMARK => self.txmark,
+            RXMARK => self.rxmark,
+            FCTRL => self.fctrl,
+            FFMT => self.ffmt,
+            IE => self.ie,
+            IP => self.ip(),
+            _ => return Err(MemError::LoadFault),
+        };
+        Ok(T::truncate_from(value))
+    }
+
+    fn write_impl<T>(&mut self, addr: WordType, data: T) -> Result<(), MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let data: u32 = data.truncate_to();
+        match addr {
+            SCKDIV => self.sckdiv = data & 0xfff,
+            SCKMODE => self.sckmode = data & 0b11,
+            CSID => {
+                // Release the device selected so far.
+                self.set_selected(false);
+                self.csid = data;
+                self.update_cs();
+            }
+            CSDEF => self.csdef = data,
+            CSMODE => {
+                self.csmode = data & 0b11;
+                self.update_cs();
+            }
+            DELAY0 => self.delay0 = data,
+            DELAY1 => self.delay1 = data,
+            FMT => self.fmt = data,
+            TXDATA => self.transmit(data as u8),
+            RXDATA => {}
+            TXMARK => self.txmark = data & 0b111,
+            RXMARK => self.rxmark = data & 0b111,
+            FCTRL => self.fctrl = data & 1,
+            FFMT => self.ffmt = data,
+            IE => self.ie = data & 0b11,
+            IP => {}
+            _ => return Err(MemError::StoreFault),
+        }
+        self.update_irq();
+        Ok(())
+    }
+}
+
+impl Default for SifiveSpi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceTrait for SifiveSpi {
+    dispatch_read_write! { read_impl, write_impl }
+
+    fn sync(&mut self) {}
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+
+    fn connect_irq(&mut self, pin: IrqPin) {
+        self.irq = Some(pin);
+    }
+
+    fn report_stats(&mut self, stats: &mut DeviceStats) {
+        if let Some(pin) = &self.irq {
+            stats.record_irq(pin);
+        }
+    }
+}
+
+impl MemMappedDeviceTrait for SifiveSpi {
+    fn name() -> &'static str {
+        SPI_NAME
+    }
+    fn base() -> WordType {
+        SPI_BASE
+    }
+    fn size() -> WordType {
+        SPI_SIZE
+    }
+    fn irq() -> Option<IrqDescriptor> {
+        Some(IrqDescriptor::level(SPI_IRQ))
+    }
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[4])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    /// Echoes every byte plus one, and records the chip select changes.
+    struct Loopback(Rc<RefCell<Vec<bool>>>);
+
+    impl SpiSlave for Loopback {
+        fn transfer(&mut self, byte: u8) -> u8 {
+            byte.wrapping_add(1)
+        }
+        fn select(&mut self, selected: bool) {
+            self.0.borrow_mut().push(selected);
+        }
+    }
+
+    #[test]
+    fn test_spi_transfer() {
+        let cs = Rc::new(RefCell::new(Vec::new()));
+        let mut spi = SifiveSpi::new().with_slave(0, Box::new(Loopback(cs.clone())));
+
+        assert_eq!(spi.read_u32(RXDATA).unwrap(), FIFO_FLAG);
+
+        // Hold chip select across both bytes.
+        spi.write_u32(CSMODE, 2).unwrap();
+        spi.write_u32(TXDATA, 0x10).unwrap();
+        spi.write_u32(TXDATA, 0x20).unwrap();
+        spi.write_u32(CSMODE, CSMODE_AUTO).unwrap();
+        assert_eq!(*cs.borrow(), vec![true, false]);
+
+        spi.write_u32(RXMARK, 0).unwrap();
+        assert_ne!(spi.read_u32(IP).unwrap() & IP_RXWM, 0);
+        assert_eq!(spi.read_u32(RXDATA).unwrap(), 0x11);
+        assert_eq!(spi.read_u32(RXDATA).unwrap(), 0x21);
+        assert_eq!(spi.read_u32(RXDATA).unwrap(), FIFO_FLAG);
+        assert_eq!(spi.read_u32(IP).unwrap() & IP_RXWM, 0);
+
+        // TX-only transfers leave the receive FIFO alone.
+        spi.write_u32(FMT, FMT_DIR_TX).unwrap();
+        spi.write_u32(TXDATA, 0x30).unwrap();
+        assert_eq!(spi.read_u32(RXDATA).unwrap(), FIFO_FLAG);
+
+        assert!(!SifiveSpi::access_widths().allows(1));
+    }
+
+    #[test]
+    fn test_spi_without_slave() {
+        let mut spi = SifiveSpi::new();
+        spi.write_u32(TXDATA, 0x40).unwrap();
+        assert_eq!(spi.read_u32(RXDATA).unwrap(), 0xff);
+    }
+
+    #[test]
+    fn test_spi_chip_selects() {
+        let cs0 = Rc::new(RefCell::new(Vec::new()));
+        let cs2 = Rc::new(RefCell::new(Vec::new()));
+        let mut spi = SifiveSpi::new()
+            .with_slave(0, Box::new(Loopback(cs0.clone())))
+            .with_slave(2, Box::new(Loopback(cs2.clone())));
+
+        spi.write_u32(CSMODE, 2).unwrap();
+        spi.write_u32(CSID, 2).unwrap();
+        spi.write_u32(TXDATA, 0x50).unwrap();
+        assert_eq!(spi.read_u32(RXDATA).unwrap(), 0x51);
+        spi.write_u32(CSID, 1).unwrap();
+        spi.write_u32(TXDATA, 0x50).unwrap();
+        assert_eq!(spi.read_u32(RXDATA).unwrap(), 0xff);
+
+        assert_eq!(*cs0.borrow(), vec![true, false]);
+        assert_eq!(*cs2.borrow(), vec![true, false]);
+    }
+}
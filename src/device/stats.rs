@@ -0,0 +1,45 @@
+//! Runtime counters of the memory-mapped devices, to check whether a guest driver actually talks
+//! to a device.
+
+use serde::Serialize;
+
+use crate::{
+    config::arch_config::WordType,
+    device::plic::{ExternalInterrupt, irq_line::IrqPin},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceStats {
+    pub name: String,
+    pub base: WordType,
+    pub size: WordType,
+
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+
+    /// The PLIC source of the device, if it has one.
+    pub irq: Option<ExternalInterrupt>,
+    /// Number of times the device raised its interrupt.
+    pub irqs_raised: u64,
+
+    /// Requests made available by the driver but not processed yet, for VirtIO devices.
+    pub queue_depth: Option<u32>,
+}
+
+impl DeviceStats {
+    pub(crate) fn record_irq(&mut self, pin: &IrqPin) {
+        self.irq = Some(pin.id());
+        self.irqs_raised = pin.raised();
+    }
+}
+
+/// Accesses to a device through the memory map.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct AccessCounters {
+    pub(crate) reads: u64,
+    pub(crate) writes: u64,
+    pub(crate) bytes_read: u64,
+    pub(crate) bytes_written: u64,
+}
@@ -2,13 +2,12 @@
 
 #![cfg(feature = "test-device")]
 use std::{
-    hint::unlikely,
     mem::transmute_copy,
     sync::{
         Arc,
         atomic::{AtomicU32, Ordering},
     },
-    time::{self, Duration, SystemTime},
+    time::Duration,
 };
 
 use crossbeam::channel::{Receiver, Sender};
@@ -16,12 +15,12 @@ use crossbeam::channel::{Receiver, Sender};
 use crate::{
     config::arch_config::WordType,
     device::{
-        DeviceTrait, MemError, MemMappedDeviceTrait,
-        config::{TEST_DEVICE_BASE, TEST_DEVICE_SIZE},
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{TEST_DEVICE_BASE, TEST_DEVICE_NAME, TEST_DEVICE_SIZE},
         plic::ExternalInterrupt,
     },
     device_poller::PollingEventTrait,
-    utils::check_align,
+    vclock::DeviceInstant,
 };
 
 pub const TEST_DEVICE_INTERRUPT_ID: ExternalInterrupt = 63;
@@ -50,7 +49,7 @@ enum PollerDataPackage {
 }
 pub struct TestDevicePoller {
     interrupt_mask_register: Arc<AtomicU32>,
-    pre_time: SystemTime,
+    pre_time: DeviceInstant,
     step_time: Duration,
     receiver: Receiver<PollerDataPackage>,
 }
@@ -59,7 +58,7 @@ impl TestDevicePoller {
     fn new(receiver: Receiver<PollerDataPackage>, imr: Arc<AtomicU32>) -> Self {
         Self {
             interrupt_mask_register: imr,
-            pre_time: SystemTime::now(),
+            pre_time: DeviceInstant::now(),
             step_time: Duration::from_micros(0),
             receiver,
         }
@@ -90,10 +89,6 @@ impl TestDevice {
     where
         T: crate::utils::UnsignedInteger,
     {
-        if unlikely(!check_align::<u32>(addr)) {
-            return Err(crate::device::MemError::LoadMisaligned);
-        }
-
         let data = match addr {
             0x00 => unsafe { transmute_copy(&self.layout.control_register) },
             0x04 => unsafe { transmute_copy(&self.layout.interrupt_mask_register) },
@@ -108,10 +103,6 @@ impl TestDevice {
     where
         T: crate::utils::UnsignedInteger,
     {
-        if unlikely(!check_align::<u32>(addr)) {
-            return Err(crate::device::MemError::StoreMisaligned);
-        }
-
         let data_u64 = data.into();
         let data_u32 = data_u64 as u32;
 
@@ -156,12 +147,18 @@ impl DeviceTrait for TestDevice {
 }
 
 impl MemMappedDeviceTrait for TestDevice {
+    fn name() -> &'static str {
+        TEST_DEVICE_NAME
+    }
     fn base() -> WordType {
         TEST_DEVICE_BASE
     }
     fn size() -> WordType {
         TEST_DEVICE_SIZE
     }
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[4])
+    }
 }
 
 impl PollingEventTrait for TestDevicePoller {
@@ -170,7 +167,7 @@ impl PollingEventTrait for TestDevicePoller {
             match v {
                 PollerDataPackage::Data(t) => {
                     self.step_time = Duration::from_micros(t);
-                    self.pre_time = time::SystemTime::now();
+                    self.pre_time = DeviceInstant::now();
                 }
             }
         }
@@ -178,8 +175,8 @@ impl PollingEventTrait for TestDevicePoller {
             return None;
         }
 
-        let cur = time::SystemTime::now();
-        if cur.duration_since(self.pre_time).unwrap() > self.step_time {
+        let cur = DeviceInstant::now();
+        if cur.duration_since(self.pre_time) > self.step_time {
             self.pre_time = cur;
             // trigger only one time -> use for debug.
             // self.interrupt_mask_register
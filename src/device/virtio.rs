@@ -1,5 +1,8 @@
+pub mod block_backend;
 pub mod common;
 pub mod config;
+pub mod dma;
+pub mod shared_memory;
 pub mod virtio_blk;
 pub mod virtio_device;
 pub mod virtio_mmio;
@@ -0,0 +1,177 @@
+//! Disk image formats behind a VirtIO block device.
+//!
+//! [`open`] detects the format from the image header: qcow2 images are recognized by their
+//! magic, everything else is a raw image.
+//!
+//! When the writes reach the host disk depends on the [`CacheMode`] of the device. A
+//! [`FaultyBackend`] wraps the image to inject errors for testing.
+
+mod fault;
+mod qcow2;
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    str::FromStr,
+};
+
+pub use fault::{FaultConfig, FaultControl, FaultyBackend};
+pub use qcow2::Qcow2Image;
+
+pub trait BlockBackend: Send {
+    /// Size of the disk as seen by the guest, in bytes.
+    fn size(&self) -> u64;
+
+    fn is_read_only(&self) -> bool;
+
+    /// Read up to `buf.len()` bytes at `offset`, stopping early at the end of the disk.
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+    /// Make the writes so far durable on the host disk.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// When the writes of the guest reach the host disk, as the `cache=` option of a block device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheMode {
+    /// The device has a write cache: writes reach the disk when the guest flushes, the driver
+    /// may turn the cache off through the `writeback` config field.
+    #[default]
+    Writeback,
+    /// Every write is synced to the disk before it completes.
+    Writethrough,
+    /// The image is opened for synchronous writes (`O_DSYNC` on Linux), so every write reaches
+    /// the disk without a separate sync. Elsewhere like [`CacheMode::Writethrough`].
+    DirectSync,
+}
+
+impl CacheMode {
+    /// Whether the device reports a volatile write cache to the guest.
+    pub fn has_write_cache(self) -> bool {
+        self == Self::Writeback
+    }
+}
+
+impl FromStr for CacheMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "writeback" => Ok(Self::Writeback),
+            "writethrough" => Ok(Self::Writethrough),
+            "directsync" => Ok(Self::DirectSync),
+            other => Err(format!("Unknown cache mode: {}", other)),
+        }
+    }
+}
+
+/// Open the image at `path`, in its own format.
+pub fn open(path: &str, read_only: bool) -> io::Result<Box<dyn BlockBackend>> {
+    open_with_cache(path, read_only, CacheMode::Writeback)
+}
+
+/// Open the image at `path`, in its own format, for a device in the cache mode `cache`.
+pub fn open_with_cache(
+    path: &str,
+    read_only: bool,
+    cache: CacheMode,
+) -> io::Result<Box<dyn BlockBackend>> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(!read_only);
+    #[cfg(target_os = "linux")]
+    if cache == CacheMode::DirectSync {
+        use std::os::unix::fs::OpenOptionsExt;
+        // O_DSYNC of the generic Linux ABI.
+        options.custom_flags(0o10000);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = cache;
+    let mut file = options.open(path)?;
+
+    if Qcow2Image::probe(&mut file)? {
+        Ok(Box::new(Qcow2Image::new(file, read_only)?))
+    } else {
+        Ok(Box::new(RawImage::new(file, read_only)?))
+    }
+}
+
+/// The disk content byte-for-byte in a file.
+pub struct RawImage {
+    file: File,
+    size: u64,
+    read_only: bool,
+}
+
+impl RawImage {
+    pub fn new(mut file: File, read_only: bool) -> io::Result<Self> {
+        let size = file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            file,
+            size,
+            read_only,
+        })
+    }
+}
+
+impl BlockBackend for RawImage {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut len = 0;
+        while len < buf.len() {
+            match self.file.read(&mut buf[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        Ok(len)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)?;
+        self.size = self.size.max(offset + buf.len() as u64);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_image() {
+        let path = "./tmp/test_raw_image.img";
+        std::fs::create_dir_all("./tmp").unwrap();
+        std::fs::write(path, [0xAAu8; 1024]).unwrap();
+
+        let mut image = open(path, false).unwrap();
+        assert_eq!(image.size(), 1024);
+        image.write_at(&[1, 2, 3, 4], 510).unwrap();
+
+        let mut buf = [0u8; 8];
+        assert_eq!(image.read_at(&mut buf, 508).unwrap(), 8);
+        assert_eq!(buf, [0xAA, 0xAA, 1, 2, 3, 4, 0xAA, 0xAA]);
+        // Short read at the end of the disk.
+        assert_eq!(image.read_at(&mut buf, 1020).unwrap(), 4);
+
+        let mut image = open(path, true).unwrap();
+        assert!(image.is_read_only());
+        assert!(image.write_at(&[0], 0).is_err());
+    }
+}
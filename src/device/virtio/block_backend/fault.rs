@@ -0,0 +1,206 @@
+//! Faults injected into a block device, to test how the guest copes with a slow or failing disk.
+//!
+//! The faults are deterministic: errors depend only on the number of operations, latency on the
+//! guest clock. They can be changed at any time through the [`FaultControl`] of the device.
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use super::BlockBackend;
+
+/// Writes are torn at a sector boundary.
+const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultConfig {
+    /// Guest clock ticks between the notification of a request and its completion.
+    pub latency: u64,
+    /// Fail every `eio_every`-th operation (read, write or flush) with an I/O error, 0 for never.
+    pub eio_every: u32,
+    /// On flush, undo the second half of the last write since the previous flush and fail the
+    /// flush, as if the power went out in the middle of the write.
+    pub torn_flush: bool,
+}
+
+/// Handle to change the faults of a device while it runs.
+#[derive(Clone, Default)]
+pub struct FaultControl(Arc<Mutex<FaultConfig>>);
+
+impl FaultControl {
+    pub fn get(&self) -> FaultConfig {
+        *self.0.lock().unwrap()
+    }
+
+    pub fn set(&self, config: FaultConfig) {
+        *self.0.lock().unwrap() = config;
+    }
+}
+
+/// The last write since a flush, with the data it overwrote past the tear.
+struct PendingWrite {
+    offset: u64,
+    old_tail: Vec<u8>,
+}
+
+/// A backend failing as told by its [`FaultControl`]. Latency is up to the device.
+pub struct FaultyBackend {
+    inner: Box<dyn BlockBackend>,
+    control: FaultControl,
+    /// Operations done so far, for [`FaultConfig::eio_every`].
+    ops: u64,
+    last_write: Option<PendingWrite>,
+}
+
+impl FaultyBackend {
+    pub fn new(inner: Box<dyn BlockBackend>, control: FaultControl) -> Self {
+        Self {
+            inner,
+            control,
+            ops: 0,
+            last_write: None,
+        }
+    }
+
+    /// Count an operation, failing it if its turn came.
+    fn inject_error(&mut self, config: &FaultConfig) -> io::Result<()> {
+        self.ops += 1;
+        if config.eio_every != 0 && self.ops.is_multiple_of(config.eio_every as u64) {
+            return Err(io::Error::other(format!(
+                "injected error on operation {}",
+                self.ops
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl BlockBackend for FaultyBackend {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let config = self.control.get();
+        self.inject_error(&config)?;
+        self.inner.read_at(buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let config = self.control.get();
+        self.inject_error(&config)?;
+        self.last_write = None;
+        if config.torn_flush {
+            let tear = (buf.len() / 2) & !(SECTOR_SIZE - 1);
+            let mut old_tail = vec![0; buf.len() - tear];
+            self.inner.read_at(&mut old_tail, offset + tear as u64)?;
+            self.last_write = Some(PendingWrite {
+                offset: offset + tear as u64,
+                old_tail,
+            });
+        }
+        self.inner.write_at(buf, offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let config = self.control.get();
+        self.inject_error(&config)?;
+        let torn = self.last_write.take().filter(|_| config.torn_flush);
+        if let Some(write) = &torn {
+            self.inner.write_at(&write.old_tail, write.offset)?;
+        }
+        self.inner.flush()?;
+        match torn {
+            Some(write) => Err(io::Error::other(format!(
+                "injected torn write at {:#x}",
+                write.offset
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MemDisk(Vec<u8>);
+
+    impl BlockBackend for MemDisk {
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
+
+        fn is_read_only(&self) -> bool {
+            false
+        }
+
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let data = &self.0[offset as usize..];
+            let len = buf.len().min(data.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok(len)
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+            self.0[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_injected_errors() {
+        let control = FaultControl::default();
+        let mut disk = FaultyBackend::new(Box::new(MemDisk(vec![0; 2048])), control.clone());
+        let mut buf = [0u8; 512];
+
+        control.set(FaultConfig {
+            eio_every: 3,
+            ..Default::default()
+        });
+        assert!(disk.read_at(&mut buf, 0).is_ok());
+        assert!(disk.write_at(&buf, 0).is_ok());
+        assert!(disk.flush().is_err());
+        assert!(disk.read_at(&mut buf, 0).is_ok());
+        assert!(disk.read_at(&mut buf, 0).is_ok());
+        assert!(disk.read_at(&mut buf, 0).is_err());
+
+        control.set(FaultConfig::default());
+        assert!((0..10).all(|_| disk.read_at(&mut buf, 0).is_ok()));
+    }
+
+    #[test]
+    fn test_torn_flush() {
+        let control = FaultControl::default();
+        control.set(FaultConfig {
+            torn_flush: true,
+            ..Default::default()
+        });
+        let mut disk = FaultyBackend::new(Box::new(MemDisk(vec![0; 2048])), control.clone());
+
+        disk.write_at(&[1; 1024], 0).unwrap();
+        disk.write_at(&[2; 1536], 512).unwrap();
+        // The writes read back before the flush.
+        let mut buf = [0u8; 2048];
+        disk.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..512], [1; 512]);
+        assert_eq!(buf[512..], [2; 1536]);
+
+        // Only the first sector of the last write survives.
+        assert!(disk.flush().is_err());
+        disk.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..512], [1; 512]);
+        assert_eq!(buf[512..1024], [2; 512]);
+        assert_eq!(buf[1024..], [0; 1024]);
+        assert!(disk.flush().is_ok());
+    }
+}
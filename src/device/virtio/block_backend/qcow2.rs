@@ -0,0 +1,291 @@
+//! Read-only qcow2 (version 2 and 3) images.
+//!
+//! Guest offsets are translated through the two-level cluster table: the L1 table, kept in
+//! memory, points to L2 tables, which point to the data clusters. Unallocated and zero clusters
+//! read as zeros. Refcounts only matter for allocating clusters, so they are never read and
+//! writable images are rejected. Backing files, encryption and compressed clusters are not
+//! supported.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use super::BlockBackend;
+
+const QCOW2_MAGIC: [u8; 4] = *b"QFI\xfb";
+const HEADER_V2_LEN: usize = 72;
+const HEADER_V3_LEN: usize = 104;
+
+/// Bits 9-55 of L1 and L2 entries.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_COMPRESSED: u64 = 1 << 62;
+const L2_ZERO: u64 = 1 << 0;
+
+/// Incompatible feature bits, only the dirty bit is harmless for reading.
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("qcow2: {msg}"))
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+pub struct Qcow2Image {
+    file: File,
+    size: u64,
+    cluster_bits: u32,
+    l1_table: Vec<u64>,
+
+    /// The most recently used L2 table and its offset in the image.
+    l2_cache: Option<(u64, Vec<u64>)>,
+}
+
+impl Qcow2Image {
+    /// Whether `file` starts with the qcow2 magic.
+    pub fn probe(file: &mut File) -> io::Result<bool> {
+        let mut magic = [0u8; 4];
+        file.seek(SeekFrom::Start(0))?;
+        let is_qcow2 = match file.read_exact(&mut magic) {
+            Ok(()) => magic == QCOW2_MAGIC,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(err) => return Err(err),
+        };
+        Ok(is_qcow2)
+    }
+
+    pub fn new(mut file: File, read_only: bool) -> io::Result<Self> {
+        if !read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "qcow2 images can only be opened read-only (refcounts are not maintained), add `:ro`",
+            ));
+        }
+
+        let mut header = [0u8; HEADER_V3_LEN];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header[..HEADER_V2_LEN])?;
+        if header[..4] != QCOW2_MAGIC {
+            return Err(invalid("bad magic"));
+        }
+
+        let version = be_u32(&header, 4);
+        match version {
+            2 => {}
+            3 => {
+                file.read_exact(&mut header[HEADER_V2_LEN..])?;
+                let incompatible = be_u64(&header, 72);
+                if incompatible & !INCOMPAT_DIRTY != 0 {
+                    return Err(invalid(&format!(
+                        "unsupported incompatible features {incompatible:#x}"
+                    )));
+                }
+            }
+            _ => return Err(invalid(&format!("unsupported version {version}"))),
+        }
+
+        if be_u64(&header, 8) != 0 {
+            return Err(invalid("backing files are not supported"));
+        }
+        let cluster_bits = be_u32(&header, 20);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid(&format!("invalid cluster bits {cluster_bits}")));
+        }
+        let size = be_u64(&header, 24);
+        if be_u32(&header, 32) != 0 {
+            return Err(invalid("encrypted images are not supported"));
+        }
+
+        // Each L1 entry maps `cluster_size * cluster_size / 8` bytes of the disk.
+        let l1_size = be_u32(&header, 36) as u64;
+        let l1_table_offset = be_u64(&header, 40);
+        let l1_needed = size.div_ceil(1 << (2 * cluster_bits - 3));
+        if l1_size < l1_needed {
+            return Err(invalid(&format!(
+                "L1 table of {l1_size} entries is too small for {size:#x} bytes"
+            )));
+        }
+        let file_len = file.metadata()?.len();
+        if l1_table_offset
+            .checked_add(l1_size * 8)
+            .is_none_or(|end| end > file_len)
+        {
+            return Err(invalid(&format!(
+                "L1 table of {l1_size} entries at {l1_table_offset:#x} is past the end of the file"
+            )));
+        }
+        let mut raw = vec![0u8; l1_size as usize * 8];
+        file.seek(SeekFrom::Start(l1_table_offset))?;
+        file.read_exact(&mut raw)?;
+        let l1_table = raw.chunks_exact(8).map(|e| be_u64(e, 0)).collect();
+
+        Ok(Self {
+            file,
+            size,
+            cluster_bits,
+            l1_table,
+            l2_cache: None,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn l2_table(&mut self, offset: u64) -> io::Result<&[u64]> {
+        if self.l2_cache.as_ref().is_none_or(|(o, _)| *o != offset) {
+            let mut raw = vec![0u8; self.cluster_size() as usize];
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut raw)?;
+            let table = raw.chunks_exact(8).map(|e| be_u64(e, 0)).collect();
+            self.l2_cache = Some((offset, table));
+        }
+        Ok(&self.l2_cache.as_ref().unwrap().1)
+    }
+
+    /// The image offset of the cluster holding guest offset `offset`, `None` if it reads as
+    /// zeros.
+    fn host_cluster(&mut self, offset: u64) -> io::Result<Option<u64>> {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_index = (offset >> (self.cluster_bits + l2_bits)) as usize;
+        let l2_index = ((offset >> self.cluster_bits) & ((1 << l2_bits) - 1)) as usize;
+
+        let Some(l1_entry) = self.l1_table.get(l1_index) else {
+            return Err(invalid("L1 table too small"));
+        };
+        let l2_offset = l1_entry & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(None);
+        }
+
+        let l2_entry = self.l2_table(l2_offset)?[l2_index];
+        if l2_entry & L2_COMPRESSED != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "qcow2: compressed clusters are not supported",
+            ));
+        }
+        let host = l2_entry & OFFSET_MASK;
+        if host == 0 || l2_entry & L2_ZERO != 0 {
+            Ok(None)
+        } else {
+            Ok(Some(host))
+        }
+    }
+}
+
+impl BlockBackend for Qcow2Image {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = buf.len().min(self.size.saturating_sub(offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let in_cluster = pos & (self.cluster_size() - 1);
+            let chunk = ((self.cluster_size() - in_cluster) as usize).min(len - done);
+            let dst = &mut buf[done..done + chunk];
+
+            match self.host_cluster(pos)? {
+                Some(host) => {
+                    self.file.seek(SeekFrom::Start(host + in_cluster))?;
+                    self.file.read_exact(dst)?;
+                }
+                None => dst.fill(0),
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&mut self, _buf: &[u8], _offset: u64) -> io::Result<()> {
+        Err(io::ErrorKind::PermissionDenied.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::virtio::block_backend::open;
+
+    const CLUSTER: usize = 512;
+
+    /// A 4-cluster disk whose second cluster is allocated, laid out as header, L1, L2, data.
+    fn write_test_image(path: &str) {
+        let mut image = vec![0u8; 4 * CLUSTER];
+        image[..4].copy_from_slice(&QCOW2_MAGIC);
+        image[4..8].copy_from_slice(&2u32.to_be_bytes());
+        image[20..24].copy_from_slice(&9u32.to_be_bytes());
+        image[24..32].copy_from_slice(&(4 * CLUSTER as u64).to_be_bytes());
+        image[36..40].copy_from_slice(&1u32.to_be_bytes());
+        image[40..48].copy_from_slice(&(CLUSTER as u64).to_be_bytes());
+
+        let l1_entry = (1u64 << 63) | (2 * CLUSTER) as u64;
+        image[CLUSTER..CLUSTER + 8].copy_from_slice(&l1_entry.to_be_bytes());
+        let l2_entry = (1u64 << 63) | (3 * CLUSTER) as u64;
+        image[2 * CLUSTER + 8..2 * CLUSTER + 16].copy_from_slice(&l2_entry.to_be_bytes());
+        image[3 * CLUSTER..].fill(0x5A);
+
+        std::fs::create_dir_all("./tmp").unwrap();
+        std::fs::write(path, image).unwrap();
+    }
+
+    #[test]
+    fn test_qcow2_read() {
+        let path = "./tmp/test_qcow2_read.qcow2";
+        write_test_image(path);
+
+        assert!(open(path, false).is_err());
+        let mut image = open(path, true).unwrap();
+        assert_eq!(image.size(), 4 * CLUSTER as u64);
+
+        // Across the unallocated first cluster and the allocated second one.
+        let mut buf = [0xFFu8; 8];
+        assert_eq!(image.read_at(&mut buf, CLUSTER as u64 - 4).unwrap(), 8);
+        assert_eq!(buf, [0, 0, 0, 0, 0x5A, 0x5A, 0x5A, 0x5A]);
+
+        let mut buf = vec![0xFFu8; 2 * CLUSTER];
+        assert_eq!(
+            image.read_at(&mut buf, 3 * CLUSTER as u64).unwrap(),
+            CLUSTER
+        );
+        assert!(buf[..CLUSTER].iter().all(|&b| b == 0));
+
+        assert!(image.write_at(&[0], 0).is_err());
+    }
+
+    #[test]
+    fn test_qcow2_bad_l1_table() {
+        let path = "./tmp/test_qcow2_bad_l1_table.qcow2";
+        write_test_image(path);
+        let mut image = std::fs::read(path).unwrap();
+
+        // Past the end of the file.
+        image[36..40].copy_from_slice(&u32::MAX.to_be_bytes());
+        std::fs::write(path, &image).unwrap();
+        let err = open(path, true).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Too small for the disk size.
+        image[36..40].copy_from_slice(&0u32.to_be_bytes());
+        std::fs::write(path, &image).unwrap();
+        let err = open(path, true).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
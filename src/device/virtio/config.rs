@@ -2,3 +2,17 @@ pub const VIRT_MAGIC: u32 = 0x74726976; // virt
 pub const VIRT_VERSION: u32 = 0x2;
 pub const VIRT_VENDOR: u32 = 0x4A444E42; /* \'JDNB'/ */
 pub const VIRTQUEUE_MAX_SIZE: u32 = 1024;
+
+/// Interrupt status bits.
+pub const VIRTIO_MMIO_INT_VRING: u8 = 1 << 0;
+pub const VIRTIO_MMIO_INT_CONFIG: u8 = 1 << 1;
+
+/// `used_event`/`avail_event` suppress interrupts and notifications, offered by the devices
+/// with virtqueues.
+pub const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
+/// Offered by every device, the driver follows the VirtIO 1.0 layouts. Drivers which only
+/// write the low word of the features (e.g. xv6) do not accept it and work as well.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// Offered by the devices behind the IOMMU, the addresses the driver hands out are I/O virtual
+/// addresses.
+pub const VIRTIO_F_ACCESS_PLATFORM: u64 = 1 << 33;
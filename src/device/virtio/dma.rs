@@ -0,0 +1,260 @@
+//! Checked access of the VirtIO devices to the guest RAM.
+//!
+//! The driver hands guest-physical addresses to the device through the ring addresses and the
+//! descriptors. Each of them is checked to lie in RAM before it becomes a host pointer, a device
+//! given a bad one stops serving its queue and asks the driver for a reset with
+//! `DEVICE_NEEDS_RESET`.
+//!
+//! A device behind the [`RiscvIommu`] hands out I/O virtual addresses instead, which are
+//! translated page by page. Rings and request headers must be contiguous in guest memory, data
+//! buffers scattered over several pages are bounced through a copy.
+
+use std::{
+    cell::RefCell,
+    ops::{Deref, DerefMut},
+    rc::Rc,
+    slice,
+};
+
+use crate::{device::riscv_iommu::RiscvIommu, ram::Ram, ram_config};
+
+const PAGE_SIZE: u64 = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum DmaError {
+    #[error("guest range {paddr:#x}+{len:#x} is outside RAM")]
+    OutOfRange { paddr: u64, len: u64 },
+
+    #[error("guest address {0:#x} is misaligned")]
+    Misaligned(u64),
+
+    #[error("descriptor {index} is outside the table of {size}")]
+    BadDescriptor { index: usize, size: usize },
+
+    #[error("descriptor chain is longer than its table")]
+    ChainLoop,
+
+    #[error("indirect descriptor table inside an indirect table")]
+    NestedIndirect,
+
+    #[error("descriptor of {len:#x} bytes is too short for {needed:#x}")]
+    ShortDescriptor { len: u32, needed: usize },
+
+    #[error("IOMMU fault {cause} at I/O virtual address {iova:#x}")]
+    IommuFault { iova: u64, cause: u16 },
+
+    #[error("I/O virtual range {iova:#x}+{len:#x} is not contiguous in guest memory")]
+    Discontiguous { iova: u64, len: u64 },
+}
+
+/// What the device does with the memory it accesses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DmaAccess {
+    Read,
+    Write,
+}
+
+/// The guest RAM as seen by a device.
+#[derive(Clone)]
+pub(crate) struct GuestRam {
+    /// The first byte of RAM, shared with the CPU which owns it.
+    base: *mut u8,
+    size: u64,
+    /// The IOMMU translating the addresses of the device, with the device ID it knows it by.
+    iommu: Option<(Rc<RefCell<RiscvIommu>>, u32)>,
+}
+
+impl GuestRam {
+    pub(crate) fn new(ram: &mut Ram) -> Self {
+        let ram = ram
+            .slice(ram_config::BASE_ADDR, ram_config::SIZE)
+            .expect("the whole RAM is in range");
+        Self {
+            base: ram.as_mut_ptr(),
+            size: ram.len() as u64,
+            iommu: None,
+        }
+    }
+
+    /// Translate the addresses of the device `device_id` with `iommu`.
+    pub(crate) fn behind_iommu(mut self, iommu: Rc<RefCell<RiscvIommu>>, device_id: u32) -> Self {
+        self.iommu = Some((iommu, device_id));
+        self
+    }
+
+    /// Guest-physical address of the `len` bytes at the device address `addr`.
+    fn translate(&self, addr: u64, len: u64, access: DmaAccess) -> Result<u64, DmaError> {
+        let Some((iommu, device_id)) = &self.iommu else {
+            return Ok(addr);
+        };
+        let end = addr
+            .checked_add(len)
+            .ok_or(DmaError::OutOfRange { paddr: addr, len })?;
+        let mut iommu = iommu.borrow_mut();
+        let paddr = iommu.translate(*device_id, addr, access)?;
+        let mut page = addr & !(PAGE_SIZE - 1);
+        while page + PAGE_SIZE < end {
+            page += PAGE_SIZE;
+            if iommu.translate(*device_id, page, access)? != paddr + (page - addr) {
+                return Err(DmaError::Discontiguous { iova: addr, len });
+            }
+        }
+        Ok(paddr)
+    }
+
+    /// Host pointer to the guest-physical range, which must be aligned for `T`.
+    fn host_ptr<T>(&self, paddr: u64, len: u64) -> Result<*mut T, DmaError> {
+        let offset = paddr
+            .checked_sub(ram_config::BASE_ADDR)
+            .filter(|offset| offset.checked_add(len).is_some_and(|end| end <= self.size))
+            .ok_or(DmaError::OutOfRange { paddr, len })?;
+        if !paddr.is_multiple_of(align_of::<T>() as u64) {
+            return Err(DmaError::Misaligned(paddr));
+        }
+        Ok(unsafe { self.base.add(offset as usize) }.cast())
+    }
+
+    /// Host pointer to the `len` bytes at `addr`, which must be aligned for `T`.
+    pub(crate) fn ptr<T>(
+        &self,
+        addr: u64,
+        len: u64,
+        access: DmaAccess,
+    ) -> Result<*mut T, DmaError> {
+        let paddr = self.translate(addr, len, access)?;
+        self.host_ptr(paddr, len)
+    }
+
+    /// The `len` bytes at `addr`, bounced if the IOMMU scatters them.
+    pub(crate) fn buffer<'a>(
+        &self,
+        addr: u64,
+        len: usize,
+        access: DmaAccess,
+    ) -> Result<DmaBuf<'a>, DmaError> {
+        match self.ptr::<u8>(addr, len as u64, access) {
+            Ok(ptr) => Ok(DmaBuf::Direct(unsafe {
+                slice::from_raw_parts_mut(ptr, len)
+            })),
+            Err(DmaError::Discontiguous { .. }) => {
+                let end = addr + len as u64;
+                let mut data = Vec::with_capacity(len);
+                let mut pages = Vec::new();
+                let mut cur = addr;
+                while cur < end {
+                    let chunk = ((cur | (PAGE_SIZE - 1)) + 1).min(end) - cur;
+                    let ptr = self.ptr::<u8>(cur, chunk, access)?;
+                    let page = unsafe { slice::from_raw_parts_mut(ptr, chunk as usize) };
+                    data.extend_from_slice(page);
+                    pages.push(page);
+                    cur += chunk;
+                }
+                Ok(DmaBuf::Bounce {
+                    data,
+                    pages,
+                    write_back: access == DmaAccess::Write,
+                })
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(crate) fn get_mut<'a, T>(
+        &self,
+        addr: u64,
+        access: DmaAccess,
+    ) -> Result<&'a mut T, DmaError> {
+        let ptr = self.ptr::<T>(addr, size_of::<T>() as u64, access)?;
+        Ok(unsafe { &mut *ptr })
+    }
+}
+
+/// A buffer in guest memory.
+#[derive(Debug)]
+pub(crate) enum DmaBuf<'a> {
+    Direct(&'a mut [u8]),
+    /// A copy of the pages, copied back when dropped if the device writes to them.
+    Bounce {
+        data: Vec<u8>,
+        pages: Vec<&'a mut [u8]>,
+        write_back: bool,
+    },
+}
+
+impl Deref for DmaBuf<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            DmaBuf::Direct(buf) => buf,
+            DmaBuf::Bounce { data, .. } => data,
+        }
+    }
+}
+
+impl DerefMut for DmaBuf<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            DmaBuf::Direct(buf) => buf,
+            DmaBuf::Bounce { data, .. } => data,
+        }
+    }
+}
+
+impl Drop for DmaBuf<'_> {
+    fn drop(&mut self) {
+        if let DmaBuf::Bounce {
+            data,
+            pages,
+            write_back: true,
+        } = self
+        {
+            let mut rest = &data[..];
+            for page in pages {
+                let (head, tail) = rest.split_at(page.len());
+                page.copy_from_slice(head);
+                rest = tail;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounds() {
+        let mut ram = Ram::new();
+        let guest = GuestRam::new(&mut ram);
+        let end = ram_config::BASE_ADDR + ram_config::SIZE as u64;
+
+        *guest
+            .get_mut::<u32>(ram_config::BASE_ADDR + 0x100, DmaAccess::Write)
+            .unwrap() = 0x1234;
+        assert_eq!(ram.read::<u32>(0x100).unwrap(), 0x1234);
+        assert_eq!(guest.buffer(end - 4, 4, DmaAccess::Read).unwrap().len(), 4);
+
+        assert_eq!(
+            guest.buffer(end - 4, 8, DmaAccess::Read).unwrap_err(),
+            DmaError::OutOfRange {
+                paddr: end - 4,
+                len: 8
+            }
+        );
+        assert!(matches!(
+            guest.get_mut::<u8>(0x1000_0000, DmaAccess::Read),
+            Err(DmaError::OutOfRange { .. })
+        ));
+        assert!(matches!(
+            guest.buffer(u64::MAX - 1, 4, DmaAccess::Read),
+            Err(DmaError::OutOfRange { .. })
+        ));
+        assert_eq!(
+            guest
+                .get_mut::<u32>(ram_config::BASE_ADDR + 2, DmaAccess::Read)
+                .unwrap_err(),
+            DmaError::Misaligned(ram_config::BASE_ADDR + 2)
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Shared memory regions of the VirtIO devices: host memory which both the device and the driver
+//! access, such as the DAX window of virtio-fs or the host-visible memory of virtio-gpu.
+//!
+//! A device asks for its regions with [`VirtIODeviceTrait::shared_memory_regions`], the board
+//! maps them into the guest physical space, in the window of the device's slot, and the driver
+//! finds them through the `SHMSel`, `SHMLen` and `SHMBase` registers of the transport.
+//!
+//! [`VirtIODeviceTrait::shared_memory_regions`]: super::virtio_device::VirtIODeviceTrait::shared_memory_regions
+
+use crate::{
+    config::arch_config::WordType,
+    device::{DeviceTrait, MemError},
+    device_poller::PollingEventTrait,
+};
+
+/// Where a shared memory region of a device is mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SharedMemoryInfo {
+    pub(crate) id: u8,
+    pub(crate) base: u64,
+    pub(crate) len: u64,
+}
+
+/// The host memory backing a shared memory region, it is mapped into the guest as a device.
+pub(crate) struct SharedMemory {
+    mem: Box<[u8]>,
+}
+
+impl SharedMemory {
+    pub(crate) fn new(len: usize) -> Self {
+        Self {
+            mem: vec![0; len].into_boxed_slice(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.mem.len() as u64
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        &self.mem
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.mem
+    }
+
+    fn read_impl<T>(&mut self, addr: WordType) -> Result<T, MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let len = size_of::<T>();
+        let bytes = (addr as usize)
+            .checked_add(len)
+            .and_then(|end| self.mem.get(addr as usize..end))
+            .ok_or(MemError::LoadFault)?;
+        let mut value = [0; 8];
+        value[..len].copy_from_slice(bytes);
+        Ok(T::truncate_from(u64::from_le_bytes(value)))
+    }
+
+    fn write_impl<T>(&mut self, addr: WordType, data: T) -> Result<(), MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let len = size_of::<T>();
+        let bytes = (addr as usize)
+            .checked_add(len)
+            .and_then(|end| self.mem.get_mut(addr as usize..end))
+            .ok_or(MemError::StoreFault)?;
+        let data: u64 = data.into();
+        bytes.copy_from_slice(&data.to_le_bytes()[..len]);
+        Ok(())
+    }
+}
+
+impl DeviceTrait for SharedMemory {
+    dispatch_read_write! { read_impl, write_impl }
+
+    fn sync(&mut self) {}
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_memory_access() {
+        let mut shm = SharedMemory::new(0x1000);
+        shm.write_u32(0x10, 0x1234_5678).unwrap();
+        assert_eq!(shm.read_u8(0x11).unwrap(), 0x56);
+        assert_eq!(&shm.as_slice()[0x10..0x14], &[0x78, 0x56, 0x34, 0x12]);
+
+        shm.as_mut_slice()[0xff8..].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(shm.read_u64(0xff8).unwrap(), u64::MAX);
+        assert_eq!(shm.read_u64(0xffc), Err(MemError::LoadFault));
+        assert_eq!(shm.write_u16(0x1000, 0), Err(MemError::StoreFault));
+    }
+}
@@ -1,17 +1,34 @@
 use core::slice;
 use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
-    sync::atomic::AtomicU8,
+    cell::{RefCell, UnsafeCell},
+    io,
+    mem::offset_of,
+    rc::Rc,
+    sync::{Arc, Mutex, atomic::AtomicU8},
 };
 
-use log::error;
+use log::{error, warn};
 use num_enum::TryFromPrimitive;
 
-use crate::device::virtio::{
-    virtio_device::{DEVICE_ID_ALLOCTOR, VirtIODeviceTrait},
-    virtio_mmio::VirtIODeviceStatus,
-    virtio_queue::{VirtQueue, VirtQueueDesc},
+use crate::{
+    device::{
+        riscv_iommu::RiscvIommu,
+        virtio::{
+            block_backend::{self, BlockBackend, CacheMode, FaultControl, FaultyBackend},
+            config::{
+                VIRTIO_F_ACCESS_PLATFORM, VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
+                VIRTIO_RING_F_EVENT_IDX,
+            },
+            dma::{DmaError, GuestRam},
+            virtio_device::VirtIODeviceTrait,
+            virtio_mmio::{VirtIODeviceID, VirtIODeviceStatus},
+            virtio_queue::{VirtQueue, VirtQueueDesc},
+        },
+    },
+    isa::riscv::taint::DiskTaint,
+    ram::Ram,
+    vclock::Timer,
+    work_queue::{CompletionQueue, Submitter, WorkQueue},
 };
 
 pub(super) const SECTOR_SIZE: usize = 512;
@@ -170,6 +187,24 @@ impl VirtioBlkStatus {
     }
 }
 
+/// A request taken off the queue, served on the work queue and handed back to
+/// [`VirtIOBlkDevice::complete_requests`] with its outcome.
+struct BlkRequest {
+    /// The [`VirtIOBlkDevice::epoch`] the request was taken in.
+    epoch: u64,
+    id: u32,
+    req_type: VirtioBlkReqType,
+    sector: u64,
+    /// The data buffers, between the header and the status.
+    segments: Vec<VirtQueueDesc>,
+    status_desc: Option<VirtQueueDesc>,
+    /// What is written to the disk, or what was read from it.
+    data: Vec<u8>,
+    status: VirtIOBlkReqStatus,
+    /// Bytes read or written.
+    len: u32,
+}
+
 // ======================================
 //          Virtio Block Device
 // ======================================
@@ -177,15 +212,28 @@ pub(crate) struct VirtIOBlkDevice {
     pub(crate) name: &'static str,
     pub(crate) status: u8,
     pub(crate) isr: AtomicU8,
-    pub(crate) device_id: u16,
 
     host_feature: u64,
     guest_feature: u64,
+    read_only: bool,
+    cache: CacheMode,
 
     pub(crate) generation: u32,
-    ram_base_raw: usize,
-
-    file: File, // the file that is bound to this device
+    ram: GuestRam,
+
+    backend: Arc<Mutex<Box<dyn BlockBackend>>>, // the disk image that is bound to this device
+    faults: FaultControl,
+    /// Serves the requests, inline unless [`VirtIOBlkDeviceBuilder::work_queue`] is used.
+    submitter: Submitter<BlkRequest>,
+    completions: CompletionQueue<BlkRequest>,
+    /// Bumped on reset, the requests of an older epoch are dropped once served.
+    epoch: u64,
+    /// Taints the memory the device reads a region of the disk to.
+    taint: Option<DiskTaint>,
+    /// The timer task completing the requests held back by the injected latency.
+    timer: Option<(Rc<UnsafeCell<Timer>>, u64)>,
+    /// The driver notified, the requests wait for the timer task.
+    delayed: bool,
 
     queue: VirtQueue,
     pub(super) config_region: VirtioBlkConfig,
@@ -194,88 +242,304 @@ pub(crate) struct VirtIOBlkDevice {
 impl VirtIOBlkDevice {
     pub(crate) fn new(
         name: &'static str,
-        ram_base_raw: *mut u8,
-        device_id: u16,
+        ram: &mut Ram,
         file_path: String,
+        read_only: bool,
     ) -> Self {
-        let mut file;
-        if let Ok(file_result) = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(false)
-            .create(false)
-            .open(file_path.as_str())
-        {
-            file = file_result;
-        } else {
-            panic!("Can not find file: {}.", file_path);
+        let backend = block_backend::open(&file_path, read_only)
+            .unwrap_or_else(|err| panic!("Can not open file {}: {}.", file_path, err));
+        Self::from_backend(name, GuestRam::new(ram), backend, CacheMode::Writeback)
+    }
+
+    pub(crate) fn from_backend(
+        name: &'static str,
+        ram: GuestRam,
+        backend: Box<dyn BlockBackend>,
+        cache: CacheMode,
+    ) -> Self {
+        let read_only = backend.is_read_only();
+        let size = backend.size();
+
+        let mut host_feature = VIRTIO_RING_F_EVENT_IDX | VirtIOBlockFeature::Flush as u64;
+        if read_only {
+            host_feature |= VirtIOBlockFeature::Ro as u64;
+        }
+        if cache.has_write_cache() {
+            host_feature |= VirtIOBlockFeature::ConfigWce as u64;
         }
-        let size = file.seek(SeekFrom::End(0)).unwrap();
+        let mut config_region = VirtioBlkConfig::new(size.div_ceil(SECTOR_SIZE as u64));
+        config_region.writeback = cache.has_write_cache() as u8;
+        let faults = FaultControl::default();
+        let (submitter, completions) = WorkQueue::new(0).channel();
 
         Self {
             name,
             status: 0,
-            device_id,
 
             isr: AtomicU8::new(0),
 
-            host_feature: 0,
+            host_feature,
             guest_feature: 0,
+            read_only,
+            cache,
 
             generation: 0,
-            ram_base_raw: ram_base_raw as usize,
-
-            file,
-
-            queue: VirtQueue::new(ram_base_raw, 0), // will be set later
-            config_region: VirtioBlkConfig::new(size.div_ceil(SECTOR_SIZE as u64)),
+            ram: ram.clone(),
+
+            backend: Arc::new(Mutex::new(Box::new(FaultyBackend::new(
+                backend,
+                faults.clone(),
+            )))),
+            faults,
+            submitter,
+            completions,
+            epoch: 0,
+            taint: None,
+            timer: None,
+            delayed: false,
+
+            queue: VirtQueue::new(ram, 0), // will be set later
+            config_region,
         }
     }
 
-    pub(crate) fn bound_file(&mut self, file: File) {
-        self.file = file;
+    pub(crate) fn bound_backend(&mut self, backend: Box<dyn BlockBackend>) {
+        *self.backend.lock().unwrap() = Box::new(FaultyBackend::new(backend, self.faults.clone()));
     }
     pub fn add_host_feature(mut self, new_feature: VirtIOBlockFeature) -> Self {
         self.host_feature |= new_feature as u64;
         self
     }
 
-    fn write_blk(file: &mut File, buf: &[u8], offset: u64) -> u32 {
-        file.seek(std::io::SeekFrom::Start(offset)).unwrap();
-        match file.write_all(buf) {
-            Ok(_) => buf.len() as u32,
-            Err(_) => 0,
+    /// Whether writes may stay in the host cache until the driver flushes. The write cache is
+    /// off if the driver turned it off, or can not flush it.
+    fn write_cache_enabled(&self) -> bool {
+        self.cache.has_write_cache()
+            && self.guest_feature & VirtIOBlockFeature::Flush as u64 != 0
+            && self.config_region.writeback != 0
+    }
+
+    /// Whether every write has to be synced to the host disk before it completes.
+    fn sync_writes(&self) -> bool {
+        match self.cache {
+            CacheMode::Writeback => !self.write_cache_enabled(),
+            CacheMode::Writethrough => true,
+            // The image is opened for synchronous writes.
+            CacheMode::DirectSync => false,
         }
     }
 
-    fn read_blk(file: &mut File, buf: &mut [u8], offset: u64) -> u32 {
-        file.seek(std::io::SeekFrom::Start(offset)).unwrap();
-        match file.read(buf) {
-            Ok(len) => len as u32,
-            #[cfg(not(test))]
-            Err(_) => 0,
-            #[cfg(test)]
-            Err(mes) => panic!("{}", mes),
+    fn write_blk(
+        backend: &mut dyn BlockBackend,
+        buf: &[u8],
+        offset: u64,
+        sync: bool,
+    ) -> io::Result<u32> {
+        backend.write_at(buf, offset)?;
+        if sync {
+            backend.flush()?;
         }
+        Ok(buf.len() as u32)
     }
 
-    fn manage_request_header(ram_base_raw: usize, desc: &VirtQueueDesc) -> (VirtioBlkReqType, u64) {
-        let req = unsafe {
-            desc.get_request_package::<VirtioBlkReq>(ram_base_raw)
-                .as_mut()
-                .unwrap()
+    /// Serve the available requests and interrupt the driver if it asked for it.
+    fn process_queue(&mut self) {
+        if self.status & VirtIODeviceStatus::DEVICE_NEEDS_RESET.bits() != 0 {
+            return;
+        }
+        while self.submit_one_request() {}
+        self.queue.update_avail_event();
+        // Requests made available before the driver saw the new `avail_event`.
+        while self.submit_one_request() {}
+
+        self.complete_requests();
+    }
+
+    /// Take the next request off the queue and submit it, returns whether there was one.
+    fn submit_one_request(&mut self) -> bool {
+        let ram = self.ram.clone();
+        let mut descs = Vec::new();
+        let res = self.queue.take_one_request(|desc, _| {
+            // Check that the buffer is in RAM before serving the request.
+            desc.buffer(&ram)?;
+            descs.push(*desc);
+            Ok(())
+        });
+        let header = res.and_then(|id| {
+            id.map(|id| Ok((id, Self::manage_request_header(&ram, &descs[0])?)))
+                .transpose()
+        });
+        let (id, (req_type, sector)) = match header {
+            Ok(Some(header)) => header,
+            Ok(None) => return false,
+            Err(err) => {
+                self.needs_reset(err);
+                return false;
+            }
         };
 
-        VirtioBlkReqType::try_from(req.request_type)
+        let status_desc = (descs.len() > 1).then(|| descs.pop().unwrap());
+        let mut segments = descs.split_off(1);
+        if status_desc.is_none() {
+            error!(
+                "illigal virtio request: {:#?}. No status descriptor",
+                req_type
+            );
+            segments.clear();
+        }
+        let mut data = Vec::new();
+        for desc in &segments {
+            match req_type {
+                VirtioBlkReqType::In => data.resize(data.len() + desc.len as usize, 0),
+                VirtioBlkReqType::Out => match desc.buffer(&ram) {
+                    Ok(buf) => data.extend_from_slice(&buf),
+                    Err(err) => {
+                        self.needs_reset(err);
+                        return false;
+                    }
+                },
+                _ => {}
+            }
+        }
+        let req = BlkRequest {
+            epoch: self.epoch,
+            id,
+            req_type,
+            sector,
+            segments,
+            status_desc,
+            data,
+            status: VirtIOBlkReqStatus::Ok,
+            len: 0,
+        };
+
+        let backend = self.backend.clone();
+        let read_only = self.read_only;
+        let sync_writes = self.sync_writes();
+        self.submitter
+            .submit(move || Self::serve_request(&backend, req, read_only, sync_writes));
+        true
+    }
+
+    /// Do the disk I/O of `req`, on a worker thread.
+    fn serve_request(
+        backend: &Mutex<Box<dyn BlockBackend>>,
+        mut req: BlkRequest,
+        read_only: bool,
+        sync_writes: bool,
+    ) -> BlkRequest {
+        let mut backend = backend.lock().unwrap();
+        let offset = req.sector * SECTOR_SIZE as u64;
+        req.len = match req.req_type {
+            VirtioBlkReqType::In => Self::read_blk(&mut **backend, &mut req.data, offset)
+                .unwrap_or_else(|err| {
+                    error!("virtio block read failed: {}", err);
+                    req.status = VirtIOBlkReqStatus::IoErr;
+                    0
+                }),
+            VirtioBlkReqType::Out if read_only => {
+                req.status = VirtIOBlkReqStatus::IoErr;
+                0
+            }
+            VirtioBlkReqType::Out => {
+                Self::write_blk(&mut **backend, &req.data, offset, sync_writes).unwrap_or_else(
+                    |err| {
+                        error!("virtio block write failed: {}", err);
+                        req.status = VirtIOBlkReqStatus::IoErr;
+                        0
+                    },
+                )
+            }
+            VirtioBlkReqType::Flush => {
+                if let Err(err) = backend.flush() {
+                    error!("virtio block flush failed: {}", err);
+                    req.status = VirtIOBlkReqStatus::IoErr;
+                }
+                0
+            }
+            _ => {
+                error!("virtio unsupport request: {:#?}", req.req_type);
+                0
+            }
+        };
+        req
+    }
+
+    /// Apply the served requests to the guest memory, use them and interrupt the driver if it
+    /// asked for it.
+    fn complete_requests(&mut self) {
+        if self.status & VirtIODeviceStatus::DEVICE_NEEDS_RESET.bits() != 0 {
+            return;
+        }
+        let old_used_idx = self.queue.used_idx();
+        while let Some(req) = self.completions.try_pop() {
+            // Served after a reset, the buffers are not the driver's anymore.
+            if req.epoch != self.epoch {
+                continue;
+            }
+            if let Err(err) = self.complete_request(req) {
+                self.needs_reset(err);
+                return;
+            }
+        }
+
+        if self.queue.needs_interrupt(old_used_idx) {
+            self.isr
+                .fetch_or(VIRTIO_MMIO_INT_VRING, std::sync::atomic::Ordering::Release);
+            self.update_irq();
+        }
+    }
+
+    fn complete_request(&mut self, req: BlkRequest) -> Result<(), DmaError> {
+        if let VirtioBlkReqType::In = req.req_type {
+            let mut offset = req.sector * SECTOR_SIZE as u64;
+            let mut rest = &req.data[..req.len as usize];
+            for desc in &req.segments {
+                let mut buf = desc.buffer(&self.ram)?;
+                let len = buf.len().min(rest.len());
+                buf[..len].copy_from_slice(&rest[..len]);
+                if let Some(taint) = &self.taint {
+                    taint.on_read(desc.paddr(), offset, len as u64);
+                }
+                rest = &rest[len..];
+                offset += len as u64;
+            }
+        }
+        if let Some(desc) = &req.status_desc {
+            desc.request::<VirtioBlkStatus>(&self.ram)?
+                .write_status(req.status);
+        }
+        self.queue.add_used(req.id, req.len)
+    }
+
+    /// The driver broke the queue, stop serving it until it resets the device.
+    fn needs_reset(&mut self, err: DmaError) {
+        error!("{}: {}, the device needs a reset", self.name, err);
+        self.status |= VirtIODeviceStatus::DEVICE_NEEDS_RESET.bits();
+        self.isr
+            .fetch_or(VIRTIO_MMIO_INT_CONFIG, std::sync::atomic::Ordering::Release);
+    }
+
+    fn read_blk(backend: &mut dyn BlockBackend, buf: &mut [u8], offset: u64) -> io::Result<u32> {
+        backend.read_at(buf, offset).map(|len| len as u32)
+    }
+
+    fn manage_request_header(
+        ram: &GuestRam,
+        desc: &VirtQueueDesc,
+    ) -> Result<(VirtioBlkReqType, u64), DmaError> {
+        let req = desc.request::<VirtioBlkReq>(ram)?;
+
+        Ok(VirtioBlkReqType::try_from(req.request_type)
             .map_or((VirtioBlkReqType::Unsupported, 0u64), |req_type| {
                 (req_type, req.sector)
-            })
+            }))
     }
 }
 
 impl VirtIODeviceTrait for VirtIOBlkDevice {
     fn get_device_id(&self) -> u16 {
-        self.device_id
+        VirtIODeviceID::Block as u16
     }
     fn status(&mut self) -> &mut u8 {
         &mut self.status
@@ -297,6 +561,8 @@ impl VirtIODeviceTrait for VirtIOBlkDevice {
             self.status &= !(VirtIODeviceStatus::DRIVER_OK.bits())
         } else {
             self.guest_feature = feature;
+            self.queue
+                .set_event_idx(feature & VIRTIO_RING_F_EVENT_IDX != 0);
         }
     }
 
@@ -318,114 +584,101 @@ impl VirtIODeviceTrait for VirtIOBlkDevice {
     }
 
     fn manage_one_request(&mut self) -> bool {
-        let mut req_type = VirtioBlkReqType::Unsupported;
-        let mut sector: u64 = 0;
-        let res = self
-            .queue
-            .manage_one_request(|desc: &VirtQueueDesc, idx: usize| match idx {
-                0 => {
-                    let t = Self::manage_request_header(self.ram_base_raw, desc);
-                    req_type = t.0;
-                    sector = t.1;
-                    0
-                }
-
-                1 => {
-                    let buf = unsafe {
-                        slice::from_raw_parts_mut(
-                            desc.get_request_package::<u8>(self.ram_base_raw),
-                            desc.len as usize,
-                        )
-                    };
-
-                    match req_type {
-                        VirtioBlkReqType::In => {
-                            Self::read_blk(&mut self.file, buf, sector * SECTOR_SIZE as u64)
-                        }
-                        VirtioBlkReqType::Out => {
-                            Self::write_blk(&mut self.file, buf, sector * SECTOR_SIZE as u64)
-                        }
-                        VirtioBlkReqType::Flush => {
-                            self.file.flush().unwrap();
-                            0
-                        }
-                        _ => {
-                            error!("virtio unsupport request: {:#?}", req_type);
-                            let status_bit = unsafe {
-                                desc.get_request_package::<VirtioBlkStatus>(self.ram_base_raw)
-                                    .as_mut()
-                                    .unwrap()
-                            };
-                            status_bit.write_status(VirtIOBlkReqStatus::Ok);
-                            0
-                        }
-                    }
-                }
-
-                2 => {
-                    let status_bit = unsafe {
-                        desc.get_request_package::<VirtioBlkStatus>(self.ram_base_raw)
-                            .as_mut()
-                            .unwrap()
-                    };
-                    status_bit.write_status(VirtIOBlkReqStatus::Ok);
-                    0
-                }
-
-                _ => {
-                    error!(
-                        "illigal virtio request: {:#?}. More than 3 description table",
-                        req_type
-                    );
-                    0
-                }
-            });
-        res
+        let submitted = self.submit_one_request();
+        self.complete_requests();
+        submitted
     }
 
     fn notify(&mut self, _idx: u32) {
-        let mut used = false;
-        loop {
-            if !self.manage_one_request() {
-                break;
-            }
-            used = true;
-        }
-
-        if used
-            && self.queue.get_avail_flag()
-                == crate::device::virtio::virtio_queue::VirtQueueAvailFlag::Default
+        let latency = self.faults.get().latency;
+        if let Some((timer, task)) = &self.timer
+            && latency != 0
         {
-            self.isr.fetch_or(1, std::sync::atomic::Ordering::Release);
-            self.update_irq();
+            if !self.delayed {
+                unsafe { timer.as_mut_unchecked() }.set_delay(*task, latency);
+                self.delayed = true;
+            }
+            return;
         }
+        self.process_queue();
     }
 
     fn queue_ready(&self) -> bool {
         self.queue.ready()
     }
 
+    fn queue_depth(&self) -> u32 {
+        self.queue.pending()
+    }
+
     fn get_num_of_queue(&self) -> u32 {
         1
     }
 
     fn read_config(&mut self, idx: u64) -> u32 {
-        self.config_region.into_slice()[idx as usize]
+        self.config_region
+            .into_slice()
+            .get(idx as usize)
+            .copied()
+            .unwrap_or(0)
     }
 
     fn write_config(&mut self, idx: u64, data: u32) {
-        self.config_region.into_slice_mut()[idx as usize] = data
+        // Only `writeback` is writable, once the driver accepted `VIRTIO_BLK_F_CONFIG_WCE`.
+        let writeback = (offset_of!(VirtioBlkConfig, writeback) / 4) as u64;
+        if idx == writeback && self.guest_feature & VirtIOBlockFeature::ConfigWce as u64 != 0 {
+            self.config_region.writeback = (data as u8 != 0) as u8;
+        } else {
+            warn!("virtio block: ignored write of {data:#x} to config word {idx}");
+        }
+    }
+
+    fn faults(&self) -> Option<FaultControl> {
+        Some(self.faults.clone())
+    }
+
+    fn attach_taint(&mut self, taint: DiskTaint) -> bool {
+        self.taint = Some(taint);
+        true
+    }
+
+    fn attach_timer(&mut self, timer: Rc<UnsafeCell<Timer>>, task: u64) {
+        self.timer = Some((timer, task));
+    }
+
+    fn timer_expired(&mut self) {
+        self.delayed = false;
+        self.process_queue();
+    }
+
+    fn complete_work(&mut self) {
+        self.complete_requests();
     }
 
     fn get_poll_event(&mut self) -> Option<Box<dyn crate::device_poller::PollingEventTrait>> {
         None
     }
+
+    fn reset(&mut self) {
+        self.status = 0;
+        *self.isr.get_mut() = 0;
+        self.guest_feature = 0;
+        self.config_region.writeback = self.cache.has_write_cache() as u8;
+        self.queue = VirtQueue::new(self.ram.clone(), 0);
+        self.epoch += 1;
+        if let Some((timer, task)) = &self.timer
+            && self.delayed
+        {
+            unsafe { timer.as_mut_unchecked() }.set_due(*task, u64::MAX);
+        }
+        self.delayed = false;
+    }
 }
 
 #[cfg(test)]
 impl VirtIOBlkDevice {
     pub(crate) fn flush(&mut self) {
-        self.file.flush().unwrap();
+        self.backend.lock().unwrap().flush().unwrap();
     }
 
     pub(crate) fn queue(&mut self) -> &mut VirtQueue {
@@ -434,48 +687,104 @@ impl VirtIOBlkDevice {
 }
 
 pub struct VirtIOBlkDeviceBuilder {
-    device: VirtIOBlkDevice,
+    name: &'static str,
+    ram: GuestRam,
+    file: String,
+    host_feature: u64,
+    generation: u32,
+    read_only: bool,
+    cache: CacheMode,
+    iommu: Option<(Rc<RefCell<RiscvIommu>>, u32)>,
+    work_queue: Option<(Submitter<BlkRequest>, CompletionQueue<BlkRequest>)>,
 }
 
 impl VirtIOBlkDeviceBuilder {
-    pub fn new(ram_base_raw: *mut u8, file: String) -> Self {
-        let device_id = DEVICE_ID_ALLOCTOR.lock().unwrap().alloc();
+    pub fn new(ram: &mut Ram, file: String) -> Self {
         Self {
-            device: VirtIOBlkDevice::new(
-                "Unnamed VirtIO Block Device",
-                ram_base_raw,
-                device_id,
-                file,
-            ),
+            name: "Unnamed VirtIO Block Device",
+            ram: GuestRam::new(ram),
+            file,
+            host_feature: 0,
+            generation: 0,
+            read_only: false,
+            cache: CacheMode::Writeback,
+            iommu: None,
+            work_queue: None,
         }
     }
 
     pub fn name(mut self, name: &'static str) -> Self {
-        self.device.name = name;
+        self.name = name;
         self
     }
 
     pub fn host_feature(mut self, feature: VirtIOBlockFeature) -> Self {
-        self.device.host_feature |= feature as u64;
+        self.host_feature |= feature as u64;
         self
     }
 
     pub fn generation(mut self, generation: u32) -> Self {
-        self.device.generation = generation;
+        self.generation = generation;
+        self
+    }
+
+    /// Open the image without write access and advertise `VIRTIO_BLK_F_RO`, writes fail with
+    /// `VIRTIO_BLK_S_IOERR`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// When writes reach the host disk, see [`CacheMode`].
+    pub fn cache(mut self, cache: CacheMode) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Place the device behind `iommu` as `device_id`, and advertise `VIRTIO_F_ACCESS_PLATFORM`.
+    pub(crate) fn iommu(mut self, iommu: Rc<RefCell<RiscvIommu>>, device_id: u32) -> Self {
+        self.iommu = Some((iommu, device_id));
+        self
+    }
+
+    /// Serve the requests on `work_queue` instead of inline, see [`crate::work_queue`].
+    pub(crate) fn work_queue(mut self, work_queue: &WorkQueue) -> Self {
+        self.work_queue = Some(work_queue.channel());
         self
     }
 
+    /// Open the image and create the device, failing if the image can not be opened.
+    pub fn try_get(self) -> io::Result<VirtIOBlkDevice> {
+        let backend = block_backend::open_with_cache(&self.file, self.read_only, self.cache)?;
+        let mut ram = self.ram;
+        let mut host_feature = self.host_feature;
+        if let Some((iommu, device_id)) = self.iommu {
+            ram = ram.behind_iommu(iommu, device_id);
+            host_feature |= VIRTIO_F_ACCESS_PLATFORM;
+        }
+        let mut device = VirtIOBlkDevice::from_backend(self.name, ram, backend, self.cache);
+        device.host_feature |= host_feature;
+        device.generation = self.generation;
+        if let Some((submitter, completions)) = self.work_queue {
+            device.submitter = submitter;
+            device.completions = completions;
+        }
+        Ok(device)
+    }
+
     pub fn get(self) -> VirtIOBlkDevice {
-        self.device
+        let file = self.file.clone();
+        self.try_get()
+            .unwrap_or_else(|err| panic!("Can not open file {}: {}.", file, err))
     }
 }
 
 #[cfg(test)]
-pub fn init_block_file<'a, F>(path: &str, blk_num: u64, mut f: F) -> File
+pub fn init_block_file<'a, F>(path: &str, blk_num: u64, mut f: F) -> std::fs::File
 where
     F: FnMut(usize) -> &'a [u8],
 {
-    use std::{fs::create_dir_all, path::Path};
+    use std::{fs::OpenOptions, fs::create_dir_all, io::Write, path::Path};
     let parent_dir = Path::new(path).parent().unwrap();
     create_dir_all(parent_dir).unwrap();
 
@@ -498,11 +807,13 @@ where
 
 #[cfg(test)]
 mod test {
+    use std::io::{Read, Seek};
+
     use crate::{
+        device::virtio::block_backend::RawImage,
         device::virtio::virtio_queue::{
             VirtQueueAvail, VirtQueueAvailFlag, VirtQueueDescFlag, VirtQueueUsed, VirtQueueUsedFlag,
         },
-        ram::Ram,
         ram_config,
     };
 
@@ -521,16 +832,17 @@ mod test {
         //     .unwrap();
         let write_buf: [u8; SECTOR_SIZE] = [0xAB; SECTOR_SIZE];
         let offset = 0;
-        let mut file = init_block_file("./tmp/test_file_read_write.txt", 1, |_| &write_buf);
+        let file = init_block_file("./tmp/test_file_read_write.txt", 1, |_| &write_buf);
 
         // 测试写入
-        let write_len = VirtIOBlkDevice::write_blk(&mut file, &write_buf, offset);
+        let mut image = RawImage::new(file.try_clone().unwrap(), false).unwrap();
+        let write_len = VirtIOBlkDevice::write_blk(&mut image, &write_buf, offset, false).unwrap();
         assert_eq!(write_len, SECTOR_SIZE as u32);
 
-        let mut file_copy = file.try_clone().unwrap();
+        let mut file_copy = RawImage::new(file, false).unwrap();
         // 测试读取
         let mut read_buf: [u8; SECTOR_SIZE] = [0u8; SECTOR_SIZE];
-        let read_len = VirtIOBlkDevice::read_blk(&mut file_copy, &mut read_buf, offset);
+        let read_len = VirtIOBlkDevice::read_blk(&mut file_copy, &mut read_buf, offset).unwrap();
         assert_eq!(read_len, SECTOR_SIZE as u32);
         assert_eq!(read_buf, write_buf);
     }
@@ -543,8 +855,7 @@ mod test {
         let _ = init_block_file(&file_name, 1, |_| &buf);
 
         let mut ram = Ram::new();
-        let ram_base = &mut ram[0] as *mut u8;
-        let mut virt_device = VirtIOBlkDevice::new("VirtIO Block 0", ram_base, 0, file_name);
+        let mut virt_device = VirtIOBlkDevice::new("VirtIO Block 0", &mut ram, file_name, false);
         virt_device.set_queue_num(QUEUE_NUM as u32);
 
         let virtq_desc_base = 0x8000_2000 as u64;
@@ -648,8 +959,7 @@ mod test {
         let mut file = init_block_file(file_name.as_str(), 1, |_| &buf);
 
         let mut ram = Ram::new();
-        let ram_base = &mut ram[0] as *mut u8;
-        let mut virt_device = VirtIOBlkDevice::new("VirtIO Block 0", ram_base, 0, file_name);
+        let mut virt_device = VirtIOBlkDevice::new("VirtIO Block 0", &mut ram, file_name, false);
         virt_device.set_queue_num(QUEUE_NUM as u32);
 
         let virtq_desc_base = 0x8000_2000 as u64;
@@ -751,4 +1061,203 @@ mod test {
         file.read(&mut buf).unwrap();
         assert_eq!(buf[93], (93 * 93) as u8);
     }
+
+    #[test]
+    fn test_blk_write_read_only() {
+        let buf: [u8; SECTOR_SIZE] = [0x55; SECTOR_SIZE];
+        let file_name = String::from("./tmp/test_blk_write_read_only.txt");
+        let mut file = init_block_file(file_name.as_str(), 1, |_| &buf);
+
+        let mut ram = Ram::new();
+        let mut virt_device = VirtIOBlkDeviceBuilder::new(&mut ram, file_name)
+            .read_only(true)
+            .get();
+        assert_ne!(
+            virt_device.get_host_feature() & VirtIOBlockFeature::Ro as u64,
+            0
+        );
+        virt_device.set_queue_num(QUEUE_NUM as u32);
+
+        let virtq_desc_base = 0x8000_2000 as u64;
+        let virtq_avail_base = 0x8000_2100 + ((QUEUE_NUM + 2) * size_of::<u16>()) as u64;
+        let virtq_used_base = 0x8000_2200 + (QUEUE_NUM * size_of::<VirtQueueUsed>() + 4) as u64;
+        virt_device.set_avail(virtq_avail_base);
+        virt_device.set_desc(virtq_desc_base);
+        virt_device.set_used(virtq_used_base);
+
+        let virt_queue_desc = unsafe {
+            slice::from_raw_parts_mut(
+                &mut ram[(virtq_desc_base - ram_config::BASE_ADDR) as usize] as *mut u8
+                    as *mut VirtQueueDesc,
+                DESC_NUM,
+            )
+        };
+        let virtq_avail = &mut ram[(virtq_avail_base - ram_config::BASE_ADDR) as usize] as *mut u8
+            as *mut VirtQueueAvail;
+        let virtq_avail = unsafe { virtq_avail.as_mut().unwrap() };
+        virtq_avail.init(VirtQueueAvailFlag::Default);
+        let avail_ring = VirtQueueAvail::mut_ring(virtq_avail as *mut _ as u64, QUEUE_NUM as u32);
+        let virtq_used = &mut ram[(virtq_used_base - ram_config::BASE_ADDR) as usize] as *mut u8
+            as *mut VirtQueueUsed;
+        let virtq_used = unsafe { virtq_used.as_mut().unwrap() };
+        virtq_used.init(VirtQueueUsedFlag::Default);
+
+        avail_ring[0] = 0;
+        virtq_avail.idx_atomic_add(1);
+
+        let desc0_buf_addr = 0x8000_2300;
+        virt_queue_desc[0].init(
+            desc0_buf_addr,
+            size_of::<VirtioBlkReq>() as u32,
+            VirtQueueDescFlag::VIRTQ_DESC_F_NEXT,
+            1,
+        );
+        let req = unsafe {
+            (&mut ram[(desc0_buf_addr - ram_config::BASE_ADDR) as usize] as *mut u8
+                as *mut VirtioBlkReq)
+                .as_mut()
+                .unwrap()
+        };
+        *req = VirtioBlkReq::new(VirtioBlkReqType::Out, 0);
+
+        virt_queue_desc[1].init(0x8000_2400, 0x200, VirtQueueDescFlag::VIRTQ_DESC_F_NEXT, 2);
+
+        let desc2_buf_addr = 0x8000_2310;
+        virt_queue_desc[2].init(
+            desc2_buf_addr,
+            size_of::<VirtioBlkStatus>() as u32,
+            VirtQueueDescFlag::empty(),
+            0,
+        );
+        let desc_status = unsafe {
+            (&mut ram[(desc2_buf_addr - ram_config::BASE_ADDR) as usize] as *mut u8
+                as *mut VirtioBlkStatus)
+                .as_mut()
+                .unwrap()
+        };
+
+        assert!(virt_device.manage_one_request());
+        assert_eq!(desc_status.status, VirtIOBlkReqStatus::IoErr as u8);
+
+        // The image is untouched.
+        let mut buf: [u8; SECTOR_SIZE] = [0u8; SECTOR_SIZE];
+        file.seek(std::io::SeekFrom::Start(0)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0x55; SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn test_blk_dma_outside_ram() {
+        let file_name = String::from("./tmp/test_blk_dma_outside_ram.img");
+        let _ = init_block_file(&file_name, 1, |_| &[0u8; SECTOR_SIZE]);
+
+        let mut ram = Ram::new();
+        let mut virt_device = VirtIOBlkDevice::new("VirtIO Block 0", &mut ram, file_name, false);
+        virt_device.set_queue_num(QUEUE_NUM as u32);
+        virt_device.set_desc(0x8000_2000);
+        virt_device.set_avail(0x8000_2100);
+        virt_device.set_used(0x8000_2200);
+
+        // A read into a buffer at the start of the address space, outside RAM.
+        let offset = |paddr: u64| paddr - ram_config::BASE_ADDR;
+        let descs = [
+            (0x8000_2300, size_of::<VirtioBlkReq>() as u32, 1, 1),
+            (0x1000, SECTOR_SIZE as u32, 1, 2),
+            (0x8000_2310, 1, 0, 0),
+        ];
+        for (i, (paddr, len, flags, next)) in descs.into_iter().enumerate() {
+            let desc = 0x8000_2000 + 16 * i as u64;
+            ram.write::<u64>(offset(desc), paddr).unwrap();
+            ram.write::<u32>(offset(desc + 8), len).unwrap();
+            ram.write::<u16>(offset(desc + 12), flags).unwrap();
+            ram.write::<u16>(offset(desc + 14), next).unwrap();
+        }
+        ram.write::<u32>(offset(0x8000_2300), VirtioBlkReqType::In as u32)
+            .unwrap();
+        ram.write::<u16>(offset(0x8000_2102), 1).unwrap();
+
+        virt_device.notify(0);
+        assert_ne!(
+            virt_device.status & VirtIODeviceStatus::DEVICE_NEEDS_RESET.bits(),
+            0
+        );
+        assert_ne!(*virt_device.isr.get_mut() & VIRTIO_MMIO_INT_CONFIG, 0);
+        assert_eq!(ram.read::<u16>(offset(0x8000_2202)).unwrap(), 0);
+
+        // The queue is left alone until the driver resets the device.
+        ram.write::<u16>(offset(0x8000_2102), 2).unwrap();
+        virt_device.notify(0);
+        assert_eq!(virt_device.queue_depth(), 1);
+        virt_device.reset();
+        assert_eq!(virt_device.status, 0);
+    }
+
+    #[test]
+    fn test_blk_work_queue() {
+        use std::time::{Duration, Instant};
+
+        let mut sector = [0u8; SECTOR_SIZE];
+        sector[0x42] = 0x55;
+        let file_name = String::from("./tmp/test_blk_work_queue.img");
+        let _ = init_block_file(&file_name, 1, |_| &sector);
+
+        let work_queue = WorkQueue::new(2);
+        let mut ram = Ram::new();
+        let mut virt_device = VirtIOBlkDeviceBuilder::new(&mut ram, file_name)
+            .work_queue(&work_queue)
+            .get();
+        virt_device.set_queue_num(QUEUE_NUM as u32);
+        virt_device.set_desc(0x8000_2000);
+        virt_device.set_avail(0x8000_2100);
+        virt_device.set_used(0x8000_2200);
+
+        // A read of the first sector, split over two buffers.
+        let offset = |paddr: u64| paddr - ram_config::BASE_ADDR;
+        let descs = [
+            (0x8000_2300, size_of::<VirtioBlkReq>() as u32, 1, 1),
+            (0x8000_2400, 0x40, 3, 2),
+            (0x8000_2800, SECTOR_SIZE as u32 - 0x40, 3, 3),
+            (0x8000_2310, 1, 2, 0),
+        ];
+        for (i, (paddr, len, flags, next)) in descs.into_iter().enumerate() {
+            let desc = 0x8000_2000 + 16 * i as u64;
+            ram.write::<u64>(offset(desc), paddr).unwrap();
+            ram.write::<u32>(offset(desc + 8), len).unwrap();
+            ram.write::<u16>(offset(desc + 12), flags).unwrap();
+            ram.write::<u16>(offset(desc + 14), next).unwrap();
+        }
+        ram.write::<u32>(offset(0x8000_2300), VirtioBlkReqType::In as u32)
+            .unwrap();
+        ram.write::<u8>(offset(0x8000_2310), 0xff).unwrap();
+        ram.write::<u16>(offset(0x8000_2102), 1).unwrap();
+
+        virt_device.notify(0);
+        let start = Instant::now();
+        while ram.read::<u16>(offset(0x8000_2202)).unwrap() == 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "request never completed"
+            );
+            if work_queue.take_ready() {
+                virt_device.complete_work();
+            }
+            std::thread::yield_now();
+        }
+
+        assert_eq!(
+            ram.read::<u8>(offset(0x8000_2310)).unwrap(),
+            VirtIOBlkReqStatus::Ok as u8
+        );
+        assert_eq!(ram.read::<u8>(offset(0x8000_2800 + 2)).unwrap(), 0x55);
+        assert_eq!(
+            ram.read::<u32>(offset(0x8000_2204 + 4)).unwrap(),
+            SECTOR_SIZE as u32
+        );
+        assert_ne!(*virt_device.isr.get_mut() & VIRTIO_MMIO_INT_VRING, 0);
+
+        // Requests served after a reset are dropped.
+        virt_device.reset();
+        virt_device.complete_work();
+        assert_eq!(virt_device.queue_depth(), 0);
+    }
 }
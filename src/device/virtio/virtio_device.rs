@@ -1,9 +1,14 @@
-use std::sync::{
-    Mutex,
-    atomic::{AtomicU8, AtomicU16},
+use std::{
+    cell::{RefCell, UnsafeCell},
+    rc::Rc,
+    sync::atomic::AtomicU8,
 };
 
-use lazy_static::lazy_static;
+use crate::{
+    device::virtio::{block_backend::FaultControl, shared_memory::SharedMemory},
+    isa::riscv::taint::DiskTaint,
+    vclock::Timer,
+};
 
 pub(crate) trait VirtIODeviceTrait {
     fn get_device_id(&self) -> u16;
@@ -14,6 +19,10 @@ pub(crate) trait VirtIODeviceTrait {
     fn update_irq(&mut self);
 
     fn get_host_feature(&self) -> u64;
+    /// Features the driver must accept, `FEATURES_OK` is refused otherwise.
+    fn required_features(&self) -> u64 {
+        0
+    }
     fn set_feature(&mut self, feature: u64);
 
     fn set_queue_num(&mut self, num: u32);
@@ -26,28 +35,43 @@ pub(crate) trait VirtIODeviceTrait {
     fn set_used(&mut self, addr: u64);
 
     fn manage_one_request(&mut self) -> bool;
+    /// Requests made available by the driver but not processed yet.
+    fn queue_depth(&self) -> u32 {
+        0
+    }
     fn notify(&mut self, queue_idx: u32);
 
+    /// The `idx`-th 32-bit word of the device configuration.
     fn read_config(&mut self, idx: u64) -> u32;
     fn write_config(&mut self, idx: u64, data: u32);
 
-    fn get_poll_event(&mut self) -> Option<Box<dyn crate::device_poller::PollingEventTrait>> {
-        None
+    /// The IDs and lengths of the shared memory regions the device needs.
+    fn shared_memory_regions(&self) -> Vec<(u8, u64)> {
+        Vec::new()
     }
-}
-
-pub(super) struct DeviceIDAllocator(AtomicU16);
+    /// The board mapped the shared memory region `id` into the guest.
+    fn attach_shared_memory(&mut self, _id: u8, _region: Rc<RefCell<SharedMemory>>) {}
 
-impl DeviceIDAllocator {
-    pub(super) fn new() -> Self {
-        Self(AtomicU16::new(0))
+    /// The faults injected into the device, for devices backed by a disk image.
+    fn faults(&self) -> Option<FaultControl> {
+        None
     }
-    pub(super) fn alloc(&mut self) -> u16 {
-        self.0.fetch_add(1, std::sync::atomic::Ordering::AcqRel)
+    /// Taint what the device reads from a region of its disk, returns `false` without a disk.
+    fn attach_taint(&mut self, _taint: DiskTaint) -> bool {
+        false
+    }
+    /// The board registered `task` in `timer` for the device, see [`Self::timer_expired`].
+    fn attach_timer(&mut self, _timer: Rc<UnsafeCell<Timer>>, _task: u64) {}
+    /// The task of [`Self::attach_timer`] is due.
+    fn timer_expired(&mut self) {}
+    /// Apply the requests served on the work queue, see
+    /// [`DeviceTrait::complete_work`](crate::device::DeviceTrait::complete_work).
+    fn complete_work(&mut self) {}
+
+    fn get_poll_event(&mut self) -> Option<Box<dyn crate::device_poller::PollingEventTrait>> {
+        None
     }
-}
 
-lazy_static! {
-    pub(super) static ref DEVICE_ID_ALLOCTOR: Mutex<DeviceIDAllocator> =
-        Mutex::new(DeviceIDAllocator::new());
+    /// Forget the negotiated features and the queues, and clear the status and interrupts.
+    fn reset(&mut self);
 }
@@ -1,41 +1,54 @@
-use std::cell::UnsafeCell;
+use std::{
+    cell::{RefCell, UnsafeCell},
+    rc::Rc,
+};
 
 use bitflags::bitflags;
-use log::error;
+use log::{error, warn};
 use num_enum::TryFromPrimitive;
 
 use crate::{
     device::{
         DeviceTrait, MemError, MemMappedDeviceTrait,
-        config::{VIRTIO_MMIO_BASE, VIRTIO_MMIO_SIZE},
-        virtio::{config::*, virtio_device::VirtIODeviceTrait},
+        config::{VIRTIO_IRQ_BASE, VIRTIO_MMIO_BASE, VIRTIO_MMIO_NAME, VIRTIO_MMIO_SIZE},
+        plic::irq_line::{IrqDescriptor, IrqPin},
+        stats::DeviceStats,
+        virtio::{
+            block_backend::FaultControl,
+            config::*,
+            shared_memory::{SharedMemory, SharedMemoryInfo},
+            virtio_device::VirtIODeviceTrait,
+        },
     },
+    isa::riscv::taint::DiskTaint,
     utils::{BIT_ONES_ARRAY, check_align},
+    vclock::Timer,
 };
 
+/// Device IDs of the VirtIO specification, read from the `DeviceID` register.
 #[repr(u32)]
 #[derive(Debug, Clone, Copy)]
 #[allow(unused)]
 pub enum VirtIODeviceID {
-    Network,
-    Block,
-    Console,
-    Entropy,
-    Balloon,
-    SCSIHost,
-    GPU,
-    Input,
-    Crypto,
-    Socket,
-    FileSystem,
-    RPMB,
-    IOMMU,
-    Sound,
-    Memory,
-    I2CAdapter,
-    SCMI,
-    GPIO,
-    PMEM,
+    Network = 1,
+    Block = 2,
+    Console = 3,
+    Entropy = 4,
+    Balloon = 5,
+    SCSIHost = 8,
+    GPU = 16,
+    Input = 18,
+    Crypto = 20,
+    Socket = 19,
+    FileSystem = 26,
+    RPMB = 28,
+    IOMMU = 23,
+    Sound = 25,
+    Memory = 24,
+    I2CAdapter = 34,
+    SCMI = 32,
+    GPIO = 41,
+    PMEM = 27,
 }
 
 #[repr(u64)]
@@ -119,6 +132,12 @@ pub(crate) struct VirtIOMMIO {
 
     queues: [VirtIOMMIOQueueStatus; 8],
     queue_select: u64,
+
+    shared_memory: Vec<SharedMemoryInfo>,
+    shared_memory_select: u32,
+
+    /// Asserted while the interrupt status is non-zero.
+    irq: Option<IrqPin>,
 }
 
 impl VirtIOMMIO {
@@ -132,7 +151,118 @@ impl VirtIOMMIO {
 
             queues: [VirtIOMMIOQueueStatus::default(); 8],
             queue_select: 0,
+
+            shared_memory: Vec::new(),
+            shared_memory_select: 0,
+
+            irq: None,
+        }
+    }
+
+    /// The features of the device and of the transport.
+    fn offered_features(&self) -> u64 {
+        let vdev = unsafe { self.device.as_ref_unchecked() };
+        vdev.get_host_feature() | VIRTIO_F_VERSION_1
+    }
+
+    /// Check the features the driver accepted when it sets `FEATURES_OK`. The bit stays clear
+    /// if they are rejected, which the driver notices when it reads the status back.
+    fn accept_features(&mut self) -> bool {
+        let offered = self.offered_features();
+        let unknown = self.guest_features & !offered;
+        let missing = self.device.get_mut().required_features() & !self.guest_features;
+        if unknown != 0 || missing != 0 {
+            warn!(
+                "VirtIO: rejected driver features {:#x}, unknown: {:#x}, missing: {:#x}",
+                self.guest_features, unknown, missing
+            );
+            return false;
         }
+        self.device
+            .get_mut()
+            .set_feature(self.guest_features & !VIRTIO_F_VERSION_1);
+        true
+    }
+
+    fn set_status(&mut self, value: u32) {
+        if value == 0 {
+            DeviceTrait::reset(self);
+            return;
+        }
+        let Some(mut new_status) = VirtIODeviceStatus::from_bits(value as u8) else {
+            error!("VirtIO: invalid status: {:#x}", value);
+            return;
+        };
+        let status = VirtIODeviceStatus::from_bits_retain(*self.device.get_mut().status());
+        if new_status.contains(VirtIODeviceStatus::FEATURES_OK)
+            && !status.contains(VirtIODeviceStatus::FEATURES_OK)
+            && !self.accept_features()
+        {
+            new_status.remove(VirtIODeviceStatus::FEATURES_OK);
+        }
+        *self.device.get_mut().status() |= new_status.bits();
+    }
+
+    fn update_irq(&mut self) {
+        if let Some(pin) = &self.irq {
+            let vdev = self.device.get_mut();
+            pin.set_level(vdev.isr().load(std::sync::atomic::Ordering::Acquire) != 0);
+        }
+    }
+
+    /// The IDs and lengths of the shared memory regions of the device, to be mapped by the board.
+    pub(crate) fn shared_memory_regions(&self) -> Vec<(u8, u64)> {
+        unsafe { self.device.as_ref_unchecked() }.shared_memory_regions()
+    }
+
+    /// The board mapped the shared memory region `id` at `base`, the driver finds it there
+    /// through the `SHMSel`, `SHMLen` and `SHMBase` registers.
+    pub(crate) fn attach_shared_memory(
+        &mut self,
+        id: u8,
+        base: u64,
+        region: Rc<RefCell<SharedMemory>>,
+    ) {
+        let len = region.borrow().len();
+        self.shared_memory.push(SharedMemoryInfo { id, base, len });
+        self.device.get_mut().attach_shared_memory(id, region);
+    }
+
+    /// The faults injected into the device, if it is backed by a disk image.
+    pub(crate) fn faults(&self) -> Option<FaultControl> {
+        unsafe { self.device.as_ref_unchecked() }.faults()
+    }
+
+    /// Taint what the device reads from a region of its disk, returns `false` without a disk.
+    pub(crate) fn attach_taint(&mut self, taint: DiskTaint) -> bool {
+        self.device.get_mut().attach_taint(taint)
+    }
+
+    /// Let the device complete requests later: the board runs [`Self::timer_expired`] once
+    /// `task` of `timer` is due.
+    pub(crate) fn attach_timer(&mut self, timer: Rc<UnsafeCell<Timer>>, task: u64) {
+        self.device.get_mut().attach_timer(timer, task);
+    }
+
+    pub(crate) fn timer_expired(&mut self) {
+        self.device.get_mut().timer_expired();
+        self.update_irq();
+    }
+
+    /// The selected shared memory region, a missing one reads as all ones.
+    fn selected_shared_memory(&self) -> Option<&SharedMemoryInfo> {
+        self.shared_memory
+            .iter()
+            .find(|region| region.id as u32 == self.shared_memory_select)
+    }
+
+    /// Tell the driver the device configuration changed, e.g. after the device was hot-plugged.
+    pub(crate) fn notify_config_change(&mut self) {
+        self.device
+            .get_mut()
+            .isr()
+            .fetch_or(VIRTIO_MMIO_INT_CONFIG, std::sync::atomic::Ordering::AcqRel);
+        self.update_irq();
     }
 
     fn read_u32_impl(&self, offset: u64) -> u32 {
@@ -159,21 +289,33 @@ impl VirtIOMMIO {
                     VirtIO_MMIO_Offset::DeviceId => vdev.get_device_id() as u32,
                     VirtIO_MMIO_Offset::VendorId => VIRT_VENDOR,
                     VirtIO_MMIO_Offset::DeviceFeatures => {
-                        (vdev.get_host_feature() >> (self.host_features_sel * 32)) as u32
+                        (self.offered_features() >> (self.host_features_sel * 32)) as u32
                     }
                     VirtIO_MMIO_Offset::QueueNumMax => VIRTQUEUE_MAX_SIZE,
                     // VirtIO_MMIO_Offset::QueuePFN => 0 as u32, // legacy
                     VirtIO_MMIO_Offset::QueueReady => {
-                        vdev.queue_ready() as u32
+                        // Drivers refuse to set up a queue which reads as ready.
+                        self.queues[self.queue_select as usize].enable as u32
                     }
                     VirtIO_MMIO_Offset::InterruptStatus => {
                         vdev.isr().load(std::sync::atomic::Ordering::Relaxed) as u32
                     }
                     VirtIO_MMIO_Offset::Status => *vdev.status() as u32,
                     VirtIO_MMIO_Offset::ConfigGeneration => vdev.get_generation(),
-                    VirtIO_MMIO_Offset::SharedMemLenLow | VirtIO_MMIO_Offset::SharedMemLenHigh => u32::MAX,
+                    VirtIO_MMIO_Offset::SharedMemLenLow => {
+                        self.selected_shared_memory().map_or(u32::MAX, |region| region.len as u32)
+                    }
+                    VirtIO_MMIO_Offset::SharedMemLenHigh => self
+                        .selected_shared_memory()
+                        .map_or(u32::MAX, |region| (region.len >> 32) as u32),
+                    VirtIO_MMIO_Offset::SharedMemBaseLow => {
+                        self.selected_shared_memory().map_or(u32::MAX, |region| region.base as u32)
+                    }
+                    VirtIO_MMIO_Offset::SharedMemBaseHigh => self
+                        .selected_shared_memory()
+                        .map_or(u32::MAX, |region| (region.base >> 32) as u32),
                     VirtIO_MMIO_Offset::Config => {
-                        vdev.read_config(offset - VirtIO_MMIO_Offset::Config as u64)
+                        vdev.read_config((offset - VirtIO_MMIO_Offset::Config as u64) / 4)
                     }
                     VirtIO_MMIO_Offset::DeviceFeaturesSelect
                     | VirtIO_MMIO_Offset::DriverFeatures
@@ -195,8 +337,8 @@ impl VirtIOMMIO {
                         unreachable!()
                     }
                     // VirtIO_MMIO_Offset::QueueReset | 
-                    VirtIO_MMIO_Offset::SharedMemBaseHigh | VirtIO_MMIO_Offset::SharedMemBaseLow | VirtIO_MMIO_Offset::SharedMemSelect => {
-                        error!("VirtIO: read of unimplemented register: {:#x}", offset);
+                    VirtIO_MMIO_Offset::SharedMemSelect => {
+                        error!("VirtIO: read of write-only register: {:#x}", offset);
                         0
                     }
                 };
@@ -224,21 +366,30 @@ impl VirtIOMMIO {
             Ok(offset_type) => match offset_type {
                 VirtIO_MMIO_Offset::DeviceFeaturesSelect => self.host_features_sel = value & 0x1,
                 VirtIO_MMIO_Offset::DriverFeatures => {
-                    let feature = (value as u64) << (self.host_features_sel * 32);
-                    self.guest_features |= feature;
+                    let shift = self.guest_features_sel * 32;
+                    self.guest_features &= !((u32::MAX as u64) << shift);
+                    self.guest_features |= (value as u64) << shift;
                 }
                 VirtIO_MMIO_Offset::DriverFeaturesSelect => {
                     self.guest_features_sel = value & 0x1;
                 }
                 // VirtIO_MMIO_Offset::GUEST_PAGE_SIZE => {}, // legacy
                 VirtIO_MMIO_Offset::QueueSelect => {
-                    vdev.set_queue_num(value);
+                    if (value as usize) < self.queues.len() {
+                        self.queue_select = value as u64;
+                        vdev.queue_select(value);
+                    } else {
+                        error!("VirtIO: select of queue {} out of range", value);
+                    }
                 }
                 VirtIO_MMIO_Offset::QueueNum => {
                     vdev.set_queue_num(value);
                 }
                 // VirtIO_MMIO_Offset::QueueAlign => {}, // legacy
                 // VirtIO_MMIO_Offset::QueuePFN => {}, // legacy
+                VirtIO_MMIO_Offset::QueueReady if value == 0 => {
+                    self.queues[self.queue_select as usize].enable = false;
+                }
                 VirtIO_MMIO_Offset::QueueReady => {
                     let q = &mut self.queues[self.queue_select as usize];
                     vdev.set_desc(q.desc);
@@ -257,17 +408,7 @@ impl VirtIOMMIO {
                         .fetch_and(!(value as u8), std::sync::atomic::Ordering::AcqRel);
                     vdev.update_irq();
                 }
-                VirtIO_MMIO_Offset::Status => {
-                    if let Some(new_status) = VirtIODeviceStatus::from_bits(value as u8) {
-                        // if (new_status & VirtIODeviceStatus::DRIVER_OK).is_empty() {
-                        //     // virtio_mmio_stop_ioeventfd(proxy);
-                        // }
-                        if !(new_status & VirtIODeviceStatus::FEATURES_OK).is_empty() {
-                            vdev.set_feature(self.guest_features)
-                        }
-                        *vdev.status() |= new_status.bits();
-                    }
-                }
+                VirtIO_MMIO_Offset::Status => self.set_status(value),
                 VirtIO_MMIO_Offset::QueueDescLow => {
                     let q = &mut self.queues[self.queue_select as usize];
                     q.desc |= value as u64;
@@ -293,7 +434,7 @@ impl VirtIOMMIO {
                     q.used |= (value as u64) << 32;
                 }
                 VirtIO_MMIO_Offset::Config => {
-                    vdev.write_config(offset - VirtIO_MMIO_Offset::Config as u64, value);
+                    vdev.write_config((offset - VirtIO_MMIO_Offset::Config as u64) / 4, value);
                 }
                 VirtIO_MMIO_Offset::MagicValue
                 | VirtIO_MMIO_Offset::Version
@@ -302,16 +443,14 @@ impl VirtIOMMIO {
                 | VirtIO_MMIO_Offset::DeviceFeatures
                 | VirtIO_MMIO_Offset::QueueNumMax
                 | VirtIO_MMIO_Offset::InterruptStatus
-                | VirtIO_MMIO_Offset::ConfigGeneration => {
-                    error!("VirtIO: write to read-only register: {:#x}", offset);
-                }
-                VirtIO_MMIO_Offset::SharedMemBaseHigh
+                | VirtIO_MMIO_Offset::ConfigGeneration
+                | VirtIO_MMIO_Offset::SharedMemBaseHigh
                 | VirtIO_MMIO_Offset::SharedMemBaseLow
                 | VirtIO_MMIO_Offset::SharedMemLenHigh
-                | VirtIO_MMIO_Offset::SharedMemLenLow
-                | VirtIO_MMIO_Offset::SharedMemSelect => {
-                    error!("VirtIO: DO NOT allow shared memory.");
+                | VirtIO_MMIO_Offset::SharedMemLenLow => {
+                    error!("VirtIO: write to read-only register: {:#x}", offset);
                 }
+                VirtIO_MMIO_Offset::SharedMemSelect => self.shared_memory_select = value,
             },
         };
     }
@@ -323,6 +462,13 @@ impl VirtIOMMIO {
     where
         T: crate::utils::UnsignedInteger,
     {
+        // The device configuration can be accessed in bytes and halfwords as well.
+        let config = VirtIO_MMIO_Offset::Config as u64;
+        if addr >= config && size_of::<T>() <= size_of::<u32>() {
+            let vdev = self.device.get_mut();
+            let word = vdev.read_config((addr - config) / 4);
+            return Ok(T::truncate_from(word >> ((addr & 3) * 8)));
+        }
         if size_of::<T>() != size_of::<u32>() {
             return Err(MemError::LoadMisaligned);
         }
@@ -341,6 +487,17 @@ impl VirtIOMMIO {
     where
         T: crate::utils::UnsignedInteger,
     {
+        let config = VirtIO_MMIO_Offset::Config as u64;
+        if addr >= config && size_of::<T>() <= size_of::<u32>() {
+            let shift = (addr & 3) * 8;
+            let mask = (u32::MAX >> (32 - size_of::<T>() * 8)) << shift;
+            let data: u64 = data.into();
+            let vdev = self.device.get_mut();
+            let idx = (addr - config) / 4;
+            let word = vdev.read_config(idx) & !mask | ((data as u32) << shift) & mask;
+            vdev.write_config(idx, word);
+            return Ok(());
+        }
         if size_of::<T>() != size_of::<u32>() {
             return Err(MemError::StoreMisaligned);
         }
@@ -348,6 +505,8 @@ impl VirtIOMMIO {
         let data = unsafe { (&data as *const T as *const u32).read() };
         let offset = addr & !BIT_ONES_ARRAY[2]; // align to u32
         self.write_u32_impl(offset, data);
+        // Both queue notifications and interrupt acks may change the interrupt status.
+        self.update_irq();
         Ok(())
     }
 }
@@ -359,15 +518,82 @@ impl DeviceTrait for VirtIOMMIO {
     fn get_poll_event(&mut self) -> Option<Box<dyn crate::device_poller::PollingEventTrait>> {
         self.device.get_mut().get_poll_event()
     }
+
+    fn connect_irq(&mut self, pin: IrqPin) {
+        self.irq = Some(pin);
+    }
+
+    fn complete_work(&mut self) {
+        self.device.get_mut().complete_work();
+        self.update_irq();
+    }
+
+    fn report_stats(&mut self, stats: &mut DeviceStats) {
+        if let Some(pin) = &self.irq {
+            stats.record_irq(pin);
+        }
+        stats.queue_depth = Some(self.device.get_mut().queue_depth());
+    }
+
+    fn reset(&mut self) {
+        self.host_features_sel = 0;
+        self.guest_features_sel = 0;
+        self.guest_features = 0;
+        self.queues = [VirtIOMMIOQueueStatus::default(); 8];
+        self.queue_select = 0;
+        self.shared_memory_select = 0;
+        self.device.get_mut().reset();
+        self.update_irq();
+    }
 }
 
 impl MemMappedDeviceTrait for VirtIOMMIO {
+    fn name() -> &'static str {
+        VIRTIO_MMIO_NAME
+    }
     fn base() -> crate::config::arch_config::WordType {
         VIRTIO_MMIO_BASE
     }
     fn size() -> crate::config::arch_config::WordType {
         VIRTIO_MMIO_SIZE
     }
+    fn irq() -> Option<IrqDescriptor> {
+        Some(IrqDescriptor::level(VIRTIO_IRQ_BASE))
+    }
+}
+
+/// A VirtIO MMIO slot without device. Like on QEMU it reads as device ID 0, which drivers
+/// skip, so the device tree can list every slot whether it is populated or not.
+pub(crate) struct EmptyVirtIOSlot;
+
+impl DeviceTrait for EmptyVirtIOSlot {
+    fn read(
+        &mut self,
+        addr: crate::config::arch_config::WordType,
+        _len: u32,
+    ) -> Result<u64, MemError> {
+        let value = match VirtIO_MMIO_Offset::try_from(addr) {
+            Ok(VirtIO_MMIO_Offset::MagicValue) => VIRT_MAGIC,
+            Ok(VirtIO_MMIO_Offset::Version) => VIRT_VERSION,
+            Ok(VirtIO_MMIO_Offset::VendorId) => VIRT_VENDOR,
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn write(
+        &mut self,
+        _addr: crate::config::arch_config::WordType,
+        _len: u32,
+        _data: u64,
+    ) -> Result<(), MemError> {
+        Ok(())
+    }
+
+    fn sync(&mut self) {}
+    fn get_poll_event(&mut self) -> Option<Box<dyn crate::device_poller::PollingEventTrait>> {
+        None
+    }
 }
 
 #[cfg(test)]
@@ -444,15 +670,16 @@ impl GuestFeatureBuilder {
 
 #[cfg(test)]
 mod test {
-    use core::slice;
+    use core::{mem::offset_of, slice};
     use std::io::{Read, Seek};
 
     use super::*;
     use crate::{
         device::virtio::{
+            block_backend::{CacheMode, FaultConfig},
             virtio_blk::{
-                VirtIOBlkDeviceBuilder, VirtIOBlkReqStatus, VirtIOBlockFeature, VirtioBlkReq,
-                VirtioBlkReqType, VirtioBlkStatus, init_block_file,
+                VirtIOBlkDeviceBuilder, VirtIOBlkReqStatus, VirtIOBlockFeature, VirtioBlkConfig,
+                VirtioBlkReq, VirtioBlkReqType, VirtioBlkStatus, init_block_file,
             },
             virtio_queue::{
                 VirtQueueAvail, VirtQueueAvailFlag, VirtQueueDesc, VirtQueueDescFlag,
@@ -461,6 +688,7 @@ mod test {
         },
         ram::Ram,
         ram_config,
+        vclock::VirtualClockRef,
     };
 
     const QUEUE_NUM: usize = 8;
@@ -474,8 +702,7 @@ mod test {
         let mut file = init_block_file(&file_name, 1, |_| &buf);
 
         let mut ram = Ram::new();
-        let ram_base = &mut ram[0] as *mut u8;
-        let virt_device = VirtIOBlkDeviceBuilder::new(ram_base, file_name)
+        let virt_device = VirtIOBlkDeviceBuilder::new(&mut ram, file_name)
             .name("VirtIO Block 0")
             .generation(0)
             .host_feature(VirtIOBlockFeature::BlockSize)
@@ -491,13 +718,15 @@ mod test {
         let driver_feature = GuestFeatureBuilder::new()
             .add_guest_feature(VirtIOBlockFeature::BlockSize as u64)
             .add_guest_feature(VirtIOBlockFeature::Flush as u64)
+            .add_guest_feature(VIRTIO_F_VERSION_1)
             .take();
         virtio_mmio_device.set_guest_feature(driver_feature);
 
-        virtio_mmio_device.write_status(VirtIODeviceStatus::DRIVER_OK);
         virtio_mmio_device.write_status(VirtIODeviceStatus::FEATURES_OK);
+        virtio_mmio_device.write_status(VirtIODeviceStatus::DRIVER_OK);
         let status = virtio_mmio_device.read_u32_impl(VirtIO_MMIO_Offset::Status as u64);
         assert!(status & VirtIODeviceStatus::DRIVER_OK.bits() as u32 != 0);
+        assert!(status & VirtIODeviceStatus::FEATURES_OK.bits() as u32 != 0);
 
         // init virt_queue.
         virtio_mmio_device.write_u32_impl(VirtIO_MMIO_Offset::QueueSelect as u64, 0);
@@ -603,4 +832,223 @@ mod test {
         let capacity = virtio_mmio_device.read_u32_impl(VirtIO_MMIO_Offset::Config as u64);
         assert_eq!(capacity, 1);
     }
+
+    #[test]
+    fn test_injected_latency() {
+        let file_name = String::from("./tmp/test_injected_latency.img");
+        init_block_file(&file_name, 1, |_| &[0u8; 512]);
+
+        let mut ram = Ram::new();
+        let virt_device = VirtIOBlkDeviceBuilder::new(&mut ram, file_name).get();
+        let mut device = VirtIOMMIO::new(Box::new(UnsafeCell::new(virt_device)));
+        let clock = VirtualClockRef::new();
+        let timer = Rc::new(UnsafeCell::new(Timer::new(clock.clone())));
+        let task = unsafe { timer.as_mut_unchecked() }.register(|| {});
+        device.attach_timer(timer.clone(), task);
+        device.faults().unwrap().set(FaultConfig {
+            latency: 100,
+            ..Default::default()
+        });
+
+        device.write_status(VirtIODeviceStatus::ACKNOWLEDGE | VirtIODeviceStatus::DRIVER);
+        device.set_guest_feature(VIRTIO_F_VERSION_1);
+        device.write_status(VirtIODeviceStatus::FEATURES_OK | VirtIODeviceStatus::DRIVER_OK);
+        device.write_u32_impl(VirtIO_MMIO_Offset::QueueNum as u64, QUEUE_NUM as u32);
+        let desc_base = 0x8000_2000;
+        let avail_base = 0x8000_2100;
+        let used_base = 0x8000_2200;
+        device.init_queue(desc_base, avail_base, used_base);
+
+        let at = |addr: u64| addr - ram_config::BASE_ADDR;
+        let descs = unsafe {
+            slice::from_raw_parts_mut(
+                &mut ram[at(desc_base) as usize] as *mut u8 as *mut VirtQueueDesc,
+                DESC_NUM,
+            )
+        };
+        // A read of sector 0.
+        unsafe {
+            *(&mut ram[at(0x8000_2300) as usize] as *mut u8 as *mut VirtioBlkReq) =
+                VirtioBlkReq::new(VirtioBlkReqType::In, 0);
+        }
+        descs[0].init(0x8000_2300, 16, VirtQueueDescFlag::VIRTQ_DESC_F_NEXT, 1);
+        descs[1].init(
+            0x8000_2400,
+            512,
+            VirtQueueDescFlag::VIRTQ_DESC_F_NEXT | VirtQueueDescFlag::VIRTQ_DESC_F_WRITE,
+            2,
+        );
+        descs[2].init(0x8000_2310, 1, VirtQueueDescFlag::VIRTQ_DESC_F_WRITE, 0);
+        let avail = unsafe {
+            (&mut ram[at(avail_base) as usize] as *mut u8 as *mut VirtQueueAvail)
+                .as_mut()
+                .unwrap()
+        };
+        avail.init(VirtQueueAvailFlag::Default);
+        VirtQueueAvail::mut_ring(avail as *mut _ as u64, QUEUE_NUM as u32)[0] = 0;
+        avail.idx_atomic_add(1);
+
+        let used_idx = |ram: &mut Ram| ram.read::<u16>(at(used_base) + 2).unwrap();
+        let interrupt = VirtIO_MMIO_Offset::InterruptStatus as u64;
+        device.write_u32_impl(VirtIO_MMIO_Offset::QueueNotify as u64, 0);
+        assert_eq!(used_idx(&mut ram), 0);
+        assert_eq!(device.read_u32_impl(interrupt), 0);
+        assert_eq!(unsafe { timer.as_ref_unchecked() }.next_due(), Some(100));
+
+        clock.advance(100);
+        device.timer_expired();
+        assert_eq!(used_idx(&mut ram), 1);
+        assert_eq!(
+            device.read_u32_impl(interrupt),
+            VIRTIO_MMIO_INT_VRING as u32
+        );
+        assert_eq!(
+            ram.read::<u8>(at(0x8000_2310)),
+            Ok(VirtIOBlkReqStatus::Ok as u8)
+        );
+    }
+
+    #[test]
+    fn test_feature_negotiation_and_reset() {
+        let file_name = String::from("./tmp/test_feature_negotiation.img");
+        init_block_file(&file_name, 1, |_| &[0u8; 512]);
+
+        let mut ram = Ram::new();
+        let virt_device = VirtIOBlkDeviceBuilder::new(&mut ram, file_name)
+            .host_feature(VirtIOBlockFeature::BlockSize)
+            .get();
+        let mut device = VirtIOMMIO::new(Box::new(UnsafeCell::new(virt_device)));
+        let status =
+            |device: &mut VirtIOMMIO| device.read_u32_impl(VirtIO_MMIO_Offset::Status as u64) as u8;
+
+        let offered = device.get_host_feature();
+        assert_eq!(
+            offered,
+            VIRTIO_F_VERSION_1
+                | VIRTIO_RING_F_EVENT_IDX
+                | VirtIOBlockFeature::BlockSize as u64
+                | VirtIOBlockFeature::Flush as u64
+                | VirtIOBlockFeature::ConfigWce as u64
+        );
+
+        // Features the device does not offer.
+        device.write_status(VirtIODeviceStatus::ACKNOWLEDGE | VirtIODeviceStatus::DRIVER);
+        device.set_guest_feature(VIRTIO_F_VERSION_1 | VirtIOBlockFeature::Multiqueue as u64);
+        device.write_status(VirtIODeviceStatus::FEATURES_OK);
+        assert_eq!(
+            status(&mut device) & VirtIODeviceStatus::FEATURES_OK.bits(),
+            0
+        );
+
+        // Selecting the high word again replaces it.
+        device.write_u32_impl(VirtIO_MMIO_Offset::DriverFeaturesSelect as u64, 1);
+        device.write_u32_impl(VirtIO_MMIO_Offset::DriverFeatures as u64, 1);
+        assert_eq!(device.guest_features >> 32, 1);
+
+        device.set_guest_feature(offered);
+        device.write_status(VirtIODeviceStatus::FEATURES_OK);
+        assert_ne!(
+            status(&mut device) & VirtIODeviceStatus::FEATURES_OK.bits(),
+            0
+        );
+
+        assert_eq!(
+            device.read_u32_impl(VirtIO_MMIO_Offset::QueueReady as u64),
+            0
+        );
+        device.init_queue(0x8000_2000, 0x8000_2100, 0x8000_2200);
+        assert_eq!(
+            device.read_u32_impl(VirtIO_MMIO_Offset::QueueReady as u64),
+            1
+        );
+        device.notify_config_change();
+        assert_ne!(
+            device.read_u32_impl(VirtIO_MMIO_Offset::InterruptStatus as u64),
+            0
+        );
+
+        device.write_u32_impl(VirtIO_MMIO_Offset::Status as u64, 0);
+        assert_eq!(status(&mut device), 0);
+        assert_eq!(
+            device.read_u32_impl(VirtIO_MMIO_Offset::InterruptStatus as u64),
+            0
+        );
+        assert_eq!(
+            device.read_u32_impl(VirtIO_MMIO_Offset::QueueReady as u64),
+            0
+        );
+        assert_eq!(device.guest_features, 0);
+    }
+
+    #[test]
+    fn test_shared_memory_registers() {
+        let file_name = String::from("./tmp/test_shared_memory_registers.img");
+        init_block_file(&file_name, 1, |_| &[0u8; 512]);
+
+        let mut ram = Ram::new();
+        let virt_device = VirtIOBlkDeviceBuilder::new(&mut ram, file_name).get();
+        let mut device = VirtIOMMIO::new(Box::new(UnsafeCell::new(virt_device)));
+        let region = |device: &mut VirtIOMMIO, id| {
+            device.write_u32_impl(VirtIO_MMIO_Offset::SharedMemSelect as u64, id);
+            [
+                VirtIO_MMIO_Offset::SharedMemLenLow,
+                VirtIO_MMIO_Offset::SharedMemLenHigh,
+                VirtIO_MMIO_Offset::SharedMemBaseLow,
+                VirtIO_MMIO_Offset::SharedMemBaseHigh,
+            ]
+            .map(|reg| device.read_u32_impl(reg as u64))
+        };
+
+        // No region reads as a length and base of all ones.
+        assert_eq!(region(&mut device, 0), [u32::MAX; 4]);
+
+        let shm = Rc::new(RefCell::new(SharedMemory::new(0x2000)));
+        device.attach_shared_memory(1, 0x1_4000_0000, shm);
+        assert_eq!(region(&mut device, 0), [u32::MAX; 4]);
+        assert_eq!(region(&mut device, 1), [0x2000, 0, 0x4000_0000, 1]);
+    }
+
+    #[test]
+    fn test_write_cache_config() {
+        let file_name = String::from("./tmp/test_write_cache_config.img");
+        init_block_file(&file_name, 1, |_| &[0u8; 512]);
+        let writeback =
+            VirtIO_MMIO_Offset::Config as u64 + offset_of!(VirtioBlkConfig, writeback) as u64;
+
+        let mut ram = Ram::new();
+        let virt_device = VirtIOBlkDeviceBuilder::new(&mut ram, file_name.clone())
+            .cache(CacheMode::Writethrough)
+            .get();
+        let mut device = VirtIOMMIO::new(Box::new(UnsafeCell::new(virt_device)));
+        assert_eq!(
+            device.get_host_feature() & VirtIOBlockFeature::ConfigWce as u64,
+            0
+        );
+        assert_eq!(device.read_u8(writeback), Ok(0));
+
+        let virt_device = VirtIOBlkDeviceBuilder::new(&mut ram, file_name).get();
+        let mut device = VirtIOMMIO::new(Box::new(UnsafeCell::new(virt_device)));
+        assert_eq!(device.read_u8(writeback), Ok(1));
+        // Read-only until the driver accepts `VIRTIO_BLK_F_CONFIG_WCE`.
+        device.write_u8(writeback, 0).unwrap();
+        assert_eq!(device.read_u8(writeback), Ok(1));
+
+        device.write_status(VirtIODeviceStatus::ACKNOWLEDGE | VirtIODeviceStatus::DRIVER);
+        device.set_guest_feature(
+            VIRTIO_F_VERSION_1
+                | VirtIOBlockFeature::Flush as u64
+                | VirtIOBlockFeature::ConfigWce as u64,
+        );
+        device.write_status(VirtIODeviceStatus::FEATURES_OK);
+        device.write_u8(writeback, 0).unwrap();
+        assert_eq!(device.read_u8(writeback), Ok(0));
+        // The capacity is not writable.
+        device
+            .write_u32(VirtIO_MMIO_Offset::Config as u64, 7)
+            .unwrap();
+        assert_eq!(device.read_u16(VirtIO_MMIO_Offset::Config as u64), Ok(1));
+
+        device.write_u32_impl(VirtIO_MMIO_Offset::Status as u64, 0);
+        assert_eq!(device.read_u8(writeback), Ok(1));
+    }
 }
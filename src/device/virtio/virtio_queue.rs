@@ -4,12 +4,13 @@ use std::{ptr::null_mut, sync::atomic::AtomicU16};
 use bitflags::bitflags;
 use log::error;
 
-use crate::ram_config;
+use crate::device::virtio::dma::{DmaAccess, DmaBuf, DmaError, GuestRam};
 
 // =====================================
 //           VirtQueueDesc
 // =====================================
 bitflags! {
+    #[derive(Clone, Copy)]
     pub(crate) struct VirtQueueDescFlag: u16 {
     /* This marks a buffer as continuing via the next field. */
     const VIRTQ_DESC_F_NEXT     = 1 << 0;
@@ -23,6 +24,7 @@ bitflags! {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 // 128 bits (0x10 bytes)
 pub(crate) struct VirtQueueDesc {
     /* Address (guest-physical). */
@@ -36,8 +38,34 @@ pub(crate) struct VirtQueueDesc {
 }
 
 impl VirtQueueDesc {
-    pub(crate) fn get_request_package<T>(&self, ram_base_raw: usize) -> *mut T {
-        (self.paddr - ram_config::BASE_ADDR + ram_base_raw as u64) as *mut T
+    /// Device-writable descriptors are written, the others are read.
+    fn access(&self) -> DmaAccess {
+        if self.flags.contains(VirtQueueDescFlag::VIRTQ_DESC_F_WRITE) {
+            DmaAccess::Write
+        } else {
+            DmaAccess::Read
+        }
+    }
+
+    /// Where the buffer of the descriptor is, for the device.
+    pub(crate) fn paddr(&self) -> u64 {
+        self.paddr
+    }
+
+    /// The buffer of the descriptor.
+    pub(crate) fn buffer<'a>(&self, ram: &GuestRam) -> Result<DmaBuf<'a>, DmaError> {
+        ram.buffer(self.paddr, self.len as usize, self.access())
+    }
+
+    /// The buffer of the descriptor as a `T`, which must fit in it.
+    pub(crate) fn request<'a, T>(&self, ram: &GuestRam) -> Result<&'a mut T, DmaError> {
+        if (self.len as usize) < size_of::<T>() {
+            return Err(DmaError::ShortDescriptor {
+                len: self.len,
+                needed: size_of::<T>(),
+            });
+        }
+        ram.get_mut(self.paddr, self.access())
     }
 }
 
@@ -53,73 +81,97 @@ impl VirtQueueDesc {
 
 pub(crate) struct VirtQueueDescHandle<'a> {
     table: &'a [VirtQueueDesc],
-    ram_base: usize,
-    idx: usize,
+    ram: GuestRam,
+    head: usize,
+    /// The next descriptor, `None` at the end of the chain.
+    idx: Option<usize>,
+    /// Descriptors left before the chain must have ended, to catch loops.
+    remaining: usize,
+    indirect: bool,
 }
 
 impl VirtQueueDescHandle<'_> {
     pub(crate) fn new(
         table: *const VirtQueueDesc,
-        ram_base: usize,
+        ram: GuestRam,
         queue_num: u32,
         idx: usize,
-    ) -> Self {
-        Self {
-            table: unsafe { slice::from_raw_parts(table, queue_num as usize) },
-            ram_base,
-            idx,
+    ) -> Result<Self, DmaError> {
+        let table = unsafe { slice::from_raw_parts(table, queue_num as usize) };
+        if idx >= table.len() {
+            return Err(DmaError::BadDescriptor {
+                index: idx,
+                size: table.len(),
+            });
         }
-    }
-
-    fn get_indirect(&mut self) {
-        let mut idx = self.idx;
-        while self.table[idx]
-            .flags
-            .contains(VirtQueueDescFlag::VIRTQ_DESC_F_INDIRECT)
-        {
-            // Handle indirect descriptor case
-            debug_assert_eq!(
-                self.table[idx].len as usize % std::mem::size_of::<VirtQueueDesc>(),
-                0
-            );
-            self.table = unsafe {
-                slice::from_raw_parts(
-                    (self.table[idx].paddr - self.ram_base as u64) as *const VirtQueueDesc,
-                    self.table[idx].len as usize / std::mem::size_of::<VirtQueueDesc>(),
-                )
-            };
-            idx = 0;
+        Ok(Self {
+            table,
+            ram,
+            head: idx,
+            idx: Some(idx),
+            remaining: table.len(),
+            indirect: false,
+        })
+    }
+
+    /// Continue the chain in the table of the indirect descriptor `desc`.
+    fn enter_indirect(&mut self, desc_idx: usize) -> Result<(), DmaError> {
+        if self.indirect {
+            return Err(DmaError::NestedIndirect);
+        }
+        let desc = &self.table[desc_idx];
+        let len = desc.len as usize / size_of::<VirtQueueDesc>();
+        if len == 0 {
+            return Err(DmaError::ShortDescriptor {
+                len: desc.len,
+                needed: size_of::<VirtQueueDesc>(),
+            });
         }
+        let table = self
+            .ram
+            .ptr::<VirtQueueDesc>(desc.paddr, desc.len as u64, DmaAccess::Read)?;
+        self.table = unsafe { slice::from_raw_parts(table, len) };
+        self.idx = Some(0);
+        self.remaining = len;
+        self.indirect = true;
+        Ok(())
     }
 
     pub(crate) fn get_entry_idx(&self) -> u32 {
-        self.idx as u32
+        self.head as u32
     }
 
-    pub(crate) fn try_get(&mut self) -> Option<&VirtQueueDesc> {
-        if self.idx < self.table.len() {
-            // indirect.
-            if self.table[self.idx]
-                .flags
-                .contains(VirtQueueDescFlag::VIRTQ_DESC_F_INDIRECT)
-            {
-                self.get_indirect();
-            }
-
-            // get current and update to next.
-            let cur = self.idx;
-            if self.table[cur]
-                .flags
-                .contains(VirtQueueDescFlag::VIRTQ_DESC_F_NEXT)
-            {
-                self.idx = self.table[cur].next as usize;
-            } else {
-                self.idx = self.table.len(); // mark as end.
+    pub(crate) fn try_get(&mut self) -> Result<Option<&VirtQueueDesc>, DmaError> {
+        let Some(mut cur) = self.idx else {
+            return Ok(None);
+        };
+        if self.table[cur]
+            .flags
+            .contains(VirtQueueDescFlag::VIRTQ_DESC_F_INDIRECT)
+        {
+            self.enter_indirect(cur)?;
+            cur = 0;
+        }
+        if self.remaining == 0 {
+            return Err(DmaError::ChainLoop);
+        }
+        self.remaining -= 1;
+
+        // get current and update to next.
+        let desc = &self.table[cur];
+        self.idx = if desc.flags.contains(VirtQueueDescFlag::VIRTQ_DESC_F_NEXT) {
+            let next = desc.next as usize;
+            if next >= self.table.len() {
+                return Err(DmaError::BadDescriptor {
+                    index: next,
+                    size: self.table.len(),
+                });
             }
-            Some(&self.table[cur])
+            Some(next)
         } else {
             None
-        }
+        };
+        Ok(Some(desc))
     }
 }
 
@@ -156,12 +208,17 @@ impl VirtQueueAvail {
             return None;
         }
 
-        *last_avail_idx = (old_idx + 1) % queue_num as u16;
+        // The indexes run freely, the ring is indexed modulo its size.
+        *last_avail_idx = old_idx.wrapping_add(1);
+        Some(self.ring(queue_num)[(old_idx as u32 % queue_num) as usize])
+    }
 
-        if (old_idx as u32) < queue_num {
-            Some(self.ring(queue_num)[old_idx as usize])
-        } else {
-            None
+    /// `used_event`: the driver wants an interrupt once the used index passes it.
+    fn used_event(&self, queue_num: u32) -> u16 {
+        unsafe {
+            (&self.ring0 as *const u16)
+                .add(queue_num as usize)
+                .read_volatile()
         }
     }
 }
@@ -219,7 +276,7 @@ pub(crate) struct VirtQueueUsed {
     idx: AtomicU16,           // Written by Device. (Locked in VirtQueue).
     ring0: VirtQueueUsedElem,
     /* ring1 ... */
-    /* Only if VIRTIO_F_EVENT_IDX: used_event: u16; */
+    /* Only if VIRTIO_F_EVENT_IDX: avail_event: u16; */
 }
 
 impl VirtQueueUsed {
@@ -233,13 +290,21 @@ impl VirtQueueUsed {
     }
 
     fn insert_used(&mut self, queue_num: u32, elem: VirtQueueUsedElem) {
-        let mut idx = self.idx.load(std::sync::atomic::Ordering::Relaxed);
-        let old_idx = idx;
-        idx += 1;
-        idx %= queue_num as u16;
-        self.idx.store(idx, std::sync::atomic::Ordering::Relaxed);
+        let idx = self.idx.load(std::sync::atomic::Ordering::Relaxed);
+        self.ring(queue_num)[(idx as u32 % queue_num) as usize] = elem;
+        // The element must be visible before the index which publishes it.
+        self.idx
+            .store(idx.wrapping_add(1), std::sync::atomic::Ordering::Release);
+    }
 
-        self.ring(queue_num)[old_idx as usize] = elem;
+    /// `avail_event`: the driver notifies once the available index passes it.
+    fn set_avail_event(&mut self, queue_num: u32, idx: u16) {
+        unsafe {
+            (&mut self.ring0 as *mut VirtQueueUsedElem)
+                .add(queue_num as usize)
+                .cast::<u16>()
+                .write_volatile(idx)
+        }
     }
 
     fn index_add(&self, val: u16) {
@@ -262,9 +327,12 @@ impl VirtQueueUsed {
 /// Needs to be wrapped in a Mutex.
 pub(crate) struct VirtQueue {
     queue_num: u32,
-    ram_base_raw: *mut u8,
+    ram: GuestRam,
 
     last_avail_idx: u16,
+    /// `VIRTIO_RING_F_EVENT_IDX` was negotiated, interrupts and notifications are suppressed
+    /// through `used_event` and `avail_event` instead of the ring flags.
+    event_idx: bool,
 
     desc_paddr: u64,
     desc: *mut VirtQueueDesc,
@@ -276,12 +344,13 @@ pub(crate) struct VirtQueue {
 
 /* Get location of event indices (only with VIRTIO_F_EVENT_IDX) */
 impl VirtQueue {
-    pub(crate) fn new(ram_base_raw: *mut u8, queue_num: u32) -> Self {
+    pub(crate) fn new(ram: GuestRam, queue_num: u32) -> Self {
         Self {
             queue_num,
-            ram_base_raw,
+            ram,
 
             last_avail_idx: 0,
+            event_idx: false,
 
             desc_paddr: 0,
             desc: null_mut::<VirtQueueDesc>(),
@@ -293,47 +362,56 @@ impl VirtQueue {
     }
     pub(crate) fn set_desc(&mut self, addr: u64) {
         self.desc_paddr = addr;
-        self.update_desc_base(self.desc_paddr);
+        let _ = self.map_rings();
     }
 
     pub(crate) fn set_avail(&mut self, addr: u64) {
         self.avail_paddr = addr;
-        self.update_avail_base(self.avail_paddr);
+        let _ = self.map_rings();
     }
 
     pub(crate) fn set_used(&mut self, addr: u64) {
         self.used_paddr = addr;
-        self.update_used_base(self.used_paddr);
+        let _ = self.map_rings();
     }
     pub(crate) fn set_used_high(&mut self, addr_high: u32) {
         self.used_paddr &= !((u32::MAX as u64) << 32);
         self.used_paddr |= (addr_high as u64) << 32;
-        self.update_used_base(self.used_paddr);
+        let _ = self.map_rings();
     }
 
-    fn update_avail_base(&mut self, paddr: u64) {
-        if paddr >= ram_config::BASE_ADDR {
-            self.avail = unsafe {
-                self.ram_base_raw
-                    .add((paddr - ram_config::BASE_ADDR) as usize)
-            } as *mut VirtQueueAvail;
-        }
+    fn is_configured(&self) -> bool {
+        self.queue_num != 0 && self.desc_paddr != 0 && self.avail_paddr != 0 && self.used_paddr != 0
     }
-    fn update_desc_base(&mut self, paddr: u64) {
-        if paddr >= ram_config::BASE_ADDR {
-            self.desc = unsafe {
-                self.ram_base_raw
-                    .add((paddr - ram_config::BASE_ADDR) as usize)
-            } as *mut VirtQueueDesc;
-        }
-    }
-    fn update_used_base(&mut self, paddr: u64) {
-        if paddr >= ram_config::BASE_ADDR {
-            self.used = unsafe {
-                self.ram_base_raw
-                    .add((paddr - ram_config::BASE_ADDR) as usize)
-            } as *mut VirtQueueUsed;
+
+    /// Check that the rings lie in RAM and map them, they are left unmapped otherwise.
+    fn map_rings(&mut self) -> Result<(), DmaError> {
+        self.desc = null_mut();
+        self.avail = null_mut();
+        self.used = null_mut();
+        if !self.is_configured() {
+            return Ok(());
         }
+
+        let num = self.queue_num as u64;
+        let desc = self.ram.ptr(
+            self.desc_paddr,
+            num * size_of::<VirtQueueDesc>() as u64,
+            DmaAccess::Read,
+        )?;
+        // Flags, index, the ring and the event index.
+        let avail = self
+            .ram
+            .ptr(self.avail_paddr, 6 + num * 2, DmaAccess::Read)?;
+        let used = self.ram.ptr(
+            self.used_paddr,
+            6 + num * size_of::<VirtQueueUsedElem>() as u64,
+            DmaAccess::Write,
+        )?;
+        self.desc = desc;
+        self.avail = avail;
+        self.used = used;
+        Ok(())
     }
 
     pub(super) fn get_used_ring(&self) -> &mut VirtQueueUsed {
@@ -351,18 +429,14 @@ impl VirtQueue {
     // }
 
     // will add `last_avail_idx`
-    fn try_get_desc(&mut self) -> Option<VirtQueueDescHandle<'_>> {
+    fn try_get_desc(&mut self) -> Result<Option<VirtQueueDescHandle<'_>>, DmaError> {
         let virt_queue_avail = unsafe { self.avail.as_ref().unwrap() };
         virt_queue_avail
             .try_get_desc_idx(self.queue_num, &mut self.last_avail_idx)
             .map(|idx| {
-                VirtQueueDescHandle::new(
-                    self.desc,
-                    self.ram_base_raw as usize,
-                    self.queue_num,
-                    idx as usize,
-                )
+                VirtQueueDescHandle::new(self.desc, self.ram.clone(), self.queue_num, idx as usize)
             })
+            .transpose()
     }
 
     fn insert_used(&mut self, elem: VirtQueueUsedElem) {
@@ -372,54 +446,131 @@ impl VirtQueue {
 
     /// # [`VirtQueue::manage_one_request<F>(&mut self, func: F)`]
     /// Manage a single request in the virtqueue.
-    /// Fn (desc: &VirtQueueDesc) -> u32
-    /// Input: The descriptor to manage.
+    /// Fn (desc: &VirtQueueDesc, idx: usize) -> Result<u32, DmaError>
+    /// Input: The descriptor to manage and its position in the chain.
     /// Output: The length of data processed in this descriptor.
-    pub(crate) fn manage_one_request<F>(&mut self, mut func: F) -> bool
+    ///
+    /// Returns whether a request was used. An error means the driver handed out memory outside
+    /// RAM or a broken chain, the device must not touch the queue until it is reset.
+    pub(crate) fn manage_one_request<F>(&mut self, mut func: F) -> Result<bool, DmaError>
     where
-        F: FnMut(&VirtQueueDesc, usize) -> u32,
+        F: FnMut(&VirtQueueDesc, usize) -> Result<u32, DmaError>,
     {
-        if (self.queue_num == 0)
-            || (self.desc.is_null())
-            || (self.avail.is_null())
-            || (self.used.is_null())
-        {
+        let mut len = 0;
+        let Some(id) = self.take_one_request(|desc, idx| {
+            len += func(desc, idx)?;
+            Ok(())
+        })?
+        else {
+            return Ok(false);
+        };
+
+        self.insert_used(VirtQueueUsedElem { id, len });
+        Ok(true)
+    }
+
+    /// Take a single request off the virtqueue without using it, `func` sees its descriptors
+    /// in chain order. The device passes the returned chain ID to [`VirtQueue::add_used`] once
+    /// it is done with the buffers.
+    ///
+    /// Errors like [`VirtQueue::manage_one_request`].
+    pub(crate) fn take_one_request<F>(&mut self, mut func: F) -> Result<Option<u32>, DmaError>
+    where
+        F: FnMut(&VirtQueueDesc, usize) -> Result<(), DmaError>,
+    {
+        if !self.is_configured() {
             error!("VirtQueue not ready to manage requests.");
-            return false;
+            return Ok(None);
         }
-        if let Some(mut handle) = self.try_get_desc() {
-            let entry_idx = handle.get_entry_idx();
-            let mut len = 0;
-            let mut idx = 0;
-
-            loop {
-                if let Some(desc) = handle.try_get() {
-                    len += func(desc, idx);
-                    idx += 1;
-                } else {
-                    break;
-                }
-            }
+        self.map_rings()?;
 
-            self.get_used_ring()
-                .insert_used(self.queue_num, VirtQueueUsedElem { id: entry_idx, len });
+        let Some(mut handle) = self.try_get_desc()? else {
+            return Ok(None);
+        };
+        let entry_idx = handle.get_entry_idx();
+        let mut idx = 0;
+        while let Some(desc) = handle.try_get()? {
+            func(desc, idx)?;
+            idx += 1;
+        }
+        Ok(Some(entry_idx))
+    }
 
-            true
-        } else {
-            false
+    /// Give the chain `id` of [`VirtQueue::take_one_request`] back to the driver, with `len`
+    /// bytes written to it. Nothing happens if the queue was torn down meanwhile.
+    pub(crate) fn add_used(&mut self, id: u32, len: u32) -> Result<(), DmaError> {
+        self.map_rings()?;
+        if !self.used.is_null() {
+            self.insert_used(VirtQueueUsedElem { id, len });
         }
+        Ok(())
     }
 
     pub(crate) fn set_used_ring_flag(&mut self, flag: VirtQueueUsedFlag) {
         self.get_used_ring().flags = flag;
     }
 
+    /// Number of available entries not consumed by the device yet.
+    pub(crate) fn pending(&self) -> u32 {
+        let Some(avail) = (unsafe { self.avail.as_ref() }) else {
+            return 0;
+        };
+        if self.queue_num == 0 {
+            return 0;
+        }
+        let head = avail.idx.load(std::sync::atomic::Ordering::Acquire);
+        head.wrapping_sub(self.last_avail_idx) as u32
+    }
+
+    pub(crate) fn set_event_idx(&mut self, event_idx: bool) {
+        self.event_idx = event_idx;
+    }
+
+    /// The index of the next used element, to be passed to [`VirtQueue::needs_interrupt`]
+    /// after a batch of requests.
+    pub(crate) fn used_idx(&self) -> u16 {
+        unsafe { self.used.as_ref() }.map_or(0, |used| {
+            used.idx.load(std::sync::atomic::Ordering::Relaxed)
+        })
+    }
+
+    /// Ask the driver to notify for the next request, only used with `event_idx`.
+    pub(crate) fn update_avail_event(&mut self) {
+        if self.event_idx && !self.used.is_null() && self.queue_num != 0 {
+            self.get_used_ring()
+                .set_avail_event(self.queue_num, self.last_avail_idx);
+        }
+    }
+
+    /// Whether the driver wants an interrupt for the elements used since `old_used_idx`.
+    pub(crate) fn needs_interrupt(&self, old_used_idx: u16) -> bool {
+        let new_used_idx = self.used_idx();
+        if new_used_idx == old_used_idx {
+            return false;
+        }
+        let Some(avail) = (unsafe { self.avail.as_ref() }) else {
+            return false;
+        };
+        if !self.event_idx {
+            return avail.flags == VirtQueueAvailFlag::Default;
+        }
+        let used_event = avail.used_event(self.queue_num);
+        // `vring_need_event`: was `used_event` passed by this batch?
+        new_used_idx.wrapping_sub(used_event).wrapping_sub(1)
+            < new_used_idx.wrapping_sub(old_used_idx)
+    }
+
     pub(crate) fn get_avail_flag(&self) -> VirtQueueAvailFlag {
         unsafe { self.avail.as_ref().unwrap().flags }
     }
 
     pub(super) fn set_queue_num(&mut self, num: u32) {
         self.queue_num = num;
+        let _ = self.map_rings();
+    }
+
+    pub(crate) fn ram(&self) -> GuestRam {
+        self.ram.clone()
     }
 
     pub(super) fn ready(&self) -> bool {
@@ -431,22 +582,21 @@ impl VirtQueue {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ram;
+    use crate::{ram, ram_config};
 
     #[test]
     fn test_virt_queue_avail_ring() {
         const QUEUE_NUM: usize = 8;
         const DESC_NUM: usize = 8;
         let mut ram = ram::Ram::new();
-        let ram_base = &mut ram[0] as *mut u8;
-        let mut virt_queue = VirtQueue::new(ram_base, QUEUE_NUM as u32);
+        let mut virt_queue = VirtQueue::new(GuestRam::new(&mut ram), QUEUE_NUM as u32);
 
         let virtq_desc_base = 0x8000_2000 as u64;
         let virtq_avail_base = 0x8000_2100 + ((QUEUE_NUM + 2) * size_of::<u16>()) as u64;
         let virtq_used_base = 0x8000_2200 + (QUEUE_NUM * size_of::<VirtQueueUsed>() + 4) as u64;
-        virt_queue.update_avail_base(virtq_avail_base);
-        virt_queue.update_desc_base(virtq_desc_base);
-        virt_queue.update_used_base(virtq_used_base);
+        virt_queue.set_avail(virtq_avail_base);
+        virt_queue.set_desc(virtq_desc_base);
+        virt_queue.set_used(virtq_used_base);
 
         // Description Table.
         let virt_queue_desc = unsafe {
@@ -485,6 +635,7 @@ mod tests {
 
         // Write Available Ring.
         avail_ring[0] = 0;
+        virtq_avail.idx_atomic_add(1);
 
         let desc0 = &mut virt_queue_desc[0];
         desc0.paddr = 0x8000_2300;
@@ -511,8 +662,10 @@ mod tests {
             .unwrap();
 
         // Test getting descriptors.
-        virt_queue.try_get_desc().map(|mut handle| {
-            let desc0_result = handle.try_get().unwrap();
+        let guest_ram = GuestRam::new(&mut ram);
+        let mut handle = virt_queue.try_get_desc().unwrap().unwrap();
+        {
+            let desc0_result = handle.try_get().unwrap().unwrap();
             assert_eq!(desc0_result.paddr, 0x8000_2300);
             assert_eq!(desc0_result.len, 0x10);
             assert!(
@@ -521,15 +674,10 @@ mod tests {
                     .contains(VirtQueueDescFlag::VIRTQ_DESC_F_NEXT)
             );
             assert_eq!(desc0_result.next, 1);
-            let buf0: &[usize] = unsafe {
-                slice::from_raw_parts_mut(
-                    desc0_result.get_request_package(ram_base as usize),
-                    desc0_result.len as usize / std::mem::size_of::<usize>(),
-                )
-            };
+            let buf0 = desc0_result.request::<[usize; 2]>(&guest_ram).unwrap();
             assert_eq!(buf0[0], 114514);
 
-            let desc1_result = handle.try_get().unwrap();
+            let desc1_result = handle.try_get().unwrap().unwrap();
             assert_eq!(desc1_result.paddr, 0x8000_2310);
             assert_eq!(desc1_result.len, 0x10);
             assert!(
@@ -538,29 +686,19 @@ mod tests {
                     .contains(VirtQueueDescFlag::VIRTQ_DESC_F_NEXT)
             );
             assert_eq!(desc1_result.next, 2);
-            let buf1: &[usize] = unsafe {
-                slice::from_raw_parts_mut(
-                    desc1_result.get_request_package(ram_base as usize),
-                    desc1_result.len as usize / std::mem::size_of::<usize>(),
-                )
-            };
+            let buf1 = desc1_result.request::<[usize; 2]>(&guest_ram).unwrap();
             assert_eq!(buf1[0], 0721);
 
-            let desc2_result = handle.try_get().unwrap();
+            let desc2_result = handle.try_get().unwrap().unwrap();
             assert_eq!(desc2_result.paddr, 0x8000_2320);
             assert_eq!(desc2_result.len, 0x10);
             assert!(desc2_result.flags.contains(VirtQueueDescFlag::empty()));
             assert_eq!(desc2_result.next, 3);
-            let buf2: &[usize] = unsafe {
-                slice::from_raw_parts_mut(
-                    desc2_result.get_request_package(ram_base as usize),
-                    desc2_result.len as usize / std::mem::size_of::<usize>(),
-                )
-            };
+            let buf2 = desc2_result.request::<[usize; 2]>(&guest_ram).unwrap();
             assert_eq!(buf2[0], 998244353);
 
-            assert!(handle.try_get().is_none());
-        });
+            assert!(handle.try_get().unwrap().is_none());
+        }
     }
 
     #[test]
@@ -568,8 +706,7 @@ mod tests {
         const QUEUE_NUM: usize = 8;
         const DESC_NUM: usize = 8;
         let mut ram = ram::Ram::new();
-        let ram_base = &mut ram[0] as *mut u8;
-        let mut virt_queue = VirtQueue::new(ram_base, QUEUE_NUM as u32);
+        let mut virt_queue = VirtQueue::new(GuestRam::new(&mut ram), QUEUE_NUM as u32);
 
         let virtq_desc_base = 0x8000_2000 as u64;
         let virtq_avail_base = 0x8000_2100 + ((QUEUE_NUM + 2) * size_of::<u16>()) as u64;
@@ -577,9 +714,6 @@ mod tests {
         virt_queue.set_avail(virtq_avail_base);
         virt_queue.set_desc(virtq_desc_base);
         virt_queue.set_used(virtq_used_base);
-        // virt_queue.update_avail_base(virtq_avail_base);
-        // virt_queue.update_desc_base(virtq_desc_base);
-        // virt_queue.update_used_base(virtq_used_base);
 
         // Description Table.
         let virt_queue_desc = unsafe {
@@ -622,23 +756,21 @@ mod tests {
             .unwrap();
 
         // Test getting descriptors.
-        virt_queue.manage_one_request(|desc, _| {
-            let buf: &mut [u32] = unsafe {
-                slice::from_raw_parts_mut(
-                    desc.get_request_package(ram_base as usize),
-                    desc.len as usize / std::mem::size_of::<u32>(),
-                )
-            };
-            assert_eq!(buf[0], 114514);
-            assert_eq!(buf.len(), 0x10 / size_of::<u32>());
-
-            // Write Used Ring.
-            buf[0] = 0x0123;
-            buf[1] = 0x4567;
-            buf[2] = 0x89ab;
-            buf[3] = 0xcdef;
-            0x10
-        });
+        let guest_ram = GuestRam::new(&mut ram);
+        virt_queue
+            .manage_one_request(|desc, _| {
+                let buf = desc.request::<[u32; 4]>(&guest_ram)?;
+                assert_eq!(buf[0], 114514);
+                assert_eq!(buf.len(), 0x10 / size_of::<u32>());
+
+                // Write Used Ring.
+                buf[0] = 0x0123;
+                buf[1] = 0x4567;
+                buf[2] = 0x89ab;
+                buf[3] = 0xcdef;
+                Ok(0x10)
+            })
+            .unwrap();
 
         assert_eq!(virtq_used.idx.load(std::sync::atomic::Ordering::Relaxed), 1);
         assert_eq!(
@@ -662,4 +794,50 @@ mod tests {
             0xcdef
         );
     }
+
+    #[test]
+    fn test_event_idx() {
+        const QUEUE_NUM: u32 = 4;
+        const DESC_BASE: u64 = 0x8000_2000;
+        const AVAIL_BASE: u64 = 0x8000_2100;
+        const USED_BASE: u64 = 0x8000_2200;
+        let mut ram = ram::Ram::new();
+        let mut virt_queue = VirtQueue::new(GuestRam::new(&mut ram), QUEUE_NUM);
+        virt_queue.set_desc(DESC_BASE);
+        virt_queue.set_avail(AVAIL_BASE);
+        virt_queue.set_used(USED_BASE);
+        virt_queue.set_event_idx(true);
+
+        let offset = |paddr: u64| paddr - ram_config::BASE_ADDR;
+        let used_event = AVAIL_BASE + 4 + 2 * QUEUE_NUM as u64;
+        let avail_event = USED_BASE + 4 + 8 * QUEUE_NUM as u64;
+        for i in 0..QUEUE_NUM as u64 {
+            ram.write::<u64>(offset(DESC_BASE + 16 * i), 0x8000_3000)
+                .unwrap();
+            ram.write::<u32>(offset(DESC_BASE + 16 * i + 8), 0x10)
+                .unwrap();
+            ram.write::<u16>(offset(AVAIL_BASE + 4 + 2 * i), i as u16)
+                .unwrap();
+        }
+
+        // Six requests, the indexes go past the size of the ring.
+        let mut avail_idx = 0u16;
+        for _ in 0..6 {
+            avail_idx += 1;
+            ram.write::<u16>(offset(AVAIL_BASE + 2), avail_idx).unwrap();
+            ram.write::<u16>(offset(used_event), 2).unwrap();
+
+            let old_used_idx = virt_queue.used_idx();
+            assert!(virt_queue.manage_one_request(|_, _| Ok(0x10)).unwrap());
+            virt_queue.update_avail_event();
+            assert_eq!(virt_queue.pending(), 0);
+            assert_eq!(ram.read::<u16>(offset(avail_event)).unwrap(), avail_idx);
+            // Only the request passing `used_event` interrupts.
+            assert_eq!(virt_queue.needs_interrupt(old_used_idx), avail_idx == 3);
+        }
+        assert_eq!(virt_queue.used_idx(), 6);
+        // The fifth element wrapped around to the first slot.
+        assert_eq!(ram.read::<u32>(offset(USED_BASE + 4)).unwrap(), 0);
+        assert!(!virt_queue.manage_one_request(|_, _| Ok(0x10)).unwrap());
+    }
 }
@@ -0,0 +1,249 @@
+//! Watchdog timer: once enabled, the guest has to kick it before the timeout elapses, otherwise
+//! the board is reset or halted, as chosen by [`WatchdogAction`].
+//!
+//! All registers are 32-bit:
+//!
+//! | Offset | Name    | Description                                                            |
+//! |--------|---------|------------------------------------------------------------------------|
+//! | 0x00   | CTRL    | Bit 0 enables the watchdog, enabling it starts a new period            |
+//! | 0x04   | TIMEOUT | Period in cycles of the virtual clock                                  |
+//! | 0x08   | KICK    | Writing [`WATCHDOG_KICK_KEY`] starts a new period, other values are ignored |
+//! | 0x0c   | COUNT   | Cycles left in the current period, 0 while disabled (read-only)        |
+//! | 0x10   | STATUS  | Bit 0 is set if the last reset was caused by the watchdog (write 1 to clear) |
+
+use std::{
+    cell::{Cell, UnsafeCell},
+    rc::Rc,
+    str::FromStr,
+};
+
+use crate::{
+    board::{BoardControl, BoardRequest},
+    config::arch_config::WordType,
+    device::{
+        AccessWidths, DeviceTrait, MemError, MemMappedDeviceTrait,
+        config::{WATCHDOG_BASE, WATCHDOG_NAME, WATCHDOG_SIZE},
+    },
+    device_poller::PollingEventTrait,
+    vclock::{Timer, VirtualClockRef},
+};
+
+/// Value to write to `KICK`, "KICK" in ASCII.
+pub const WATCHDOG_KICK_KEY: u32 = 0x4b49_434b;
+
+const CTRL: WordType = 0x00;
+const TIMEOUT: WordType = 0x04;
+const KICK: WordType = 0x08;
+const COUNT: WordType = 0x0c;
+const STATUS: WordType = 0x10;
+
+const CTRL_ENABLE: u32 = 1 << 0;
+const STATUS_WATCHDOG_RESET: u32 = 1 << 0;
+
+const DEFAULT_TIMEOUT: u32 = 0x1000_0000;
+
+/// What happens when the watchdog expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogAction {
+    /// Reset the board, the guest boots again from the reset vector.
+    Reset,
+    /// Stop the emulator and report where the guest got stuck.
+    Halt,
+}
+
+impl FromStr for WatchdogAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reset" => Ok(Self::Reset),
+            "halt" => Ok(Self::Halt),
+            other => Err(format!("Unknown watchdog action: {}", other)),
+        }
+    }
+}
+
+pub struct Watchdog {
+    clock: VirtualClockRef,
+    timer: Rc<UnsafeCell<Timer>>,
+    timer_cb_id: u64,
+
+    enabled: bool,
+    timeout: u32,
+    /// Clock time the current period ends at.
+    deadline: u64,
+    /// Set by the expiry callback, survives the reset it causes.
+    expired: Rc<Cell<bool>>,
+}
+
+impl Watchdog {
+    pub fn new(
+        action: WatchdogAction,
+        clock: VirtualClockRef,
+        timer: Rc<UnsafeCell<Timer>>,
+        control: BoardControl,
+    ) -> Self {
+        let expired = Rc::new(Cell::new(false));
+        let timer_cb_id = unsafe { timer.as_mut_unchecked() }.register({
+            let expired = expired.clone();
+            move || {
+                expired.set(true);
+                control.request(match action {
+                    WatchdogAction::Reset => BoardRequest::Reset,
+                    WatchdogAction::Halt => BoardRequest::Halt("watchdog expired".into()),
+                });
+            }
+        });
+
+        Self {
+            clock,
+            timer,
+            timer_cb_id,
+            enabled: false,
+            timeout: DEFAULT_TIMEOUT,
+            deadline: 0,
+            expired,
+        }
+    }
+
+    /// Start a new period, or cancel the expiry if disabled.
+    fn reload(&mut self) {
+        let timer = unsafe { self.timer.as_mut_unchecked() };
+        if self.enabled {
+            self.deadline = self.clock.now().saturating_add(self.timeout as u64);
+            timer.set_due(self.timer_cb_id, self.deadline);
+        } else {
+            timer.set_due(self.timer_cb_id, u64::MAX);
+        }
+    }
+
+    fn read_impl<T>(&mut self, addr: WordType) -> Result<T, MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let value = match addr {
+            CTRL => {
+                if self.enabled {
+                    CTRL_ENABLE
+                } else {
+                    0
+                }
+            }
+            TIMEOUT => self.timeout,
+            KICK => 0,
+            COUNT if self.enabled => self.deadline.saturating_sub(self.clock.now()) as u32,
+            COUNT => 0,
+            STATUS => {
+                if self.expired.get() {
+                    STATUS_WATCHDOG_RESET
+                } else {
+                    0
+                }
+            }
+            _ => return Err(MemError::LoadFault),
+        };
+        Ok(T::truncate_from(value))
+    }
+
+    fn write_impl<T>(&mut self, addr: WordType, data: T) -> Result<(), MemError>
+    where
+        T: crate::utils::UnsignedInteger,
+    {
+        let data: u32 = data.truncate_to();
+        match addr {
+            CTRL => {
+                let enabled = data & CTRL_ENABLE != 0;
+                if enabled != self.enabled {
+                    self.enabled = enabled;
+                    self.reload();
+                }
+            }
+            TIMEOUT => {
+                self.timeout = data;
+                self.reload();
+            }
+            KICK if data == WATCHDOG_KICK_KEY => self.reload(),
+            KICK | COUNT => {}
+            STATUS => {
+                if data & STATUS_WATCHDOG_RESET != 0 {
+                    self.expired.set(false);
+                }
+            }
+            _ => return Err(MemError::StoreFault),
+        }
+        Ok(())
+    }
+}
+
+impl DeviceTrait for Watchdog {
+    dispatch_read_write! { read_impl, write_impl }
+
+    fn sync(&mut self) {}
+    fn get_poll_event(&mut self) -> Option<Box<dyn PollingEventTrait>> {
+        None
+    }
+
+    fn reset(&mut self) {
+        self.enabled = false;
+        self.timeout = DEFAULT_TIMEOUT;
+        self.reload();
+    }
+}
+
+impl MemMappedDeviceTrait for Watchdog {
+    fn name() -> &'static str {
+        WATCHDOG_NAME
+    }
+    fn base() -> WordType {
+        WATCHDOG_BASE
+    }
+    fn size() -> WordType {
+        WATCHDOG_SIZE
+    }
+    fn access_widths() -> AccessWidths {
+        AccessWidths::of(&[4])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_expiry() {
+        let clock = VirtualClockRef::new();
+        let timer = Rc::new(UnsafeCell::new(Timer::new(clock.clone())));
+        let control = BoardControl::default();
+        let mut watchdog = Watchdog::new(
+            WatchdogAction::Reset,
+            clock.clone(),
+            timer.clone(),
+            control.clone(),
+        );
+        let advance = |cycles| {
+            clock.advance(cycles);
+            unsafe { timer.as_mut_unchecked() }.tick();
+        };
+
+        watchdog.write_u32(TIMEOUT, 100).unwrap();
+        advance(1000);
+        assert_eq!(control.take(), None);
+
+        watchdog.write_u32(CTRL, CTRL_ENABLE).unwrap();
+        advance(60);
+        assert_eq!(watchdog.read_u32(COUNT).unwrap(), 40);
+        watchdog.write_u32(KICK, WATCHDOG_KICK_KEY).unwrap();
+        advance(60);
+        // A wrong key is no kick.
+        watchdog.write_u32(KICK, 0).unwrap();
+        assert_eq!(control.take(), None);
+        advance(40);
+        assert_eq!(control.take(), Some(BoardRequest::Reset));
+
+        watchdog.reset();
+        assert_eq!(watchdog.read_u32(CTRL).unwrap(), 0);
+        assert_eq!(watchdog.read_u32(STATUS).unwrap(), STATUS_WATCHDOG_RESET);
+        watchdog.write_u32(STATUS, STATUS_WATCHDOG_RESET).unwrap();
+        assert_eq!(watchdog.read_u32(STATUS).unwrap(), 0);
+    }
+}
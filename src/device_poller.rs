@@ -1,4 +1,4 @@
-use crate::device::plic::ExternalInterrupt;
+use crate::{device::plic::ExternalInterrupt, doorbell::Doorbell};
 use crossbeam::channel::{Receiver, Sender};
 
 #[cfg(feature = "riscv64")]
@@ -34,8 +34,15 @@ impl<F: PollingFn + Send> PollingEventTrait for PollingFnWrapper<F> {
     }
 }
 
+/// Identifies an event added to a [`DevicePoller`], see [`DevicePoller::remove_event`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollEventId(usize);
+
+type PollEvents = Arc<Mutex<Vec<(PollEventId, Box<dyn PollingEventTrait>)>>>;
+
 struct PollerCore {
-    events: Arc<Mutex<Vec<Box<dyn PollingEventTrait>>>>,
+    events: PollEvents,
+    next_id: usize,
 
     #[cfg(feature = "riscv64")]
     plic_irq_line: Option<PlicIRQLine>,
@@ -45,21 +52,30 @@ impl PollerCore {
     fn new() -> Self {
         Self {
             events: Arc::new(Mutex::new(Vec::new())),
+            next_id: 0,
             #[cfg(feature = "riscv64")]
             plic_irq_line: None,
         }
     }
 
-    fn add_event(&mut self, event: Box<dyn PollingEventTrait>) {
-        self.events.lock().unwrap().push(event);
+    fn add_event(&mut self, event: Box<dyn PollingEventTrait>) -> PollEventId {
+        let id = PollEventId(self.next_id);
+        self.next_id += 1;
+        self.events.lock().unwrap().push((id, event));
+        id
+    }
+
+    fn remove_event(&mut self, id: PollEventId) -> bool {
+        let mut events = self.events.lock().unwrap();
+        let len = events.len();
+        events.retain(|(event_id, _)| *event_id != id);
+        events.len() != len
     }
 
-    fn poll_once_collect(
-        events: &Arc<Mutex<Vec<Box<dyn PollingEventTrait>>>>,
-    ) -> Vec<ExternalInterrupt> {
+    fn poll_once_collect(events: &PollEvents) -> Vec<ExternalInterrupt> {
         let mut pending = Vec::new();
         let mut guard = events.lock().unwrap();
-        for event in guard.iter_mut() {
+        for (_, event) in guard.iter_mut() {
             if let Some(id) = event.poll_nonblocking() {
                 pending.push(id);
             }
@@ -92,28 +108,42 @@ impl PollerCore {
 /// - With `multithreading`, the executor runs the task on its worker thread and the interrupts
 ///   arrive over the channel asynchronously.
 /// - Without it, the executor runs the task inline on `poll_once`, just before the drain.
+///
+/// The task rings the board's [`Doorbell`] after sending, so the main thread only drains the
+/// channel once the doorbell was rung.
 pub struct DevicePoller {
     core: PollerCore,
 
     /// Sent from the polling task (any thread), received on the main thread.
     irq_sender: Sender<ExternalInterrupt>,
     irq_receiver: Receiver<ExternalInterrupt>,
+
+    /// Rung once the polling task sent interrupts.
+    doorbell: Doorbell,
 }
 
 impl DevicePoller {
     pub fn new(
         plic_irq_tx: Sender<ExternalInterrupt>,
         plic_irq_rx: Receiver<ExternalInterrupt>,
+        doorbell: Doorbell,
     ) -> Self {
         Self {
             core: PollerCore::new(),
             irq_sender: plic_irq_tx,
             irq_receiver: plic_irq_rx,
+            doorbell,
         }
     }
 
-    pub fn add_event(&mut self, event: Box<dyn PollingEventTrait>) {
-        self.core.add_event(event);
+    pub fn add_event(&mut self, event: Box<dyn PollingEventTrait>) -> PollEventId {
+        self.core.add_event(event)
+    }
+
+    /// Stop polling an event, e.g. of an unplugged device. Returns `false` if it's already
+    /// removed.
+    pub fn remove_event(&mut self, id: PollEventId) -> bool {
+        self.core.remove_event(id)
     }
 
     /// Build the polling task to register on a
@@ -123,19 +153,22 @@ impl DevicePoller {
     pub fn poll_task(&self) -> impl FnMut() -> bool + Send + 'static {
         let events = self.core.events.clone();
         let sender = self.irq_sender.clone();
+        let doorbell = self.doorbell.clone();
         move || {
             let pending = PollerCore::poll_once_collect(&events);
             let triggered = !pending.is_empty();
             for id in pending {
                 let _ = sender.send(id);
             }
+            if triggered {
+                doorbell.ring();
+            }
             triggered
         }
     }
 
     /// Drain the interrupts produced by the polling task and dispatch them to the PLIC. Call on the
-    /// main thread after
-    /// [`BackgroundExecutor::poll_once`](crate::background::BackgroundExecutor::poll_once).
+    /// main thread once the doorbell was rung.
     pub fn trigger_external_interrupt(&mut self) {
         while let Ok(_id) = self.irq_receiver.try_recv() {
             #[cfg(feature = "riscv64")]
@@ -150,3 +183,42 @@ impl PlicIRQSource for DevicePoller {
         self.core.set_irq_line(line);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll_task_doorbell() {
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let doorbell = Doorbell::new();
+        let mut poller = DevicePoller::new(tx, rx, doorbell.clone());
+        let mut fired = false;
+        poller.add_event(Box::new(PollingFnWrapper::new(move || {
+            (!std::mem::replace(&mut fired, true)).then_some(3)
+        })));
+
+        let mut task = poller.poll_task();
+        assert!(task());
+        assert!(doorbell.take());
+        poller.trigger_external_interrupt();
+        assert!(poller.irq_receiver.is_empty());
+
+        // Nothing fired, the doorbell stays quiet.
+        assert!(!task());
+        assert!(!doorbell.take());
+    }
+
+    #[test]
+    fn test_remove_event() {
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let mut poller = DevicePoller::new(tx, rx, Doorbell::new());
+        let id = poller.add_event(Box::new(PollingFnWrapper::new(|| Some(3))));
+        let mut task = poller.poll_task();
+        assert!(task());
+
+        assert!(poller.remove_event(id));
+        assert!(!poller.remove_event(id));
+        assert!(!task());
+    }
+}
@@ -0,0 +1,58 @@
+//! Wakes the vCPU up to service the devices.
+//!
+//! The device side runs apart from the instruction loop: the background executor, the host event
+//! loop and the work queue workers have threads of their own. They hand their results over
+//! lock-free queues (the crossbeam channels and the atomics of the [`IrqPin`]s), then ring the
+//! board's [`Doorbell`]. Between instructions the board only loads the doorbell, and syncs the
+//! PLIC with the devices once it was rung.
+//!
+//! Ringing is a release store and [`Doorbell::take`] an acquire swap, so whatever was pushed before
+//! ringing is visible to the vCPU when it services the devices. Things rung on the vCPU itself,
+//! such as PLIC register writes, ring the same doorbell and are serviced before the next
+//! instruction.
+//!
+//! [`IrqPin`]: crate::device::plic::irq_line::IrqPin
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+#[derive(Clone, Default)]
+pub struct Doorbell(Arc<AtomicBool>);
+
+impl Doorbell {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the board to service the devices before the next instruction. Any thread.
+    pub fn ring(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Whether it was rung since the last call.
+    ///
+    /// The relaxed load keeps the flag in the cache of the vCPU while nothing happens. Rings after
+    /// the swap are seen by the next call.
+    #[inline]
+    pub fn take(&self) -> bool {
+        self.0.load(Ordering::Relaxed) && self.0.swap(false, Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_doorbell() {
+        let doorbell = Doorbell::new();
+        assert!(!doorbell.take());
+
+        let other = doorbell.clone();
+        std::thread::spawn(move || other.ring()).join().unwrap();
+        assert!(doorbell.take());
+        assert!(!doorbell.take());
+    }
+}
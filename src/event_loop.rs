@@ -0,0 +1,444 @@
+//! Host event loop for device backends.
+//!
+//! Backends such as sockets, TAP devices or pipes register their file descriptor together with a
+//! callback, which is invoked once the descriptor becomes ready, instead of checking the
+//! descriptor on every device tick. Periodic timers are supported too, and devices without a
+//! descriptor wake the loop up with a [`Notifier`].
+//!
+//! [`EventLoop::spawn`] runs the loop on its own thread, which sleeps in the poller until a
+//! descriptor is ready, a notifier fires or the next timer is due. In deterministic mode register
+//! [`EventLoop::into_task`] on the inline
+//! [`BackgroundExecutor`](crate::background::BackgroundExecutor) instead.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use polling::{AsSource, Event, Events, PollMode, Poller};
+
+use crate::vclock::DeviceInstant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interest {
+    Readable,
+    Writable,
+    Both,
+}
+
+impl Interest {
+    fn event(self, key: usize) -> Event {
+        match self {
+            Interest::Readable => Event::readable(key),
+            Interest::Writable => Event::writable(key),
+            Interest::Both => Event::all(key),
+        }
+    }
+}
+
+/// Readiness reported to an I/O callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Readiness {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+/// Identifies a registered source or timer, see [`EventLoop::remove`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Token(usize);
+
+type IoCallback = Box<dyn FnMut(Readiness) -> bool + Send>;
+type TimerCallback = Box<dyn FnMut() + Send>;
+type NotifyCallback = Box<dyn FnMut() + Send>;
+
+struct IoSource {
+    source: Box<dyn AsSource + Send>,
+    callback: IoCallback,
+}
+
+struct Timer {
+    period: Duration,
+    last_fire: DeviceInstant,
+    callback: TimerCallback,
+}
+
+impl Timer {
+    fn remaining(&self, now: DeviceInstant) -> Duration {
+        self.period
+            .saturating_sub(now.duration_since(self.last_fire))
+    }
+}
+
+struct NotifySource {
+    pending: Arc<AtomicBool>,
+    callback: NotifyCallback,
+}
+
+/// Runs the callback of [`EventLoop::add_notifier`] on the loop, from any thread.
+#[derive(Clone)]
+pub struct Notifier {
+    pending: Arc<AtomicBool>,
+    poller: Arc<Poller>,
+}
+
+impl Notifier {
+    /// Run the callback on the next poll. Notifications are merged until the callback runs, so
+    /// only the first one wakes up the loop.
+    pub fn notify(&self) {
+        if !self.pending.swap(true, Ordering::AcqRel)
+            && let Err(err) = self.poller.notify()
+        {
+            log::warn!("failed to wake event loop: {err}");
+        }
+    }
+}
+
+/// Wakes up an [`EventLoop`] blocked in [`EventLoop::poll`] from another thread.
+#[derive(Clone)]
+pub struct EventLoopWaker {
+    poller: Arc<Poller>,
+}
+
+impl EventLoopWaker {
+    pub fn wake(&self) {
+        if let Err(err) = self.poller.notify() {
+            log::warn!("failed to wake event loop: {err}");
+        }
+    }
+}
+
+pub struct EventLoop {
+    poller: Arc<Poller>,
+    events: Events,
+
+    sources: HashMap<usize, IoSource>,
+    timers: HashMap<usize, Timer>,
+    notifiers: HashMap<usize, NotifySource>,
+    next_key: usize,
+}
+
+impl EventLoop {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            poller: Arc::new(Poller::new()?),
+            events: Events::new(),
+            sources: HashMap::new(),
+            timers: HashMap::new(),
+            notifiers: HashMap::new(),
+            next_key: 0,
+        })
+    }
+
+    fn alloc_key(&mut self) -> usize {
+        let key = self.next_key;
+        self.next_key += 1;
+        key
+    }
+
+    /// Call `callback` whenever `source` is ready for `interest`. The source is level-triggered,
+    /// so the callback is invoked again on the next poll until the readiness is consumed. The
+    /// callback returns `false` to unregister the source, e.g. once the peer hung up.
+    pub fn register<S>(
+        &mut self,
+        source: S,
+        interest: Interest,
+        callback: impl FnMut(Readiness) -> bool + Send + 'static,
+    ) -> io::Result<Token>
+    where
+        S: AsSource + Send + 'static,
+    {
+        let key = self.alloc_key();
+        // SAFETY: the source is owned by the loop and deleted from the poller before it's dropped.
+        unsafe {
+            self.poller
+                .add_with_mode(&source.source(), interest.event(key), PollMode::Level)?;
+        }
+        self.sources.insert(
+            key,
+            IoSource {
+                source: Box::new(source),
+                callback: Box::new(callback),
+            },
+        );
+        Ok(Token(key))
+    }
+
+    /// Call `callback` every `period`, measured in guest time in deterministic mode.
+    pub fn add_timer(
+        &mut self,
+        period: Duration,
+        callback: impl FnMut() + Send + 'static,
+    ) -> Token {
+        let key = self.alloc_key();
+        self.timers.insert(
+            key,
+            Timer {
+                period,
+                last_fire: DeviceInstant::now(),
+                callback: Box::new(callback),
+            },
+        );
+        Token(key)
+    }
+
+    /// Call `callback` on the loop after [`Notifier::notify`], for backends without a file
+    /// descriptor, e.g. to forward what a device queued.
+    pub fn add_notifier(&mut self, callback: impl FnMut() + Send + 'static) -> (Token, Notifier) {
+        let key = self.alloc_key();
+        let pending = Arc::new(AtomicBool::new(false));
+        self.notifiers.insert(
+            key,
+            NotifySource {
+                pending: pending.clone(),
+                callback: Box::new(callback),
+            },
+        );
+        let notifier = Notifier {
+            pending,
+            poller: self.poller.clone(),
+        };
+        (Token(key), notifier)
+    }
+
+    /// Unregister a source, timer or notifier, returns `false` if it's already removed.
+    pub fn remove(&mut self, token: Token) -> bool {
+        if let Some(io) = self.sources.remove(&token.0) {
+            if let Err(err) = self.poller.delete(io.source.source()) {
+                log::warn!("failed to unregister event source: {err}");
+            }
+            true
+        } else {
+            self.timers.remove(&token.0).is_some() || self.notifiers.remove(&token.0).is_some()
+        }
+    }
+
+    pub fn waker(&self) -> EventLoopWaker {
+        EventLoopWaker {
+            poller: self.poller.clone(),
+        }
+    }
+
+    /// Wait up to `timeout` (forever if `None`) for a source, timer or notifier to fire and run
+    /// the callbacks. Returns whether any callback was run.
+    pub fn poll(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        let now = DeviceInstant::now();
+        let next_timer = self.timers.values().map(|t| t.remaining(now)).min();
+        let timeout = match (timeout, next_timer) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        self.events.clear();
+        self.poller.wait(&mut self.events, timeout)?;
+
+        let mut fired = false;
+        let mut done = Vec::new();
+        for event in self.events.iter() {
+            if let Some(io) = self.sources.get_mut(&event.key) {
+                let keep = (io.callback)(Readiness {
+                    readable: event.readable,
+                    writable: event.writable,
+                });
+                if !keep {
+                    done.push(Token(event.key));
+                }
+                fired = true;
+            }
+        }
+        for token in done {
+            self.remove(token);
+        }
+
+        for notifier in self.notifiers.values_mut() {
+            if notifier.pending.swap(false, Ordering::AcqRel) {
+                (notifier.callback)();
+                fired = true;
+            }
+        }
+
+        let now = DeviceInstant::now();
+        for timer in self.timers.values_mut() {
+            if timer.remaining(now).is_zero() {
+                timer.last_fire = now;
+                (timer.callback)();
+                fired = true;
+            }
+        }
+
+        Ok(fired)
+    }
+
+    /// Run the loop on a new thread, blocked in the poller while nothing is ready. The thread
+    /// stops once the returned handle is dropped.
+    pub fn spawn(mut self) -> io::Result<EventLoopThread> {
+        let running = Arc::new(AtomicBool::new(true));
+        let waker = self.waker();
+        let handle = std::thread::Builder::new()
+            .name("event-loop".into())
+            .spawn({
+                let running = running.clone();
+                move || {
+                    while running.load(Ordering::Acquire) {
+                        if let Err(err) = self.poll(None) {
+                            log::error!("event loop poll failed: {err}");
+                            break;
+                        }
+                    }
+                }
+            })?;
+        Ok(EventLoopThread {
+            running,
+            waker,
+            handle: Some(handle),
+        })
+    }
+
+    /// Build the task to register on a [`BackgroundExecutor`](crate::background::BackgroundExecutor)
+    /// started inline, which polls the loop without blocking at well defined points.
+    pub fn into_task(mut self) -> impl FnMut() -> bool + Send + 'static {
+        move || {
+            self.poll(Some(Duration::ZERO)).unwrap_or_else(|err| {
+                log::error!("event loop poll failed: {err}");
+                false
+            })
+        }
+    }
+}
+
+/// The thread of [`EventLoop::spawn`].
+pub struct EventLoopThread {
+    running: Arc<AtomicBool>,
+    waker: EventLoopWaker,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for EventLoopThread {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        self.waker.wake();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for EventLoop {
+    fn drop(&mut self) {
+        for io in self.sources.values() {
+            let _ = self.poller.delete(io.source.source());
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+        sync::{
+            Mutex,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_io_source() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        rx.set_nonblocking(true).unwrap();
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut reader = rx.try_clone().unwrap();
+        let sink = received.clone();
+        let token = event_loop
+            .register(rx, Interest::Readable, move |readiness| {
+                assert!(readiness.readable);
+                let mut buf = [0u8; 16];
+                while let Ok(n @ 1..) = reader.read(&mut buf) {
+                    sink.lock().unwrap().extend_from_slice(&buf[..n]);
+                }
+                true
+            })
+            .unwrap();
+
+        assert!(!event_loop.poll(Some(Duration::ZERO)).unwrap());
+
+        tx.write_all(b"hello").unwrap();
+        assert!(event_loop.poll(Some(Duration::from_secs(1))).unwrap());
+        assert_eq!(received.lock().unwrap().as_slice(), b"hello");
+        // Readiness was consumed.
+        assert!(!event_loop.poll(Some(Duration::ZERO)).unwrap());
+
+        // Removing drops the source together with the callback.
+        assert!(event_loop.remove(token));
+        assert!(!event_loop.remove(token));
+        assert!(tx.write_all(b"!").is_err());
+        assert!(!event_loop.poll(Some(Duration::ZERO)).unwrap());
+    }
+
+    #[test]
+    fn test_timer_and_waker() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        event_loop.add_timer(Duration::from_millis(5), move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        // Blocks until the timer is due.
+        while !event_loop.poll(None).unwrap() {}
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        let waker = event_loop.waker();
+        let handle = std::thread::spawn(move || waker.wake());
+        event_loop.poll(Some(Duration::from_secs(1))).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_notifier_on_thread() {
+        let mut event_loop = EventLoop::new().unwrap();
+        let (tx, rx) = crossbeam::channel::unbounded();
+
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+        let mut stream = reader.try_clone().unwrap();
+        let closed = tx.clone();
+        event_loop
+            .register(reader, Interest::Readable, move |_| {
+                let mut buf = [0u8; 16];
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => {
+                        closed.send("closed").unwrap();
+                        false
+                    }
+                    Ok(_) => true,
+                }
+            })
+            .unwrap();
+        let (_, notifier) = event_loop.add_notifier(move || tx.send("notified").unwrap());
+
+        let thread = event_loop.spawn().unwrap();
+        let timeout = Duration::from_secs(5);
+        notifier.notify();
+        assert_eq!(rx.recv_timeout(timeout), Ok("notified"));
+
+        // The source is unregistered at end of file, instead of firing on every poll.
+        writer.write_all(b"x").unwrap();
+        drop(writer);
+        assert_eq!(rx.recv_timeout(timeout), Ok("closed"));
+        notifier.notify();
+        assert_eq!(rx.recv_timeout(timeout), Ok("notified"));
+        assert!(rx.is_empty());
+
+        // Stops the blocked thread.
+        drop(thread);
+    }
+}
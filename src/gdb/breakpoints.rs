@@ -15,7 +15,7 @@ impl<'a, B: Board> ext::breakpoints::SwBreakpoint for GdbDebugger<'a, B> {
         addr: <Self::Arch as gdbstub::arch::Arch>::Usize,
         _kind: <Self::Arch as gdbstub::arch::Arch>::BreakpointKind,
     ) -> gdbstub::target::TargetResult<bool, Self> {
-        match self.dbg.set_breakpoint(Address::Virt(addr)) {
+        match self.dbg.set_breakpoint(Address::Virt(addr), None) {
             Ok(is_set) => Ok(is_set),
             Err(_) => Ok(false),
         }
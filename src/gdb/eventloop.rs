@@ -72,6 +72,7 @@ impl<'a, B: Board> run_blocking::BlockingEventLoop for EmuGdbEventLoop<'a, B> {
                     DebugEvent::StepCompleted => SingleThreadStopReason::DoneStep,
                     DebugEvent::BoardHalted => SingleThreadStopReason::Terminated(Signal::SIGSTOP),
                     DebugEvent::BreakpointHit => SingleThreadStopReason::SwBreak(()),
+                    DebugEvent::BoardBreak(_) => SingleThreadStopReason::Signal(Signal::SIGTRAP),
                 };
 
                 run_blocking::Event::TargetStopped(stop_reason)
@@ -115,6 +115,51 @@ impl<T: Cacheable, const S: usize, const W: usize> SetCache<T, S, W> {
     fn set_index_of(addr: WordType) -> usize {
         (T::index_of(addr)) & (S - 1)
     }
+
+    /// Like [`Cache::get`], for caches holding several entries per address told apart by a tag
+    /// in the data, e.g. the ASID of a translation.
+    #[inline]
+    pub(super) fn get_matching(&self, addr: WordType, matches: impl Fn(&T) -> bool) -> Option<T> {
+        let set = &self.cache[Self::set_index_of(addr)];
+        set.source_addr
+            .iter()
+            .zip(set.data.iter())
+            .find_map(|(&item, data)| data.filter(|data| item == addr && matches(data)))
+    }
+
+    /// Like [`Cache::invalidate`], for the entries of `addr` which `matches`.
+    #[inline]
+    pub(super) fn invalidate_matching(&mut self, addr: WordType, matches: impl Fn(&T) -> bool) {
+        let set = &mut self.cache[Self::set_index_of(addr)];
+        for (item, data) in set.source_addr.iter_mut().zip(set.data.iter_mut()) {
+            if *item == addr && data.as_ref().is_some_and(&matches) {
+                *item = 0;
+                *data = None;
+            }
+        }
+    }
+
+    /// Every entry with its address.
+    pub(super) fn iter(&self) -> impl Iterator<Item = (WordType, &T)> {
+        self.cache.iter().flat_map(|set| {
+            set.source_addr
+                .iter()
+                .zip(set.data.iter())
+                .filter_map(|(&addr, data)| data.as_ref().map(|data| (addr, data)))
+        })
+    }
+
+    /// Drop the entries for which `keep` is false.
+    pub(super) fn retain(&mut self, mut keep: impl FnMut(WordType, &T) -> bool) {
+        for set in self.cache.iter_mut() {
+            for (addr, data) in set.source_addr.iter_mut().zip(set.data.iter_mut()) {
+                if data.as_ref().is_some_and(|data| !keep(*addr, data)) {
+                    *addr = 0;
+                    *data = None;
+                }
+            }
+        }
+    }
 }
 
 impl<T: Cacheable, const S: usize, const W: usize> Cache<T> for SetCache<T, S, W> {
@@ -233,4 +278,28 @@ mod tests {
 
         assert_eq!(cache.get(8), Some(MockCacheable(8)));
     }
+
+    #[test]
+    fn tagged_set_cache_test() {
+        let mut cache = SetCache::<MockCacheable, 4, 2>::new();
+        cache.put(1, MockCacheable(10));
+        cache.put(1, MockCacheable(11));
+        cache.put(2, MockCacheable(20));
+
+        assert_eq!(
+            cache.get_matching(1, |data| data.0 == 11),
+            Some(MockCacheable(11))
+        );
+        assert_eq!(cache.get_matching(1, |data| data.0 == 20), None);
+        assert_eq!(cache.iter().count(), 3);
+
+        cache.invalidate_matching(1, |data| data.0 == 10);
+        assert_eq!(cache.get_matching(1, |data| data.0 == 10), None);
+        assert_eq!(cache.get(1), Some(MockCacheable(11)));
+        assert_eq!(cache.iter().count(), 2);
+
+        cache.retain(|addr, _| addr != 2);
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.iter().count(), 1);
+    }
 }
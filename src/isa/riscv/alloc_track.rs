@@ -0,0 +1,274 @@
+//! Leak tracking for guest heaps through allocator hooks.
+//!
+//! A hook is a pair of guest functions such as `kmalloc`/`kfree`. When the hart enters the
+//! allocator, its size argument and return address are remembered, and the pointer it returns
+//! in `a0` stays an outstanding [`Allocation`] until the free function is entered with it.
+//! [`Debugger`](super::debugger::Debugger) feeds the tracker the PC after every step, so the
+//! guest needs no instrumentation.
+
+use std::collections::BTreeMap;
+
+use crate::config::arch_config::WordType;
+
+/// Allocator calls still waiting for their return are capped to this number, the guest may
+/// never return to some of them.
+const MAX_PENDING_ALLOCS: usize = 64;
+
+const RA: u8 = 1;
+const SP: u8 = 2;
+const A0: u8 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocHook {
+    /// Entry of the allocation function.
+    pub alloc: WordType,
+    /// Entry of the free function.
+    pub free: WordType,
+    /// Index of the size argument of `alloc`, `0` for `a0`.
+    pub size_arg: u8,
+    /// Index of the pointer argument of `free`, `0` for `a0`.
+    pub ptr_arg: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub ptr: WordType,
+    pub size: WordType,
+    /// Where the allocator returned to.
+    pub caller: WordType,
+    /// `mcycle` when the allocator returned.
+    pub cycle: WordType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AllocStats {
+    pub allocs: u64,
+    pub frees: u64,
+    /// Allocations which returned a null pointer.
+    pub failed_allocs: u64,
+    /// Frees of pointers which were not allocated while tracking.
+    pub unknown_frees: u64,
+    pub outstanding: usize,
+    pub outstanding_bytes: WordType,
+}
+
+struct PendingAlloc {
+    return_addr: WordType,
+    sp: WordType,
+    size: WordType,
+}
+
+#[derive(Default)]
+pub struct AllocTracker {
+    hooks: Vec<AllocHook>,
+    pending: Vec<PendingAlloc>,
+    live: BTreeMap<WordType, Allocation>,
+    stats: AllocStats,
+}
+
+impl AllocTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns false if `hook.alloc` is hooked already.
+    pub fn add_hook(&mut self, hook: AllocHook) -> bool {
+        if self.hooks.iter().any(|h| h.alloc == hook.alloc) {
+            return false;
+        }
+        self.hooks.push(hook);
+        true
+    }
+
+    pub fn hooks(&self) -> &[AllocHook] {
+        &self.hooks
+    }
+
+    /// Forget the recorded allocations, the hooks are kept.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.live.clear();
+        self.stats = AllocStats::default();
+    }
+
+    pub fn stats(&self) -> AllocStats {
+        AllocStats {
+            outstanding: self.live.len(),
+            outstanding_bytes: self
+                .live
+                .values()
+                .fold(0, |sum: WordType, a| sum.wrapping_add(a.size)),
+            ..self.stats
+        }
+    }
+
+    /// The allocations not freed yet, oldest first.
+    pub fn outstanding(&self) -> Vec<Allocation> {
+        let mut allocations: Vec<_> = self.live.values().copied().collect();
+        allocations.sort_by_key(|a| a.cycle);
+        allocations
+    }
+
+    /// Called after every step with the PC of the next instruction.
+    pub fn on_step(&mut self, pc: WordType, reg: impl Fn(u8) -> WordType, cycle: WordType) {
+        if self.hooks.is_empty() {
+            return;
+        }
+
+        let sp = reg(SP);
+        if let Some(idx) = self
+            .pending
+            .iter()
+            .rposition(|p| p.return_addr == pc && p.sp == sp)
+        {
+            let pending = self.pending.remove(idx);
+            let ptr = reg(A0);
+            if ptr == 0 {
+                self.stats.failed_allocs += 1;
+            } else {
+                self.stats.allocs += 1;
+                let allocation = Allocation {
+                    ptr,
+                    size: pending.size,
+                    caller: pc,
+                    cycle,
+                };
+                self.live.insert(ptr, allocation);
+            }
+        }
+
+        for hook in &self.hooks {
+            if pc == hook.alloc {
+                let return_addr = reg(RA);
+                // An interrupt taken at the entry executes it a second time.
+                self.pending
+                    .retain(|p| p.return_addr != return_addr || p.sp != sp);
+                if self.pending.len() == MAX_PENDING_ALLOCS {
+                    self.pending.remove(0);
+                }
+                self.pending.push(PendingAlloc {
+                    return_addr,
+                    sp,
+                    size: reg(A0 + hook.size_arg),
+                });
+            } else if pc == hook.free {
+                let ptr = reg(A0 + hook.ptr_arg);
+                if ptr == 0 {
+                    continue;
+                }
+                match self.live.remove(&ptr) {
+                    Some(_) => self.stats.frees += 1,
+                    None => self.stats.unknown_frees += 1,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MALLOC: WordType = 0x1000;
+    const FREE: WordType = 0x2000;
+
+    /// Step the tracker to `pc` with the given `ra`, `sp` and `a0`-`a1`.
+    fn step(tracker: &mut AllocTracker, pc: WordType, regs: [WordType; 4], cycle: WordType) {
+        let [ra, sp, a0, a1] = regs;
+        tracker.on_step(
+            pc,
+            |idx| match idx {
+                RA => ra,
+                SP => sp,
+                A0 => a0,
+                11 => a1,
+                _ => 0,
+            },
+            cycle,
+        );
+    }
+
+    fn tracker() -> AllocTracker {
+        let mut tracker = AllocTracker::new();
+        assert!(tracker.add_hook(AllocHook {
+            alloc: MALLOC,
+            free: FREE,
+            size_arg: 0,
+            ptr_arg: 0,
+        }));
+        tracker
+    }
+
+    #[test]
+    fn test_outstanding_allocations() {
+        let mut tracker = tracker();
+
+        for (i, ptr) in [0x8000, 0x9000, 0].into_iter().enumerate() {
+            let i = i as WordType;
+            step(
+                &mut tracker,
+                MALLOC,
+                [0x500 + 4 * i, 0x3000, 16 * (i + 1), 0],
+                10 * i,
+            );
+            // Allocators may call themselves, with another stack pointer.
+            step(
+                &mut tracker,
+                0x500 + 4 * i,
+                [0, 0x2f00, 0x1234, 0],
+                10 * i + 2,
+            );
+            step(&mut tracker, 0x500 + 4 * i, [0, 0x3000, ptr, 0], 10 * i + 5);
+        }
+        step(&mut tracker, FREE, [0x600, 0x3000, 0x8000, 0], 40);
+        step(&mut tracker, FREE, [0x600, 0x3000, 0xa000, 0], 41);
+
+        assert_eq!(
+            tracker.outstanding(),
+            [Allocation {
+                ptr: 0x9000,
+                size: 32,
+                caller: 0x504,
+                cycle: 15,
+            }]
+        );
+        assert_eq!(
+            tracker.stats(),
+            AllocStats {
+                allocs: 2,
+                frees: 1,
+                failed_allocs: 1,
+                unknown_frees: 1,
+                outstanding: 1,
+                outstanding_bytes: 32,
+            }
+        );
+
+        tracker.clear();
+        assert!(tracker.outstanding().is_empty());
+        assert_eq!(tracker.hooks().len(), 1);
+    }
+
+    #[test]
+    fn test_hook_arguments() {
+        let mut tracker = AllocTracker::new();
+        let hook = AllocHook {
+            alloc: MALLOC,
+            free: FREE,
+            size_arg: 1,
+            ptr_arg: 1,
+        };
+        assert!(tracker.add_hook(hook));
+        assert!(!tracker.add_hook(hook));
+
+        step(&mut tracker, MALLOC, [0x500, 0x3000, 0xff, 64], 0);
+        // Interrupted at the entry, which runs again after the handler.
+        step(&mut tracker, MALLOC, [0x500, 0x3000, 0xff, 64], 1);
+        step(&mut tracker, 0x500, [0, 0x3000, 0x8000, 0], 2);
+        assert_eq!(tracker.stats().outstanding_bytes, 64);
+
+        step(&mut tracker, FREE, [0x600, 0x3000, 0xff, 0x8000], 3);
+        assert_eq!(tracker.stats().frees, 1);
+        assert!(tracker.outstanding().is_empty());
+    }
+}
@@ -0,0 +1,378 @@
+//! Coarse cost estimate per guest function, for performance triage of firmware.
+//!
+//! Every instruction class costs a fixed number of cycles, and optionally some energy, as given
+//! by a [`CostModel`]. A [`CostProfiler`] samples the pc every `period` instructions through a
+//! pre-exec hook, charges the cost of the sampled instruction `period` times to the function
+//! containing it, and writes the totals as CSV.
+
+use std::{cell::RefCell, collections::BTreeMap, collections::HashMap, io, rc::Rc, str::FromStr};
+
+use crate::{
+    config::arch_config::WordType,
+    isa::riscv::{executor::RVCPU, hooks::HookId, instruction::instr_table::RiscvInstr},
+    load::SymTab,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstrClass {
+    Alu,
+    Mul,
+    Div,
+    Load,
+    Store,
+    Branch,
+    Jump,
+    Atomic,
+    Float,
+    /// Floating-point divisions and square roots.
+    FloatDiv,
+    Vector,
+    /// CSR accesses, fences, traps and returns from traps.
+    System,
+}
+
+impl InstrClass {
+    pub const ALL: [InstrClass; 12] = [
+        InstrClass::Alu,
+        InstrClass::Mul,
+        InstrClass::Div,
+        InstrClass::Load,
+        InstrClass::Store,
+        InstrClass::Branch,
+        InstrClass::Jump,
+        InstrClass::Atomic,
+        InstrClass::Float,
+        InstrClass::FloatDiv,
+        InstrClass::Vector,
+        InstrClass::System,
+    ];
+
+    pub fn of(instr: RiscvInstr) -> Self {
+        use RiscvInstr::*;
+
+        match instr {
+            MUL | MULH | MULHSU | MULHU | MULW => InstrClass::Mul,
+            DIV | DIVU | REM | REMU | DIVW | DIVUW | REMW | REMUW => InstrClass::Div,
+            LB | LBU | LH | LHU | LW | LWU | LD | FLW | FLD | C_LW | C_LD | C_LWSP | C_LDSP
+            | C_FLW | C_FLD | C_FLWSP | C_FLDSP => InstrClass::Load,
+            SB | SH | SW | SD | FSW | FSD | C_SW | C_SD | C_SWSP | C_SDSP | C_FSW | C_FSD
+            | C_FSWSP | C_FSDSP => InstrClass::Store,
+            BEQ | BNE | BLT | BGE | BLTU | BGEU | C_BEQZ | C_BNEZ => InstrClass::Branch,
+            JAL | JALR | C_J | C_JAL | C_JR | C_JALR => InstrClass::Jump,
+            LR_W | SC_W | AMOSWAP_W | AMOADD_W | AMOAND_W | AMOOR_W | AMOXOR_W | AMOMAX_W
+            | AMOMAXU_W | AMOMIN_W | AMOMINU_W | LR_D | SC_D | AMOSWAP_D | AMOADD_D | AMOAND_D
+            | AMOOR_D | AMOXOR_D | AMOMAX_D | AMOMAXU_D | AMOMIN_D | AMOMINU_D => {
+                InstrClass::Atomic
+            }
+            FDIV_S | FDIV_D | FSQRT_S | FSQRT_D => InstrClass::FloatDiv,
+            ECALL | EBREAK | C_EBREAK | MRET | SRET | WFI | FENCE | FENCE_I | SFENCE_VMA
+            | CSRRW | CSRRS | CSRRC | CSRRWI | CSRRSI | CSRRCI => InstrClass::System,
+            _ => match instr.name().as_bytes()[0] {
+                b'F' => InstrClass::Float,
+                b'V' => InstrClass::Vector,
+                _ => InstrClass::Alu,
+            },
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            InstrClass::Alu => "alu",
+            InstrClass::Mul => "mul",
+            InstrClass::Div => "div",
+            InstrClass::Load => "load",
+            InstrClass::Store => "store",
+            InstrClass::Branch => "branch",
+            InstrClass::Jump => "jump",
+            InstrClass::Atomic => "atomic",
+            InstrClass::Float => "float",
+            InstrClass::FloatDiv => "fdiv",
+            InstrClass::Vector => "vector",
+            InstrClass::System => "system",
+        }
+    }
+}
+
+impl FromStr for InstrClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        InstrClass::ALL
+            .into_iter()
+            .find(|class| class.name() == s)
+            .ok_or_else(|| format!("Unknown instruction class: {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClassCost {
+    pub cycles: u32,
+    /// In any unit, e.g. pJ, 0 unless given.
+    pub energy: f64,
+}
+
+/// The cost of each [`InstrClass`], by default the cycles of a simple in-order core.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostModel {
+    costs: [ClassCost; InstrClass::ALL.len()],
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        let cycles = |class| match class {
+            InstrClass::Alu | InstrClass::Store | InstrClass::Branch => 1,
+            InstrClass::Load | InstrClass::Jump => 2,
+            InstrClass::Mul => 3,
+            InstrClass::Float | InstrClass::System | InstrClass::Atomic | InstrClass::Vector => 4,
+            InstrClass::Div | InstrClass::FloatDiv => 20,
+        };
+        Self {
+            costs: InstrClass::ALL.map(|class| ClassCost {
+                cycles: cycles(class),
+                energy: 0.0,
+            }),
+        }
+    }
+}
+
+impl CostModel {
+    pub fn cost(&self, class: InstrClass) -> ClassCost {
+        self.costs[class as usize]
+    }
+
+    pub fn set_cost(&mut self, class: InstrClass, cost: ClassCost) {
+        self.costs[class as usize] = cost;
+    }
+
+    /// Whether an energy weight was given.
+    pub fn has_energy(&self) -> bool {
+        self.costs.iter().any(|cost| cost.energy != 0.0)
+    }
+}
+
+/// The defaults with some classes overridden, e.g. `load=3,div=34:12.5` for 3-cycle loads and
+/// 34-cycle divisions costing 12.5 units of energy.
+impl FromStr for CostModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut model = CostModel::default();
+        for weight in s.split(',').filter(|weight| !weight.is_empty()) {
+            let (class, cost) = weight
+                .split_once('=')
+                .ok_or_else(|| format!("Expected <class>=<cycles>[:<energy>]: {}", weight))?;
+            let (cycles, energy) = match cost.split_once(':') {
+                Some((cycles, energy)) => (cycles, Some(energy)),
+                None => (cost, None),
+            };
+            let cost = ClassCost {
+                cycles: cycles
+                    .parse()
+                    .map_err(|_| format!("Invalid cycle count: {}", cycles))?,
+                energy: match energy {
+                    Some(energy) => energy
+                        .parse()
+                        .map_err(|_| format!("Invalid energy: {}", energy))?,
+                    None => 0.0,
+                },
+            };
+            model.set_cost(class.parse()?, cost);
+        }
+        Ok(model)
+    }
+}
+
+/// The estimated cost of a function.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionCost {
+    pub samples: u64,
+    /// The instructions the samples stand for.
+    pub instrs: u64,
+    pub cycles: u64,
+    pub energy: f64,
+}
+
+pub struct CostProfiler {
+    model: CostModel,
+    /// Instructions between two samples.
+    period: u64,
+    until_sample: u64,
+    /// Function entries by address.
+    functions: BTreeMap<WordType, String>,
+    /// Keyed by function entry, `None` for code outside the symbols.
+    costs: HashMap<Option<WordType>, FunctionCost>,
+}
+
+impl CostProfiler {
+    /// Sample every `period` instructions, 1 to charge every instruction.
+    pub fn new(symtab: &SymTab, model: CostModel, period: u64) -> Self {
+        let functions = symtab
+            .iter()
+            .filter(|(name, _)| {
+                !name.is_empty() && !name.starts_with(".L") && !name.starts_with('$')
+            })
+            .map(|(name, addr)| (*addr as WordType, name.clone()))
+            .collect();
+        let period = period.max(1);
+        Self {
+            model,
+            period,
+            until_sample: 1,
+            functions,
+            costs: HashMap::new(),
+        }
+    }
+
+    /// Sample the instructions `cpu` executes from now on.
+    pub fn attach(self, cpu: &mut RVCPU) -> (Rc<RefCell<Self>>, HookId) {
+        let profiler = Rc::new(RefCell::new(self));
+        let id = cpu.add_pre_exec_hook(None, {
+            let profiler = profiler.clone();
+            Box::new(move |_, pc, instr| profiler.borrow_mut().on_instr(pc, instr.instr))
+        });
+        (profiler, id)
+    }
+
+    /// Called before each instruction.
+    pub fn on_instr(&mut self, pc: WordType, instr: RiscvInstr) {
+        self.until_sample -= 1;
+        if self.until_sample != 0 {
+            return;
+        }
+        self.until_sample = self.period;
+
+        let function = self
+            .functions
+            .range(..=pc)
+            .next_back()
+            .map(|(&addr, _)| addr);
+        let cost = self.model.cost(InstrClass::of(instr));
+        let entry = self.costs.entry(function).or_default();
+        entry.samples += 1;
+        entry.instrs += self.period;
+        entry.cycles += cost.cycles as u64 * self.period;
+        entry.energy += cost.energy * self.period as f64;
+    }
+
+    /// The functions with their cost, the most expensive first. Code outside the symbols is
+    /// named `??`.
+    pub fn report(&self) -> Vec<(&str, &FunctionCost)> {
+        let mut report: Vec<_> = self
+            .costs
+            .iter()
+            .map(|(function, cost)| {
+                let name = function.map_or("??", |addr| self.functions[&addr].as_str());
+                (name, cost)
+            })
+            .collect();
+        report.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+        report
+    }
+
+    /// Write the report as CSV, with an `energy` column if the model has energy weights.
+    pub fn write_csv(&self, mut out: impl io::Write) -> io::Result<()> {
+        let energy = self.model.has_energy();
+        let total: u64 = self.costs.values().map(|cost| cost.cycles).sum();
+        write!(out, "function,samples,instructions,cycles,cycles_percent")?;
+        if energy {
+            write!(out, ",energy")?;
+        }
+        writeln!(out)?;
+        for (name, cost) in self.report() {
+            write!(
+                out,
+                "{},{},{},{},{:.2}",
+                name,
+                cost.samples,
+                cost.instrs,
+                cost.cycles,
+                cost.cycles as f64 * 100.0 / total.max(1) as f64
+            )?;
+            if energy {
+                write!(out, ",{:.3}", cost.energy)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        board::{
+            Board,
+            virt::{SerialDestination, VirtBoardBuilder},
+        },
+        ram_config,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_cost_model() {
+        assert_eq!(InstrClass::of(RiscvInstr::DIVW), InstrClass::Div);
+        assert_eq!(InstrClass::of(RiscvInstr::FMADD_D), InstrClass::Float);
+        assert_eq!(InstrClass::of(RiscvInstr::VADD_VV), InstrClass::Vector);
+        assert_eq!(InstrClass::of(RiscvInstr::C_ADDI), InstrClass::Alu);
+
+        let model: CostModel = "load=3,div=34:12.5".parse().unwrap();
+        assert_eq!(model.cost(InstrClass::Load).cycles, 3);
+        assert_eq!(
+            model.cost(InstrClass::Div),
+            ClassCost {
+                cycles: 34,
+                energy: 12.5
+            }
+        );
+        assert_eq!(model.cost(InstrClass::Mul).cycles, 3);
+        assert!(model.has_energy());
+        assert!(!CostModel::default().has_energy());
+        assert!("cache=3".parse::<CostModel>().is_err());
+        assert!("load".parse::<CostModel>().is_err());
+    }
+
+    #[test]
+    fn test_cost_profiler() {
+        let program: Vec<u8> = [
+            // main
+            0x0030_0293u32, // addi x5, x0, 3
+            0x0252_8333,    // mul x6, x5, x5
+            // helper
+            0x0010_0393, // addi x7, x0, 1
+            0x0253_4433, // div x8, x6, x5
+        ]
+        .iter()
+        .flat_map(|instr| instr.to_le_bytes())
+        .collect();
+        let mut board = VirtBoardBuilder::new()
+            .serial(SerialDestination::Buffer)
+            .binary(program)
+            .build()
+            .unwrap();
+        let base = ram_config::BASE_ADDR;
+        let symtab = SymTab::from(&[("main".to_string(), base), ("helper".to_string(), base + 8)]);
+        let model: CostModel = "div=20:10".parse().unwrap();
+        let (profiler, _) = CostProfiler::new(&symtab, model, 1).attach(&mut board.cpu);
+
+        for _ in 0..4 {
+            board.step().unwrap();
+        }
+        let profiler = profiler.borrow();
+        let report = profiler.report();
+        assert_eq!(report[0].0, "helper");
+        assert_eq!(report[0].1.cycles, 21);
+        assert_eq!(report[1].0, "main");
+        assert_eq!(report[1].1.instrs, 2);
+        assert_eq!(report[1].1.cycles, 4);
+
+        let mut csv = Vec::new();
+        profiler.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "function,samples,instructions,cycles,cycles_percent,energy\n\
+             helper,2,2,21,84.00,10.000\n\
+             main,2,2,4,16.00,0.000\n"
+        );
+    }
+}
@@ -9,10 +9,10 @@ use crate::{
     device::mmio::MemoryMapIO,
     isa::riscv::{
         csr_reg::{
-            NamedCsrReg,
+            NamedCsrReg, PrivilegeLevel,
             csr_macro::{Mstatus, Vl, Vtype},
         },
-        decoder::DecodeInstr,
+        decoder::{DecodeInstr, Decoder},
         executor::RVCPU,
         instruction::{RVInstrInfo, instr_table::RiscvInstr},
         mmu::VirtAddrManager,
@@ -30,9 +30,30 @@ pub(super) struct TestCPUBuilder {
 impl TestCPUBuilder {
     /// Build a RISC-V CPU, only has RAM, don't have other devices.
     pub(super) fn new() -> Self {
+        Self::with_decoder(Decoder::new())
+    }
+
+    /// Like [`Self::new`], for a hart implementing the ISA string `isa`.
+    pub(super) fn with_isa(isa: &str) -> Self {
+        Self::with_decoder(Decoder::from_isa_str(isa).unwrap())
+    }
+
+    /// Like [`Self::new`], for `count` harts sharing one RAM.
+    pub(super) fn harts(count: usize) -> Vec<Self> {
         let ram_ref = Rc::new(UnsafeCell::new(Ram::new()));
+        (0..count)
+            .map(|_| Self::with_ram(Decoder::new(), ram_ref.clone()))
+            .collect()
+    }
+
+    fn with_decoder(decoder: Decoder) -> Self {
+        Self::with_ram(decoder, Rc::new(UnsafeCell::new(Ram::new())))
+    }
+
+    fn with_ram(decoder: Decoder, ram_ref: Rc<UnsafeCell<Ram>>) -> Self {
         let mmio = MemoryMapIO::from_mmio_items(ram_ref.clone(), vec![]);
-        let mut cpu = RVCPU::from_vaddr_manager(VirtAddrManager::from_ram_and_mmio(ram_ref, mmio));
+        let mut cpu =
+            RVCPU::from_decoder(decoder, VirtAddrManager::from_ram_and_mmio(ram_ref, mmio));
         cpu.csr.get_by_type_existing::<Mstatus>().set_fs(1); // Enable FPU by default for convienience
         cpu.csr.get_by_type_existing::<Mstatus>().set_vs_directly(1); // Enable vector unit by default for convenience
         Self { cpu: cpu }
@@ -123,8 +144,11 @@ impl TestCPUBuilder {
         self
     }
 
-    pub(super) fn program(mut self, instrs: &[u32]) -> Self {
-        let mut addr = BASE_ADDR;
+    pub(super) fn program(self, instrs: &[u32]) -> Self {
+        self.program_at(BASE_ADDR, instrs)
+    }
+
+    pub(super) fn program_at(mut self, mut addr: WordType, instrs: &[u32]) -> Self {
         for instr in instrs {
             self.cpu
                 .memory
@@ -140,6 +164,11 @@ impl TestCPUBuilder {
         self
     }
 
+    pub(super) fn privilege(mut self, level: PrivilegeLevel) -> Self {
+        self.cpu.csr.set_current_privileged(level);
+        self
+    }
+
     pub(super) fn build(self) -> RVCPU {
         self.cpu
     }
@@ -220,6 +249,15 @@ impl<'a> CPUChecker<'a> {
         self.mem::<T>(BASE_ADDR + addr, value)
     }
 
+    pub(super) fn privilege(self, level: PrivilegeLevel) -> Self {
+        assert_eq!(
+            self.cpu.csr.privelege_level(),
+            level,
+            "Privilege level incorrect"
+        );
+        self
+    }
+
     pub(super) fn csr(self, addr: WordType, value: WordType) -> Self {
         assert_eq!(
             self.cpu.csr.read_uncheck_privilege(addr).unwrap(),
@@ -319,6 +357,27 @@ impl ExecTester {
         (idx1, idx2)
     }
 
+    /// Executes `count` random encodings with the `rd` field cleared and checks that `x0` still
+    /// reads as zero, whatever the instruction did. Encodings that don't decode are skipped,
+    /// exceptions are fine.
+    pub(super) fn fuzz_rd_zero(&mut self, count: usize) {
+        const RD_MASK: u32 = 0b11111 << 7;
+
+        let mut cpu = TestCPUBuilder::new().build();
+        for _ in 0..count {
+            let raw = self.rng.random::<u32>() & !RD_MASK;
+            let Some(DecodeInstr { instr, info, .. }) = cpu.decoder.decode(raw.into()) else {
+                continue;
+            };
+
+            for idx in 1..REGFILE_CNT as u8 {
+                cpu.reg_file.write(idx, self.rand_word());
+            }
+            let _ = cpu.execute(instr, info);
+            assert_eq!(cpu.reg_file[0], 0, "{instr:?} ({raw:#010x}) wrote x0");
+        }
+    }
+
     pub(super) fn test_rand_r_with(
         &mut self,
         instr: RiscvInstr,
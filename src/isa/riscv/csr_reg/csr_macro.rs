@@ -45,6 +45,14 @@ macro_rules! gen_csr_reg {
             }
         }
 
+        impl $name {
+            #[allow(unused)]
+            #[inline]
+            pub(super) fn context(&self) -> &CsrContext {
+                unsafe { &*self.ctx }
+            }
+        }
+
         impl $name {
             $(
                 #[allow(non_upper_case_globals)]
@@ -286,6 +294,14 @@ gen_csr_regfile! {
         2, XLEN - 2, base;
     ];
 
+    // see mcounteren.
+    Scounteren, "scounteren", 0x106u64, 0x00, [
+        0, 1, cy;
+        1, 1, tm;
+        2, 1, ir;
+        3, 29, hpm;
+    ];
+
     Sscratch, "sscratch", 0x140u64, 0x00, [
         0, XLEN, scratch;
     ];
@@ -358,7 +374,7 @@ gen_csr_regfile! {
     ];
 
     Misa, "misa", 0x301u64, 0x00, [
-        0, 25, extension, validate_readonly;  // TODO: We don't support changing extension yet.
+        0, 26, extension, validate_misa_extension;
         -2, 2, mxl, validate_readonly;
     ];
 
@@ -411,8 +427,18 @@ gen_csr_regfile! {
         2, XLEN - 2, base;
     ];
 
+    // Whether `cycle`, `time`, `instret` and `hpmcounter3`-`hpmcounter31` are readable in the
+    // next lower privilege level.
+    Mcounteren, "mcounteren", 0x306u64, 0x00, [
+        0, 1, cy;
+        1, 1, tm;
+        2, 1, ir;
+        3, 29, hpm;
+    ];
+
     Mcountinhibit, "mcountinhibit", 0x320u64, 0x00, [
-        0, XLEN, mcountinhibit, validate_readonly;
+        0, 1, cy; // Stop `mcycle` from incrementing.
+        2, 1, ir; // Stop `minstret` from incrementing.
     ];
 
     Mscratch, "mscratch", 0x340u64, 0x00, [
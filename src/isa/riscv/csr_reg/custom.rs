@@ -0,0 +1,116 @@
+//! User-defined CSRs for modeling vendor-specific cores.
+//!
+//! Loaded from a TOML file, one `[[csr]]` table per register:
+//!
+//! ```toml
+//! [[csr]]
+//! name = "mcustom0"
+//! addr = 0x7c0
+//! reset = 0x0        # optional, defaults to 0
+//! mask = 0xff        # optional WARL mask of writable bits, defaults to all bits
+//! ```
+//!
+//! Access privilege and read-only-ness follow the standard rules for the address.
+
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::{
+    config::arch_config::WordType,
+    isa::riscv::csr_reg::{CsrRegFile, csr_macro::CSR_REG_TABLE},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CustomCsr {
+    pub name: String,
+    pub addr: WordType,
+    #[serde(default)]
+    pub reset: WordType,
+    #[serde(default = "write_all")]
+    pub mask: WordType,
+}
+
+fn write_all() -> WordType {
+    WordType::MAX
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CustomCsrError {
+    #[error("failed to read CSR config: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid CSR config: {0}")]
+    Parse(#[from] toml::de::Error),
+
+    #[error("CSR {name}: address {addr:#x} out of range")]
+    InvalidAddress { name: String, addr: WordType },
+
+    #[error("CSR {name}: address {addr:#x} is already defined")]
+    Duplicated { name: String, addr: WordType },
+}
+
+#[derive(Deserialize)]
+struct CustomCsrFile {
+    #[serde(default)]
+    csr: Vec<CustomCsr>,
+}
+
+/// Parse and check that the CSRs don't conflict with the builtin ones.
+pub fn parse_custom_csrs(text: &str) -> Result<Vec<CustomCsr>, CustomCsrError> {
+    let csrs = toml::from_str::<CustomCsrFile>(text)?.csr;
+    CsrRegFile::from(CSR_REG_TABLE, &csrs)?;
+    Ok(csrs)
+}
+
+pub fn load_custom_csrs(path: &Path) -> Result<Vec<CustomCsr>, CustomCsrError> {
+    parse_custom_csrs(&std::fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let csrs = parse_custom_csrs(
+            r#"
+            [[csr]]
+            name = "mcustom0"
+            addr = 0x7c0
+            reset = 0x5
+            mask = 0xff
+
+            [[csr]]
+            name = "mcustom1"
+            addr = 0x7c1
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            csrs,
+            vec![
+                CustomCsr {
+                    name: "mcustom0".to_string(),
+                    addr: 0x7c0,
+                    reset: 0x5,
+                    mask: 0xff,
+                },
+                CustomCsr {
+                    name: "mcustom1".to_string(),
+                    addr: 0x7c1,
+                    reset: 0,
+                    mask: WordType::MAX,
+                },
+            ]
+        );
+
+        assert!(parse_custom_csrs("[[csr]]\naddr = 1").is_err());
+        assert!(matches!(
+            parse_custom_csrs("[[csr]]\nname = \"x\"\naddr = 0x300"),
+            Err(CustomCsrError::Duplicated { .. })
+        ));
+        assert!(parse_custom_csrs("").unwrap().is_empty());
+    }
+}
@@ -4,14 +4,23 @@ mod write_validator;
 mod read_validator;
 
 pub mod csr_macro;
+pub mod custom;
+pub mod trace;
 pub mod utils;
 
 use self::{
-    csr_macro::{CSR_REG_TABLE, Fcsr, Mstatus, Satp, Vcsr, resolve_shadow_addr},
+    csr_macro::{
+        CSR_REG_TABLE, Cycle, Fcsr, Instret, Mcounteren, Mcountinhibit, Mstatus, Satp, Scounteren,
+        Vcsr, resolve_shadow_addr,
+    },
+    custom::{CustomCsr, CustomCsrError},
     read_validator::ReadValidator,
-    write_validator::WriteValidator,
+    write_validator::{WriteValidator, validate_readonly},
+};
+use crate::{
+    config::arch_config::WordType,
+    isa::riscv::isa_builder::{Extension, ISABuilder},
 };
-use crate::config::arch_config::WordType;
 use std::cmp::Ordering;
 
 /// Constants in this module are not complete. Use `get_index` static method for each CSR type, like [`Mstatus::get_index`].
@@ -30,7 +39,10 @@ pub(crate) mod csr_index {
     pub const mcause    : WordType  = 0x342;    // 异常原因
     pub const mtval     : WordType  = 0x343;    // 异常附加信息（例如非法访问地址）
     pub const mip       : WordType  = 0x344;    // 中断挂起寄存器
-    // pub const mhartid   : WordType  = 0xF14;    // CPU hart ID（多核情况下）
+    pub const mvendorid : WordType  = 0xF11;    // 厂商 ID
+    pub const marchid   : WordType  = 0xF12;    // 微架构 ID
+    pub const mimpid    : WordType  = 0xF13;    // 实现版本 ID
+    pub const mhartid   : WordType  = 0xF14;    // CPU hart ID（多核情况下）
 
     // Floating-Point CSR
     pub const fflags    : WordType  = 0x001;
@@ -170,9 +182,11 @@ impl CsrWriteOp {
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct CsrContext {
-    pub extension: WordType, // Used in `misa`
-    pub xlen: u8,            // 32 or 64
+    pub extension: WordType,     // Used in `misa`
+    pub xlen: u8,                // 32 or 64
+    pub count_inhibit: WordType, // Mirror of `mcountinhibit`
 }
 
 impl CsrContext {
@@ -180,6 +194,7 @@ impl CsrContext {
         CsrContext {
             extension: 0,
             xlen: 0,
+            count_inhibit: 0,
         }
     }
 }
@@ -189,6 +204,8 @@ pub(crate) struct CsrReg {
     value: WordType,
     write_validator: Option<WriteValidator>,
     read_validator: Option<ReadValidator>,
+    /// Writable bits when there is no `write_validator`, used by custom CSRs.
+    write_mask: WordType,
 }
 
 impl CsrReg {
@@ -201,6 +218,14 @@ impl CsrReg {
             value,
             write_validator: validator,
             read_validator: shadow_view,
+            write_mask: WordType::MAX,
+        }
+    }
+
+    fn with_write_mask(value: WordType, write_mask: WordType) -> CsrReg {
+        CsrReg {
+            write_mask,
+            ..CsrReg::new(value, None, None)
         }
     }
 
@@ -220,7 +245,7 @@ impl CsrReg {
             let op = validator(new_value, context);
             op.apply(&mut self.value, new_value);
         } else {
-            self.value = new_value;
+            CsrWriteOp::new(self.write_mask).apply(&mut self.value, new_value);
         }
     }
 
@@ -233,7 +258,8 @@ impl CsrReg {
             let op = validator(new_value, context).mask(&mask);
             op.apply(&mut self.value, new_value);
         } else {
-            self.value = new_value;
+            let op = CsrWriteOp::new(self.write_mask).mask(&mask);
+            op.apply(&mut self.value, new_value);
         }
     }
 
@@ -245,6 +271,16 @@ impl CsrReg {
 
 const CSR_SIZE: usize = 1 << 12;
 
+/// Implementation IDs reported by `mvendorid`, `marchid`, `mimpid` and `mhartid`, all zero by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HartIdentity {
+    pub vendor_id: WordType,
+    pub arch_id: WordType,
+    pub imp_id: WordType,
+    pub hart_id: WordType,
+}
+
+#[derive(Clone)]
 pub(crate) struct CsrRegFile {
     table: Vec<Option<CsrReg>>,
     cpl: PrivilegeLevel, // current privileged level
@@ -253,10 +289,15 @@ pub(crate) struct CsrRegFile {
 
 impl CsrRegFile {
     pub fn new() -> Self {
-        Self::from(CSR_REG_TABLE)
+        Self::from(CSR_REG_TABLE, &[]).expect("builtin CSRs never conflict")
     }
 
-    pub fn from(csr_table: &[(WordType, WordType, WriteValidator)]) -> Self {
+    /// Build from the builtin `csr_table` plus user-defined `custom` CSRs,
+    /// which must not overlap the builtin ones or each other.
+    pub fn from(
+        csr_table: &[(WordType, WordType, WriteValidator)],
+        custom: &[CustomCsr],
+    ) -> Result<Self, CustomCsrError> {
         let mut table = vec![None; CSR_SIZE];
         for (addr, default_value, validator) in csr_table.iter() {
             table[*addr as usize] = Some(CsrReg::new(
@@ -266,10 +307,61 @@ impl CsrRegFile {
             ));
         }
 
-        Self {
+        for csr in custom {
+            let slot =
+                table
+                    .get_mut(csr.addr as usize)
+                    .ok_or_else(|| CustomCsrError::InvalidAddress {
+                        name: csr.name.clone(),
+                        addr: csr.addr,
+                    })?;
+            if slot.is_some() {
+                return Err(CustomCsrError::Duplicated {
+                    name: csr.name.clone(),
+                    addr: csr.addr,
+                });
+            }
+            *slot = Some(CsrReg::with_write_mask(csr.reset, csr.mask));
+        }
+
+        Ok(Self {
             table,
             cpl: PrivilegeLevel::M,
             ctx: CsrContext::new(),
+        })
+    }
+
+    /// Keep only the counters `isa` implements: `cycle` and `instret` come with Zicntr,
+    /// `hpmcounter3`-`hpmcounter31`, `mhpmcounter3`-`mhpmcounter31` and their event selectors
+    /// with Zihpm, hardwired to zero. `time` has no register, see [`RVCPU::read_csr`].
+    ///
+    /// [`RVCPU::read_csr`]: crate::isa::riscv::executor::RVCPU::read_csr
+    pub fn configure_counters(&mut self, isa: &ISABuilder) {
+        if !isa.has(Extension::Zicntr) {
+            self.table[Cycle::get_index() as usize] = None;
+            self.table[Instret::get_index() as usize] = None;
+        }
+        if isa.has(Extension::Zihpm) {
+            for i in 3..32 {
+                for base in [0xC00, 0xB00, 0x320] {
+                    self.table[base + i].get_or_insert(CsrReg::new(
+                        0,
+                        Some(validate_readonly),
+                        None,
+                    ));
+                }
+            }
+        }
+    }
+
+    pub fn set_identity(&mut self, id: &HartIdentity) {
+        for (addr, value) in [
+            (csr_index::mvendorid, id.vendor_id),
+            (csr_index::marchid, id.arch_id),
+            (csr_index::mimpid, id.imp_id),
+            (csr_index::mhartid, id.hart_id),
+        ] {
+            assert!(self.write_directly(addr, value));
         }
     }
 
@@ -281,6 +373,19 @@ impl CsrRegFile {
             return false;
         }
 
+        // Unprivileged counters are gated by `mcounteren` below M-mode and `scounteren` in U-mode.
+        if (0xC00..0xC20).contains(&csr_addr) && self.privelege_level() != PrivilegeLevel::M {
+            let bit = 1 << (csr_addr - 0xC00);
+            if self.get_by_type_existing::<Mcounteren>().data() & bit == 0 {
+                return false;
+            }
+            if self.privelege_level() == PrivilegeLevel::U
+                && self.get_by_type_existing::<Scounteren>().data() & bit == 0
+            {
+                return false;
+            }
+        }
+
         match CSR_PRIVILEGE_TABLE.binary_search_by(|&(k, _)| {
             if k > csr_addr {
                 Ordering::Greater
@@ -347,6 +452,7 @@ impl CsrRegFile {
         }
         if let Some(reg) = self.table[addr as usize].as_mut() {
             reg.write_directly(data);
+            self.sync_context(addr);
             true
         } else {
             false
@@ -391,6 +497,7 @@ impl CsrRegFile {
                     }
                 } else {
                     csr.write(data, &self.ctx);
+                    self.sync_context(addr);
                 }
             } else {
                 // TODO: Raise error
@@ -398,6 +505,13 @@ impl CsrRegFile {
         }
     }
 
+    /// Keep the fields of [`CsrContext`] mirroring a CSR up to date after writing `addr`.
+    fn sync_context(&mut self, addr: WordType) {
+        if addr == Mcountinhibit::get_index() {
+            self.ctx.count_inhibit = self.get_by_type_existing::<Mcountinhibit>().data();
+        }
+    }
+
     pub fn read_uncheck_privilege(&self, addr: WordType) -> Option<WordType> {
         // Special-case fflags and frm; they are subfields of fcsr
         if addr == csr_index::fflags {
@@ -409,6 +523,8 @@ impl CsrRegFile {
         } else if addr == csr_index::vxrm {
             Some((self.table[Vcsr::get_index() as usize].unwrap().value() >> 1) & 0b11)
         } else {
+            // Shadow CSRs have a slot of their own too, it is gone if the CSR is not implemented.
+            let reg = self.table[addr as usize]?;
             if let Some(base_addr) = resolve_shadow_addr(addr) {
                 // This is a shadow CSR.
                 // The base CSR must exist because it can be resolved by `resolve_shadow_addr`.
@@ -417,7 +533,7 @@ impl CsrRegFile {
                         & base_addr.view_mask,
                 )
             } else {
-                Some(self.table[addr as usize]?.value())
+                Some(reg.value())
             }
         }
     }
@@ -468,8 +584,13 @@ impl CsrRegFile {
 
 #[cfg(test)]
 mod test {
-    use crate::isa::riscv::csr_reg::{
-        CsrRegFile, NamedCsrReg, PrivilegeLevel, csr_index, csr_macro::*,
+    use crate::{
+        config::arch_config::WordType,
+        isa::riscv::csr_reg::{
+            CsrRegFile, HartIdentity, NamedCsrReg, PrivilegeLevel, csr_index,
+            csr_macro::*,
+            custom::{CustomCsr, CustomCsrError},
+        },
     };
 
     #[test]
@@ -601,4 +722,61 @@ mod test {
         mie.set_seie(1);
         assert_eq!(sie.get_seie(), 1);
     }
+
+    #[test]
+    fn test_custom_csr() {
+        let custom = |name: &str, addr, mask| CustomCsr {
+            name: name.to_string(),
+            addr,
+            reset: 0x5,
+            mask,
+        };
+
+        let mut csr = CsrRegFile::from(
+            CSR_REG_TABLE,
+            &[
+                custom("mcustom0", 0x7c0, 0xf0),
+                custom("mcustom1", 0x7c1, WordType::MAX),
+            ],
+        )
+        .unwrap();
+        assert_eq!(csr.read(0x7c0), Some(0x5));
+
+        // WARL: only bits in the mask are written.
+        assert!(csr.write(0x7c0, 0xabc));
+        assert_eq!(csr.read(0x7c0), Some(0xb5));
+        assert!(csr.write(0x7c1, 0xabc));
+        assert_eq!(csr.read(0x7c1), Some(0xabc));
+
+        assert!(matches!(
+            CsrRegFile::from(CSR_REG_TABLE, &[custom("mstatus", 0x300, 0)]),
+            Err(CustomCsrError::Duplicated { .. })
+        ));
+        assert!(matches!(
+            CsrRegFile::from(CSR_REG_TABLE, &[custom("bad", 0x1000, 0)]),
+            Err(CustomCsrError::InvalidAddress { .. })
+        ));
+    }
+
+    #[test]
+    fn test_identity() {
+        let mut csr = CsrRegFile::new();
+        let id = HartIdentity {
+            vendor_id: 0x489,
+            arch_id: 0x8000_0007,
+            imp_id: 0x1,
+            hart_id: 3,
+        };
+        csr.set_identity(&id);
+
+        assert_eq!(csr.read(csr_index::mvendorid), Some(0x489));
+        assert_eq!(csr.read(csr_index::marchid), Some(0x8000_0007));
+        assert_eq!(csr.read(csr_index::mimpid), Some(0x1));
+        assert_eq!(csr.read(csr_index::mhartid), Some(3));
+
+        // Read-only.
+        assert!(!csr.write(csr_index::mhartid, 0));
+        csr.set_current_privileged(PrivilegeLevel::S);
+        assert_eq!(csr.read(csr_index::mhartid), None);
+    }
 }
@@ -0,0 +1,198 @@
+//! Tracing of the CSR writes of the guest, to follow how it programs `mstatus`, `satp`, `mtvec`
+//! and the like while bringing up an OS.
+//!
+//! Attach a [`CsrTracer`] to the CPU with
+//! [`RVCPU::set_csr_tracer`](crate::isa::riscv::executor::RVCPU::set_csr_tracer), then every
+//! successful write by a CSR instruction is reported with the value before and after, and the
+//! `pc` and privilege level of the instruction. Writes made by the hart itself, e.g. `mepc` when
+//! taking a trap, are not traced.
+
+use std::{fs::File, io::BufWriter, io::Write, path::Path};
+
+use crate::{
+    config::arch_config::WordType,
+    isa::riscv::csr_reg::{
+        PrivilegeLevel,
+        csr_macro::{CSR_ADDRESS, CSR_NAME},
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsrWrite {
+    pub addr: WordType,
+    pub old: WordType,
+    pub new: WordType,
+    /// The value the guest wrote, `new` unless some fields are read-only or WARL.
+    pub written: WordType,
+    pub pc: WordType,
+    pub privilege: PrivilegeLevel,
+}
+
+impl std::fmt::Display for CsrWrite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match CSR_NAME.get(&self.addr) {
+            Some(name) => write!(f, "{name}")?,
+            None => write!(f, "csr {:#x}", self.addr)?,
+        }
+        write!(f, ": {:#x} -> {:#x}", self.old, self.new)?;
+        if self.written != self.new {
+            write!(f, " (wrote {:#x})", self.written)?;
+        }
+        write!(f, " @ pc = {:#x} ({:?})", self.pc, self.privilege)
+    }
+}
+
+/// A name given to [`CsrTracer::filter`] which is neither a CSR nor an address.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("unknown CSR \"{0}\"")]
+pub struct UnknownCsr(pub String);
+
+enum CsrTraceSink {
+    Log,
+    Writer(Box<dyn Write>),
+}
+
+pub struct CsrTracer {
+    sink: CsrTraceSink,
+    /// Addresses of the CSRs to trace, trace every CSR when empty.
+    filter: Vec<WordType>,
+}
+
+impl CsrTracer {
+    /// Trace to the log, with target `csr`.
+    pub fn to_log() -> Self {
+        Self::new(CsrTraceSink::Log)
+    }
+
+    /// Trace to a dedicated file, one write per line.
+    pub fn to_file(path: &Path) -> std::io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Self::to_writer(Box::new(file)))
+    }
+
+    pub fn to_writer(writer: Box<dyn Write>) -> Self {
+        Self::new(CsrTraceSink::Writer(writer))
+    }
+
+    fn new(sink: CsrTraceSink) -> Self {
+        Self {
+            sink,
+            filter: Vec::new(),
+        }
+    }
+
+    /// Only trace the CSRs in `csrs`, given by name (`mstatus`) or address (`0x7c0`, for the
+    /// custom CSRs).
+    pub fn filter(mut self, csrs: &[String]) -> Result<Self, UnknownCsr> {
+        self.filter = csrs
+            .iter()
+            .map(|csr| {
+                let csr = csr.trim();
+                let addr = match csr.starts_with(|c: char| c.is_ascii_digit()) {
+                    true => crate::parse_u64(csr).ok().map(|addr| addr as WordType),
+                    false => CSR_ADDRESS.get(csr).copied(),
+                };
+                addr.ok_or_else(|| UnknownCsr(csr.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(self)
+    }
+
+    pub(crate) fn is_traced(&self, addr: WordType) -> bool {
+        self.filter.is_empty() || self.filter.contains(&addr)
+    }
+
+    pub(crate) fn record(&mut self, write: &CsrWrite) {
+        match &mut self.sink {
+            CsrTraceSink::Log => log::info!(target: "csr", "{}", write),
+            CsrTraceSink::Writer(w) => {
+                if let Err(e) = writeln!(w, "{}", write) {
+                    log::warn!("Failed to write CSR trace: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for CsrTracer {
+    fn drop(&mut self) {
+        if let CsrTraceSink::Writer(w) = &mut self.sink {
+            let _ = w.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{
+        config::arch_config::WordType,
+        isa::riscv::{cpu_tester::TestCPUBuilder, csr_reg::csr_index},
+    };
+
+    use super::*;
+
+    struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_csr_trace() {
+        const HANDLER: WordType = 0x8000_2000;
+
+        let mut cpu = TestCPUBuilder::new()
+            .program(&[
+                0x30529073, // csrw mtvec, t0
+                0x34031073, // csrw mscratch, t1
+                0x30039073, // csrw mstatus, t2
+            ])
+            .reg(5, HANDLER | 0b10)
+            .reg(6, 0x1234)
+            .reg(7, 1 << 3)
+            .build();
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let tracer = CsrTracer::to_writer(Box::new(SharedWriter(output.clone())))
+            .filter(&["mtvec".to_string(), "0x300".to_string()])
+            .unwrap();
+        cpu.set_csr_tracer(Some(tracer));
+
+        let mstatus = cpu.csr.read_uncheck_privilege(csr_index::mstatus).unwrap();
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                // mtvec.MODE 2 is reserved and ignored
+                format!(
+                    "mtvec: 0x0 -> {HANDLER:#x} (wrote {:#x}) @ pc = 0x80000000 (M)",
+                    HANDLER | 0b10
+                ),
+                format!(
+                    "mstatus: {mstatus:#x} -> {:#x} (wrote 0x8) @ pc = 0x80000008 (M)",
+                    mstatus & !0xffff | 1 << 3
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filter_unknown_csr() {
+        assert_eq!(
+            CsrTracer::to_log().filter(&["mstatsu".to_string()]).err(),
+            Some(UnknownCsr("mstatsu".to_string()))
+        );
+    }
+}
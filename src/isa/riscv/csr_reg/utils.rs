@@ -8,6 +8,9 @@ use crate::{
 
 impl Minstret {
     pub fn wrapping_add(&self, rhs: WordType) {
+        if self.context().count_inhibit & (1 << Mcountinhibit::ir_start) != 0 {
+            return;
+        }
         let v = self.get_minstret() + rhs;
         self.set_minstret(v);
     }
@@ -94,3 +94,18 @@ macro_rules! combine_validators {
 pub(super) fn validate_readonly(_value: WordType, _ctx: &CsrContext) -> CsrWriteOp {
     CsrWriteOp { mask: 0 }
 }
+
+/// `misa.extension` is WARL: only the extensions the decoder was built with
+/// (`ctx.extension`) can be toggled, the base (`I` or `E`), `S` and `U` stay on, and a write
+/// enabling `D` without `F` is ignored.
+pub(super) fn validate_misa_extension(value: WordType, ctx: &CsrContext) -> CsrWriteOp {
+    const fn bit(letter: u8) -> WordType {
+        1 << (letter - b'A')
+    }
+
+    let enabled = value & ctx.extension;
+    if enabled & bit(b'D') != 0 && enabled & bit(b'F') == 0 {
+        return CsrWriteOp { mask: 0 };
+    }
+    CsrWriteOp::new(ctx.extension & !(bit(b'I') | bit(b'E') | bit(b'S') | bit(b'U')))
+}
@@ -0,0 +1,689 @@
+//! RISC-V Debug Module (debug specification 1.0): what an external debugger such as OpenOCD
+//! talks to, through the registers of the Debug Module Interface (DMI).
+//!
+//! The DM drives the hart of a board through a [`Debugger`]: [`DebugModule::run`] executes
+//! instructions while the hart is running, and stops in Debug Mode on a halt request, a single
+//! step or an `ebreak` enabled in `dcsr`. A halted hart is accessed with abstract commands:
+//!
+//! - Access Register: the GPRs (`0x1000`-`0x101f`), the FPRs (`0x1020`-`0x103f`) and the CSRs,
+//!   with `dcsr` and `dpc` which only exist in Debug Mode.
+//! - Access Memory: 8 to XLEN bits, physical or virtual addresses.
+//!
+//! There is no program buffer, no system bus access and no trigger module. Commands complete
+//! instantly, so `abstractcs.busy` never reads as set. There is one hart, hart 0.
+//!
+//! | Address | Name         | Description                                                   |
+//! |---------|--------------|---------------------------------------------------------------|
+//! | 0x04    | data0-3      | Arguments of the abstract commands                            |
+//! | 0x10    | dmcontrol    | Halt, resume, reset and hart selection                        |
+//! | 0x11    | dmstatus     | State of the selected hart (read-only)                        |
+//! | 0x12    | hartinfo     | Zero, no data registers are shadowed in memory (read-only)    |
+//! | 0x16    | abstractcs   | `cmderr` (write 1 to clear) and `datacount` = 4               |
+//! | 0x17    | command      | Runs an abstract command when written                         |
+//! | 0x40    | haltsum0     | Bit 0 is set when hart 0 is halted (read-only)                |
+//!
+//! The other registers read as zero.
+
+use crate::{
+    board::Board,
+    config::arch_config::{WordType, XLEN},
+    device::MemError,
+    isa::riscv::{
+        csr_reg::{NamedCsrReg, PrivilegeLevel, csr_macro::Misa},
+        debugger::{Address, DebugError, DebugEvent, Debugger},
+    },
+    utils::UnsignedInteger,
+};
+
+const DATA0: u32 = 0x04;
+const DMCONTROL: u32 = 0x10;
+const DMSTATUS: u32 = 0x11;
+const HARTINFO: u32 = 0x12;
+const ABSTRACTCS: u32 = 0x16;
+const COMMAND: u32 = 0x17;
+const HALTSUM0: u32 = 0x40;
+
+/// Two XLEN arguments, the data address and the data of an Access Memory command.
+pub const DATA_COUNT: usize = 4;
+
+const DMCONTROL_HALTREQ: u32 = 1 << 31;
+const DMCONTROL_RESUMEREQ: u32 = 1 << 30;
+const DMCONTROL_ACKHAVERESET: u32 = 1 << 28;
+const DMCONTROL_HARTSELLO_SHIFT: u32 = 16;
+const DMCONTROL_HARTSELLO_MASK: u32 = 0x3ff;
+const DMCONTROL_SETRESETHALTREQ: u32 = 1 << 3;
+const DMCONTROL_CLRRESETHALTREQ: u32 = 1 << 2;
+const DMCONTROL_NDMRESET: u32 = 1 << 1;
+const DMCONTROL_DMACTIVE: u32 = 1 << 0;
+
+const DMSTATUS_ALLHAVERESET: u32 = 1 << 19;
+const DMSTATUS_ANYHAVERESET: u32 = 1 << 18;
+const DMSTATUS_ALLRESUMEACK: u32 = 1 << 17;
+const DMSTATUS_ANYRESUMEACK: u32 = 1 << 16;
+const DMSTATUS_ALLNONEXISTENT: u32 = 1 << 15;
+const DMSTATUS_ANYNONEXISTENT: u32 = 1 << 14;
+const DMSTATUS_ALLUNAVAIL: u32 = 1 << 13;
+const DMSTATUS_ANYUNAVAIL: u32 = 1 << 12;
+const DMSTATUS_ALLRUNNING: u32 = 1 << 11;
+const DMSTATUS_ANYRUNNING: u32 = 1 << 10;
+const DMSTATUS_ALLHALTED: u32 = 1 << 9;
+const DMSTATUS_ANYHALTED: u32 = 1 << 8;
+const DMSTATUS_AUTHENTICATED: u32 = 1 << 7;
+const DMSTATUS_HASRESETHALTREQ: u32 = 1 << 5;
+/// Debug specification 1.0.
+const DMSTATUS_VERSION: u32 = 3;
+
+const ABSTRACTCS_CMDERR_SHIFT: u32 = 8;
+
+const CMDTYPE_ACCESS_REGISTER: u32 = 0;
+const CMDTYPE_ACCESS_MEMORY: u32 = 2;
+
+const COMMAND_AAMVIRTUAL: u32 = 1 << 23;
+const COMMAND_POSTINCREMENT: u32 = 1 << 19;
+const COMMAND_POSTEXEC: u32 = 1 << 18;
+const COMMAND_TRANSFER: u32 = 1 << 17;
+const COMMAND_WRITE: u32 = 1 << 16;
+
+const REGNO_CSR_END: u32 = 0x1000;
+const REGNO_GPR: u32 = 0x1000;
+const REGNO_FPR: u32 = 0x1020;
+const REGNO_FPR_END: u32 = 0x1040;
+
+const DCSR: u32 = 0x7b0;
+const DPC: u32 = 0x7b1;
+
+/// Debug specification 1.0.
+const DCSR_DEBUGVER: u32 = 4 << 28;
+const DCSR_EBREAKM: u32 = 1 << 15;
+const DCSR_EBREAKS: u32 = 1 << 13;
+const DCSR_EBREAKU: u32 = 1 << 12;
+const DCSR_STEPIE: u32 = 1 << 11;
+/// Counters and timers only advance with the instructions, and none run in Debug Mode.
+const DCSR_STOPCOUNT: u32 = 1 << 10;
+const DCSR_STOPTIME: u32 = 1 << 9;
+const DCSR_CAUSE_SHIFT: u32 = 6;
+const DCSR_STEP: u32 = 1 << 2;
+const DCSR_WRITABLE: u32 = DCSR_EBREAKM | DCSR_EBREAKS | DCSR_EBREAKU | DCSR_STEPIE | DCSR_STEP;
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u32 = 0x9002;
+
+/// Why the hart entered Debug Mode, `dcsr.cause`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltCause {
+    Ebreak = 1,
+    HaltReq = 3,
+    Step = 4,
+    ResetHaltReq = 5,
+}
+
+/// `abstractcs.cmderr`, sticky until the debugger clears it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmdErr {
+    None = 0,
+    NotSupported = 2,
+    Exception = 3,
+    HaltResume = 4,
+}
+
+pub struct DebugModule<'a, B: Board> {
+    dbg: Debugger<'a, B>,
+
+    dmactive: bool,
+    hartsel: u32,
+    ndmreset: bool,
+    haltreq: bool,
+    resethaltreq: bool,
+
+    halted: bool,
+    cause: HaltCause,
+    resumeack: bool,
+    havereset: bool,
+    /// The board is powered off.
+    unavailable: bool,
+    /// Writable fields of `dcsr`, `prv` is the privilege level of the hart.
+    dcsr: u32,
+
+    data: [u32; DATA_COUNT],
+    cmderr: CmdErr,
+}
+
+impl<'a, B: Board> DebugModule<'a, B> {
+    pub fn new(board: &'a mut B) -> Self {
+        Self {
+            dbg: Debugger::new(board),
+            dmactive: false,
+            hartsel: 0,
+            ndmreset: false,
+            haltreq: false,
+            resethaltreq: false,
+            halted: false,
+            cause: HaltCause::HaltReq,
+            resumeack: false,
+            havereset: true,
+            unavailable: false,
+            dcsr: 0,
+            data: [0; DATA_COUNT],
+            cmderr: CmdErr::None,
+        }
+    }
+
+    pub fn debugger(&mut self) -> &mut Debugger<'a, B> {
+        &mut self.dbg
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Whether the hart executes instructions: it is neither halted, nor in reset, nor powered
+    /// off.
+    pub fn running(&self) -> bool {
+        !self.halted && !self.unavailable && !self.ndmreset
+    }
+
+    /// Execute up to `max_steps` instructions while the hart runs, returns how many were.
+    pub fn run(&mut self, max_steps: u64) -> Result<u64, DebugError> {
+        let mut steps = 0;
+        while steps < max_steps && self.running() {
+            if self.on_ebreak() {
+                self.enter_debug_mode(HaltCause::Ebreak);
+                break;
+            }
+
+            let single_step = self.dcsr & DCSR_STEP != 0;
+            self.dbg
+                .set_step_over_interrupts(single_step && self.dcsr & DCSR_STEPIE == 0);
+            let event = self.dbg.step()?;
+            steps += 1;
+            match event {
+                DebugEvent::BoardHalted => self.unavailable = true,
+                DebugEvent::BoardBreak(_) => self.enter_debug_mode(HaltCause::HaltReq),
+                _ if single_step => self.enter_debug_mode(HaltCause::Step),
+                _ => {}
+            }
+        }
+        Ok(steps)
+    }
+
+    pub fn dmi_read(&mut self, addr: u32) -> u32 {
+        match addr {
+            _ if (DATA0..DATA0 + DATA_COUNT as u32).contains(&addr) => {
+                self.data[(addr - DATA0) as usize]
+            }
+            DMCONTROL => {
+                let mut value = self.hartsel << DMCONTROL_HARTSELLO_SHIFT;
+                if self.ndmreset {
+                    value |= DMCONTROL_NDMRESET;
+                }
+                if self.dmactive {
+                    value |= DMCONTROL_DMACTIVE;
+                }
+                value
+            }
+            DMSTATUS => self.dmstatus(),
+            HARTINFO => 0,
+            ABSTRACTCS => (self.cmderr as u32) << ABSTRACTCS_CMDERR_SHIFT | DATA_COUNT as u32,
+            HALTSUM0 => (self.selected() && self.halted) as u32,
+            _ => 0,
+        }
+    }
+
+    pub fn dmi_write(&mut self, addr: u32, value: u32) {
+        if addr == DMCONTROL {
+            self.write_dmcontrol(value);
+            return;
+        }
+        if !self.dmactive {
+            return;
+        }
+        match addr {
+            _ if (DATA0..DATA0 + DATA_COUNT as u32).contains(&addr) => {
+                self.data[(addr - DATA0) as usize] = value;
+            }
+            ABSTRACTCS => {
+                // Write 1 to clear.
+                if self.cmderr as u32 & !(value >> ABSTRACTCS_CMDERR_SHIFT) == 0 {
+                    self.cmderr = CmdErr::None;
+                }
+            }
+            COMMAND => {
+                // Commands are ignored until the error of the previous one is cleared.
+                if self.cmderr == CmdErr::None
+                    && let Err(err) = self.execute(value)
+                {
+                    self.cmderr = err;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the selected hart exists.
+    fn selected(&self) -> bool {
+        self.hartsel == 0
+    }
+
+    fn dmstatus(&mut self) -> u32 {
+        let mut value = DMSTATUS_AUTHENTICATED | DMSTATUS_HASRESETHALTREQ | DMSTATUS_VERSION;
+        if !self.selected() {
+            return value | DMSTATUS_ANYNONEXISTENT | DMSTATUS_ALLNONEXISTENT;
+        }
+        value |= if self.unavailable || self.ndmreset {
+            DMSTATUS_ANYUNAVAIL | DMSTATUS_ALLUNAVAIL
+        } else if self.halted {
+            DMSTATUS_ANYHALTED | DMSTATUS_ALLHALTED
+        } else {
+            DMSTATUS_ANYRUNNING | DMSTATUS_ALLRUNNING
+        };
+        if self.resumeack {
+            value |= DMSTATUS_ANYRESUMEACK | DMSTATUS_ALLRESUMEACK;
+        }
+        if self.havereset {
+            value |= DMSTATUS_ANYHAVERESET | DMSTATUS_ALLHAVERESET;
+        }
+        value
+    }
+
+    fn write_dmcontrol(&mut self, value: u32) {
+        if value & DMCONTROL_DMACTIVE == 0 {
+            // Reset the DM, the hart keeps its state.
+            self.dmactive = false;
+            self.hartsel = 0;
+            self.ndmreset = false;
+            self.haltreq = false;
+            self.resethaltreq = false;
+            self.data = [0; DATA_COUNT];
+            self.cmderr = CmdErr::None;
+            return;
+        }
+        self.dmactive = true;
+        self.hartsel = (value >> DMCONTROL_HARTSELLO_SHIFT) & DMCONTROL_HARTSELLO_MASK;
+
+        // The system stays in reset while `ndmreset` is set.
+        let ndmreset = value & DMCONTROL_NDMRESET != 0;
+        if ndmreset && !self.ndmreset {
+            self.dbg.reset(false).unwrap();
+            self.halted = false;
+            self.unavailable = false;
+            self.dcsr = 0;
+        } else if !ndmreset && self.ndmreset {
+            self.havereset = true;
+            if self.resethaltreq {
+                self.enter_debug_mode(HaltCause::ResetHaltReq);
+            }
+        }
+        self.ndmreset = ndmreset;
+
+        if !self.selected() {
+            return;
+        }
+        if value & DMCONTROL_ACKHAVERESET != 0 {
+            self.havereset = false;
+        }
+        if value & DMCONTROL_SETRESETHALTREQ != 0 {
+            self.resethaltreq = true;
+        } else if value & DMCONTROL_CLRRESETHALTREQ != 0 {
+            self.resethaltreq = false;
+        }
+
+        // The hart is between two instructions, it halts right away.
+        self.haltreq = value & DMCONTROL_HALTREQ != 0;
+        if self.haltreq && self.running() {
+            self.enter_debug_mode(HaltCause::HaltReq);
+        } else if !self.haltreq && value & DMCONTROL_RESUMEREQ != 0 {
+            // The hart resumes at `dpc` in the privilege level of `dcsr.prv`, which are its own.
+            self.halted = false;
+            self.resumeack = true;
+        }
+    }
+
+    fn enter_debug_mode(&mut self, cause: HaltCause) {
+        log::debug!(
+            "[DM] halted at {:#x}, cause {:?}",
+            self.dbg.read_pc(),
+            cause
+        );
+        self.halted = true;
+        self.cause = cause;
+        self.resumeack = false;
+    }
+
+    /// Whether the next instruction is an `ebreak` which enters Debug Mode.
+    fn on_ebreak(&mut self) -> bool {
+        let enabled = match self.dbg.get_current_privilege() {
+            PrivilegeLevel::M => DCSR_EBREAKM,
+            PrivilegeLevel::S => DCSR_EBREAKS,
+            _ => DCSR_EBREAKU,
+        };
+        self.dcsr & enabled != 0
+            && self
+                .dbg
+                .next_instr()
+                .is_some_and(|instr| instr.val == EBREAK || instr.val == C_EBREAK)
+    }
+
+    fn read_dcsr(&mut self) -> u32 {
+        DCSR_DEBUGVER
+            | DCSR_STOPCOUNT
+            | DCSR_STOPTIME
+            | self.dcsr
+            | (self.cause as u32) << DCSR_CAUSE_SHIFT
+            | self.dbg.get_current_privilege() as u32
+    }
+
+    fn write_dcsr(&mut self, value: u32) {
+        self.dcsr = value & DCSR_WRITABLE;
+        // `prv` is WARL, there is no hypervisor.
+        match PrivilegeLevel::try_from((value & 0x3) as u8) {
+            Ok(PrivilegeLevel::V) | Err(_) => {}
+            Ok(prv) => self.dbg.set_current_privilege(prv),
+        }
+    }
+
+    /// XLEN-bit argument `index` of the abstract commands.
+    fn arg(&self, index: usize) -> u64 {
+        match XLEN {
+            32 => self.data[index] as u64,
+            _ => self.data[index * 2] as u64 | (self.data[index * 2 + 1] as u64) << 32,
+        }
+    }
+
+    fn set_arg(&mut self, index: usize, value: u64) {
+        match XLEN {
+            32 => self.data[index] = value as u32,
+            _ => {
+                self.data[index * 2] = value as u32;
+                self.data[index * 2 + 1] = (value >> 32) as u32;
+            }
+        }
+    }
+
+    fn execute(&mut self, command: u32) -> Result<(), CmdErr> {
+        match command >> 24 {
+            CMDTYPE_ACCESS_REGISTER | CMDTYPE_ACCESS_MEMORY if !self.selected() || !self.halted => {
+                Err(CmdErr::HaltResume)
+            }
+            CMDTYPE_ACCESS_REGISTER => self.access_register(command),
+            CMDTYPE_ACCESS_MEMORY => self.access_memory(command),
+            _ => Err(CmdErr::NotSupported),
+        }
+    }
+
+    fn access_register(&mut self, command: u32) -> Result<(), CmdErr> {
+        let bits = 8usize << ((command >> 20) & 0x7);
+        // There is no program buffer to execute.
+        if !(32..=XLEN).contains(&bits) || command & COMMAND_POSTEXEC != 0 {
+            return Err(CmdErr::NotSupported);
+        }
+        if command & COMMAND_TRANSFER == 0 {
+            return Ok(());
+        }
+
+        let regno = command & 0xffff;
+        let mask = u64::MAX >> (64 - bits);
+        if command & COMMAND_WRITE != 0 {
+            self.write_register(regno, self.arg(0) & mask)?;
+        } else {
+            let value = self.read_register(regno)?;
+            self.set_arg(0, value & mask);
+        }
+        Ok(())
+    }
+
+    fn has_fpu(&mut self) -> bool {
+        let misa = self.dbg.read_csr(Misa::get_index()).unwrap_or(0);
+        misa & (1 << (b'F' - b'A')) != 0
+    }
+
+    fn read_register(&mut self, regno: u32) -> Result<u64, CmdErr> {
+        Ok(match regno {
+            DCSR => self.read_dcsr() as u64,
+            DPC => self.dbg.read_pc() as _,
+            ..REGNO_CSR_END => self
+                .dbg
+                .read_csr(regno as WordType)
+                .ok_or(CmdErr::Exception)? as _,
+            REGNO_GPR..REGNO_FPR => self.dbg.read_reg((regno - REGNO_GPR) as u8) as _,
+            REGNO_FPR..REGNO_FPR_END if self.has_fpu() => self
+                .dbg
+                .read_float_reg((regno - REGNO_FPR) as u8)
+                .1
+                .to_bits(),
+            _ => return Err(CmdErr::Exception),
+        })
+    }
+
+    fn write_register(&mut self, regno: u32, value: u64) -> Result<(), CmdErr> {
+        match regno {
+            DCSR => self.write_dcsr(value as u32),
+            DPC => self.dbg.write_pc(value as WordType),
+            ..REGNO_CSR_END => self
+                .dbg
+                .write_csr(regno as WordType, value as WordType)
+                .map_err(|_| CmdErr::Exception)?,
+            REGNO_GPR..REGNO_FPR => self
+                .dbg
+                .write_reg((regno - REGNO_GPR) as u8, value as WordType),
+            REGNO_FPR..REGNO_FPR_END if self.has_fpu() => self
+                .dbg
+                .write_float_reg((regno - REGNO_FPR) as u8, f64::from_bits(value)),
+            _ => return Err(CmdErr::Exception),
+        }
+        Ok(())
+    }
+
+    fn access_memory(&mut self, command: u32) -> Result<(), CmdErr> {
+        let size = (command >> 20) & 0x7;
+        if 8 << size > XLEN {
+            return Err(CmdErr::NotSupported);
+        }
+
+        let addr = self.arg(1);
+        let target = if command & COMMAND_AAMVIRTUAL != 0 {
+            Address::Virt(addr as WordType)
+        } else {
+            Address::Phys(addr)
+        };
+        let write = command & COMMAND_WRITE != 0;
+        match size {
+            0 => self.transfer_memory::<u8>(target, write),
+            1 => self.transfer_memory::<u16>(target, write),
+            2 => self.transfer_memory::<u32>(target, write),
+            _ => self.transfer_memory::<u64>(target, write),
+        }
+        .map_err(|_| CmdErr::Exception)?;
+
+        if command & COMMAND_POSTINCREMENT != 0 {
+            self.set_arg(1, addr.wrapping_add(1 << size));
+        }
+        Ok(())
+    }
+
+    fn transfer_memory<V: UnsignedInteger>(
+        &mut self,
+        addr: Address,
+        write: bool,
+    ) -> Result<(), MemError> {
+        if write {
+            self.dbg.write_memory(addr, V::truncate_from(self.arg(0)))
+        } else {
+            let value: V = self.dbg.read_memory(addr)?;
+            self.set_arg(0, value.into());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{board::virt::RVBoardBuilder, ram::Ram, ram_config::BASE_ADDR};
+
+    const NOP: u32 = 0x0000_0013;
+    const ADDI_X5_X5_1: u32 = 0x0012_8293;
+    const ACCESS_REGISTER_64: u32 = 3 << 20 | COMMAND_TRANSFER;
+    const ACCESS_MEMORY_32: u32 = CMDTYPE_ACCESS_MEMORY << 24 | 2 << 20;
+
+    fn board(program: &[u32]) -> crate::board::virt::VirtBoard {
+        let mut ram = Ram::new();
+        for (i, instr) in program.iter().enumerate() {
+            ram.write::<u32>(i as u64 * 4, *instr).unwrap();
+        }
+        RVBoardBuilder::new().build(ram)
+    }
+
+    fn halt<B: Board>(dm: &mut DebugModule<B>) {
+        dm.dmi_write(DMCONTROL, DMCONTROL_HALTREQ | DMCONTROL_DMACTIVE);
+        dm.run(1).unwrap();
+        assert_ne!(dm.dmi_read(DMSTATUS) & DMSTATUS_ALLHALTED, 0);
+        dm.dmi_write(DMCONTROL, DMCONTROL_DMACTIVE);
+    }
+
+    fn read_register<B: Board>(dm: &mut DebugModule<B>, regno: u32) -> u64 {
+        dm.dmi_write(COMMAND, ACCESS_REGISTER_64 | regno);
+        assert_eq!(dm.dmi_read(ABSTRACTCS) >> ABSTRACTCS_CMDERR_SHIFT & 0x7, 0);
+        dm.dmi_read(DATA0) as u64 | (dm.dmi_read(DATA0 + 1) as u64) << 32
+    }
+
+    #[test]
+    fn test_dm_halt_resume() {
+        let mut board = board(&[ADDI_X5_X5_1, ADDI_X5_X5_1, ADDI_X5_X5_1, NOP]);
+        let mut dm = DebugModule::new(&mut board);
+        dm.dmi_write(DMCONTROL, DMCONTROL_DMACTIVE);
+        let status = dm.dmi_read(DMSTATUS);
+        assert_eq!(status & 0xf, DMSTATUS_VERSION);
+        assert_ne!(status & DMSTATUS_ALLRUNNING, 0);
+        assert_eq!(dm.dmi_read(ABSTRACTCS) & 0xf, DATA_COUNT as u32);
+
+        // Commands need a halted hart.
+        dm.dmi_write(COMMAND, ACCESS_REGISTER_64 | DPC);
+        assert_eq!(
+            dm.dmi_read(ABSTRACTCS) >> ABSTRACTCS_CMDERR_SHIFT,
+            CmdErr::HaltResume as u32
+        );
+        dm.dmi_write(ABSTRACTCS, 0x7 << ABSTRACTCS_CMDERR_SHIFT);
+
+        assert_eq!(dm.run(1).unwrap(), 1);
+        halt(&mut dm);
+        assert_eq!(dm.run(10).unwrap(), 0);
+        assert_eq!(dm.dmi_read(HALTSUM0), 1);
+        assert_eq!(read_register(&mut dm, DPC), BASE_ADDR + 4);
+        assert_eq!(read_register(&mut dm, REGNO_GPR + 5), 1);
+        let dcsr = read_register(&mut dm, DCSR) as u32;
+        assert_eq!(dcsr >> DCSR_CAUSE_SHIFT & 0x7, HaltCause::HaltReq as u32);
+        assert_eq!(dcsr & 0x3, PrivilegeLevel::M as u32);
+
+        // Single step.
+        dm.dmi_write(DATA0, DCSR_STEP | PrivilegeLevel::M as u32);
+        dm.dmi_write(COMMAND, ACCESS_REGISTER_64 | COMMAND_WRITE | DCSR);
+        dm.dmi_write(DMCONTROL, DMCONTROL_RESUMEREQ | DMCONTROL_DMACTIVE);
+        assert_ne!(dm.dmi_read(DMSTATUS) & DMSTATUS_ALLRESUMEACK, 0);
+        assert_eq!(dm.run(10).unwrap(), 1);
+        assert!(dm.halted());
+        let dcsr = read_register(&mut dm, DCSR) as u32;
+        assert_eq!(dcsr >> DCSR_CAUSE_SHIFT & 0x7, HaltCause::Step as u32);
+        assert_eq!(read_register(&mut dm, REGNO_GPR + 5), 2);
+
+        // Resume at a new `dpc`, in S-mode.
+        dm.dmi_write(DATA0, PrivilegeLevel::S as u32);
+        dm.dmi_write(COMMAND, ACCESS_REGISTER_64 | COMMAND_WRITE | DCSR);
+        dm.dmi_write(DATA0, BASE_ADDR as u32);
+        dm.dmi_write(COMMAND, ACCESS_REGISTER_64 | COMMAND_WRITE | DPC);
+        dm.dmi_write(DMCONTROL, DMCONTROL_RESUMEREQ | DMCONTROL_DMACTIVE);
+        assert_eq!(dm.run(3).unwrap(), 3);
+        assert_eq!(dm.debugger().read_reg(5), 5);
+        assert_eq!(dm.debugger().get_current_privilege(), PrivilegeLevel::S);
+    }
+
+    #[test]
+    fn test_dm_ebreak() {
+        let mut board = board(&[NOP, EBREAK]);
+        let mut dm = DebugModule::new(&mut board);
+        halt(&mut dm);
+        dm.dmi_write(DATA0, DCSR_EBREAKM | PrivilegeLevel::M as u32);
+        dm.dmi_write(COMMAND, ACCESS_REGISTER_64 | COMMAND_WRITE | DCSR);
+        dm.dmi_write(DMCONTROL, DMCONTROL_RESUMEREQ | DMCONTROL_DMACTIVE);
+
+        // The hart stops on the `ebreak` instead of taking the exception.
+        assert_eq!(dm.run(10).unwrap(), 1);
+        assert_eq!(dm.debugger().read_pc(), BASE_ADDR + 4);
+        let dcsr = read_register(&mut dm, DCSR) as u32;
+        assert_eq!(dcsr >> DCSR_CAUSE_SHIFT & 0x7, HaltCause::Ebreak as u32);
+    }
+
+    #[test]
+    fn test_dm_memory_access() {
+        let mut board = board(&[NOP]);
+        let mut dm = DebugModule::new(&mut board);
+        halt(&mut dm);
+
+        // Write two words with post-increment, then read them back.
+        let addr = BASE_ADDR + 0x100;
+        dm.dmi_write(DATA0 + 2, addr as u32);
+        dm.dmi_write(DATA0 + 3, (addr >> 32) as u32);
+        for word in [0x1234_5678, 0x9abc_def0] {
+            dm.dmi_write(DATA0, word);
+            dm.dmi_write(
+                COMMAND,
+                ACCESS_MEMORY_32 | COMMAND_WRITE | COMMAND_POSTINCREMENT,
+            );
+        }
+        assert_eq!(dm.dmi_read(DATA0 + 2), addr as u32 + 8);
+        assert_eq!(
+            dm.debugger().read_memory::<u64>(Address::Phys(addr)),
+            Ok(0x9abc_def0_1234_5678)
+        );
+
+        dm.dmi_write(DATA0 + 2, (addr + 4) as u32);
+        dm.dmi_write(COMMAND, ACCESS_MEMORY_32);
+        assert_eq!(dm.dmi_read(DATA0), 0x9abc_def0);
+
+        // A bus error is reported as an exception, and sticks.
+        dm.dmi_write(DATA0 + 2, 0);
+        dm.dmi_write(DATA0 + 3, 0);
+        dm.dmi_write(COMMAND, ACCESS_MEMORY_32);
+        assert_eq!(
+            dm.dmi_read(ABSTRACTCS) >> ABSTRACTCS_CMDERR_SHIFT,
+            CmdErr::Exception as u32
+        );
+        dm.dmi_write(DATA0 + 2, addr as u32);
+        dm.dmi_write(DATA0 + 3, (addr >> 32) as u32);
+        dm.dmi_write(COMMAND, ACCESS_MEMORY_32);
+        assert_eq!(dm.dmi_read(DATA0), 0x9abc_def0);
+    }
+
+    #[test]
+    fn test_dm_reset_halt() {
+        let mut board = board(&[ADDI_X5_X5_1, NOP]);
+        let mut dm = DebugModule::new(&mut board);
+        dm.dmi_write(DMCONTROL, DMCONTROL_DMACTIVE);
+        assert_ne!(dm.dmi_read(DMSTATUS) & DMSTATUS_ALLHAVERESET, 0);
+        dm.dmi_write(DMCONTROL, DMCONTROL_ACKHAVERESET | DMCONTROL_DMACTIVE);
+        assert_eq!(dm.dmi_read(DMSTATUS) & DMSTATUS_ANYHAVERESET, 0);
+        dm.run(1).unwrap();
+
+        dm.dmi_write(DMCONTROL, DMCONTROL_SETRESETHALTREQ | DMCONTROL_DMACTIVE);
+        dm.dmi_write(DMCONTROL, DMCONTROL_NDMRESET | DMCONTROL_DMACTIVE);
+        assert_ne!(dm.dmi_read(DMSTATUS) & DMSTATUS_ALLUNAVAIL, 0);
+        assert_eq!(dm.run(1).unwrap(), 0);
+        dm.dmi_write(DMCONTROL, DMCONTROL_DMACTIVE);
+
+        // Halted before the first instruction.
+        assert!(dm.halted());
+        assert_ne!(dm.dmi_read(DMSTATUS) & DMSTATUS_ALLHAVERESET, 0);
+        assert_eq!(read_register(&mut dm, DPC), BASE_ADDR);
+        let dcsr = read_register(&mut dm, DCSR) as u32;
+        assert_eq!(
+            dcsr >> DCSR_CAUSE_SHIFT & 0x7,
+            HaltCause::ResetHaltReq as u32
+        );
+
+        // A hart other than 0 does not exist.
+        dm.dmi_write(
+            DMCONTROL,
+            1 << DMCONTROL_HARTSELLO_SHIFT | DMCONTROL_DMACTIVE,
+        );
+        assert_ne!(dm.dmi_read(DMSTATUS) & DMSTATUS_ALLNONEXISTENT, 0);
+    }
+}
@@ -1,24 +1,39 @@
 use std::{
     collections::{BTreeMap, VecDeque},
     fmt::Debug,
-    ops::Add,
+    ops::{Add, Range},
     u64,
 };
 
 use crate::{
-    board::Board,
+    DeviceConfig,
+    board::{Board, HotplugError, HotplugInfo},
     config::arch_config::WordType,
-    device::MemError,
+    device::{
+        FaultConfig, MemError, MemoryRegion,
+        plic::ExternalInterrupt,
+        sifive_gpio::{GPIO_PINS, GpioState},
+        stats::DeviceStats,
+    },
     isa::{
         DebugTarget, ISATypes,
         riscv::{
             RawInstr, RiscvTypes,
-            csr_reg::{NamedCsrReg, PrivilegeLevel, csr_macro::Mcycle},
+            alloc_track::{AllocHook, AllocTracker},
+            csr_reg::{
+                NamedCsrReg, PrivilegeLevel,
+                csr_macro::{Mcycle, Satp},
+            },
             decoder::DecodeInstr,
             executor::{ExcuteInstrInfo, RVCPU},
+            instr_stats::{InstrStats, IstatsSnapshot},
             instruction::{RVInstrInfo, instr_table::RiscvInstr},
-            mmu::{AccessType, PageTableError},
-            trap::Exception,
+            mmu::{
+                AccessType, PageTableError,
+                config::{PAGE_SIZE_XLEN, satp_mode_name},
+            },
+            taint::TaintTracker,
+            trap::{Exception, trap_log::TrapRecord},
         },
     },
     load::SymTab,
@@ -30,6 +45,8 @@ pub enum DebugEvent {
     StepCompleted,
     BreakpointHit,
     BoardHalted,
+    /// The board asked to break, see [`Board::take_break`].
+    BoardBreak(String),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -48,6 +65,27 @@ pub enum DebugError {
 
     #[error("symbol table not available")]
     NoSymbolTable,
+
+    #[error("interrupt source {0} not exist")]
+    IrqNotExist(ExternalInterrupt),
+
+    #[error("{0}")]
+    Hotplug(#[from] HotplugError),
+
+    #[error("the board keeps no boot images to reload")]
+    NoBootImage,
+
+    #[error("the board has no UART")]
+    NoUart,
+
+    #[error("the board has no GPIO controller")]
+    NoGpio,
+
+    #[error("GPIO pin {0} not exist")]
+    GpioPinNotExist(u32),
+
+    #[error("hart {0} not exist")]
+    HartNotExist(usize),
 }
 
 impl From<MemError> for DebugError {
@@ -76,21 +114,12 @@ impl DebugTarget<RiscvTypes> for RVCPU {
         self.reg_file.write(idx, value)
     }
 
-    /// Fetch the instruction at `vaddr`, respecting its length.
-    ///
-    /// The low 16 bits are read first to learn whether the instruction is a
-    /// 2-byte RVC instruction or a full 4-byte one. This avoids a misaligned
-    /// 32-bit fetch when a 4-byte instruction sits at a 2-byte boundary (e.g.
-    /// right after a compressed instruction).
+    /// Fetch the instruction at `vaddr`, respecting its length, see
+    /// [`VirtAddrManager::ifetch`](crate::isa::riscv::mmu::VirtAddrManager::ifetch).
     fn read_instr(&mut self, vaddr: WordType) -> Result<RawInstr, MemError> {
-        let lo = self.memory.debug_ifetch::<u16>(vaddr, &mut self.csr)? as u32;
-        if lo & 0b11 != 0b11 {
-            return Ok(RawInstr::from(lo));
-        }
-        let hi = self
-            .memory
-            .debug_ifetch::<u16>(vaddr.wrapping_add(2), &mut self.csr)? as u32;
-        Ok(RawInstr::from(lo | (hi << 16)))
+        Ok(RawInstr::from(
+            self.memory.debug_ifetch(vaddr, &mut self.csr)?,
+        ))
     }
 
     fn read_instr_directly(&mut self, addr: Address) -> Result<RawInstr, MemError> {
@@ -180,10 +209,32 @@ impl Add<u64> for Address {
     }
 }
 
+/// A byte which differs between two ranges compared by [`Debugger::compare_memory`], `None`
+/// where the memory can't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemDiff {
+    pub offset: u64,
+    pub left: Option<u8>,
+    pub right: Option<u8>,
+}
+
+/// The address translation state of the hart, from `satp` and the TLB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmInfo {
+    /// `Bare`, `Sv39`, `Sv48` or `Sv57`.
+    pub mode: &'static str,
+    pub root: WordType,
+    pub asid: u16,
+    /// Translations cached for `asid`, global ones included.
+    pub tlb_entries: usize,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Breakpoint {
     pub id: usize,
     pub addr: Address,
+    /// The hart it stops, `None` for every hart.
+    pub hart: Option<usize>,
     // TODO: add symbol_name: Option<String> for better user experience
 }
 
@@ -323,11 +374,19 @@ pub struct Debugger<'a, B: Board> {
     history: VecDeque<(WordType, Option<RawInstr>)>,
     ftrace: FtraceState,
     symtab: Option<SymTab>,
+    step_over_interrupts: bool,
+    alloc: AllocTracker,
+    /// See [`Self::select_hart`].
+    hart: usize,
 }
 
 impl<'a, B: Board> Debugger<'a, B> {
     pub fn new(board: &'a mut B) -> Self {
-        board.cpu_mut().debug = true;
+        for id in 0..board.hart_count() {
+            let cpu = board.hart_mut(id).unwrap();
+            cpu.debug = true;
+            cpu.enable_instr_stats();
+        }
         let symtab = board.loader().and_then(|loader| loader.get_symbol_table());
 
         Self {
@@ -336,7 +395,45 @@ impl<'a, B: Board> Debugger<'a, B> {
             history: VecDeque::with_capacity(MAX_HISTORY),
             ftrace: FtraceState::new(),
             symtab: symtab,
+            step_over_interrupts: false,
+            alloc: AllocTracker::new(),
+            hart: 0,
+        }
+    }
+
+    /// The selected hart, which the register, memory and CSR accesses go to.
+    fn cpu(&self) -> &RVCPU {
+        self.board.hart(self.hart).unwrap()
+    }
+
+    fn cpu_mut(&mut self) -> &mut RVCPU {
+        self.board.hart_mut(self.hart).unwrap()
+    }
+
+    pub fn hart(&self) -> usize {
+        self.hart
+    }
+
+    pub fn hart_count(&self) -> usize {
+        self.board.hart_count()
+    }
+
+    /// The pc and the privilege level of every hart.
+    pub fn harts(&mut self) -> Vec<(WordType, PrivilegeLevel)> {
+        (0..self.board.hart_count())
+            .map(|id| {
+                let cpu = self.board.hart_mut(id).unwrap();
+                (cpu.read_pc(), cpu.get_current_privilege())
+            })
+            .collect()
+    }
+
+    pub fn select_hart(&mut self, id: usize) -> Result<(), DebugError> {
+        if id >= self.board.hart_count() {
+            return Err(DebugError::HartNotExist(id));
         }
+        self.hart = id;
+        Ok(())
     }
 
     pub fn set_symbol_table(&mut self, symtab: SymTab) {
@@ -429,6 +526,19 @@ impl<'a, B: Board> Debugger<'a, B> {
         self.ftrace.snapshot()
     }
 
+    /// Track the allocations made through `hook`, returns false if its allocator is hooked already.
+    pub fn add_alloc_hook(&mut self, hook: AllocHook) -> bool {
+        self.alloc.add_hook(hook)
+    }
+
+    pub fn alloc_tracker(&self) -> &AllocTracker {
+        &self.alloc
+    }
+
+    pub fn clear_alloc_records(&mut self) {
+        self.alloc.clear();
+    }
+
     pub fn breakpoints(&self) -> &Vec<Breakpoint> {
         &self.breakpoints
     }
@@ -471,45 +581,87 @@ impl<'a, B: Board> Debugger<'a, B> {
     }
 
     /// Returns true if a new breakpoint is added, otherwise the breakpoint already exists.
-    pub fn set_breakpoint(&mut self, addr: Address) -> Result<bool, DebugError> {
-        if let Some(_) = self.breakpoints.iter().find(|bp| bp.addr == addr) {
+    pub fn set_breakpoint(
+        &mut self,
+        addr: Address,
+        hart: Option<usize>,
+    ) -> Result<bool, DebugError> {
+        if let Some(id) = hart
+            && id >= self.board.hart_count()
+        {
+            return Err(DebugError::HartNotExist(id));
+        }
+        if self
+            .breakpoints
+            .iter()
+            .any(|bp| bp.addr == addr && bp.hart == hart)
+        {
             return Ok(false);
         }
         let breakpoint = Breakpoint {
             id: self.breakpoints.len(),
             addr,
+            hart,
         };
         self.breakpoints.push(breakpoint);
 
         Ok(true)
     }
 
-    /// Returns true if any breakpoint is removed.
+    /// Returns true if any breakpoint is removed, those of every hart at `addr` are.
     pub fn clear_breakpoint(&mut self, addr: Address) -> Result<bool, DebugError> {
         let original_len = self.breakpoints.len();
         self.breakpoints.retain(|bp| bp.addr != addr);
         Ok(self.breakpoints.len() != original_len)
     }
 
+    /// Whether a hart is at one of its breakpoints, the first one found is selected.
     pub fn on_breakpoint(&mut self) -> bool {
-        let pc = self.read_pc();
-        if let Ok(pc_paddr) = self.board.cpu_mut().debug_vaddr_to_paddr(pc) {
-            // TOOD: Totally useless clone to bypass the borrow checker, because `unify_to_phys_addr` needs a mutable reference now
-            self.breakpoints
-                .clone()
-                .iter()
-                .find(|bp| self.unify_to_phys_addr(bp.addr) == Some(pc_paddr))
-                .is_some()
-        } else {
-            false
+        for id in 0..self.board.hart_count() {
+            if self.hart_on_breakpoint(id) {
+                self.hart = id;
+                return true;
+            }
         }
+        false
+    }
+
+    fn hart_on_breakpoint(&mut self, id: usize) -> bool {
+        let cpu = self.board.hart_mut(id).unwrap();
+        let Ok(pc_paddr) = cpu.debug_vaddr_to_paddr(cpu.read_pc()) else {
+            return false;
+        };
+        self.breakpoints.iter().any(|bp| {
+            bp.hart.is_none_or(|hart| hart == id)
+                && match bp.addr {
+                    Address::Phys(paddr) => paddr == pc_paddr,
+                    Address::Virt(vaddr) => cpu.debug_vaddr_to_paddr(vaddr) == Ok(pc_paddr),
+                }
+        })
     }
 
+    /// Execute one instruction. With [`Self::set_step_over_interrupts`] pending interrupts are
+    /// not taken, so the step lands on the next instruction of the interrupted code.
     pub fn step(&mut self) -> Result<DebugEvent, DebugError> {
-        self.continue_until_step(1).map(|(event, _steps)| event)
+        self.cpu_mut().mask_interrupts = self.step_over_interrupts;
+        let rst = self.continue_until_step(1).map(|(event, _steps)| event);
+        self.cpu_mut().mask_interrupts = false;
+        rst
+    }
+
+    /// Keep interrupts pending during [`Self::step`], they are taken on the next `continue`.
+    pub fn set_step_over_interrupts(&mut self, enabled: bool) {
+        self.step_over_interrupts = enabled;
+    }
+
+    pub fn step_over_interrupts(&self) -> bool {
+        self.step_over_interrupts
     }
 
     fn cpu_step_internal(&mut self) -> Result<(), DebugError> {
+        if self.board.status() == crate::board::BoardStatus::Resetting {
+            self.board.reset();
+        }
         self.push_history();
 
         let rst = self
@@ -523,6 +675,13 @@ impl<'a, B: Board> Debugger<'a, B> {
             }
         }
 
+        if !self.alloc.hooks().is_empty() {
+            let cycle = self.cycle();
+            let cpu = self.board.hart(self.hart).unwrap();
+            self.alloc
+                .on_step(cpu.read_pc(), |idx| cpu.read_reg(idx), cycle);
+        }
+
         rst
     }
 
@@ -542,6 +701,9 @@ impl<'a, B: Board> Debugger<'a, B> {
 
             self.cpu_step_internal()?;
 
+            if let Some(reason) = self.board.take_break() {
+                return Ok(Some(DebugEvent::BoardBreak(reason)));
+            }
             if self.on_breakpoint() {
                 return Ok(Some(DebugEvent::BreakpointHit));
             }
@@ -566,6 +728,9 @@ impl<'a, B: Board> Debugger<'a, B> {
 
             remain -= 1;
 
+            if let Some(reason) = self.board.take_break() {
+                return Ok((DebugEvent::BoardBreak(reason), max_steps - remain));
+            }
             if self.on_breakpoint() {
                 return Ok((DebugEvent::BreakpointHit, max_steps - remain));
             }
@@ -578,12 +743,12 @@ impl<'a, B: Board> Debugger<'a, B> {
     }
 
     pub fn last_instr_info(&self) -> ExcuteInstrInfo {
-        self.board.cpu().debug_info.last_instr.clone()
+        self.cpu().debug_info.last_instr.clone()
     }
 
     pub fn next_instr(&mut self) -> Option<RawInstr> {
-        let pc = self.board.cpu().read_pc();
-        self.board.cpu_mut().read_instr(pc).ok()
+        let pc = self.cpu().read_pc();
+        self.cpu_mut().read_instr(pc).ok()
     }
 
     pub fn unify_to_phys_addr(&mut self, addr: Address) -> Option<u64> {
@@ -597,39 +762,100 @@ impl<'a, B: Board> Debugger<'a, B> {
 
     // TODO: Add checks here.
     pub fn read_reg(&self, idx: u8) -> WordType {
-        self.board.cpu().read_reg(idx)
+        self.cpu().read_reg(idx)
     }
 
     pub fn write_reg(&mut self, idx: u8, val: WordType) {
-        self.board.cpu_mut().write_reg(idx, val)
+        self.cpu_mut().write_reg(idx, val)
     }
 
     pub fn read_pc(&self) -> WordType {
-        self.board.cpu().read_pc()
+        self.cpu().read_pc()
     }
 
     pub fn write_pc(&mut self, val: WordType) {
-        self.board.cpu_mut().write_pc(val)
+        self.cpu_mut().write_pc(val)
     }
 
     pub fn read_float_reg(&self, idx: u8) -> (f32, f64) {
-        self.board.cpu().read_float_reg(idx)
+        self.cpu().read_float_reg(idx)
     }
 
     pub fn write_float_reg(&mut self, idx: u8, value: f64) {
-        self.board.cpu_mut().fpu.store(idx, value);
+        self.cpu_mut().fpu.store(idx, value);
     }
 
     pub fn read_vector_reg<T>(&self, idx: u8) -> Option<&[T]> {
-        self.board.cpu().read_vector_reg(idx)
+        self.cpu().read_vector_reg(idx)
     }
 
     pub fn read_instr(&mut self, addr: WordType) -> Option<RawInstr> {
-        self.board.cpu_mut().read_instr(addr).ok()
+        self.cpu_mut().read_instr(addr).ok()
     }
 
     pub fn read_memory<V: UnsignedInteger>(&mut self, addr: Address) -> Result<V, MemError> {
-        self.board.cpu_mut().read_memory(addr)
+        self.cpu_mut().read_memory(addr)
+    }
+
+    /// The addresses where `pattern` starts in the `len` bytes from `start`, at most `max` of
+    /// them. Unreadable bytes match nothing.
+    pub fn find_memory(
+        &mut self,
+        start: Address,
+        len: u64,
+        pattern: &[u8],
+        max: usize,
+    ) -> Vec<Address> {
+        let mut found = Vec::new();
+        if pattern.is_empty() {
+            return found;
+        }
+        let mut window = VecDeque::with_capacity(pattern.len());
+        for offset in 0..len {
+            if window.len() == pattern.len() {
+                window.pop_front();
+            }
+            window.push_back(self.read_memory::<u8>(start + offset).ok());
+            if window.len() == pattern.len()
+                && window
+                    .iter()
+                    .zip(pattern)
+                    .all(|(byte, p)| *byte == Some(*p))
+            {
+                found.push(start + (offset + 1 - pattern.len() as u64));
+                if found.len() == max {
+                    break;
+                }
+            }
+        }
+        found
+    }
+
+    /// The number of bytes which differ between the `len` bytes from `left` and from `right`,
+    /// with the first `max` differences. Unreadable bytes always differ.
+    pub fn compare_memory(
+        &mut self,
+        left: Address,
+        right: Address,
+        len: u64,
+        max: usize,
+    ) -> (u64, Vec<MemDiff>) {
+        let mut count = 0;
+        let mut diffs = Vec::new();
+        for offset in 0..len {
+            let diff = MemDiff {
+                offset,
+                left: self.read_memory::<u8>(left + offset).ok(),
+                right: self.read_memory::<u8>(right + offset).ok(),
+            };
+            if diff.left != diff.right || diff.left.is_none() {
+                count += 1;
+                if diffs.len() < max {
+                    diffs.push(diff);
+                }
+            }
+        }
+        (count, diffs)
     }
 
     pub fn write_memory<V: UnsignedInteger>(
@@ -637,47 +863,168 @@ impl<'a, B: Board> Debugger<'a, B> {
         addr: Address,
         data: V,
     ) -> Result<(), MemError> {
-        self.board.cpu_mut().write_memory::<V>(addr, data)
+        self.cpu_mut().write_memory::<V>(addr, data)
     }
 
     pub fn read_csr(&mut self, addr: WordType) -> Option<WordType> {
-        self.board.cpu_mut().debug_csr(addr, None)
+        self.cpu_mut().debug_csr(addr, None)
     }
 
     pub fn write_csr(&mut self, addr: WordType, data: WordType) -> Result<(), DebugError> {
-        self.board
-            .cpu_mut()
+        self.cpu_mut()
             .debug_csr(addr, Some(data))
             .ok_or(DebugError::CSRNotExist(addr))?;
         Ok(())
     }
 
     pub fn get_current_privilege(&mut self) -> PrivilegeLevel {
-        self.board.cpu_mut().get_current_privilege()
+        self.cpu_mut().get_current_privilege()
     }
 
     pub fn set_current_privilege(&mut self, priv_level: PrivilegeLevel) {
-        self.board.cpu_mut().csr.set_current_privileged(priv_level);
+        self.cpu_mut().csr.set_current_privileged(priv_level);
     }
 
     pub fn decoded_info(&self, raw: RawInstr) -> Option<<RiscvTypes as ISATypes>::DecodeRst> {
-        self.board.cpu().decoded_instr(raw)
+        self.cpu().decoded_instr(raw)
     }
 
     pub fn vaddr_to_paddr(&mut self, vaddr: WordType) -> Result<u64, PageTableError> {
-        self.board.cpu_mut().debug_vaddr_to_paddr(vaddr)
+        self.cpu_mut().debug_vaddr_to_paddr(vaddr)
     }
 
     pub fn translate(&mut self, addr: u64, access: AccessType) -> Result<u64, PageTableError> {
-        self.board.cpu_mut().debug_translate(addr, access)
+        self.cpu_mut().debug_translate(addr, access)
+    }
+
+    pub fn vm_info(&mut self) -> VmInfo {
+        let cpu = self.cpu_mut();
+        let satp = cpu.csr.get_by_type_existing::<Satp>();
+        VmInfo {
+            mode: satp_mode_name(satp.get_mode()),
+            root: satp.get_ppn() << PAGE_SIZE_XLEN,
+            asid: satp.get_asid() as u16,
+            tlb_entries: cpu.memory.tlb_entries(),
+        }
+    }
+
+    /// Set external interrupt source `id` pending in the interrupt controller.
+    pub fn raise_irq(&mut self, id: ExternalInterrupt) -> Result<(), DebugError> {
+        if self.board.raise_external_interrupt(id) {
+            Ok(())
+        } else {
+            Err(DebugError::IrqNotExist(id))
+        }
+    }
+
+    /// The pins of the GPIO controller.
+    pub fn gpio_state(&self) -> Result<GpioState, DebugError> {
+        self.board.gpio_state().ok_or(DebugError::NoGpio)
+    }
+
+    /// Drive GPIO `pin` to `level`, or stop driving it with `None`.
+    pub fn drive_gpio(&mut self, pin: u32, level: Option<bool>) -> Result<(), DebugError> {
+        if pin >= GPIO_PINS {
+            return Err(DebugError::GpioPinNotExist(pin));
+        }
+        match self.board.drive_gpio(pin, level) {
+            true => Ok(()),
+            false => Err(DebugError::NoGpio),
+        }
+    }
+
+    /// Reset the board as at power-on, RAM keeps its content unless `reload` writes the boot
+    /// images to it again. Breakpoints stay, the instruction history is cleared.
+    pub fn reset(&mut self, reload: bool) -> Result<(), DebugError> {
+        if reload && !self.board.reload_images() {
+            return Err(DebugError::NoBootImage);
+        }
+        self.board.reset();
+        self.history.clear();
+        Ok(())
+    }
+
+    /// Plug a device in while the guest runs.
+    pub fn add_device(&mut self, cfg: &DeviceConfig) -> Result<HotplugInfo, DebugError> {
+        Ok(self.board.hotplug_device(cfg)?)
+    }
+
+    /// Remove the device in `slot` while the guest runs.
+    pub fn remove_device(&mut self, slot: usize) -> Result<(), DebugError> {
+        Ok(self.board.unplug_device(slot)?)
+    }
+
+    /// The faults injected into the disk in `slot`.
+    pub fn block_faults(&self, slot: usize) -> Result<FaultConfig, DebugError> {
+        Ok(self.board.block_faults(slot)?)
+    }
+
+    /// Inject `faults` into the disk in `slot`.
+    pub fn set_block_faults(&mut self, slot: usize, faults: FaultConfig) -> Result<(), DebugError> {
+        Ok(self.board.set_block_faults(slot, faults)?)
+    }
+
+    /// Taint what the guest reads from the UART, from now on.
+    pub fn taint_uart(&mut self) -> Result<(), DebugError> {
+        match self.board.taint_uart() {
+            true => Ok(()),
+            false => Err(DebugError::NoUart),
+        }
+    }
+
+    /// Taint what the disk in `slot` reads from the byte `region`, from now on.
+    pub fn taint_disk(&mut self, slot: usize, region: Range<u64>) -> Result<(), DebugError> {
+        Ok(self.board.taint_disk(slot, region)?)
+    }
+
+    /// Taint or clean `len` bytes of physical memory from `paddr`.
+    pub fn taint_memory(&mut self, paddr: u64, len: u64, tainted: bool) {
+        let taint = self.cpu_mut().enable_taint();
+        taint.memory().borrow_mut().set(paddr, len, tainted);
+    }
+
+    /// Clean every register and byte, the sources stay tainted.
+    pub fn clear_taint(&mut self) {
+        if let Some(taint) = self.cpu_mut().taint_mut() {
+            taint.clear();
+        }
+    }
+
+    /// `None` until a taint source is set.
+    pub fn taint(&self) -> Option<&TaintTracker> {
+        self.cpu().taint()
+    }
+
+    /// The instructions executed since the debugger was attached or the counters cleared.
+    pub fn instr_stats(&self) -> IstatsSnapshot {
+        self.cpu()
+            .instr_stats()
+            .map_or_else(|| InstrStats::new().snapshot(), InstrStats::snapshot)
+    }
+
+    pub fn clear_instr_stats(&mut self) {
+        if let Some(stats) = self.cpu_mut().instr_stats_mut() {
+            stats.clear();
+        }
+    }
+
+    /// The last traps taken by the hart, most recent first.
+    pub fn recent_traps(&self) -> impl Iterator<Item = &TrapRecord> {
+        self.cpu().recent_traps()
+    }
+
+    /// The guest physical address map, in address order.
+    pub fn memory_regions(&self) -> Vec<MemoryRegion> {
+        self.cpu().memory.mmio.regions()
+    }
+
+    /// Runtime counters of every memory-mapped device.
+    pub fn device_stats(&self) -> Vec<DeviceStats> {
+        self.cpu().memory.mmio.device_stats()
     }
 
     pub fn cycle(&mut self) -> WordType {
-        self.board
-            .cpu_mut()
-            .csr
-            .get_by_type_existing::<Mcycle>()
-            .data()
+        self.cpu_mut().csr.get_by_type_existing::<Mcycle>().data()
     }
 }
 
@@ -706,6 +1053,8 @@ mod test {
             crate::board::BoardStatus::Running
         }
 
+        fn reset(&mut self) {}
+
         fn cpu(&self) -> &RVCPU {
             &self.cpu
         }
@@ -723,6 +1072,47 @@ mod test {
         Debugger::new(Box::leak(Box::new(TestEmptyBoard::new(cpu))))
     }
 
+    /// Two harts stepping in lockstep, each with its own memory.
+    struct TestTwoHartBoard {
+        harts: [RVCPU; 2],
+    }
+
+    impl Board for TestTwoHartBoard {
+        fn step(&mut self) -> Result<(), Exception> {
+            self.harts.iter_mut().try_for_each(RVCPU::step)
+        }
+
+        fn status(&self) -> crate::board::BoardStatus {
+            crate::board::BoardStatus::Running
+        }
+
+        fn reset(&mut self) {}
+
+        fn cpu(&self) -> &RVCPU {
+            &self.harts[0]
+        }
+
+        fn cpu_mut(&mut self) -> &mut RVCPU {
+            &mut self.harts[0]
+        }
+
+        fn hart_count(&self) -> usize {
+            2
+        }
+
+        fn hart(&self, id: usize) -> Option<&RVCPU> {
+            self.harts.get(id)
+        }
+
+        fn hart_mut(&mut self, id: usize) -> Option<&mut RVCPU> {
+            self.harts.get_mut(id)
+        }
+
+        fn loader(&self) -> Option<&crate::load::ELFLoader> {
+            None
+        }
+    }
+
     #[test]
     fn test_breakpoint_riscv() {
         // Test that a breakpoint can be hit
@@ -739,7 +1129,7 @@ mod test {
         let mut debugger = create_debugger(cpu);
 
         debugger
-            .set_breakpoint(Address::Phys(BASE_ADDR + 4))
+            .set_breakpoint(Address::Phys(BASE_ADDR + 4), None)
             .unwrap();
         debugger.continue_run().unwrap();
 
@@ -755,7 +1145,7 @@ mod test {
         assert_eq!(debugger.read_pc(), BASE_ADDR + 8);
 
         debugger
-            .set_breakpoint(Address::Phys(BASE_ADDR + 12))
+            .set_breakpoint(Address::Phys(BASE_ADDR + 12), None)
             .unwrap();
 
         debugger.continue_until_step(2).unwrap();
@@ -778,13 +1168,65 @@ mod test {
             .build();
 
         let mut debugger = create_debugger(cpu);
-        debugger.set_breakpoint(Address::Phys(BASE_ADDR)).unwrap();
+        debugger
+            .set_breakpoint(Address::Phys(BASE_ADDR), None)
+            .unwrap();
 
         debugger.step().unwrap();
 
         assert_eq!(debugger.read_pc(), BASE_ADDR + 4);
     }
 
+    #[test]
+    fn test_breakpoint_per_hart() {
+        let build = || {
+            TestCPUBuilder::new()
+                .program(&[
+                    0x00128293, // addi t0, t0, 1
+                    0x00128293, // addi t0, t0, 1
+                    0x00128293, // addi t0, t0, 1
+                    0x00128293, // addi t0, t0, 1
+                ])
+                .build()
+        };
+        let mut board = TestTwoHartBoard {
+            harts: [build(), build()],
+        };
+        board.harts[1].write_reg(5, 100);
+        let mut debugger = Debugger::new(&mut board);
+        assert_eq!(debugger.hart_count(), 2);
+        assert!(matches!(
+            debugger.select_hart(2),
+            Err(DebugError::HartNotExist(2))
+        ));
+        assert!(
+            debugger
+                .set_breakpoint(Address::Phys(BASE_ADDR), Some(2))
+                .is_err()
+        );
+
+        // Stopped by the breakpoint of hart 1, which becomes the selected one.
+        debugger
+            .set_breakpoint(Address::Phys(BASE_ADDR + 8), Some(1))
+            .unwrap();
+        assert_eq!(
+            debugger.continue_run().unwrap().0,
+            DebugEvent::BreakpointHit
+        );
+        assert_eq!(debugger.hart(), 1);
+        assert_eq!(debugger.read_reg(5), 102);
+
+        debugger.select_hart(0).unwrap();
+        assert_eq!(debugger.read_reg(5), 2);
+        assert_eq!(
+            debugger.harts(),
+            [
+                (BASE_ADDR + 8, PrivilegeLevel::M),
+                (BASE_ADDR + 8, PrivilegeLevel::M)
+            ]
+        );
+    }
+
     #[test]
     fn test_ftrace_jal_call_and_ret_with_symbols() {
         let cpu = TestCPUBuilder::new()
@@ -1071,4 +1513,40 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn test_step_over_interrupt() {
+        use crate::isa::riscv::csr_reg::csr_macro::{Mcause, Mepc, Mie, Mip, Mstatus, Mtvec};
+
+        const HANDLER: WordType = BASE_ADDR + 0x100;
+        const MTIP: WordType = 1 << 7;
+        let cpu = TestCPUBuilder::new()
+            .program(&[0x00000013, 0x00000013]) // nop; nop
+            .csr(Mstatus::get_index(), 1 << 3)
+            .csr(Mie::get_index(), MTIP)
+            .csr(Mip::get_index(), MTIP)
+            .csr(Mtvec::get_index(), HANDLER)
+            .build();
+        let mut debugger = create_debugger(cpu);
+
+        debugger.set_step_over_interrupts(true);
+        debugger.step().unwrap();
+        assert_eq!(debugger.read_pc(), BASE_ADDR + 4);
+        assert_eq!(debugger.read_csr(Mip::get_index()), Some(MTIP));
+
+        // Continuing takes the interrupt which is still pending.
+        debugger.continue_until_step(1).unwrap();
+        assert_eq!(debugger.read_pc(), HANDLER);
+        assert_eq!(debugger.read_csr(Mepc::get_index()), Some(BASE_ADDR + 4));
+
+        debugger.write_csr(Mstatus::get_index(), 1 << 3).unwrap();
+        debugger.write_pc(BASE_ADDR);
+        debugger.set_step_over_interrupts(false);
+        debugger.step().unwrap();
+        assert_eq!(debugger.read_pc(), HANDLER);
+        assert_eq!(
+            debugger.read_csr(Mcause::get_index()),
+            Some(1 << (WordType::BITS - 1) | 7)
+        );
+    }
 }
@@ -0,0 +1,212 @@
+//! Why an encoding does not decode, for the diagnostics of [`UndecodableAction`].
+//!
+//! [`UndecodableAction`]: crate::isa::riscv::executor::UndecodableAction
+
+use std::fmt::Display;
+
+use crate::{
+    config::arch_config::WordType,
+    isa::{
+        InstrLen,
+        riscv::{
+            RawInstr,
+            decoder::{Decoder, is_compressed},
+            instruction::instr_table::RiscvInstr,
+            isa_builder::{Extension, known_tables},
+        },
+    },
+};
+
+/// Why [`Decoder::decode`] rejected an encoding, see [`Decoder::explain`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UndecodableReason {
+    /// The encoding is an instruction of an extension the hart doesn't implement.
+    Missing(Extension),
+    /// The encoding is an instruction of an extension the guest disabled in `misa`.
+    Disabled(Extension),
+    /// The encoding is an instruction of the hart, with operands it doesn't allow, e.g. `x16`
+    /// on an RV32E hart or a reserved vector encoding.
+    Operands,
+    /// No instruction has this encoding.
+    Unknown,
+}
+
+/// The diagnostic of an undecodable encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Undecodable {
+    pub raw: u32,
+    pub len: WordType,
+    /// The instruction the encoding is closest to, with the bits which differ, zero unless the
+    /// reason is [`UndecodableReason::Unknown`].
+    pub closest: Option<(RiscvInstr, u32)>,
+    pub reason: UndecodableReason,
+}
+
+impl Display for Undecodable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = self.len as usize * 2;
+        write!(
+            f,
+            "undecodable instruction {:#0w$x}",
+            self.raw,
+            w = width + 2
+        )?;
+        let Some((instr, differ)) = self.closest else {
+            return write!(f, ", no instruction of this length");
+        };
+        match self.reason {
+            UndecodableReason::Missing(ext) => {
+                write!(f, ": {}, needs the {ext} extension", instr.name())
+            }
+            UndecodableReason::Disabled(ext) => write!(
+                f,
+                ": {}, the {ext} extension is disabled in misa",
+                instr.name()
+            ),
+            UndecodableReason::Operands => {
+                write!(f, ": {} with operands the hart doesn't allow", instr.name())
+            }
+            UndecodableReason::Unknown => write!(
+                f,
+                ", closest is {} ({} bit{} differ{})",
+                instr.name(),
+                differ,
+                if differ == 1 { "" } else { "s" },
+                if differ == 1 { "s" } else { "" }
+            ),
+        }
+    }
+}
+
+impl Decoder {
+    /// Explain why `instr` does not decode: the instruction it is, or else the one it is
+    /// closest to, among every extension the emulator knows.
+    ///
+    /// This walks all the instruction tables, only call it on the undecodable path.
+    pub fn explain(&self, instr: RawInstr) -> Undecodable {
+        let compressed = instr.len() == 2;
+        let raw = instr.val;
+        let enabled = self.supported.retain_misa(self.extension_bits);
+
+        let mut closest: Option<(&'static [Extension], RiscvInstr, u32)> = None;
+        for (requires, table) in known_tables() {
+            for desc in table.iter().filter(|d| is_compressed(d) == compressed) {
+                let differ = ((raw ^ desc.key) & desc.mask).count_ones();
+                if closest.is_none_or(|(_, _, best)| differ < best) {
+                    closest = Some((requires, desc.instr, differ));
+                }
+            }
+        }
+
+        let Some((requires, instr_kind, differ)) = closest else {
+            return Undecodable {
+                raw,
+                len: instr.len(),
+                closest: None,
+                reason: UndecodableReason::Unknown,
+            };
+        };
+        let reason = if differ != 0 {
+            UndecodableReason::Unknown
+        } else if let Some(&ext) = requires.iter().find(|&&e| !self.supported.provides(e)) {
+            UndecodableReason::Missing(ext)
+        } else if let Some(&ext) = requires.iter().find(|&&e| !enabled.provides(e)) {
+            UndecodableReason::Disabled(ext)
+        } else {
+            UndecodableReason::Operands
+        };
+
+        Undecodable {
+            raw,
+            len: instr.len(),
+            closest: Some((instr_kind, differ)),
+            reason,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::arch_config::XLEN,
+        isa::riscv::{csr_reg::csr_index, isa_builder::ISABuilder},
+    };
+
+    use super::*;
+
+    const MUL_A0_A0_A1: u32 = 0x02b5_0533;
+
+    fn explain(decoder: &Decoder, raw: u32) -> Undecodable {
+        let undecodable = decoder.explain(raw.into());
+        assert!(decoder.decode(raw.into()).is_none());
+        undecodable
+    }
+
+    #[test]
+    fn test_missing_extension() {
+        let decoder = Decoder::from_isa_str(&format!("RV{XLEN}I")).unwrap();
+        let undecodable = explain(&decoder, MUL_A0_A0_A1);
+        assert_eq!(undecodable.closest, Some((RiscvInstr::MUL, 0)));
+        assert_eq!(undecodable.reason, UndecodableReason::Missing(Extension::M));
+        assert_eq!(
+            undecodable.to_string(),
+            "undecodable instruction 0x02b50533: MUL, needs the M extension"
+        );
+
+        // fadd.s fa0, fa0, fa1, F is missing and brings in Zicsr
+        let undecodable = explain(&decoder, 0x00b5_7553);
+        assert_eq!(undecodable.reason, UndecodableReason::Missing(Extension::F));
+
+        // csrr a0, mscratch
+        let csrr = 0x0000_2573 | (csr_index::mscratch as u32) << 20;
+        let undecodable = explain(&decoder, csrr);
+        assert_eq!(
+            undecodable.reason,
+            UndecodableReason::Missing(Extension::Zicsr)
+        );
+        assert_eq!(
+            undecodable.to_string(),
+            format!("undecodable instruction {csrr:#010x}: CSRRS, needs the Zicsr extension")
+        );
+
+        // c.fld fa0, 0(a0) needs both C and D
+        let decoder = Decoder::from_builder(ISABuilder::new().add(Extension::C));
+        let undecodable = explain(&decoder, 0x2108);
+        assert_eq!(undecodable.reason, UndecodableReason::Missing(Extension::D));
+        assert!(
+            undecodable
+                .to_string()
+                .starts_with("undecodable instruction 0x2108:")
+        );
+    }
+
+    #[test]
+    fn test_disabled_extension() {
+        let mut decoder = Decoder::from_isa_str(&format!("RV{XLEN}IM")).unwrap();
+        decoder.reconfigure(decoder.extension_bits() & !(1 << (b'M' - b'A')));
+        let undecodable = explain(&decoder, MUL_A0_A0_A1);
+        assert_eq!(
+            undecodable.reason,
+            UndecodableReason::Disabled(Extension::M)
+        );
+    }
+
+    #[test]
+    fn test_operands() {
+        // add a6, a0, a1 writes x16, which an RVE hart doesn't have
+        let decoder = Decoder::from_isa_str(&format!("RV{XLEN}E")).unwrap();
+        let undecodable = explain(&decoder, 0x00b5_0833);
+        assert_eq!(undecodable.closest, Some((RiscvInstr::ADD, 0)));
+        assert_eq!(undecodable.reason, UndecodableReason::Operands);
+    }
+
+    #[test]
+    fn test_unknown() {
+        let decoder = Decoder::new();
+        // mul with funct7 bit 26 set, which no extension defines
+        let undecodable = explain(&decoder, MUL_A0_A0_A1 | 1 << 26);
+        assert_eq!(undecodable.reason, UndecodableReason::Unknown);
+        assert_eq!(undecodable.closest.map(|(_, differ)| differ), Some(1));
+        assert!(undecodable.to_string().contains("(1 bit differs)"));
+    }
+}
@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use std::{fmt::Display, sync::Arc};
 
 use crate::{
     config::arch_config::WordType,
@@ -9,6 +9,7 @@ use crate::{
         riscv::{
             RawInstr,
             decoder::compress_decoder::CompressedDecoder,
+            extension::{ExtensionEntry, InstructionExtension, extension_table},
             instruction::{InstrFormat, RVInstrInfo, instr_table::*},
             isa_builder::{Extension, ISABuilder, IsaParseError},
         },
@@ -17,8 +18,12 @@ use crate::{
 };
 
 mod compress_decoder;
+mod explain;
 mod funct_decoder;
 mod mask_decoder;
+mod rve;
+
+pub use explain::{Undecodable, UndecodableReason};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct DecodeInstr {
@@ -43,19 +48,18 @@ pub struct Decoder {
     compress_decoder: compress_decoder::CompressedDecoder,
     /// `misa` extension bitmap of the ISA this decoder was built for.
     extension_bits: WordType,
+    /// Every extension this decoder may be switched to, see [`Self::reconfigure`].
+    supported: ISABuilder,
+    /// Only `x0`-`x15` exist, see [`Extension::E`].
+    rve: bool,
+    /// Registered with [`Self::new_with_extensions`], whatever `misa` says.
+    extensions: Vec<Arc<dyn InstructionExtension>>,
+    extension_table: Vec<ExtensionEntry>,
 }
 
 impl Decoder {
     pub fn new() -> Self {
-        Self::from_builder(
-            ISABuilder::new()
-                .add(Extension::M)
-                .add(Extension::A)
-                .add(Extension::D)
-                .add(Extension::C)
-                .add(Extension::V)
-                .add(Extension::Zifencei),
-        )
+        Self::from_builder(ISABuilder::all())
     }
 
     /// Builds a decoder for the ISA described by `isa`,
@@ -69,8 +73,39 @@ impl Decoder {
         self.extension_bits
     }
 
-    fn from_builder(builder: ISABuilder) -> Self {
+    /// Every extension the hart implements, whatever the guest disabled in `misa`.
+    pub fn isa(&self) -> &ISABuilder {
+        &self.supported
+    }
+
+    /// Like [`Self::from_builder`], also decoding the instructions of `extensions`.
+    pub fn new_with_extensions(
+        builder: ISABuilder,
+        extensions: Vec<Arc<dyn InstructionExtension>>,
+    ) -> Self {
+        let mut decoder = Self::from_builder(builder);
+        decoder.extension_table = extension_table(&extensions);
+        decoder.extensions = extensions;
+        decoder
+    }
+
+    /// Rebuilds the decoding tables for the supported extensions enabled in the
+    /// `misa` bitmap `bits`, instructions of the others become undecodable.
+    pub fn reconfigure(&mut self, bits: WordType) {
+        let supported = std::mem::take(&mut self.supported);
+        let extensions = std::mem::take(&mut self.extensions);
+        *self = Self::new_with_extensions(supported.retain_misa(bits), extensions);
+        self.supported = supported;
+    }
+
+    pub(in crate::isa::riscv) fn extension_entry(&self, index: u16) -> &ExtensionEntry {
+        &self.extension_table[index as usize]
+    }
+
+    pub fn from_builder(builder: ISABuilder) -> Self {
         let extension_bits = builder.extension_bits();
+        let rve = builder.has(Extension::E);
+        let supported = builder.clone();
 
         #[allow(unused_mut)]
         let mut isa = builder.build();
@@ -84,6 +119,8 @@ impl Decoder {
 
         let mut decoder = Self::from_isa(isa);
         decoder.extension_bits = extension_bits;
+        decoder.supported = supported;
+        decoder.rve = rve;
         decoder
     }
 }
@@ -113,16 +150,42 @@ impl Decoder {
             // Unknown when building from a raw instruction list; the
             // builder-aware constructors set this via `from_builder`.
             extension_bits: 0,
+            supported: ISABuilder::new(),
+            rve: false,
+            extensions: Vec::new(),
+            extension_table: Vec::new(),
         }
     }
 
     pub fn decode(&self, instr: RawInstr) -> Option<DecodeInstr> {
-        if instr.len() == 2 {
+        let decoded = if instr.len() == 2 {
             self.compress_decoder.decode(instr)
         } else {
             None.or_else(|| self.mask_decoder.decode(instr))
                 .or_else(|| self.funct3_decoder.decode(instr))
         }
+        .or_else(|| self.decode_extension(instr))?;
+
+        if self.rve && rve::uses_missing_regs(instr.val, decoded.instr, &decoded.info) {
+            return None;
+        }
+        Some(decoded)
+    }
+
+    fn decode_extension(&self, instr: RawInstr) -> Option<DecodeInstr> {
+        let compressed = instr.len() == 2;
+        let index = self
+            .extension_table
+            .iter()
+            .position(|entry| entry.compressed == compressed && entry.mask.matches(instr.val))?;
+        Some(DecodeInstr {
+            instr: RiscvInstr::EXTENSION,
+            info: RVInstrInfo::Extension {
+                index: index as u16,
+                raw: instr.val,
+            },
+            len: instr.len(),
+        })
     }
 }
 
@@ -938,4 +1001,39 @@ mod tests {
         // 0x9002 also matches C_JALR; must decode as C_EBREAK.
         checker.check(0x9002, RiscvInstr::C_EBREAK, RVInstrInfo::None);
     }
+
+    #[test]
+    fn test_decoder_rve() {
+        use crate::config::arch_config::XLEN;
+
+        let decoder = Decoder::from_isa_str(&format!("RV{XLEN}EFC")).unwrap();
+        let decode = |raw: u32| decoder.decode(raw.into()).map(|d| d.instr);
+
+        // add x5, x6, x15 / add x16, x6, x7
+        assert_eq!(
+            decode(get_instr_r(0b0110011, 0, 0, 5, 6, 15)),
+            Some(RiscvInstr::ADD)
+        );
+        assert_eq!(decode(get_instr_r(0b0110011, 0, 0, 16, 6, 7)), None);
+        assert_eq!(decode(get_instr_r(0b0110011, 0, 0, 5, 6, 31)), None);
+
+        // fadd.s f16, f17, f18 only names FP registers.
+        assert_eq!(
+            decode(get_instr_r(0b1010011, 0, 0, 16, 17, 18)),
+            Some(RiscvInstr::FADD_S)
+        );
+        // flw f20, 0(x5) / flw f5, 0(x20)
+        assert_eq!(
+            decode(get_instr_r(0b0000111, 0b010, 0, 20, 5, 0)),
+            Some(RiscvInstr::FLW)
+        );
+        assert_eq!(decode(get_instr_r(0b0000111, 0b010, 0, 5, 20, 0)), None);
+
+        // c.mv x15, x5 / c.mv x16, x5
+        assert_eq!(
+            decode(0x8000 | (15 << 7) | (5 << 2) | 0b10),
+            Some(RiscvInstr::C_MV)
+        );
+        assert_eq!(decode(0x8000 | (16 << 7) | (5 << 2) | 0b10), None);
+    }
 }
@@ -0,0 +1,101 @@
+//! RV32E / RV64E: only `x0`-`x15` exist, encodings naming `x16`-`x31` are reserved and decode
+//! as illegal instructions.
+//!
+//! The register fields of the floating-point and vector instructions may name `f` / `v`
+//! registers, which are all still there, so only the integer operands are checked.
+
+use crate::isa::riscv::instruction::{
+    RVInstrInfo,
+    instr_table::RiscvInstr::{self, *},
+};
+
+const RVE_REGS: u8 = 16;
+
+/// Whether `instr` names one of the integer registers missing in the E profile.
+pub(super) fn uses_missing_regs(raw: u32, instr: RiscvInstr, info: &RVInstrInfo) -> bool {
+    int_regs(raw, instr, info)
+        .into_iter()
+        .flatten()
+        .any(|reg| reg >= RVE_REGS)
+}
+
+/// The integer registers among the register fields of `instr`.
+///
+/// Compressed instructions with 3-bit register fields always name `x8`-`x15` and are not
+/// listed.
+fn int_regs(raw: u32, instr: RiscvInstr, info: &RVInstrInfo) -> [Option<u8>; 3] {
+    match *info {
+        RVInstrInfo::R { rd, rs1, rs2 } => match instr {
+            FCLASS_S | FCLASS_D | FEQ_S | FEQ_D | FLE_S | FLE_D | FLT_S | FLT_D | FMV_X_W
+            | FMV_X_D => [Some(rd), None, None],
+            FMV_W_X | FMV_D_X => [None, Some(rs1), None],
+            FMAX_S | FMAX_D | FMIN_S | FMIN_D | FSGNJ_S | FSGNJ_D | FSGNJN_S | FSGNJN_D
+            | FSGNJX_S | FSGNJX_D => [None; 3],
+            _ => [Some(rd), Some(rs1), Some(rs2)],
+        },
+        RVInstrInfo::R_rm { rd, rs1, .. } => match instr {
+            FCVT_W_S | FCVT_WU_S | FCVT_L_S | FCVT_LU_S | FCVT_W_D | FCVT_WU_D | FCVT_L_D
+            | FCVT_LU_D => [Some(rd), None, None],
+            FCVT_S_W | FCVT_S_WU | FCVT_S_L | FCVT_S_LU | FCVT_D_W | FCVT_D_WU | FCVT_D_L
+            | FCVT_D_LU => [None, Some(rs1), None],
+            _ => [None; 3],
+        },
+        RVInstrInfo::R4_rm { .. } => [None; 3],
+        RVInstrInfo::I { rd, rs1, .. } => match instr {
+            FLW | FLD => [None, Some(rs1), None],
+            // `rs1` holds an immediate.
+            CSRRWI | CSRRSI | CSRRCI => [Some(rd), None, None],
+            // The register fields are reserved and ignored.
+            FENCE | FENCE_I => [None; 3],
+            _ => [Some(rd), Some(rs1), None],
+        },
+        RVInstrInfo::S { rs1, rs2, .. } => match instr {
+            FSW | FSD => [None, Some(rs1), None],
+            _ => [None, Some(rs1), Some(rs2)],
+        },
+        RVInstrInfo::B { rs1, rs2, .. } => [None, Some(rs1), Some(rs2)],
+        RVInstrInfo::U { rd, .. } | RVInstrInfo::J { rd, .. } => [Some(rd), None, None],
+        RVInstrInfo::A { rd, rs1, rs2, .. } => [Some(rd), Some(rs1), Some(rs2)],
+        RVInstrInfo::V { rd, rs1, rs2, .. } => vector_int_regs(raw, instr, rd, rs1, rs2),
+
+        RVInstrInfo::CR { rd_rs1, rs2 } => [Some(rd_rs1), None, Some(rs2)],
+        RVInstrInfo::CI { rd_rs1, .. } => match instr {
+            C_FLWSP | C_FLDSP => [None; 3],
+            _ => [Some(rd_rs1), None, None],
+        },
+        RVInstrInfo::CSS { rs2, .. } => match instr {
+            C_FSWSP | C_FSDSP => [None; 3],
+            _ => [None, None, Some(rs2)],
+        },
+        RVInstrInfo::CIW { .. }
+        | RVInstrInfo::CL { .. }
+        | RVInstrInfo::CS { .. }
+        | RVInstrInfo::CA { .. }
+        | RVInstrInfo::CB { .. }
+        | RVInstrInfo::CJ { .. }
+        | RVInstrInfo::None => [None; 3],
+        // Up to the extension.
+        RVInstrInfo::Extension { .. } => [None; 3],
+    }
+}
+
+/// Major opcode of the vector arithmetic and configuration instructions, the other vector
+/// instructions are loads and stores.
+const OP_V: u32 = 0b1010111;
+/// `funct3` of the vector instructions with a scalar `rs1` operand.
+const OPIVX: u32 = 0b100;
+const OPMVX: u32 = 0b110;
+
+fn vector_int_regs(raw: u32, instr: RiscvInstr, rd: u8, rs1: u8, rs2: u8) -> [Option<u8>; 3] {
+    let opcode = raw & 0x7f;
+    let funct3 = (raw >> 12) & 0b111;
+    match instr {
+        VSETVL => [Some(rd), Some(rs1), Some(rs2)],
+        VSETVLI => [Some(rd), Some(rs1), None],
+        VSETIVLI | VMV_X_S | VCPOP_M | VFIRST_M => [Some(rd), None, None],
+        // The base address of loads and stores.
+        _ if opcode != OP_V => [None, Some(rs1), None],
+        _ if funct3 == OPIVX || funct3 == OPMVX => [None, Some(rs1), None],
+        _ => [None; 3],
+    }
+}
@@ -4,18 +4,35 @@ use crate::{
     board::virt::RiscvIRQHandler,
     config::arch_config::WordType,
     cpu::RegFile,
-    device::MemError,
+    device::{MemError, mmio::MemoryMapIO, mmio_trace::MmioTracer},
     fpu::soft_float::SoftFPU,
     isa::{
-        InstrLen,
         cache::{Cache, SetCache},
         riscv::{
             RawInstr,
-            csr_reg::{CsrRegFile, NamedCsrReg, PrivilegeLevel, csr_macro::*},
+            csr_reg::{
+                CsrRegFile, NamedCsrReg, PrivilegeLevel,
+                csr_macro::*,
+                trace::{CsrTracer, CsrWrite},
+            },
             decoder::{DecodeInstr, Decoder},
+            func_trace::{FunctionTracer, JumpKind},
+            hooks::ExecHooks,
+            instr_stats::InstrStats,
             instruction::{RVInstrInfo, exec_mapping::get_exec_func, instr_table::RiscvInstr},
-            mmu::VirtAddrManager,
-            trap::{Exception, Interrupt, Trap, trap_controller::TrapController},
+            isa_builder::Extension,
+            mmu::{
+                VirtAddrManager,
+                config::{PAGE_SIZE_XLEN, satp_mode_name},
+                trace::{MmuEvent, MmuTracer},
+            },
+            syscall_trace::SyscallTracer,
+            taint::{TaintFlow, TaintTracker},
+            trap::{
+                Exception, Interrupt, Trap,
+                trap_controller::TrapController,
+                trap_log::{TrapLog, TrapRecord},
+            },
             vector::Vector,
         },
     },
@@ -38,6 +55,19 @@ impl ExcuteInstrInfo {
     }
 }
 
+/// What the hart does with an instruction it cannot decode, see [`RVCPU::set_undecodable_action`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UndecodableAction {
+    /// Raise an illegal instruction exception, as the hardware does.
+    #[default]
+    Trap,
+    /// Log why it doesn't decode, see [`Decoder::explain`], then trap.
+    Explain,
+    /// Log why it doesn't decode and stop: [`RVCPU::step`] returns the illegal instruction
+    /// exception instead of trapping to the guest.
+    Halt,
+}
+
 pub(crate) struct DebugInfo {
     pub(crate) last_instr: ExcuteInstrInfo,
 }
@@ -61,6 +91,8 @@ pub struct RVCPU {
     pub(super) pc: WordType,
     pub(super) decoder: Decoder,
     pub(super) csr: CsrRegFile,
+    /// The CSRs as they were after construction, restored by [`Self::reset`].
+    reset_csr: CsrRegFile,
     pub(super) icache: SetCache<DecodeInstr, 256, 8>,
     pub(super) fpu: SoftFPU,
     pub(super) vector: Vector,
@@ -70,6 +102,40 @@ pub struct RVCPU {
 
     /// The trap value pending to be written to `mtval`/`stval`.
     pub(super) pending_tval: Option<WordType>,
+
+    /// Return exceptions from [`Self::step`] instead of trapping, used by
+    /// [`UserEmulator`](crate::isa::riscv::user_mode::UserEmulator) to emulate the kernel on the host.
+    pub(crate) user_mode: bool,
+
+    /// Traces `ECALL`s when set, see [`Self::set_syscall_tracer`].
+    pub(crate) syscall_tracer: Option<Box<SyscallTracer>>,
+
+    /// Traces the CSR writes when set, see [`Self::set_csr_tracer`].
+    pub(crate) csr_tracer: Option<Box<CsrTracer>>,
+
+    /// Traces function entries and exits when set, see [`Self::set_function_tracer`].
+    pub(crate) function_tracer: Option<Box<FunctionTracer>>,
+
+    /// Called around instructions once one is registered, see [`Self::add_pre_exec_hook`].
+    pub(crate) exec_hooks: Option<Box<ExecHooks>>,
+
+    /// Counts the executed instructions once enabled, see [`Self::enable_instr_stats`].
+    pub(super) instr_stats: Option<Box<InstrStats>>,
+
+    /// Shadow state of the registers and memory once enabled, see [`Self::enable_taint`].
+    pub(super) taint: Option<Box<TaintTracker>>,
+
+    /// The last traps taken, kept across resets, see [`Self::recent_traps`].
+    pub(super) trap_log: TrapLog,
+
+    /// Don't take interrupts, they stay pending in `mip`. Set by the debugger while
+    /// single-stepping, see [`Debugger::set_step_over_interrupts`].
+    ///
+    /// [`Debugger::set_step_over_interrupts`]: crate::isa::riscv::debugger::Debugger::set_step_over_interrupts
+    pub(crate) mask_interrupts: bool,
+
+    /// See [`Self::set_undecodable_action`].
+    undecodable_action: UndecodableAction,
 }
 
 impl RVCPU {
@@ -78,10 +144,17 @@ impl RVCPU {
     }
 
     pub(crate) fn from_decoder(decoder: Decoder, v_memory: VirtAddrManager) -> Self {
-        let mut csr = CsrRegFile::new();
+        Self::from_parts(decoder, CsrRegFile::new(), v_memory)
+    }
 
+    pub(crate) fn from_parts(
+        decoder: Decoder,
+        mut csr: CsrRegFile,
+        v_memory: VirtAddrManager,
+    ) -> Self {
         let ext = decoder.extension_bits();
         csr.ctx.extension = ext;
+        csr.configure_counters(decoder.isa());
 
         let mxl = if WordType::BITS == 32 {
             1
@@ -105,6 +178,7 @@ impl RVCPU {
         let fpu = SoftFPU::from(true);
 
         Self {
+            reset_csr: csr.clone(),
             debug: false,
             debug_info: DebugInfo::new(),
             icache_cnt: 0,
@@ -118,6 +192,16 @@ impl RVCPU {
             fpu,
             time_addr: None,
             pending_tval: None,
+            user_mode: false,
+            syscall_tracer: None,
+            csr_tracer: None,
+            function_tracer: None,
+            exec_hooks: None,
+            instr_stats: None,
+            taint: None,
+            trap_log: TrapLog::new(),
+            mask_interrupts: false,
+            undecodable_action: UndecodableAction::Trap,
         }
     }
 
@@ -130,7 +214,7 @@ impl RVCPU {
         // makes the program 10%-20% slower on my machine.
         // This is likely because it hurts jump-table dispatch and pulls some cold paths into the hot path.
         let rst = get_exec_func(instr)(info, self);
-        self.reg_file[0] = 0;
+        debug_assert_eq!(self.reg_file[0], 0, "{instr:?} wrote x0");
 
         if let Err(ex) = rst {
             cold_path();
@@ -151,6 +235,9 @@ impl RVCPU {
     pub fn read_csr(&mut self, addr: WordType) -> Result<WordType, Exception> {
         if addr == 0xc01 {
             // time CSR
+            if !self.decoder.isa().has(Extension::Zicntr) || !self.csr.is_read_priv_legal(addr) {
+                return Err(Exception::IllegalInstruction);
+            }
             if let Some(time_addr) = self.time_addr {
                 if let Ok(time) = self.memory.read_by_paddr::<u64>(time_addr) {
                     return Ok(time as WordType);
@@ -170,17 +257,57 @@ impl RVCPU {
     ///
     /// You may need [`CsrRegFile::write_directly`] in some cases.
     pub fn write_csr(&mut self, addr: WordType, data: WordType) -> Result<(), Exception> {
+        let is_misa = addr == Misa::get_index();
+        let data = if is_misa && self.pc & 0b11 != 0 {
+            // "Writing misa that would clear C is suppressed if the next instruction
+            // would not be 4-byte aligned."
+            const C_BIT: WordType = 1 << (b'C' - b'A');
+            data | (self.csr.get_by_type_existing::<Misa>().data() & C_BIT)
+        } else {
+            data
+        };
+
+        let traced = match &self.csr_tracer {
+            Some(tracer) if tracer.is_traced(addr) => self.csr.read_uncheck_privilege(addr),
+            _ => None,
+        };
+
         if !self.csr.write(addr, data) {
             log::warn!("Failed to write CSR {:#x} with data {:#x}", addr, data);
             return Err(Exception::IllegalInstruction);
         }
 
+        if let Some(old) = traced {
+            cold_path();
+            self.trace_csr_write(addr, old, data);
+        }
+
         // Changing satp.MODE from Bare to other modes and vice versa also takes effect immediately,
         // without the need to execute an SFENCE.VMA instruction.
         if addr == Satp::get_index() {
             let satp = self.csr.get_by_type_existing::<Satp>();
             self.memory.set_mode(satp.get_mode() as u8);
             self.memory.set_root_ppn(satp.get_ppn() as u64);
+            self.memory.set_asid(satp.get_asid() as u16);
+            if let Some(tracer) = self.memory.tracer_mut() {
+                cold_path();
+                tracer.record(&MmuEvent::SatpWrite {
+                    satp: satp.data(),
+                    mode: satp_mode_name(satp.get_mode()),
+                    asid: satp.get_asid() as u16,
+                    root: satp.get_ppn() << PAGE_SIZE_XLEN,
+                });
+            }
+            // Unlike the TLB, the decoded instructions are only tagged with their virtual address,
+            // and switching ASID needs no `sfence.vma`.
+            self.flush_icache();
+        }
+
+        // Extensions disabled in `misa` stop decoding right away.
+        if is_misa {
+            let ext = self.csr.get_by_type_existing::<Misa>().get_extension();
+            self.decoder.reconfigure(ext);
+            self.flush_icache();
         }
 
         Ok(())
@@ -191,10 +318,26 @@ impl RVCPU {
             self.debug_info.last_instr.trap = false;
         }
 
+        if let Some(tracer) = self.memory.mmio.tracer.as_mut() {
+            cold_path();
+            tracer.pc = self.pc;
+        }
+        if let Some(tracer) = self.memory.tracer_mut() {
+            cold_path();
+            tracer.pc = self.pc;
+        }
+
         let rst = self.step_impl();
 
-        let mcycle = self.csr.get_by_type_existing::<Mcycle>();
-        mcycle.set_mcycle_directly(mcycle.data().wrapping_add(1));
+        if self.syscall_tracer.is_some() {
+            cold_path();
+            self.trace_syscall_return();
+        }
+
+        if self.csr.ctx.count_inhibit & (1 << Mcountinhibit::cy_start) == 0 {
+            let mcycle = self.csr.get_by_type_existing::<Mcycle>();
+            mcycle.set_mcycle_directly(mcycle.data().wrapping_add(1));
+        }
 
         debug_assert!(self.pending_tval.is_none());
 
@@ -202,34 +345,15 @@ impl RVCPU {
     }
 
     fn ifetch(&mut self) -> Result<RawInstr, MemError> {
-        let mut bytes: RawInstr =
-            (self.memory.ifetch::<u16>(self.pc, &mut self.csr)? as u32).into();
-
-        if bytes.len() == 4 {
-            // 32-bit instr.
-
-            // "The C extension allows 16-bit instructions to be freely intermixed with 32-bit instructions,
-            // with the latter now able to start on any 16-bit boundary."
-
-            // but the next half may sit on the next page, causing a page fault.
-            let next_half = match self.memory.ifetch::<u16>(self.pc + 2, &mut self.csr) {
-                Ok(half) => half as u32,
-                Err(err) => {
-                    self.pending_tval = Some(self.pc + 2);
-                    return Err(err);
-                }
-            };
-            bytes.val |= next_half << 16;
-        };
-
-        Ok(bytes)
+        Ok(self.memory.ifetch(self.pc, &mut self.csr)?.into())
     }
 
     fn step_impl(&mut self) -> Result<(), Exception> {
-        if let Some(interrupt) = TrapController::has_interrupt(self) {
-            if TrapController::try_send_trap_signal(self, Trap::Interrupt(interrupt), 0) {
-                return Ok(());
-            }
+        if !self.mask_interrupts
+            && let Some(interrupt) = TrapController::has_interrupt(self)
+            && TrapController::try_send_trap_signal(self, Trap::Interrupt(interrupt), 0)
+        {
+            return Ok(());
         }
 
         let DecodeInstr { instr, info, len } = if let Some(decode_instr) = self.icache.get(self.pc)
@@ -240,12 +364,8 @@ impl RVCPU {
             let raw_instr = match self.ifetch() {
                 Ok(bytes) => bytes,
                 Err(err) => {
-                    TrapController::try_send_trap_signal(
-                        self,
-                        Trap::Exception(Exception::from_instr_fetch_err(err)),
-                        self.pc,
-                    );
-                    return Ok(());
+                    let tval = err.addr().unwrap_or(self.pc);
+                    return self.raise_exception(err.into(), tval);
                 }
             };
 
@@ -261,17 +381,7 @@ impl RVCPU {
 
             let decoder_result = self.decoder.decode(raw_instr);
             let Some(decode_instr) = decoder_result else {
-                log::warn!(
-                    "Illegal instruction: {:#x} at {:#x}",
-                    raw_instr.val,
-                    self.pc
-                );
-                TrapController::try_send_trap_signal(
-                    self,
-                    Trap::Exception(Exception::IllegalInstruction),
-                    raw_instr.val as WordType,
-                );
-                return Ok(());
+                return self.undecodable(raw_instr);
             };
 
             self.icache.put(self.pc, decode_instr.clone());
@@ -289,8 +399,35 @@ impl RVCPU {
             };
         }
 
+        if let Some(stats) = self.instr_stats.as_mut() {
+            stats.record(instr);
+        }
+
+        let jump = match self.function_tracer {
+            Some(_) => JumpKind::of(instr, &info),
+            None => None,
+        };
+        let return_addr = self.pc.wrapping_add(len);
+
+        let mut hooks = self.exec_hooks.take();
+        let pc = self.pc;
+        let decoded = DecodeInstr { instr, info, len };
+        if let Some(hooks) = hooks.as_mut() {
+            hooks.before(self, pc, &decoded);
+        }
+
+        let taint_flow = match self.taint {
+            Some(_) => Some(TaintFlow::of(instr, info, self.reg_file.as_slice())),
+            None => None,
+        };
+
         // EX && MEM && WB
         let excute_result = self.execute(instr, info);
+
+        if let Some(mut hooks) = hooks {
+            hooks.after(self, pc, &decoded, excute_result.err());
+            self.exec_hooks = Some(hooks);
+        }
         match excute_result {
             // XXX: OpenSBI have semihosting test, and we don't implement breakpoint exception handling yet,
             // so we can't throw and panic here.
@@ -302,21 +439,147 @@ impl RVCPU {
                 // because the raw instruction bytes are not stored in the i-cache.
                 // This is acceptable because `illegal instruction` is a cold path.
                 let raw_instr = self.ifetch().expect("ifetch should not fail here");
-                TrapController::try_send_trap_signal(
-                    self,
-                    Trap::Exception(Exception::IllegalInstruction),
-                    raw_instr.val as WordType,
-                );
+                return self
+                    .raise_exception(Exception::IllegalInstruction, raw_instr.val as WordType);
+            }
+            Err(
+                nr @ (Exception::UserEnvCall
+                | Exception::SupervisorEnvCall
+                | Exception::MachineEnvCall),
+            ) => {
+                if let Some(tracer) = self.syscall_tracer.as_mut() {
+                    let privilege = self.csr.privelege_level();
+                    if let Some(record) =
+                        tracer.on_ecall(privilege, self.pc, self.reg_file.as_slice())
+                    {
+                        log::info!(target: "syscall", "{}", record);
+                    }
+                }
+                return self.raise_exception(nr, 0);
             }
             Err(nr) => {
-                TrapController::try_send_trap_signal(self, Trap::Exception(nr), 0);
+                return self.raise_exception(nr, 0);
+            }
+            Ok(()) => {
+                if let Some(flow) = taint_flow {
+                    self.propagate_taint(flow);
+                }
+                if let Some(kind) = jump {
+                    cold_path();
+                    self.trace_function(kind, return_addr);
+                }
             }
-            Ok(()) => {} // there is nothing to do.
         }
 
         return Ok(());
     }
 
+    /// Trace every access to memory-mapped devices with `tracer`, or stop tracing with `None`.
+    pub fn set_mmio_tracer(&mut self, tracer: Option<MmioTracer>) {
+        self.memory.mmio.set_tracer(tracer);
+    }
+
+    /// Trace the `satp` writes and the A/D bits set by the page table walker with `tracer`, or
+    /// stop tracing with `None`.
+    pub fn set_mmu_tracer(&mut self, tracer: Option<MmuTracer>) {
+        self.memory.set_tracer(tracer);
+    }
+
+    /// Trap to the guest's handler for `exception`, or return it to the caller in user-mode emulation,
+    /// where there is no guest kernel to handle it.
+    fn raise_exception(
+        &mut self,
+        exception: Exception,
+        trap_value: WordType,
+    ) -> Result<(), Exception> {
+        if self.user_mode {
+            self.pending_tval = None;
+            return Err(exception);
+        }
+
+        TrapController::try_send_trap_signal(self, Trap::Exception(exception), trap_value);
+        Ok(())
+    }
+
+    /// Choose what the hart does with an instruction it cannot decode, it traps by default.
+    pub fn set_undecodable_action(&mut self, action: UndecodableAction) {
+        self.undecodable_action = action;
+    }
+
+    #[cold]
+    fn undecodable(&mut self, raw_instr: RawInstr) -> Result<(), Exception> {
+        match self.undecodable_action {
+            UndecodableAction::Trap => {
+                log::warn!(
+                    "Illegal instruction: {:#x} at {:#x}",
+                    raw_instr.val,
+                    self.pc
+                );
+            }
+            UndecodableAction::Explain => {
+                log::warn!("{} at {:#x}", self.decoder.explain(raw_instr), self.pc);
+            }
+            UndecodableAction::Halt => {
+                log::error!(
+                    "{} at {:#x}, halting",
+                    self.decoder.explain(raw_instr),
+                    self.pc
+                );
+                return Err(Exception::IllegalInstruction);
+            }
+        }
+        self.raise_exception(Exception::IllegalInstruction, raw_instr.val as WordType)
+    }
+
+    /// Trace every `ECALL` raised from U-mode or S-mode with `tracer`, or stop tracing with `None`.
+    pub fn set_syscall_tracer(&mut self, tracer: Option<SyscallTracer>) {
+        self.syscall_tracer = tracer.map(Box::new);
+    }
+
+    /// Report the traced call which returns to the current `pc`, if any.
+    pub(super) fn trace_syscall_return(&mut self) {
+        if let Some(tracer) = self.syscall_tracer.as_mut() {
+            let privilege = self.csr.privelege_level();
+            if let Some(record) = tracer.on_step(self.pc, privilege, self.reg_file.as_slice()) {
+                log::info!(target: "syscall", "{}", record);
+            }
+        }
+    }
+
+    /// Trace the CSR writes of the guest with `tracer`, or stop tracing with `None`.
+    pub fn set_csr_tracer(&mut self, tracer: Option<CsrTracer>) {
+        self.csr_tracer = tracer.map(Box::new);
+    }
+
+    fn trace_csr_write(&mut self, addr: WordType, old: WordType, written: WordType) {
+        let write = CsrWrite {
+            addr,
+            old,
+            new: self.csr.read_uncheck_privilege(addr).unwrap_or(written),
+            written,
+            pc: self.pc,
+            privilege: self.csr.privelege_level(),
+        };
+        if let Some(tracer) = self.csr_tracer.as_mut() {
+            tracer.record(&write);
+        }
+    }
+
+    /// Trace the functions entered and left by the hart with `tracer`, or stop tracing with `None`.
+    pub fn set_function_tracer(&mut self, tracer: Option<FunctionTracer>) {
+        self.function_tracer = tracer.map(Box::new);
+    }
+
+    /// Report the functions entered or left by the jump of `kind` which has just been taken.
+    fn trace_function(&mut self, kind: JumpKind, return_addr: WordType) {
+        let instret = self.csr.get_by_type_existing::<Minstret>().get_minstret();
+        if let Some(tracer) = self.function_tracer.as_mut() {
+            for record in tracer.on_jump(kind, self.pc, return_addr, instret) {
+                log::info!(target: "ftrace", "{}", record);
+            }
+        }
+    }
+
     pub fn flush_icache(&mut self) {
         self.icache.clear();
     }
@@ -325,6 +588,36 @@ impl RVCPU {
         self.memory.flush_tlb();
     }
 
+    /// The physical memory map, for the board to (un)map devices at runtime.
+    pub(crate) fn mmio_mut(&mut self) -> &mut MemoryMapIO {
+        &mut self.memory.mmio
+    }
+
+    /// Put the hart back in its reset state: M-mode at the reset vector, with the registers
+    /// cleared and the CSRs at their reset values. Memory is left alone.
+    pub fn reset(&mut self) {
+        self.reg_file = RegFile::new();
+        self.pc = DEFAULT_PC_VALUE;
+        self.csr = self.reset_csr.clone();
+        self.fpu = SoftFPU::from(true);
+        self.vector = Vector::new();
+        self.pending_tval = None;
+
+        let ext = self.csr.get_by_type_existing::<Misa>().get_extension();
+        self.decoder.reconfigure(ext);
+        self.memory.set_mode(0);
+        self.memory.set_root_ppn(0);
+        self.memory.set_asid(0);
+        self.flush_tlb();
+        self.flush_icache();
+    }
+
+    /// The last [`TRAP_LOG_SIZE`](super::trap::trap_log::TRAP_LOG_SIZE) traps taken by the hart,
+    /// most recent first.
+    pub fn recent_traps(&self) -> impl Iterator<Item = &TrapRecord> {
+        self.trap_log.iter()
+    }
+
     pub fn power_off(&mut self) -> Result<(), Exception> {
         self.memory.sync();
         Ok(())
@@ -364,6 +657,7 @@ mod tests {
 
     use super::*;
     use crate::{
+        config::arch_config::XLEN,
         isa::riscv::{cpu_tester::*, csr_reg::csr_index, vector::VLEN},
         ram_config,
         utils::{UnsignedInteger, negative_of, sign_extend},
@@ -1177,4 +1471,354 @@ mod tests {
             },
         );
     }
+
+    #[test]
+    fn test_misa_disable_extension() {
+        const MISA_M: WordType = 1 << (b'M' - b'A');
+        const MISA_F: WordType = 1 << (b'F' - b'A');
+        const MISA_H: WordType = 1 << (b'H' - b'A');
+        const HANDLER: WordType = 0x8000_2000;
+
+        let mut cpu = TestCPUBuilder::new()
+            .program(&[
+                0x02520333, // mul x6, x4, x5
+                0x02520333, // mul x6, x4, x5
+            ])
+            .reg(4, 6)
+            .reg(5, 7)
+            .csr(csr_index::mtvec, HANDLER)
+            .build();
+        let misa = cpu.csr.read_uncheck_privilege(csr_index::misa).unwrap();
+        assert_ne!(misa & MISA_M, 0);
+
+        // Unsupported extensions can't be enabled, D can't stay without F.
+        cpu.write_csr(csr_index::misa, misa | MISA_H).unwrap();
+        cpu.write_csr(csr_index::misa, misa & !MISA_F).unwrap();
+        assert_eq!(cpu.csr.read_uncheck_privilege(csr_index::misa), Some(misa));
+
+        cpu.write_csr(csr_index::misa, misa & !MISA_M).unwrap();
+        assert_eq!(
+            cpu.csr.read_uncheck_privilege(csr_index::misa),
+            Some(misa & !MISA_M)
+        );
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, HANDLER);
+        assert_eq!(
+            cpu.csr.read_uncheck_privilege(csr_index::mcause),
+            Some(Exception::IllegalInstruction.into())
+        );
+
+        cpu.write_csr(csr_index::misa, misa).unwrap();
+        cpu.pc = ram_config::BASE_ADDR;
+        cpu.step().unwrap();
+        CPUChecker::new(&mut cpu)
+            .reg(6, 42)
+            .pc(ram_config::BASE_ADDR + 4);
+    }
+
+    #[test]
+    fn test_undecodable_action() {
+        const MISA_M: WordType = 1 << (b'M' - b'A');
+        const HANDLER: WordType = 0x8000_2000;
+
+        let mut cpu = TestCPUBuilder::new()
+            .program(&[
+                0x02520333, // mul x6, x4, x5
+            ])
+            .csr(csr_index::mtvec, HANDLER)
+            .build();
+        let misa = cpu.csr.read_uncheck_privilege(csr_index::misa).unwrap();
+        cpu.write_csr(csr_index::misa, misa & !MISA_M).unwrap();
+
+        cpu.set_undecodable_action(UndecodableAction::Halt);
+        assert_eq!(cpu.step(), Err(Exception::IllegalInstruction));
+        assert_eq!(cpu.pc, ram_config::BASE_ADDR);
+        assert_eq!(cpu.csr.read_uncheck_privilege(csr_index::mcause), Some(0));
+
+        cpu.set_undecodable_action(UndecodableAction::Explain);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, HANDLER);
+        assert_eq!(
+            cpu.csr.read_uncheck_privilege(csr_index::mtval),
+            Some(0x02520333)
+        );
+    }
+
+    #[test]
+    fn test_mcountinhibit() {
+        let mut cpu = TestCPUBuilder::new()
+            .program(&[
+                0x00000013, // addi x0, x0, 0
+                0x00000013, // addi x0, x0, 0
+                0x00000013, // addi x0, x0, 0
+            ])
+            .build();
+
+        cpu.write_csr(Mcountinhibit::get_index(), 0b101).unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.csr.read_uncheck_privilege(Mcycle::get_index()), Some(0));
+        assert_eq!(
+            cpu.csr.read_uncheck_privilege(Minstret::get_index()),
+            Some(0)
+        );
+
+        // Explicit writes still work while inhibited.
+        cpu.write_csr(Minstret::get_index(), 10).unwrap();
+        cpu.write_csr(Mcountinhibit::get_index(), 0b001).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.csr.read_uncheck_privilege(Mcycle::get_index()), Some(0));
+        assert_eq!(
+            cpu.csr.read_uncheck_privilege(Minstret::get_index()),
+            Some(11)
+        );
+    }
+
+    #[test]
+    fn test_x0_write_discarded() {
+        const DATA_ADDR: WordType = ram_config::BASE_ADDR + 0x1000;
+
+        let program = [
+            0x00128013, // addi x0, x5, 1
+            0x12345037, // lui x0, 0x12345
+            0x12345017, // auipc x0, 0x12345
+            0x02628033, // mul x0, x5, x6
+            0x0003a003, // lw x0, 0(x7)
+            0x0053a02f, // amoadd.w x0, x5, (x7)
+            0x1853a02f, // sc.w x0, x5, (x7), fails without a reservation
+            0x1003a02f, // lr.w x0, (x7)
+            0xe0008053, // fmv.x.w x0, f1
+            0xc000f053, // fcvt.w.s x0, f1
+            0xa010a053, // feq.s x0, f1, f1
+            0x34029073, // csrrw x0, mscratch, x5
+            0xb0002073, // csrrs x0, mcycle, x0
+            0x0040006f, // jal x0, 4
+        ];
+        let mut cpu = TestCPUBuilder::new()
+            .program(&program)
+            .reg(5, 7)
+            .reg(6, 6)
+            .reg(7, DATA_ADDR)
+            .reg_f32(1, 3.0)
+            .mem(DATA_ADDR, 5u32)
+            .csr(Mscratch::get_index(), 0x55)
+            .build();
+        for (i, raw) in program.iter().enumerate() {
+            cpu.step().unwrap();
+            assert_eq!(cpu.reg_file[0], 0, "{raw:#010x} wrote x0");
+            assert_eq!(cpu.pc, ram_config::BASE_ADDR + 4 * (i as WordType + 1));
+        }
+        assert_eq!(cpu.memory.read_by_paddr::<u32>(DATA_ADDR), Ok(12));
+
+        ExecTester::new().fuzz_rd_zero(20000);
+    }
+
+    #[test]
+    fn test_big_endian_data() {
+        const DATA_ADDR: WordType = ram_config::BASE_ADDR + 0x1000;
+        const MSTATUS_UBE: WordType = 1 << 6;
+        const MSTATUS_MPRV: WordType = 1 << 17;
+        const MSTATUS_MBE: WordType = 1 << 37;
+
+        let program = [
+            0x00532023, // sw x5, 0(x6)
+            0x00032383, // lw x7, 0(x6)
+            0x00034403, // lbu x8, 0(x6)
+            0x005324af, // amoadd.w x9, x5, (x6)
+            0x2053252f, // amoxor.w x10, x5, (x6)
+        ];
+        let mut cpu = TestCPUBuilder::new()
+            .program(&program)
+            .reg(5, 0x1122_3344)
+            .reg(6, DATA_ADDR)
+            .csr(Mstatus::get_index(), MSTATUS_MBE)
+            .build();
+        for _ in 0..program.len() {
+            cpu.step().unwrap();
+        }
+        CPUChecker::new(&mut cpu)
+            .reg(7, 0x1122_3344)
+            .reg(8, 0x11)
+            .reg(9, 0x1122_3344)
+            .reg(10, 0x2244_6688);
+        assert_eq!(cpu.memory.read_by_paddr::<u32>(DATA_ADDR), Ok(0xCC55_6633));
+
+        // Instructions are still fetched little-endian, accesses under MPRV follow UBE instead.
+        cpu.pc = ram_config::BASE_ADDR;
+        cpu.csr.write_uncheck_privilege(
+            Mstatus::get_index(),
+            MSTATUS_MBE | MSTATUS_MPRV | MSTATUS_UBE,
+        );
+        cpu.step().unwrap();
+        cpu.csr
+            .get_by_type_existing::<Mstatus>()
+            .set_ube_directly(0);
+        cpu.step().unwrap();
+        CPUChecker::new(&mut cpu).reg(7, 0x4433_2211);
+    }
+
+    #[test]
+    fn test_counters_follow_isa() {
+        const HPMCOUNTER3: WordType = 0xC03;
+        const MHPMEVENT3: WordType = 0x323;
+
+        let mut cpu = TestCPUBuilder::with_isa(&format!("RV{XLEN}IM")).build();
+        assert_eq!(
+            cpu.read_csr(Cycle::get_index()),
+            Err(Exception::IllegalInstruction)
+        );
+        assert_eq!(cpu.read_csr(0xc01), Err(Exception::IllegalInstruction));
+        assert_eq!(
+            cpu.read_csr(HPMCOUNTER3),
+            Err(Exception::IllegalInstruction)
+        );
+        assert!(cpu.read_csr(Mcycle::get_index()).is_ok());
+
+        let mut cpu = TestCPUBuilder::with_isa(&format!("RV{XLEN}IM_Zicntr_Zihpm")).build();
+        cpu.write_csr(Mcycle::get_index(), 42).unwrap();
+        assert_eq!(cpu.read_csr(Cycle::get_index()), Ok(42));
+        cpu.write_csr(MHPMEVENT3, 1).unwrap();
+        assert_eq!(cpu.read_csr(MHPMEVENT3), Ok(0));
+        assert_eq!(cpu.read_csr(HPMCOUNTER3), Ok(0));
+    }
+
+    #[test]
+    fn test_counter_enable() {
+        const CSRR_CYCLE: u32 = 0xC00022F3; // csrr x5, cycle
+        const HANDLER: WordType = 0x8000_2000;
+
+        fn illegal(builder: TestCPUBuilder) -> TestCPUBuilder {
+            builder.csr(Mtvec::get_index(), HANDLER)
+        }
+        fn trapped(checker: CPUChecker) -> CPUChecker {
+            checker
+                .pc(HANDLER)
+                .csr(Mcause::get_index(), Exception::IllegalInstruction.into())
+        }
+
+        run_test_cpu_step(
+            &[CSRR_CYCLE],
+            |builder| illegal(builder.privilege(PrivilegeLevel::S)),
+            trapped,
+        );
+        run_test_cpu_step(
+            &[CSRR_CYCLE],
+            |builder| {
+                illegal(builder.privilege(PrivilegeLevel::U)).csr(Scounteren::get_index(), 0b111)
+            },
+            trapped,
+        );
+        run_test_cpu_step(
+            &[CSRR_CYCLE],
+            |builder| {
+                illegal(builder.privilege(PrivilegeLevel::U)).csr(Mcounteren::get_index(), 0b111)
+            },
+            trapped,
+        );
+
+        run_test_cpu_step(
+            &[CSRR_CYCLE],
+            |builder| {
+                builder
+                    .privilege(PrivilegeLevel::S)
+                    .csr(Mcounteren::get_index(), 0b001)
+                    .csr(Mcycle::get_index(), 42)
+            },
+            |checker| checker.reg(5, 42).pc(ram_config::BASE_ADDR + 4),
+        );
+        run_test_cpu_step(
+            &[CSRR_CYCLE],
+            |builder| {
+                builder
+                    .privilege(PrivilegeLevel::U)
+                    .csr(Mcounteren::get_index(), 0b001)
+                    .csr(Scounteren::get_index(), 0b001)
+                    .csr(Mcycle::get_index(), 42)
+            },
+            |checker| checker.reg(5, 42).pc(ram_config::BASE_ADDR + 4),
+        );
+    }
+
+    #[test]
+    fn test_instruction_fetch_fault() {
+        const JALR_X5: u32 = 0x0002_8067; // jalr x0, 0(x5)
+        const HANDLER: WordType = 0x8000_2000;
+        const UNMAPPED: WordType = 0x1000;
+        let ram_end = ram_config::BASE_ADDR + ram_config::SIZE as WordType;
+
+        run_test_cpu_step(
+            &[JALR_X5, JALR_X5],
+            |builder| builder.csr(Mtvec::get_index(), HANDLER).reg(5, UNMAPPED),
+            |checker| {
+                checker
+                    .pc(HANDLER)
+                    .csr(Mcause::get_index(), Exception::InstructionFault.into())
+                    .csr(Mtval::get_index(), UNMAPPED)
+            },
+        );
+
+        // A 32-bit instruction whose second half is past the end of RAM faults at that half.
+        run_test_cpu_step(
+            &[JALR_X5],
+            |builder| {
+                builder
+                    .csr(Mtvec::get_index(), HANDLER)
+                    .mem(ram_end - 2, 0x0013u16)
+                    .pc(ram_end - 2)
+            },
+            |checker| {
+                checker
+                    .pc(HANDLER)
+                    .csr(Mepc::get_index(), ram_end - 2)
+                    .csr(Mtval::get_index(), ram_end)
+            },
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "riscv64")]
+    fn test_ifetch_across_pages() {
+        const ADDI_X5_42: u32 = 0x02a0_0293; // addi x5, x0, 42
+        const HANDLER: WordType = 0x8000_2000;
+        const ROOT: WordType = 0x8000_1000;
+        // Sv39 tables of the lowest 2 MiB: `ROOT[0]` -> `MID[0]` -> `LEAF`.
+        const MID: WordType = 0x8000_3000;
+        const LEAF: WordType = 0x8000_4000;
+        // The trampoline's virtual pages are not contiguous in RAM.
+        const FIRST: WordType = 0x8000_5000;
+        const SECOND: WordType = 0x8000_8000;
+
+        let pte = |paddr: WordType, flags: WordType| (paddr >> 12) << 10 | flags;
+        let cpu_with = |second_flags: WordType| {
+            let mut cpu = TestCPUBuilder::new()
+                .csr(Mtvec::get_index(), HANDLER)
+                .mem(ROOT, pte(MID, 0b1))
+                .mem(MID, pte(LEAF, 0b1))
+                // V, R, X and A.
+                .mem(LEAF + 8, pte(FIRST, 0b100_1011))
+                .mem(LEAF + 16, pte(SECOND, second_flags))
+                // The instruction at 0x1ffe straddles the pages 0x1000 and 0x2000.
+                .mem(FIRST + 0xffe, ADDI_X5_42 as u16)
+                .mem(SECOND, (ADDI_X5_42 >> 16) as u16)
+                .pc(0x1ffe)
+                .build();
+            cpu.write_csr(Satp::get_index(), 8 << 60 | ROOT >> 12)
+                .unwrap();
+            cpu.csr.set_current_privileged(PrivilegeLevel::S);
+            cpu.step().unwrap();
+            cpu
+        };
+
+        let mut cpu = cpu_with(0b100_1011);
+        CPUChecker::new(&mut cpu).reg(5, 42).pc(0x2002);
+
+        // A fault of the second page is reported at its address.
+        let mut cpu = cpu_with(0);
+        CPUChecker::new(&mut cpu)
+            .reg(5, 0)
+            .pc(HANDLER)
+            .csr(Mcause::get_index(), Exception::InstructionPageFault.into())
+            .csr(Mepc::get_index(), 0x1ffe)
+            .csr(Mtval::get_index(), 0x2000);
+    }
 }
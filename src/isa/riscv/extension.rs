@@ -0,0 +1,273 @@
+//! Instruction-set extensions defined outside the emulator, e.g. the custom instructions of a
+//! vendor core, without touching the generated instruction tables.
+//!
+//! An [`InstructionExtension`] lists the encodings of its instructions, executes them and may
+//! bring its own CSRs. Register it with
+//! [`Decoder::new_with_extensions`](crate::isa::riscv::decoder::Decoder::new_with_extensions), or on a board with
+//! [`RVBoardBuilder::extension`](crate::board::virt::RVBoardBuilder::extension) which also adds
+//! the CSRs. Standard instructions win over extension ones with the same encoding.
+
+use std::sync::Arc;
+
+use crate::{
+    config::arch_config::WordType,
+    isa::{
+        DebugTarget,
+        riscv::{
+            csr_reg::custom::CustomCsr,
+            executor::RVCPU,
+            instruction::{RVInstrInfo, normal_compress_exec, normal_exec},
+            trap::Exception,
+        },
+        utils::DecodeMask,
+    },
+    utils::UnsignedInteger,
+};
+
+/// An encoding of an extension: `raw & mask == key`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtensionInstr {
+    pub name: &'static str,
+    pub mask: u32,
+    pub key: u32,
+}
+
+pub trait InstructionExtension: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// The encodings, `index` in [`Self::execute`] is the place of one in this list. 16-bit
+    /// encodings (whose two low bits are not `0b11`) are compressed instructions.
+    fn instructions(&self) -> Vec<ExtensionInstr>;
+
+    /// Execute the `index`-th instruction. The pc then moves to the next instruction and
+    /// `minstret` counts it, unless an exception is returned, which traps.
+    fn execute(&self, index: usize, ctx: &mut ExtensionContext) -> Result<(), Exception>;
+
+    /// CSRs the extension adds, they must not collide with the standard ones.
+    fn csrs(&self) -> Vec<CustomCsr> {
+        Vec::new()
+    }
+}
+
+/// What an extension instruction sees of the hart.
+pub struct ExtensionContext<'a> {
+    cpu: &'a mut RVCPU,
+    raw: u32,
+}
+
+impl<'a> ExtensionContext<'a> {
+    /// The encoding being executed.
+    pub fn raw(&self) -> u32 {
+        self.raw
+    }
+
+    /// The `rd`, `rs1` and `rs2` fields of the encoding.
+    pub fn rd(&self) -> u8 {
+        ((self.raw >> 7) & 0b11111) as u8
+    }
+
+    pub fn rs1(&self) -> u8 {
+        ((self.raw >> 15) & 0b11111) as u8
+    }
+
+    pub fn rs2(&self) -> u8 {
+        ((self.raw >> 20) & 0b11111) as u8
+    }
+
+    pub fn pc(&self) -> WordType {
+        self.cpu.pc
+    }
+
+    pub fn read_reg(&self, idx: u8) -> WordType {
+        self.cpu.read_reg(idx)
+    }
+
+    /// Writes to `x0` are ignored.
+    pub fn write_reg(&mut self, idx: u8, value: WordType) {
+        self.cpu.reg_file.write(idx, value);
+    }
+
+    /// Load from the virtual address `addr`, as a load instruction of the hart would.
+    pub fn load<T: UnsignedInteger>(&mut self, addr: WordType) -> Result<T, Exception> {
+        self.cpu
+            .memory
+            .read::<T>(addr, &mut self.cpu.csr)
+            .map_err(|err| {
+                self.cpu.pending_tval = Some(addr);
+                Exception::from_memory_err(err)
+            })
+    }
+
+    /// Store to the virtual address `addr`, as a store instruction of the hart would.
+    pub fn store<T: UnsignedInteger>(&mut self, addr: WordType, value: T) -> Result<(), Exception> {
+        self.cpu
+            .memory
+            .write(addr, value, &mut self.cpu.csr)
+            .map_err(|err| {
+                self.cpu.pending_tval = Some(addr);
+                Exception::from_memory_err(err)
+            })
+    }
+
+    /// Read a CSR with the privilege checks of `csrr`.
+    pub fn read_csr(&mut self, addr: WordType) -> Result<WordType, Exception> {
+        self.cpu.read_csr(addr)
+    }
+
+    /// Write a CSR with the privilege checks of `csrw`.
+    pub fn write_csr(&mut self, addr: WordType, value: WordType) -> Result<(), Exception> {
+        self.cpu.write_csr(addr, value)
+    }
+}
+
+/// An instruction of a registered extension.
+pub(super) struct ExtensionEntry {
+    pub mask: DecodeMask,
+    pub compressed: bool,
+    pub extension: Arc<dyn InstructionExtension>,
+    /// Place of the instruction in [`InstructionExtension::instructions`].
+    pub index: usize,
+}
+
+/// The entries of `extensions`, in registration order.
+pub(super) fn extension_table(extensions: &[Arc<dyn InstructionExtension>]) -> Vec<ExtensionEntry> {
+    let mut table = Vec::new();
+    for extension in extensions {
+        let instrs = extension.instructions();
+        log::info!(
+            "Extension {} adds {} instructions",
+            extension.name(),
+            instrs.len()
+        );
+        for (index, instr) in instrs.into_iter().enumerate() {
+            table.push(ExtensionEntry {
+                mask: DecodeMask {
+                    mask: instr.mask,
+                    key: instr.key,
+                },
+                compressed: instr.key & 0b11 != 0b11,
+                extension: extension.clone(),
+                index,
+            });
+        }
+    }
+    assert!(
+        table.len() <= u16::MAX as usize,
+        "too many extension instructions"
+    );
+    table
+}
+
+/// Execution of [`RiscvInstr::EXTENSION`](crate::isa::riscv::instruction::instr_table::RiscvInstr::EXTENSION).
+pub(super) fn exec_extension(info: RVInstrInfo, cpu: &mut RVCPU) -> Result<(), Exception> {
+    let RVInstrInfo::Extension { index, raw } = info else {
+        std::unreachable!();
+    };
+    let entry = cpu.decoder.extension_entry(index);
+    let (extension, index, compressed) = (entry.extension.clone(), entry.index, entry.compressed);
+    let execute = |cpu: &mut RVCPU| extension.execute(index, &mut ExtensionContext { cpu, raw });
+    match compressed {
+        true => normal_compress_exec(cpu, execute),
+        false => normal_exec(cpu, execute),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        board::{
+            Board,
+            virt::{SerialDestination, VirtBoardBuilder},
+        },
+        isa::riscv::{
+            decoder::Decoder, instruction::instr_table::RiscvInstr, isa_builder::ISABuilder,
+        },
+        ram_config,
+    };
+
+    use super::*;
+
+    const MACCOUNT: WordType = 0x7c0;
+    // mac x7, x5, x6 in the custom-0 opcode.
+    const MAC: u32 = 0x0062_838b;
+
+    /// `mac rd, rs1, rs2`: `rd += rs1 * rs2`, counted in `maccount`.
+    struct Mac;
+
+    impl InstructionExtension for Mac {
+        fn name(&self) -> &str {
+            "xmac"
+        }
+
+        fn instructions(&self) -> Vec<ExtensionInstr> {
+            vec![ExtensionInstr {
+                name: "mac",
+                mask: 0xfe00_707f,
+                key: 0x0000_000b,
+            }]
+        }
+
+        fn execute(&self, index: usize, ctx: &mut ExtensionContext) -> Result<(), Exception> {
+            assert_eq!(index, 0);
+            let product = ctx
+                .read_reg(ctx.rs1())
+                .wrapping_mul(ctx.read_reg(ctx.rs2()));
+            ctx.write_reg(ctx.rd(), ctx.read_reg(ctx.rd()).wrapping_add(product));
+            let count = ctx.read_csr(MACCOUNT)?;
+            ctx.write_csr(MACCOUNT, count + 1)
+        }
+
+        fn csrs(&self) -> Vec<CustomCsr> {
+            vec![CustomCsr {
+                name: "maccount".into(),
+                addr: MACCOUNT,
+                reset: 0,
+                mask: WordType::MAX,
+            }]
+        }
+    }
+
+    #[test]
+    fn test_decode_extension() {
+        assert_eq!(Decoder::new().decode(MAC.into()), None);
+
+        let mut decoder = Decoder::new_with_extensions(ISABuilder::all(), vec![Arc::new(Mac)]);
+        let decoded = decoder.decode(MAC.into()).unwrap();
+        assert_eq!(decoded.instr, RiscvInstr::EXTENSION);
+        assert_eq!(decoded.info, RVInstrInfo::Extension { index: 0, raw: MAC });
+        // addi x5, x0, 3 is still a standard instruction.
+        assert_eq!(
+            decoder.decode(0x0030_0293.into()).unwrap().instr,
+            RiscvInstr::ADDI
+        );
+
+        decoder.reconfigure(decoder.extension_bits());
+        assert!(decoder.decode(MAC.into()).is_some());
+    }
+
+    #[test]
+    fn test_execute_extension() {
+        let program: Vec<u8> = [
+            0x0030_0293u32, // addi x5, x0, 3
+            0x0040_0313,    // addi x6, x0, 4
+            0x00a0_0393,    // addi x7, x0, 10
+            MAC,
+            MAC,
+        ]
+        .iter()
+        .flat_map(|instr| instr.to_le_bytes())
+        .collect();
+        let mut board = VirtBoardBuilder::new()
+            .serial(SerialDestination::Buffer)
+            .binary(program)
+            .machine(|board| board.extension(Arc::new(Mac)))
+            .build()
+            .unwrap();
+        for _ in 0..5 {
+            board.step().unwrap();
+        }
+        assert_eq!(board.cpu.read_reg(7), 34);
+        assert_eq!(board.cpu.debug_csr(MACCOUNT, None), Some(2));
+        assert_eq!(board.cpu.read_pc(), ram_config::BASE_ADDR + 20);
+    }
+}
@@ -0,0 +1,304 @@
+//! ftrace-like tracing of guest function calls, using the symbols of the loaded ELF.
+//!
+//! A `jal`/`jalr` which links `ra` (or `t0`) to the start of a known symbol enters the function,
+//! the `ret` to its return address leaves it, together with the frames it skipped (e.g. after a
+//! `longjmp`). A jump without link to the start of another symbol is a tail call: the current
+//! function is left and the new one entered at the same depth. Every entry and exit is reported
+//! with the `minstret` value at that point and indented by the call depth, so no instrumentation
+//! of the guest is needed.
+
+use std::collections::HashMap;
+
+use crate::{
+    config::arch_config::WordType,
+    isa::riscv::instruction::{RVInstrInfo, instr_table::RiscvInstr},
+    load::SymTab,
+};
+
+/// Frames are capped to this depth, the guest may never return to some of them
+/// (e.g. a kernel switching to another task's stack).
+const MAX_DEPTH: usize = 256;
+
+/// Registers used as the link register by the calling convention.
+const RA: u8 = 1;
+const T0: u8 = 5;
+
+/// What a control transfer instruction means for the call stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JumpKind {
+    /// A jump linking `ra` or `t0`.
+    Call,
+    /// A `jr` through `ra` or `t0`.
+    Return,
+    /// Any other jump, a tail call if it lands on the start of a function.
+    Jump,
+}
+
+impl JumpKind {
+    /// Classify `instr`, `None` for instructions which are not unconditional jumps.
+    pub fn of(instr: RiscvInstr, info: &RVInstrInfo) -> Option<Self> {
+        let is_link = |reg: u8| reg == RA || reg == T0;
+        match (instr, info) {
+            (RiscvInstr::JAL, RVInstrInfo::J { rd, .. }) => {
+                Some(if is_link(*rd) { Self::Call } else { Self::Jump })
+            }
+            (RiscvInstr::JALR, RVInstrInfo::I { rs1, rd, .. }) => {
+                if is_link(*rd) {
+                    Some(Self::Call)
+                } else if *rd == 0 && is_link(*rs1) {
+                    Some(Self::Return)
+                } else {
+                    Some(Self::Jump)
+                }
+            }
+            (RiscvInstr::C_JAL | RiscvInstr::C_JALR, _) => Some(Self::Call),
+            (RiscvInstr::C_J, _) => Some(Self::Jump),
+            (RiscvInstr::C_JR, RVInstrInfo::CR { rd_rs1, .. }) => Some(if is_link(*rd_rs1) {
+                Self::Return
+            } else {
+                Self::Jump
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionEvent {
+    Entry,
+    Exit,
+}
+
+/// An entry to or an exit from a function, see [`FunctionTracer::on_jump`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionRecord {
+    pub event: FunctionEvent,
+    pub name: String,
+    /// Number of frames around this one.
+    pub depth: usize,
+    /// `minstret` when the jump was taken.
+    pub instret: WordType,
+}
+
+impl std::fmt::Display for FunctionRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let indent = "  ".repeat(self.depth);
+        match self.event {
+            FunctionEvent::Entry => {
+                write!(f, "{:>12} | {}{}() {{", self.instret, indent, self.name)
+            }
+            FunctionEvent::Exit => {
+                write!(f, "{:>12} | {}}} /* {} */", self.instret, indent, self.name)
+            }
+        }
+    }
+}
+
+struct Frame {
+    entry: WordType,
+    /// `None` for functions entered by a jump, which can not be returned from.
+    return_addr: Option<WordType>,
+}
+
+/// Turns the jumps of a hart into [`FunctionRecord`]s. Attach it to a CPU with
+/// [`RVCPU::set_function_tracer`](crate::isa::riscv::executor::RVCPU::set_function_tracer).
+pub struct FunctionTracer {
+    functions: HashMap<WordType, String>,
+    frames: Vec<Frame>,
+}
+
+impl FunctionTracer {
+    pub fn new(symtab: &SymTab) -> Self {
+        let functions = symtab
+            .iter()
+            .filter(|(name, _)| {
+                !name.is_empty() && !name.starts_with(".L") && !name.starts_with('$')
+            })
+            .map(|(name, addr)| (*addr as WordType, name.clone()))
+            .collect();
+        Self {
+            functions,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Called after a jump of `kind` to `target` was taken, `return_addr` is the address of the
+    /// instruction after the jump.
+    pub fn on_jump(
+        &mut self,
+        kind: JumpKind,
+        target: WordType,
+        return_addr: WordType,
+        instret: WordType,
+    ) -> Vec<FunctionRecord> {
+        let mut records = Vec::new();
+        match kind {
+            JumpKind::Call => {
+                if self.functions.contains_key(&target) {
+                    self.enter(target, Some(return_addr), instret, &mut records);
+                }
+            }
+            JumpKind::Return => {
+                if let Some(idx) = self
+                    .frames
+                    .iter()
+                    .rposition(|frame| frame.return_addr == Some(target))
+                {
+                    while self.frames.len() > idx {
+                        self.exit(instret, &mut records);
+                    }
+                }
+            }
+            JumpKind::Jump => {
+                let is_new_function = self.functions.contains_key(&target)
+                    && self.frames.last().is_none_or(|frame| frame.entry != target);
+                if is_new_function {
+                    let return_addr = match self.frames.last() {
+                        Some(_) => self.exit(instret, &mut records),
+                        None => None,
+                    };
+                    self.enter(target, return_addr, instret, &mut records);
+                }
+            }
+        }
+        records
+    }
+
+    fn enter(
+        &mut self,
+        entry: WordType,
+        return_addr: Option<WordType>,
+        instret: WordType,
+        records: &mut Vec<FunctionRecord>,
+    ) {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        records.push(self.record(FunctionEvent::Entry, entry, instret));
+        self.frames.push(Frame { entry, return_addr });
+    }
+
+    /// Leave the innermost function, returns where it would have returned to.
+    fn exit(&mut self, instret: WordType, records: &mut Vec<FunctionRecord>) -> Option<WordType> {
+        let frame = self.frames.pop()?;
+        records.push(self.record(FunctionEvent::Exit, frame.entry, instret));
+        frame.return_addr
+    }
+
+    fn record(&self, event: FunctionEvent, entry: WordType, instret: WordType) -> FunctionRecord {
+        FunctionRecord {
+            event,
+            name: self.functions[&entry].clone(),
+            depth: self.frames.len(),
+            instret,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracer() -> FunctionTracer {
+        let symbols = [
+            ("main".to_string(), 0x1000),
+            ("foo".to_string(), 0x2000),
+            ("bar".to_string(), 0x3000),
+            ("".to_string(), 0x4000),
+        ];
+        FunctionTracer::new(&SymTab::from(&symbols))
+    }
+
+    fn lines(records: Vec<FunctionRecord>) -> Vec<String> {
+        records.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_classify() {
+        let jal = |rd| RVInstrInfo::J { rd, imm: 0 };
+        let jalr = |rd, rs1| RVInstrInfo::I { rs1, rd, imm: 0 };
+        assert_eq!(JumpKind::of(RiscvInstr::JAL, &jal(1)), Some(JumpKind::Call));
+        assert_eq!(JumpKind::of(RiscvInstr::JAL, &jal(0)), Some(JumpKind::Jump));
+        assert_eq!(
+            JumpKind::of(RiscvInstr::JALR, &jalr(5, 6)),
+            Some(JumpKind::Call)
+        );
+        assert_eq!(
+            JumpKind::of(RiscvInstr::JALR, &jalr(0, 1)),
+            Some(JumpKind::Return)
+        );
+        assert_eq!(
+            JumpKind::of(RiscvInstr::JALR, &jalr(0, 6)),
+            Some(JumpKind::Jump)
+        );
+        let jr = |rd_rs1| RVInstrInfo::CR { rd_rs1, rs2: 0 };
+        assert_eq!(
+            JumpKind::of(RiscvInstr::C_JR, &jr(1)),
+            Some(JumpKind::Return)
+        );
+        assert_eq!(
+            JumpKind::of(RiscvInstr::C_JR, &jr(10)),
+            Some(JumpKind::Jump)
+        );
+        assert_eq!(JumpKind::of(RiscvInstr::ADDI, &jalr(1, 1)), None);
+    }
+
+    #[test]
+    fn test_nested_calls() {
+        let mut tracer = tracer();
+        assert_eq!(
+            lines(tracer.on_jump(JumpKind::Call, 0x1000, 0x8, 1)),
+            ["           1 | main() {"]
+        );
+        assert_eq!(
+            lines(tracer.on_jump(JumpKind::Call, 0x2000, 0x1010, 5)),
+            ["           5 |   foo() {"]
+        );
+        // Calls to unknown addresses are not frames.
+        assert!(tracer.on_jump(JumpKind::Call, 0x2100, 0x2010, 7).is_empty());
+        assert!(tracer.on_jump(JumpKind::Return, 0x2010, 0, 8).is_empty());
+        assert!(tracer.on_jump(JumpKind::Call, 0x4000, 0x2014, 9).is_empty());
+
+        assert_eq!(
+            lines(tracer.on_jump(JumpKind::Return, 0x1010, 0, 12)),
+            ["          12 |   } /* foo */"]
+        );
+        assert_eq!(
+            lines(tracer.on_jump(JumpKind::Return, 0x8, 0, 20)),
+            ["          20 | } /* main */"]
+        );
+    }
+
+    #[test]
+    fn test_return_unwinds_skipped_frames() {
+        let mut tracer = tracer();
+        tracer.on_jump(JumpKind::Call, 0x1000, 0x8, 1);
+        tracer.on_jump(JumpKind::Call, 0x2000, 0x1010, 2);
+        tracer.on_jump(JumpKind::Call, 0x3000, 0x2010, 3);
+
+        let records = tracer.on_jump(JumpKind::Return, 0x1010, 0, 4);
+        let names: Vec<_> = records.iter().map(|r| (r.event, r.name.as_str())).collect();
+        assert_eq!(
+            names,
+            [(FunctionEvent::Exit, "bar"), (FunctionEvent::Exit, "foo")]
+        );
+        assert_eq!(tracer.frames.len(), 1);
+    }
+
+    #[test]
+    fn test_tail_call() {
+        let mut tracer = tracer();
+        tracer.on_jump(JumpKind::Call, 0x2000, 0x1010, 1);
+        assert_eq!(
+            lines(tracer.on_jump(JumpKind::Jump, 0x3000, 0x2020, 6)),
+            ["           6 | } /* foo */", "           6 | bar() {"]
+        );
+        // A loop back to the start of the function is no call.
+        assert!(tracer.on_jump(JumpKind::Jump, 0x3000, 0x3020, 7).is_empty());
+        // `bar` returns to the caller of `foo`.
+        assert_eq!(
+            lines(tracer.on_jump(JumpKind::Return, 0x1010, 0, 9)),
+            ["           9 | } /* bar */"]
+        );
+    }
+}
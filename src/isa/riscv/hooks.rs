@@ -0,0 +1,230 @@
+//! Closures called around the instructions executed by the hart, to build tracers, taint
+//! tracking or coverage tools on top of the emulator.
+//!
+//! Hooks are registered on the CPU with [`RVCPU::add_pre_exec_hook`] and
+//! [`RVCPU::add_post_exec_hook`], for every instruction or only for one [`RiscvInstr`]. They see
+//! the hart through a read-only [`CpuView`]. Without hooks the executor only pays a branch.
+
+use crate::{
+    config::arch_config::WordType,
+    isa::riscv::{
+        csr_reg::PrivilegeLevel, decoder::DecodeInstr, executor::RVCPU,
+        instruction::instr_table::RiscvInstr, trap::Exception,
+    },
+};
+
+/// Called before an instruction executes, with its address.
+pub type PreExecHook = Box<dyn FnMut(&CpuView, WordType, &DecodeInstr)>;
+/// Called after an instruction executed, with its address and the exception it raised, if any.
+/// Instructions which trap have not changed the registers.
+pub type PostExecHook = Box<dyn FnMut(&CpuView, WordType, &DecodeInstr, Option<Exception>)>;
+
+/// Identifies a hook for [`RVCPU::remove_exec_hook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HookId(u32);
+
+/// The state of the hart, as hooks see it.
+pub struct CpuView<'a> {
+    cpu: &'a RVCPU,
+}
+
+impl CpuView<'_> {
+    /// The next instruction to execute: the hooked one before it runs, the following one after.
+    pub fn pc(&self) -> WordType {
+        self.cpu.pc
+    }
+
+    pub fn reg(&self, idx: u8) -> WordType {
+        self.cpu.reg_file[idx as usize]
+    }
+
+    /// `x0`-`x31`.
+    pub fn regs(&self) -> &[WordType] {
+        self.cpu.reg_file.as_slice()
+    }
+
+    /// Raw bits of the floating-point register `f<idx>`.
+    pub fn float_reg(&self, idx: u8) -> u64 {
+        self.cpu.fpu.load::<f64>(idx).to_bits()
+    }
+
+    /// Read a CSR, whatever the privilege of the hart.
+    pub fn csr(&self, addr: WordType) -> Option<WordType> {
+        self.cpu.csr.read_uncheck_privilege(addr)
+    }
+
+    pub fn privilege(&self) -> PrivilegeLevel {
+        self.cpu.csr.privelege_level()
+    }
+}
+
+struct Hook<F> {
+    id: HookId,
+    /// Only this instruction, or every one.
+    filter: Option<RiscvInstr>,
+    f: F,
+}
+
+fn matches<F>(hook: &Hook<F>, instr: RiscvInstr) -> bool {
+    hook.filter.is_none_or(|filter| filter == instr)
+}
+
+/// The hooks of a hart, in registration order.
+#[derive(Default)]
+pub(crate) struct ExecHooks {
+    pre: Vec<Hook<PreExecHook>>,
+    post: Vec<Hook<PostExecHook>>,
+    next_id: u32,
+}
+
+impl ExecHooks {
+    fn next_id(&mut self) -> HookId {
+        self.next_id += 1;
+        HookId(self.next_id)
+    }
+
+    pub(crate) fn before(&mut self, cpu: &RVCPU, pc: WordType, instr: &DecodeInstr) {
+        let view = CpuView { cpu };
+        for hook in self
+            .pre
+            .iter_mut()
+            .filter(|hook| matches(hook, instr.instr))
+        {
+            (hook.f)(&view, pc, instr);
+        }
+    }
+
+    pub(crate) fn after(
+        &mut self,
+        cpu: &RVCPU,
+        pc: WordType,
+        instr: &DecodeInstr,
+        exception: Option<Exception>,
+    ) {
+        let view = CpuView { cpu };
+        for hook in self
+            .post
+            .iter_mut()
+            .filter(|hook| matches(hook, instr.instr))
+        {
+            (hook.f)(&view, pc, instr, exception);
+        }
+    }
+}
+
+impl RVCPU {
+    /// Call `hook` before every instruction, or only before `filter` instructions.
+    pub fn add_pre_exec_hook(&mut self, filter: Option<RiscvInstr>, hook: PreExecHook) -> HookId {
+        let hooks = self.exec_hooks.get_or_insert_default();
+        let id = hooks.next_id();
+        hooks.pre.push(Hook {
+            id,
+            filter,
+            f: hook,
+        });
+        id
+    }
+
+    /// Call `hook` after every instruction, or only after `filter` instructions.
+    pub fn add_post_exec_hook(&mut self, filter: Option<RiscvInstr>, hook: PostExecHook) -> HookId {
+        let hooks = self.exec_hooks.get_or_insert_default();
+        let id = hooks.next_id();
+        hooks.post.push(Hook {
+            id,
+            filter,
+            f: hook,
+        });
+        id
+    }
+
+    /// Unregister the hook `id`, returns whether it was registered.
+    pub fn remove_exec_hook(&mut self, id: HookId) -> bool {
+        let Some(hooks) = self.exec_hooks.as_mut() else {
+            return false;
+        };
+        let count = hooks.pre.len() + hooks.post.len();
+        hooks.pre.retain(|hook| hook.id != id);
+        hooks.post.retain(|hook| hook.id != id);
+        hooks.pre.len() + hooks.post.len() != count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+    use crate::{
+        board::{
+            Board,
+            virt::{SerialDestination, VirtBoardBuilder},
+        },
+        isa::DebugTarget,
+        ram_config,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_exec_hooks() {
+        let program: Vec<u8> = [
+            0x0030_0293u32, // addi x5, x0, 3
+            0x0040_0313,    // addi x6, x0, 4
+            0x0062_83b3,    // add x7, x5, x6
+            0x0000_0073,    // ecall
+        ]
+        .iter()
+        .flat_map(|instr| instr.to_le_bytes())
+        .collect();
+        let mut board = VirtBoardBuilder::new()
+            .serial(SerialDestination::Buffer)
+            .binary(program)
+            .build()
+            .unwrap();
+
+        let coverage = Rc::new(RefCell::new(BTreeMap::new()));
+        let seen = coverage.clone();
+        let counter = board.cpu.add_pre_exec_hook(
+            None,
+            Box::new(move |cpu, pc, _| {
+                assert_eq!(cpu.pc(), pc);
+                *seen.borrow_mut().entry(pc).or_insert(0) += 1;
+            }),
+        );
+
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let seen = results.clone();
+        board.cpu.add_post_exec_hook(
+            Some(RiscvInstr::ADD),
+            Box::new(move |cpu, _, instr, exception| {
+                assert_eq!(instr.instr, RiscvInstr::ADD);
+                seen.borrow_mut().push((cpu.reg(7), exception));
+            }),
+        );
+        let traps = Rc::new(RefCell::new(Vec::new()));
+        let seen = traps.clone();
+        board.cpu.add_post_exec_hook(
+            Some(RiscvInstr::ECALL),
+            Box::new(move |_, pc, _, exception| seen.borrow_mut().push((pc, exception))),
+        );
+
+        for _ in 0..4 {
+            board.step().unwrap();
+        }
+        let base = ram_config::BASE_ADDR;
+        assert_eq!(
+            coverage.borrow().keys().copied().collect::<Vec<_>>(),
+            [base, base + 4, base + 8, base + 12]
+        );
+        assert_eq!(*results.borrow(), [(7, None)]);
+        assert_eq!(
+            *traps.borrow(),
+            [(base + 12, Some(Exception::MachineEnvCall))]
+        );
+
+        assert!(board.cpu.remove_exec_hook(counter));
+        assert!(!board.cpu.remove_exec_hook(counter));
+        board.cpu.write_pc(base);
+        board.step().unwrap();
+        assert_eq!(coverage.borrow()[&base], 1);
+    }
+}
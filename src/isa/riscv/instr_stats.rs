@@ -0,0 +1,190 @@
+//! Histogram of the instructions executed by the hart, to see the opcode mix of a workload, e.g.
+//! which fraction of it is memory accesses or multiplications and divisions.
+
+use serde::Serialize;
+
+use crate::isa::riscv::{
+    cost_profile::InstrClass, executor::RVCPU, instruction::instr_table::RiscvInstr,
+};
+
+/// Executions of each [`RiscvInstr`], including the ones which trapped.
+pub struct InstrStats {
+    counts: Box<[u64]>,
+}
+
+/// A line of an [`IstatsSnapshot`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InstrCount {
+    pub name: &'static str,
+    pub count: u64,
+    /// Of every instruction executed.
+    pub percent: f64,
+}
+
+/// The counters grouped for display, every list sorted by decreasing count.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IstatsSnapshot {
+    pub total: u64,
+    /// By ISA subset, e.g. `RV64I` or `RVC`.
+    pub isa: Vec<InstrCount>,
+    /// By [`InstrClass`].
+    pub class: Vec<InstrCount>,
+    pub instr: Vec<InstrCount>,
+}
+
+impl Default for InstrStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InstrStats {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; RiscvInstr::ALL.len()].into_boxed_slice(),
+        }
+    }
+
+    #[inline]
+    pub fn record(&mut self, instr: RiscvInstr) {
+        self.counts[instr as usize] += 1;
+    }
+
+    pub fn count(&self, instr: RiscvInstr) -> u64 {
+        self.counts[instr as usize]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.fill(0);
+    }
+
+    pub fn snapshot(&self) -> IstatsSnapshot {
+        let total = self.total();
+        let executed = || {
+            RiscvInstr::ALL
+                .iter()
+                .zip(self.counts.iter())
+                .filter(|&(_, &count)| count != 0)
+                .map(|(&instr, &count)| (instr, count))
+        };
+        let group = |key: &dyn Fn(RiscvInstr) -> &'static str| {
+            let mut groups: Vec<(&'static str, u64)> = Vec::new();
+            for (instr, count) in executed() {
+                let name = key(instr);
+                match groups.iter_mut().find(|(group, _)| *group == name) {
+                    Some((_, sum)) => *sum += count,
+                    None => groups.push((name, count)),
+                }
+            }
+            sorted(groups, total)
+        };
+
+        IstatsSnapshot {
+            total,
+            isa: group(&|instr| instr.isa_name()),
+            class: group(&|instr| InstrClass::of(instr).name()),
+            instr: sorted(
+                executed()
+                    .map(|(instr, count)| (instr.name(), count))
+                    .collect(),
+                total,
+            ),
+        }
+    }
+}
+
+fn sorted(mut counts: Vec<(&'static str, u64)>, total: u64) -> Vec<InstrCount> {
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    counts
+        .into_iter()
+        .map(|(name, count)| InstrCount {
+            name,
+            count,
+            percent: count as f64 * 100.0 / total.max(1) as f64,
+        })
+        .collect()
+}
+
+impl RVCPU {
+    /// Start counting the executed instructions, keeping the counts if already counting.
+    pub fn enable_instr_stats(&mut self) -> &mut InstrStats {
+        self.instr_stats.get_or_insert_default()
+    }
+
+    pub fn disable_instr_stats(&mut self) {
+        self.instr_stats = None;
+    }
+
+    /// `None` unless enabled with [`Self::enable_instr_stats`].
+    pub fn instr_stats(&self) -> Option<&InstrStats> {
+        self.instr_stats.as_deref()
+    }
+
+    pub fn instr_stats_mut(&mut self) -> Option<&mut InstrStats> {
+        self.instr_stats.as_deref_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::board::{
+        Board,
+        virt::{SerialDestination, VirtBoardBuilder},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_instr_stats() {
+        let program: Vec<u8> = [
+            0x0030_0293u32, // addi x5, x0, 3
+            0x0040_0313,    // addi x6, x0, 4
+            0x0262_83b3,    // mul x7, x5, x6
+            0x0000_0013,    // addi x0, x0, 0
+        ]
+        .iter()
+        .flat_map(|instr| instr.to_le_bytes())
+        .collect();
+        let mut board = VirtBoardBuilder::new()
+            .serial(SerialDestination::Buffer)
+            .binary(program)
+            .build()
+            .unwrap();
+        assert!(board.cpu.instr_stats().is_none());
+        board.cpu.enable_instr_stats();
+        for _ in 0..4 {
+            board.step().unwrap();
+        }
+
+        let stats = board.cpu.instr_stats().unwrap();
+        assert_eq!(stats.count(RiscvInstr::ADDI), 3);
+        assert_eq!(stats.count(RiscvInstr::MUL), 1);
+        assert_eq!(stats.total(), 4);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.instr.len(), 2);
+        assert_eq!(snapshot.instr[0].name, "ADDI");
+        assert_eq!(snapshot.instr[0].percent, 75.0);
+        assert_eq!(
+            snapshot
+                .isa
+                .iter()
+                .map(|group| group.name)
+                .collect::<Vec<_>>(),
+            [RiscvInstr::ADDI.isa_name(), RiscvInstr::MUL.isa_name()]
+        );
+        assert_eq!(snapshot.class[1].name, "mul");
+        assert_eq!(snapshot.class[1].count, 1);
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["total"], 4);
+        assert_eq!(json["instr"][1]["name"], "MUL");
+
+        board.cpu.instr_stats_mut().unwrap().clear();
+        assert_eq!(board.cpu.instr_stats().unwrap().total(), 0);
+    }
+}
@@ -1,9 +1,39 @@
+//! The A extension and `fence`.
+//!
+//! # Memory model
+//!
+//! The guest's RVWMO is mapped onto the host's (C++11) memory model. Each ordering annotation
+//! becomes the host ordering below:
+//!
+//! | Guest                               | Host                     |
+//! |-------------------------------------|--------------------------|
+//! | AMO                                 | atomic read-modify-write |
+//! | `.aq`                               | `Acquire`                |
+//! | `.rl`                               | `Release`                |
+//! | `.aqrl`                             | `SeqCst`                 |
+//! | `fence` with `w` in pred, `r` in succ | `fence(SeqCst)`        |
+//! | `fence r, rw`                       | `fence(Acquire)`         |
+//! | `fence rw, w`                       | `fence(Release)`         |
+//! | other `fence`, `fence.tso`          | `fence(AcqRel)`          |
+//!
+//! An AMO with both bits set is sequentially consistent in RVWMO, and a fence is the only way to
+//! order a store before a later load, which the host only guarantees with `SeqCst`. `lr` and `sc`
+//! keep their reservation in the RAM of the hart. Device
+//! accesses are ordered by the single device bus, the `i` and `o` bits are treated as `r` and `w`.
+//!
+//! The harts of a board are stepped one after the other on one thread, so every execution is
+//! sequentially consistent and the mapping is never put to the test by the host. Only the mapping
+//! itself, [`get_fence_order`] and [`get_amo_order`], is unit-tested. The interleaving tests of this module
+//! check the instructions over shared RAM under many schedules, not the host ordering.
+
 use std::cmp;
 use std::sync::atomic::{self, Ordering};
 
+use super::normal_exec;
 use crate::utils::WordTrait;
 use crate::{
     config::arch_config::WordType,
+    device::pma::AmoClass,
     isa::riscv::{
         csr_reg::csr_macro::Minstret, executor::RVCPU, instruction::RVInstrInfo, trap::Exception,
     },
@@ -17,11 +47,16 @@ pub(super) trait AMOTrait<T>
 where
     T: UnsignedInteger,
 {
+    /// The PMA class of regions where the operation is supported.
+    const CLASS: AmoClass;
+
     fn exec(a: &T::AtomicType, b: T, order: atomic::Ordering) -> Result<T, Exception>;
 }
 
 pub(super) struct ExecAmoAdd {}
 impl AMOTrait<u64> for ExecAmoAdd {
+    const CLASS: AmoClass = AmoClass::Arithmetic;
+
     fn exec(
         lhs: &<u64 as UnsignedInteger>::AtomicType,
         rhs: u64,
@@ -31,6 +66,8 @@ impl AMOTrait<u64> for ExecAmoAdd {
     }
 }
 impl AMOTrait<u32> for ExecAmoAdd {
+    const CLASS: AmoClass = AmoClass::Arithmetic;
+
     fn exec(
         lhs: &<u32 as UnsignedInteger>::AtomicType,
         rhs: u32,
@@ -42,6 +79,8 @@ impl AMOTrait<u32> for ExecAmoAdd {
 
 pub(super) struct ExecAmoAnd {}
 impl AMOTrait<u64> for ExecAmoAnd {
+    const CLASS: AmoClass = AmoClass::Logical;
+
     fn exec(
         lhs: &<u64 as UnsignedInteger>::AtomicType,
         rhs: u64,
@@ -51,6 +90,8 @@ impl AMOTrait<u64> for ExecAmoAnd {
     }
 }
 impl AMOTrait<u32> for ExecAmoAnd {
+    const CLASS: AmoClass = AmoClass::Logical;
+
     fn exec(
         lhs: &<u32 as UnsignedInteger>::AtomicType,
         rhs: u32,
@@ -62,6 +103,8 @@ impl AMOTrait<u32> for ExecAmoAnd {
 
 pub(super) struct ExecAmoOr {}
 impl AMOTrait<u64> for ExecAmoOr {
+    const CLASS: AmoClass = AmoClass::Logical;
+
     fn exec(
         lhs: &<u64 as UnsignedInteger>::AtomicType,
         rhs: u64,
@@ -71,6 +114,8 @@ impl AMOTrait<u64> for ExecAmoOr {
     }
 }
 impl AMOTrait<u32> for ExecAmoOr {
+    const CLASS: AmoClass = AmoClass::Logical;
+
     fn exec(
         lhs: &<u32 as UnsignedInteger>::AtomicType,
         rhs: u32,
@@ -82,6 +127,8 @@ impl AMOTrait<u32> for ExecAmoOr {
 
 pub(super) struct ExecAmoXor {}
 impl AMOTrait<u64> for ExecAmoXor {
+    const CLASS: AmoClass = AmoClass::Logical;
+
     fn exec(
         lhs: &<u64 as UnsignedInteger>::AtomicType,
         rhs: u64,
@@ -91,6 +138,8 @@ impl AMOTrait<u64> for ExecAmoXor {
     }
 }
 impl AMOTrait<u32> for ExecAmoXor {
+    const CLASS: AmoClass = AmoClass::Logical;
+
     fn exec(
         lhs: &<u32 as UnsignedInteger>::AtomicType,
         rhs: u32,
@@ -102,6 +151,8 @@ impl AMOTrait<u32> for ExecAmoXor {
 
 pub(super) struct ExecAmoMax {}
 impl AMOTrait<u64> for ExecAmoMax {
+    const CLASS: AmoClass = AmoClass::Arithmetic;
+
     fn exec(
         lhs: &<u64 as UnsignedInteger>::AtomicType,
         rhs: u64,
@@ -114,6 +165,8 @@ impl AMOTrait<u64> for ExecAmoMax {
     }
 }
 impl AMOTrait<u32> for ExecAmoMax {
+    const CLASS: AmoClass = AmoClass::Arithmetic;
+
     fn exec(
         lhs: &<u32 as UnsignedInteger>::AtomicType,
         rhs: u32,
@@ -128,6 +181,8 @@ impl AMOTrait<u32> for ExecAmoMax {
 
 pub(super) struct ExecAmoMin {}
 impl AMOTrait<u64> for ExecAmoMin {
+    const CLASS: AmoClass = AmoClass::Arithmetic;
+
     fn exec(
         lhs: &<u64 as UnsignedInteger>::AtomicType,
         rhs: u64,
@@ -140,6 +195,8 @@ impl AMOTrait<u64> for ExecAmoMin {
     }
 }
 impl AMOTrait<u32> for ExecAmoMin {
+    const CLASS: AmoClass = AmoClass::Arithmetic;
+
     fn exec(
         lhs: &<u32 as UnsignedInteger>::AtomicType,
         rhs: u32,
@@ -154,6 +211,8 @@ impl AMOTrait<u32> for ExecAmoMin {
 
 pub(super) struct ExecAmoMaxU {}
 impl AMOTrait<u64> for ExecAmoMaxU {
+    const CLASS: AmoClass = AmoClass::Arithmetic;
+
     fn exec(
         lhs: &<u64 as UnsignedInteger>::AtomicType,
         rhs: u64,
@@ -163,6 +222,8 @@ impl AMOTrait<u64> for ExecAmoMaxU {
     }
 }
 impl AMOTrait<u32> for ExecAmoMaxU {
+    const CLASS: AmoClass = AmoClass::Arithmetic;
+
     fn exec(
         lhs: &<u32 as UnsignedInteger>::AtomicType,
         rhs: u32,
@@ -174,6 +235,8 @@ impl AMOTrait<u32> for ExecAmoMaxU {
 
 pub(super) struct ExecAmoMinU {}
 impl AMOTrait<u64> for ExecAmoMinU {
+    const CLASS: AmoClass = AmoClass::Arithmetic;
+
     fn exec(
         lhs: &<u64 as UnsignedInteger>::AtomicType,
         rhs: u64,
@@ -183,6 +246,8 @@ impl AMOTrait<u64> for ExecAmoMinU {
     }
 }
 impl AMOTrait<u32> for ExecAmoMinU {
+    const CLASS: AmoClass = AmoClass::Arithmetic;
+
     fn exec(
         lhs: &<u32 as UnsignedInteger>::AtomicType,
         rhs: u32,
@@ -194,6 +259,8 @@ impl AMOTrait<u32> for ExecAmoMinU {
 
 pub(super) struct ExecAmoSwap {}
 impl AMOTrait<u64> for ExecAmoSwap {
+    const CLASS: AmoClass = AmoClass::Swap;
+
     fn exec(
         lhs: &<u64 as UnsignedInteger>::AtomicType,
         rhs: u64,
@@ -203,6 +270,8 @@ impl AMOTrait<u64> for ExecAmoSwap {
     }
 }
 impl AMOTrait<u32> for ExecAmoSwap {
+    const CLASS: AmoClass = AmoClass::Swap;
+
     fn exec(
         lhs: &<u32 as UnsignedInteger>::AtomicType,
         rhs: u32,
@@ -215,15 +284,52 @@ impl AMOTrait<u32> for ExecAmoSwap {
 // ----------------------------------
 // Atomic Memory Operation executor
 // ----------------------------------
-fn get_amo_order(aq: bool, rl: bool) -> std::sync::atomic::Ordering {
+fn get_amo_order(aq: bool, rl: bool) -> Ordering {
     match (aq, rl) {
-        (false, false) => std::sync::atomic::Ordering::Relaxed,
-        (true, false) => std::sync::atomic::Ordering::Acquire,
-        (false, true) => std::sync::atomic::Ordering::Release,
-        (true, true) => std::sync::atomic::Ordering::AcqRel,
+        (false, false) => Ordering::Relaxed,
+        (true, false) => Ordering::Acquire,
+        (false, true) => Ordering::Release,
+        (true, true) => Ordering::SeqCst,
+    }
+}
+
+const FENCE_R: u8 = 0b1010; // `r` or `i`
+const FENCE_W: u8 = 0b0101; // `w` or `o`
+const FENCE_FM_TSO: u8 = 0b1000;
+
+/// The host fence for a `fence` whose immediate holds `fm`, `pred` and `succ`, `None` if it
+/// orders nothing.
+fn get_fence_order(imm: WordType) -> Option<Ordering> {
+    let pred = (imm >> 4) as u8 & 0xf;
+    let succ = imm as u8 & 0xf;
+    let tso = (imm >> 8) as u8 & 0xf == FENCE_FM_TSO;
+
+    if pred == 0 || succ == 0 {
+        None
+    } else if !tso && pred & FENCE_W != 0 && succ & FENCE_R != 0 {
+        Some(Ordering::SeqCst)
+    } else if pred & FENCE_W == 0 {
+        Some(Ordering::Acquire)
+    } else if succ & FENCE_R == 0 {
+        Some(Ordering::Release)
+    } else {
+        Some(Ordering::AcqRel)
     }
 }
 
+pub(super) fn exec_fence(info: RVInstrInfo, cpu: &mut RVCPU) -> Result<(), Exception> {
+    let RVInstrInfo::I { imm, .. } = info else {
+        unreachable!()
+    };
+
+    normal_exec(cpu, |_| {
+        if let Some(order) = get_fence_order(imm) {
+            atomic::fence(order);
+        }
+        Ok(())
+    })
+}
+
 /// let t = mem[x[rs1]];  
 /// x[rd] = t;
 /// mem[x[rs1]] = t OP x[rs2];
@@ -248,11 +354,13 @@ where
 
     let (val1, val2) = cpu.reg_file.read(rs1, rs2);
     let order = get_amo_order(aq, rl);
-    let res = cpu
-        .memory
-        .fetch_and_op_amo(val1, T::truncate_from(val2), &mut cpu.csr, |l, r| {
-            F::exec(l, r, order)
-        });
+    let res = cpu.memory.fetch_and_op_amo(
+        val1,
+        T::truncate_from(val2),
+        F::CLASS,
+        &mut cpu.csr,
+        |l, r| F::exec(l, r, order),
+    );
 
     let res = match res {
         Err(e) => {
@@ -341,3 +449,175 @@ where
         unreachable!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha12Rng;
+
+    use super::*;
+    use crate::{isa::riscv::cpu_tester::TestCPUBuilder, ram_config::BASE_ADDR};
+
+    /// The two locations of the interleaving tests, in different reservation granules.
+    const X: WordType = BASE_ADDR + 0x1000;
+    const Y: WordType = BASE_ADDR + 0x1008;
+    const SCHEDULES: u64 = 200;
+
+    const SW_T0_A0: u32 = 0x0055_2023; // sw t0, 0(a0)
+    const SW_T0_A1: u32 = 0x0055_a023; // sw t0, 0(a1)
+    const LW_T1_A0: u32 = 0x0005_2303; // lw t1, 0(a0)
+    const LW_T1_A1: u32 = 0x0005_a303; // lw t1, 0(a1)
+    const LW_T2_A0: u32 = 0x0005_2383; // lw t2, 0(a0)
+    const BEQZ_T1_BACK: u32 = 0xfe03_0ee3; // beqz t1, -4
+    const FENCE_RW_RW: u32 = 0x0330_000f; // fence rw, rw
+    const FENCE_RW_W: u32 = 0x0310_000f; // fence rw, w
+    const FENCE_R_RW: u32 = 0x0230_000f; // fence r, rw
+
+    /// Harts sharing the RAM, each running its program from its own page with `a0` = `X`,
+    /// `a1` = `Y` and `t0` = 1.
+    fn harts(programs: &[&[u32]]) -> Vec<RVCPU> {
+        TestCPUBuilder::harts(programs.len())
+            .into_iter()
+            .zip(programs)
+            .enumerate()
+            .map(|(i, (hart, program))| {
+                let start = BASE_ADDR + 0x100 * i as WordType;
+                hart.program_at(start, program)
+                    .pc(start)
+                    .reg(10, X)
+                    .reg(11, Y)
+                    .reg(5, 1)
+                    .build()
+            })
+            .collect()
+    }
+
+    /// Runs [`harts`] one instruction at a time, in an order drawn from `seed`, until every
+    /// hart is past its program.
+    fn run(programs: &[&[u32]], seed: u64) -> Vec<RVCPU> {
+        let mut harts = harts(programs);
+        let ends: Vec<_> = programs
+            .iter()
+            .enumerate()
+            .map(|(i, program)| BASE_ADDR + 0x100 * i as WordType + 4 * program.len() as WordType)
+            .collect();
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        for _ in 0..10_000 {
+            let running: Vec<_> = (0..harts.len())
+                .filter(|&i| harts[i].pc != ends[i])
+                .collect();
+            if running.is_empty() {
+                return harts;
+            }
+            let hart = running[rng.random_range(0..running.len())];
+            harts[hart].step().unwrap();
+        }
+        panic!("the harts did not finish");
+    }
+
+    #[test]
+    fn test_fence_order() {
+        let fence = |fm: WordType, pred: WordType, succ: WordType| fm << 8 | pred << 4 | succ;
+        // fence rw, rw
+        assert_eq!(get_fence_order(fence(0, 3, 3)), Some(Ordering::SeqCst));
+        // fence w, r
+        assert_eq!(get_fence_order(fence(0, 1, 2)), Some(Ordering::SeqCst));
+        // fence r, rw
+        assert_eq!(get_fence_order(fence(0, 2, 3)), Some(Ordering::Acquire));
+        // fence rw, w
+        assert_eq!(get_fence_order(fence(0, 3, 1)), Some(Ordering::Release));
+        // fence.tso, with the sign-extended immediate of the decoder
+        assert_eq!(
+            get_fence_order(fence(0x8, 3, 3) | !0xfff),
+            Some(Ordering::AcqRel)
+        );
+        // fence o, i
+        assert_eq!(get_fence_order(fence(0, 4, 8)), Some(Ordering::SeqCst));
+        assert_eq!(get_fence_order(fence(0, 0, 3)), None);
+
+        assert_eq!(get_amo_order(true, true), Ordering::SeqCst);
+    }
+
+    /// Message passing under random schedules: hart 0 writes the data then the flag, hart 1 must
+    /// not see the flag without the data.
+    #[test]
+    fn test_interleaving_message_passing() {
+        let writer: &[u32] = &[SW_T0_A0, FENCE_RW_W, SW_T0_A1];
+        let reader: &[u32] = &[LW_T1_A1, BEQZ_T1_BACK, FENCE_R_RW, LW_T2_A0];
+        for seed in 0..SCHEDULES {
+            let harts = run(&[writer, reader], seed);
+            assert_eq!(harts[1].reg_file[7], 1);
+        }
+
+        // The same with `amoswap.w.rl` and `lr.w.aq`.
+        let writer: &[u32] = &[
+            SW_T0_A0,
+            0x0a55_a02f, // amoswap.w.rl x0, t0, (a1)
+        ];
+        let reader: &[u32] = &[
+            0x1405_a32f, // lr.w.aq t1, (a1)
+            BEQZ_T1_BACK,
+            LW_T2_A0,
+        ];
+        for seed in 0..SCHEDULES {
+            let harts = run(&[writer, reader], seed);
+            assert_eq!(harts[1].reg_file[7], 1);
+        }
+    }
+
+    /// Store buffering under random schedules: each hart writes its location then reads the other
+    /// one, they cannot both read the initial value.
+    #[test]
+    fn test_interleaving_store_buffering() {
+        let allowed = BTreeSet::from([(0, 1), (1, 0), (1, 1)]);
+        let outcomes = |hart0: &[u32], hart1: &[u32]| -> BTreeSet<_> {
+            (0..SCHEDULES)
+                .map(|seed| {
+                    let harts = run(&[hart0, hart1], seed);
+                    (harts[0].reg_file[6], harts[1].reg_file[6])
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            outcomes(
+                &[SW_T0_A0, FENCE_RW_RW, LW_T1_A1],
+                &[SW_T0_A1, FENCE_RW_RW, LW_T1_A0]
+            ),
+            allowed
+        );
+
+        // The same with `amoswap.w.aqrl` and `amoadd.w.aqrl x0` on the locations.
+        assert_eq!(
+            outcomes(
+                &[
+                    0x0e55_202f, // amoswap.w.aqrl x0, t0, (a0)
+                    0x0605_a32f, // amoadd.w.aqrl t1, x0, (a1)
+                ],
+                &[
+                    0x0e55_a02f, // amoswap.w.aqrl x0, t0, (a1)
+                    0x0605_232f, // amoadd.w.aqrl t1, x0, (a0)
+                ]
+            ),
+            allowed
+        );
+    }
+
+    /// A store of another hart between `lr` and `sc` makes the `sc` fail.
+    #[test]
+    fn test_lr_sc_across_harts() {
+        let lr_sc: &[u32] = &[
+            0x1005_232f, // lr.w t1, (a0)
+            0x1855_23af, // sc.w t2, t0, (a0)
+        ];
+        for (other, success) in [(SW_T0_A0, false), (SW_T0_A1, true)] {
+            let mut harts = harts(&[lr_sc, &[other]]);
+            harts[0].step().unwrap();
+            harts[1].step().unwrap();
+            harts[0].step().unwrap();
+            assert_eq!(harts[0].reg_file[7], if success { 0 } else { 1 });
+        }
+    }
+}
@@ -5,8 +5,8 @@ use crate::{
         DebugTarget,
         riscv::{
             csr_reg::{
-                PrivilegeLevel,
-                csr_macro::{Minstret, Mstatus},
+                NamedCsrReg, PrivilegeLevel,
+                csr_macro::{Mie, Minstret, Mip, Mstatus},
             },
             executor::RVCPU,
             instruction::{
@@ -153,7 +153,7 @@ pub(in crate::isa::riscv) fn get_exec_func(
         },
 
         // We are executing in order, so don't need to do anything.
-        RiscvInstr::FENCE => exec_nop,
+        RiscvInstr::FENCE => exec_fence,
 
         RiscvInstr::FENCE_I => |_info, cpu| {
             cpu.flush_icache();
@@ -179,7 +179,19 @@ pub(in crate::isa::riscv) fn get_exec_func(
             cpu.csr.get_by_type_existing::<Minstret>().wrapping_add(1);
             Ok(())
         },
-        RiscvInstr::WFI => exec_nop,
+        RiscvInstr::WFI => |info, cpu| {
+            // WFI does not stall, it returns at once when an interrupt is pending. Otherwise it
+            // would wait, and the time limit of TW is zero: it traps below M-mode.
+            if cpu.get_current_privilege() != PrivilegeLevel::M
+                && cpu.csr.get_by_type_existing::<Mstatus>().get_tw() == 1
+                && cpu.csr.get_by_type_existing::<Mip>().data()
+                    & cpu.csr.get_by_type_existing::<Mie>().data()
+                    == 0
+            {
+                return Err(Exception::IllegalInstruction);
+            }
+            exec_nop(info, cpu)
+        },
 
         //---------------------------------------
         // RV_F
@@ -342,7 +354,7 @@ pub(in crate::isa::riscv) fn get_exec_func(
             Ok(())
         },
 
-        RiscvInstr::SFENCE_VMA => |_info, cpu| {
+        RiscvInstr::SFENCE_VMA => |info, cpu| {
             if cpu.get_current_privilege() < PrivilegeLevel::S {
                 return Err(Exception::IllegalInstruction);
             }
@@ -352,7 +364,15 @@ pub(in crate::isa::riscv) fn get_exec_func(
                 return Err(Exception::IllegalInstruction);
             }
 
-            cpu.memory.flush_tlb();
+            let RVInstrInfo::R { rs1, rs2, .. } = info else {
+                std::unreachable!();
+            };
+            // `x0` selects every address or every ASID, whatever the register value.
+            let (vaddr, asid) = cpu.reg_file.read(rs1, rs2);
+            cpu.memory.sfence(
+                (rs1 != 0).then_some(vaddr),
+                (rs2 != 0).then_some(asid as u16),
+            );
             cpu.flush_icache();
 
             cpu.write_pc(cpu.pc.wrapping_add(4));
@@ -417,6 +437,7 @@ pub(in crate::isa::riscv) fn get_exec_func(
 
         // Reserved / canonical illegal encoding (e.g. 0x0000).
         RiscvInstr::ILLEGAL => |_info, _cpu| Err(Exception::IllegalInstruction),
+        RiscvInstr::EXTENSION => crate::isa::riscv::extension::exec_extension,
 
         //---------------------------------------
         // RV_V
@@ -249,6 +249,15 @@ pub enum RVInstrInfo {
     CJ {
         target: WordType,
     },
+
+    /// An instruction of an [`InstructionExtension`], `index` is its place in the extension
+    /// table of the decoder.
+    ///
+    /// [`InstructionExtension`]: crate::isa::riscv::extension::InstructionExtension
+    Extension {
+        index: u16,
+        raw: u32,
+    },
 }
 
 #[allow(non_camel_case_types)]
@@ -7,6 +7,11 @@
 //!
 //! Table selection is `XLEN`-aware, so the same code produces an RV32 or RV64
 //! instruction set depending on the compiled `WordType`.
+//!
+//! The builder is also the one description of the hart the rest of the emulator
+//! derives from: the `misa` bitmap ([`ISABuilder::extension_bits`]), which counter
+//! CSRs exist, and the ISA string advertised to the guest in the device tree
+//! ([`ISABuilder::isa_string`]).
 
 use std::str::FromStr;
 
@@ -19,14 +24,20 @@ use crate::{
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Extension {
     I,
+    /// The embedded base: [`Extension::I`] with only `x0`-`x15`, replaces it.
+    E,
     M,
     A,
     F,
     D,
     C,
     V,
+    /// The `cycle`, `time` and `instret` counters.
+    Zicntr,
     Zicsr,
     Zifencei,
+    /// The `hpmcounter3`-`hpmcounter31` counters, hardwired to zero.
+    Zihpm,
 }
 
 impl Extension {
@@ -38,7 +49,7 @@ impl Extension {
         use Extension::*;
         match self {
             D => &[F],
-            F => &[Zicsr],
+            F | Zicntr | Zihpm => &[Zicsr],
             _ => &[],
         }
     }
@@ -48,7 +59,7 @@ impl Extension {
         use Extension::*;
         let is_rv64 = XLEN == 64;
         match self {
-            I => xlen_tables(TABLE_RV32I, TABLE_RV64I, is_rv64),
+            I | E => xlen_tables(TABLE_RV32I, TABLE_RV64I, is_rv64),
             M => xlen_tables(TABLE_RV32M, TABLE_RV64M, is_rv64),
             A => xlen_tables(TABLE_RV32A, TABLE_RV64A, is_rv64),
             F => xlen_tables(TABLE_RV32F, TABLE_RV64F, is_rv64),
@@ -60,6 +71,8 @@ impl Extension {
             C => vec![TABLE_RVC, if is_rv64 { TABLE_RV64C } else { TABLE_RV32C }],
             Zicsr => vec![TABLE_RVZICSR],
             Zifencei => vec![TABLE_RVZIFENCEI],
+            // Counters are CSRs, read with the Zicsr instructions.
+            Zicntr | Zihpm => vec![],
         }
     }
 
@@ -67,17 +80,53 @@ impl Extension {
         use Extension::*;
         Some(match self {
             I => 'I',
+            E => 'E',
             M => 'M',
             A => 'A',
             F => 'F',
             D => 'D',
             C => 'C',
             V => 'V',
-            Zicsr | Zifencei => return None,
+            Zicntr | Zicsr | Zifencei | Zihpm => return None,
         })
     }
+
+    /// The name used in ISA strings, lowercase.
+    fn name(self) -> &'static str {
+        use Extension::*;
+        match self {
+            I => "i",
+            E => "e",
+            M => "m",
+            A => "a",
+            F => "f",
+            D => "d",
+            C => "c",
+            V => "v",
+            Zicntr => "zicntr",
+            Zicsr => "zicsr",
+            Zifencei => "zifencei",
+            Zihpm => "zihpm",
+        }
+    }
 }
 
+impl std::fmt::Display for Extension {
+    /// The name as written in the specification, e.g. `M` or `Zicsr`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.name();
+        let (first, rest) = name.split_at(1);
+        write!(f, "{}{rest}", first.to_uppercase())
+    }
+}
+
+/// The order extensions appear in an ISA string: the base, the single-letter
+/// extensions in canonical order, then the `Z` extensions alphabetically.
+const CANONICAL_ORDER: &[Extension] = {
+    use Extension::*;
+    &[I, E, M, A, F, D, C, V, Zicntr, Zicsr, Zifencei, Zihpm]
+};
+
 /// On RV64 the base set is the RV32 table plus the 64-bit additions;
 /// on RV32 only the RV32 table applies.
 ///
@@ -94,6 +143,25 @@ fn xlen_tables(
     }
 }
 
+/// Every instruction table the emulator knows, with the extensions a hart needs to decode it.
+/// The privileged instructions need none.
+///
+/// [`Extension::E`] is left out, it decodes the tables of [`Extension::I`].
+pub(crate) fn known_tables() -> Vec<(&'static [Extension], &'static [RVInstrDesc])> {
+    let mut tables = Vec::new();
+    for ext in CANONICAL_ORDER.iter().filter(|&&ext| ext != Extension::E) {
+        for table in ext.tables() {
+            tables.push((std::slice::from_ref(ext), table));
+        }
+    }
+    tables.push((&[Extension::C, Extension::D], TABLE_RVC_D));
+    if XLEN == 32 {
+        tables.push((&[Extension::C, Extension::F], TABLE_RV32C_F));
+    }
+    tables.extend([TABLE_RVSYSTEM, TABLE_RVS, TABLE_RVILLEGAL].map(|table| (&[][..], table)));
+    tables
+}
+
 /// `misa` bit for a single-letter extension: bit `letter - 'A'`.
 fn misa_bit(letter: char) -> WordType {
     1 << (letter as u8 - b'A')
@@ -106,7 +174,7 @@ fn misa_bit(letter: char) -> WordType {
 /// let a = ISABuilder::new().add(Extension::M).add(Extension::A).add(Extension::D).add(Extension::C);
 /// let b: ISABuilder = "RV64IMAFDC_Zicsr_Zifencei".parse().unwrap();
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ISABuilder {
     /// Selected extensions, deduplicated. Order is not guarenteed.
     extensions: Vec<Extension>,
@@ -122,6 +190,19 @@ impl ISABuilder {
         builder
     }
 
+    /// Every extension the emulator implements, the ISA of a default hart.
+    pub fn all() -> Self {
+        ISABuilder::new()
+            .add(Extension::M)
+            .add(Extension::A)
+            .add(Extension::D)
+            .add(Extension::C)
+            .add(Extension::V)
+            .add(Extension::Zicntr)
+            .add(Extension::Zifencei)
+            .add(Extension::Zihpm)
+    }
+
     /// Adds `ext` and, transitively, every extension it depends on.
     pub fn add(mut self, ext: Extension) -> Self {
         self.insert(ext);
@@ -132,10 +213,19 @@ impl ISABuilder {
         self.extensions.contains(&ext)
     }
 
+    /// Like [`Self::has`], the embedded base also provides [`Extension::I`].
+    pub(crate) fn provides(&self, ext: Extension) -> bool {
+        self.has(ext) || (ext == Extension::I && self.has(Extension::E))
+    }
+
     fn insert(&mut self, ext: Extension) {
-        if self.has(ext) {
+        // `I` and `E` are the two bases, the embedded one wins.
+        if self.has(ext) || (ext == Extension::I && self.has(Extension::E)) {
             return;
         }
+        if ext == Extension::E {
+            self.extensions.retain(|&e| e != Extension::I);
+        }
         for &dep in ext.dependencies() {
             self.insert(dep);
         }
@@ -169,6 +259,19 @@ impl ISABuilder {
         instrs
     }
 
+    /// Keeps only the extensions enabled in the `misa` bitmap `bits`.
+    /// Extensions without a `misa` bit (e.g. `Zicsr`) are always kept.
+    pub fn retain_misa(&self, bits: WordType) -> Self {
+        ISABuilder {
+            extensions: self
+                .extensions
+                .iter()
+                .copied()
+                .filter(|ext| ext.misa_letter().is_none_or(|l| bits & misa_bit(l) != 0))
+                .collect(),
+        }
+    }
+
     pub fn extension_bits(&self) -> WordType {
         let mut bits = misa_bit('S') | misa_bit('U');
         for &ext in &self.extensions {
@@ -178,6 +281,34 @@ impl ISABuilder {
         }
         bits
     }
+
+    /// The selected extensions in ISA string order.
+    pub fn extensions(&self) -> Vec<Extension> {
+        CANONICAL_ORDER
+            .iter()
+            .copied()
+            .filter(|&ext| self.has(ext))
+            .collect()
+    }
+
+    /// Lowercase names of the selected extensions in ISA string order, e.g.
+    /// `["i", "m", "zicsr"]`.
+    pub fn extension_names(&self) -> Vec<&'static str> {
+        self.extensions().into_iter().map(Extension::name).collect()
+    }
+
+    /// The canonical ISA string, e.g. `rv64imafdc_zicsr_zifencei`, which parses
+    /// back to the same set of extensions.
+    pub fn isa_string(&self) -> String {
+        let mut isa = format!("rv{XLEN}");
+        for name in self.extension_names() {
+            if name.len() > 1 {
+                isa.push('_');
+            }
+            isa.push_str(name);
+        }
+        isa
+    }
 }
 
 impl Default for ISABuilder {
@@ -255,11 +386,13 @@ fn base_extensions(letter: char) -> Result<&'static [Extension], IsaParseError>
     use Extension::*;
     Ok(match letter {
         'i' => &[I],
+        'e' => &[E],
         'm' => &[M],
         'a' => &[A],
         'f' => &[F],
         'd' => &[D],
         'c' => &[C],
+        'v' => &[V],
         // The "general" shorthand.
         'g' => &[I, M, A, F, D, Zicsr, Zifencei],
         other => return Err(IsaParseError::UnknownBaseExtension(other)),
@@ -268,8 +401,10 @@ fn base_extensions(letter: char) -> Result<&'static [Extension], IsaParseError>
 
 fn multi_letter_extension(token: &str) -> Result<Extension, IsaParseError> {
     Ok(match token {
+        "zicntr" => Extension::Zicntr,
         "zicsr" => Extension::Zicsr,
         "zifencei" => Extension::Zifencei,
+        "zihpm" => Extension::Zihpm,
         other => return Err(IsaParseError::UnknownExtension(other.to_string())),
     })
 }
@@ -330,6 +465,33 @@ mod tests {
         assert_eq!(with_z.extension_bits(), misa_of("ISU"));
     }
 
+    #[test]
+    fn retain_misa_keeps_z_extensions() {
+        let builder = ISABuilder::new()
+            .add(Extension::M)
+            .add(Extension::D)
+            .add(Extension::Zifencei)
+            .retain_misa(misa_of("IF"));
+        assert!(!builder.has(Extension::M));
+        assert!(!builder.has(Extension::D));
+        assert!(builder.has(Extension::F));
+        assert!(builder.has(Extension::Zicsr));
+        assert!(builder.has(Extension::Zifencei));
+        assert_eq!(builder.extension_bits(), misa_of("IFSU"));
+    }
+
+    #[test]
+    fn e_replaces_i() {
+        let builder: ISABuilder = isa("EC").parse().unwrap();
+        assert!(builder.has(Extension::E));
+        assert!(!builder.has(Extension::I));
+        assert_eq!(builder.extension_bits(), misa_of("CESU"));
+        assert!(has_instr(&builder.build(), RiscvInstr::ADDI));
+
+        let builder = builder.add(Extension::I);
+        assert!(!builder.has(Extension::I));
+    }
+
     #[test]
     fn d_pulls_in_f_and_zicsr() {
         let builder = ISABuilder::new().add(Extension::D);
@@ -387,6 +549,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn isa_string_is_canonical() {
+        let builder: ISABuilder = isa("CDMI_Zihpm_Zifencei").parse().unwrap();
+        assert_eq!(
+            builder.isa_string(),
+            format!("rv{XLEN}imfdc_zicsr_zifencei_zihpm")
+        );
+        assert_eq!(ISABuilder::new().isa_string(), format!("rv{XLEN}i"));
+
+        let all = ISABuilder::all();
+        assert_eq!(
+            all.isa_string(),
+            format!("rv{XLEN}imafdcv_zicntr_zicsr_zifencei_zihpm")
+        );
+        let parsed: ISABuilder = all.isa_string().parse().unwrap();
+        assert_eq!(parsed.extensions(), all.extensions());
+    }
+
     #[test]
     fn rejects_wrong_xlen() {
         let s = format!("RV{}I", wrong_xlen());
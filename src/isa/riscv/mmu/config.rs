@@ -8,6 +8,17 @@ pub const PPN_WIDTH: usize = PHYSICAL_ADDR_WIDTH - PAGE_SIZE_XLEN; // PPN width
 pub const SUB_VPN_XLEN: usize = 9;
 pub const SUB_VPN_MASK: WordType = (1 << SUB_VPN_XLEN) - 1;
 
+/// Name of a `satp.MODE` value.
+pub fn satp_mode_name(mode: WordType) -> &'static str {
+    match mode {
+        0 => "Bare",
+        8 => "Sv39",
+        9 => "Sv48",
+        10 => "Sv57",
+        _ => "?",
+    }
+}
+
 // ============================================
 // ======= PTE flags in page table entry ======
 // ============================================
@@ -1,6 +1,7 @@
 pub mod address;
 pub mod config;
 mod page_table;
+pub mod trace;
 
 pub use page_table::PageTableError;
 
@@ -10,8 +11,8 @@ use self::config::*;
 use self::page_table::*;
 
 use crate::{
-    config::arch_config::WordType,
-    device::{DeviceTrait, MemError, mmio::MemoryMapIO},
+    config::arch_config::{Endianness, WordType},
+    device::{DeviceTrait, MemError, mmio::MemoryMapIO, pma::AmoClass},
     isa::riscv::{
         csr_reg::{
             CsrRegFile, PrivilegeLevel,
@@ -97,6 +98,40 @@ fn determine_data_access_privilege(csr: &mut CsrRegFile) -> AccessPrivilege {
     }
 }
 
+/// Byte order of explicit data accesses: `mstatus.MBE`, `SBE` or `UBE` of the privilege level the
+/// access is performed in, so `MPRV` applies.
+fn data_endianness(csr: &mut CsrRegFile) -> Endianness {
+    let mstatus = csr.get_by_type_existing::<Mstatus>();
+    let level = if csr.privelege_level() == PrivilegeLevel::M && mstatus.get_mprv() == 1 {
+        PrivilegeLevel::try_from(mstatus.get_mpp() as u8)
+            .expect("mstatus.mpp must contain a valid privilege level")
+    } else {
+        csr.privelege_level()
+    };
+
+    let big = match level {
+        PrivilegeLevel::M => mstatus.get_mbe(),
+        PrivilegeLevel::S => mstatus.get_sbe(),
+        PrivilegeLevel::U => mstatus.get_ube(),
+        PrivilegeLevel::V => unreachable!(), // Doesn't have V-mode.
+    };
+    if big == 1 {
+        Endianness::Big
+    } else {
+        Endianness::Little
+    }
+}
+
+/// Converts between the little-endian value the memory system works with and the value in the
+/// byte order of the access. The conversion is its own inverse.
+#[inline]
+fn in_order<T: UnsignedInteger>(data: T, order: Endianness) -> T {
+    match order {
+        Endianness::Little => data,
+        Endianness::Big => data.swap_bytes(),
+    }
+}
+
 pub(crate) struct VirtAddrManager {
     pub(crate) mmio: MemoryMapIO,
     page_table: PageTableWalker,
@@ -175,7 +210,11 @@ impl VirtAddrManager {
     }
 
     #[inline]
-    fn resolve_ifetch_policy(csr: &mut CsrRegFile, with_side_effect: bool) -> AccessPolicy {
+    fn resolve_ifetch_policy(
+        csr: &mut CsrRegFile,
+        addr: WordType,
+        with_side_effect: bool,
+    ) -> AccessPolicy {
         let effect = if with_side_effect {
             AccessEffect::Accessed
         } else {
@@ -191,7 +230,7 @@ impl VirtAddrManager {
                     exact_flags: PTEFlags::X,
                 },
                 effect,
-                fault: MemError::LoadPageFault,
+                fault: MemError::ExecPageFault(addr),
             },
             PrivilegeLevel::U => AccessPolicy::Translated {
                 check: PermissionCheck {
@@ -200,7 +239,7 @@ impl VirtAddrManager {
                     exact_flags: PTEFlags::X | PTEFlags::U,
                 },
                 effect,
-                fault: MemError::LoadPageFault,
+                fault: MemError::ExecPageFault(addr),
             },
             PrivilegeLevel::V => unreachable!(), // Doesn't have V-mode.
         }
@@ -233,7 +272,8 @@ impl VirtAddrManager {
         let policy = Self::resolve_data_policy(csr, AccessType::Read, true);
         let paddr = self.translate_with_policy(addr, policy)?;
 
-        self.mmio.read_by_type(paddr)
+        let data = self.mmio.read_by_type(paddr)?;
+        Ok(in_order(data, data_endianness(csr)))
     }
 
     pub(crate) fn write<T>(
@@ -248,7 +288,8 @@ impl VirtAddrManager {
         let policy = Self::resolve_data_policy(csr, AccessType::Write, true);
         let paddr = self.translate_with_policy(addr, policy)?;
 
-        self.mmio.write_by_type(paddr, data)
+        self.mmio
+            .write_by_type(paddr, in_order(data, data_endianness(csr)))
     }
 
     pub(crate) fn load_reserved<T>(
@@ -266,7 +307,8 @@ impl VirtAddrManager {
         let policy = Self::resolve_data_policy(csr, AccessType::Read, true);
         let paddr = self.translate_with_policy(addr, policy)?;
 
-        self.mmio.load_reserved(paddr)
+        let data = self.mmio.load_reserved(paddr)?;
+        Ok(in_order(data, data_endianness(csr)))
     }
 
     pub(crate) fn store_conditional<T>(
@@ -285,32 +327,65 @@ impl VirtAddrManager {
         let policy = Self::resolve_data_policy(csr, AccessType::Write, true);
         let paddr = self.translate_with_policy(addr, policy)?;
 
-        self.mmio.store_conditional(paddr, data)
+        self.mmio
+            .store_conditional(paddr, in_order(data, data_endianness(csr)))
     }
 
-    pub(crate) fn ifetch<T>(&mut self, addr: WordType, csr: &mut CsrRegFile) -> Result<T, MemError>
-    where
-        T: UnsignedInteger,
-    {
-        let policy = Self::resolve_ifetch_policy(csr, true);
-        let paddr = self.translate_with_policy(addr, policy)?;
-        self.mmio.read_by_type(paddr)
+    /// Fetch the instruction at `addr`: its low half, then the high one for a 32-bit
+    /// instruction, which is zero-extended otherwise.
+    ///
+    /// "The C extension allows 16-bit instructions to be freely intermixed with 32-bit
+    /// instructions, with the latter now able to start on any 16-bit boundary", so a 32-bit
+    /// instruction may straddle a page boundary. Its high half is then translated on its own, and
+    /// a fault of the second page is reported at the page boundary.
+    pub(crate) fn ifetch(&mut self, addr: WordType, csr: &mut CsrRegFile) -> Result<u32, MemError> {
+        self.ifetch_with(addr, csr, true)
     }
 
     /// Fetch instruction without side-effect, respecting the privilege mode.
     ///
     /// Provided for debugger.
-    pub(crate) fn debug_ifetch<T>(
+    pub(crate) fn debug_ifetch(
         &mut self,
         addr: WordType,
         csr: &mut CsrRegFile,
-    ) -> Result<T, MemError>
+    ) -> Result<u32, MemError> {
+        self.ifetch_with(addr, csr, false)
+    }
+
+    fn ifetch_with(
+        &mut self,
+        addr: WordType,
+        csr: &mut CsrRegFile,
+        side_effect: bool,
+    ) -> Result<u32, MemError> {
+        let policy = Self::resolve_ifetch_policy(csr, addr, side_effect);
+        let paddr = self.translate_with_policy(addr, policy)?;
+        let low = self.fetch_by_paddr::<u16>(addr, paddr)? as u32;
+        if low & 0b11 != 0b11 {
+            return Ok(low);
+        }
+
+        let next = addr.wrapping_add(2);
+        let next_paddr = if next.is_multiple_of(PAGE_SIZE) {
+            let policy = Self::resolve_ifetch_policy(csr, next, side_effect);
+            self.translate_with_policy(next, policy)?
+        } else {
+            paddr + 2
+        };
+        let high = self.fetch_by_paddr::<u16>(next, next_paddr)? as u32;
+        Ok(low | high << 16)
+    }
+
+    /// Read the instruction at `paddr`, faults are reported at the fetched `vaddr`.
+    fn fetch_by_paddr<T>(&mut self, vaddr: WordType, paddr: WordType) -> Result<T, MemError>
     where
         T: UnsignedInteger,
     {
-        let policy = Self::resolve_ifetch_policy(csr, false);
-        let paddr = self.translate_with_policy(addr, policy)?;
-        self.mmio.read_by_type(paddr)
+        self.mmio.read_by_type(paddr).map_err(|err| match err {
+            MemError::LoadMisaligned => MemError::ExecMisaligned(vaddr),
+            _ => MemError::ExecFault(vaddr),
+        })
     }
 
     /// Atomic Memory Operation.
@@ -318,6 +393,7 @@ impl VirtAddrManager {
         &mut self,
         addr: WordType,
         rhs_val: T,
+        class: AmoClass,
         csr: &mut CsrRegFile,
         f: F,
     ) -> Result<T, Exception>
@@ -335,11 +411,19 @@ impl VirtAddrManager {
             return Err(Exception::StoreMisaligned);
         }
 
+        // The full name of this exception is Store/AMO access fault
+        if !self
+            .mmio
+            .pma(paddr)
+            .is_some_and(|pma| pma.allows_amo(class))
+        {
+            return Err(Exception::StoreFault);
+        }
+        let order = data_endianness(csr);
         if !(ram_config::BASE_ADDR..ram_config::BASE_ADDR + ram_config::SIZE as WordType)
             .contains(&paddr)
         {
-            // The full name of this exception is Store/AMO access fault
-            return Err(Exception::StoreFault);
+            return self.device_amo(paddr, rhs_val, order, f);
         }
 
         paddr -= ram_config::BASE_ADDR;
@@ -348,7 +432,49 @@ impl VirtAddrManager {
         let ptr = &mut ram[paddr as usize] as *mut u8 as *mut T::AtomicType;
         let lhs = unsafe { &*ptr };
 
-        f(lhs, rhs_val)
+        if order == Endianness::Little {
+            return f(lhs, rhs_val);
+        }
+
+        // The word stays in memory order. The bitwise operations do not care about the byte
+        // order, so only the register values are swapped; the arithmetic ones are computed on
+        // a swapped copy and stored back if the word did not change meanwhile.
+        match class {
+            AmoClass::Arithmetic => loop {
+                let old = T::atomic_load(lhs);
+                let cell = T::atomic_new(old.swap_bytes());
+                let ret = f(&cell, rhs_val)?;
+                let value = T::atomic_into_inner(cell).swap_bytes();
+                if T::atomic_compare_exchange(lhs, old, value) {
+                    return Ok(ret);
+                }
+            },
+            _ => f(lhs, rhs_val.swap_bytes()).map(T::swap_bytes),
+        }
+    }
+
+    /// An AMO on a device: `f` runs on a copy of the value, which is then stored.
+    fn device_amo<T, F>(
+        &mut self,
+        paddr: WordType,
+        rhs_val: T,
+        order: Endianness,
+        f: F,
+    ) -> Result<T, Exception>
+    where
+        T: UnsignedInteger,
+        F: Fn(&T::AtomicType, T) -> Result<T, Exception>,
+    {
+        let mut ret = Err(Exception::StoreFault);
+        self.mmio
+            .atomic_rmw(paddr, |old: T| {
+                let cell = T::atomic_new(in_order(old, order));
+                ret = f(&cell, rhs_val);
+                in_order(T::atomic_into_inner(cell), order)
+            })
+            // The full name of this exception is Store/AMO access fault
+            .map_err(|_| Exception::StoreFault)?;
+        ret
     }
 
     pub(crate) fn read_by_paddr<T>(&mut self, paddr: WordType) -> Result<T, MemError>
@@ -492,4 +618,31 @@ impl VirtAddrManager {
     pub fn flush_tlb(&mut self) {
         self.page_table.flush_tlb();
     }
+
+    /// Drop the translations `sfence.vma` selects, see [`PageTableWalker::sfence`].
+    pub fn sfence(&mut self, vaddr: Option<WordType>, asid: Option<u16>) {
+        self.page_table.sfence(vaddr, asid);
+    }
+
+    pub fn set_asid(&mut self, asid: u16) {
+        self.page_table.set_asid(asid);
+    }
+
+    pub fn asid(&self) -> u16 {
+        self.page_table.asid()
+    }
+
+    /// Trace the A/D updates of the page table walker with `tracer`, or stop tracing with `None`.
+    pub fn set_tracer(&mut self, tracer: Option<trace::MmuTracer>) {
+        self.page_table.tracer = tracer.map(Box::new);
+    }
+
+    pub(crate) fn tracer_mut(&mut self) -> Option<&mut trace::MmuTracer> {
+        self.page_table.tracer.as_deref_mut()
+    }
+
+    /// Number of translations usable in the current address space.
+    pub fn tlb_entries(&self) -> usize {
+        self.page_table.tlb_entries()
+    }
 }
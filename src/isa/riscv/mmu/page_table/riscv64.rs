@@ -10,6 +10,7 @@ use crate::{
         riscv::mmu::{
             address::{PhysicalAddr, PhysicalPageNum, VirtualAddr, VirtualPageNum},
             config::*,
+            trace::{MmuEvent, MmuTracer},
         },
     },
     ram::Ram,
@@ -139,17 +140,33 @@ struct WalkInfo {
     leaf_flags: PTEFlags,
     leaf_pte_addr: u64,
     leaf_ppn: PhysicalPageNum,
+    /// The ASID of `satp` during the walk, for the TLB.
+    asid: u16,
+    /// Whether a PTE of the walk has the G bit, making the translation valid in every ASID.
+    global: bool,
+}
+
+impl WalkInfo {
+    fn page_shift(&self) -> usize {
+        PAGE_SIZE_XLEN + self.leaf_level * SUB_VPN_XLEN
+    }
 }
 
 impl Cacheable for WalkInfo {
     const ADDR_SHIFT_BITS: usize = PAGE_SIZE_XLEN;
 }
 
+/// Walks the page tables, caching the translations in a TLB tagged with the ASID of `satp`, so
+/// switching address spaces needs no flush.
 pub struct PageTableWalker {
+    /// Indexed by the virtual page of the access, so a superpage takes an entry per 4 KiB page
+    /// used. The guest must `sfence.vma` after changing a PTE, as on hardware.
     tlb: SetCache<WalkInfo, 64, 8>,
     root_address: WordType,
     mode: VirtualMemoryMode,
+    asid: u16,
     ad_update_policy: AdUpdatePolicy,
+    pub(crate) tracer: Option<Box<MmuTracer>>,
 }
 
 impl PageTableWalker {
@@ -158,7 +175,9 @@ impl PageTableWalker {
             tlb: SetCache::new(),
             root_address,
             mode,
+            asid: 0,
             ad_update_policy: AdUpdatePolicy::FaultOnClear,
+            tracer: None,
         }
     }
 
@@ -166,6 +185,38 @@ impl PageTableWalker {
         self.tlb.clear();
     }
 
+    /// `sfence.vma`: drop the translations of the page holding `vaddr`, or of every page
+    /// without it, only the non-global ones of `asid` if given.
+    pub fn sfence(&mut self, vaddr: Option<WordType>, asid: Option<u16>) {
+        if vaddr.is_none() && asid.is_none() {
+            self.tlb.clear();
+            return;
+        }
+        self.tlb.retain(|page, info| {
+            let maps_vaddr =
+                vaddr.is_none_or(|vaddr| page >> info.page_shift() == vaddr >> info.page_shift());
+            let in_asid = asid.is_none_or(|asid| !info.global && info.asid == asid);
+            !(maps_vaddr && in_asid)
+        });
+    }
+
+    /// Number of translations cached for the current ASID, global ones included.
+    pub fn tlb_entries(&self) -> usize {
+        self.tlb
+            .iter()
+            .filter(|(_, info)| info.global || info.asid == self.asid)
+            .count()
+    }
+
+    pub fn asid(&self) -> u16 {
+        self.asid
+    }
+
+    /// Switch to the address space `asid`, keeping the translations of the others cached.
+    pub fn set_asid(&mut self, asid: u16) {
+        self.asid = asid;
+    }
+
     pub fn set_ad_update_policy(&mut self, ad_update_policy: AdUpdatePolicy) {
         self.ad_update_policy = ad_update_policy;
     }
@@ -204,14 +255,59 @@ impl PageTableWalker {
             return Err(PageTableError::PageFault);
         }
 
-        let walk_info = if let Some(info) = self.tlb.get(vaddr.vpn().address) {
-            info
-        } else {
-            let info = self.walk_pte(mem, vaddr.vpn())?;
-            // self.tlb.put(vaddr.vpn().address, info);
-            info
-        };
+        let page = vaddr.vpn().address;
+        let asid = self.asid;
+        let in_asid = |info: &WalkInfo| info.global || info.asid == asid;
+        if let Some(info) = self.tlb.get_matching(page, in_asid) {
+            let access = self.access(mem, vaddr, info, &check, effect);
+            if let Ok((paddr, flags)) = access
+                && flags == info.leaf_flags
+            {
+                return Ok(paddr);
+            }
+            // Faults are decided on the page table in memory, which the guest may have fixed
+            // since, e.g. setting the A bit.
+            self.tlb.invalidate_matching(page, in_asid);
+            if let Ok((paddr, flags)) = access {
+                self.cache(vaddr, info, flags);
+                return Ok(paddr);
+            }
+        }
 
+        let walk_info = self.walk_pte(mem, vaddr.vpn())?;
+        let (paddr, flags) = self.access(mem, vaddr, walk_info, &check, effect)?;
+        self.cache(vaddr, walk_info, flags);
+        Ok(paddr)
+    }
+
+    /// Cache the translation of `vaddr`, whose leaf PTE now has `flags`, tracing the A and D
+    /// bits the access set.
+    fn cache(&mut self, vaddr: VirtualAddr, mut walk_info: WalkInfo, flags: PTEFlags) {
+        if flags != walk_info.leaf_flags
+            && let Some(tracer) = self.tracer.as_mut()
+        {
+            tracer.record(&MmuEvent::PteUpdate {
+                vaddr: vaddr.0,
+                pte_addr: walk_info.leaf_pte_addr,
+                old: walk_info.leaf_flags.bits(),
+                new: flags.bits(),
+            });
+        }
+
+        walk_info.leaf_flags = flags;
+        self.tlb.put(vaddr.vpn().address, walk_info);
+    }
+
+    /// Check the permissions of the access to `vaddr` through `walk_info` and update the A and D
+    /// bits, returning the physical address and the leaf flags after the update.
+    fn access(
+        &self,
+        mem: &mut Ram,
+        vaddr: VirtualAddr,
+        walk_info: WalkInfo,
+        check: &PermissionCheck,
+        effect: AccessEffect,
+    ) -> Result<(PhysicalAddr, PTEFlags), PageTableError> {
         if (walk_info.leaf_flags & check.exact_mask) != check.exact_flags
             || (check.any_of.is_empty() == false
                 && (walk_info.leaf_flags & check.any_of) == PTEFlags::empty())
@@ -235,12 +331,11 @@ impl PageTableWalker {
             return Err(PageTableError::PrivilegeFault);
         }
 
-        self.apply_ad_policy(mem, &walk_info, effect)?;
+        let flags = self.apply_ad_policy(mem, &walk_info, effect)?;
 
-        let page_shift = PAGE_SIZE_XLEN + walk_info.leaf_level * SUB_VPN_XLEN;
-        let page_offset_mask = (1 << page_shift) - 1;
+        let page_offset_mask = (1 << walk_info.page_shift()) - 1;
         let paddr = walk_info.leaf_ppn.address | (vaddr.0 & page_offset_mask);
-        Ok(paddr.into())
+        Ok((paddr.into(), flags))
     }
 
     fn apply_ad_policy(
@@ -248,7 +343,7 @@ impl PageTableWalker {
         mem: &mut Ram,
         walk_info: &WalkInfo,
         effect: AccessEffect,
-    ) -> Result<(), PageTableError> {
+    ) -> Result<PTEFlags, PageTableError> {
         let (need_accessed, need_dirty) = match effect {
             AccessEffect::None => (false, false),
             AccessEffect::Accessed => (!walk_info.leaf_flags.contains(PTEFlags::A), false),
@@ -259,7 +354,7 @@ impl PageTableWalker {
         };
 
         if !need_accessed && !need_dirty {
-            return Ok(());
+            return Ok(walk_info.leaf_flags);
         }
 
         match self.ad_update_policy {
@@ -271,7 +366,7 @@ impl PageTableWalker {
                 if need_dirty {
                     pte.set_dirty();
                 }
-                Ok(())
+                Ok(pte.flags())
             }
             AdUpdatePolicy::FaultOnClear => Err(PageTableError::PageFault),
         }
@@ -307,6 +402,7 @@ impl PageTableWalker {
         vpn: VirtualPageNum,
     ) -> Result<WalkInfo, PageTableError> {
         let mut entry = PhysicalPageNum::from_paddr(self.root_address);
+        let mut global = false;
 
         for i in (0..M::LEVELS).rev() {
             let sub_vpn = M::vpn_index(vpn.address, i);
@@ -323,6 +419,7 @@ impl PageTableWalker {
             if pte.is_invalid_encoding() {
                 return Err(PageTableError::PageFault);
             }
+            global |= pte.is_global();
 
             if pte.is_leaf() {
                 // A leaf PTE has been reached. If i>0 and pte.ppn[i-1:0] ≠ 0  this is a misaligned superpage;
@@ -336,6 +433,8 @@ impl PageTableWalker {
                         leaf_flags: pte.flags(),
                         leaf_pte_addr: pte_addr,
                         leaf_ppn: pte.ppn(),
+                        asid: self.asid,
+                        global,
                     });
                 }
             }
@@ -605,4 +704,109 @@ mod test_sv39 {
 
         assert_eq!(paddr.0, DATA_PAGE | 0x123);
     }
+
+    #[test]
+    fn asid_tlb_test() {
+        let mut ram: Ram = Ram::new();
+        let leaf_flags = PTEFlags::V | PTEFlags::R | PTEFlags::A;
+        setup_3level_leaf(&mut ram, DATA_PAGE, leaf_flags);
+        // A second address space mapping the same page elsewhere.
+        let (pt0, pt1, pt2, other_page) = (0x8000_5000, 0x8000_6000, 0x8000_7000, 0x8000_8000);
+        setup_pte(&mut ram, pt0, pt1, PTEFlags::V);
+        setup_pte(&mut ram, pt1, pt2, PTEFlags::V);
+        setup_pte(&mut ram, pt2, other_page, leaf_flags);
+
+        let read = |page_table: &mut PageTableWalker, ram: &mut Ram| {
+            let check = PermissionCheck {
+                any_of: PTEFlags::empty(),
+                exact_mask: PTEFlags::R,
+                exact_flags: PTEFlags::R,
+            };
+            page_table
+                .translate_vaddr(ram, 0x0000_0123.into(), check, AccessEffect::Accessed)
+                .unwrap()
+                .0
+        };
+
+        let mut page_table = PageTableWalker::new(PT0.into(), VirtualMemoryMode::Page39bit);
+        page_table.set_asid(1);
+        assert_eq!(read(&mut page_table, &mut ram), DATA_PAGE | 0x123);
+        page_table.set_root_addr(pt0);
+        page_table.set_asid(2);
+        assert_eq!(read(&mut page_table, &mut ram), other_page | 0x123);
+
+        // Back in ASID 1, its translation is still cached after the PTE changed.
+        setup_pte(&mut ram, PT2, 0x8000_9000, leaf_flags);
+        page_table.set_root_addr(PT0);
+        page_table.set_asid(1);
+        assert_eq!(page_table.tlb_entries(), 1);
+        assert_eq!(read(&mut page_table, &mut ram), DATA_PAGE | 0x123);
+        page_table.sfence(None, Some(2));
+        assert_eq!(read(&mut page_table, &mut ram), DATA_PAGE | 0x123);
+        page_table.sfence(Some(0x0000_0fff), Some(1));
+        assert_eq!(read(&mut page_table, &mut ram), 0x8000_9123);
+
+        // Global translations are kept by the flushes of an ASID.
+        setup_pte(&mut ram, pt2, other_page, leaf_flags | PTEFlags::G);
+        page_table.set_root_addr(pt0);
+        page_table.set_asid(3);
+        assert_eq!(read(&mut page_table, &mut ram), other_page | 0x123);
+        page_table.sfence(None, Some(3));
+        page_table.set_asid(4);
+        assert_eq!(page_table.tlb_entries(), 1);
+        page_table.sfence(Some(0x0000_0123), None);
+        assert_eq!(page_table.tlb_entries(), 0);
+    }
+
+    #[test]
+    fn ad_trace_test() {
+        use std::{cell::RefCell, io::Write, rc::Rc};
+
+        struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for SharedWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut ram: Ram = Ram::new();
+        setup_3level_leaf(&mut ram, DATA_PAGE, PTEFlags::V | PTEFlags::R | PTEFlags::W);
+        let mut page_table = PageTableWalker::new(PT0.into(), VirtualMemoryMode::Page39bit);
+        page_table.set_ad_update_policy(AdUpdatePolicy::AutoSet);
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut tracer = MmuTracer::to_writer(Box::new(SharedWriter(output.clone())));
+        tracer.pc = 0x8000_0000;
+        page_table.tracer = Some(Box::new(tracer));
+
+        let mut access = |effect, rwx| {
+            let check = PermissionCheck {
+                any_of: PTEFlags::empty(),
+                exact_mask: rwx,
+                exact_flags: rwx,
+            };
+            page_table
+                .translate_vaddr(&mut ram, 0x0000_0123.into(), check, effect)
+                .unwrap();
+        };
+        access(AccessEffect::Accessed, PTEFlags::R);
+        // Cached with A set, no update to trace.
+        access(AccessEffect::Accessed, PTEFlags::R);
+        access(AccessEffect::AccessedDirty, PTEFlags::W);
+        access(AccessEffect::AccessedDirty, PTEFlags::W);
+
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "pte 0x80003000 for va 0x123: VRW----- -> VRW---A- @ pc = 0x80000000",
+                "pte 0x80003000 for va 0x123: VRW---A- -> VRW---AD @ pc = 0x80000000",
+            ]
+        );
+    }
 }
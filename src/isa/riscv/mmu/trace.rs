@@ -0,0 +1,177 @@
+//! Tracing of the paging state changes made outside of guest stores: the A and D bits the page
+//! table walker sets, and the writes to `satp`, to debug the paging setup of a guest without
+//! dumping its page tables.
+//!
+//! Attach a [`MmuTracer`] to the CPU with
+//! [`RVCPU::set_mmu_tracer`](crate::isa::riscv::executor::RVCPU::set_mmu_tracer).
+
+use std::{fs::File, io::BufWriter, io::Write, path::Path};
+
+use crate::config::arch_config::WordType;
+
+/// Names of the PTE flags, from bit 0.
+const PTE_FLAG_NAMES: &[u8; 8] = b"VRWXUGAD";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmuEvent {
+    /// The walker set the A and/or D bits of the leaf PTE at `pte_addr` for an access to `vaddr`.
+    PteUpdate {
+        vaddr: WordType,
+        pte_addr: u64,
+        /// The flag bits of the PTE before and after.
+        old: u8,
+        new: u8,
+    },
+    SatpWrite {
+        satp: WordType,
+        mode: &'static str,
+        asid: u16,
+        root: WordType,
+    },
+}
+
+impl std::fmt::Display for MmuEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MmuEvent::PteUpdate {
+                vaddr,
+                pte_addr,
+                old,
+                new,
+            } => write!(
+                f,
+                "pte {:#x} for va {:#x}: {} -> {}",
+                pte_addr,
+                vaddr,
+                FlagNames(*old),
+                FlagNames(*new)
+            ),
+            MmuEvent::SatpWrite {
+                satp,
+                mode,
+                asid,
+                root,
+            } => write!(
+                f,
+                "satp = {:#x}: mode {} asid {} root {:#x}",
+                satp, mode, asid, root
+            ),
+        }
+    }
+}
+
+/// PTE flags as `VRWXUGAD`, with `-` for the clear ones.
+struct FlagNames(u8);
+
+impl std::fmt::Display for FlagNames {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (bit, &name) in PTE_FLAG_NAMES.iter().enumerate() {
+            let set = self.0 & (1 << bit) != 0;
+            write!(f, "{}", if set { name as char } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+enum MmuTraceSink {
+    Log,
+    Writer(Box<dyn Write>),
+}
+
+pub struct MmuTracer {
+    sink: MmuTraceSink,
+    /// The `pc` of the instruction currently executing, updated by the CPU.
+    pub(crate) pc: WordType,
+}
+
+impl MmuTracer {
+    /// Trace to the log, with target `mmu`.
+    pub fn to_log() -> Self {
+        Self::new(MmuTraceSink::Log)
+    }
+
+    /// Trace to a dedicated file, one event per line.
+    pub fn to_file(path: &Path) -> std::io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Self::to_writer(Box::new(file)))
+    }
+
+    pub fn to_writer(writer: Box<dyn Write>) -> Self {
+        Self::new(MmuTraceSink::Writer(writer))
+    }
+
+    fn new(sink: MmuTraceSink) -> Self {
+        Self { sink, pc: 0 }
+    }
+
+    pub(crate) fn record(&mut self, event: &MmuEvent) {
+        match &mut self.sink {
+            MmuTraceSink::Log => log::info!(target: "mmu", "{} @ pc = {:#x}", event, self.pc),
+            MmuTraceSink::Writer(w) => {
+                if let Err(e) = writeln!(w, "{} @ pc = {:#x}", event, self.pc) {
+                    log::warn!("Failed to write MMU trace: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for MmuTracer {
+    fn drop(&mut self) {
+        if let MmuTraceSink::Writer(w) = &mut self.sink {
+            let _ = w.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{
+        board::virt::{SerialDestination, VirtBoardBuilder},
+        isa::riscv::csr_reg::{NamedCsrReg, csr_macro::Satp},
+    };
+
+    use super::*;
+
+    struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_satp_trace() {
+        let mut board = VirtBoardBuilder::new()
+            .serial(SerialDestination::Buffer)
+            .build()
+            .unwrap();
+        let output = Rc::new(RefCell::new(Vec::new()));
+        board
+            .cpu
+            .set_mmu_tracer(Some(MmuTracer::to_writer(Box::new(SharedWriter(
+                output.clone(),
+            )))));
+
+        let satp = (8 << 60) | (3 << 44) | 0x80201;
+        board.cpu.write_csr(Satp::get_index(), satp).unwrap();
+        board.cpu.write_csr(Satp::get_index(), 0).unwrap();
+
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "satp = 0x8000300000080201: mode Sv39 asid 3 root 0x80201000 @ pc = 0x0",
+                "satp = 0x0: mode Bare asid 0 root 0x0 @ pc = 0x0",
+            ]
+        );
+    }
+}
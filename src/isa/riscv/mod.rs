@@ -6,15 +6,30 @@ use crate::{
     },
 };
 
+pub mod alloc_track;
+pub mod cost_profile;
 mod cpu_tester;
 pub mod csr_reg;
+pub mod debug_module;
 pub mod debugger;
 pub mod decoder;
 pub mod executor;
+pub mod extension;
+pub mod func_trace;
+pub mod hooks;
+pub mod instr_stats;
 pub mod instruction;
 pub mod isa_builder;
 pub mod mmu;
+pub mod random_test;
+pub mod shadow_stack;
+pub mod stack_guard;
+pub mod syscall_trace;
+pub mod taint;
+pub mod torture;
 pub mod trap;
+#[cfg(feature = "riscv64")]
+pub mod user_mode;
 pub mod vector;
 
 #[derive(Debug)]
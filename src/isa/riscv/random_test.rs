@@ -0,0 +1,614 @@
+//! Seedable random instruction sequences, run in lockstep with a reference model.
+//!
+//! [`RandomProgram::new`] generates a straight-line program of integer, `M`, load/store and
+//! forward branch instructions from a seed. [`check`] runs it on a bare [`RVCPU`] and on a
+//! small, independent model of the same instructions, comparing the `pc` and every register
+//! after each step and the data memory at the end. The same seed always gives the same
+//! program, so a mismatch is reproduced from the seed alone.
+//!
+//! Loads and stores only address a scratch area through `x31`, which the generator never
+//! writes, so programs can't trap.
+//!
+//! `cargo test --features long-tests` runs many more seeds than the default test, the `soak`
+//! subcommand of the emulator runs them until told to stop. The
+//! [`torture`](super::torture) programs wrap the same sequences with self-checks to run them on
+//! a whole board.
+
+use std::{cell::UnsafeCell, fmt, rc::Rc};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+
+use crate::{
+    config::arch_config::{REGFILE_CNT, SignedWordType, WordType, XLEN},
+    device::mmio::MemoryMapIO,
+    isa::riscv::{executor::RVCPU, mmu::VirtAddrManager, trap::Exception},
+    ram::Ram,
+    ram_config::BASE_ADDR,
+};
+
+/// Holds [`DATA_BASE`] for every load and store.
+pub(super) const DATA_REG: u8 = 31;
+pub(super) const DATA_BASE: WordType = BASE_ADDR + 0x10_0000;
+pub(super) const DATA_SIZE: usize = 512;
+/// Programs end before the scratch area.
+const MAX_LEN: usize = ((DATA_BASE - BASE_ADDR) / 4) as usize;
+/// Branches skip up to this many instructions, always forward.
+const MAX_BRANCH_SKIP: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    R,
+    I,
+    Shift,
+    U,
+    Load,
+    Store,
+    B,
+}
+
+#[derive(Clone, Copy)]
+enum Semantics {
+    /// `rd = f(rs1, rs2)`, or `f(rs1, imm)` for the immediate formats.
+    Alu(fn(WordType, WordType) -> WordType),
+    Lui,
+    Auipc,
+    Load {
+        bytes: usize,
+        signed: bool,
+    },
+    Store {
+        bytes: usize,
+    },
+    Branch(fn(WordType, WordType) -> bool),
+}
+
+struct OpDesc {
+    name: &'static str,
+    format: Format,
+    opcode: u32,
+    funct3: u32,
+    funct7: u32,
+    rv64_only: bool,
+    semantics: Semantics,
+}
+
+macro_rules! ops {
+    ($($name:literal, $format:ident, $opcode:literal, $funct3:literal, $funct7:literal, $rv64:literal, $sem:expr;)*) => {
+        &[$(OpDesc {
+            name: $name,
+            format: Format::$format,
+            opcode: $opcode,
+            funct3: $funct3,
+            funct7: $funct7,
+            rv64_only: $rv64,
+            semantics: $sem,
+        }),*]
+    };
+}
+
+const OPS: &[OpDesc] = ops! {
+    "add",    R,     0x33, 0, 0x00, false, Semantics::Alu(|a, b| a.wrapping_add(b));
+    "sub",    R,     0x33, 0, 0x20, false, Semantics::Alu(|a, b| a.wrapping_sub(b));
+    "sll",    R,     0x33, 1, 0x00, false, Semantics::Alu(|a, b| a << (b as usize % XLEN));
+    "slt",    R,     0x33, 2, 0x00, false, Semantics::Alu(|a, b| ((a as SignedWordType) < (b as SignedWordType)) as WordType);
+    "sltu",   R,     0x33, 3, 0x00, false, Semantics::Alu(|a, b| (a < b) as WordType);
+    "xor",    R,     0x33, 4, 0x00, false, Semantics::Alu(|a, b| a ^ b);
+    "srl",    R,     0x33, 5, 0x00, false, Semantics::Alu(|a, b| a >> (b as usize % XLEN));
+    "sra",    R,     0x33, 5, 0x20, false, Semantics::Alu(|a, b| ((a as SignedWordType) >> (b as usize % XLEN)) as WordType);
+    "or",     R,     0x33, 6, 0x00, false, Semantics::Alu(|a, b| a | b);
+    "and",    R,     0x33, 7, 0x00, false, Semantics::Alu(|a, b| a & b);
+    "mul",    R,     0x33, 0, 0x01, false, Semantics::Alu(|a, b| a.wrapping_mul(b));
+    "mulh",   R,     0x33, 1, 0x01, false, Semantics::Alu(|a, b| ((signed(a) * signed(b)) >> XLEN) as WordType);
+    "mulhsu", R,     0x33, 2, 0x01, false, Semantics::Alu(|a, b| ((signed(a) * b as i128) >> XLEN) as WordType);
+    "mulhu",  R,     0x33, 3, 0x01, false, Semantics::Alu(|a, b| ((a as u128 * b as u128) >> XLEN) as WordType);
+    "div",    R,     0x33, 4, 0x01, false, Semantics::Alu(div);
+    "divu",   R,     0x33, 5, 0x01, false, Semantics::Alu(|a, b| a.checked_div(b).unwrap_or(WordType::MAX));
+    "rem",    R,     0x33, 6, 0x01, false, Semantics::Alu(rem);
+    "remu",   R,     0x33, 7, 0x01, false, Semantics::Alu(|a, b| a.checked_rem(b).unwrap_or(a));
+    "addw",   R,     0x3b, 0, 0x00, true,  Semantics::Alu(|a, b| sext32((a as u32).wrapping_add(b as u32)));
+    "subw",   R,     0x3b, 0, 0x20, true,  Semantics::Alu(|a, b| sext32((a as u32).wrapping_sub(b as u32)));
+    "sllw",   R,     0x3b, 1, 0x00, true,  Semantics::Alu(|a, b| sext32((a as u32) << (b % 32)));
+    "srlw",   R,     0x3b, 5, 0x00, true,  Semantics::Alu(|a, b| sext32((a as u32) >> (b % 32)));
+    "sraw",   R,     0x3b, 5, 0x20, true,  Semantics::Alu(|a, b| sext32(((a as i32) >> (b % 32)) as u32));
+    "mulw",   R,     0x3b, 0, 0x01, true,  Semantics::Alu(|a, b| sext32((a as u32).wrapping_mul(b as u32)));
+    "divw",   R,     0x3b, 4, 0x01, true,  Semantics::Alu(|a, b| sext32(div32(a as i32, b as i32) as u32));
+    "divuw",  R,     0x3b, 5, 0x01, true,  Semantics::Alu(|a, b| sext32((a as u32).checked_div(b as u32).unwrap_or(u32::MAX)));
+    "remw",   R,     0x3b, 6, 0x01, true,  Semantics::Alu(|a, b| sext32(rem32(a as i32, b as i32) as u32));
+    "remuw",  R,     0x3b, 7, 0x01, true,  Semantics::Alu(|a, b| sext32((a as u32).checked_rem(b as u32).unwrap_or(a as u32)));
+
+    "addi",   I,     0x13, 0, 0x00, false, Semantics::Alu(|a, b| a.wrapping_add(b));
+    "slti",   I,     0x13, 2, 0x00, false, Semantics::Alu(|a, b| ((a as SignedWordType) < (b as SignedWordType)) as WordType);
+    "sltiu",  I,     0x13, 3, 0x00, false, Semantics::Alu(|a, b| (a < b) as WordType);
+    "xori",   I,     0x13, 4, 0x00, false, Semantics::Alu(|a, b| a ^ b);
+    "ori",    I,     0x13, 6, 0x00, false, Semantics::Alu(|a, b| a | b);
+    "andi",   I,     0x13, 7, 0x00, false, Semantics::Alu(|a, b| a & b);
+    "addiw",  I,     0x1b, 0, 0x00, true,  Semantics::Alu(|a, b| sext32((a as u32).wrapping_add(b as u32)));
+    "slli",   Shift, 0x13, 1, 0x00, false, Semantics::Alu(|a, b| a << b);
+    "srli",   Shift, 0x13, 5, 0x00, false, Semantics::Alu(|a, b| a >> b);
+    "srai",   Shift, 0x13, 5, 0x20, false, Semantics::Alu(|a, b| ((a as SignedWordType) >> b) as WordType);
+    "slliw",  Shift, 0x1b, 1, 0x00, true,  Semantics::Alu(|a, b| sext32((a as u32) << b));
+    "srliw",  Shift, 0x1b, 5, 0x00, true,  Semantics::Alu(|a, b| sext32((a as u32) >> b));
+    "sraiw",  Shift, 0x1b, 5, 0x20, true,  Semantics::Alu(|a, b| sext32(((a as i32) >> b) as u32));
+
+    "lui",    U,     0x37, 0, 0x00, false, Semantics::Lui;
+    "auipc",  U,     0x17, 0, 0x00, false, Semantics::Auipc;
+
+    "lb",     Load,  0x03, 0, 0x00, false, Semantics::Load { bytes: 1, signed: true };
+    "lh",     Load,  0x03, 1, 0x00, false, Semantics::Load { bytes: 2, signed: true };
+    "lw",     Load,  0x03, 2, 0x00, false, Semantics::Load { bytes: 4, signed: true };
+    "ld",     Load,  0x03, 3, 0x00, true,  Semantics::Load { bytes: 8, signed: true };
+    "lbu",    Load,  0x03, 4, 0x00, false, Semantics::Load { bytes: 1, signed: false };
+    "lhu",    Load,  0x03, 5, 0x00, false, Semantics::Load { bytes: 2, signed: false };
+    "lwu",    Load,  0x03, 6, 0x00, true,  Semantics::Load { bytes: 4, signed: false };
+    "sb",     Store, 0x23, 0, 0x00, false, Semantics::Store { bytes: 1 };
+    "sh",     Store, 0x23, 1, 0x00, false, Semantics::Store { bytes: 2 };
+    "sw",     Store, 0x23, 2, 0x00, false, Semantics::Store { bytes: 4 };
+    "sd",     Store, 0x23, 3, 0x00, true,  Semantics::Store { bytes: 8 };
+
+    "beq",    B,     0x63, 0, 0x00, false, Semantics::Branch(|a, b| a == b);
+    "bne",    B,     0x63, 1, 0x00, false, Semantics::Branch(|a, b| a != b);
+    "blt",    B,     0x63, 4, 0x00, false, Semantics::Branch(|a, b| (a as SignedWordType) < (b as SignedWordType));
+    "bge",    B,     0x63, 5, 0x00, false, Semantics::Branch(|a, b| (a as SignedWordType) >= (b as SignedWordType));
+    "bltu",   B,     0x63, 6, 0x00, false, Semantics::Branch(|a, b| a < b);
+    "bgeu",   B,     0x63, 7, 0x00, false, Semantics::Branch(|a, b| a >= b);
+};
+
+fn signed(value: WordType) -> i128 {
+    value as SignedWordType as i128
+}
+
+fn sext32(value: u32) -> WordType {
+    value as i32 as SignedWordType as WordType
+}
+
+fn div(a: WordType, b: WordType) -> WordType {
+    match b {
+        0 => WordType::MAX,
+        _ => (a as SignedWordType).wrapping_div(b as SignedWordType) as WordType,
+    }
+}
+
+fn rem(a: WordType, b: WordType) -> WordType {
+    match b {
+        0 => a,
+        _ => (a as SignedWordType).wrapping_rem(b as SignedWordType) as WordType,
+    }
+}
+
+fn div32(a: i32, b: i32) -> i32 {
+    if b == 0 { -1 } else { a.wrapping_div(b) }
+}
+
+fn rem32(a: i32, b: i32) -> i32 {
+    if b == 0 { a } else { a.wrapping_rem(b) }
+}
+
+/// One generated instruction. `imm` is the sign-extended immediate, the shift amount, the
+/// upper 20 bits of `lui`/`auipc` or the branch offset in bytes.
+#[derive(Clone, Copy)]
+pub struct RandomInstr {
+    op: &'static OpDesc,
+    rd: u8,
+    rs1: u8,
+    rs2: u8,
+    imm: i32,
+}
+
+impl RandomInstr {
+    /// The instruction `name` of the generator, for the code around the generated programs.
+    pub(super) fn new(name: &str, rd: u8, rs1: u8, rs2: u8, imm: i32) -> Self {
+        let op = OPS
+            .iter()
+            .find(|op| op.name == name)
+            .unwrap_or_else(|| panic!("no instruction {name}"));
+        Self {
+            op,
+            rd,
+            rs1,
+            rs2,
+            imm,
+        }
+    }
+
+    pub fn encode(&self) -> u32 {
+        let op = self.op;
+        let (rd, rs1, rs2) = (self.rd as u32, self.rs1 as u32, self.rs2 as u32);
+        let imm = self.imm as u32;
+        let base = (op.funct3 << 12) | op.opcode;
+        match op.format {
+            Format::R => (op.funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (rd << 7) | base,
+            Format::I | Format::Load => (imm << 20) | (rs1 << 15) | (rd << 7) | base,
+            Format::Shift => (op.funct7 << 25) | (imm << 20) | (rs1 << 15) | (rd << 7) | base,
+            Format::U => (imm << 12) | (rd << 7) | op.opcode,
+            Format::Store => {
+                ((imm >> 5) << 25) | (rs2 << 20) | (rs1 << 15) | ((imm & 0x1f) << 7) | base
+            }
+            Format::B => {
+                (((imm >> 12) & 1) << 31)
+                    | (((imm >> 5) & 0x3f) << 25)
+                    | (rs2 << 20)
+                    | (rs1 << 15)
+                    | (((imm >> 1) & 0xf) << 8)
+                    | (((imm >> 11) & 1) << 7)
+                    | base
+            }
+        }
+    }
+}
+
+impl fmt::Display for RandomInstr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            op,
+            rd,
+            rs1,
+            rs2,
+            imm,
+        } = *self;
+        let name = op.name;
+        match op.format {
+            Format::R => write!(f, "{name} x{rd}, x{rs1}, x{rs2}"),
+            Format::I | Format::Shift => write!(f, "{name} x{rd}, x{rs1}, {imm}"),
+            Format::U => write!(f, "{name} x{rd}, {imm:#x}"),
+            Format::Load => write!(f, "{name} x{rd}, {imm}(x{rs1})"),
+            Format::Store => write!(f, "{name} x{rs2}, {imm}(x{rs1})"),
+            Format::B => write!(f, "{name} x{rs1}, x{rs2}, {imm:+}"),
+        }
+    }
+}
+
+impl fmt::Debug for RandomInstr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self} ({:#010x})", self.encode())
+    }
+}
+
+/// A generated program with the register values it starts from.
+#[derive(Clone, Debug)]
+pub struct RandomProgram {
+    pub seed: u64,
+    pub regs: [WordType; REGFILE_CNT],
+    pub instrs: Vec<RandomInstr>,
+}
+
+impl RandomProgram {
+    /// Generates `len` instructions (at most 256Ki) from `seed`.
+    pub fn new(seed: u64, len: usize) -> Self {
+        assert!(
+            len <= MAX_LEN,
+            "programs have at most {MAX_LEN} instructions"
+        );
+
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        let ops: Vec<&OpDesc> = OPS
+            .iter()
+            .filter(|op| XLEN == 64 || !op.rv64_only)
+            .collect();
+
+        let mut regs = [0; REGFILE_CNT];
+        for reg in regs.iter_mut().skip(1) {
+            *reg = random_value(&mut rng);
+        }
+        regs[DATA_REG as usize] = DATA_BASE;
+
+        let instrs = (0..len)
+            .map(|idx| {
+                let op = ops[rng.random_range(0..ops.len())];
+                random_instr(&mut rng, op, len - idx - 1)
+            })
+            .collect();
+
+        Self { seed, regs, instrs }
+    }
+
+    /// The program as it is laid out in memory.
+    pub fn encode(&self) -> Vec<u32> {
+        self.instrs.iter().map(RandomInstr::encode).collect()
+    }
+
+    /// One instruction per line, with its address.
+    pub fn listing(&self) -> String {
+        self.instrs
+            .iter()
+            .enumerate()
+            .map(|(idx, instr)| format!("{:#x}: {instr:?}\n", BASE_ADDR + 4 * idx as WordType))
+            .collect()
+    }
+}
+
+/// Mostly full-width values, with enough small and boundary values to hit the corner cases of
+/// comparisons, shifts and division.
+fn random_value(rng: &mut ChaCha12Rng) -> WordType {
+    match rng.random_range(0..8) {
+        0 => rng.random_range(0..16),
+        1 => [
+            0,
+            1,
+            WordType::MAX,
+            WordType::MAX >> 1,
+            !(WordType::MAX >> 1),
+        ][rng.random_range(0..5)],
+        _ => rng.random(),
+    }
+}
+
+/// `remaining` is the number of instructions after this one, branches never jump past the end.
+fn random_instr(rng: &mut ChaCha12Rng, op: &'static OpDesc, remaining: usize) -> RandomInstr {
+    // `rd` is never `x31`, `x0` is fine and must stay zero.
+    let rd = rng.random_range(0..DATA_REG);
+    let rs1 = rng.random_range(0..REGFILE_CNT as u8);
+    let rs2 = rng.random_range(0..REGFILE_CNT as u8);
+
+    let (rs1, imm) = match op.semantics {
+        Semantics::Load { bytes, .. } | Semantics::Store { bytes } => {
+            let slots = DATA_SIZE / bytes;
+            (DATA_REG, (rng.random_range(0..slots) * bytes) as i32)
+        }
+        Semantics::Branch(_) => {
+            let skip = rng.random_range(0..=remaining.min(MAX_BRANCH_SKIP));
+            (rs1, 4 * (skip as i32 + 1))
+        }
+        _ => match op.format {
+            Format::I => (rs1, rng.random_range(-2048..2048)),
+            Format::Shift if op.opcode == 0x1b => (rs1, rng.random_range(0..32)),
+            Format::Shift => (rs1, rng.random_range(0..XLEN as i32)),
+            Format::U => (rs1, rng.random_range(0..1 << 20)),
+            _ => (rs1, 0),
+        },
+    };
+
+    RandomInstr {
+        op,
+        rd,
+        rs1,
+        rs2,
+        imm,
+    }
+}
+
+/// The reference model: the architectural state the instructions of [`OPS`] touch.
+pub(super) struct Model {
+    pub(super) regs: [WordType; REGFILE_CNT],
+    /// Where the first instruction is.
+    base: WordType,
+    /// Index of the next instruction.
+    pub(super) next: usize,
+    pub(super) data: Vec<u8>,
+}
+
+impl Model {
+    /// The state before `program`, laid out from `base`.
+    pub(super) fn new(program: &RandomProgram, base: WordType) -> Self {
+        Self {
+            regs: program.regs,
+            base,
+            next: 0,
+            data: vec![0; DATA_SIZE],
+        }
+    }
+
+    pub(super) fn pc(&self) -> WordType {
+        self.base + 4 * self.next as WordType
+    }
+
+    /// Compare the `pc` and the registers of `cpu` with the model, after `instr`, the `step`th
+    /// instruction of the program `seed`.
+    pub(super) fn compare(
+        &self,
+        cpu: &RVCPU,
+        seed: u64,
+        step: usize,
+        instr: &RandomInstr,
+    ) -> Result<(), RandomTestError> {
+        let describe = || format!("{instr:?}");
+        if cpu.pc != self.pc() {
+            return Err(RandomTestError::Pc {
+                seed,
+                step,
+                instr: describe(),
+                expected: self.pc(),
+                actual: cpu.pc,
+            });
+        }
+        for reg in 0..REGFILE_CNT as u8 {
+            let (actual, _) = cpu.reg_file.read(reg, 0);
+            let expected = self.regs[reg as usize];
+            if actual != expected {
+                return Err(RandomTestError::Register {
+                    seed,
+                    step,
+                    instr: describe(),
+                    reg,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn step(&mut self, instr: &RandomInstr) {
+        let a = self.regs[instr.rs1 as usize];
+        let b = self.regs[instr.rs2 as usize];
+        let imm = instr.imm as SignedWordType as WordType;
+
+        let mut next = self.next + 1;
+        let result = match instr.op.semantics {
+            Semantics::Alu(f) if instr.op.format == Format::R => Some(f(a, b)),
+            Semantics::Alu(f) => Some(f(a, imm)),
+            Semantics::Lui => Some(sext32((instr.imm as u32) << 12)),
+            Semantics::Auipc => Some(self.pc().wrapping_add(sext32((instr.imm as u32) << 12))),
+            Semantics::Load { bytes, signed } => {
+                let offset = instr.imm as usize;
+                let mut raw = [0; 8];
+                raw[..bytes].copy_from_slice(&self.data[offset..offset + bytes]);
+                let value = u64::from_le_bytes(raw);
+                let shift = 64 - 8 * bytes as u32;
+                Some(if signed {
+                    ((value << shift) as i64 >> shift) as WordType
+                } else {
+                    value as WordType
+                })
+            }
+            Semantics::Store { bytes } => {
+                let offset = instr.imm as usize;
+                self.data[offset..offset + bytes].copy_from_slice(&b.to_le_bytes()[..bytes]);
+                None
+            }
+            Semantics::Branch(taken) => {
+                if taken(a, b) {
+                    next = self.next + instr.imm as usize / 4;
+                }
+                None
+            }
+        };
+
+        if let Some(value) = result
+            && instr.rd != 0
+        {
+            self.regs[instr.rd as usize] = value;
+        }
+        self.next = next;
+    }
+}
+
+/// How the emulator diverged from the model.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RandomTestError {
+    #[error("seed {seed}, step {step} ({instr}): raised {exception:?}")]
+    Exception {
+        seed: u64,
+        step: usize,
+        instr: String,
+        exception: Exception,
+    },
+    #[error("seed {seed}, step {step} ({instr}): pc is {actual:#x}, the model has {expected:#x}")]
+    Pc {
+        seed: u64,
+        step: usize,
+        instr: String,
+        expected: WordType,
+        actual: WordType,
+    },
+    #[error(
+        "seed {seed}, step {step} ({instr}): x{reg} is {actual:#x}, the model has {expected:#x}"
+    )]
+    Register {
+        seed: u64,
+        step: usize,
+        instr: String,
+        reg: u8,
+        expected: WordType,
+        actual: WordType,
+    },
+    #[error("seed {seed}: memory at {addr:#x} is {actual:#04x}, the model has {expected:#04x}")]
+    Memory {
+        seed: u64,
+        addr: WordType,
+        expected: u8,
+        actual: u8,
+    },
+}
+
+/// Runs `program` on a bare hart and on the model, see the [module documentation](self).
+pub fn check(program: &RandomProgram) -> Result<(), RandomTestError> {
+    let seed = program.seed;
+    let ram = Rc::new(UnsafeCell::new(Ram::new()));
+    let mmio = MemoryMapIO::from_mmio_items(ram.clone(), vec![]);
+    let mut cpu = RVCPU::from_vaddr_manager(VirtAddrManager::from_ram_and_mmio(ram, mmio));
+
+    for (idx, raw) in program.encode().into_iter().enumerate() {
+        cpu.memory
+            .write_by_paddr(BASE_ADDR + 4 * idx as WordType, raw)
+            .expect("programs fit in RAM");
+    }
+    for (idx, &value) in program.regs.iter().enumerate() {
+        cpu.reg_file.write(idx as u8, value);
+    }
+    cpu.pc = BASE_ADDR;
+
+    let mut model = Model::new(program, BASE_ADDR);
+
+    let mut step = 0;
+    while let Some(instr) = program.instrs.get(model.next) {
+        cpu.step().map_err(|exception| RandomTestError::Exception {
+            seed,
+            step,
+            instr: format!("{instr:?}"),
+            exception,
+        })?;
+        model.step(instr);
+        model.compare(&cpu, seed, step, instr)?;
+        step += 1;
+    }
+
+    for (offset, &expected) in model.data.iter().enumerate() {
+        let addr = DATA_BASE + offset as WordType;
+        let actual = cpu
+            .memory
+            .read_by_paddr::<u8>(addr)
+            .expect("the data area is RAM");
+        if actual != expected {
+            return Err(RandomTestError::Memory {
+                seed,
+                addr,
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_seeds(seeds: std::ops::Range<u64>, len: usize) {
+        for seed in seeds {
+            let program = RandomProgram::new(seed, len);
+            if let Err(e) = check(&program) {
+                panic!("{e}\n{}", program.listing());
+            }
+        }
+    }
+
+    #[test]
+    fn test_generator_is_deterministic() {
+        let a = RandomProgram::new(42, 64);
+        let b = RandomProgram::new(42, 64);
+        assert_eq!(a.encode(), b.encode());
+        assert_eq!(a.regs, b.regs);
+        assert_ne!(a.encode(), RandomProgram::new(43, 64).encode());
+    }
+
+    #[test]
+    fn test_encoding() {
+        let op = |name| OPS.iter().find(|op| op.name == name).unwrap();
+        let instr = |name, rd, rs1, rs2, imm| RandomInstr {
+            op: op(name),
+            rd,
+            rs1,
+            rs2,
+            imm,
+        };
+        assert_eq!(instr("add", 1, 2, 3, 0).encode(), 0x003100b3);
+        assert_eq!(instr("addi", 1, 2, 0, -1).encode(), 0xfff10093);
+        assert_eq!(instr("srai", 1, 2, 0, 3).encode(), 0x40315093);
+        assert_eq!(instr("lui", 1, 0, 0, 0x12345).encode(), 0x123450b7);
+        assert_eq!(instr("sw", 0, 31, 5, 8).encode(), 0x005fa423);
+        assert_eq!(instr("bne", 0, 1, 2, 8).encode(), 0x00209463);
+    }
+
+    #[test]
+    fn test_random_programs() {
+        check_seeds(0..32, 256);
+    }
+
+    #[test]
+    #[cfg(feature = "long-tests")]
+    fn test_random_programs_long() {
+        check_seeds(0..10_000, 1024);
+    }
+}
@@ -0,0 +1,247 @@
+//! Shadow stack of the calls of the guest, kept on the host, to catch returns to another address
+//! than the one the call linked: a return address smashed on the stack, or an interrupt handler
+//! whose epilogue doesn't restore `ra` for the interrupted code.
+//!
+//! A post-exec hook classifies the jumps as [`JumpKind`] does for the function tracer. A call
+//! pushes its return address, a return pops it and a [`ReturnMismatch`] is reported if it went
+//! elsewhere. A return to a deeper frame unwinds to it silently, as `longjmp` does, and a return
+//! with no frame left (from the code which ran before the shadow stack) is not checked. Guests
+//! switching between task stacks see a mismatch at every switch.
+
+use std::{cell::RefCell, collections::VecDeque, fmt::Display, rc::Rc};
+
+use crate::{
+    board::{BoardControl, BoardRequest},
+    config::arch_config::WordType,
+    isa::riscv::{decoder::DecodeInstr, executor::RVCPU, func_trace::JumpKind, hooks::HookId},
+    load::SymTab,
+};
+
+/// Frames are capped to this depth, the oldest ones are forgotten.
+const MAX_DEPTH: usize = 1024;
+
+/// A return which didn't go back to the instruction after its call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReturnMismatch {
+    /// The returning instruction.
+    pub pc: WordType,
+    /// Where it returned to.
+    pub target: WordType,
+    /// Where the innermost call would have returned to.
+    pub expected: WordType,
+    /// The symbol `pc` is in, if the ELF has symbols.
+    pub function: Option<String>,
+}
+
+impl Display for ReturnMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "return mismatch")?;
+        if let Some(function) = &self.function {
+            write!(f, " in {function}")?;
+        }
+        write!(
+            f,
+            ": returned to {:#x} instead of {:#x}, pc = {:#x}",
+            self.target, self.expected, self.pc
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct ShadowStack {
+    /// Return addresses of the calls, innermost last.
+    frames: VecDeque<WordType>,
+    symtab: Option<SymTab>,
+    /// Stop the board at a mismatch instead of only warning.
+    control: Option<BoardControl>,
+    mismatches: Vec<ReturnMismatch>,
+}
+
+impl ShadowStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Name the function of each mismatch after the symbols of `symtab`.
+    pub fn symbols(mut self, symtab: SymTab) -> Self {
+        self.symtab = Some(symtab);
+        self
+    }
+
+    /// Break into the debugger, or stop a plain run, at the first mismatch, see
+    /// [`Board::take_break`](crate::board::Board::take_break).
+    pub fn break_on_mismatch(mut self, control: BoardControl) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Follow the calls of `cpu` from now on.
+    pub fn attach(self, cpu: &mut RVCPU) -> (Rc<RefCell<Self>>, HookId) {
+        let shadow = Rc::new(RefCell::new(self));
+        let id = cpu.add_post_exec_hook(None, {
+            let shadow = shadow.clone();
+            Box::new(move |cpu, pc, instr, exception| {
+                if exception.is_none() {
+                    shadow.borrow_mut().on_instr(pc, instr, cpu.pc());
+                }
+            })
+        });
+        (shadow, id)
+    }
+
+    /// The return addresses of the calls not returned from, innermost first, as a backtrace.
+    pub fn frames(&self) -> impl Iterator<Item = WordType> + '_ {
+        self.frames.iter().rev().copied()
+    }
+
+    /// The mismatches reported so far, oldest first.
+    pub fn mismatches(&self) -> &[ReturnMismatch] {
+        &self.mismatches
+    }
+
+    /// Called after the instruction at `pc` executed, `next_pc` is where it went.
+    pub fn on_instr(&mut self, pc: WordType, instr: &DecodeInstr, next_pc: WordType) {
+        match JumpKind::of(instr.instr, &instr.info) {
+            Some(JumpKind::Call) => {
+                if self.frames.len() == MAX_DEPTH {
+                    self.frames.pop_front();
+                }
+                self.frames.push_back(pc.wrapping_add(instr.len));
+            }
+            Some(JumpKind::Return) => self.on_return(pc, next_pc),
+            Some(JumpKind::Jump) | None => {}
+        }
+    }
+
+    fn on_return(&mut self, pc: WordType, target: WordType) {
+        let Some(&expected) = self.frames.back() else {
+            return;
+        };
+        match self.frames.iter().rposition(|&addr| addr == target) {
+            Some(idx) => self.frames.truncate(idx),
+            None => {
+                self.frames.pop_back();
+                self.report(pc, target, expected);
+            }
+        }
+    }
+
+    #[cold]
+    fn report(&mut self, pc: WordType, target: WordType, expected: WordType) {
+        let function = self
+            .symtab
+            .as_ref()
+            .and_then(|symtab| symtab.func_name_in_addr_range(pc))
+            .cloned();
+        let mismatch = ReturnMismatch {
+            pc,
+            target,
+            expected,
+            function,
+        };
+        match &self.control {
+            Some(control) => control.request(BoardRequest::Break(mismatch.to_string())),
+            None => log::warn!("{mismatch}"),
+        }
+        self.mismatches.push(mismatch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        board::{
+            Board,
+            virt::{SerialDestination, VirtBoardBuilder},
+        },
+        isa::riscv::instruction::{RVInstrInfo, instr_table::RiscvInstr},
+        ram_config,
+    };
+
+    use super::*;
+
+    const CALL: DecodeInstr = DecodeInstr {
+        instr: RiscvInstr::JAL,
+        info: RVInstrInfo::J { rd: 1, imm: 0 },
+        len: 4,
+    };
+    const RET: DecodeInstr = DecodeInstr {
+        instr: RiscvInstr::JALR,
+        info: RVInstrInfo::I {
+            rs1: 1,
+            rd: 0,
+            imm: 0,
+        },
+        len: 4,
+    };
+
+    #[test]
+    fn test_unwind() {
+        let mut shadow = ShadowStack::new();
+        // Returns from the code before the shadow stack are not checked.
+        shadow.on_instr(0x1000, &RET, 0x2000);
+        shadow.on_instr(0x1000, &CALL, 0x3000);
+        shadow.on_instr(0x3000, &CALL, 0x4000);
+        shadow.on_instr(0x4000, &CALL, 0x5000);
+        assert_eq!(
+            shadow.frames().collect::<Vec<_>>(),
+            [0x4004, 0x3004, 0x1004]
+        );
+
+        // `longjmp` back to the outermost function.
+        shadow.on_instr(0x5010, &RET, 0x1004);
+        assert_eq!(shadow.frames().count(), 0);
+        assert!(shadow.mismatches().is_empty());
+    }
+
+    #[test]
+    fn test_return_mismatch() {
+        const F: WordType = ram_config::BASE_ADDR + 0x10;
+        const G: WordType = ram_config::BASE_ADDR + 0x18;
+
+        let program: Vec<u8> = [
+            0x010000efu32, // jal ra, f
+            0x014000ef,    // jal ra, g
+            0x0000006f,    // j .
+            0x0000006f,    // j .
+            0x00008067,    // f: ret
+            0x00000013,    // nop
+            0x00408093,    // g: addi ra, ra, 4
+            0x00008067,    // ret
+        ]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+        let mut board = VirtBoardBuilder::new()
+            .serial(SerialDestination::Buffer)
+            .binary(program)
+            .build()
+            .unwrap();
+        let symtab = SymTab::from(&[("f".to_string(), F), ("g".to_string(), G)]);
+        let (shadow, _) = ShadowStack::new().symbols(symtab).attach(&mut board.cpu);
+
+        for _ in 0..8 {
+            board.step().unwrap();
+        }
+        let shadow = shadow.borrow();
+        assert_eq!(
+            shadow.mismatches(),
+            [ReturnMismatch {
+                pc: G + 4,
+                target: ram_config::BASE_ADDR + 12,
+                expected: ram_config::BASE_ADDR + 8,
+                function: Some("g".to_string()),
+            }]
+        );
+        assert_eq!(
+            shadow.mismatches()[0].to_string(),
+            format!(
+                "return mismatch in g: returned to {:#x} instead of {:#x}, pc = {:#x}",
+                ram_config::BASE_ADDR + 12,
+                ram_config::BASE_ADDR + 8,
+                G + 4
+            )
+        );
+        assert_eq!(shadow.frames().count(), 0);
+    }
+}
@@ -0,0 +1,352 @@
+//! Stack overflow detection for bare-metal guests, whose stacks have no guard page to fault on
+//! and overflow silently into the data below them.
+//!
+//! A [`StackGuard`] watches one stack, `bottom..top` growing down from `top`, through a pre-exec
+//! hook. It reports when `sp` leaves the stack, and when the guest stores to the guard bytes
+//! right below `bottom`, where an overflowing frame lands whatever register addresses it. `sp`
+//! is only checked once it has been inside the stack, and not while it is zero as after a reset,
+//! so the boot code may set it up.
+
+use std::{cell::RefCell, collections::HashSet, fmt::Display, ops::Range, rc::Rc, str::FromStr};
+
+use crate::{
+    board::{BoardControl, BoardRequest},
+    config::arch_config::WordType,
+    isa::riscv::{decoder::DecodeInstr, executor::RVCPU, hooks::HookId, taint::TaintFlow},
+    load::SymTab,
+};
+
+const SP: usize = 2;
+
+/// An end of the stack, see [`StackSpec`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackBound {
+    Addr(WordType),
+    Symbol(String),
+}
+
+impl StackBound {
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if s.starts_with(|c: char| c.is_ascii_digit()) {
+            return crate::parse_u64(s)
+                .ok()
+                .map(|addr| StackBound::Addr(addr as WordType));
+        }
+        (!s.is_empty()).then(|| StackBound::Symbol(s.to_string()))
+    }
+
+    fn resolve(&self, symtab: Option<&SymTab>) -> Result<WordType, StackSpecError> {
+        match self {
+            StackBound::Addr(addr) => Ok(*addr),
+            StackBound::Symbol(name) => {
+                let symtab = symtab.ok_or_else(|| StackSpecError::NoSymbolTable(name.clone()))?;
+                symtab
+                    .func_addr_by_name(name)
+                    .map(|addr| addr as WordType)
+                    .ok_or_else(|| StackSpecError::SymbolNotFound(name.clone()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum StackSpecError {
+    #[error("expected BOTTOM..TOP, addresses or ELF symbols, got \"{0}\"")]
+    Syntax(String),
+
+    #[error("symbol {0} not found")]
+    SymbolNotFound(String),
+
+    #[error("symbol {0} needs an ELF with a symbol table")]
+    NoSymbolTable(String),
+
+    #[error("the stack {0:#x}..{1:#x} is empty")]
+    Empty(WordType, WordType),
+}
+
+/// The stack as given by the user, `BOTTOM..TOP` with addresses or symbols of the ELF, e.g.
+/// `_stack_bottom.._stack_top` or `0x80010000..0x80020000`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackSpec {
+    pub bottom: StackBound,
+    pub top: StackBound,
+}
+
+impl FromStr for StackSpec {
+    type Err = StackSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || StackSpecError::Syntax(s.to_string());
+        let (bottom, top) = s.split_once("..").ok_or_else(err)?;
+        Ok(StackSpec {
+            bottom: StackBound::parse(bottom).ok_or_else(err)?,
+            top: StackBound::parse(top).ok_or_else(err)?,
+        })
+    }
+}
+
+impl StackSpec {
+    /// The addresses of the stack, looking the symbols up in `symtab`.
+    pub fn resolve(&self, symtab: Option<&SymTab>) -> Result<Range<WordType>, StackSpecError> {
+        let bottom = self.bottom.resolve(symtab)?;
+        let top = self.top.resolve(symtab)?;
+        if bottom >= top {
+            return Err(StackSpecError::Empty(bottom, top));
+        }
+        Ok(bottom..top)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackViolationKind {
+    /// `sp` left the stack, to this value.
+    Sp(WordType),
+    /// A store to the guard.
+    GuardWrite { addr: WordType, size: u8 },
+}
+
+/// What [`StackGuard`] reports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackViolation {
+    pub kind: StackViolationKind,
+    /// The instruction which moved `sp` or stored.
+    pub pc: WordType,
+    pub stack: Range<WordType>,
+}
+
+impl Display for StackViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Range { start, end } = self.stack;
+        match self.kind {
+            StackViolationKind::Sp(sp) if sp < start => write!(
+                f,
+                "stack overflow: sp = {sp:#x}, {} bytes below the stack {start:#x}..{end:#x}",
+                start - sp
+            )?,
+            StackViolationKind::Sp(sp) => write!(
+                f,
+                "stack underflow: sp = {sp:#x}, {} bytes above the stack {start:#x}..{end:#x}",
+                sp - end
+            )?,
+            StackViolationKind::GuardWrite { addr, size } => write!(
+                f,
+                "stack overflow: {size}-byte store to {addr:#x}, {} bytes below the stack \
+                 {start:#x}..{end:#x}",
+                start - addr
+            )?,
+        }
+        write!(f, ", pc = {:#x}", self.pc)
+    }
+}
+
+pub struct StackGuard {
+    stack: Range<WordType>,
+    /// `stack.start - guard..stack.start`.
+    guard: Range<WordType>,
+    /// Stop the board at a violation instead of only warning.
+    control: Option<BoardControl>,
+    /// `sp` has been inside the stack, see the [module documentation](self).
+    armed: bool,
+    /// `sp` is outside the stack and was reported, until it comes back.
+    outside: bool,
+    /// The pc of the last instruction, which moved `sp` if it changed.
+    last_pc: WordType,
+    /// Store instructions which wrote the guard, each is reported once.
+    guard_writers: HashSet<WordType>,
+    violations: Vec<StackViolation>,
+}
+
+impl StackGuard {
+    /// Watch `stack` and the `guard` bytes below it.
+    pub fn new(stack: Range<WordType>, guard: WordType) -> Self {
+        let guard = stack.start.saturating_sub(guard)..stack.start;
+        Self {
+            stack,
+            guard,
+            control: None,
+            armed: false,
+            outside: false,
+            last_pc: 0,
+            guard_writers: HashSet::new(),
+            violations: Vec::new(),
+        }
+    }
+
+    /// Break into the debugger, or stop a plain run, at the first violation, see
+    /// [`Board::take_break`](crate::board::Board::take_break).
+    pub fn break_on_violation(mut self, control: BoardControl) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Watch the stack of `cpu` from now on.
+    pub fn attach(self, cpu: &mut RVCPU) -> (Rc<RefCell<Self>>, HookId) {
+        let guard = Rc::new(RefCell::new(self));
+        let id = cpu.add_pre_exec_hook(None, {
+            let guard = guard.clone();
+            Box::new(move |cpu, pc, instr| guard.borrow_mut().on_instr(pc, instr, cpu.regs()))
+        });
+        (guard, id)
+    }
+
+    /// The violations reported so far, oldest first.
+    pub fn violations(&self) -> &[StackViolation] {
+        &self.violations
+    }
+
+    /// Called before each instruction, `regs` hold what the previous one left.
+    pub fn on_instr(&mut self, pc: WordType, instr: &DecodeInstr, regs: &[WordType]) {
+        let last_pc = std::mem::replace(&mut self.last_pc, pc);
+
+        let sp = regs[SP];
+        if sp == 0 {
+            self.armed = false;
+            self.outside = false;
+        } else if (self.stack.start..=self.stack.end).contains(&sp) {
+            self.armed = true;
+            self.outside = false;
+        } else if self.armed && !self.outside {
+            self.outside = true;
+            self.report(StackViolationKind::Sp(sp), last_pc);
+        }
+
+        let (addr, size) = match TaintFlow::of(instr.instr, instr.info, regs) {
+            TaintFlow::Store { addr, size, .. }
+            | TaintFlow::Amo { addr, size, .. }
+            | TaintFlow::StoreConditional { addr, size, .. } => (addr, size),
+            _ => return,
+        };
+        let end = addr.wrapping_add(size as WordType);
+        if addr < self.guard.end && self.guard.start < end && self.guard_writers.insert(pc) {
+            self.report(StackViolationKind::GuardWrite { addr, size }, pc);
+        }
+    }
+
+    #[cold]
+    fn report(&mut self, kind: StackViolationKind, pc: WordType) {
+        let violation = StackViolation {
+            kind,
+            pc,
+            stack: self.stack.clone(),
+        };
+        match &self.control {
+            Some(control) => control.request(BoardRequest::Break(violation.to_string())),
+            None => log::warn!("{violation}"),
+        }
+        self.violations.push(violation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        board::{
+            Board,
+            virt::{SerialDestination, VirtBoardBuilder},
+        },
+        ram_config,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(
+            "_stack_bottom.._stack_top".parse(),
+            Ok(StackSpec {
+                bottom: StackBound::Symbol("_stack_bottom".to_string()),
+                top: StackBound::Symbol("_stack_top".to_string()),
+            })
+        );
+        let spec: StackSpec = "0x80010000..2148663296".parse().unwrap();
+        assert_eq!(spec.resolve(None), Ok(0x8001_0000..0x8012_0000));
+        assert!("0x80010000".parse::<StackSpec>().is_err());
+        assert!("..0x80010000".parse::<StackSpec>().is_err());
+        assert!("0x8001000g..0x80020000".parse::<StackSpec>().is_err());
+
+        let symtab = SymTab::from(&[("_stack_top".to_string(), 0x8002_0000)]);
+        let spec: StackSpec = "0x80010000.._stack_top".parse().unwrap();
+        assert_eq!(spec.resolve(Some(&symtab)), Ok(0x8001_0000..0x8002_0000));
+        assert_eq!(
+            spec.resolve(None),
+            Err(StackSpecError::NoSymbolTable("_stack_top".to_string()))
+        );
+        let spec: StackSpec = "_stack_top..0x80010000".parse().unwrap();
+        assert_eq!(
+            spec.resolve(Some(&symtab)),
+            Err(StackSpecError::Empty(0x8002_0000, 0x8001_0000))
+        );
+    }
+
+    #[test]
+    fn test_stack_guard() {
+        const STACK: Range<WordType> =
+            ram_config::BASE_ADDR + 0x1000..ram_config::BASE_ADDR + 0x1100;
+
+        let program: Vec<u8> = [
+            0x00001117u32, // auipc sp, 0x1
+            0x10010113,    // addi sp, sp, 0x100
+            0xfe812e23,    // sw s0, -4(sp)
+            0xf0010113,    // addi sp, sp, -0x100
+            0xfe812c23,    // sw s0, -8(sp)
+            0xfd010113,    // addi sp, sp, -0x30
+            0x00812023,    // sw s0, 0(sp)
+            0xfe812023,    // sw s0, -0x20(sp)
+            0x0000006f,    // j .
+        ]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+        let mut board = VirtBoardBuilder::new()
+            .serial(SerialDestination::Buffer)
+            .binary(program)
+            .build()
+            .unwrap();
+        let (guard, _) = StackGuard::new(STACK, 0x40).attach(&mut board.cpu);
+
+        for _ in 0..10 {
+            board.step().unwrap();
+        }
+        let pc = |idx: WordType| ram_config::BASE_ADDR + idx * 4;
+        let guard = guard.borrow();
+        assert_eq!(
+            guard.violations(),
+            [
+                // Below sp, e.g. a frame too large for what is left of the stack.
+                StackViolation {
+                    kind: StackViolationKind::GuardWrite {
+                        addr: STACK.start - 8,
+                        size: 4,
+                    },
+                    pc: pc(4),
+                    stack: STACK,
+                },
+                StackViolation {
+                    kind: StackViolationKind::Sp(STACK.start - 0x30),
+                    pc: pc(5),
+                    stack: STACK,
+                },
+                // The store beyond the guard is not seen.
+                StackViolation {
+                    kind: StackViolationKind::GuardWrite {
+                        addr: STACK.start - 0x30,
+                        size: 4,
+                    },
+                    pc: pc(6),
+                    stack: STACK,
+                },
+            ]
+        );
+        assert_eq!(
+            guard.violations()[1].to_string(),
+            format!(
+                "stack overflow: sp = {:#x}, 48 bytes below the stack {:#x}..{:#x}, pc = {:#x}",
+                STACK.start - 0x30,
+                STACK.start,
+                STACK.end,
+                pc(5)
+            )
+        );
+    }
+}
@@ -0,0 +1,398 @@
+//! strace-like tracing of guest environment calls.
+//!
+//! Every `ECALL` raised from U-mode is decoded as a Linux syscall (number in `a7`,
+//! arguments in `a0`-`a5`), and every `ECALL` raised from S-mode is decoded as an SBI call
+//! (extension in `a7`, function in `a6`). The call is reported once the trap handler
+//! returns to the instruction right after the `ECALL`, together with the return value.
+//!
+//! The Linux syscall table can be replaced with a plain text file, see [`SyscallTable::from_file`].
+
+use std::{collections::HashMap, path::Path};
+
+use crate::{config::arch_config::WordType, isa::riscv::csr_reg::PrivilegeLevel};
+
+/// Calls still waiting for their return are capped to this number, because a guest kernel
+/// may never return to some of them (e.g. the calling process has been killed).
+const MAX_PENDING_CALLS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyscallDesc {
+    pub name: String,
+    pub nargs: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SyscallTableError {
+    #[error("failed to read syscall table: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("line {line}: {msg}")]
+    Parse { line: usize, msg: String },
+}
+
+/// Maps syscall numbers to their names and argument counts.
+#[derive(Debug, Clone, Default)]
+pub struct SyscallTable {
+    entries: HashMap<WordType, SyscallDesc>,
+}
+
+#[rustfmt::skip]
+const LINUX_SYSCALLS: &[(WordType, &str, usize)] = &[
+    (17,  "getcwd",          2),
+    (23,  "dup",             1),
+    (24,  "dup3",            3),
+    (25,  "fcntl",           3),
+    (29,  "ioctl",           3),
+    (34,  "mkdirat",         3),
+    (35,  "unlinkat",        3),
+    (48,  "faccessat",       4),
+    (49,  "chdir",           1),
+    (56,  "openat",          4),
+    (57,  "close",           1),
+    (61,  "getdents64",      3),
+    (62,  "lseek",           3),
+    (63,  "read",            3),
+    (64,  "write",           3),
+    (65,  "readv",           3),
+    (66,  "writev",          3),
+    (78,  "readlinkat",      4),
+    (79,  "newfstatat",      4),
+    (80,  "fstat",           2),
+    (93,  "exit",            1),
+    (94,  "exit_group",      1),
+    (96,  "set_tid_address", 1),
+    (98,  "futex",           6),
+    (99,  "set_robust_list", 2),
+    (101, "nanosleep",       2),
+    (113, "clock_gettime",   2),
+    (124, "sched_yield",     0),
+    (129, "kill",            2),
+    (134, "rt_sigaction",    4),
+    (135, "rt_sigprocmask",  4),
+    (160, "uname",           1),
+    (169, "gettimeofday",    2),
+    (172, "getpid",          0),
+    (174, "getuid",          0),
+    (178, "gettid",          0),
+    (214, "brk",             1),
+    (215, "munmap",          2),
+    (220, "clone",           5),
+    (221, "execve",          3),
+    (222, "mmap",            6),
+    (226, "mprotect",        3),
+    (260, "wait4",           4),
+    (261, "prlimit64",       4),
+    (278, "getrandom",       3),
+];
+
+#[rustfmt::skip]
+const SBI_EXTENSIONS: &[(WordType, &str)] = &[
+    (0x00,        "sbi_set_timer"),
+    (0x01,        "sbi_console_putchar"),
+    (0x02,        "sbi_console_getchar"),
+    (0x03,        "sbi_clear_ipi"),
+    (0x04,        "sbi_send_ipi"),
+    (0x05,        "sbi_remote_fence_i"),
+    (0x06,        "sbi_remote_sfence_vma"),
+    (0x07,        "sbi_remote_sfence_vma_asid"),
+    (0x08,        "sbi_shutdown"),
+    (0x10,        "sbi_base"),
+    (0x735049,    "sbi_ipi"),
+    (0x48534D,    "sbi_hsm"),
+    (0x52464E43,  "sbi_rfence"),
+    (0x53525354,  "sbi_srst"),
+    (0x54494D45,  "sbi_time"),
+    (0x4442434E,  "sbi_dbcn"),
+];
+
+impl SyscallTable {
+    /// The common syscalls of Linux on RISC-V (the asm-generic numbering).
+    pub fn linux() -> Self {
+        let entries = LINUX_SYSCALLS
+            .iter()
+            .map(|&(nr, name, nargs)| {
+                (
+                    nr,
+                    SyscallDesc {
+                        name: name.to_string(),
+                        nargs,
+                    },
+                )
+            })
+            .collect();
+        Self { entries }
+    }
+
+    /// Parse a syscall table, one syscall per line: `<nr> <name> [nargs]`.
+    ///
+    /// `nr` can be decimal or `0x`-prefixed hexadecimal, `nargs` defaults to 6.
+    /// Empty lines and everything after a `#` are ignored.
+    pub fn parse(content: &str) -> Result<Self, SyscallTableError> {
+        let mut entries = HashMap::new();
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let err = |msg: &str| SyscallTableError::Parse {
+                line: idx + 1,
+                msg: msg.to_string(),
+            };
+
+            let mut fields = line.split_whitespace();
+            let nr = fields
+                .next()
+                .and_then(parse_number)
+                .ok_or_else(|| err("invalid syscall number"))?;
+            let name = fields.next().ok_or_else(|| err("missing syscall name"))?;
+            let nargs = match fields.next() {
+                Some(s) => s
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&n| n <= 6)
+                    .ok_or_else(|| err("argument count must be in 0..=6"))?,
+                None => 6,
+            };
+            if fields.next().is_some() {
+                return Err(err("unexpected trailing fields"));
+            }
+
+            entries.insert(
+                nr,
+                SyscallDesc {
+                    name: name.to_string(),
+                    nargs,
+                },
+            );
+        }
+
+        Ok(Self { entries })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, SyscallTableError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn get(&self, nr: WordType) -> Option<&SyscallDesc> {
+        self.entries.get(&nr)
+    }
+}
+
+fn parse_number(s: &str) -> Option<WordType> {
+    crate::parse_u64(s).ok().map(|n| n as WordType)
+}
+
+/// A traced call, reported by [`SyscallTracer::on_step`] once it has returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyscallRecord {
+    /// The privilege level the `ECALL` was raised from.
+    pub from: PrivilegeLevel,
+    pub pc: WordType,
+    pub name: String,
+    pub args: Vec<WordType>,
+    /// `a0` when the call returns; `None` for calls which never return.
+    pub ret: Option<WordType>,
+    /// `a1` when the call returns, used by SBI calls to return a value.
+    pub ret_value: WordType,
+}
+
+impl std::fmt::Display for SyscallRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let args: Vec<String> = self.args.iter().map(|a| format!("{:#x}", a)).collect();
+        write!(f, "[{:#x}] {}({})", self.pc, self.name, args.join(", "))?;
+
+        match self.ret {
+            None => write!(f, " = ?"),
+            Some(ret) if self.from == PrivilegeLevel::S => {
+                write!(f, " = {}, {:#x}", ret.cast_signed(), self.ret_value)
+            }
+            // Linux returns `-errno` in the range [-4095, -1].
+            Some(ret) if (-4095..0).contains(&ret.cast_signed()) => {
+                write!(f, " = {}", ret.cast_signed())
+            }
+            Some(ret) => write!(f, " = {:#x}", ret),
+        }
+    }
+}
+
+struct PendingCall {
+    record: SyscallRecord,
+    return_pc: WordType,
+}
+
+/// Decodes `ECALL`s into [`SyscallRecord`]s. Attach it to a CPU with
+/// [`RVCPU::set_syscall_tracer`](crate::isa::riscv::executor::RVCPU::set_syscall_tracer).
+pub struct SyscallTracer {
+    table: SyscallTable,
+    pending: Vec<PendingCall>,
+}
+
+impl SyscallTracer {
+    pub fn new(table: SyscallTable) -> Self {
+        Self {
+            table,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Called when an `ECALL` at `pc` traps, `regs` is the general purpose register file.
+    ///
+    /// Returns the record right away for calls which never return.
+    pub fn on_ecall(
+        &mut self,
+        from: PrivilegeLevel,
+        pc: WordType,
+        regs: &[WordType],
+    ) -> Option<SyscallRecord> {
+        let (name, args) = match from {
+            PrivilegeLevel::U => {
+                let nr = regs[17];
+                match self.table.get(nr) {
+                    Some(desc) => (desc.name.clone(), regs[10..10 + desc.nargs].to_vec()),
+                    None => (format!("syscall_{}", nr), regs[10..16].to_vec()),
+                }
+            }
+            PrivilegeLevel::S => {
+                let (eid, fid) = (regs[17], regs[16]);
+                let name = match SBI_EXTENSIONS.iter().find(|(id, _)| *id == eid) {
+                    Some((_, name)) => format!("{}#{}", name, fid),
+                    None => format!("sbi_{:#x}#{}", eid, fid),
+                };
+                (name, regs[10..16].to_vec())
+            }
+            _ => return None,
+        };
+
+        let record = SyscallRecord {
+            from,
+            pc,
+            name,
+            args,
+            ret: None,
+            ret_value: 0,
+        };
+
+        if matches!(record.name.as_str(), "exit" | "exit_group") {
+            return Some(record);
+        }
+
+        if self.pending.len() >= MAX_PENDING_CALLS {
+            self.pending.remove(0);
+        }
+        self.pending.push(PendingCall {
+            record,
+            return_pc: pc.wrapping_add(4),
+        });
+        None
+    }
+
+    /// Called after every step, returns the record of the call which has just returned.
+    pub fn on_step(
+        &mut self,
+        pc: WordType,
+        privilege: PrivilegeLevel,
+        regs: &[WordType],
+    ) -> Option<SyscallRecord> {
+        let idx = self
+            .pending
+            .iter()
+            .rposition(|p| p.return_pc == pc && p.record.from == privilege)?;
+
+        let mut record = self.pending.remove(idx).record;
+        record.ret = Some(regs[10]);
+        record.ret_value = regs[11];
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regs_with(values: &[(usize, WordType)]) -> Vec<WordType> {
+        let mut regs = vec![0; 32];
+        for &(idx, value) in values {
+            regs[idx] = value;
+        }
+        regs
+    }
+
+    #[test]
+    fn test_parse_table() {
+        let table = SyscallTable::parse(
+            "# custom table\n\
+             64 write 3\n\
+             0x5d exit 1 # exit\n\
+             \n\
+             1000 my_call\n",
+        )
+        .unwrap();
+
+        assert_eq!(table.get(64).unwrap().name, "write");
+        assert_eq!(table.get(93).unwrap().nargs, 1);
+        assert_eq!(table.get(1000).unwrap().nargs, 6);
+        assert!(table.get(63).is_none());
+
+        assert!(matches!(
+            SyscallTable::parse("64 write 3\nwrite 64"),
+            Err(SyscallTableError::Parse { line: 2, .. })
+        ));
+        assert!(SyscallTable::parse("64 write 7").is_err());
+    }
+
+    #[test]
+    fn test_trace_linux_syscall() {
+        let mut tracer = SyscallTracer::new(SyscallTable::linux());
+
+        let regs = regs_with(&[(17, 64), (10, 1), (11, 0x8000_1000), (12, 13)]);
+        assert!(tracer.on_ecall(PrivilegeLevel::U, 0x1000, &regs).is_none());
+
+        // Still in the kernel, or back in user mode at another address.
+        assert!(tracer.on_step(0x1004, PrivilegeLevel::S, &regs).is_none());
+        assert!(tracer.on_step(0x2000, PrivilegeLevel::U, &regs).is_none());
+
+        let regs = regs_with(&[(10, 13)]);
+        let record = tracer.on_step(0x1004, PrivilegeLevel::U, &regs).unwrap();
+        assert_eq!(record.name, "write");
+        assert_eq!(record.args, vec![1, 0x8000_1000, 13]);
+        assert_eq!(record.ret, Some(13));
+        assert_eq!(
+            record.to_string(),
+            "[0x1000] write(0x1, 0x80001000, 0xd) = 0xd"
+        );
+
+        // Returned only once.
+        assert!(tracer.on_step(0x1004, PrivilegeLevel::U, &regs).is_none());
+    }
+
+    #[test]
+    fn test_trace_error_and_exit() {
+        let mut tracer = SyscallTracer::new(SyscallTable::linux());
+
+        let regs = regs_with(&[(17, 57), (10, 3)]);
+        tracer.on_ecall(PrivilegeLevel::U, 0x1000, &regs);
+        let regs = regs_with(&[(10, (-9i64) as WordType)]);
+        let record = tracer.on_step(0x1004, PrivilegeLevel::U, &regs).unwrap();
+        assert_eq!(record.to_string(), "[0x1000] close(0x3) = -9");
+
+        let regs = regs_with(&[(17, 93), (10, 0)]);
+        let record = tracer.on_ecall(PrivilegeLevel::U, 0x2000, &regs).unwrap();
+        assert_eq!(record.to_string(), "[0x2000] exit(0x0) = ?");
+    }
+
+    #[test]
+    fn test_trace_sbi_call() {
+        let mut tracer = SyscallTracer::new(SyscallTable::default());
+
+        let regs = regs_with(&[(17, 0x4442434E), (16, 2), (10, b'a' as WordType)]);
+        tracer.on_ecall(PrivilegeLevel::S, 0x8020_0000, &regs);
+
+        let regs = regs_with(&[(10, 0), (11, 0)]);
+        let record = tracer
+            .on_step(0x8020_0004, PrivilegeLevel::S, &regs)
+            .unwrap();
+        assert_eq!(record.name, "sbi_dbcn#2");
+        assert_eq!(record.args[0], b'a' as WordType);
+    }
+}
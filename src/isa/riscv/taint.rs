@@ -0,0 +1,503 @@
+//! Taint tracking, to follow the data the guest reads from untrusted sources, e.g. to see which
+//! registers and buffers of a parser an input reaches.
+//!
+//! Every integer register and every byte of physical memory has a shadow bit. Loads from a
+//! source range (the receive register of the UART) and disk reads of a tainted region taint what
+//! they write, and the hart propagates taint through the integer instructions: the result of an
+//! operation is tainted if one of its operands is, loads and stores copy the taint of the bytes
+//! they move, and constants, CSRs and floating-point values are clean. Taint through control flow
+//! (a branch on tainted data) is not tracked.
+
+use std::{cell::RefCell, collections::HashMap, ops::Range, rc::Rc};
+
+use crate::{
+    config::arch_config::{REGFILE_CNT, WordType},
+    isa::riscv::{
+        executor::RVCPU,
+        instruction::{RVInstrInfo, instr_table::RiscvInstr},
+        mmu::AccessType,
+    },
+    utils::wrapping_add_as_signed,
+};
+
+const PAGE_SHIFT: u64 = 12;
+const PAGE_WORDS: usize = (1 << PAGE_SHIFT) / 64;
+
+/// One bit per byte of physical memory, allocated by page on the first taint.
+#[derive(Debug, Default)]
+pub struct ShadowMemory {
+    pages: HashMap<u64, Box<[u64; PAGE_WORDS]>>,
+}
+
+impl ShadowMemory {
+    pub fn is_tainted(&self, addr: u64) -> bool {
+        self.pages
+            .get(&(addr >> PAGE_SHIFT))
+            .is_some_and(|page| page[word(addr)] & bit(addr) != 0)
+    }
+
+    pub fn any_tainted(&self, addr: u64, len: u64) -> bool {
+        (addr..addr.saturating_add(len)).any(|addr| self.is_tainted(addr))
+    }
+
+    /// Taint or clean `len` bytes from `addr`.
+    pub fn set(&mut self, addr: u64, len: u64, tainted: bool) {
+        for addr in addr..addr.saturating_add(len) {
+            let page_nr = addr >> PAGE_SHIFT;
+            if tainted {
+                self.pages
+                    .entry(page_nr)
+                    .or_insert_with(|| Box::new([0; PAGE_WORDS]))[word(addr)] |= bit(addr);
+            } else if let Some(page) = self.pages.get_mut(&page_nr) {
+                page[word(addr)] &= !bit(addr);
+                if page.iter().all(|&word| word == 0) {
+                    self.pages.remove(&page_nr);
+                }
+            }
+        }
+    }
+
+    /// The number of tainted bytes.
+    pub fn tainted_bytes(&self) -> u64 {
+        self.pages
+            .values()
+            .flat_map(|page| page.iter())
+            .map(|word| word.count_ones() as u64)
+            .sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+}
+
+fn word(addr: u64) -> usize {
+    (addr as usize % (1 << PAGE_SHIFT)) / 64
+}
+
+fn bit(addr: u64) -> u64 {
+    1 << (addr % 64)
+}
+
+/// The taint state of a hart, see [`RVCPU::enable_taint`].
+#[derive(Debug)]
+pub struct TaintTracker {
+    regs: [bool; REGFILE_CNT],
+    memory: Rc<RefCell<ShadowMemory>>,
+    /// Physical ranges whose loads return tainted data.
+    sources: Vec<Range<u64>>,
+}
+
+impl TaintTracker {
+    fn new() -> Self {
+        Self {
+            regs: [false; REGFILE_CNT],
+            memory: Rc::new(RefCell::new(ShadowMemory::default())),
+            sources: Vec::new(),
+        }
+    }
+
+    pub fn is_reg_tainted(&self, idx: u8) -> bool {
+        self.regs[idx as usize]
+    }
+
+    /// Taint or clean `x<idx>`, `x0` stays clean.
+    pub fn set_reg(&mut self, idx: u8, tainted: bool) {
+        if idx != 0 {
+            self.regs[idx as usize] = tainted;
+        }
+    }
+
+    /// The shadow of the physical memory, shared with the devices tainting it.
+    pub fn memory(&self) -> &Rc<RefCell<ShadowMemory>> {
+        &self.memory
+    }
+
+    /// Taint what the hart loads from the physical `range`, e.g. a receive register.
+    pub fn add_mmio_source(&mut self, range: Range<u64>) {
+        if !self.sources.contains(&range) {
+            self.sources.push(range);
+        }
+    }
+
+    /// Clean the registers and the memory, the sources are kept.
+    pub fn clear(&mut self) {
+        self.regs = [false; REGFILE_CNT];
+        self.memory.borrow_mut().clear();
+    }
+
+    fn is_source(&self, paddr: u64, size: u64) -> bool {
+        self.sources
+            .iter()
+            .any(|range| paddr < range.end && range.start < paddr + size)
+    }
+}
+
+/// How an instruction moves taint, computed before it executes since it may overwrite the
+/// registers its addresses come from.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum TaintFlow {
+    None,
+    /// `rd` is tainted if one of `srcs` is, `x0` being always clean.
+    Regs {
+        rd: u8,
+        srcs: [u8; 2],
+    },
+    Load {
+        rd: u8,
+        addr: WordType,
+        size: u8,
+    },
+    /// Stores `src`, `x0` for the floating-point stores.
+    Store {
+        addr: WordType,
+        size: u8,
+        src: u8,
+    },
+    /// `rd` gets the old memory, which gets `src` or both with `swap` clear.
+    Amo {
+        rd: u8,
+        addr: WordType,
+        size: u8,
+        src: u8,
+        swap: bool,
+    },
+    /// Stores `src` if it succeeds, `rd` is clean.
+    StoreConditional {
+        rd: u8,
+        addr: WordType,
+        size: u8,
+        src: u8,
+    },
+}
+
+impl TaintFlow {
+    pub(super) fn of(instr: RiscvInstr, info: RVInstrInfo, regs: &[WordType]) -> Self {
+        use RiscvInstr::*;
+
+        let addr = |base: u8, imm: WordType| wrapping_add_as_signed(regs[base as usize], imm);
+        let clean = |rd: u8| TaintFlow::Regs { rd, srcs: [0, 0] };
+        match (instr, info) {
+            (
+                ADD | SUB | SLL | SLT | SLTU | XOR | SRL | SRA | OR | AND | ADDW | SUBW | SLLW
+                | SRLW | SRAW | MUL | MULH | MULHSU | MULHU | DIV | DIVU | REM | REMU | MULW | DIVW
+                | DIVUW | REMW | REMUW,
+                RVInstrInfo::R { rs1, rs2, rd },
+            ) => TaintFlow::Regs {
+                rd,
+                srcs: [rs1, rs2],
+            },
+            (
+                ADDI | SLTI | SLTIU | XORI | ORI | ANDI | SLLI | SRLI | SRAI | ADDIW | SLLIW
+                | SRLIW | SRAIW,
+                RVInstrInfo::I { rs1, rd, .. },
+            ) => TaintFlow::Regs { rd, srcs: [rs1, 0] },
+            (LUI | AUIPC, RVInstrInfo::U { rd, .. }) | (JAL, RVInstrInfo::J { rd, .. }) => {
+                clean(rd)
+            }
+            (
+                JALR | CSRRW | CSRRS | CSRRC | CSRRWI | CSRRSI | CSRRCI,
+                RVInstrInfo::I { rd, .. },
+            ) => clean(rd),
+
+            (LB | LBU | LH | LHU | LW | LWU | LD, RVInstrInfo::I { rs1, rd, imm }) => {
+                TaintFlow::Load {
+                    rd,
+                    addr: addr(rs1, imm),
+                    size: access_size(instr),
+                }
+            }
+            (SB | SH | SW | SD, RVInstrInfo::S { rs1, rs2, imm }) => TaintFlow::Store {
+                addr: addr(rs1, imm),
+                size: access_size(instr),
+                src: rs2,
+            },
+            (FSW | FSD, RVInstrInfo::S { rs1, imm, .. }) => TaintFlow::Store {
+                addr: addr(rs1, imm),
+                size: access_size(instr),
+                src: 0,
+            },
+
+            (LR_W | LR_D, RVInstrInfo::A { rs1, rd, .. }) => TaintFlow::Load {
+                rd,
+                addr: regs[rs1 as usize],
+                size: access_size(instr),
+            },
+            (SC_W | SC_D, RVInstrInfo::A { rs1, rs2, rd, .. }) => TaintFlow::StoreConditional {
+                rd,
+                addr: regs[rs1 as usize],
+                size: access_size(instr),
+                src: rs2,
+            },
+            (
+                AMOSWAP_W | AMOADD_W | AMOAND_W | AMOOR_W | AMOXOR_W | AMOMAX_W | AMOMAXU_W
+                | AMOMIN_W | AMOMINU_W | AMOSWAP_D | AMOADD_D | AMOAND_D | AMOOR_D | AMOXOR_D
+                | AMOMAX_D | AMOMAXU_D | AMOMIN_D | AMOMINU_D,
+                RVInstrInfo::A { rs1, rs2, rd, .. },
+            ) => TaintFlow::Amo {
+                rd,
+                addr: regs[rs1 as usize],
+                size: access_size(instr),
+                src: rs2,
+                swap: matches!(instr, AMOSWAP_W | AMOSWAP_D),
+            },
+
+            // Floating-point and vector values are not tracked.
+            (
+                FEQ_S | FLT_S | FLE_S | FCLASS_S | FMV_X_W | FEQ_D | FLT_D | FLE_D | FCLASS_D
+                | FMV_X_D,
+                RVInstrInfo::R { rd, .. },
+            )
+            | (
+                FCVT_W_S | FCVT_WU_S | FCVT_L_S | FCVT_LU_S | FCVT_W_D | FCVT_WU_D | FCVT_L_D
+                | FCVT_LU_D,
+                RVInstrInfo::R_rm { rd, .. },
+            )
+            | (VMV_X_S | VSETVL | VSETVLI | VSETIVLI, RVInstrInfo::V { rd, .. }) => clean(rd),
+
+            (
+                C_ADD | C_ADDW | C_SUB | C_SUBW | C_AND | C_OR | C_XOR,
+                RVInstrInfo::CR { rd_rs1, rs2 } | RVInstrInfo::CA { rd_rs1, rs2 },
+            ) => TaintFlow::Regs {
+                rd: rd_rs1,
+                srcs: [rd_rs1, rs2],
+            },
+            (C_MV, RVInstrInfo::CR { rd_rs1, rs2 }) => TaintFlow::Regs {
+                rd: rd_rs1,
+                srcs: [rs2, 0],
+            },
+            (C_ADDI4SPN, RVInstrInfo::CIW { rd, .. }) => TaintFlow::Regs { rd, srcs: [2, 0] },
+            (C_LI | C_LUI, RVInstrInfo::CI { rd_rs1, .. }) => clean(rd_rs1),
+            (C_JAL | C_JALR, _) => clean(1),
+
+            (C_LW | C_LD, RVInstrInfo::CL { rd, rs1, imm }) => TaintFlow::Load {
+                rd,
+                addr: addr(rs1, imm),
+                size: access_size(instr),
+            },
+            (C_LWSP | C_LDSP, RVInstrInfo::CI { rd_rs1, imm }) => TaintFlow::Load {
+                rd: rd_rs1,
+                addr: addr(2, imm),
+                size: access_size(instr),
+            },
+            (C_SW | C_SD | C_FSW | C_FSD, RVInstrInfo::CS { rs1, rs2, imm }) => TaintFlow::Store {
+                addr: addr(rs1, imm),
+                size: access_size(instr),
+                src: if matches!(instr, C_SW | C_SD) { rs2 } else { 0 },
+            },
+            (C_SWSP | C_SDSP | C_FSWSP | C_FSDSP, RVInstrInfo::CSS { rs2, imm }) => {
+                TaintFlow::Store {
+                    addr: addr(2, imm),
+                    size: access_size(instr),
+                    src: if matches!(instr, C_SWSP | C_SDSP) {
+                        rs2
+                    } else {
+                        0
+                    },
+                }
+            }
+
+            // The other instructions don't write integer registers or memory, or change a
+            // register with a constant (`c.addi`, `c.slli`...).
+            _ => TaintFlow::None,
+        }
+    }
+}
+
+fn access_size(instr: RiscvInstr) -> u8 {
+    use RiscvInstr::*;
+
+    match instr {
+        LB | LBU | SB => 1,
+        LH | LHU | SH => 2,
+        LD | SD | FSD | LR_D | SC_D | C_LD | C_LDSP | C_SD | C_SDSP | C_FSD | C_FSDSP => 8,
+        AMOSWAP_D | AMOADD_D | AMOAND_D | AMOOR_D | AMOXOR_D | AMOMAX_D | AMOMAXU_D | AMOMIN_D
+        | AMOMINU_D => 8,
+        _ => 4,
+    }
+}
+
+impl RVCPU {
+    /// Start tracking taint, or return the tracker if it runs already.
+    pub fn enable_taint(&mut self) -> &mut TaintTracker {
+        self.taint
+            .get_or_insert_with(|| Box::new(TaintTracker::new()))
+    }
+
+    /// Stop tracking taint and forget it.
+    pub fn disable_taint(&mut self) {
+        self.taint = None;
+    }
+
+    pub fn taint(&self) -> Option<&TaintTracker> {
+        self.taint.as_deref()
+    }
+
+    pub fn taint_mut(&mut self) -> Option<&mut TaintTracker> {
+        self.taint.as_deref_mut()
+    }
+
+    /// Apply `flow` of an instruction which executed without trapping.
+    pub(super) fn propagate_taint(&mut self, flow: TaintFlow) {
+        let Some(mut taint) = self.taint.take() else {
+            return;
+        };
+        match flow {
+            TaintFlow::None => {}
+            TaintFlow::Regs { rd, srcs } => {
+                let tainted = srcs.iter().any(|&src| taint.is_reg_tainted(src));
+                taint.set_reg(rd, tainted);
+            }
+            TaintFlow::Load { rd, addr, size } => {
+                let tainted = self
+                    .taint_paddr(addr, AccessType::Read)
+                    .is_some_and(|paddr| {
+                        taint.is_source(paddr, size as u64)
+                            || taint.memory.borrow().any_tainted(paddr, size as u64)
+                    });
+                taint.set_reg(rd, tainted);
+            }
+            TaintFlow::Store { addr, size, src } => {
+                if let Some(paddr) = self.taint_paddr(addr, AccessType::Write) {
+                    let tainted = taint.is_reg_tainted(src);
+                    taint.memory.borrow_mut().set(paddr, size as u64, tainted);
+                }
+            }
+            TaintFlow::Amo {
+                rd,
+                addr,
+                size,
+                src,
+                swap,
+            } => {
+                if let Some(paddr) = self.taint_paddr(addr, AccessType::Write) {
+                    let mut memory = taint.memory.borrow_mut();
+                    let old = memory.any_tainted(paddr, size as u64);
+                    let new = taint.is_reg_tainted(src) || (old && !swap);
+                    memory.set(paddr, size as u64, new);
+                    drop(memory);
+                    taint.set_reg(rd, old);
+                }
+            }
+            TaintFlow::StoreConditional {
+                rd,
+                addr,
+                size,
+                src,
+            } => {
+                // `rd` is 0 if the store happened.
+                if self.reg_file[rd as usize] == 0
+                    && let Some(paddr) = self.taint_paddr(addr, AccessType::Write)
+                {
+                    let tainted = taint.is_reg_tainted(src);
+                    taint.memory.borrow_mut().set(paddr, size as u64, tainted);
+                }
+                taint.set_reg(rd, false);
+            }
+        }
+        self.taint = Some(taint);
+    }
+
+    /// The physical address the instruction just accessed at `addr`.
+    fn taint_paddr(&mut self, addr: WordType, access: AccessType) -> Option<u64> {
+        self.memory
+            .debug_translate(addr, access, &mut self.csr)
+            .ok()
+    }
+}
+
+/// A region of a disk whose content is tainted when the device reads it to memory.
+#[derive(Debug, Clone)]
+pub(crate) struct DiskTaint {
+    /// Byte offsets in the disk.
+    region: Range<u64>,
+    memory: Rc<RefCell<ShadowMemory>>,
+}
+
+impl DiskTaint {
+    pub(crate) fn new(region: Range<u64>, memory: Rc<RefCell<ShadowMemory>>) -> Self {
+        Self { region, memory }
+    }
+
+    /// The device read `len` bytes from `offset` of the disk to the physical `paddr`.
+    pub(crate) fn on_read(&self, paddr: u64, offset: u64, len: u64) {
+        let mut memory = self.memory.borrow_mut();
+        for i in 0..len {
+            memory.set(paddr + i, 1, self.region.contains(&(offset + i)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        board::{
+            Board,
+            virt::{SerialDestination, VirtBoardBuilder},
+        },
+        device::config::UART_BASE,
+        isa::DebugTarget,
+        ram_config,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_shadow_memory() {
+        let mut memory = ShadowMemory::default();
+        memory.set(0x8000_0ffe, 4, true);
+        assert!(memory.is_tainted(0x8000_1001));
+        assert!(!memory.is_tainted(0x8000_1002));
+        assert!(memory.any_tainted(0x8000_0ff0, 16));
+        assert_eq!(memory.tainted_bytes(), 4);
+
+        memory.set(0x8000_0ffe, 2, false);
+        assert_eq!(memory.pages.len(), 1);
+        assert_eq!(memory.tainted_bytes(), 2);
+
+        let disk = DiskTaint::new(512..1024, Rc::new(RefCell::new(memory)));
+        disk.on_read(0x8000_1000, 0, 1024);
+        let memory = disk.memory.borrow();
+        assert!(!memory.is_tainted(0x8000_1001));
+        assert!(memory.is_tainted(0x8000_1200));
+        assert_eq!(memory.tainted_bytes(), 512);
+    }
+
+    #[test]
+    fn test_taint_propagation() {
+        let program: Vec<u8> = [
+            0x1000_02b7u32, // lui x5, 0x10000
+            0x0002_c303,    // lbu x6, 0(x5)
+            0x0000_0397,    // auipc x7, 0
+            0x0463_8023,    // sb x6, 64(x7)
+            0x0403_c403,    // lbu x8, 64(x7)
+            0x0004_04b3,    // add x9, x8, x0
+            0x0010_0413,    // addi x8, x0, 1
+        ]
+        .iter()
+        .flat_map(|instr| instr.to_le_bytes())
+        .collect();
+        let mut board = VirtBoardBuilder::new()
+            .serial(SerialDestination::Buffer)
+            .binary(program)
+            .build()
+            .unwrap();
+        board.push_uart_input(b"A");
+        board
+            .cpu
+            .enable_taint()
+            .add_mmio_source(UART_BASE..UART_BASE + 1);
+
+        for _ in 0..7 {
+            board.step().unwrap();
+        }
+        assert_eq!(board.cpu.read_reg(6), b'A' as WordType);
+        let taint = board.cpu.taint().unwrap();
+        let tainted: Vec<u8> = (0..32).filter(|&idx| taint.is_reg_tainted(idx)).collect();
+        assert_eq!(tainted, [6, 9]);
+
+        let buffer = ram_config::BASE_ADDR + 8 + 64;
+        assert!(taint.memory().borrow().is_tainted(buffer));
+        assert_eq!(taint.memory().borrow().tainted_bytes(), 1);
+    }
+}
@@ -0,0 +1,334 @@
+//! Self-checking torture programs, after riscv-torture: the random sequences of
+//! [`random_test`](super::random_test) wrapped into a bare-metal image which checks its own
+//! results, run on a whole [`VirtBoard`].
+//!
+//! The image loads the initial registers from a table, runs the sequence, stores the registers
+//! next to the scratch area and compares them, and the scratch area, with the values the
+//! reference model computed when the program was generated. It reports through the
+//! `CHECKPOINT` register of the hypercall window, as the `tohost` of riscv-tests: `1` when every
+//! check passed, `check << 1 | 1` for the first one which failed, where checks `1` to `30` are
+//! `x1` to `x30` and the following ones the words of the scratch area.
+//!
+//! With `cosim`, [`run`] also steps the model in lockstep with the board through the sequence,
+//! so a divergence is reported at the instruction which caused it rather than at the end.
+
+use crate::{
+    board::{
+        Board,
+        virt::{SerialDestination, VirtBoard, VirtBoardBuilder},
+    },
+    config::arch_config::{REGFILE_CNT, WordType, XLEN},
+    device::config::HYPERCALL_BASE,
+    isa::riscv::{
+        random_test::{
+            DATA_BASE, DATA_REG, DATA_SIZE, Model, RandomInstr, RandomProgram, RandomTestError,
+        },
+        trap::Trap,
+    },
+    ram_config::BASE_ADDR,
+};
+
+/// Bytes per register.
+const WORD: usize = XLEN / 8;
+/// The registers are stored after the scratch area to be checked.
+const DUMP_BASE: WordType = DATA_BASE + DATA_SIZE as WordType;
+/// The scratch area is checked by words, after the registers.
+const FIRST_DATA_CHECK: u32 = REGFILE_CNT as u32;
+const CHECKPOINT: i32 = 0x04;
+
+const PASS: u32 = 1;
+
+const T0: u8 = 1;
+const T1: u8 = 2;
+const CHECK: u8 = 3;
+/// Points to the expected values during the checks.
+const EXPECTED_REG: u8 = 30;
+
+/// A [`RandomProgram`] with the results the reference model computed for it.
+#[derive(Clone, Debug)]
+pub struct TortureProgram {
+    pub program: RandomProgram,
+    expected_regs: [WordType; REGFILE_CNT],
+    expected_data: Vec<u8>,
+}
+
+impl TortureProgram {
+    /// Generates `len` instructions from `seed`, see [`RandomProgram::new`].
+    pub fn new(seed: u64, len: usize) -> Self {
+        let program = RandomProgram::new(seed, len);
+        let mut model = Model::new(&program, body_base());
+        while let Some(instr) = program.instrs.get(model.next) {
+            model.step(instr);
+        }
+        Self {
+            program,
+            expected_regs: model.regs,
+            expected_data: model.data,
+        }
+    }
+
+    /// The image to load at the base of RAM: the code, then the initial registers, the expected
+    /// registers and the expected scratch area.
+    pub fn image(&self) -> Vec<u8> {
+        let load = if XLEN == 64 { "ld" } else { "lw" };
+        let store = if XLEN == 64 { "sd" } else { "sw" };
+        let word = |idx: usize| (idx * WORD) as i32;
+
+        let mut code = Vec::new();
+
+        // Prologue: `x31` points to the table until it gets its own value, which is the last.
+        code.extend([0, 0]);
+        for reg in 1..REGFILE_CNT as u8 {
+            code.push(RandomInstr::new(load, reg, DATA_REG, 0, word(reg as usize)).encode());
+        }
+        debug_assert_eq!(code.len(), PROLOGUE_LEN);
+
+        code.extend(self.program.encode());
+
+        for reg in 1..DATA_REG {
+            let offset = (DUMP_BASE - DATA_BASE) as i32 + word(reg as usize);
+            code.push(RandomInstr::new(store, 0, DATA_REG, reg, offset).encode());
+        }
+        let expected_idx = code.len();
+        code.extend([0, 0]);
+        let mut check_branches = Vec::new();
+        let mut check = |code: &mut Vec<u32>, idx: u32, expected: i32, actual: i32| {
+            code.push(RandomInstr::new(load, T0, EXPECTED_REG, 0, expected).encode());
+            code.push(RandomInstr::new(load, T1, DATA_REG, 0, actual).encode());
+            code.push(RandomInstr::new("addi", CHECK, 0, 0, idx as i32).encode());
+            check_branches.push(code.len());
+            code.push(0);
+        };
+        for reg in 1..DATA_REG {
+            let dump = (DUMP_BASE - DATA_BASE) as i32 + word(reg as usize);
+            check(&mut code, reg as u32, word(reg as usize), dump);
+        }
+        for idx in 0..DATA_SIZE / WORD {
+            let expected = word(REGFILE_CNT + idx);
+            check(
+                &mut code,
+                FIRST_DATA_CHECK + idx as u32,
+                expected,
+                word(idx),
+            );
+        }
+        code.push(RandomInstr::new("addi", CHECK, 0, 0, 0).encode());
+        let report_idx = code.len();
+        code.extend([
+            RandomInstr::new("lui", T0, 0, 0, (HYPERCALL_BASE >> 12) as i32).encode(),
+            RandomInstr::new("slli", CHECK, CHECK, 0, 1).encode(),
+            RandomInstr::new("ori", CHECK, CHECK, 0, 1).encode(),
+            RandomInstr::new("sw", 0, T0, CHECK, CHECKPOINT).encode(),
+            0x0000_006f, // j .
+        ]);
+        for idx in check_branches {
+            let offset = 4 * (report_idx - idx) as i32;
+            code[idx] = RandomInstr::new("bne", 0, T0, T1, offset).encode();
+        }
+        if code.len() % (WORD / 4) != 0 {
+            code.push(RandomInstr::new("addi", 0, 0, 0, 0).encode());
+        }
+
+        let table = BASE_ADDR + 4 * code.len() as WordType;
+        let pc_relative = |code: &mut Vec<u32>, idx: usize, reg: u8, target: WordType| {
+            let pc = BASE_ADDR + 4 * idx as WordType;
+            let offset = target.wrapping_sub(pc) as i32;
+            let hi = offset.wrapping_add(0x800) >> 12;
+            code[idx] = RandomInstr::new("auipc", reg, 0, 0, hi & 0xf_ffff).encode();
+            code[idx + 1] = RandomInstr::new("addi", reg, reg, 0, offset - (hi << 12)).encode();
+        };
+        pc_relative(&mut code, 0, DATA_REG, table);
+        let expected = table + (REGFILE_CNT * WORD) as WordType;
+        pc_relative(&mut code, expected_idx, EXPECTED_REG, expected);
+
+        let mut image: Vec<u8> = code.iter().flat_map(|raw| raw.to_le_bytes()).collect();
+        let mut regs = self.program.regs;
+        regs[DATA_REG as usize] = DATA_BASE;
+        for value in regs.iter().chain(&self.expected_regs) {
+            image.extend_from_slice(&value.to_le_bytes()[..WORD]);
+        }
+        image.extend_from_slice(&self.expected_data);
+        image
+    }
+
+    /// The generated sequence, one instruction per line, at the address it runs from.
+    pub fn listing(&self) -> String {
+        self.program
+            .instrs
+            .iter()
+            .enumerate()
+            .map(|(idx, instr)| format!("{:#x}: {instr:?}\n", body_base() + 4 * idx as WordType))
+            .collect()
+    }
+}
+
+/// Instructions before the sequence: the address of the table, then a load per register.
+const PROLOGUE_LEN: usize = 2 + REGFILE_CNT - 1;
+
+fn body_base() -> WordType {
+    BASE_ADDR + 4 * PROLOGUE_LEN as WordType
+}
+
+/// How a torture program failed, each with the seed reproducing it.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TortureError {
+    #[error("seed {seed}: self-check of {what} failed, it is {actual:#x}, expected {expected:#x}")]
+    Check {
+        seed: u64,
+        what: String,
+        expected: WordType,
+        actual: WordType,
+    },
+    #[error("seed {seed}: the guest reported {value:#x}, which is no check")]
+    UnknownCheck { seed: u64, value: u32 },
+    #[error("seed {seed}: took a trap, {cause:?} at pc = {pc:#x}")]
+    Trap {
+        seed: u64,
+        cause: Trap,
+        pc: WordType,
+    },
+    #[error("seed {seed}: no result after {cycles} cycles, pc = {pc:#x}")]
+    Timeout {
+        seed: u64,
+        cycles: u64,
+        pc: WordType,
+    },
+    #[error("seed {seed}: {message}")]
+    Board { seed: u64, message: String },
+    #[error(transparent)]
+    Cosim(#[from] RandomTestError),
+}
+
+/// Run `program` on a fresh board until it reports, see the [module documentation](self).
+pub fn run(program: &TortureProgram, cosim: bool) -> Result<(), TortureError> {
+    let seed = program.program.seed;
+    let board_error = |message: String| TortureError::Board { seed, message };
+    let mut board = VirtBoardBuilder::new()
+        .serial(SerialDestination::Buffer)
+        .binary(program.image())
+        .build()
+        .map_err(board_error)?;
+
+    let instrs = &program.program.instrs;
+    let body = body_base()..body_base() + 4 * instrs.len() as WordType;
+    // Every instruction runs at most once, the checks and the report take a few more.
+    let max_cycles = 4 * (PROLOGUE_LEN + instrs.len() + REGFILE_CNT + DATA_SIZE) as u64;
+    let mut model = cosim.then(|| Model::new(&program.program, body.start));
+    let mut step = 0;
+    let value = loop {
+        if let Some(checkpoint) = board.guest_checkpoints().first() {
+            break checkpoint.value;
+        }
+        if board.clock.now() >= max_cycles {
+            return Err(TortureError::Timeout {
+                seed,
+                cycles: max_cycles,
+                pc: board.cpu.pc,
+            });
+        }
+
+        let pc = board.cpu.pc;
+        board
+            .step()
+            .map_err(|exception| board_error(format!("{exception:?} at pc = {pc:#x}")))?;
+        if let Some(trap) = board.cpu.recent_traps().next() {
+            return Err(TortureError::Trap {
+                seed,
+                cause: trap.cause,
+                pc: trap.pc,
+            });
+        }
+        if let Some(model) = model.as_mut()
+            && body.contains(&pc)
+            && let Some(instr) = instrs.get(model.next)
+        {
+            model.step(instr);
+            model.compare(&board.cpu, seed, step, instr)?;
+            step += 1;
+        }
+    };
+
+    if value == PASS {
+        return Ok(());
+    }
+    let check = value >> 1;
+    let data_checks = FIRST_DATA_CHECK..FIRST_DATA_CHECK + (DATA_SIZE / WORD) as u32;
+    let (what, expected, addr) = if value & 1 == 0 {
+        return Err(TortureError::UnknownCheck { seed, value });
+    } else if (1..DATA_REG as u32).contains(&check) {
+        (
+            format!("x{check}"),
+            program.expected_regs[check as usize],
+            DUMP_BASE + (check as usize * WORD) as WordType,
+        )
+    } else if data_checks.contains(&check) {
+        let offset = (check - FIRST_DATA_CHECK) as usize * WORD;
+        let addr = DATA_BASE + offset as WordType;
+        let mut raw = [0; 8];
+        raw[..WORD].copy_from_slice(&program.expected_data[offset..offset + WORD]);
+        (
+            format!("the word at {addr:#x}"),
+            u64::from_le_bytes(raw) as WordType,
+            addr,
+        )
+    } else {
+        return Err(TortureError::UnknownCheck { seed, value });
+    };
+    Err(TortureError::Check {
+        seed,
+        what,
+        expected,
+        actual: read_word(&mut board, addr),
+    })
+}
+
+fn read_word(board: &mut VirtBoard, addr: WordType) -> WordType {
+    board
+        .cpu
+        .memory
+        .read_by_paddr::<WordType>(addr)
+        .expect("the scratch area is RAM")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_torture() {
+        for seed in 0..4 {
+            let program = TortureProgram::new(seed, 128);
+            for cosim in [false, true] {
+                if let Err(e) = run(&program, cosim) {
+                    panic!("{e}\n{}", program.listing());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_self_check_fails() {
+        let mut program = TortureProgram::new(7, 64);
+        program.expected_regs[5] ^= 1;
+        let actual = program.expected_regs[5] ^ 1;
+        assert_eq!(
+            run(&program, false),
+            Err(TortureError::Check {
+                seed: 7,
+                what: "x5".to_string(),
+                expected: program.expected_regs[5],
+                actual,
+            })
+        );
+
+        let mut program = TortureProgram::new(7, 64);
+        program.expected_data[3 * WORD] ^= 0x80;
+        let Err(TortureError::Check { what, .. }) = run(&program, false) else {
+            panic!("the scratch area is checked");
+        };
+        assert_eq!(
+            what,
+            format!("the word at {:#x}", DATA_BASE + 3 * WORD as WordType)
+        );
+    }
+}
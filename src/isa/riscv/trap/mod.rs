@@ -5,6 +5,7 @@ use crate::{
     device::MemError,
 };
 pub mod trap_controller;
+pub mod trap_log;
 
 /// Trap Cause
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -118,15 +119,9 @@ impl Exception {
             MemError::StoreFault => Exception::StoreFault,
             MemError::LoadPageFault => Exception::LoadPageFault,
             MemError::StorePageFault => Exception::StorePageFault,
-        }
-    }
-
-    pub fn from_instr_fetch_err(err: MemError) -> Self {
-        match err {
-            MemError::LoadMisaligned => Exception::InstructionMisaligned,
-            MemError::LoadFault => Exception::InstructionFault,
-            MemError::LoadPageFault => Exception::InstructionPageFault,
-            _ => unreachable!("Invalid instruction fetch error: {:?}", err),
+            MemError::ExecMisaligned(_) => Exception::InstructionMisaligned,
+            MemError::ExecFault(_) => Exception::InstructionFault,
+            MemError::ExecPageFault(_) => Exception::InstructionPageFault,
         }
     }
 }
@@ -5,11 +5,21 @@ use crate::{
         riscv::{
             csr_reg::{NamedCsrReg, PrivilegeLevel, csr_index, csr_macro::*},
             executor::RVCPU,
-            trap::{Exception, Interrupt, Trap},
+            trap::{Exception, Interrupt, Trap, trap_log::TrapRecord},
         },
     },
 };
 
+/// Interrupts in decreasing priority for the same destination mode.
+const INTERRUPT_PRIORITY: [Interrupt; 6] = [
+    Interrupt::MachineExternal,
+    Interrupt::MachineSoft,
+    Interrupt::MachineTimer,
+    Interrupt::SupervisorExternal,
+    Interrupt::SupervisorSoft,
+    Interrupt::SupervisorTimer,
+];
+
 // TODO: Remove static class `TrapController` with sepreate modules for each privilege level.
 pub(in crate::isa::riscv) struct TrapController {}
 
@@ -51,9 +61,10 @@ impl TrapController {
             cpu.debug_info.last_instr.trap = true;
         }
 
+        let from = cpu.csr.privelege_level();
         cpu.csr
             .get_by_type_existing::<Mstatus>()
-            .set_mpp(cpu.csr.privelege_level() as u8 as WordType);
+            .set_mpp(from as u8 as WordType);
         cpu.csr.set_current_privileged(PrivilegeLevel::M);
         cpu.csr
             .write_uncheck_privilege(Mcause::get_index(), cause.into());
@@ -61,6 +72,7 @@ impl TrapController {
 
         let tval = cpu.pending_tval.take().unwrap_or(trap_value);
         cpu.csr.write_uncheck_privilege(csr_index::mtval, tval);
+        Self::log_trap(cpu, cause, tval, from, PrivilegeLevel::M);
 
         let mstatus = cpu.csr.get_by_type_existing::<Mstatus>();
         mstatus.set_mpie(mstatus.get_mie());
@@ -101,9 +113,10 @@ impl TrapController {
             cpu.debug_info.last_instr.trap = true;
         }
 
+        let from = cpu.csr.privelege_level();
         cpu.csr
             .get_by_type_existing::<Sstatus>()
-            .set_spp(cpu.csr.privelege_level() as u8 as WordType);
+            .set_spp(from as u8 as WordType);
         cpu.csr.set_current_privileged(PrivilegeLevel::S);
 
         assert!(cpu.csr.write_directly(Scause::get_index(), cause.into()));
@@ -111,6 +124,7 @@ impl TrapController {
 
         let tval = cpu.pending_tval.take().unwrap_or(trap_value);
         assert!(cpu.csr.write_directly(Stval::get_index(), tval));
+        Self::log_trap(cpu, cause, tval, from, PrivilegeLevel::S);
 
         let sstatus = cpu.csr.get_by_type_existing::<Sstatus>();
         sstatus.set_spie(sstatus.get_sie());
@@ -150,6 +164,14 @@ impl TrapController {
     pub fn try_send_trap_signal(cpu: &mut RVCPU, cause: Trap, trap_value: WordType) -> bool {
         let level = cpu.csr.privelege_level();
 
+        // Interrupts delegated to S-mode are never taken while running in M-mode.
+        if let Trap::Interrupt(_) = cause
+            && level == PrivilegeLevel::M
+            && Self::is_delegated_m_mode(cpu, cause)
+        {
+            return false;
+        }
+
         if level == PrivilegeLevel::M || Self::is_delegated_m_mode(cpu, cause) == false {
             match cause {
                 Trap::Exception(_) => {
@@ -190,25 +212,56 @@ impl TrapController {
         }
     }
 
-    /// Check if there is any pending interrupt that can be handled now, considering `mip` and `mie`.
+    /// The interrupt to take now, if any.
+    ///
+    /// "Multiple simultaneous interrupts destined for different privilege modes are handled in
+    /// decreasing order of destined privilege mode. Multiple simultaneous interrupts destined
+    /// for the same privilege mode are handled in the following decreasing priority order:
+    /// MEI, MSI, MTI, SEI, SSI, STI." Interrupts whose destination is globally disabled are
+    /// skipped, so they can not hide an enabled one of lower priority.
     pub fn has_interrupt(cpu: &mut RVCPU) -> Option<Interrupt> {
-        let mip = cpu.csr.get_by_type_existing::<Mip>();
-        let mie = cpu.csr.get_by_type_existing::<Mie>();
-
-        let pending_interrupts = mip.data() & mie.data();
-
-        if pending_interrupts == 0 {
+        let pending = cpu.csr.get_by_type_existing::<Mip>().data()
+            & cpu.csr.get_by_type_existing::<Mie>().data();
+        if pending == 0 {
             return None;
         }
 
-        for i in 0..=15 {
-            if (pending_interrupts & (1 << i)) != 0 {
-                let interrupt = Interrupt::from(i as usize);
-                return Some(interrupt);
-            }
-        }
+        let delegated = cpu.csr.get_by_type_existing::<Mideleg>().data();
+        let mstatus = cpu.csr.get_by_type_existing::<Mstatus>();
+        let level = cpu.csr.privelege_level();
+
+        // "Interrupts for higher-privilege modes, y>x, are always globally enabled regardless of
+        // the setting of the global yIE bit for the higher-privilege mode. Interrupts for
+        // lower-privilege modes, w<x, are always globally disabled."
+        let m_enabled = level < PrivilegeLevel::M || mstatus.get_mie() == 1;
+        let s_enabled =
+            level < PrivilegeLevel::S || (level == PrivilegeLevel::S && mstatus.get_sie() == 1);
+
+        let m_pending = if m_enabled { pending & !delegated } else { 0 };
+        let s_pending = if s_enabled { pending & delegated } else { 0 };
 
-        unreachable!()
+        [m_pending, s_pending].into_iter().find_map(|pending| {
+            INTERRUPT_PRIORITY.into_iter().find(|&interrupt| {
+                let code: WordType = interrupt.into();
+                pending & (1 << code) != 0
+            })
+        })
+    }
+
+    fn log_trap(
+        cpu: &mut RVCPU,
+        cause: Trap,
+        tval: WordType,
+        from: PrivilegeLevel,
+        to: PrivilegeLevel,
+    ) {
+        cpu.trap_log.push(TrapRecord {
+            cause,
+            pc: cpu.pc,
+            tval,
+            from,
+            to,
+        });
     }
 
     /// Get the next pc value according to the trap vector (like `mtvec` or `stvec`).
@@ -237,11 +290,50 @@ impl TrapController {
 mod test {
     use super::*;
     use crate::{
-        isa::riscv::{cpu_tester::run_test_cpu_step, trap::Exception},
+        isa::riscv::{
+            cpu_tester::{CPUChecker, TestCPUBuilder, run_test_cpu_step},
+            trap::Exception,
+        },
         ram_config::{self, BASE_ADDR},
     };
 
     const IRQ_HANDLER_ADDR: WordType = 0x80002000;
+    const S_HANDLER_ADDR: WordType = 0x80001000;
+
+    const ECALL: u32 = 0x00000073;
+    const SRET: u32 = 0x10200073;
+    const MRET: u32 = 0x30200073;
+    const NOP: u32 = 0x00000013;
+    const WFI: u32 = 0x10500073;
+    const CSRSI_MSTATUS_MIE: u32 = 0x30046073; // csrsi mstatus, 8
+    const CSRSI_SSTATUS_SIE: u32 = 0x10016073; // csrsi sstatus, 2
+
+    const MSTATUS_SIE: WordType = 1 << 1;
+    const MSTATUS_MIE: WordType = 1 << 3;
+    const MSTATUS_SPIE: WordType = 1 << 5;
+    const MSTATUS_MPIE: WordType = 1 << 7;
+    const MSTATUS_SPP: WordType = 1 << 8;
+    const MSTATUS_MPP_S: WordType = 1 << 11;
+    const MSTATUS_MPP_M: WordType = 3 << 11;
+    const MSTATUS_MPRV: WordType = 1 << 17;
+    const MSTATUS_TVM: WordType = 1 << 20;
+    const MSTATUS_TW: WordType = 1 << 21;
+    const MSTATUS_TSR: WordType = 1 << 22;
+
+    fn write_code(cpu: &mut RVCPU, base: WordType, code: &[u32]) {
+        for (i, instr) in code.iter().enumerate() {
+            let addr = base + (i * size_of::<u32>()) as WordType;
+            cpu.memory.write(addr, *instr, &mut cpu.csr).unwrap();
+        }
+    }
+
+    fn set_mip(cpu: &mut RVCPU, mip: WordType) {
+        assert!(cpu.csr.write_directly(Mip::get_index(), mip));
+    }
+
+    fn mstatus_of(checker: &mut CPUChecker) -> WordType {
+        checker.cpu.csr.get_by_type_existing::<Mstatus>().data()
+    }
 
     #[test]
     fn test_load_fault() {
@@ -256,7 +348,10 @@ mod test {
                     .customized(|checker| {
                         let mcause = checker.cpu.csr.get_by_type_existing::<Mcause>();
                         assert_eq!(mcause.get_interrupt_flag(), 0);
-                        assert_eq!(mcause.get_cause(), Exception::LoadFault.into());
+                        assert_eq!(
+                            mcause.get_cause(),
+                            Into::<WordType>::into(Exception::LoadFault)
+                        );
                         checker
                     })
             },
@@ -282,7 +377,10 @@ mod test {
                     .customized(|checker| {
                         let mcause = checker.cpu.csr.get_by_type_existing::<Mcause>();
                         assert_eq!(mcause.get_interrupt_flag(), 0);
-                        assert_eq!(mcause.get_cause(), Exception::LoadMisaligned.into());
+                        assert_eq!(
+                            mcause.get_cause(),
+                            Into::<WordType>::into(Exception::LoadMisaligned)
+                        );
                         checker
                     })
             },
@@ -301,7 +399,10 @@ mod test {
                     .customized(|checker| {
                         let mcause = checker.cpu.csr.get_by_type_existing::<Mcause>();
                         assert_eq!(mcause.get_interrupt_flag(), 0);
-                        assert_eq!(mcause.get_cause(), Exception::StoreFault.into());
+                        assert_eq!(
+                            mcause.get_cause(),
+                            Into::<WordType>::into(Exception::StoreFault)
+                        );
                         checker
                     })
             },
@@ -326,7 +427,10 @@ mod test {
                     .customized(|checker| {
                         let mcause = checker.cpu.csr.get_by_type_existing::<Mcause>();
                         assert_eq!(mcause.get_interrupt_flag(), 0);
-                        assert_eq!(mcause.get_cause(), Exception::StoreMisaligned.into());
+                        assert_eq!(
+                            mcause.get_cause(),
+                            Into::<WordType>::into(Exception::StoreMisaligned)
+                        );
                         checker
                     })
             },
@@ -350,7 +454,10 @@ mod test {
                     .customized(|checker| {
                         let mcause = checker.cpu.csr.get_by_type_existing::<Mcause>();
                         assert_eq!(mcause.get_interrupt_flag(), 0);
-                        assert_eq!(mcause.get_cause(), Exception::IllegalInstruction.into());
+                        assert_eq!(
+                            mcause.get_cause(),
+                            Into::<WordType>::into(Exception::IllegalInstruction)
+                        );
                         checker
                     })
             },
@@ -374,7 +481,10 @@ mod test {
                     .customized(|checker| {
                         let mcause = checker.cpu.csr.get_by_type_existing::<Mcause>();
                         assert_eq!(mcause.get_interrupt_flag(), 0);
-                        assert_eq!(mcause.get_cause(), Exception::InstructionFault.into());
+                        assert_eq!(
+                            mcause.get_cause(),
+                            Into::<WordType>::into(Exception::InstructionFault)
+                        );
                         checker
                     })
             },
@@ -398,7 +508,10 @@ mod test {
                     .customized(|checker| {
                         let mcause = checker.cpu.csr.get_by_type_existing::<Mcause>();
                         assert_eq!(mcause.get_interrupt_flag(), 0);
-                        assert_eq!(mcause.get_cause(), Exception::InstructionMisaligned.into());
+                        assert_eq!(
+                            mcause.get_cause(),
+                            Into::<WordType>::into(Exception::InstructionMisaligned)
+                        );
                         checker
                     })
             },
@@ -444,4 +557,639 @@ mod test {
             },
         );
     }
+
+    #[test]
+    fn test_sret_to_user() {
+        run_test_cpu_step(
+            &[SRET],
+            |builder| {
+                builder
+                    .privilege(PrivilegeLevel::S)
+                    .csr(Mstatus::get_index(), MSTATUS_SPIE)
+                    .csr(Sepc::get_index(), S_HANDLER_ADDR)
+            },
+            |mut checker| {
+                let mstatus = mstatus_of(&mut checker);
+                assert_ne!(mstatus & MSTATUS_SIE, 0, "SIE restored from SPIE");
+                assert_ne!(mstatus & MSTATUS_SPIE, 0, "SPIE set to 1");
+                assert_eq!(mstatus & MSTATUS_SPP, 0, "SPP set to U");
+                checker.pc(S_HANDLER_ADDR).privilege(PrivilegeLevel::U)
+            },
+        );
+    }
+
+    #[test]
+    fn test_sret_to_supervisor() {
+        run_test_cpu_step(
+            &[SRET],
+            |builder| {
+                builder
+                    .privilege(PrivilegeLevel::S)
+                    .csr(Mstatus::get_index(), MSTATUS_SPP | MSTATUS_SIE)
+                    .csr(Sepc::get_index(), S_HANDLER_ADDR)
+            },
+            |mut checker| {
+                let mstatus = mstatus_of(&mut checker);
+                assert_eq!(mstatus & MSTATUS_SIE, 0, "SIE restored from SPIE");
+                assert_eq!(mstatus & MSTATUS_SPP, 0, "SPP set to U");
+                checker.pc(S_HANDLER_ADDR).privilege(PrivilegeLevel::S)
+            },
+        );
+    }
+
+    #[test]
+    fn test_sret_clears_mprv() {
+        run_test_cpu_step(
+            &[SRET],
+            |builder| {
+                builder
+                    .privilege(PrivilegeLevel::M)
+                    .csr(Mstatus::get_index(), MSTATUS_MPRV | MSTATUS_SPP)
+                    .csr(Sepc::get_index(), S_HANDLER_ADDR)
+            },
+            |mut checker| {
+                assert_eq!(mstatus_of(&mut checker) & MSTATUS_MPRV, 0);
+                checker.pc(S_HANDLER_ADDR).privilege(PrivilegeLevel::S)
+            },
+        );
+    }
+
+    #[test]
+    fn test_sret_tsr() {
+        // Trapped in S-mode when TSR is set.
+        run_test_cpu_step(
+            &[SRET],
+            |builder| {
+                builder
+                    .privilege(PrivilegeLevel::S)
+                    .csr(Mstatus::get_index(), MSTATUS_TSR)
+                    .csr(Mtvec::get_index(), IRQ_HANDLER_ADDR)
+                    .csr(Sepc::get_index(), S_HANDLER_ADDR)
+            },
+            |checker| {
+                checker
+                    .pc(IRQ_HANDLER_ADDR)
+                    .privilege(PrivilegeLevel::M)
+                    .csr(Mcause::get_index(), Exception::IllegalInstruction.into())
+                    .csr(Mepc::get_index(), BASE_ADDR)
+            },
+        );
+
+        // TSR does not affect M-mode.
+        run_test_cpu_step(
+            &[SRET],
+            |builder| {
+                builder
+                    .privilege(PrivilegeLevel::M)
+                    .csr(Mstatus::get_index(), MSTATUS_TSR)
+                    .csr(Sepc::get_index(), S_HANDLER_ADDR)
+            },
+            |checker| checker.pc(S_HANDLER_ADDR).privilege(PrivilegeLevel::U),
+        );
+    }
+
+    #[test]
+    fn test_wfi_tw() {
+        // Trapped in S-mode and U-mode when TW is set.
+        for privilege in [PrivilegeLevel::S, PrivilegeLevel::U] {
+            run_test_cpu_step(
+                &[WFI],
+                |builder| {
+                    builder
+                        .privilege(privilege)
+                        .csr(Mstatus::get_index(), MSTATUS_TW)
+                        .csr(Mtvec::get_index(), IRQ_HANDLER_ADDR)
+                },
+                |checker| {
+                    checker
+                        .pc(IRQ_HANDLER_ADDR)
+                        .privilege(PrivilegeLevel::M)
+                        .csr(Mcause::get_index(), Exception::IllegalInstruction.into())
+                        .csr(Mepc::get_index(), BASE_ADDR)
+                },
+            );
+        }
+
+        // Unless an interrupt is pending, then it does not wait, even if the interrupt is masked.
+        const SSIP: WordType = 1 << 1;
+        run_test_cpu_step(
+            &[WFI],
+            |builder| {
+                builder
+                    .privilege(PrivilegeLevel::S)
+                    .csr(Mstatus::get_index(), MSTATUS_TW)
+                    .csr(Mideleg::get_index(), SSIP)
+                    .csr(Mie::get_index(), SSIP)
+                    .csr(Mip::get_index(), SSIP)
+            },
+            |checker| checker.pc(BASE_ADDR + 4).privilege(PrivilegeLevel::S),
+        );
+
+        // TW does not affect M-mode, and WFI is a nop without it.
+        run_test_cpu_step(
+            &[WFI],
+            |builder| builder.csr(Mstatus::get_index(), MSTATUS_TW),
+            |checker| checker.pc(BASE_ADDR + 4).privilege(PrivilegeLevel::M),
+        );
+        run_test_cpu_step(
+            &[WFI],
+            |builder| builder.privilege(PrivilegeLevel::U),
+            |checker| checker.pc(BASE_ADDR + 4).privilege(PrivilegeLevel::U),
+        );
+    }
+
+    #[test]
+    fn test_satp_tvm() {
+        const CSRR_SATP: u32 = 0x180022f3; // csrr t0, satp
+        const CSRW_SATP: u32 = 0x18029073; // csrw satp, t0
+        const SFENCE_VMA: u32 = 0x12000073;
+
+        for instr in [CSRR_SATP, CSRW_SATP, SFENCE_VMA] {
+            // Trapped in S-mode when TVM is set.
+            run_test_cpu_step(
+                &[instr],
+                |builder| {
+                    builder
+                        .privilege(PrivilegeLevel::S)
+                        .csr(Mstatus::get_index(), MSTATUS_TVM)
+                        .csr(Mtvec::get_index(), IRQ_HANDLER_ADDR)
+                },
+                |checker| {
+                    checker
+                        .pc(IRQ_HANDLER_ADDR)
+                        .csr(Mcause::get_index(), Exception::IllegalInstruction.into())
+                },
+            );
+
+            // Allowed in S-mode without it, and in M-mode with it.
+            run_test_cpu_step(
+                &[instr],
+                |builder| builder.privilege(PrivilegeLevel::S),
+                |checker| checker.pc(BASE_ADDR + 4).privilege(PrivilegeLevel::S),
+            );
+            run_test_cpu_step(
+                &[instr],
+                |builder| builder.csr(Mstatus::get_index(), MSTATUS_TVM),
+                |checker| checker.pc(BASE_ADDR + 4),
+            );
+        }
+    }
+
+    #[test]
+    fn test_sret_in_user() {
+        run_test_cpu_step(
+            &[SRET],
+            |builder| {
+                builder
+                    .privilege(PrivilegeLevel::U)
+                    .csr(Mtvec::get_index(), IRQ_HANDLER_ADDR)
+            },
+            |checker| {
+                checker
+                    .pc(IRQ_HANDLER_ADDR)
+                    .csr(Mcause::get_index(), Exception::IllegalInstruction.into())
+            },
+        );
+    }
+
+    #[test]
+    fn test_mret_mprv() {
+        // Returning to a lower privilege clears MPRV.
+        run_test_cpu_step(
+            &[MRET],
+            |builder| {
+                builder
+                    .csr(Mstatus::get_index(), MSTATUS_MPRV | MSTATUS_MPP_S)
+                    .csr(Mepc::get_index(), S_HANDLER_ADDR)
+            },
+            |mut checker| {
+                let mstatus = mstatus_of(&mut checker);
+                assert_eq!(mstatus & MSTATUS_MPRV, 0);
+                assert_eq!(mstatus & MSTATUS_MPP_M, 0, "MPP set to U");
+                checker.pc(S_HANDLER_ADDR).privilege(PrivilegeLevel::S)
+            },
+        );
+
+        // Returning to M-mode keeps MPRV.
+        run_test_cpu_step(
+            &[MRET],
+            |builder| {
+                builder
+                    .csr(Mstatus::get_index(), MSTATUS_MPRV | MSTATUS_MPP_M)
+                    .csr(Mepc::get_index(), S_HANDLER_ADDR)
+            },
+            |mut checker| {
+                assert_ne!(mstatus_of(&mut checker) & MSTATUS_MPRV, 0);
+                checker.pc(S_HANDLER_ADDR).privilege(PrivilegeLevel::M)
+            },
+        );
+    }
+
+    #[test]
+    fn test_nested_s_in_m() {
+        // U: ecall (delegated to S)
+        // S: ecall (to M), then skip the ecall and sret
+        // M: skip the ecall and mret
+        let mut cpu = TestCPUBuilder::new()
+            .program(&[ECALL, NOP])
+            .csr(Medeleg::get_index(), 1 << Exception::UserEnvCall as u8)
+            .csr(Stvec::get_index(), S_HANDLER_ADDR)
+            .csr(Mtvec::get_index(), IRQ_HANDLER_ADDR)
+            .csr(Mstatus::get_index(), MSTATUS_SPIE)
+            .privilege(PrivilegeLevel::U)
+            .build();
+        let s_handler = [
+            ECALL, 0x141022f3, // csrr t0, sepc
+            0x00428293, // addi t0, t0, 4
+            0x14129073, // csrw sepc, t0
+            SRET,
+        ];
+        let m_handler = [
+            0x341022f3, // csrr t0, mepc
+            0x00428293, // addi t0, t0, 4
+            0x34129073, // csrw mepc, t0
+            MRET,
+        ];
+        write_code(&mut cpu, S_HANDLER_ADDR, &s_handler);
+        write_code(&mut cpu, IRQ_HANDLER_ADDR, &m_handler);
+
+        // U -> S
+        cpu.step().unwrap();
+        CPUChecker::new(&mut cpu)
+            .pc(S_HANDLER_ADDR)
+            .privilege(PrivilegeLevel::S)
+            .csr(Sepc::get_index(), BASE_ADDR)
+            .csr(Scause::get_index(), Exception::UserEnvCall.into())
+            .csr(Mcause::get_index(), 0);
+
+        // S -> M
+        cpu.step().unwrap();
+        let mut checker = CPUChecker::new(&mut cpu)
+            .pc(IRQ_HANDLER_ADDR)
+            .privilege(PrivilegeLevel::M)
+            .csr(Mepc::get_index(), S_HANDLER_ADDR)
+            .csr(Mcause::get_index(), Exception::SupervisorEnvCall.into());
+        assert_eq!(mstatus_of(&mut checker) & MSTATUS_MPP_M, MSTATUS_MPP_S);
+        let traps: Vec<_> = cpu.recent_traps().copied().collect();
+        assert_eq!(
+            traps,
+            [
+                TrapRecord {
+                    cause: Trap::Exception(Exception::SupervisorEnvCall),
+                    pc: S_HANDLER_ADDR,
+                    tval: 0,
+                    from: PrivilegeLevel::S,
+                    to: PrivilegeLevel::M,
+                },
+                TrapRecord {
+                    cause: Trap::Exception(Exception::UserEnvCall),
+                    pc: BASE_ADDR,
+                    tval: 0,
+                    from: PrivilegeLevel::U,
+                    to: PrivilegeLevel::S,
+                },
+            ]
+        );
+
+        // M -> S, the S-mode trap state survives the nested trap.
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        CPUChecker::new(&mut cpu)
+            .pc(S_HANDLER_ADDR + 4)
+            .privilege(PrivilegeLevel::S)
+            .csr(Sepc::get_index(), BASE_ADDR)
+            .csr(Scause::get_index(), Exception::UserEnvCall.into());
+
+        // S -> U
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        CPUChecker::new(&mut cpu)
+            .pc(BASE_ADDR + 4)
+            .privilege(PrivilegeLevel::U);
+    }
+
+    #[test]
+    fn test_delegated_interrupt_masked_in_m() {
+        const STIP: WordType = 1 << 5;
+        let build = |builder: TestCPUBuilder| {
+            builder
+                .program(&[NOP])
+                .csr(Mideleg::get_index(), STIP)
+                .csr(Mie::get_index(), STIP)
+                .csr(Mip::get_index(), STIP)
+                .csr(Mtvec::get_index(), IRQ_HANDLER_ADDR)
+                .csr(Stvec::get_index(), S_HANDLER_ADDR)
+        };
+
+        // Not taken in M-mode, even with MIE set.
+        run_test_cpu_step(
+            &[NOP],
+            |builder| build(builder).csr(Mstatus::get_index(), MSTATUS_MIE | MSTATUS_SIE),
+            |checker| checker.pc(BASE_ADDR + 4).privilege(PrivilegeLevel::M),
+        );
+
+        // Taken to S-mode from U-mode, regardless of SIE.
+        run_test_cpu_step(
+            &[NOP],
+            |builder| build(builder).privilege(PrivilegeLevel::U),
+            |checker| {
+                checker
+                    .pc(S_HANDLER_ADDR)
+                    .privilege(PrivilegeLevel::S)
+                    .csr(Sepc::get_index(), BASE_ADDR)
+            },
+        );
+    }
+
+    fn interrupt_bit(interrupt: Interrupt) -> WordType {
+        let code: WordType = interrupt.into();
+        1 << code
+    }
+
+    fn interrupt_cause(interrupt: Interrupt) -> WordType {
+        Trap::Interrupt(interrupt).into()
+    }
+
+    #[test]
+    fn test_interrupt_priority() {
+        // Take the highest one, then drop it and check the next.
+        for (i, &interrupt) in INTERRUPT_PRIORITY.iter().enumerate() {
+            let pending = INTERRUPT_PRIORITY[i..]
+                .iter()
+                .fold(0, |mask, &interrupt| mask | interrupt_bit(interrupt));
+            run_test_cpu_step(
+                &[NOP],
+                |builder| {
+                    builder
+                        .csr(Mie::get_index(), pending)
+                        .csr(Mip::get_index(), pending)
+                        .csr(Mtvec::get_index(), IRQ_HANDLER_ADDR)
+                        .privilege(PrivilegeLevel::U)
+                },
+                |checker| {
+                    checker
+                        .pc(IRQ_HANDLER_ADDR)
+                        .csr(Mcause::get_index(), interrupt_cause(interrupt))
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn test_interrupt_destination_before_priority() {
+        // STI stays in M-mode and SEI is delegated, the M-mode one goes first.
+        let sti = interrupt_bit(Interrupt::SupervisorTimer);
+        let sei = interrupt_bit(Interrupt::SupervisorExternal);
+        run_test_cpu_step(
+            &[NOP],
+            |builder| {
+                builder
+                    .csr(Mideleg::get_index(), sei)
+                    .csr(Mie::get_index(), sti | sei)
+                    .csr(Mip::get_index(), sti | sei)
+                    .csr(Mtvec::get_index(), IRQ_HANDLER_ADDR)
+                    .csr(Stvec::get_index(), S_HANDLER_ADDR)
+                    .privilege(PrivilegeLevel::U)
+            },
+            |checker| {
+                checker
+                    .pc(IRQ_HANDLER_ADDR)
+                    .privilege(PrivilegeLevel::M)
+                    .csr(
+                        Mcause::get_index(),
+                        interrupt_cause(Interrupt::SupervisorTimer),
+                    )
+            },
+        );
+    }
+
+    #[test]
+    fn test_disabled_interrupt_does_not_hide_enabled_one() {
+        // In S-mode with SIE clear the delegated SSI waits, the MTI below it is still taken.
+        let ssi = interrupt_bit(Interrupt::SupervisorSoft);
+        let mti = interrupt_bit(Interrupt::MachineTimer);
+        run_test_cpu_step(
+            &[NOP],
+            |builder| {
+                builder
+                    .csr(Mideleg::get_index(), ssi)
+                    .csr(Mie::get_index(), ssi | mti)
+                    .csr(Mip::get_index(), ssi | mti)
+                    .csr(Mtvec::get_index(), IRQ_HANDLER_ADDR)
+                    .csr(Stvec::get_index(), S_HANDLER_ADDR)
+                    .privilege(PrivilegeLevel::S)
+            },
+            |checker| {
+                checker
+                    .pc(IRQ_HANDLER_ADDR)
+                    .privilege(PrivilegeLevel::M)
+                    .csr(
+                        Mcause::get_index(),
+                        interrupt_cause(Interrupt::MachineTimer),
+                    )
+            },
+        );
+
+        // With SIE set the delegated SSI is taken in S-mode once MTI is gone.
+        run_test_cpu_step(
+            &[NOP],
+            |builder| {
+                builder
+                    .csr(Mideleg::get_index(), ssi)
+                    .csr(Mie::get_index(), ssi | mti)
+                    .csr(Mip::get_index(), ssi)
+                    .csr(Mstatus::get_index(), MSTATUS_SIE)
+                    .csr(Stvec::get_index(), S_HANDLER_ADDR)
+                    .privilege(PrivilegeLevel::S)
+            },
+            |checker| {
+                checker.pc(S_HANDLER_ADDR).privilege(PrivilegeLevel::S).csr(
+                    Scause::get_index(),
+                    interrupt_cause(Interrupt::SupervisorSoft),
+                )
+            },
+        );
+
+        // In M-mode with MIE clear nothing is taken.
+        run_test_cpu_step(
+            &[NOP],
+            |builder| {
+                builder
+                    .csr(Mideleg::get_index(), ssi)
+                    .csr(Mie::get_index(), ssi | mti)
+                    .csr(Mip::get_index(), ssi | mti)
+                    .csr(Mstatus::get_index(), MSTATUS_SIE)
+                    .csr(Mtvec::get_index(), IRQ_HANDLER_ADDR)
+            },
+            |checker| checker.pc(BASE_ADDR + 4).privilege(PrivilegeLevel::M),
+        );
+    }
+
+    #[test]
+    fn test_nested_m_interrupt() {
+        // The handler re-enables MIE, a second interrupt preempts it and returns into it.
+        let mti = interrupt_bit(Interrupt::MachineTimer);
+        let msi = interrupt_bit(Interrupt::MachineSoft);
+        let mut cpu = TestCPUBuilder::new()
+            .program(&[NOP])
+            .csr(Mie::get_index(), mti | msi)
+            .csr(Mip::get_index(), mti)
+            .csr(Mstatus::get_index(), MSTATUS_MIE | MSTATUS_MPP_M)
+            .csr(Mtvec::get_index(), IRQ_HANDLER_ADDR)
+            .build();
+        write_code(&mut cpu, IRQ_HANDLER_ADDR, &[CSRSI_MSTATUS_MIE, MRET]);
+
+        // Entry: MIE is pushed to MPIE and cleared.
+        cpu.step().unwrap();
+        let mut checker = CPUChecker::new(&mut cpu)
+            .pc(IRQ_HANDLER_ADDR)
+            .csr(Mepc::get_index(), BASE_ADDR)
+            .csr(
+                Mcause::get_index(),
+                interrupt_cause(Interrupt::MachineTimer),
+            );
+        let mstatus = mstatus_of(&mut checker);
+        assert_eq!(mstatus & (MSTATUS_MIE | MSTATUS_MPIE), MSTATUS_MPIE);
+
+        // MTI is still pending but masked until the handler sets MIE.
+        cpu.step().unwrap();
+        let mut checker = CPUChecker::new(&mut cpu).pc(IRQ_HANDLER_ADDR + 4);
+        assert_ne!(mstatus_of(&mut checker) & MSTATUS_MIE, 0);
+
+        // MSI preempts the handler.
+        set_mip(&mut cpu, msi);
+        cpu.step().unwrap();
+        let mut checker = CPUChecker::new(&mut cpu)
+            .pc(IRQ_HANDLER_ADDR)
+            .privilege(PrivilegeLevel::M)
+            .csr(Mepc::get_index(), IRQ_HANDLER_ADDR + 4)
+            .csr(Mcause::get_index(), interrupt_cause(Interrupt::MachineSoft));
+        let mstatus = mstatus_of(&mut checker);
+        assert_eq!(mstatus & (MSTATUS_MIE | MSTATUS_MPIE), MSTATUS_MPIE);
+        assert_eq!(mstatus & MSTATUS_MPP_M, MSTATUS_MPP_M);
+
+        // MRET pops MPIE back into MIE and resumes the first handler.
+        set_mip(&mut cpu, 0);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let mut checker = CPUChecker::new(&mut cpu)
+            .pc(IRQ_HANDLER_ADDR + 4)
+            .privilege(PrivilegeLevel::M);
+        let mstatus = mstatus_of(&mut checker);
+        assert_eq!(
+            mstatus & (MSTATUS_MIE | MSTATUS_MPIE),
+            MSTATUS_MIE | MSTATUS_MPIE
+        );
+    }
+
+    #[test]
+    fn test_nested_s_interrupt() {
+        let sti = interrupt_bit(Interrupt::SupervisorTimer);
+        let ssi = interrupt_bit(Interrupt::SupervisorSoft);
+        let mut cpu = TestCPUBuilder::new()
+            .program(&[NOP])
+            .csr(Mideleg::get_index(), sti | ssi)
+            .csr(Mie::get_index(), sti | ssi)
+            .csr(Mip::get_index(), sti)
+            .csr(Stvec::get_index(), S_HANDLER_ADDR)
+            .privilege(PrivilegeLevel::U)
+            .build();
+        write_code(&mut cpu, S_HANDLER_ADDR, &[CSRSI_SSTATUS_SIE, SRET]);
+
+        // U -> S, interrupts of S are enabled in U-mode whatever SIE says.
+        cpu.step().unwrap();
+        let mut checker = CPUChecker::new(&mut cpu)
+            .pc(S_HANDLER_ADDR)
+            .privilege(PrivilegeLevel::S)
+            .csr(Sepc::get_index(), BASE_ADDR)
+            .csr(
+                Scause::get_index(),
+                interrupt_cause(Interrupt::SupervisorTimer),
+            );
+        let mstatus = mstatus_of(&mut checker);
+        assert_eq!(mstatus & (MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP), 0);
+
+        // The handler sets SIE, then SSI preempts it.
+        set_mip(&mut cpu, sti | ssi);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let mut checker = CPUChecker::new(&mut cpu)
+            .pc(S_HANDLER_ADDR)
+            .privilege(PrivilegeLevel::S)
+            .csr(Sepc::get_index(), S_HANDLER_ADDR + 4)
+            .csr(
+                Scause::get_index(),
+                interrupt_cause(Interrupt::SupervisorSoft),
+            );
+        let mstatus = mstatus_of(&mut checker);
+        assert_eq!(
+            mstatus & (MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP),
+            MSTATUS_SPIE | MSTATUS_SPP
+        );
+
+        // SRET pops SPIE back into SIE and stays in S-mode.
+        set_mip(&mut cpu, 0);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let mut checker = CPUChecker::new(&mut cpu)
+            .pc(S_HANDLER_ADDR + 4)
+            .privilege(PrivilegeLevel::S);
+        let mstatus = mstatus_of(&mut checker);
+        assert_eq!(
+            mstatus & (MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP),
+            MSTATUS_SIE | MSTATUS_SPIE
+        );
+    }
+
+    #[test]
+    fn test_m_interrupt_in_s_handler() {
+        // An M-mode interrupt taken while the S-mode handler runs with SIE clear leaves the
+        // S-mode trap state alone.
+        let sti = interrupt_bit(Interrupt::SupervisorTimer);
+        let mti = interrupt_bit(Interrupt::MachineTimer);
+        let mut cpu = TestCPUBuilder::new()
+            .program(&[NOP])
+            .csr(Mideleg::get_index(), sti)
+            .csr(Mie::get_index(), sti | mti)
+            .csr(Mip::get_index(), sti)
+            .csr(Mstatus::get_index(), MSTATUS_SIE)
+            .csr(Mtvec::get_index(), IRQ_HANDLER_ADDR)
+            .csr(Stvec::get_index(), S_HANDLER_ADDR)
+            .privilege(PrivilegeLevel::U)
+            .build();
+        write_code(&mut cpu, S_HANDLER_ADDR, &[NOP, SRET]);
+        write_code(&mut cpu, IRQ_HANDLER_ADDR, &[MRET]);
+
+        cpu.step().unwrap();
+        set_mip(&mut cpu, mti);
+        cpu.step().unwrap();
+        let mut checker = CPUChecker::new(&mut cpu)
+            .pc(IRQ_HANDLER_ADDR)
+            .privilege(PrivilegeLevel::M)
+            .csr(Mepc::get_index(), S_HANDLER_ADDR)
+            .csr(Sepc::get_index(), BASE_ADDR)
+            .csr(
+                Scause::get_index(),
+                interrupt_cause(Interrupt::SupervisorTimer),
+            );
+        let mstatus = mstatus_of(&mut checker);
+        assert_eq!(mstatus & MSTATUS_MPP_M, MSTATUS_MPP_S);
+        assert_eq!(mstatus & (MSTATUS_SIE | MSTATUS_SPIE), MSTATUS_SPIE);
+
+        // Back to the S-mode handler with SIE still clear, its SRET returns to U-mode.
+        set_mip(&mut cpu, 0);
+        cpu.step().unwrap();
+        let mut checker = CPUChecker::new(&mut cpu)
+            .pc(S_HANDLER_ADDR)
+            .privilege(PrivilegeLevel::S);
+        assert_eq!(mstatus_of(&mut checker) & MSTATUS_SIE, 0);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let mut checker = CPUChecker::new(&mut cpu)
+            .pc(BASE_ADDR)
+            .privilege(PrivilegeLevel::U);
+        assert_ne!(mstatus_of(&mut checker) & MSTATUS_SIE, 0);
+    }
 }
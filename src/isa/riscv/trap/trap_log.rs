@@ -0,0 +1,75 @@
+//! The last traps taken by a hart, so the chain of traps which led to a crash can be inspected
+//! after the fact (`info exceptions` in rvdb, [`RVCPU::recent_traps`]).
+//!
+//! [`RVCPU::recent_traps`]: crate::isa::riscv::executor::RVCPU::recent_traps
+
+use std::collections::VecDeque;
+
+use crate::{
+    config::arch_config::WordType,
+    isa::riscv::{csr_reg::PrivilegeLevel, trap::Trap},
+};
+
+/// How many traps are kept, older ones are dropped.
+pub const TRAP_LOG_SIZE: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrapRecord {
+    pub cause: Trap,
+    /// The `xepc` of the trap.
+    pub pc: WordType,
+    pub tval: WordType,
+    /// The privilege level the trap was taken from.
+    pub from: PrivilegeLevel,
+    /// The privilege level handling the trap.
+    pub to: PrivilegeLevel,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct TrapLog(VecDeque<TrapRecord>);
+
+impl TrapLog {
+    pub(crate) fn new() -> Self {
+        Self(VecDeque::with_capacity(TRAP_LOG_SIZE))
+    }
+
+    pub(crate) fn push(&mut self, record: TrapRecord) {
+        if self.0.len() == TRAP_LOG_SIZE {
+            self.0.pop_front();
+        }
+        self.0.push_back(record);
+    }
+
+    /// The traps, most recent first.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &TrapRecord> {
+        self.0.iter().rev()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::riscv::trap::Exception;
+
+    fn record(pc: WordType) -> TrapRecord {
+        TrapRecord {
+            cause: Trap::Exception(Exception::Breakpoint),
+            pc,
+            tval: 0,
+            from: PrivilegeLevel::U,
+            to: PrivilegeLevel::M,
+        }
+    }
+
+    #[test]
+    fn test_keeps_the_most_recent() {
+        let mut log = TrapLog::new();
+        for pc in 0..(TRAP_LOG_SIZE as WordType + 3) {
+            log.push(record(pc));
+        }
+        let pcs: Vec<_> = log.iter().map(|record| record.pc).collect();
+        assert_eq!(pcs.len(), TRAP_LOG_SIZE);
+        assert_eq!(pcs[0], TRAP_LOG_SIZE as WordType + 2);
+        assert_eq!(pcs[TRAP_LOG_SIZE - 1], 3);
+    }
+}
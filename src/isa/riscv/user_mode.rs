@@ -0,0 +1,778 @@
+//! User-mode emulation, qemu-user style.
+//!
+//! A static Linux RISC-V executable is loaded into its own Sv39 address space and started
+//! in U-mode, with the initial stack (`argc`, `argv`, `envp`, `auxv`) synthesized by the emulator.
+//! There is no guest kernel: the CPU returns every exception to [`UserEmulator`],
+//! which serves `ECALL`s by forwarding the common Linux syscalls to the host.
+
+use std::{
+    cell::UnsafeCell,
+    collections::HashMap,
+    io::{Read, Write},
+    rc::Rc,
+};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use xmas_elf::program::{ProgramHeader, Type};
+
+use crate::{
+    config::arch_config::WordType,
+    device::mmio::MemoryMapIO,
+    isa::riscv::{
+        csr_reg::{NamedCsrReg, PrivilegeLevel, csr_macro::*},
+        executor::RVCPU,
+        mmu::{VirtAddrManager, config::PAGE_SIZE},
+        trap::Exception,
+    },
+    ram::Ram,
+    ram_config, vclock,
+};
+
+const STACK_TOP: WordType = 0x3f_ffff_f000;
+const STACK_SIZE: WordType = 8 << 20;
+const MMAP_BASE: WordType = 0x20_0000_0000;
+
+const PTE_V: WordType = 1 << 0;
+const PTE_R: WordType = 1 << 1;
+const PTE_W: WordType = 1 << 2;
+const PTE_X: WordType = 1 << 3;
+const PTE_U: WordType = 1 << 4;
+const PTE_A: WordType = 1 << 6;
+const PTE_D: WordType = 1 << 7;
+
+const SATP_MODE_SV39: WordType = 8;
+const EM_RISCV: u16 = 0xf3;
+
+#[rustfmt::skip]
+mod nr {
+    use crate::config::arch_config::WordType;
+    pub const IOCTL           : WordType = 29;
+    pub const READ            : WordType = 63;
+    pub const WRITE           : WordType = 64;
+    pub const WRITEV          : WordType = 66;
+    pub const EXIT            : WordType = 93;
+    pub const EXIT_GROUP      : WordType = 94;
+    pub const SET_TID_ADDRESS : WordType = 96;
+    pub const BRK             : WordType = 214;
+    pub const MUNMAP          : WordType = 215;
+    pub const MMAP            : WordType = 222;
+}
+
+#[rustfmt::skip]
+mod errno {
+    pub const EBADF   : i64 = 9;
+    pub const ENOMEM  : i64 = 12;
+    pub const EFAULT  : i64 = 14;
+    pub const ENODEV  : i64 = 19;
+    pub const EINVAL  : i64 = 22;
+    pub const ENOTTY  : i64 = 25;
+    pub const ENOSYS  : i64 = 38;
+}
+
+#[rustfmt::skip]
+mod auxv {
+    use crate::config::arch_config::WordType;
+    pub const AT_NULL   : WordType = 0;
+    pub const AT_PHDR   : WordType = 3;
+    pub const AT_PHENT  : WordType = 4;
+    pub const AT_PHNUM  : WordType = 5;
+    pub const AT_PAGESZ : WordType = 6;
+    pub const AT_ENTRY  : WordType = 9;
+    pub const AT_RANDOM : WordType = 25;
+}
+
+const MAP_FIXED: WordType = 0x10;
+const MAP_ANONYMOUS: WordType = 0x20;
+
+/// Linux's `UIO_MAXIOV`: the most iovecs one `writev` may take.
+const IOV_MAX: WordType = 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum UserModeError {
+    #[error("invalid ELF file: {0}")]
+    InvalidElf(&'static str),
+    #[error("ELF file is not a static RISC-V executable")]
+    NotStaticExecutable,
+    #[error("out of guest physical memory")]
+    OutOfMemory,
+    #[error("unhandled exception {exception:?} at pc = {pc:#x}")]
+    UnhandledException { exception: Exception, pc: WordType },
+}
+
+/// Runs a static Linux user program without a kernel.
+pub struct UserEmulator {
+    cpu: RVCPU,
+    ram: Rc<UnsafeCell<Ram>>,
+
+    /// Physical address of the root page table.
+    root_table: WordType,
+    /// Next free physical page, pages are never reclaimed.
+    next_frame: WordType,
+    /// Virtual page number -> physical page address.
+    pages: HashMap<WordType, WordType>,
+
+    brk_start: WordType,
+    brk: WordType,
+    mmap_next: WordType,
+
+    exit_code: Option<i32>,
+}
+
+impl UserEmulator {
+    /// Load the static executable `elf` and prepare the initial stack with `argv` and `envp`.
+    pub fn from_elf(elf: &[u8], argv: &[String], envp: &[String]) -> Result<Self, UserModeError> {
+        let file = xmas_elf::ElfFile::new(elf).map_err(UserModeError::InvalidElf)?;
+        if file.header.pt2.type_().as_type() != xmas_elf::header::Type::Executable
+            || file.header.pt2.machine().as_machine() != xmas_elf::header::Machine::Other(EM_RISCV)
+            || file
+                .program_iter()
+                .any(|ph| ph.get_type() == Ok(Type::Interp))
+        {
+            return Err(UserModeError::NotStaticExecutable);
+        }
+
+        let ram = Rc::new(UnsafeCell::new(Ram::new()));
+        let mmio = MemoryMapIO::from_mmio_items(ram.clone(), vec![]);
+        let mut cpu =
+            RVCPU::from_vaddr_manager(VirtAddrManager::from_ram_and_mmio(ram.clone(), mmio));
+        cpu.user_mode = true;
+
+        let mut emu = Self {
+            cpu,
+            ram,
+            root_table: 0,
+            next_frame: ram_config::BASE_ADDR,
+            pages: HashMap::new(),
+            brk_start: 0,
+            brk: 0,
+            mmap_next: MMAP_BASE,
+            exit_code: None,
+        };
+        emu.root_table = emu.alloc_frame()?;
+
+        // Load segments.
+        let mut image_end = 0;
+        let mut phdr_addr = 0;
+        for ph in file.program_iter() {
+            if ph.get_type() != Ok(Type::Load) {
+                continue;
+            }
+
+            let vaddr = ph.virtual_addr() as WordType;
+            let end = vaddr
+                .checked_add(ph.mem_size() as WordType)
+                .ok_or(UserModeError::InvalidElf("segment past the end of memory"))?;
+            emu.map_range(vaddr, end, segment_flags(&ph))?;
+
+            let data = ph
+                .offset()
+                .checked_add(ph.file_size())
+                .filter(|_| ph.file_size() <= ph.mem_size())
+                .and_then(|file_end| elf.get(ph.offset() as usize..file_end as usize))
+                .ok_or(UserModeError::InvalidElf(
+                    "segment past the end of the file",
+                ))?;
+            if !emu.write_bytes(vaddr, data) {
+                return Err(UserModeError::InvalidElf("segment not mapped"));
+            }
+
+            let phoff = file.header.pt2.ph_offset();
+            if (ph.offset()..ph.offset() + ph.file_size()).contains(&phoff) {
+                phdr_addr = vaddr + (phoff - ph.offset()) as WordType;
+            }
+            image_end = image_end.max(end);
+        }
+
+        emu.brk_start = page_align_up(image_end);
+        emu.brk = emu.brk_start;
+
+        emu.map_range(STACK_TOP - STACK_SIZE, STACK_TOP, PTE_R | PTE_W)?;
+        let auxv = [
+            (auxv::AT_PHDR, phdr_addr),
+            (auxv::AT_PHENT, file.header.pt2.ph_entry_size() as WordType),
+            (auxv::AT_PHNUM, file.header.pt2.ph_count() as WordType),
+            (auxv::AT_PAGESZ, PAGE_SIZE),
+            (auxv::AT_ENTRY, file.header.pt2.entry_point() as WordType),
+        ];
+        let sp = emu.setup_stack(argv, envp, &auxv);
+
+        // Enter U-mode with the new address space.
+        let cpu = &mut emu.cpu;
+        cpu.write_csr(
+            Satp::get_index(),
+            (SATP_MODE_SV39 << 60) | (emu.root_table >> 12),
+        )
+        .unwrap();
+        cpu.csr.get_by_type_existing::<Mstatus>().set_fs(1);
+        cpu.csr.get_by_type_existing::<Mstatus>().set_vs(1);
+        cpu.csr.set_current_privileged(PrivilegeLevel::U);
+        cpu.reg_file.write(2, sp);
+        cpu.pc = file.header.pt2.entry_point() as WordType;
+
+        Ok(emu)
+    }
+
+    /// Run until the program exits, returning its exit code.
+    pub fn run(&mut self) -> Result<i32, UserModeError> {
+        loop {
+            if let Some(code) = self.step()? {
+                return Ok(code);
+            }
+        }
+    }
+
+    /// Execute a single instruction, returns the exit code once the program has exited.
+    pub fn step(&mut self) -> Result<Option<i32>, UserModeError> {
+        if let Some(code) = self.exit_code {
+            return Ok(Some(code));
+        }
+
+        match self.cpu.step() {
+            Ok(()) => {}
+            Err(Exception::UserEnvCall) => {
+                let ret = self.handle_syscall();
+                self.cpu.reg_file.write(10, ret as WordType);
+                self.cpu.pc = self.cpu.pc.wrapping_add(4);
+                self.cpu.trace_syscall_return();
+            }
+            Err(exception) => {
+                return Err(UserModeError::UnhandledException {
+                    exception,
+                    pc: self.cpu.pc,
+                });
+            }
+        }
+
+        Ok(self.exit_code)
+    }
+
+    pub fn cpu(&self) -> &RVCPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut RVCPU {
+        &mut self.cpu
+    }
+
+    fn handle_syscall(&mut self) -> i64 {
+        let regs = self.cpu.reg_file.as_slice();
+        let nr = regs[17];
+        let args: [WordType; 6] = regs[10..16].try_into().unwrap();
+
+        match nr {
+            nr::READ => self.sys_read(args[0], args[1], args[2]),
+            nr::WRITE => match self.read_bytes(args[1], args[2]) {
+                Some(data) => host_write(args[0], &data),
+                None => -errno::EFAULT,
+            },
+            nr::WRITEV => self.sys_writev(args[0], args[1], args[2]),
+            nr::EXIT | nr::EXIT_GROUP => {
+                self.exit_code = Some(args[0] as i32);
+                0
+            }
+            nr::BRK => self.sys_brk(args[0]),
+            nr::MMAP => self.sys_mmap(args[0], args[1], args[3]),
+            // Pages are never reclaimed.
+            nr::MUNMAP => 0,
+            // Single-threaded, the tid is always 1.
+            nr::SET_TID_ADDRESS => 1,
+            // No terminal is exposed to the guest.
+            nr::IOCTL => -errno::ENOTTY,
+            _ => {
+                log::warn!(
+                    "Unsupported syscall {} at pc = {:#x}, args: {:x?}",
+                    nr,
+                    self.cpu.pc,
+                    args
+                );
+                -errno::ENOSYS
+            }
+        }
+    }
+
+    fn sys_read(&mut self, fd: WordType, buf: WordType, len: WordType) -> i64 {
+        if fd != 0 {
+            return -errno::EBADF;
+        }
+
+        // Check the buffer before consuming the input, a read may be shorter than asked.
+        let len = self.mapped_len(buf, len);
+        if len == 0 {
+            return -errno::EFAULT;
+        }
+        let mut data = vec![0u8; len as usize];
+        match std::io::stdin().read(&mut data) {
+            Ok(n) => {
+                if !self.write_bytes(buf, &data[..n]) {
+                    return -errno::EFAULT;
+                }
+                n as i64
+            }
+            Err(e) => -(e.raw_os_error().unwrap_or(errno::EINVAL as i32) as i64),
+        }
+    }
+
+    fn sys_writev(&mut self, fd: WordType, iov: WordType, iovcnt: WordType) -> i64 {
+        if iovcnt > IOV_MAX {
+            return -errno::EINVAL;
+        }
+        let mut written = 0;
+        for i in 0..iovcnt {
+            let Some(entry) = i
+                .checked_mul(16)
+                .and_then(|offset| iov.checked_add(offset))
+                .and_then(|addr| self.read_bytes(addr, 16))
+            else {
+                return if written > 0 { written } else { -errno::EFAULT };
+            };
+            let base = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+            let len = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+            let Some(buf) = base
+                .checked_add(len)
+                .and_then(|_| self.read_bytes(base, len))
+            else {
+                return if written > 0 { written } else { -errno::EFAULT };
+            };
+            // Like Linux, a later failure still reports what was already written.
+            let ret = host_write(fd, &buf);
+            if ret < 0 {
+                return if written > 0 { written } else { ret };
+            }
+            written += ret;
+        }
+        written
+    }
+
+    fn sys_brk(&mut self, addr: WordType) -> i64 {
+        if addr < self.brk_start || addr >= MMAP_BASE {
+            return self.brk as i64;
+        }
+        if self.map_range(self.brk, addr, PTE_R | PTE_W).is_ok() {
+            self.brk = addr;
+        }
+        self.brk as i64
+    }
+
+    fn sys_mmap(&mut self, addr: WordType, len: WordType, flags: WordType) -> i64 {
+        if flags & MAP_ANONYMOUS == 0 {
+            // File mappings are not supported.
+            return -errno::ENODEV;
+        }
+        if len == 0 {
+            return -errno::EINVAL;
+        }
+
+        let fixed = flags & MAP_FIXED != 0;
+        if fixed && !addr.is_multiple_of(PAGE_SIZE) {
+            return -errno::EINVAL;
+        }
+        let start = if fixed { addr } else { self.mmap_next };
+        let Some(end) = start.checked_add(len).filter(|&end| {
+            end <= if fixed {
+                STACK_TOP
+            } else {
+                STACK_TOP - STACK_SIZE
+            }
+        }) else {
+            return -errno::ENOMEM;
+        };
+
+        match self.map_range(start, end, PTE_R | PTE_W) {
+            Ok(()) => {
+                // Fixed mappings may replace existing pages, whose contents must read as zero.
+                if fixed {
+                    self.zero_bytes(start, len);
+                } else {
+                    self.mmap_next = page_align_up(end);
+                }
+                start as i64
+            }
+            Err(_) => -errno::ENOMEM,
+        }
+    }
+
+    /// Build the initial stack as the Linux ELF loader does, returns the initial `sp`.
+    fn setup_stack(
+        &mut self,
+        argv: &[String],
+        envp: &[String],
+        auxv: &[(WordType, WordType)],
+    ) -> WordType {
+        let mut sp = STACK_TOP;
+        let mut push_bytes = |emu: &mut Self, bytes: &[u8]| {
+            sp -= bytes.len() as WordType;
+            emu.write_bytes(sp, bytes);
+            sp
+        };
+
+        let mut push_str = |emu: &mut Self, s: &String| {
+            let mut bytes = s.as_bytes().to_vec();
+            bytes.push(0);
+            push_bytes(emu, &bytes)
+        };
+        let argv_ptrs: Vec<WordType> = argv.iter().map(|s| push_str(self, s)).collect();
+        let envp_ptrs: Vec<WordType> = envp.iter().map(|s| push_str(self, s)).collect();
+        let seed = if vclock::is_deterministic() {
+            0
+        } else {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64)
+        };
+        let random = push_bytes(self, &ChaCha12Rng::seed_from_u64(seed).random::<[u8; 16]>());
+
+        let mut words = vec![argv.len() as WordType];
+        words.extend(&argv_ptrs);
+        words.push(0);
+        words.extend(&envp_ptrs);
+        words.push(0);
+        for &(key, value) in auxv.iter().chain(&[(auxv::AT_RANDOM, random)]) {
+            words.extend([key, value]);
+        }
+        words.extend([auxv::AT_NULL, 0]);
+
+        let sp = (random - (words.len() * size_of::<WordType>()) as WordType) & !0xf;
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        self.write_bytes(sp, &bytes);
+        sp
+    }
+
+    fn ram(&mut self) -> &mut Ram {
+        unsafe { &mut *self.ram.get() }
+    }
+
+    fn alloc_frame(&mut self) -> Result<WordType, UserModeError> {
+        let frame = self.next_frame;
+        if frame + PAGE_SIZE > ram_config::BASE_ADDR + ram_config::SIZE as WordType {
+            return Err(UserModeError::OutOfMemory);
+        }
+        self.next_frame += PAGE_SIZE;
+        Ok(frame)
+    }
+
+    fn read_pte(&mut self, addr: WordType) -> WordType {
+        self.ram()
+            .read::<WordType>(addr - ram_config::BASE_ADDR)
+            .unwrap()
+    }
+
+    fn write_pte(&mut self, addr: WordType, pte: WordType) {
+        self.ram()
+            .write::<WordType>(addr - ram_config::BASE_ADDR, pte)
+            .unwrap();
+    }
+
+    /// Map every page overlapping `[start, end)` with `flags`, existing pages keep their frames
+    /// and get `flags` added.
+    fn map_range(
+        &mut self,
+        start: WordType,
+        end: WordType,
+        flags: WordType,
+    ) -> Result<(), UserModeError> {
+        let mut vpn = start / PAGE_SIZE;
+        while vpn * PAGE_SIZE < end {
+            self.map_page(vpn, flags)?;
+            vpn += 1;
+        }
+        self.cpu.flush_tlb();
+        Ok(())
+    }
+
+    fn map_page(&mut self, vpn: WordType, flags: WordType) -> Result<(), UserModeError> {
+        let mut table = self.root_table;
+        for level in [2, 1] {
+            let pte_addr = table + ((vpn >> (9 * level)) & 0x1ff) * 8;
+            let pte = self.read_pte(pte_addr);
+            table = if pte & PTE_V != 0 {
+                (pte >> 10) << 12
+            } else {
+                let next = self.alloc_frame()?;
+                self.write_pte(pte_addr, ((next >> 12) << 10) | PTE_V);
+                next
+            };
+        }
+
+        let pte_addr = table + (vpn & 0x1ff) * 8;
+        let frame = match self.pages.get(&vpn) {
+            Some(&frame) => frame,
+            None => {
+                let frame = self.alloc_frame()?;
+                self.pages.insert(vpn, frame);
+                frame
+            }
+        };
+        let old_flags = self.read_pte(pte_addr) & 0x3ff;
+        let leaf_flags = old_flags | flags | PTE_V | PTE_U | PTE_A | PTE_D;
+        self.write_pte(pte_addr, ((frame >> 12) << 10) | leaf_flags);
+        Ok(())
+    }
+
+    /// Walk `[vaddr, vaddr + len)` page by page as RAM offsets, `None` if any page is unmapped.
+    fn guest_chunks(&self, vaddr: WordType, len: WordType) -> Option<Vec<(usize, usize)>> {
+        let mut chunks = Vec::new();
+        let mut addr = vaddr;
+        let end = vaddr.checked_add(len)?;
+        while addr < end {
+            let frame = self.pages.get(&(addr / PAGE_SIZE))?;
+            let offset = addr % PAGE_SIZE;
+            let size = (PAGE_SIZE - offset).min(end - addr);
+            chunks.push((
+                (frame + offset - ram_config::BASE_ADDR) as usize,
+                size as usize,
+            ));
+            addr += size;
+        }
+        Some(chunks)
+    }
+
+    /// The bytes from `vaddr` which are mapped, at most `len`.
+    fn mapped_len(&self, vaddr: WordType, len: WordType) -> WordType {
+        let end = vaddr.saturating_add(len);
+        let mut addr = vaddr;
+        while addr < end && self.pages.contains_key(&(addr / PAGE_SIZE)) {
+            addr = (addr | (PAGE_SIZE - 1)).saturating_add(1);
+        }
+        addr.min(end) - vaddr
+    }
+
+    fn read_bytes(&mut self, vaddr: WordType, len: WordType) -> Option<Vec<u8>> {
+        let chunks = self.guest_chunks(vaddr, len)?;
+        let ram = self.ram();
+        let mut data = Vec::with_capacity(len as usize);
+        for (offset, size) in chunks {
+            data.extend((offset..offset + size).map(|i| ram[i]));
+        }
+        Some(data)
+    }
+
+    /// Returns `false` without writing anything if any page is unmapped.
+    fn write_bytes(&mut self, vaddr: WordType, data: &[u8]) -> bool {
+        let Some(chunks) = self.guest_chunks(vaddr, data.len() as WordType) else {
+            return false;
+        };
+        let ram = self.ram();
+        let mut data = data.iter();
+        for (offset, size) in chunks {
+            for i in offset..offset + size {
+                ram[i] = *data.next().unwrap();
+            }
+        }
+        true
+    }
+
+    /// Zero the mapped pages of `[vaddr, vaddr + len)`.
+    fn zero_bytes(&mut self, vaddr: WordType, len: WordType) {
+        let Some(chunks) = self.guest_chunks(vaddr, len) else {
+            return;
+        };
+        let ram = self.ram();
+        for (offset, size) in chunks {
+            for i in offset..offset + size {
+                ram[i] = 0;
+            }
+        }
+    }
+}
+
+fn page_align_up(addr: WordType) -> WordType {
+    addr.div_ceil(PAGE_SIZE) * PAGE_SIZE
+}
+
+fn segment_flags(ph: &ProgramHeader) -> WordType {
+    let flags = ph.flags();
+    let mut pte = 0;
+    if flags.is_read() {
+        pte |= PTE_R;
+    }
+    if flags.is_write() {
+        pte |= PTE_W;
+    }
+    if flags.is_execute() {
+        pte |= PTE_X;
+    }
+    pte
+}
+
+fn host_write(fd: WordType, data: &[u8]) -> i64 {
+    let result = match fd {
+        1 => {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(data).and_then(|_| stdout.flush())
+        }
+        2 => std::io::stderr().write_all(data),
+        _ => return -errno::EBADF,
+    };
+
+    match result {
+        Ok(()) => data.len() as i64,
+        Err(e) => -(e.raw_os_error().unwrap_or(errno::EINVAL as i32) as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: u64 = 0x10078;
+
+    fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+        ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (rd << 7) | 0x13
+    }
+
+    fn lui(rd: u32, imm: u32) -> u32 {
+        (imm << 12) | (rd << 7) | 0x37
+    }
+
+    fn add(rd: u32, rs1: u32, rs2: u32) -> u32 {
+        (rs2 << 20) | (rs1 << 15) | (rd << 7) | 0x33
+    }
+
+    fn ld(rd: u32, rs1: u32, imm: i32) -> u32 {
+        ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (3 << 12) | (rd << 7) | 0x03
+    }
+
+    fn sd(rs2: u32, rs1: u32, imm: i32) -> u32 {
+        let imm = imm as u32 & 0xfff;
+        ((imm >> 5) << 25) | (rs2 << 20) | (rs1 << 15) | (3 << 12) | ((imm & 0x1f) << 7) | 0x23
+    }
+
+    const ECALL: u32 = 0x73;
+
+    /// A minimal static ELF64 executable with a single RX segment at `0x10000`.
+    fn make_elf(code: &[u32]) -> Vec<u8> {
+        let code: Vec<u8> = code.iter().flat_map(|i| i.to_le_bytes()).collect();
+        let size = (0x78 + code.len()) as u64;
+
+        let mut elf = Vec::new();
+        elf.extend(b"\x7fELF\x02\x01\x01\x00");
+        elf.extend([0u8; 8]);
+        elf.extend(2u16.to_le_bytes()); // ET_EXEC
+        elf.extend(0xf3u16.to_le_bytes()); // EM_RISCV
+        elf.extend(1u32.to_le_bytes());
+        elf.extend(ENTRY.to_le_bytes());
+        elf.extend(0x40u64.to_le_bytes()); // e_phoff
+        elf.extend(0u64.to_le_bytes()); // e_shoff
+        elf.extend(0u32.to_le_bytes());
+        elf.extend(0x40u16.to_le_bytes()); // e_ehsize
+        elf.extend(0x38u16.to_le_bytes()); // e_phentsize
+        elf.extend(1u16.to_le_bytes()); // e_phnum
+        elf.extend([0u8; 6]);
+
+        elf.extend(1u32.to_le_bytes()); // PT_LOAD
+        elf.extend(5u32.to_le_bytes()); // R | X
+        elf.extend(0u64.to_le_bytes()); // p_offset
+        elf.extend(0x10000u64.to_le_bytes()); // p_vaddr
+        elf.extend(0x10000u64.to_le_bytes()); // p_paddr
+        elf.extend(size.to_le_bytes());
+        elf.extend(size.to_le_bytes());
+        elf.extend(0x1000u64.to_le_bytes());
+
+        assert_eq!(elf.len(), 0x78);
+        elf.extend(code);
+        elf
+    }
+
+    fn run(code: &[u32], argv: &[&str]) -> (UserEmulator, i32) {
+        let argv: Vec<String> = argv.iter().map(|s| s.to_string()).collect();
+        let mut emu = UserEmulator::from_elf(&make_elf(code), &argv, &[]).unwrap();
+        let code = emu.run().unwrap();
+        (emu, code)
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let (_, code) = run(&[addi(10, 0, 42), addi(17, 0, 93), ECALL], &["prog"]);
+        assert_eq!(code, 42);
+    }
+
+    #[test]
+    fn test_initial_stack() {
+        // exit(argc)
+        let (emu, code) = run(&[ld(10, 2, 0), addi(17, 0, 94), ECALL], &["prog", "a", "b"]);
+        assert_eq!(code, 3);
+
+        let sp = emu.cpu().reg_file[2];
+        assert_eq!(sp % 16, 0);
+    }
+
+    #[test]
+    fn test_brk() {
+        let (emu, code) = run(
+            &[
+                // s0 = brk(0)
+                addi(10, 0, 0),
+                addi(17, 0, 214),
+                ECALL,
+                addi(8, 10, 0),
+                // brk(s0 + 0x1000)
+                lui(5, 1),
+                add(10, 8, 5),
+                ECALL,
+                // *s0 = 7, exit(*s0)
+                addi(6, 0, 7),
+                sd(6, 8, 0),
+                ld(10, 8, 0),
+                addi(17, 0, 93),
+                ECALL,
+            ],
+            &["prog"],
+        );
+        assert_eq!(code, 7);
+        assert_eq!(emu.brk, emu.brk_start + 0x1000);
+    }
+
+    #[test]
+    fn test_page_fault_is_reported() {
+        let mut emu =
+            UserEmulator::from_elf(&make_elf(&[sd(0, 0, 0)]), &["prog".to_string()], &[]).unwrap();
+        assert!(matches!(
+            emu.run(),
+            Err(UserModeError::UnhandledException {
+                exception: Exception::StorePageFault,
+                pc: ENTRY,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_bad_syscall_arguments() {
+        let mut emu = UserEmulator::from_elf(&make_elf(&[]), &[], &[]).unwrap();
+        // The buffer is checked before stdin is read.
+        assert_eq!(emu.sys_read(0, 0, WordType::MAX), -errno::EFAULT);
+        assert_eq!(emu.sys_writev(1, 0, IOV_MAX + 1), -errno::EINVAL);
+        assert_eq!(emu.sys_writev(1, WordType::MAX - 8, 2), -errno::EFAULT);
+        assert_eq!(
+            emu.sys_mmap(0, WordType::MAX, MAP_ANONYMOUS),
+            -errno::ENOMEM
+        );
+        assert_eq!(
+            emu.sys_mmap(PAGE_SIZE, WordType::MAX, MAP_ANONYMOUS | MAP_FIXED),
+            -errno::ENOMEM
+        );
+        assert_eq!(emu.mmap_next, MMAP_BASE);
+    }
+
+    #[test]
+    fn test_reject_truncated_segment() {
+        let mut elf = make_elf(&[]);
+        // p_filesz and p_memsz
+        elf[0x60..0x68].copy_from_slice(&0x10000u64.to_le_bytes());
+        elf[0x68..0x70].copy_from_slice(&0x10000u64.to_le_bytes());
+        assert!(matches!(
+            UserEmulator::from_elf(&elf, &[], &[]),
+            Err(UserModeError::InvalidElf(_))
+        ));
+    }
+
+    #[test]
+    fn test_reject_non_executable() {
+        let mut elf = make_elf(&[]);
+        elf[16] = 3; // ET_DYN
+        assert!(matches!(
+            UserEmulator::from_elf(&elf, &[], &[]),
+            Err(UserModeError::NotStaticExecutable)
+        ));
+    }
+}
@@ -8,6 +8,9 @@ macro_rules! define_instr_enum {
         }
 
         impl $isa_name {
+            /// Every instruction, in declaration order: `ALL[instr as usize] == instr`.
+            pub const ALL: &'static [$isa_name] = &[$($isa_name::$name),*];
+
             pub fn name(&self) -> &'static str {
                 match self {
                     $($isa_name::$name => stringify!($name)),*
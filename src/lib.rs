@@ -20,7 +20,6 @@ compile_error!("feature 'web' requires wasm32 target");
 mod cpu;
 mod fpu;
 mod utils;
-mod vclock;
 
 #[cfg(feature = "native-cli")]
 pub mod gdb;
@@ -28,13 +27,24 @@ pub mod gdb;
 pub mod background;
 pub mod board;
 pub mod byte_io;
+#[cfg(feature = "capi")]
+pub mod capi;
 pub mod cli_coordinator;
 pub mod config;
 pub mod device;
 pub mod device_poller;
+pub mod doorbell;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod event_loop;
 pub mod isa;
 pub mod load;
 pub mod ram;
+#[cfg(feature = "native-cli")]
+pub mod remote_bitbang;
+#[cfg(feature = "repl")]
+pub mod repl;
+pub mod vclock;
+pub mod work_queue;
 
 #[cfg(feature = "web")]
 pub mod wasm_api;
@@ -43,9 +53,18 @@ pub use config::ram_config;
 use lazy_static::lazy_static;
 
 use crate::{
-    board::{Board, BoardStatus, virt::VirtBoard},
-    device::virtio::virtio_mmio::VirtIODeviceID,
-    isa::riscv::trap::Exception,
+    board::{Board, BoardStatus, memory_map::MemoryMap, virt::VirtBoard},
+    device::{
+        plic::{ExternalInterrupt, storm::StormConfig},
+        virtio::{block_backend::CacheMode, virtio_mmio::VirtIODeviceID},
+        watchdog::WatchdogAction,
+    },
+    isa::riscv::{
+        csr_reg::{HartIdentity, custom::CustomCsr},
+        isa_builder::ISABuilder,
+        trap::{Exception, trap_log::TrapRecord},
+    },
+    ram::RamInit,
 };
 use std::{
     path::PathBuf,
@@ -53,10 +72,23 @@ use std::{
     sync::{Mutex, MutexGuard},
 };
 
+/// Parse a decimal number, or a hexadecimal one prefixed with `0x`, as taken by the command line,
+/// configuration files and scripts.
+pub fn parse_u64(s: &str) -> Result<u64, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DeviceConfig {
     pub dev_type: VirtIODeviceID,
     pub path: PathBuf,
+    /// Set by the `:ro` suffix, the guest can not modify the backing image.
+    pub read_only: bool,
+    /// Set by the `:cache=<mode>` suffix, when the writes reach the host disk.
+    pub cache: CacheMode,
 }
 
 impl FromStr for DeviceConfig {
@@ -71,16 +103,164 @@ impl FromStr for DeviceConfig {
             None => return Err("Invalid device arguments.".into()),
         };
         let path = PathBuf::from(parts.next().ok_or("Need input a device path.")?);
-        Ok(DeviceConfig { dev_type, path })
+        let mut read_only = false;
+        let mut cache = CacheMode::default();
+        for option in parts {
+            match option.split_once('=') {
+                None if option == "ro" => read_only = true,
+                Some(("cache", mode)) => cache = mode.parse()?,
+                _ => return Err(format!("Unknown device option: {}", option)),
+            }
+        }
+        Ok(DeviceConfig {
+            dev_type,
+            path,
+            read_only,
+            cache,
+        })
+    }
+}
+
+/// A device on the SPI or I2C bus, `<kind>@<address>[:<option>]...`, e.g.
+/// `eeprom@0x50:size=4096:image=eeprom.bin` or `lm75@0x48:temp=36.5`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BusDeviceConfig {
+    pub kind: BusDeviceKind,
+    /// The 7-bit I2C address, or the SPI chip select.
+    pub addr: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BusDeviceKind {
+    /// An erased EEPROM of `size` bytes, or holding `image`.
+    Eeprom { size: usize, image: Option<PathBuf> },
+    /// An LM75 temperature sensor, I2C only.
+    Lm75 { millicelsius: i32 },
+}
+
+impl FromStr for BusDeviceConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let (kind, addr) = parts
+            .next()
+            .and_then(|device| device.split_once('@'))
+            .ok_or("Expected <kind>@<address>.")?;
+        let addr = parse_u64(addr).map_err(|_| format!("Invalid bus address: {}", addr))?;
+        if addr >= 0x80 {
+            return Err(format!("Bus address {:#x} is not a 7-bit address", addr));
+        }
+        let addr = addr as u8;
+
+        let mut kind = match kind {
+            "eeprom" => BusDeviceKind::Eeprom {
+                size: 256,
+                image: None,
+            },
+            "lm75" => BusDeviceKind::Lm75 {
+                millicelsius: 25_000,
+            },
+            other => return Err(format!("Unknown bus device type: {}", other)),
+        };
+        for option in parts {
+            match (&mut kind, option.split_once('=')) {
+                (BusDeviceKind::Eeprom { size, .. }, Some(("size", value))) => {
+                    *size = value
+                        .parse()
+                        .ok()
+                        .filter(|size: &usize| size.is_power_of_two() && *size <= 1 << 24)
+                        .ok_or(format!("Invalid EEPROM size: {}", value))?;
+                }
+                (BusDeviceKind::Eeprom { image, .. }, Some(("image", path))) => {
+                    *image = Some(PathBuf::from(path));
+                }
+                (BusDeviceKind::Lm75 { millicelsius }, Some(("temp", value))) => {
+                    let celsius: f64 = value
+                        .parse()
+                        .map_err(|_| format!("Invalid temperature: {}", value))?;
+                    *millicelsius = (celsius * 1000.0).round() as i32;
+                }
+                _ => return Err(format!("Unknown bus device option: {}", option)),
+            }
+        }
+        Ok(BusDeviceConfig { kind, addr })
     }
 }
 
 pub struct EmulatorConfig {
     pub(crate) devices: Vec<DeviceConfig>,
+    /// Action of the watchdog, `None` leaves it out.
+    pub(crate) watchdog: Option<WatchdogAction>,
+    /// When to warn about an interrupt storm, `None` doesn't look for them.
+    pub(crate) irq_storm: Option<StormConfig>,
+    /// Whether the VirtIO devices are behind a RISC-V IOMMU.
+    pub(crate) iommu: bool,
+    /// Whether the board has a DMA engine.
+    pub(crate) dma: bool,
+    /// Whether the board has a PWM, see [`device::sifive_pwm`].
+    pub(crate) pwm: bool,
+    /// Whether the board has an entropy source, and the seed of its stream.
+    pub(crate) rng: bool,
+    pub(crate) rng_seed: Option<u64>,
+    /// Whether the board has a GPIO controller, and the script of its inputs.
+    pub(crate) gpio: bool,
+    pub(crate) gpio_script: Option<PathBuf>,
+    /// Image of the CFI flash and whether it is read-only.
+    pub(crate) flash: Option<(PathBuf, bool)>,
+    /// Image of the second flash bank, the UEFI variable store, and whether it is read-only.
+    pub(crate) flash_vars: Option<(PathBuf, bool)>,
+    /// Image of the SD card on the SPI controller and whether it is read-only.
+    pub(crate) sd_card: Option<(PathBuf, bool)>,
+    /// Devices on the chip selects of the SPI controller, and on the I2C bus.
+    pub(crate) spi_devices: Vec<BusDeviceConfig>,
+    pub(crate) i2c_devices: Vec<BusDeviceConfig>,
+    /// Script of the scripted device, see [`device::scripted`].
+    pub(crate) scripted_device: Option<PathBuf>,
+    pub(crate) isa: Option<ISABuilder>,
+    pub(crate) custom_csrs: Vec<CustomCsr>,
+    pub(crate) identity: HartIdentity,
+    /// Whether the UART is connected to the host terminal.
+    pub(crate) serial_console: bool,
+    /// Connect the UART to a TCP client on this port instead.
+    pub(crate) serial_tcp: Option<u16>,
+    /// Break when the serial output contains one of these.
+    pub(crate) panic_patterns: Vec<String>,
+    pub(crate) memory_map: MemoryMap,
+    /// The kernel command line, see [`load_cmdline`](crate::load::load_cmdline).
+    pub(crate) bootargs: Option<String>,
+    /// What the RAM holds before the images are loaded, zeroes if `None`.
+    pub(crate) ram_init: Option<RamInit>,
 }
 impl EmulatorConfig {
     pub fn new() -> Self {
-        Self { devices: vec![] }
+        Self {
+            devices: vec![],
+            watchdog: None,
+            irq_storm: Some(StormConfig::default()),
+            iommu: false,
+            dma: false,
+            pwm: false,
+            rng: false,
+            rng_seed: None,
+            gpio: false,
+            gpio_script: None,
+            flash: None,
+            flash_vars: None,
+            sd_card: None,
+            spi_devices: vec![],
+            i2c_devices: vec![],
+            scripted_device: None,
+            isa: None,
+            custom_csrs: vec![],
+            identity: HartIdentity::default(),
+            serial_console: true,
+            serial_tcp: None,
+            panic_patterns: vec![],
+            memory_map: MemoryMap::default(),
+            bootargs: None,
+            ram_init: None,
+        }
     }
 }
 
@@ -103,6 +283,129 @@ impl<'a> EmulatorConfigurator<'a> {
         self.lock.devices.push(device);
         self
     }
+    /// Add a watchdog which takes `action` once the guest stops kicking it.
+    pub fn watchdog(mut self, action: WatchdogAction) -> Self {
+        self.lock.watchdog = Some(action);
+        self
+    }
+    /// Warn when a PLIC source storms as `config` describes, `None` doesn't look for storms.
+    pub fn irq_storm(mut self, config: Option<StormConfig>) -> Self {
+        self.lock.irq_storm = config;
+        self
+    }
+    /// Translate the DMA of the VirtIO devices with a RISC-V IOMMU.
+    pub fn iommu(mut self, enabled: bool) -> Self {
+        self.lock.iommu = enabled;
+        self
+    }
+    /// Add a memory-to-memory DMA engine, see [`device::dma_engine`].
+    pub fn dma(mut self, enabled: bool) -> Self {
+        self.lock.dma = enabled;
+        self
+    }
+    /// Add a SiFive PWM, see [`device::sifive_pwm`].
+    pub fn pwm(mut self, enabled: bool) -> Self {
+        self.lock.pwm = enabled;
+        self
+    }
+    /// Add an entropy source, see [`device::entropy`].
+    pub fn rng(mut self, enabled: bool) -> Self {
+        self.lock.rng = enabled;
+        self
+    }
+    /// Add an entropy source whose stream is derived from `seed`.
+    pub fn rng_seed(mut self, seed: u64) -> Self {
+        self.lock.rng_seed = Some(seed);
+        self
+    }
+    /// Add a GPIO controller, see [`device::sifive_gpio`].
+    pub fn gpio(mut self, enabled: bool) -> Self {
+        self.lock.gpio = enabled;
+        self
+    }
+    /// Add a GPIO controller whose inputs follow the script at `path`.
+    pub fn gpio_script(mut self, path: PathBuf) -> Self {
+        self.lock.gpio_script = Some(path);
+        self
+    }
+    /// Back the CFI flash at the virt flash range with the image at `path`.
+    pub fn flash(mut self, path: PathBuf, read_only: bool) -> Self {
+        self.lock.flash = Some((path, read_only));
+        self
+    }
+    /// Back the second flash bank, where UEFI firmware keeps its variables, with the image at
+    /// `path`. A writable image is created when it does not exist.
+    pub fn flash_vars(mut self, path: PathBuf, read_only: bool) -> Self {
+        self.lock.flash_vars = Some((path, read_only));
+        self
+    }
+    /// Attach the image at `path` as an SD card, behind a SiFive SPI controller.
+    pub fn sd_card(mut self, path: PathBuf, read_only: bool) -> Self {
+        self.lock.sd_card = Some((path, read_only));
+        self
+    }
+    /// Put a device on the SPI controller, its address is the chip select.
+    pub fn append_spi_device(mut self, device: BusDeviceConfig) -> Self {
+        self.lock.spi_devices.push(device);
+        self
+    }
+    /// Put a device on the I2C bus, see [`device::ocores_i2c`].
+    pub fn append_i2c_device(mut self, device: BusDeviceConfig) -> Self {
+        self.lock.i2c_devices.push(device);
+        self
+    }
+    /// Add a device whose registers follow the script at `path`, see [`device::scripted`].
+    pub fn scripted_device(mut self, path: PathBuf) -> Self {
+        self.lock.scripted_device = Some(path);
+        self
+    }
+    /// Restrict the CPU to the extensions of `isa` instead of the default RV64GCV.
+    pub fn isa(mut self, isa: ISABuilder) -> Self {
+        self.lock.isa = Some(isa);
+        self
+    }
+    /// Add user-defined CSRs, see [`csr_reg::custom`](crate::isa::riscv::csr_reg::custom).
+    pub fn custom_csrs(mut self, csrs: Vec<CustomCsr>) -> Self {
+        self.lock.custom_csrs.extend(csrs);
+        self
+    }
+    pub fn identity(mut self, identity: HartIdentity) -> Self {
+        self.lock.identity = identity;
+        self
+    }
+    /// Keep the UART off the host terminal, so the output can be captured with
+    /// [`Emulator::take_uart_output_bytes`].
+    pub fn serial_console(mut self, enabled: bool) -> Self {
+        self.lock.serial_console = enabled;
+        self
+    }
+    /// Connect the UART to the first TCP client on localhost:`port`, which the board waits for.
+    pub fn serial_tcp(mut self, port: u16) -> Self {
+        self.lock.serial_tcp = Some(port);
+        self
+    }
+    /// Break when the serial output contains one of `patterns`, see
+    /// [`serial_scanner`](crate::board::serial_scanner).
+    pub fn panic_patterns(mut self, patterns: Vec<String>) -> Self {
+        self.lock.panic_patterns.extend(patterns);
+        self
+    }
+    /// Move the UART, PLIC, CLINT and VirtIO devices, see [`board::memory_map`].
+    pub fn memory_map(mut self, map: MemoryMap) -> Self {
+        self.lock.memory_map = map;
+        self
+    }
+    /// Write the kernel command line `bootargs` to the last page of RAM, see
+    /// [`ram_config::CMDLINE_ADDR`].
+    pub fn bootargs(mut self, bootargs: String) -> Self {
+        self.lock.bootargs = Some(bootargs);
+        self
+    }
+    /// Fill the RAM with `init` instead of zeroes, see [`RamInit`].
+    pub fn ram_init(mut self, init: RamInit) -> Self {
+        self.lock.ram_init = Some(init);
+        self
+    }
 }
 
 pub struct Emulator {
@@ -128,15 +431,18 @@ impl Emulator {
 
     pub fn run(&mut self) -> Result<(), Exception> {
         while self.board.status() != BoardStatus::Halt {
-            self.board.step()?;
+            self.step()?;
         }
 
         Ok(())
     }
 
+    /// Execute one instruction, or serve a pending reset.
     pub fn step(&mut self) -> Result<(), Exception> {
-        if self.board.status() != BoardStatus::Halt {
-            self.board.step()?;
+        match self.board.status() {
+            BoardStatus::Running => self.board.step()?,
+            BoardStatus::Resetting => self.board.reset(),
+            BoardStatus::Halt => {}
         }
         Ok(())
     }
@@ -144,7 +450,7 @@ impl Emulator {
     pub fn run_steps(&mut self, max_steps: u64) -> Result<u64, Exception> {
         let mut steps = 0;
         while self.board.status() != BoardStatus::Halt && steps < max_steps {
-            self.board.step()?;
+            self.step()?;
             steps += 1;
         }
         Ok(steps)
@@ -165,4 +471,14 @@ impl Emulator {
     pub fn take_uart_output_bytes(&mut self) -> Vec<u8> {
         self.board.take_uart_output()
     }
+
+    /// The last traps taken by the hart, most recent first.
+    pub fn recent_traps(&self) -> Vec<TrapRecord> {
+        self.board.cpu.recent_traps().copied().collect()
+    }
+
+    /// Set PLIC source `id` pending. Returns `false` if `id` is not a valid source.
+    pub fn inject_external_interrupt(&mut self, id: ExternalInterrupt) -> bool {
+        self.board.raise_external_interrupt(id)
+    }
 }
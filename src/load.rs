@@ -1,6 +1,6 @@
 use xmas_elf::symbol_table::{Entry, Entry32, Entry64};
 
-use crate::{config::arch_config::WordType, ram::Ram, ram_config::BASE_ADDR, utils::BiMap};
+use crate::{config::arch_config::WordType, ram::Ram, ram_config, utils::BiMap};
 
 pub struct SymTab {
     pub symbols: BiMap<String, u64>,
@@ -59,12 +59,18 @@ impl ELFLoader {
         for ph in elf.program_iter() {
             if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
                 let start_addr = ph.virtual_addr() as WordType;
-                // let end_addr = ((ph.virtual_addr() + ph.mem_size()) as usize) as WordType;
-
-                ram.insert_section(
-                    &elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize],
-                    start_addr - BASE_ADDR,
-                );
+                let data =
+                    &elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize];
+
+                let Ok(dest) = ram.slice(start_addr, data.len()) else {
+                    log::error!(
+                        "ELF segment {:#x}+{:#x} is outside RAM",
+                        start_addr,
+                        data.len()
+                    );
+                    panic!();
+                };
+                dest.copy_from_slice(data);
             }
         }
     }
@@ -124,3 +130,39 @@ impl ELFLoader {
 pub fn load_bin(ram: &mut Ram, raw_data: &[u8]) {
     ram.insert_section(raw_data, 0);
 }
+
+/// Write the kernel command line to [`CMDLINE_ADDR`](ram_config::CMDLINE_ADDR).
+pub fn load_cmdline(ram: &mut Ram, cmdline: &str) -> Result<(), String> {
+    if cmdline.len() >= ram_config::CMDLINE_SIZE || cmdline.contains('\0') {
+        return Err(format!(
+            "the kernel command line must be shorter than {} bytes, without NUL",
+            ram_config::CMDLINE_SIZE
+        ));
+    }
+    let dest = ram
+        .slice(ram_config::CMDLINE_ADDR, cmdline.len() + 1)
+        .map_err(|_| "the kernel command line is outside RAM".to_string())?;
+    let (text, nul) = dest.split_at_mut(cmdline.len());
+    text.copy_from_slice(cmdline.as_bytes());
+    nul[0] = 0;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_cmdline() {
+        let mut ram = Ram::new();
+        let offset = ram_config::CMDLINE_ADDR - ram_config::BASE_ADDR;
+        load_cmdline(&mut ram, "console=ttyS0").unwrap();
+        let cmdline: Vec<u8> = (0..14)
+            .map(|i| ram.read::<u8>(offset + i).unwrap())
+            .collect();
+        assert_eq!(cmdline, b"console=ttyS0\0");
+
+        assert!(load_cmdline(&mut ram, &"x".repeat(ram_config::CMDLINE_SIZE)).is_err());
+        assert!(load_cmdline(&mut ram, "a\0b").is_err());
+    }
+}
@@ -1,11 +1,19 @@
-use std::time;
+use std::{io::Write, path::Path, str::FromStr, sync::Mutex, time};
 
 use clap::ValueEnum;
 use flexi_logger::{
-    Cleanup, Criterion, Duplicate, FileSpec, LogSpecBuilder, Logger, LoggerHandle, Naming,
+    Cleanup, Criterion, DeferredNow, FileSpec, LogSpecBuilder, Logger, LoggerHandle, Naming,
     WriteMode,
+    filter::{LogLineFilter, LogLineWriter},
 };
-use log::LevelFilter;
+use log::{Level, LevelFilter, Record};
+use riscv_emulator::{cli_coordinator::CliCoordinator, vclock};
+
+/// Rotated log files kept besides the current one.
+const LOG_FILES_KEPT: usize = 3;
+
+/// Identical messages logged in a row beyond this many are dropped and counted instead.
+const REPEAT_BURST: u32 = 3;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum LogLevel {
@@ -28,6 +36,160 @@ impl LogLevel {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, with `time`, `level`, `target` and `message` keys.
+    Json,
+}
+
+/// The levels of `--log`, e.g. `plic=debug,virtio=trace`. A target matches every module with
+/// that name in its path (`virtio` matches `device::virtio::virtio_blk`), the last matching
+/// target wins. A bare level replaces the default level of `--loglevel`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogFilters {
+    default: Option<LevelFilter>,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl FromStr for LogFilters {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filters = Self::default();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let parse_level = |level: &str| {
+                LevelFilter::from_str(level).map_err(|_| format!("Invalid level: {}", level))
+            };
+            match directive.split_once('=') {
+                Some((target, level)) => filters
+                    .targets
+                    .push((target.trim().to_string(), parse_level(level.trim())?)),
+                None => filters.default = Some(parse_level(directive)?),
+            }
+        }
+        Ok(filters)
+    }
+}
+
+impl LogFilters {
+    /// The level of the messages of `target`, a module path.
+    fn level_of(&self, target: &str, default: LevelFilter) -> LevelFilter {
+        let path = format!("::{}::", target);
+        self.targets
+            .iter()
+            .rev()
+            .find(|(name, _)| path.contains(&format!("::{}::", name)))
+            .map(|(_, level)| *level)
+            .or(self.default)
+            .unwrap_or(default)
+    }
+
+    /// The most verbose level of any target.
+    fn max_level(&self, default: LevelFilter) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .chain([self.default.unwrap_or(default)])
+            .max()
+            .unwrap()
+    }
+}
+
+/// The last message written, to detect repetitions.
+#[derive(Default)]
+struct LastMessage {
+    level: Option<Level>,
+    target: String,
+    message: String,
+    repeats: u32,
+}
+
+/// Applies the per-target levels of [`LogFilters`] and drops runs of identical messages, so a
+/// device polled in a loop does not drown the log.
+struct TargetFilter {
+    filters: LogFilters,
+    default: LevelFilter,
+    last: Mutex<LastMessage>,
+    /// Also show errors on the terminal.
+    echo_errors: bool,
+}
+
+impl TargetFilter {
+    fn new(filters: LogFilters, default: LevelFilter, echo_errors: bool) -> Self {
+        Self {
+            filters,
+            default,
+            last: Mutex::new(LastMessage::default()),
+            echo_errors,
+        }
+    }
+
+    /// Write an error to stderr, between two chunks of the guest serial output. The terminal may
+    /// be in raw mode, so the line ends with `\r\n`.
+    fn echo(record: &Record) {
+        let _console = CliCoordinator::global().lock_console();
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "[{}] {}\r\n", record.level(), record.args());
+        let _ = stderr.flush();
+    }
+}
+
+impl LogLineFilter for TargetFilter {
+    fn write(
+        &self,
+        now: &mut DeferredNow,
+        record: &Record,
+        log_line_writer: &dyn LogLineWriter,
+    ) -> std::io::Result<()> {
+        if record.level() > self.filters.level_of(record.target(), self.default) {
+            return Ok(());
+        }
+
+        let message = record.args().to_string();
+        let mut last = self.last.lock().unwrap();
+        if last.level == Some(record.level())
+            && last.target == record.target()
+            && last.message == message
+        {
+            last.repeats += 1;
+            if last.repeats > REPEAT_BURST {
+                return Ok(());
+            }
+        } else {
+            let dropped = last.repeats.saturating_sub(REPEAT_BURST);
+            if let Some(level) = last.level
+                && dropped != 0
+            {
+                log_line_writer.write(
+                    now,
+                    &Record::builder()
+                        .args(format_args!("last message repeated {} more times", dropped))
+                        .level(level)
+                        .target(&last.target)
+                        .build(),
+                )?;
+            }
+            *last = LastMessage {
+                level: Some(record.level()),
+                target: record.target().to_string(),
+                message,
+                repeats: 1,
+            };
+        }
+        drop(last);
+        if self.echo_errors && record.level() == Level::Error {
+            Self::echo(record);
+        }
+        log_line_writer.write(now, record)
+    }
+}
+
+fn start_time() -> std::time::Instant {
+    static START_DATE: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    *START_DATE.get_or_init(std::time::Instant::now)
+}
+
 fn duration_to_str_min(dur: time::Duration) -> String {
     format!(
         "{:02}:{:02}.{:03}",
@@ -42,9 +204,13 @@ fn format_msg_elapsed_time(
     _now: &mut flexi_logger::DeferredNow,
     record: &log::Record,
 ) -> Result<(), std::io::Error> {
-    static START_DATE: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
-    let start_time = START_DATE.get_or_init(|| std::time::Instant::now());
-    let elapsed = start_time.elapsed();
+    // Host timestamps would make traces differ between runs.
+    if vclock::is_deterministic() {
+        write!(w, "[{:>12}][{:5}] ", vclock::guest_time(), record.level())?;
+        return write!(w, "{}", &record.args());
+    }
+
+    let elapsed = start_time().elapsed();
 
     write!(
         w,
@@ -59,30 +225,70 @@ fn format_msg_elapsed_time(
     write!(w, "{}", &record.args())
 }
 
-/// Initialize the logger.
+fn format_json(
+    w: &mut dyn std::io::Write,
+    _now: &mut flexi_logger::DeferredNow,
+    record: &log::Record,
+) -> Result<(), std::io::Error> {
+    // Guest time in deterministic runs, seconds since the start otherwise.
+    let time = if vclock::is_deterministic() {
+        serde_json::json!(vclock::guest_time())
+    } else {
+        serde_json::json!(start_time().elapsed().as_secs_f64())
+    };
+    let line = serde_json::json!({
+        "time": time,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    write!(w, "{}", line)
+}
+
+/// Initialize the logger, writing to `file`, or `logs/emulator.log` by default, which is rotated
+/// every `rotate_size` bytes.
 /// Must keep the [`LoggerHandle`] (returned value) alive up to the very end of your program
 /// to ensure that all buffered log lines are flushed out.
 #[must_use]
-pub fn init(level: LogLevel) -> LoggerHandle {
+pub fn init(
+    level: LogLevel,
+    filters: &LogFilters,
+    format: LogFormat,
+    file: Option<&Path>,
+    rotate_size: u64,
+) -> LoggerHandle {
+    let default = level.to_level_filter();
     let mut builder = LogSpecBuilder::new();
     builder.module("rustyline", log::LevelFilter::Warn);
-    builder.default(level.to_level_filter());
+    // Let everything any target asks for through, `TargetFilter` sorts it out.
+    builder.default(filters.max_level(default));
+
+    let file_spec = match file {
+        Some(path) => FileSpec::try_from(path).unwrap_or_else(|e| {
+            eprintln!("Invalid log file {}: {}", path.display(), e);
+            std::process::exit(1);
+        }),
+        None => FileSpec::default()
+            .directory("logs")
+            .basename("emulator")
+            .suffix("log"),
+    };
 
+    // Errors are echoed by `TargetFilter` rather than duplicated to stderr by the logger, so
+    // they do not land in the middle of the guest output.
     Logger::with(builder.build())
-        .log_to_file(
-            FileSpec::default()
-                .directory("logs")
-                .basename("emulator")
-                .suffix("log"),
-        )
+        .log_to_file(file_spec)
         .rotate(
-            Criterion::Size(10_000_000), // 10 MB
+            Criterion::Size(rotate_size),
             Naming::Numbers,
-            Cleanup::KeepLogFiles(3),
+            Cleanup::KeepLogFiles(LOG_FILES_KEPT),
         )
         .write_mode(WriteMode::BufferAndFlush)
-        .duplicate_to_stderr(Duplicate::Error)
-        .format_for_files(format_msg_elapsed_time)
+        .format_for_files(match format {
+            LogFormat::Text => format_msg_elapsed_time,
+            LogFormat::Json => format_json,
+        })
+        .filter(Box::new(TargetFilter::new(filters.clone(), default, true)))
         .start()
         .unwrap()
 }
@@ -96,3 +302,108 @@ macro_rules! error_and_panic {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    fn test_log_filters() {
+        let filters: LogFilters = "plic=debug, virtio=trace,device::virtio::virtio_blk=off"
+            .parse()
+            .unwrap();
+        let level_of = |target| filters.level_of(target, LevelFilter::Info);
+        assert_eq!(level_of("riscv_emulator::device::plic"), LevelFilter::Debug);
+        assert_eq!(
+            level_of("riscv_emulator::device::virtio::virtio_mmio"),
+            LevelFilter::Trace
+        );
+        assert_eq!(
+            level_of("riscv_emulator::device::virtio::virtio_blk"),
+            LevelFilter::Off
+        );
+        assert_eq!(level_of("riscv_emulator::device::uart"), LevelFilter::Info);
+        // Whole names only.
+        assert_eq!(
+            level_of("riscv_emulator::device::plic_like"),
+            LevelFilter::Info
+        );
+        assert_eq!(filters.max_level(LevelFilter::Info), LevelFilter::Trace);
+
+        let filters: LogFilters = "warn,uart=debug".parse().unwrap();
+        assert_eq!(
+            filters.level_of("riscv_emulator::cpu", LevelFilter::Info),
+            LevelFilter::Warn
+        );
+        assert!("plic=loud".parse::<LogFilters>().is_err());
+    }
+
+    struct Lines(RefCell<Vec<String>>);
+
+    impl LogLineWriter for Lines {
+        fn write(&self, _now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+            self.0.borrow_mut().push(record.args().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_repeated_messages() {
+        let filter = TargetFilter::new("plic=off".parse().unwrap(), LevelFilter::Info, false);
+        let lines = Lines(RefCell::new(Vec::new()));
+        let mut now = DeferredNow::new();
+        let mut log = |target: &str, message: &str| {
+            filter
+                .write(
+                    &mut now,
+                    &Record::builder()
+                        .args(format_args!("{}", message))
+                        .level(Level::Info)
+                        .target(target)
+                        .build(),
+                    &lines,
+                )
+                .unwrap();
+        };
+
+        for _ in 0..10 {
+            log("uart", "busy");
+        }
+        log("plic", "hidden");
+        log("uart", "idle");
+
+        assert_eq!(
+            *lines.0.borrow(),
+            [
+                "busy",
+                "busy",
+                "busy",
+                "last message repeated 7 more times",
+                "idle"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_json_format() {
+        let mut out = Vec::new();
+        format_json(
+            &mut out,
+            &mut DeferredNow::new(),
+            &Record::builder()
+                .args(format_args!("quoted \"value\""))
+                .level(Level::Warn)
+                .target("riscv_emulator::device::plic")
+                .build(),
+        )
+        .unwrap();
+
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["target"], "riscv_emulator::device::plic");
+        assert_eq!(value["message"], "quoted \"value\"");
+        assert!(value["time"].is_number());
+    }
+}
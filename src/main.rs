@@ -3,24 +3,56 @@
 #![feature(generic_const_exprs)]
 
 mod logging;
-mod rvdb;
+mod run_config;
 mod welcome;
 
 use std::fs;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use lazy_static::lazy_static;
-use riscv_emulator::board::Board;
+use riscv_emulator::board::memory_map::MemoryMap;
+use riscv_emulator::board::serial_scanner::DEFAULT_PANIC_PATTERNS;
+use riscv_emulator::board::{Board, BoardStatus, dts::virt_dts};
+use riscv_emulator::cli_coordinator::CliCoordinator;
+use riscv_emulator::config::arch_config::WordType;
+use riscv_emulator::device::StormConfig;
+use riscv_emulator::device::mmio_trace::MmioTracer;
+use riscv_emulator::device::watchdog::WatchdogAction;
 use riscv_emulator::gdb;
 use riscv_emulator::isa::DebugTarget;
+use riscv_emulator::isa::riscv::cost_profile::{CostModel, CostProfiler};
+use riscv_emulator::isa::riscv::csr_reg::HartIdentity;
+use riscv_emulator::isa::riscv::csr_reg::custom::load_custom_csrs;
+use riscv_emulator::isa::riscv::csr_reg::trace::CsrTracer;
 use riscv_emulator::isa::riscv::debugger::Address;
-use riscv_emulator::{DeviceConfig, EmulatorConfigurator, board::virt::VirtBoard};
+use riscv_emulator::isa::riscv::executor::UndecodableAction;
+use riscv_emulator::isa::riscv::func_trace::FunctionTracer;
+use riscv_emulator::isa::riscv::isa_builder::ISABuilder;
+use riscv_emulator::isa::riscv::mmu::trace::MmuTracer;
+use riscv_emulator::isa::riscv::random_test::{self, RandomProgram};
+use riscv_emulator::isa::riscv::shadow_stack::ShadowStack;
+use riscv_emulator::isa::riscv::stack_guard::{StackGuard, StackSpec};
+use riscv_emulator::isa::riscv::syscall_trace::{SyscallTable, SyscallTracer};
+use riscv_emulator::isa::riscv::torture::{self, TortureProgram};
+use riscv_emulator::ram::RamInit;
+use riscv_emulator::remote_bitbang;
+use riscv_emulator::repl::DebugREPL;
+use riscv_emulator::vclock;
+use riscv_emulator::{BusDeviceConfig, DeviceConfig, EmulatorConfigurator, board::virt::VirtBoard};
 
-use crate::{logging::LogLevel, rvdb::DebugREPL, welcome::display_welcome_message};
+use crate::{
+    logging::{LogFilters, LogFormat, LogLevel},
+    run_config::RunConfig,
+    welcome::display_welcome_message,
+};
+
+/// The host clock is only read for --heartbeat every this many cycles.
+const HEARTBEAT_CHECK_CYCLES: u64 = 0x10000;
 
 lazy_static! {
-    static ref cli_args: Args = Args::parse();
+    static ref cli_args: Args = Args::parse_with_config();
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
@@ -39,10 +71,26 @@ fn display_device_list(devices: &Vec<DeviceConfig>) {
 }
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true,
+    args_override_self = true,
+    group(clap::ArgGroup::new("stack_checks").args(["stack", "shadow_stack"]).multiple(true))
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path of the target executable file (elf/bin).
-    path: std::path::PathBuf,
+    #[arg(required_unless_present_any = ["dump_dts", "config"])]
+    path: Option<std::path::PathBuf>,
+
+    /// Read the options from a TOML file, see `run_config` for the format. Options given on the
+    /// command line override those of the file.
+    #[arg(long = "config", value_name = "FILE")]
+    config: Option<std::path::PathBuf>,
 
     /// Specify target executable file format.
     #[arg(value_enum, short, long, default_value_t = TargetFormat::Auto)]
@@ -56,10 +104,19 @@ struct Args {
     #[arg(short = 'G', long = "gdb", default_value_t = false)]
     gdb: bool,
 
+    /// Serve OpenOCD on localhost:PORT through its remote_bitbang JTAG driver.
+    #[arg(long = "remote-bitbang", value_name = "PORT")]
+    remote_bitbang: Option<u16>,
+
     /// Script file for debugger REPL, will be ignored if --debug is not set.
     #[arg(short = 'S', long = "script")]
     script: Option<std::path::PathBuf>,
 
+    /// Run the --script in rvdb and exit, printing only the command results. Exits with status 1
+    /// if a command failed.
+    #[arg(long = "batch", requires = "script")]
+    batch: bool,
+
     /// Enable to print more details.
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
@@ -68,10 +125,105 @@ struct Args {
     #[arg(value_enum, long = "loglevel", default_value_t = LogLevel::Info)]
     log_level: LogLevel,
 
-    /// Add devices to emulator. Example: --device=virtio-block:./tmp/img_blk
+    /// Per-target log levels, e.g. `--log plic=debug,virtio=trace`. A target matches every module
+    /// with that name in its path, a bare level replaces --loglevel.
+    #[arg(long = "log", value_name = "FILTERS")]
+    log_filters: Option<LogFilters>,
+
+    /// Format of the lines of the log file.
+    #[arg(value_enum, long = "log-format", default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Write the log to this file instead of `logs/emulator.log`.
+    #[arg(long = "log-file", value_name = "FILE")]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Start a new log file when the current one reaches this many bytes, 3 old files are kept.
+    #[arg(
+        long = "log-file-size",
+        value_name = "BYTES",
+        default_value_t = 10_000_000
+    )]
+    log_file_size: u64,
+
+    /// Add devices to emulator. Example: --device=virtio-block:./tmp/img_blk[:ro][:cache=writeback|writethrough|directsync]
     #[arg(long = "device", action = clap::ArgAction::Append)]
     devices: Vec<DeviceConfig>,
 
+    /// Add a watchdog at 0x102000 which resets or halts the board when the guest stops kicking it.
+    #[arg(long = "watchdog", value_name = "reset|halt")]
+    watchdog: Option<WatchdogAction>,
+
+    /// Warn, with the PLIC state, when a source is claimed more than CLAIMS times within
+    /// INSTRUCTIONS instructions, every time interrupting the same pc. Defaults to 1000/1000000.
+    #[arg(long = "irq-storm", value_name = "CLAIMS/INSTRUCTIONS")]
+    irq_storm: Option<StormConfig>,
+
+    /// Don't look for interrupt storms.
+    #[arg(
+        long = "no-irq-storm",
+        default_value_t = false,
+        conflicts_with = "irq_storm"
+    )]
+    no_irq_storm: bool,
+
+    /// Add a RISC-V IOMMU at 0x3010000 translating the DMA of the VirtIO devices, the device ID is the VirtIO slot.
+    #[arg(long = "iommu", default_value_t = false)]
+    iommu: bool,
+
+    /// Add a memory-to-memory DMA engine at 0x105000, behind the IOMMU with --iommu.
+    #[arg(long = "dma", default_value_t = false)]
+    dma: bool,
+
+    /// Add a SiFive PWM at 0x10020000, also usable as a periodic timer.
+    #[arg(long = "pwm", default_value_t = false)]
+    pwm: bool,
+
+    /// Add an entropy source at 0x106000, seeded randomly (or with 0 with --deterministic).
+    #[arg(long = "rng", default_value_t = false)]
+    rng: bool,
+
+    /// Add the entropy source and derive its stream from SEED, for reproducible runs.
+    #[arg(long = "rng-seed", value_name = "SEED")]
+    rng_seed: Option<u64>,
+
+    /// Fill the RAM before loading the image: `zero`, a byte such as `0xaa`, `random` or
+    /// `random:SEED`, to make reads of uninitialized memory reproducible and easy to spot.
+    #[arg(long = "ram-init", value_name = "PATTERN")]
+    ram_init: Option<RamInit>,
+
+    /// Add a SiFive GPIO controller at 0x10060000, its pins are driven with `gpio set` in rvdb.
+    #[arg(long = "gpio", default_value_t = false)]
+    gpio: bool,
+
+    /// Add the GPIO controller and drive its pins with the timed events of a script.
+    #[arg(long = "gpio-script", value_name = "SCRIPT")]
+    gpio_script: Option<std::path::PathBuf>,
+
+    /// Back the CFI flash at 0x20000000 with an image, writes are persisted. Example: --flash=./tmp/flash.img[:ro]
+    #[arg(long = "flash")]
+    flash: Option<String>,
+
+    /// Back the second flash bank at 0x22000000, the UEFI variable store, with an image; it is created if missing. Example: --flash-vars=./tmp/vars.img[:ro]
+    #[arg(long = "flash-vars")]
+    flash_vars: Option<String>,
+
+    /// Attach an SD card image to the SPI controller. Example: --sd-card=./tmp/sd.img[:ro]
+    #[arg(long = "sd-card")]
+    sd_card: Option<String>,
+
+    /// Put a device on a chip select of the SPI controller at 0x10050000. Example: --spi-device=eeprom@1:size=32768[:image=./tmp/eeprom.bin]
+    #[arg(long = "spi-device", action = clap::ArgAction::Append)]
+    spi_devices: Vec<BusDeviceConfig>,
+
+    /// Put a device on the I2C controller at 0x10030000. Example: --i2c-device=eeprom@0x50 --i2c-device=lm75@0x48:temp=36.5
+    #[arg(long = "i2c-device", action = clap::ArgAction::Append)]
+    i2c_devices: Vec<BusDeviceConfig>,
+
+    /// Add a device at 0x104000 whose reads and expected writes follow a script, for driver tests.
+    #[arg(long = "scripted-device", value_name = "SCRIPT")]
+    scripted_device: Option<std::path::PathBuf>,
+
     /// Dump RISC-V arch-test signature into this file on exit.
     #[arg(long = "signature")]
     signature: Option<std::path::PathBuf>,
@@ -83,6 +235,253 @@ struct Args {
     /// Maximum cycles to execute before aborting (0 means no limit).
     #[arg(long = "max-cycles", default_value_t = 0)]
     max_cycles: u64,
+
+    /// Without --debug or --gdb, report the instructions retired, the guest time and the pc on
+    /// stderr every SECONDS, so a long boot can be told apart from a hang.
+    #[arg(long = "heartbeat", value_name = "SECONDS", value_parser = parse_seconds)]
+    heartbeat: Option<Duration>,
+
+    /// Trace guest syscalls (U-mode ECALL) and SBI calls (S-mode ECALL) to the log.
+    #[arg(long = "strace", default_value_t = false)]
+    strace: bool,
+
+    /// Syscall table used by --strace, one `<nr> <name> [nargs]` per line.
+    /// Defaults to the builtin Linux table.
+    #[arg(long = "syscall-table", requires = "strace")]
+    syscall_table: Option<std::path::PathBuf>,
+
+    /// Trace guest function entries and exits to the log, using the symbols of the ELF.
+    #[arg(long = "ftrace", default_value_t = false)]
+    ftrace: bool,
+
+    /// Estimate the cycles spent in each function of the ELF and write them as CSV to this file
+    /// at exit.
+    #[arg(long = "profile")]
+    profile: Option<std::path::PathBuf>,
+
+    /// Sample the pc for --profile every this many instructions.
+    #[arg(long = "profile-period", default_value_t = 1, requires = "profile")]
+    profile_period: u64,
+
+    /// Count the executed instructions and write the histogram as JSON to this file at exit.
+    #[arg(long = "istats")]
+    istats: Option<std::path::PathBuf>,
+
+    /// Cycles and energy of instruction classes for --profile, e.g. `load=3,div=34:12.5`.
+    /// Classes: alu, mul, div, load, store, branch, jump, atomic, float, fdiv, vector, system.
+    #[arg(long = "profile-weights", requires = "profile")]
+    profile_weights: Option<CostModel>,
+
+    /// Trace guest accesses to devices, optionally only the listed devices (e.g. --trace-mmio=uart,plic).
+    #[arg(long = "trace-mmio", value_delimiter = ',', num_args = 0.., require_equals = true)]
+    trace_mmio: Option<Vec<String>>,
+
+    /// Write the device access trace to this file instead of the log.
+    #[arg(long = "trace-mmio-file", requires = "trace_mmio")]
+    trace_mmio_file: Option<std::path::PathBuf>,
+
+    /// Trace the CSR writes of the guest with the values before and after, optionally only the
+    /// listed CSRs (e.g. --trace-csr=mstatus,satp,mtvec).
+    #[arg(long = "trace-csr", value_delimiter = ',', num_args = 0.., require_equals = true)]
+    trace_csr: Option<Vec<String>>,
+
+    /// Write the CSR trace to this file instead of the log.
+    #[arg(long = "trace-csr-file", requires = "trace_csr")]
+    trace_csr_file: Option<std::path::PathBuf>,
+
+    /// Trace the satp writes and the A/D bits the page table walker sets.
+    #[arg(long = "trace-mmu", default_value_t = false)]
+    trace_mmu: bool,
+
+    /// Write the MMU trace to this file instead of the log.
+    #[arg(long = "trace-mmu-file", requires = "trace_mmu")]
+    trace_mmu_file: Option<std::path::PathBuf>,
+
+    /// Restrict the CPU to an ISA, e.g. `RV64IMAC`. Defaults to all supported extensions.
+    #[arg(long = "isa")]
+    isa: Option<ISABuilder>,
+
+    /// Log why an instruction doesn't decode: the instruction it is and the extension it needs,
+    /// or the closest one. The guest still takes the illegal instruction trap.
+    #[arg(long = "explain-illegal", default_value_t = false)]
+    explain_illegal: bool,
+
+    /// Stop with the diagnostic of --explain-illegal instead of trapping when an instruction
+    /// doesn't decode, to find the extensions a binary needs.
+    #[arg(long = "strict-isa", default_value_t = false)]
+    strict_isa: bool,
+
+    /// Write the device tree source of the board, describing the ISA chosen by --isa, to this
+    /// file and exit.
+    #[arg(long = "dump-dts")]
+    dump_dts: Option<std::path::PathBuf>,
+
+    /// Kernel command line, put into the `bootargs` of the device tree and, NUL-terminated, into
+    /// the last page of RAM for custom loaders.
+    #[arg(long = "append", value_name = "ARGS")]
+    append: Option<String>,
+
+    /// Define extra custom CSRs from a TOML file, see `csr_reg::custom` for the format.
+    #[arg(long = "csr-config")]
+    csr_config: Option<std::path::PathBuf>,
+
+    /// Move the UART, PLIC, CLINT and VirtIO devices as described by a TOML file,
+    /// see `board::memory_map` for the format.
+    #[arg(long = "board")]
+    board_config: Option<std::path::PathBuf>,
+
+    /// Value of the `mvendorid` CSR (decimal or 0x-prefixed hex).
+    #[arg(long = "mvendorid", value_parser = parse_word, default_value = "0")]
+    mvendorid: u64,
+
+    /// Value of the `marchid` CSR.
+    #[arg(long = "marchid", value_parser = parse_word, default_value = "0")]
+    marchid: u64,
+
+    /// Value of the `mimpid` CSR.
+    #[arg(long = "mimpid", value_parser = parse_word, default_value = "0")]
+    mimpid: u64,
+
+    /// Value of the `mhartid` CSR.
+    #[arg(long = "mhartid", value_parser = parse_word, default_value = "0")]
+    mhartid: u64,
+
+    /// Derive all device time from the instruction count instead of the host clock, so the same
+    /// binary and inputs always give the same interrupt timing and traces.
+    #[arg(long = "deterministic", default_value_t = false)]
+    deterministic: bool,
+
+    /// Run a static Linux user binary in U-mode, serving its syscalls on the host.
+    #[arg(long = "user", default_value_t = false)]
+    user: bool,
+
+    /// Connect the UART to a TCP client on localhost:PORT instead of the terminal, waiting for
+    /// the client before the guest starts (e.g. `nc localhost PORT`).
+    #[arg(long = "serial-tcp", value_name = "PORT")]
+    serial_tcp: Option<u16>,
+
+    /// Stop when the serial output contains PATTERN, rvdb breaks into the prompt, otherwise the
+    /// hart state is dumped and the emulator exits with code 1. May be repeated.
+    #[arg(long = "panic-pattern", value_name = "PATTERN", action = clap::ArgAction::Append)]
+    panic_patterns: Vec<String>,
+
+    /// Same as --panic-pattern for the usual kernel crash messages ("Kernel panic", "Oops").
+    #[arg(long = "detect-panic", default_value_t = false)]
+    detect_panic: bool,
+
+    /// Warn when sp leaves the stack BOTTOM..TOP, given by addresses or ELF symbols (e.g.
+    /// --stack=_stack_bottom.._stack_top), or when the guest writes the guard bytes below it.
+    #[arg(long = "stack", value_name = "BOTTOM..TOP")]
+    stack: Option<StackSpec>,
+
+    /// Size in bytes of the guard below the stack of --stack.
+    #[arg(
+        long = "stack-guard",
+        value_name = "BYTES",
+        default_value_t = 256,
+        requires = "stack"
+    )]
+    stack_guard: WordType,
+
+    /// Warn when a return of the guest doesn't go back to the instruction after its call, e.g.
+    /// to a return address smashed on the stack.
+    #[arg(long = "shadow-stack", default_value_t = false)]
+    shadow_stack: bool,
+
+    /// Stop at the first problem found by --stack or --shadow-stack instead of warning, as
+    /// --panic-pattern does.
+    #[arg(
+        long = "stack-break",
+        default_value_t = false,
+        requires = "stack_checks"
+    )]
+    stack_break: bool,
+
+    /// Arguments passed to the guest program in --user mode, after `--`.
+    #[arg(last = true)]
+    guest_args: Vec<String>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Run seeded random instruction sequences in lockstep with a reference model, for soak testing.
+    Soak {
+        /// Seed of the first program, the next programs use the following seeds.
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Number of programs to run, 0 runs until a mismatch.
+        #[arg(long, default_value_t = 0)]
+        iterations: u64,
+
+        /// Instructions per program.
+        #[arg(long, default_value_t = 1024)]
+        length: usize,
+    },
+
+    /// Run seeded self-checking random programs on the whole board, as riscv-torture does.
+    Torture {
+        /// Seed of the first program, the next programs use the following seeds.
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Number of programs to run, 0 runs until a failure.
+        #[arg(long, default_value_t = 0)]
+        iterations: u64,
+
+        /// Instructions per program.
+        #[arg(long, default_value_t = 1024)]
+        length: usize,
+
+        /// Also step the reference model in lockstep, to report the instruction which diverged.
+        #[arg(long)]
+        cosim: bool,
+    },
+}
+
+impl Args {
+    /// Parse the command line, on top of the `--config` file if there is one.
+    fn parse_with_config() -> Self {
+        let args = Self::parse();
+        let Some(path) = &args.config else {
+            return args;
+        };
+        // The logger is configured by the arguments, so report like the other argument errors.
+        let config = RunConfig::load(path, &Self::command()).unwrap_or_else(|e| {
+            Self::command()
+                .error(ErrorKind::Io, format!("{}: {}", path.display(), e))
+                .exit()
+        });
+        let argv = config.merge(std::env::args_os().collect(), args.path.is_some());
+        let mut matches = Self::command().get_matches_from(argv);
+        let args = Self::from_arg_matches_mut(&mut matches).unwrap_or_else(|e| e.exit());
+        if args.path.is_none() && args.dump_dts.is_none() && args.command.is_none() {
+            Self::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "the target path is required, on the command line or as `image` in the configuration file",
+                )
+                .exit();
+        }
+        args
+    }
+}
+
+fn parse_word(s: &str) -> Result<u64, String> {
+    riscv_emulator::parse_u64(s).map_err(|e| format!("invalid number {}: {}", s, e))
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|secs| *secs > 0.0)
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| {
+            format!(
+                "invalid duration {}: expected a positive number of seconds",
+                s
+            )
+        })
 }
 
 /// Used for riscv-arch-test.
@@ -165,8 +564,167 @@ fn dump_signature(
     Ok(())
 }
 
+#[cfg(feature = "riscv64")]
+fn run_user_mode() -> ! {
+    use riscv_emulator::isa::riscv::user_mode::UserEmulator;
+
+    let bytes = std::fs::read(target_path()).expect("Failed to read target file");
+
+    let mut argv = vec![target_path().to_string_lossy().into_owned()];
+    argv.extend(cli_args.guest_args.iter().cloned());
+    let envp: Vec<String> = std::env::vars()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+
+    let mut emu = UserEmulator::from_elf(&bytes, &argv, &envp).unwrap_or_else(|e| {
+        log::error!("{}", e);
+        panic!();
+    });
+
+    if cli_args.strace {
+        let table = load_syscall_table();
+        emu.cpu_mut()
+            .set_syscall_tracer(Some(SyscallTracer::new(table)));
+    }
+
+    match emu.run() {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(feature = "riscv64"))]
+fn run_user_mode() -> ! {
+    log::error!("User-mode emulation is only supported on riscv64.");
+    panic!();
+}
+
+/// The target executable, clap only lets it be missing with --dump-dts.
+fn target_path() -> &'static std::path::Path {
+    cli_args
+        .path
+        .as_deref()
+        .expect("the target path is required")
+}
+
+fn dump_dts(out_path: &std::path::Path) -> ! {
+    let isa = cli_args.isa.clone().unwrap_or_else(ISABuilder::all);
+    if let Err(e) = std::fs::write(out_path, virt_dts(&isa, cli_args.append.as_deref())) {
+        log::error!("Failed to write {}: {}", out_path.display(), e);
+        panic!();
+    }
+    std::process::exit(0);
+}
+
+fn soak(first_seed: u64, iterations: u64, length: usize) -> ! {
+    let start = Instant::now();
+    let mut seed = first_seed;
+    loop {
+        if iterations != 0 && seed - first_seed == iterations {
+            break;
+        }
+
+        let program = RandomProgram::new(seed, length);
+        if let Err(e) = random_test::check(&program) {
+            eprintln!("{}", e);
+            eprintln!("{}", program.listing());
+            std::process::exit(1);
+        }
+
+        seed += 1;
+        if (seed - first_seed) % 1000 == 0 {
+            println!(
+                "{} programs passed ({:.1?})",
+                seed - first_seed,
+                start.elapsed()
+            );
+        }
+    }
+
+    println!("{} programs passed", iterations);
+    std::process::exit(0);
+}
+
+fn run_torture(first_seed: u64, iterations: u64, length: usize, cosim: bool) -> ! {
+    let start = Instant::now();
+    let mut seed = first_seed;
+    loop {
+        if iterations != 0 && seed - first_seed == iterations {
+            break;
+        }
+
+        let program = TortureProgram::new(seed, length);
+        if let Err(e) = torture::run(&program, cosim) {
+            eprintln!("{}", e);
+            eprintln!("{}", program.listing());
+            eprintln!(
+                "rerun with: torture --seed {} --iterations 1 --length {}{}",
+                seed,
+                length,
+                if cosim { " --cosim" } else { "" }
+            );
+            std::process::exit(1);
+        }
+
+        seed += 1;
+        if (seed - first_seed) % 1000 == 0 {
+            println!(
+                "{} programs passed ({:.1?})",
+                seed - first_seed,
+                start.elapsed()
+            );
+        }
+    }
+
+    println!("{} programs passed", iterations);
+    std::process::exit(0);
+}
+
+fn load_syscall_table() -> SyscallTable {
+    match &cli_args.syscall_table {
+        Some(path) => SyscallTable::from_file(path).unwrap_or_else(|e| {
+            log::error!("{}", e);
+            panic!();
+        }),
+        None => SyscallTable::linux(),
+    }
+}
+
 fn main() {
-    display_welcome_message();
+    match cli_args.command {
+        Some(Command::Soak {
+            seed,
+            iterations,
+            length,
+        }) => soak(seed, iterations, length),
+        Some(Command::Torture {
+            seed,
+            iterations,
+            length,
+            cosim,
+        }) => run_torture(seed, iterations, length, cosim),
+        None => {}
+    }
+
+    vclock::set_deterministic(cli_args.deterministic);
+    let _logger_handle = logging::init(
+        cli_args.log_level,
+        &cli_args.log_filters.clone().unwrap_or_default(),
+        cli_args.log_format,
+        cli_args.log_file.as_deref(),
+        cli_args.log_file_size,
+    );
+
+    if let Some(path) = &cli_args.dump_dts {
+        dump_dts(path);
+    }
+
+    if !cli_args.batch {
+        display_welcome_message();
+    }
 
     if cli_args.verbose {
         println!(
@@ -176,22 +734,115 @@ fn main() {
         display_device_list(&cli_args.devices);
     }
 
-    if cli_args.debug && cli_args.gdb {
+    if (cli_args.debug || cli_args.batch) && cli_args.gdb {
         log::error!("Cannot enable both rvdb and gdb.");
         panic!();
     }
+    if (cli_args.debug || cli_args.batch || cli_args.gdb) && cli_args.remote_bitbang.is_some() {
+        log::error!("Cannot enable remote_bitbang with rvdb or gdb.");
+        panic!();
+    }
 
     // Init emulator configuration by cli_args.
     let mut emu_cfg = EmulatorConfigurator::new();
     for device in cli_args.devices.iter() {
         emu_cfg = emu_cfg.append_device(device.clone())
     }
+    if let Some(action) = cli_args.watchdog {
+        emu_cfg = emu_cfg.watchdog(action);
+    }
+    if cli_args.no_irq_storm {
+        emu_cfg = emu_cfg.irq_storm(None);
+    } else if let Some(config) = cli_args.irq_storm {
+        emu_cfg = emu_cfg.irq_storm(Some(config));
+    }
+    emu_cfg = emu_cfg
+        .iommu(cli_args.iommu)
+        .dma(cli_args.dma)
+        .pwm(cli_args.pwm)
+        .rng(cli_args.rng)
+        .gpio(cli_args.gpio);
+    if let Some(seed) = cli_args.rng_seed {
+        emu_cfg = emu_cfg.rng_seed(seed);
+    }
+    if let Some(init) = cli_args.ram_init {
+        emu_cfg = emu_cfg.ram_init(init);
+    }
+    if let Some(script) = &cli_args.gpio_script {
+        emu_cfg = emu_cfg.gpio_script(script.clone());
+    }
+    if let Some(flash) = &cli_args.flash {
+        emu_cfg = match flash.strip_suffix(":ro") {
+            Some(path) => emu_cfg.flash(path.into(), true),
+            None => emu_cfg.flash(flash.into(), false),
+        };
+    }
+    if let Some(flash_vars) = &cli_args.flash_vars {
+        emu_cfg = match flash_vars.strip_suffix(":ro") {
+            Some(path) => emu_cfg.flash_vars(path.into(), true),
+            None => emu_cfg.flash_vars(flash_vars.into(), false),
+        };
+    }
+    if let Some(sd_card) = &cli_args.sd_card {
+        emu_cfg = match sd_card.strip_suffix(":ro") {
+            Some(path) => emu_cfg.sd_card(path.into(), true),
+            None => emu_cfg.sd_card(sd_card.into(), false),
+        };
+    }
+    for device in cli_args.spi_devices.iter() {
+        emu_cfg = emu_cfg.append_spi_device(device.clone());
+    }
+    for device in cli_args.i2c_devices.iter() {
+        emu_cfg = emu_cfg.append_i2c_device(device.clone());
+    }
+    if let Some(script) = &cli_args.scripted_device {
+        emu_cfg = emu_cfg.scripted_device(script.clone());
+    }
+    if let Some(isa) = &cli_args.isa {
+        emu_cfg = emu_cfg.isa(isa.clone());
+    }
+    if let Some(port) = cli_args.serial_tcp {
+        emu_cfg = emu_cfg.serial_tcp(port);
+    }
+    if let Some(bootargs) = &cli_args.append {
+        emu_cfg = emu_cfg.bootargs(bootargs.clone());
+    }
+    emu_cfg = emu_cfg.identity(HartIdentity {
+        vendor_id: cli_args.mvendorid as WordType,
+        arch_id: cli_args.marchid as WordType,
+        imp_id: cli_args.mimpid as WordType,
+        hart_id: cli_args.mhartid as WordType,
+    });
+    if let Some(path) = &cli_args.csr_config {
+        match load_custom_csrs(path) {
+            Ok(csrs) => emu_cfg = emu_cfg.custom_csrs(csrs),
+            Err(e) => {
+                log::error!("{}", e);
+                panic!();
+            }
+        }
+    }
+    if let Some(path) = &cli_args.board_config {
+        match MemoryMap::load(path) {
+            Ok(map) => emu_cfg = emu_cfg.memory_map(map),
+            Err(e) => {
+                log::error!("{}", e);
+                panic!();
+            }
+        }
+    }
+    let mut panic_patterns = cli_args.panic_patterns.clone();
+    if cli_args.detect_panic {
+        panic_patterns.extend(DEFAULT_PANIC_PATTERNS.map(String::from));
+    }
+    emu_cfg = emu_cfg.panic_patterns(panic_patterns);
     drop(emu_cfg);
 
-    let _logger_handle = logging::init(cli_args.log_level);
+    if cli_args.user {
+        run_user_mode();
+    }
 
-    let ext = cli_args
-        .path
+    let ext = target_path()
         .extension()
         .and_then(|ext| ext.to_str())
         .unwrap_or("<unknown>");
@@ -201,7 +852,7 @@ fn main() {
             if cli_args.verbose {
                 println!("ELF file detected\r");
             }
-            let bytes = std::fs::read(cli_args.path.clone()).expect("Failed to read target file");
+            let bytes = std::fs::read(target_path()).expect("Failed to read target file");
             VirtBoard::from_elf(bytes)
         }
 
@@ -209,7 +860,7 @@ fn main() {
             if cli_args.verbose {
                 println!("Binary file detected\r");
             }
-            let bytes = std::fs::read(cli_args.path.clone()).expect("Failed to read target file");
+            let bytes = std::fs::read(target_path()).expect("Failed to read target file");
             VirtBoard::from_binary(&bytes)
         }
 
@@ -219,19 +870,158 @@ fn main() {
         }
     };
 
-    if cli_args.debug {
+    if cli_args.strace {
+        let table = load_syscall_table();
+        board
+            .cpu
+            .set_syscall_tracer(Some(SyscallTracer::new(table)));
+    }
+
+    if cli_args.ftrace {
+        match board.loader().and_then(|loader| loader.get_symbol_table()) {
+            Some(symtab) => board
+                .cpu
+                .set_function_tracer(Some(FunctionTracer::new(&symtab))),
+            None => log::error!("--ftrace needs an ELF with a symbol table"),
+        }
+    }
+
+    let profiler = cli_args.profile.as_ref().and_then(|path| {
+        let Some(symtab) = board.loader().and_then(|loader| loader.get_symbol_table()) else {
+            log::error!("--profile needs an ELF with a symbol table");
+            return None;
+        };
+        let model = cli_args.profile_weights.clone().unwrap_or_default();
+        let (profiler, _) =
+            CostProfiler::new(&symtab, model, cli_args.profile_period).attach(&mut board.cpu);
+        Some((profiler, path))
+    });
+    if let Some(spec) = &cli_args.stack {
+        let symtab = board.loader().and_then(|loader| loader.get_symbol_table());
+        match spec.resolve(symtab.as_ref()) {
+            Ok(stack) => {
+                let mut guard = StackGuard::new(stack, cli_args.stack_guard);
+                if cli_args.stack_break {
+                    guard = guard.break_on_violation(board.board_control());
+                }
+                guard.attach(&mut board.cpu);
+            }
+            Err(e) => {
+                log::error!("--stack: {}", e);
+                panic!();
+            }
+        }
+    }
+    if cli_args.shadow_stack {
+        let mut shadow = ShadowStack::new();
+        if let Some(symtab) = board.loader().and_then(|loader| loader.get_symbol_table()) {
+            shadow = shadow.symbols(symtab);
+        }
+        if cli_args.stack_break {
+            shadow = shadow.break_on_mismatch(board.board_control());
+        }
+        shadow.attach(&mut board.cpu);
+    }
+    if cli_args.strict_isa {
+        board.cpu.set_undecodable_action(UndecodableAction::Halt);
+    } else if cli_args.explain_illegal {
+        board.cpu.set_undecodable_action(UndecodableAction::Explain);
+    }
+    if cli_args.istats.is_some() {
+        board.cpu.enable_instr_stats();
+    }
+    let write_reports = |board: &VirtBoard| {
+        if let Some((profiler, path)) = &profiler {
+            let result = fs::File::create(path)
+                .and_then(|file| profiler.borrow().write_csv(std::io::BufWriter::new(file)));
+            if let Err(e) = result {
+                log::error!("Failed to write profile {}: {}", path.display(), e);
+            }
+        }
+        if let Some(path) = &cli_args.istats
+            && let Some(stats) = board.cpu.instr_stats()
+        {
+            let json = serde_json::to_string_pretty(&stats.snapshot()).unwrap();
+            if let Err(e) = fs::write(path, json) {
+                log::error!(
+                    "Failed to write instruction stats {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    };
+
+    if let Some(devices) = &cli_args.trace_mmio {
+        let tracer = match &cli_args.trace_mmio_file {
+            Some(path) => MmioTracer::to_file(path).unwrap_or_else(|e| {
+                log::error!("Failed to create MMIO trace file {}: {}", path.display(), e);
+                panic!();
+            }),
+            None => MmioTracer::to_log(),
+        };
+        board
+            .cpu
+            .set_mmio_tracer(Some(tracer.filter(devices.clone())));
+    }
+
+    if let Some(csrs) = &cli_args.trace_csr {
+        let tracer = match &cli_args.trace_csr_file {
+            Some(path) => CsrTracer::to_file(path).unwrap_or_else(|e| {
+                log::error!("Failed to create CSR trace file {}: {}", path.display(), e);
+                panic!();
+            }),
+            None => CsrTracer::to_log(),
+        };
+        match tracer.filter(csrs) {
+            Ok(tracer) => board.cpu.set_csr_tracer(Some(tracer)),
+            Err(e) => {
+                log::error!("--trace-csr: {}", e);
+                panic!();
+            }
+        }
+    }
+
+    if cli_args.trace_mmu {
+        let tracer = match &cli_args.trace_mmu_file {
+            Some(path) => MmuTracer::to_file(path).unwrap_or_else(|e| {
+                log::error!("Failed to create MMU trace file {}: {}", path.display(), e);
+                panic!();
+            }),
+            None => MmuTracer::to_log(),
+        };
+        board.cpu.set_mmu_tracer(Some(tracer));
+    }
+
+    if cli_args.debug || cli_args.batch {
         let mut repl = DebugREPL::new(&mut board);
+        repl.set_batch(cli_args.batch);
         if let Some(script) = &cli_args.script {
             let script_content = std::fs::read_to_string(script).unwrap();
             let lines: Vec<String> = script_content.lines().map(|s| s.to_string()).collect();
             repl.run_script(&lines);
         }
-        repl.run();
+        let failed = cli_args.batch && repl.failed();
+        if !cli_args.batch {
+            repl.run();
+        }
+        drop(repl);
+        write_reports(&board);
+        if failed {
+            std::process::exit(1);
+        }
     } else if cli_args.gdb {
         if let Err(e) = gdb::event_loop(&mut board, gdb::Config::Tcp(1234)) {
             log::error!("{:?}", e);
             panic!();
         }
+        write_reports(&board);
+    } else if let Some(port) = cli_args.remote_bitbang {
+        if let Err(e) = remote_bitbang::event_loop(&mut board, port) {
+            log::error!("{:?}", e);
+            panic!();
+        }
+        write_reports(&board);
     } else {
         if let Some(sig_path) = &cli_args.signature {
             // Create the signature file before running the emulator to ensure the file exists even if the emulator crashes.
@@ -241,9 +1031,17 @@ fn main() {
         crossterm::terminal::enable_raw_mode().unwrap();
 
         let now = Instant::now();
+        let heartbeat = cli_args.heartbeat;
+        let mut last_heartbeat = now;
+        let mut failed = false;
         loop {
-            if board.status() == riscv_emulator::board::BoardStatus::Halt {
-                break;
+            match board.status() {
+                BoardStatus::Halt => break,
+                BoardStatus::Resetting => {
+                    board.reset();
+                    continue;
+                }
+                BoardStatus::Running => {}
             }
 
             if let Err(e) = board.step() {
@@ -251,6 +1049,22 @@ fn main() {
                 break;
             }
 
+            if let Some(reason) = board.take_break() {
+                log::error!("Stopped: {}\r", reason);
+                println!("\r\n{}\r", board.dump_state().replace('\n', "\r\n"));
+                failed = true;
+                break;
+            }
+
+            if let Some(interval) = heartbeat
+                && board.clock.now().is_multiple_of(HEARTBEAT_CHECK_CYCLES)
+                && last_heartbeat.elapsed() >= interval
+            {
+                last_heartbeat = Instant::now();
+                let _console = CliCoordinator::global().lock_console();
+                eprint!("[heartbeat {:.0?}] {}\r\n", now.elapsed(), board.progress());
+            }
+
             if cli_args.max_cycles != 0 && board.clock.now() >= cli_args.max_cycles {
                 log::error!("Max cycles reached: {}", cli_args.max_cycles);
                 break;
@@ -268,8 +1082,12 @@ fn main() {
             }
         }
 
+        write_reports(&board);
         drop(board);
 
         println!("Used time: {}s", now.elapsed().as_secs_f32());
+        if failed {
+            std::process::exit(1);
+        }
     }
 }
@@ -1,5 +1,11 @@
 use core::panic;
-use std::ops::{Index, IndexMut};
+use std::{
+    ops::{Index, IndexMut},
+    str::FromStr,
+};
+
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha12Rng;
 
 use crate::{
     config::arch_config::WordType,
@@ -23,6 +29,44 @@ impl Reservation {
     }
 }
 
+/// What the RAM holds before the images are loaded, so a guest reading memory it never wrote
+/// gets the same, recognizable, bytes on every run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamInit {
+    /// Every byte, e.g. `0xaa`.
+    Byte(u8),
+    /// Pseudo-random bytes from the seed, a random seed if `None`.
+    Random(Option<u64>),
+}
+
+impl FromStr for RamInit {
+    type Err = String;
+
+    /// `zero`, a byte such as `0xaa`, `random` or `random:SEED`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(Self::Byte(0)),
+            "random" => Ok(Self::Random(None)),
+            _ => {
+                if let Some(seed) = s.strip_prefix("random:") {
+                    let seed =
+                        crate::parse_u64(seed).map_err(|_| format!("Invalid seed: {seed}"))?;
+                    return Ok(Self::Random(Some(seed)));
+                }
+                crate::parse_u64(s)
+                    .ok()
+                    .and_then(|byte| u8::try_from(byte).ok())
+                    .map(Self::Byte)
+                    .ok_or_else(|| format!("Unknown RAM pattern: {s}"))
+            }
+        }
+    }
+}
+
+fn fill_random(data: &mut [u8], seed: u64) {
+    ChaCha12Rng::seed_from_u64(seed).fill_bytes(data);
+}
+
 pub struct Ram {
     // TODO: 4KB align the inner box ptr for better performance.
     data: Box<[u8]>,
@@ -57,6 +101,17 @@ impl Ram {
         }
     }
 
+    /// Filled with the stream of ChaCha12 seeded with `seed`. Unlike zeroed memory, every page
+    /// of RAM is touched up front.
+    pub fn with_random(seed: u64) -> Self {
+        let mut data = vec![0u8; ram_config::SIZE].into_boxed_slice();
+        fill_random(&mut data, seed);
+        Self {
+            data,
+            reserved: None,
+        }
+    }
+
     pub fn with_data(mut data: Vec<u8>) -> Self {
         if data.len() > ram_config::SIZE {
             log::error!(
@@ -74,27 +129,33 @@ impl Ram {
     }
 
     pub fn insert_section(&mut self, elf_section_data: &[u8], start_addr: WordType) {
-        let Some(end_addr) = (start_addr as usize).checked_add(elf_section_data.len()) else {
-            log::error!(
-                "ram::insert_section address overflow! start_addr = {}, len = {}",
-                start_addr,
-                elf_section_data.len()
-            );
-            panic!();
-        };
-        if end_addr > ram_config::SIZE {
+        let Some(range) = Self::offset_range(start_addr, elf_section_data.len()) else {
             log::error!(
                 "ram::insert_section out of range! start_addr = {}, len = {}",
                 start_addr,
                 elf_section_data.len()
             );
             panic!();
-        }
+        };
+        self.data[range].copy_from_slice(elf_section_data);
+    }
 
-        let start_addr = start_addr as usize;
-        elf_section_data.iter().enumerate().for_each(|(index, v)| {
-            self.data[start_addr + index] = *v;
-        });
+    /// The `len` bytes at the guest-physical address `paddr`, for devices and loaders to access
+    /// guest memory without going through a `read` or `write` per word.
+    ///
+    /// Fails with [`MemError::LoadFault`] unless the whole range is in RAM.
+    pub fn slice(&mut self, paddr: WordType, len: usize) -> Result<&mut [u8], MemError> {
+        let range = paddr
+            .checked_sub(ram_config::BASE_ADDR)
+            .and_then(|offset| Self::offset_range(offset, len))
+            .ok_or(MemError::LoadFault)?;
+        Ok(&mut self.data[range])
+    }
+
+    fn offset_range(offset: WordType, len: usize) -> Option<std::ops::Range<usize>> {
+        let start = usize::try_from(offset).ok()?;
+        let end = start.checked_add(len)?;
+        (end <= ram_config::SIZE).then_some(start..end)
     }
 
     pub fn read<T>(&self, addr: WordType) -> Result<T, MemError> {
@@ -148,12 +209,7 @@ impl Ram {
     }
 
     fn contains_access<T>(addr: WordType) -> bool {
-        let Ok(start) = usize::try_from(addr) else {
-            return false;
-        };
-        start
-            .checked_add(size_of::<T>())
-            .is_some_and(|end| end <= ram_config::SIZE)
+        Self::offset_range(addr, size_of::<T>()).is_some()
     }
     #[cfg(test)]
     pub fn get_raw_ptr(&mut self) -> *mut u8 {
@@ -268,6 +324,47 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_ram_init() {
+        assert_eq!("zero".parse(), Ok(RamInit::Byte(0)));
+        assert_eq!("0xaa".parse(), Ok(RamInit::Byte(0xaa)));
+        assert_eq!("random".parse(), Ok(RamInit::Random(None)));
+        assert_eq!("random:42".parse(), Ok(RamInit::Random(Some(42))));
+        assert!("0x100".parse::<RamInit>().is_err());
+        assert!("random:x".parse::<RamInit>().is_err());
+
+        let random = |seed| {
+            let mut data = [0u8; 64];
+            fill_random(&mut data, seed);
+            data
+        };
+        assert_eq!(random(42), random(42));
+        assert_ne!(random(42), random(43));
+    }
+
+    #[test]
+    fn test_slice() {
+        let mut ram = Ram::new();
+        let end = ram_config::BASE_ADDR + ram_config::SIZE as WordType;
+
+        ram.slice(ram_config::BASE_ADDR + 0x10, 4)
+            .unwrap()
+            .copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(ram.read::<u32>(0x10).unwrap(), 0x0403_0201);
+        assert_eq!(ram.slice(end - 4, 4).unwrap().len(), 4);
+        assert!(ram.slice(end, 0).unwrap().is_empty());
+
+        assert_eq!(ram.slice(end - 4, 8), Err(MemError::LoadFault));
+        assert_eq!(
+            ram.slice(ram_config::BASE_ADDR - 1, 1),
+            Err(MemError::LoadFault)
+        );
+        assert_eq!(
+            ram.slice(WordType::MAX, usize::MAX),
+            Err(MemError::LoadFault)
+        );
+    }
+
     #[test]
     #[should_panic]
     fn test_insert_section_rejects_crossing_end() {
@@ -0,0 +1,362 @@
+//! OpenOCD `remote_bitbang` server: a JTAG Debug Transport Module in front of the
+//! [`DebugModule`], so OpenOCD drives the emulator as it drives a board through a JTAG adapter.
+//!
+//! Each byte from OpenOCD is a command:
+//!
+//! - `0`-`7`: set TCK, TMS and TDI (bits 2, 1 and 0).
+//! - `R`: read TDO, answered with `0` or `1`.
+//! - `r`-`u`: set TRST and SRST (bits 1 and 0 of the offset from `r`). TRST resets the TAP, SRST
+//!   is ignored: the Debug Module resets the system with `dmcontrol.ndmreset`.
+//! - `B`, `b`: blink, ignored.
+//! - `Q`: quit, the server returns.
+//!
+//! The TAP has a 5-bit instruction register with the instructions of the debug specification:
+//! `IDCODE`, `dtmcs`, `dmi` and `BYPASS`. A DMI access completes on Update-DR, it is never busy.
+//! The `IDCODE` is [`IDCODE`], the value of Spike, so its OpenOCD configurations work as they are:
+//!
+//! ```text
+//! adapter driver remote_bitbang
+//! remote_bitbang host localhost
+//! remote_bitbang port 9824
+//! transport select jtag
+//! jtag newtap riscv cpu -irlen 5 -expected-id 0xdeadbeef
+//! target create riscv.cpu riscv -chain-position riscv.cpu
+//! ```
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{board::Board, isa::riscv::debug_module::DebugModule};
+
+pub const IDCODE: u32 = 0xdead_beef;
+
+const IR_LEN: u32 = 5;
+const IR_IDCODE: u32 = 0x01;
+const IR_DTMCS: u32 = 0x10;
+const IR_DMI: u32 = 0x11;
+
+/// Width of a DMI address, the DM registers are below 0x80.
+const DMI_ABITS: u32 = 7;
+const DMI_OP_READ: u64 = 1;
+const DMI_OP_WRITE: u64 = 2;
+
+/// Debug specification 0.13 and 1.0.
+const DTMCS_VERSION: u32 = 1;
+const DTMCS_ABITS_SHIFT: u32 = 4;
+
+/// Instructions executed between two polls of the connection.
+const STEPS_PER_POLL: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TapState {
+    TestLogicReset,
+    RunTestIdle,
+    SelectDrScan,
+    CaptureDr,
+    ShiftDr,
+    Exit1Dr,
+    PauseDr,
+    Exit2Dr,
+    UpdateDr,
+    SelectIrScan,
+    CaptureIr,
+    ShiftIr,
+    Exit1Ir,
+    PauseIr,
+    Exit2Ir,
+    UpdateIr,
+}
+
+impl TapState {
+    fn next(self, tms: bool) -> Self {
+        use TapState::*;
+        match (self, tms) {
+            (TestLogicReset, false) => RunTestIdle,
+            (TestLogicReset, true) => TestLogicReset,
+            (RunTestIdle | UpdateDr | UpdateIr, false) => RunTestIdle,
+            (RunTestIdle | UpdateDr | UpdateIr, true) => SelectDrScan,
+            (SelectDrScan, false) => CaptureDr,
+            (SelectDrScan, true) => SelectIrScan,
+            (CaptureDr | ShiftDr | Exit2Dr, false) => ShiftDr,
+            (CaptureDr | ShiftDr, true) => Exit1Dr,
+            (Exit1Dr | PauseDr, false) => PauseDr,
+            (Exit1Dr | Exit2Dr, true) => UpdateDr,
+            (PauseDr, true) => Exit2Dr,
+            (SelectIrScan, false) => CaptureIr,
+            (SelectIrScan, true) => TestLogicReset,
+            (CaptureIr | ShiftIr | Exit2Ir, false) => ShiftIr,
+            (CaptureIr | ShiftIr, true) => Exit1Ir,
+            (Exit1Ir | PauseIr, false) => PauseIr,
+            (Exit1Ir | Exit2Ir, true) => UpdateIr,
+            (PauseIr, true) => Exit2Ir,
+        }
+    }
+}
+
+/// The TAP and the data registers of the JTAG DTM.
+pub struct JtagDtm {
+    state: TapState,
+    tck: bool,
+    tdo: bool,
+
+    ir: u32,
+    /// The data register being shifted, `dr_len` bits.
+    dr: u64,
+    dr_len: u32,
+    /// The address and the data of the last DMI access, as captured by `dmi`.
+    dmi: u64,
+}
+
+impl JtagDtm {
+    pub fn new() -> Self {
+        Self {
+            state: TapState::TestLogicReset,
+            tck: false,
+            tdo: false,
+            ir: IR_IDCODE,
+            dr: 0,
+            dr_len: 1,
+            dmi: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = TapState::TestLogicReset;
+        self.ir = IR_IDCODE;
+    }
+
+    pub fn tdo(&self) -> bool {
+        self.tdo
+    }
+
+    /// Drive the pins. TMS and TDI are sampled on the rising edge of TCK, the TAP acts and TDO
+    /// changes on the falling edge.
+    pub fn set_pins<B: Board>(
+        &mut self,
+        tck: bool,
+        tms: bool,
+        tdi: bool,
+        dm: &mut DebugModule<'_, B>,
+    ) {
+        if tck && !self.tck {
+            match self.state {
+                TapState::ShiftDr | TapState::ShiftIr => {
+                    self.dr = self.dr >> 1 | (tdi as u64) << (self.dr_len - 1);
+                }
+                _ => {}
+            }
+            self.state = self.state.next(tms);
+        } else if !tck && self.tck {
+            match self.state {
+                TapState::TestLogicReset => self.ir = IR_IDCODE,
+                TapState::CaptureDr => self.capture_dr(),
+                TapState::UpdateDr => self.update_dr(dm),
+                TapState::CaptureIr => {
+                    self.dr = 0b01;
+                    self.dr_len = IR_LEN;
+                }
+                TapState::UpdateIr => self.ir = self.dr as u32,
+                TapState::ShiftDr | TapState::ShiftIr => self.tdo = self.dr & 1 != 0,
+                _ => {}
+            }
+        }
+        self.tck = tck;
+    }
+
+    fn dmi_len() -> u32 {
+        DMI_ABITS + 34
+    }
+
+    fn capture_dr(&mut self) {
+        (self.dr, self.dr_len) = match self.ir {
+            IR_IDCODE => (IDCODE as u64, 32),
+            IR_DTMCS => ((DTMCS_VERSION | DMI_ABITS << DTMCS_ABITS_SHIFT) as u64, 32),
+            // The op field reads 0, the previous access succeeded.
+            IR_DMI => (self.dmi, Self::dmi_len()),
+            _ => (0, 1),
+        };
+    }
+
+    fn update_dr<B: Board>(&mut self, dm: &mut DebugModule<'_, B>) {
+        // Writes to `dtmcs` only reset the DMI, which has no error to clear.
+        if self.ir != IR_DMI {
+            return;
+        }
+        let addr = (self.dr >> 34) as u32 & ((1 << DMI_ABITS) - 1);
+        let data = (self.dr >> 2) as u32;
+        match self.dr & 0x3 {
+            DMI_OP_READ => {
+                let value = dm.dmi_read(addr);
+                self.dmi = (addr as u64) << 34 | (value as u64) << 2;
+            }
+            DMI_OP_WRITE => {
+                dm.dmi_write(addr, data);
+                self.dmi = (addr as u64) << 34 | (data as u64) << 2;
+            }
+            _ => {}
+        }
+    }
+
+    /// Handle a command byte, returns the byte to answer.
+    pub fn command<B: Board>(&mut self, cmd: u8, dm: &mut DebugModule<'_, B>) -> Option<u8> {
+        match cmd {
+            b'0'..=b'7' => {
+                let pins = cmd - b'0';
+                self.set_pins(pins & 4 != 0, pins & 2 != 0, pins & 1 != 0, dm);
+            }
+            b'R' => return Some(if self.tdo { b'1' } else { b'0' }),
+            b'r'..=b'u' => {
+                if (cmd - b'r') & 2 != 0 {
+                    self.reset();
+                }
+            }
+            b'B' | b'b' | b'Q' => {}
+            _ => log::debug!("[JTAG] unknown remote_bitbang command {:#04x}", cmd),
+        }
+        None
+    }
+}
+
+impl Default for JtagDtm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Serve OpenOCD on `localhost:port` until it quits or disconnects. The board runs between the
+/// commands while the hart is not halted.
+pub fn event_loop(board: &mut impl Board, port: u16) -> DynResult<()> {
+    let sockaddr = format!("127.0.0.1:{}", port);
+    eprintln!(
+        "Waiting for a remote_bitbang connection on {:?}...",
+        sockaddr
+    );
+    let (mut stream, addr) = TcpListener::bind(sockaddr)?.accept()?;
+    eprintln!("OpenOCD connected from {}", addr);
+    stream.set_nodelay(true)?;
+
+    let mut dm = DebugModule::new(board);
+    let mut dtm = JtagDtm::new();
+    let mut buf = [0u8; 4096];
+    let mut replies = Vec::new();
+    loop {
+        // Wait for OpenOCD only while the hart has nothing to execute.
+        stream.set_nonblocking(dm.running())?;
+        let len = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                dm.run(STEPS_PER_POLL)?;
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        replies.clear();
+        for &cmd in &buf[..len] {
+            if cmd == b'Q' {
+                flush(&mut stream, &replies)?;
+                eprintln!("OpenOCD quit");
+                return Ok(());
+            }
+            replies.extend(dtm.command(cmd, &mut dm));
+        }
+        flush(&mut stream, &replies)?;
+    }
+    eprintln!("OpenOCD disconnected");
+    Ok(())
+}
+
+fn flush(stream: &mut TcpStream, replies: &[u8]) -> std::io::Result<()> {
+    if replies.is_empty() {
+        return Ok(());
+    }
+    stream.set_nonblocking(false)?;
+    stream.write_all(replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{board::virt::RVBoardBuilder, ram::Ram};
+
+    /// Drives the TAP as OpenOCD does, one clock per bit.
+    struct Driver<'a, 'b, B: Board> {
+        dtm: JtagDtm,
+        dm: &'a mut DebugModule<'b, B>,
+    }
+
+    impl<B: Board> Driver<'_, '_, B> {
+        fn clock(&mut self, tms: bool, tdi: bool) -> bool {
+            let pins = (tms as u8) << 1 | tdi as u8;
+            self.dtm.command(b'0' + pins, self.dm);
+            let tdo = self.dtm.command(b'R', self.dm) == Some(b'1');
+            self.dtm.command(b'4' + pins, self.dm);
+            tdo
+        }
+
+        /// From Run-Test/Idle, shift `len` bits through the IR or a DR and return to it.
+        fn scan(&mut self, ir: bool, value: u64, len: u32) -> u64 {
+            self.clock(true, false);
+            if ir {
+                self.clock(true, false);
+            }
+            self.clock(false, false);
+            self.clock(false, false);
+            let mut out = 0;
+            for i in 0..len {
+                let tdo = self.clock(i == len - 1, value >> i & 1 != 0);
+                out |= (tdo as u64) << i;
+            }
+            self.clock(true, false);
+            self.clock(false, false);
+            out
+        }
+
+        fn dmi(&mut self, addr: u32, data: u32, op: u64) -> u32 {
+            let request = (addr as u64) << 34 | (data as u64) << 2 | op;
+            self.scan(false, request, JtagDtm::dmi_len());
+            (self.scan(false, 0, JtagDtm::dmi_len()) >> 2) as u32
+        }
+    }
+
+    #[test]
+    fn test_jtag_dtm() {
+        let mut board = RVBoardBuilder::new().build(Ram::new());
+        let mut dm = DebugModule::new(&mut board);
+        let mut jtag = Driver {
+            dtm: JtagDtm::new(),
+            dm: &mut dm,
+        };
+        for _ in 0..5 {
+            jtag.clock(true, false);
+        }
+        jtag.clock(false, false);
+
+        // IDCODE is selected after a reset.
+        assert_eq!(jtag.scan(false, 0, 32), IDCODE as u64);
+        assert_eq!(jtag.scan(true, IR_DTMCS as u64, IR_LEN), 0b01);
+        let dtmcs = jtag.scan(false, 0, 32) as u32;
+        assert_eq!(dtmcs & 0xf, DTMCS_VERSION);
+        assert_eq!(dtmcs >> DTMCS_ABITS_SHIFT & 0x3f, DMI_ABITS);
+
+        // Activate the DM and halt the hart through the DMI.
+        jtag.scan(true, IR_DMI as u64, IR_LEN);
+        jtag.dmi(0x10, 1, DMI_OP_WRITE);
+        assert_eq!(jtag.dmi(0x10, 0, DMI_OP_READ), 1);
+        jtag.dmi(0x10, 1 << 31 | 1, DMI_OP_WRITE);
+        jtag.dm.run(1).unwrap();
+        assert!(jtag.dm.halted());
+        assert_ne!(jtag.dmi(0x11, 0, DMI_OP_READ) & 1 << 9, 0);
+
+        // TRST selects IDCODE again.
+        jtag.dtm.command(b't', jtag.dm);
+        jtag.clock(false, false);
+        assert_eq!(jtag.scan(false, 0, 32), IDCODE as u64);
+    }
+}
@@ -0,0 +1,174 @@
+//! Tab completion of rvdb command lines: command names from the [`Cli`] definition, then the
+//! names of registers, CSRs or symbols the command takes.
+
+use clap::{Command, CommandFactory};
+use rustyline::{
+    Context, Helper, completion::Completer, highlight::Highlighter, hint::Hinter,
+    validate::Validator,
+};
+
+use super::Cli;
+use crate::{
+    config::arch_config::{FLOAT_REG_NAME, REG_NAME, VECTOR_REG_NAME},
+    isa::riscv::csr_reg::csr_macro::CSR_ADDRESS,
+};
+
+/// Positional arguments which take an address or a symbol.
+const SYMBOL_ARGS: [&str; 8] = [
+    "addr", "addr1", "addr2", "start", "symbol", "alloc", "free", "item",
+];
+
+pub struct ReplHelper {
+    cli: Command,
+    /// Sorted symbol names of the loaded ELF.
+    symbols: Vec<String>,
+}
+
+impl Default for ReplHelper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplHelper {
+    pub fn new() -> Self {
+        let mut cli = Cli::command();
+        cli.build();
+        Self {
+            cli,
+            symbols: Vec::new(),
+        }
+    }
+
+    pub fn set_symbols(&mut self, mut symbols: Vec<String>) {
+        symbols.sort_unstable();
+        self.symbols = symbols;
+    }
+
+    /// The completions of the word before the end of `line`, and where that word starts.
+    fn candidates(&self, line: &str) -> (usize, Vec<String>) {
+        let start = line.rfind(char::is_whitespace).map_or(0, |idx| idx + 1);
+        let word = &line[start..];
+        let mut words = line[..start].split_whitespace().peekable();
+
+        // `help` takes a command, completed as at the start of a line.
+        if words.peek().is_some_and(|first| {
+            self.cli
+                .find_subcommand(first)
+                .is_some_and(|cmd| cmd.get_name() == "help")
+        }) {
+            words.next();
+        }
+
+        let mut cmd = &self.cli;
+        let mut has_args = false;
+        for word in words {
+            match cmd.find_subcommand(word) {
+                Some(sub) if !has_args => cmd = sub,
+                _ => has_args = true,
+            }
+        }
+
+        let mut candidates: Vec<String> = if word.starts_with('-') {
+            cmd.get_arguments()
+                .filter_map(|arg| arg.get_long())
+                .map(|long| format!("--{}", long))
+                .collect()
+        } else if cmd.has_subcommands() && !has_args {
+            cmd.get_subcommands()
+                .filter(|sub| !sub.is_hide_set())
+                .map(|sub| sub.get_name().to_string())
+                .collect()
+        } else {
+            self.arg_names(cmd)
+        };
+        candidates.retain(|candidate| candidate.starts_with(word));
+        candidates.sort();
+        candidates.dedup();
+        (start, candidates)
+    }
+
+    /// The names the positional arguments of `cmd` may take.
+    fn arg_names(&self, cmd: &Command) -> Vec<String> {
+        let registers = |names: &[&str]| -> Vec<String> {
+            names
+                .iter()
+                .flat_map(|name| name.split('/'))
+                .map(str::to_string)
+                .collect()
+        };
+        match cmd.get_name() {
+            "reg" => registers(&REG_NAME),
+            "f-reg" => registers(&FLOAT_REG_NAME),
+            "v-reg" => registers(&VECTOR_REG_NAME),
+            "csr" => CSR_ADDRESS.keys().map(|name| name.to_string()).collect(),
+            _ if cmd
+                .get_positionals()
+                .any(|arg| SYMBOL_ARGS.contains(&arg.get_id().as_str())) =>
+            {
+                let mut names = self.symbols.clone();
+                if cmd.get_name() == "taint" {
+                    names.extend(registers(&REG_NAME));
+                }
+                names
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(&line[..pos]))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        let mut helper = ReplHelper::new();
+        helper.set_symbols(vec!["main".to_string(), "memcpy".to_string()]);
+        let complete = |line: &str| helper.candidates(line).1;
+
+        assert_eq!(
+            helper.candidates("info ist"),
+            (5, vec!["istats".to_string()])
+        );
+        assert!(complete("").contains(&"continue".to_string()));
+        assert_eq!(complete("sym"), ["symbol-file"]);
+        assert_eq!(complete("p r"), ["reg", "regs"]);
+        assert_eq!(
+            complete("print reg s"),
+            [
+                "s0", "s1", "s10", "s11", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "sp"
+            ]
+        );
+        assert_eq!(complete("p reg f"), ["fp"]);
+        assert_eq!(complete("print csr mstat"), ["mstatus"]);
+        assert_eq!(complete("break m"), ["main", "memcpy"]);
+        assert_eq!(complete("b -"), ["--delete", "--hart", "--help", "--virt"]);
+        assert_eq!(complete("help info reg"), ["registers"]);
+        assert!(complete("continue ").is_empty());
+        assert!(complete("si ").is_empty());
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::process::exit;
+
+use super::CommandOutput;
+use super::completer::ReplHelper;
+use super::handler::Handler;
+use super::printer::Printer;
+use super::script::DebugScript;
+use crate::{board::Board, cli_coordinator::CliCoordinator, config::arch_config::WordType};
+use rustyline::{CompletionType, Config, Editor, error::ReadlineError, history::DefaultHistory};
+
+const PROMPT: &str = "(rvdb) ";
+
+pub struct DebugREPL<'a, B: Board> {
+    editor: Editor<ReplHelper, DefaultHistory>,
+    handler: Handler<'a, B>,
+    printer: Printer,
+    /// Variables of the scripts, kept from one to the next.
+    vars: HashMap<String, WordType>,
+    /// Don't echo script commands, only their results.
+    batch: bool,
+    /// Whether a script command failed.
+    failed: bool,
+}
+
+impl<'a, B: Board> DebugREPL<'a, B> {
+    pub fn new(board: &'a mut B) -> Self {
+        CliCoordinator::global().pause_uart();
+        let config = Config::builder()
+            .completion_type(CompletionType::List)
+            .build();
+        let mut editor =
+            Editor::with_config(config).expect("Failed to create line editor of rvdb.");
+        editor.set_helper(Some(ReplHelper::new()));
+        Self {
+            editor,
+            handler: Handler::new(board),
+            printer: Printer::new(),
+            vars: HashMap::new(),
+            batch: false,
+            failed: false,
+        }
+    }
+
+    /// Only print the results of script commands, errors going to stderr, for scripted
+    /// inspection e.g. in CI.
+    pub fn set_batch(&mut self, batch: bool) {
+        self.batch = batch;
+    }
+
+    /// Whether a command of a script failed.
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Run a script of commands, with the variables, loops and conditionals of [`DebugScript`].
+    ///
+    /// Exits the process if the script runs an exit command, with status 1 if a command failed
+    /// in batch mode, returns false otherwise.
+    pub fn run_script(&mut self, lines: &[String]) -> bool {
+        let result = DebugScript::parse(lines).and_then(|script| {
+            let (printer, batch, failed) = (&self.printer, self.batch, &mut self.failed);
+            script.run(&mut self.handler, &mut self.vars, |handler, line| {
+                if !batch {
+                    println!("{}{}", PROMPT, line);
+                }
+                match run_line(handler, printer, line) {
+                    Ok(exit) => exit,
+                    Err(err) => {
+                        *failed = true;
+                        report_error(batch, &err);
+                        false
+                    }
+                }
+            })
+        });
+        match result {
+            Ok(true) => exit((self.batch && self.failed) as i32),
+            Ok(false) => {}
+            Err(err) => {
+                self.failed = true;
+                report_error(self.batch, &err.to_string());
+            }
+        }
+
+        false
+    }
+
+    /// REPL main loop.
+    pub fn run(&mut self) {
+        let mut last_line = String::new();
+
+        loop {
+            // `symbol-file` may have changed the symbols.
+            let symbols = self.handler.symbol_names();
+            if let Some(helper) = self.editor.helper_mut() {
+                helper.set_symbols(symbols);
+            }
+
+            match self.editor.readline(PROMPT) {
+                Ok(line) => {
+                    let mut line = line.trim();
+
+                    if !line.is_empty() {
+                        last_line = line.to_string();
+                        self.editor.add_history_entry(line).unwrap();
+                    } else if !last_line.is_empty() {
+                        // Repeat the last command if the current line is empty.
+                        line = last_line.as_str();
+                    }
+
+                    let _ = self.editor.add_history_entry(line);
+                    match run_line(&mut self.handler, &self.printer, line) {
+                        Ok(true) => break,
+                        Ok(false) => {}
+                        Err(err) => println!("Error: {}", err),
+                    }
+                }
+                Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
+                    break;
+                }
+                Err(ex) => {
+                    println!("Error: {:?}", ex);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl<B: Board> Drop for DebugREPL<'_, B> {
+    fn drop(&mut self) {
+        // The UART thread must run again for the board to shut down.
+        CliCoordinator::global().resume_uart();
+    }
+}
+
+fn report_error(batch: bool, err: &str) {
+    match batch {
+        true => eprintln!("Error: {}", err),
+        false => println!("Error: {}", err),
+    }
+}
+
+/// Run one command line and print its output, or write it without colors to the file of
+/// `command > file` or `command >> file`. Returns whether the command asked to exit.
+fn run_line<B: Board>(
+    handler: &mut Handler<'_, B>,
+    printer: &Printer,
+    line: &str,
+) -> Result<bool, String> {
+    let (command, redirect) = split_redirect(line)?;
+    let output = handler.execute(command)?;
+    if output == CommandOutput::Exit {
+        return Ok(true);
+    }
+    match redirect {
+        None => printer.print(&output),
+        Some(Redirect { path, append }) => OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .and_then(|mut file| printer.write_plain(&output, &mut file))
+            .map_err(|e| format!("cannot write {}: {}", path, e))?,
+    }
+    Ok(false)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Redirect<'l> {
+    path: &'l str,
+    append: bool,
+}
+
+fn split_redirect(line: &str) -> Result<(&str, Option<Redirect<'_>>), String> {
+    let Some(idx) = line.find(" >") else {
+        return Ok((line, None));
+    };
+    let rest = &line[idx + 2..];
+    let (append, path) = match rest.strip_prefix('>') {
+        Some(path) => (true, path.trim()),
+        None => (false, rest.trim()),
+    };
+    if path.is_empty() || path.contains(char::is_whitespace) {
+        return Err("expected one file after `>`".to_string());
+    }
+    Ok((line[..idx].trim_end(), Some(Redirect { path, append })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_redirect() {
+        assert_eq!(split_redirect("print pc"), Ok(("print pc", None)));
+        assert_eq!(
+            split_redirect("info registers > regs.txt"),
+            Ok((
+                "info registers",
+                Some(Redirect {
+                    path: "regs.txt",
+                    append: false
+                })
+            ))
+        );
+        assert_eq!(
+            split_redirect("print pc >> log.txt"),
+            Ok((
+                "print pc",
+                Some(Redirect {
+                    path: "log.txt",
+                    append: true
+                })
+            ))
+        );
+        assert!(split_redirect("print pc >").is_err());
+        assert!(split_redirect("print pc > a b").is_err());
+    }
+
+    #[test]
+    fn test_write_plain() {
+        let mut out = Vec::new();
+        Printer::new()
+            .write_plain(&CommandOutput::Pc(0x8000_0000), &mut out)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "pc = 0x80000000\n");
+    }
+}
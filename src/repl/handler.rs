@@ -0,0 +1,1752 @@
+use std::{fs, io::Write};
+
+use clap::{CommandFactory, error::ErrorKind};
+
+use super::*;
+
+#[cfg(not(test))]
+use crate::cli_coordinator::CliCoordinator;
+
+use crate::{
+    DeviceConfig,
+    board::Board,
+    config::arch_config::{
+        FLOAT_REG_NAME, FLOAT_REGFILE_CNT, REG_NAME, REGFILE_CNT, VECTOR_REG_NAME, WordType,
+    },
+    dispatch_integer_sew,
+    isa::{
+        InstrLen,
+        riscv::{
+            alloc_track::AllocHook,
+            csr_reg::csr_macro::{CSR_ADDRESS, CSR_NAME},
+            debugger::{Address, DebugError, DebugEvent, Debugger},
+            mmu::AccessType,
+        },
+    },
+    load::ELFLoader,
+};
+
+/// The CSRs shown by `info registers`.
+const SUMMARY_CSRS: [&str; 10] = [
+    "mstatus", "mie", "mip", "mtvec", "mepc", "mcause", "satp", "stvec", "sepc", "scause",
+];
+
+/// Bytes `dump mem` reads before writing them out.
+const DUMP_CHUNK_SIZE: u64 = 4096;
+
+pub struct Handler<'a, B: Board> {
+    dbg: Debugger<'a, B>,
+    watch_list: Vec<PrintObject>,
+    /// The integer registers at the previous stop, to highlight the ones which changed since.
+    prev_regs: [WordType; REGFILE_CNT],
+}
+
+impl<'a, B: Board> Handler<'a, B> {
+    pub fn new(board: &'a mut B) -> Self {
+        let dbg = Debugger::new(board);
+        let prev_regs = std::array::from_fn(|i| dbg.read_reg(i as u8));
+        Self {
+            dbg,
+            watch_list: Vec::new(),
+            prev_regs,
+        }
+    }
+
+    /// The value of `pc` or of an integer register named as in commands, for scripts.
+    pub fn read_value(&self, name: &str) -> Option<WordType> {
+        match name {
+            "pc" => Some(self.dbg.read_pc()),
+            _ => parse_common_reg(name)
+                .ok()
+                .map(|idx| self.dbg.read_reg(idx)),
+        }
+    }
+
+    /// Names of the symbols of the loaded ELF, for completion.
+    pub fn symbol_names(&self) -> Vec<String> {
+        self.dbg
+            .symbol_table()
+            .map(|symtab| symtab.iter().map(|(name, _)| name.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    fn reg_value(&self, idx: u8) -> RegValue {
+        let val = self.dbg.read_reg(idx);
+        RegValue {
+            name: REG_NAME[idx as usize],
+            val,
+            changed: val != self.prev_regs[idx as usize],
+        }
+    }
+
+    /// Parse and run one command line.
+    pub fn execute(&mut self, line: &str) -> Result<CommandOutput, String> {
+        let argv = line.split_whitespace().map(|s| s.to_string());
+        match Cli::try_parse_from(argv) {
+            Ok(cli) => self.handle(cli),
+            // `--help` of a command.
+            Err(e) if e.kind() == ErrorKind::DisplayHelp => {
+                Ok(CommandOutput::Help(e.render().ansi().to_string()))
+            }
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    pub fn handle(&mut self, cli: Cli) -> Result<CommandOutput, String> {
+        match cli {
+            Cli::Print(cmd) => self.handle_print(cmd),
+            Cli::Display(cmd) => self.handle_display(cmd),
+            Cli::Undisplay(cmd) => self.handle_undisplay(cmd),
+            Cli::Translate { addr, access } => self.handle_translate(addr, access.into()),
+            Cli::List => self.handle_list(),
+            Cli::Find {
+                start,
+                len,
+                pattern,
+                kind,
+                virt,
+                max,
+            } => self.handle_find(start, len, pattern, kind, virt, max),
+            Cli::Compare {
+                addr1,
+                addr2,
+                len,
+                virt,
+                max,
+            } => {
+                let addr1 = make_address(parse_u64(&addr1)?, virt);
+                let addr2 = make_address(parse_u64(&addr2)?, virt);
+                let len = parse_u64(&len)?;
+                let (count, diffs) = self.dbg.compare_memory(addr1, addr2, len, max);
+                Ok(CommandOutput::Compare {
+                    addr1,
+                    addr2,
+                    len,
+                    count,
+                    diffs,
+                })
+            }
+            Cli::Dump(DumpCmd::Mem {
+                addr,
+                len,
+                file,
+                virt,
+            }) => self.handle_dump_mem(addr, len, file, virt),
+            Cli::Load(LoadCmd::Mem { file, addr, virt }) => self.handle_load_mem(file, addr, virt),
+            Cli::History { count } => self.handle_history(count),
+            Cli::FTrace(cmd) => self.handle_ftrace(cmd),
+            Cli::Alloc(cmd) => self.handle_alloc(cmd),
+            Cli::Taint(cmd) => self.handle_taint(cmd),
+            Cli::Si => self.handle_step(),
+            Cli::Continue { steps } => self.handle_continue(steps),
+            Cli::Breakpoint {
+                delete,
+                symbol,
+                virt,
+                hart,
+            } => self.handle_breakpoint(delete, symbol, virt, hart),
+            Cli::Hart { id } => self.handle_hart(id),
+            Cli::Info(cmd) => self.handle_info(cmd),
+            Cli::Set(cmd) => self.handle_set(cmd),
+            Cli::Irq { id } => self.handle_irq(id),
+            Cli::Device(cmd) => self.handle_device(cmd),
+            Cli::Gpio(cmd) => self.handle_gpio(cmd),
+            Cli::Reset { reload } => self.handle_reset(reload),
+            Cli::Help { command } => help_page(&command).map(CommandOutput::Help),
+            Cli::Quit => Ok(CommandOutput::Exit),
+            Cli::SymbolFile { path } => self.handle_symbol_file(path),
+        }
+    }
+
+    fn handle_translate(
+        &mut self,
+        addr: String,
+        access: AccessType,
+    ) -> Result<CommandOutput, String> {
+        let virt_addr = parse_u64(&addr)?;
+        let phys_addr = self
+            .dbg
+            .translate(virt_addr, access)
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(CommandOutput::Translate {
+            phys_addr,
+            virt_addr,
+        })
+    }
+
+    fn handle_set(&mut self, cmd: SetCmd) -> Result<CommandOutput, String> {
+        match cmd {
+            SetCmd::StepOverInterrupt { enabled } => self.dbg.set_step_over_interrupts(enabled),
+        }
+        Ok(CommandOutput::None)
+    }
+
+    fn handle_hart(&mut self, id: Option<usize>) -> Result<CommandOutput, String> {
+        if let Some(id) = id {
+            self.dbg.select_hart(id).map_err(|e| e.to_string())?;
+            self.prev_regs = std::array::from_fn(|i| self.dbg.read_reg(i as u8));
+        }
+        Ok(CommandOutput::Harts {
+            current: self.dbg.hart(),
+            harts: self.dbg.harts(),
+        })
+    }
+
+    fn handle_irq(&mut self, id: u32) -> Result<CommandOutput, String> {
+        self.dbg.raise_irq(id).map_err(|e| e.to_string())?;
+        Ok(CommandOutput::None)
+    }
+
+    fn handle_device(&mut self, cmd: DeviceCmd) -> Result<CommandOutput, String> {
+        match cmd {
+            DeviceCmd::Add { config } => {
+                let cfg = config.parse::<DeviceConfig>()?;
+                let info = self.dbg.add_device(&cfg).map_err(|e| e.to_string())?;
+                Ok(CommandOutput::DeviceAdded(info))
+            }
+            DeviceCmd::Remove { id } => {
+                self.dbg.remove_device(id).map_err(|e| e.to_string())?;
+                Ok(CommandOutput::None)
+            }
+            DeviceCmd::Faults {
+                id,
+                latency,
+                eio_every,
+                torn_flush,
+            } => {
+                let mut faults = self.dbg.block_faults(id).map_err(|e| e.to_string())?;
+                if latency.is_some() || eio_every.is_some() || torn_flush.is_some() {
+                    faults.latency = latency.unwrap_or(faults.latency);
+                    faults.eio_every = eio_every.unwrap_or(faults.eio_every);
+                    faults.torn_flush = torn_flush.unwrap_or(faults.torn_flush);
+                    self.dbg
+                        .set_block_faults(id, faults)
+                        .map_err(|e| e.to_string())?;
+                }
+                Ok(CommandOutput::DeviceFaults { slot: id, faults })
+            }
+        }
+    }
+
+    fn handle_gpio(&mut self, cmd: GpioCmd) -> Result<CommandOutput, String> {
+        match cmd {
+            GpioCmd::Show => {}
+            GpioCmd::Set { pin, level } => self
+                .dbg
+                .drive_gpio(pin, Some(level))
+                .map_err(|e| e.to_string())?,
+            GpioCmd::Release { pin } => {
+                self.dbg.drive_gpio(pin, None).map_err(|e| e.to_string())?
+            }
+        }
+        let state = self.dbg.gpio_state().map_err(|e| e.to_string())?;
+        Ok(CommandOutput::Gpio(state))
+    }
+
+    fn handle_reset(&mut self, reload: bool) -> Result<CommandOutput, String> {
+        self.dbg.reset(reload).map_err(|e| e.to_string())?;
+        Ok(CommandOutput::None)
+    }
+
+    fn handle_symbol_file(&mut self, path: String) -> Result<CommandOutput, String> {
+        let bytes = fs::read(&path).map_err(|e| e.to_string() + ", when reading " + &path)?;
+        let loader = ELFLoader::try_new(bytes).ok_or("Failed to parse ELF file")?;
+        if let Some(symtab) = loader.get_symbol_table() {
+            self.dbg.set_symbol_table(symtab);
+            Ok(CommandOutput::None)
+        } else {
+            Err("No symbol table found in ELF file".to_string())
+        }
+    }
+
+    fn handle_ftrace(&mut self, cmd: FTraceCmd) -> Result<CommandOutput, String> {
+        match cmd {
+            FTraceCmd::Start => {
+                self.dbg.ftrace_start();
+                Ok(CommandOutput::FTraceStatus { enabled: true })
+            }
+            FTraceCmd::Stop => {
+                self.dbg.ftrace_stop();
+                Ok(CommandOutput::FTraceStatus { enabled: false })
+            }
+            FTraceCmd::Show { count } => Ok(CommandOutput::FTraceShow(
+                self.dbg.ftrace_show().take(count).collect(),
+            )),
+            FTraceCmd::Stat => Ok(CommandOutput::FTraceStat(self.dbg.ftrace_stat())),
+        }
+    }
+
+    fn handle_print(&mut self, cmd: PrintCmd) -> Result<CommandOutput, String> {
+        match cmd {
+            PrintCmd::Pc => Ok(CommandOutput::Pc(self.dbg.read_pc())),
+            PrintCmd::Reg { reg } => {
+                let idx = parse_common_reg(&reg)?;
+                Ok(CommandOutput::Reg(self.reg_value(idx)))
+            }
+            PrintCmd::Regs { start, len } => {
+                let mut regs = Vec::new();
+                for i in start..start + len {
+                    if i >= REGFILE_CNT as u8 {
+                        break;
+                    }
+                    regs.push(self.reg_value(i));
+                }
+                Ok(CommandOutput::Regs(regs))
+            }
+            PrintCmd::Mem { addr, len, virt } => {
+                let addr_val = parse_u64(&addr)?;
+                let start_addr = make_address(addr_val, virt);
+                let mut data = Vec::new();
+                let mut curr = start_addr;
+                for _ in 0..len {
+                    let byte = self.dbg.read_memory::<u8>(curr).ok();
+                    data.push(byte);
+                    curr = curr + 1;
+                }
+                Ok(CommandOutput::Mem {
+                    addr: start_addr,
+                    data,
+                })
+            }
+            PrintCmd::Csr { addr } => {
+                let csr_addr = parse_csr(&addr)?;
+                let name = CSR_NAME
+                    .get(&csr_addr)
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| format!("0x{:03x}", csr_addr));
+                Ok(CommandOutput::Csr {
+                    name,
+                    val: self.dbg.read_csr(csr_addr),
+                })
+            }
+            PrintCmd::FReg { reg } => {
+                let idx = parse_float_reg(&reg)?;
+                let (f32_val, f64_val) = self.dbg.read_float_reg(idx);
+                Ok(CommandOutput::FReg {
+                    name: FLOAT_REG_NAME[idx as usize].to_string(),
+                    f32_val,
+                    f64_val,
+                })
+            }
+            PrintCmd::VReg { reg } => {
+                let idx = parse_vector_reg(&reg)?;
+                let value = [(8, "d"), (4, "w"), (2, "h"), (1, "b")]
+                    .iter()
+                    .map(|(size, size_prompt)| {
+                        (
+                            size_prompt.to_string(),
+                            dispatch_integer_sew!(*size, |T| {
+                                self.dbg
+                                    .read_vector_reg::<T>(idx)
+                                    .unwrap()
+                                    .iter()
+                                    .map(|v| *v as WordType)
+                                    .collect()
+                            }),
+                        )
+                    })
+                    .collect();
+
+                Ok(CommandOutput::VReg {
+                    name: VECTOR_REG_NAME[idx as usize].to_string(),
+                    val: value,
+                })
+            }
+            PrintCmd::Priv => Ok(CommandOutput::Privilege(self.dbg.get_current_privilege())),
+        }
+    }
+
+    fn handle_display(&mut self, cmd: PrintCmd) -> Result<CommandOutput, String> {
+        let obj = match cmd {
+            PrintCmd::Pc => PrintObject::Pc,
+            PrintCmd::Reg { reg } => PrintObject::Reg(parse_common_reg(&reg)?),
+            PrintCmd::Regs { start, len } => PrintObject::Regs(start, len),
+            PrintCmd::Mem { addr, len, virt } => PrintObject::Mem(parse_u64(&addr)?, len, virt),
+            PrintCmd::Csr { addr } => PrintObject::CSR(parse_csr(&addr)?),
+            PrintCmd::FReg { reg } => PrintObject::FReg(parse_float_reg(&reg)?),
+            PrintCmd::VReg { reg, .. } => PrintObject::VReg(parse_vector_reg(&reg)?),
+            PrintCmd::Priv => PrintObject::Privilege,
+        };
+        self.watch_list.push(obj);
+        Ok(CommandOutput::None)
+    }
+
+    fn handle_undisplay(&mut self, cmd: PrintCmd) -> Result<CommandOutput, String> {
+        let target = match cmd {
+            PrintCmd::Pc => PrintObject::Pc,
+            PrintCmd::Reg { reg } => PrintObject::Reg(parse_common_reg(&reg)?),
+            PrintCmd::Regs { start, len } => PrintObject::Regs(start, len),
+            PrintCmd::Mem { addr, len, virt } => PrintObject::Mem(parse_u64(&addr)?, len, virt),
+            PrintCmd::Csr { addr } => PrintObject::CSR(parse_csr(&addr)?),
+            PrintCmd::FReg { reg } => PrintObject::FReg(parse_float_reg(&reg)?),
+            PrintCmd::VReg { reg, .. } => PrintObject::VReg(parse_vector_reg(&reg)?),
+            PrintCmd::Priv => PrintObject::Privilege,
+        };
+        self.watch_list.retain(|item| *item != target);
+        Ok(CommandOutput::None)
+    }
+
+    fn handle_list(&mut self) -> Result<CommandOutput, String> {
+        const NUM_LINES: usize = 20;
+
+        // Variable-length instructions make it impossible to reliably disassemble backwards,
+        // unless record decode result or decode from the last symbol.
+        let mut addr = self.dbg.read_pc();
+        let mut lines = Vec::new();
+
+        for _ in 0..NUM_LINES {
+            let line = self.instr_from_addr(addr);
+            let step = line
+                .decoded
+                .as_ref()
+                .map(|d| d.len)
+                .or_else(|| line.raw.map(|r| r.len()))
+                .unwrap_or(2);
+            lines.push(line);
+            addr = addr.wrapping_add(step);
+        }
+        Ok(CommandOutput::CodeList(lines))
+    }
+
+    fn handle_find(
+        &mut self,
+        start: String,
+        len: String,
+        pattern: Vec<String>,
+        kind: PatternKind,
+        virt: bool,
+        max: usize,
+    ) -> Result<CommandOutput, String> {
+        let start = make_address(parse_u64(&start)?, virt);
+        let len = parse_u64(&len)?;
+        let pattern = parse_pattern(&pattern, kind)?;
+        // One more to know whether there are others.
+        let mut matches = self.dbg.find_memory(start, len, &pattern, max + 1);
+        let truncated = matches.len() > max;
+        matches.truncate(max);
+        Ok(CommandOutput::Found { matches, truncated })
+    }
+
+    fn handle_dump_mem(
+        &mut self,
+        addr: String,
+        len: String,
+        path: String,
+        virt: bool,
+    ) -> Result<CommandOutput, String> {
+        let addr = make_address(parse_u64(&addr)?, virt);
+        let len = parse_u64(&len)?;
+        // Written in chunks as they are read, the file is only created once the first one is.
+        let mut file = None;
+        let mut chunk = Vec::with_capacity(DUMP_CHUNK_SIZE as usize);
+        for start in (0..len).step_by(DUMP_CHUNK_SIZE as usize) {
+            chunk.clear();
+            for offset in start..len.min(start + DUMP_CHUNK_SIZE) {
+                let byte = self
+                    .dbg
+                    .read_memory::<u8>(addr + offset)
+                    .map_err(|e| format!("cannot read {:#x}: {:?}", (addr + offset).value(), e))?;
+                chunk.push(byte);
+            }
+            let file = match &mut file {
+                Some(file) => file,
+                None => file.insert(
+                    fs::File::create(&path).map_err(|e| format!("cannot write {}: {}", path, e))?,
+                ),
+            };
+            file.write_all(&chunk)
+                .map_err(|e| format!("cannot write {}: {}", path, e))?;
+        }
+        if file.is_none() {
+            fs::write(&path, []).map_err(|e| format!("cannot write {}: {}", path, e))?;
+        }
+        Ok(CommandOutput::MemDumped { addr, len, path })
+    }
+
+    fn handle_load_mem(
+        &mut self,
+        path: String,
+        addr: String,
+        virt: bool,
+    ) -> Result<CommandOutput, String> {
+        let addr = make_address(parse_u64(&addr)?, virt);
+        let data = fs::read(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        for (offset, byte) in data.iter().enumerate() {
+            let target = addr + offset as u64;
+            self.dbg.write_memory(target, *byte).map_err(|e| {
+                format!(
+                    "cannot write {:#x}: {:?}, {} bytes loaded",
+                    target.value(),
+                    e,
+                    offset
+                )
+            })?;
+        }
+        Ok(CommandOutput::MemLoaded {
+            addr,
+            len: data.len() as u64,
+        })
+    }
+
+    fn handle_history(&mut self, count: usize) -> Result<CommandOutput, String> {
+        let history: Vec<_> = self
+            .dbg
+            .pc_history(count)
+            .map(|(addr, raw)| DbgInstrLine {
+                addr,
+                raw,
+                decoded: raw.and_then(|r| self.dbg.decoded_info(r)),
+                symbol: self.dbg.symbol_by_addr(addr).ok().cloned(),
+                is_current_pc: addr == self.dbg.read_pc(),
+            })
+            .collect();
+        Ok(CommandOutput::History(history))
+    }
+
+    fn handle_step(&mut self) -> Result<CommandOutput, String> {
+        self.handle_run(|dbg| {
+            dbg.step().map(|event| {
+                let steps = (event != DebugEvent::BoardHalted) as u64;
+                (event, steps)
+            })
+        })
+    }
+
+    fn handle_continue(&mut self, steps: u64) -> Result<CommandOutput, String> {
+        self.handle_run(|dbg| dbg.continue_until_step(steps))
+    }
+
+    fn handle_run(
+        &mut self,
+        run: impl FnOnce(&mut Debugger<'a, B>) -> Result<(DebugEvent, u64), DebugError>,
+    ) -> Result<CommandOutput, String> {
+        #[cfg(not(test))]
+        {
+            CliCoordinator::global().resume_uart();
+            // Fails without a terminal, e.g. for --batch in CI, where there are no keys to pass.
+            let _ = crossterm::terminal::enable_raw_mode();
+        }
+
+        self.prev_regs = std::array::from_fn(|i| self.dbg.read_reg(i as u8));
+        let rst = run(&mut self.dbg);
+
+        #[cfg(not(test))]
+        {
+            CliCoordinator::global().pause_uart();
+            let _ = crossterm::terminal::disable_raw_mode();
+        }
+
+        let (event, actual_steps) = match rst {
+            Ok(rst) => rst,
+            Err(e) => return Err(format!("step failed: {}", e)),
+        };
+
+        let watch_results = self.collect_watch_results()?;
+        let pc = self.dbg.read_pc();
+
+        Ok(CommandOutput::ContinueDone {
+            instr: self.instr_from_addr(pc),
+            watch_results,
+            event,
+            actual_steps,
+        })
+    }
+
+    fn collect_watch_results(&mut self) -> Result<Vec<CommandOutput>, String> {
+        let mut results = Vec::new();
+        let watch_list = self.watch_list.clone();
+
+        for item in watch_list {
+            let output = match item {
+                PrintObject::Pc => self.handle_print(PrintCmd::Pc)?,
+                PrintObject::Reg(idx) => {
+                    let name = REG_NAME[idx as usize].to_string();
+                    self.handle_print(PrintCmd::Reg { reg: name })?
+                }
+                PrintObject::Regs(start, len) => {
+                    self.handle_print(PrintCmd::Regs { start, len })?
+                }
+                PrintObject::Mem(addr, len, virt) => {
+                    let addr_str = format!("0x{:x}", addr);
+                    self.handle_print(PrintCmd::Mem {
+                        addr: addr_str,
+                        len,
+                        virt,
+                    })?
+                }
+                PrintObject::CSR(addr) => {
+                    let addr_str = format!("0x{:x}", addr);
+                    self.handle_print(PrintCmd::Csr { addr: addr_str })?
+                }
+                PrintObject::FReg(idx) => {
+                    let name = FLOAT_REG_NAME[idx as usize].to_string();
+                    self.handle_print(PrintCmd::FReg { reg: name })?
+                }
+                PrintObject::VReg(idx) => {
+                    let name = VECTOR_REG_NAME[idx as usize].to_string();
+                    self.handle_print(PrintCmd::VReg { reg: name })?
+                }
+                PrintObject::Privilege => self.handle_print(PrintCmd::Priv)?,
+            };
+            results.push(output);
+        }
+        Ok(results)
+    }
+
+    fn handle_alloc(&mut self, cmd: AllocCmd) -> Result<CommandOutput, String> {
+        match cmd {
+            AllocCmd::Hook {
+                alloc,
+                free,
+                size_arg,
+                ptr_arg,
+            } => {
+                if size_arg >= 8 || ptr_arg >= 8 {
+                    return Err("argument index must be in 0..8 (a0-a7)".to_string());
+                }
+                let hook = AllocHook {
+                    alloc: self.resolve_symbol(&alloc)?,
+                    free: self.resolve_symbol(&free)?,
+                    size_arg,
+                    ptr_arg,
+                };
+                let ok = self.dbg.add_alloc_hook(hook);
+                Ok(CommandOutput::AllocHookSet { ok, alloc, free })
+            }
+            AllocCmd::Report { count } => {
+                let tracker = self.dbg.alloc_tracker();
+                if tracker.hooks().is_empty() {
+                    return Err("no allocator hooked, see `alloc hook`".to_string());
+                }
+                let allocations = tracker
+                    .outstanding()
+                    .into_iter()
+                    .take(count)
+                    .map(|allocation| {
+                        let symbol = self.dbg.symbol_in_addr_range(allocation.caller).ok();
+                        (allocation, symbol.cloned())
+                    })
+                    .collect();
+                Ok(CommandOutput::AllocReport {
+                    stats: tracker.stats(),
+                    allocations,
+                })
+            }
+            AllocCmd::Clear => {
+                self.dbg.clear_alloc_records();
+                Ok(CommandOutput::None)
+            }
+        }
+    }
+
+    /// An address, or the address of a symbol.
+    fn resolve_symbol(&self, symbol: &str) -> Result<WordType, String> {
+        if let Ok(addr) = parse_word(symbol) {
+            return Ok(addr);
+        }
+        self.dbg
+            .addr_by_symbol(symbol)
+            .map(|addr| addr as WordType)
+            .map_err(|_| format!("Symbol not found: {}", symbol))
+    }
+
+    fn handle_breakpoint(
+        &mut self,
+        delete: bool,
+        symbol: String,
+        virt: bool,
+        hart: Option<usize>,
+    ) -> Result<CommandOutput, String> {
+        let (addr_val, symbol_name) = if let Ok(addr) = parse_u64(&symbol) {
+            (addr, None)
+        } else if let Ok(addr) = self.dbg.addr_by_symbol(&symbol) {
+            (addr, Some(symbol))
+        } else {
+            return Err(format!("Symbol not found: {}", symbol));
+        };
+
+        let address = make_address(addr_val, virt);
+
+        if delete {
+            let ok = self
+                .dbg
+                .clear_breakpoint(address)
+                .map_err(|err| err.to_string())?;
+
+            Ok(CommandOutput::BreakpointCleared {
+                addr: address,
+                symbol: symbol_name,
+                ok,
+            })
+        } else {
+            let ok = self
+                .dbg
+                .set_breakpoint(address, hart)
+                .map_err(|err| err.to_string())?;
+
+            Ok(CommandOutput::BreakpointSet {
+                ok,
+                addr: address,
+                symbol: symbol_name,
+            })
+        }
+    }
+
+    fn handle_taint(&mut self, cmd: TaintCmd) -> Result<CommandOutput, String> {
+        match cmd {
+            TaintCmd::Uart => self.dbg.taint_uart().map_err(|e| e.to_string())?,
+            TaintCmd::Disk { id, offset, len } => {
+                let offset = parse_u64(&offset)?;
+                let end = offset.saturating_add(parse_u64(&len)?);
+                self.dbg
+                    .taint_disk(id, offset..end)
+                    .map_err(|e| e.to_string())?;
+            }
+            TaintCmd::Mem { addr, len } => self.dbg.taint_memory(parse_u64(&addr)?, len, true),
+            TaintCmd::Clear => self.dbg.clear_taint(),
+        }
+        Ok(CommandOutput::None)
+    }
+
+    fn handle_info_taint(
+        &mut self,
+        item: Option<String>,
+        len: u32,
+        virt: bool,
+    ) -> Result<CommandOutput, String> {
+        if self.dbg.taint().is_none() {
+            return Err("no taint source, see `taint`".to_string());
+        }
+        let Some(item) = item else {
+            let taint = self.dbg.taint().unwrap();
+            return Ok(CommandOutput::TaintSummary {
+                regs: (0..REGFILE_CNT as u8)
+                    .filter(|&idx| taint.is_reg_tainted(idx))
+                    .map(|idx| REG_NAME[idx as usize])
+                    .collect(),
+                bytes: taint.memory().borrow().tainted_bytes(),
+            });
+        };
+        if let Ok(idx) = parse_common_reg(&item) {
+            return Ok(CommandOutput::TaintReg {
+                name: item,
+                tainted: self.dbg.taint().unwrap().is_reg_tainted(idx),
+            });
+        }
+
+        let mut addr = parse_u64(&item)?;
+        if virt {
+            addr = self
+                .dbg
+                .vaddr_to_paddr(addr as WordType)
+                .map_err(|e| format!("{:?}", e))?;
+        }
+        let memory = self.dbg.taint().unwrap().memory().borrow();
+        Ok(CommandOutput::TaintMem {
+            addr,
+            tainted: (addr..addr + len as u64)
+                .map(|addr| memory.is_tainted(addr))
+                .collect(),
+        })
+    }
+
+    fn handle_info(&mut self, cmd: InfoCmd) -> Result<CommandOutput, String> {
+        match cmd {
+            InfoCmd::Registers => Ok(CommandOutput::Registers {
+                pc: self.dbg.read_pc(),
+                privilege: self.dbg.get_current_privilege(),
+                regs: (0..REGFILE_CNT as u8).map(|i| self.reg_value(i)).collect(),
+                fregs: (0..FLOAT_REGFILE_CNT)
+                    .map(|i| {
+                        let (f32_val, f64_val) = self.dbg.read_float_reg(i as u8);
+                        (FLOAT_REG_NAME[i], f32_val, f64_val)
+                    })
+                    .collect(),
+                csrs: SUMMARY_CSRS
+                    .iter()
+                    .map(|&name| (name, self.dbg.read_csr(CSR_ADDRESS[name])))
+                    .collect(),
+            }),
+            InfoCmd::Breakpoints => Ok(CommandOutput::Breakpoints(self.dbg.breakpoints().clone())),
+            InfoCmd::Symbols => {
+                let Some(symbol_table) = self.dbg.symbol_table() else {
+                    return Err("No symbol table available".to_string());
+                };
+
+                Ok(CommandOutput::Symbols(
+                    symbol_table.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+                ))
+            }
+            InfoCmd::Exceptions { count } => Ok(CommandOutput::Traps(
+                self.dbg.recent_traps().take(count).copied().collect(),
+            )),
+            InfoCmd::Devices { json } => {
+                let stats = self.dbg.device_stats();
+                if json {
+                    serde_json::to_string_pretty(&stats)
+                        .map(CommandOutput::Json)
+                        .map_err(|e| e.to_string())
+                } else {
+                    Ok(CommandOutput::Devices(stats))
+                }
+            }
+            InfoCmd::Mtree { json } => {
+                let regions = self.dbg.memory_regions();
+                if json {
+                    serde_json::to_string_pretty(&regions)
+                        .map(CommandOutput::Json)
+                        .map_err(|e| e.to_string())
+                } else {
+                    Ok(CommandOutput::Mtree(regions))
+                }
+            }
+            InfoCmd::Taint { item, len, virt } => self.handle_info_taint(item, len, virt),
+            InfoCmd::Vm => Ok(CommandOutput::Vm(self.dbg.vm_info())),
+            InfoCmd::Istats { count, json, reset } => {
+                let stats = self.dbg.instr_stats();
+                if reset {
+                    self.dbg.clear_instr_stats();
+                }
+                if json {
+                    serde_json::to_string_pretty(&stats)
+                        .map(CommandOutput::Json)
+                        .map_err(|e| e.to_string())
+                } else {
+                    Ok(CommandOutput::Istats { stats, count })
+                }
+            }
+        }
+    }
+
+    fn instr_from_addr(&mut self, addr: WordType) -> DbgInstrLine {
+        let raw = self.dbg.read_instr(addr);
+        let decoded = raw.and_then(|r| self.dbg.decoded_info(r));
+        let symbol = self.dbg.symbol_by_addr(addr).ok().cloned();
+
+        DbgInstrLine {
+            addr,
+            raw,
+            decoded,
+            symbol,
+            is_current_pc: addr == self.dbg.read_pc(),
+        }
+    }
+}
+
+fn make_address(addr: u64, virt: bool) -> Address {
+    if virt {
+        Address::Virt(addr)
+    } else {
+        Address::Phys(addr)
+    }
+}
+
+/// The command list without `words`, or the help of the (sub)command they name.
+fn help_page(words: &[String]) -> Result<String, String> {
+    let cli = Cli::command();
+    if words.is_empty() {
+        let commands: Vec<(String, String)> = cli
+            .get_subcommands()
+            .filter(|cmd| !cmd.is_hide_set())
+            .map(|cmd| {
+                let names: Vec<&str> = std::iter::once(cmd.get_name())
+                    .chain(cmd.get_all_aliases())
+                    .collect();
+                let about = cmd.get_about().map(|about| about.to_string());
+                (
+                    names.join(", "),
+                    first_sentence(about.as_deref().unwrap_or("")),
+                )
+            })
+            .collect();
+        let width = commands
+            .iter()
+            .map(|(names, _)| names.len())
+            .max()
+            .unwrap_or(0);
+        let mut page = String::from("Commands, `help <command>` for details:\n");
+        for (names, about) in commands {
+            page += &format!("  {:<width$}  {}\n", names, about);
+        }
+        return Ok(page);
+    }
+
+    // Built from the top-level command, as the usages would start with the empty name of the
+    // multicall root.
+    let unknown = || format!("unknown command: {}", words.join(" "));
+    let mut top = cli.find_subcommand(&words[0]).ok_or_else(unknown)?.clone();
+    top.build();
+    let mut cmd = &mut top;
+    for word in &words[1..] {
+        cmd = cmd.find_subcommand_mut(word).ok_or_else(unknown)?;
+    }
+    Ok(cmd.render_long_help().ansi().to_string())
+}
+
+/// The text up to the first sentence end, the details being in the page of the command.
+fn first_sentence(text: &str) -> String {
+    let end = text
+        .match_indices(". ")
+        .find(|&(idx, sep)| text[idx + sep.len()..].starts_with(|c: char| c.is_uppercase()));
+    match end {
+        Some((idx, _)) => text[..idx].to_string(),
+        None => text.to_string(),
+    }
+}
+
+fn parse_u64(s: &str) -> Result<u64, String> {
+    crate::parse_u64(s.trim()).map_err(|e| e.to_string())
+}
+
+fn parse_pattern(words: &[String], kind: PatternKind) -> Result<Vec<u8>, String> {
+    match kind {
+        PatternKind::Bytes => {
+            let mut bytes = Vec::new();
+            for word in words {
+                let hex = word.strip_prefix("0x").unwrap_or(word);
+                if hex.is_empty() || hex.len() % 2 != 0 {
+                    return Err(format!("invalid byte pattern: {}", word));
+                }
+                for i in (0..hex.len()).step_by(2) {
+                    let byte = hex
+                        .get(i..i + 2)
+                        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                        .ok_or_else(|| format!("invalid byte pattern: {}", word))?;
+                    bytes.push(byte);
+                }
+            }
+            Ok(bytes)
+        }
+        PatternKind::Ascii => Ok(words.join(" ").into_bytes()),
+        PatternKind::U32 => {
+            let mut bytes = Vec::new();
+            for word in words {
+                let value = u32::try_from(parse_u64(word)?)
+                    .map_err(|_| format!("not a 32-bit value: {}", word))?;
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+fn parse_word(s: &str) -> Result<WordType, String> {
+    parse_u64(s).map(|v| v as WordType)
+}
+
+fn parse_reg(s: &str, reg_list: &[&str], prefix: char) -> Result<u8, String> {
+    let t = s.trim();
+    if let Some(index) = reg_list.iter().position(|s| s.split("/").any(|r| r == t)) {
+        return Ok(index as u8);
+    }
+
+    if let Some(rest) = t.strip_prefix(prefix)
+        && let Ok(n) = rest.parse::<u8>()
+        && n < 32
+    {
+        return Ok(n);
+    }
+
+    Err(format!("invalid register: {}", s))
+}
+
+fn parse_common_reg(s: &str) -> Result<u8, String> {
+    parse_reg(s, &REG_NAME, 'x')
+}
+
+fn parse_float_reg(s: &str) -> Result<u8, String> {
+    parse_reg(s, &FLOAT_REG_NAME, 'f')
+}
+
+fn parse_vector_reg(s: &str) -> Result<u8, String> {
+    parse_reg(s, &FLOAT_REG_NAME, 'v')
+}
+
+fn parse_csr(s: &str) -> Result<WordType, String> {
+    let t = s.trim();
+    if let Some(index) = CSR_ADDRESS.get(t) {
+        return Ok(*index);
+    }
+
+    if let Ok(n) = parse_word(s) {
+        return Ok(n);
+    }
+
+    Err(format!("invalid csr: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        board::virt::{RVBoardBuilder, VirtBoard},
+        device::{RegionKind, sifive_gpio::GpioScript},
+        isa::riscv::trap::{Exception, Trap},
+        ram::Ram,
+        ram_config,
+    };
+
+    #[test]
+    #[cfg(feature = "riscv64")]
+    fn test_parse_reg_riscv64() {
+        assert_eq!(parse_common_reg("x0"), Ok(0));
+        assert_eq!(parse_common_reg("a5"), Ok(15));
+        assert_eq!(parse_common_reg("x31"), Ok(31));
+        assert!(matches!(parse_common_reg("x32"), Err(_)));
+
+        assert!(REG_NAME[parse_common_reg("s0").unwrap() as usize] == "s0/fp");
+        assert!(REG_NAME[parse_common_reg("fp").unwrap() as usize] == "s0/fp");
+    }
+
+    fn create_board() -> VirtBoard {
+        VirtBoard::from_binary(&[])
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+
+        assert_eq!(
+            handler.handle(Cli::Irq { id: 1 }).unwrap(),
+            CommandOutput::None
+        );
+        assert!(handler.handle(Cli::Irq { id: 0 }).is_err());
+        assert!(handler.handle(Cli::Irq { id: 1024 }).is_err());
+    }
+
+    #[test]
+    fn test_set_step_over_interrupt() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+
+        for (arg, enabled) in [("on", true), ("off", false)] {
+            let cli = Cli::try_parse_from(["set", "step-over-interrupt", arg]).unwrap();
+            assert_eq!(handler.handle(cli), Ok(CommandOutput::None));
+            assert_eq!(handler.dbg.step_over_interrupts(), enabled);
+        }
+    }
+
+    #[test]
+    fn test_info_exceptions() {
+        let mut board = VirtBoard::from_binary(&0x00100073u32.to_le_bytes()); // EBREAK
+        let mut handler = Handler::new(&mut board);
+
+        handler.handle(Cli::Si).unwrap();
+        let Ok(CommandOutput::Traps(traps)) =
+            handler.handle(Cli::Info(InfoCmd::Exceptions { count: 20 }))
+        else {
+            panic!("expected the trap list");
+        };
+        assert_eq!(traps.len(), 1);
+        assert_eq!(traps[0].pc, ram_config::BASE_ADDR);
+        assert_eq!(traps[0].cause, Trap::Exception(Exception::Breakpoint));
+    }
+
+    #[test]
+    fn test_alloc_report() {
+        let program = [
+            0x01800513u32, // addi a0, zero, 24
+            0x00c000ef,    // jal ra, malloc
+            0x00000013,    // nop
+            0x00000013,    // free: nop
+            0x00010537,    // malloc: lui a0, 0x10
+            0x00008067,    // ret
+        ];
+        let bytes: Vec<u8> = program.iter().flat_map(|i| i.to_le_bytes()).collect();
+        let mut board = VirtBoard::from_binary(&bytes);
+        let mut handler = Handler::new(&mut board);
+
+        assert!(
+            handler
+                .handle(Cli::Alloc(AllocCmd::Report { count: 20 }))
+                .is_err()
+        );
+        let malloc = format!("{:#x}", ram_config::BASE_ADDR + 16);
+        let free = format!("{:#x}", ram_config::BASE_ADDR + 12);
+        let cli = Cli::try_parse_from(["alloc", "hook", &malloc, &free]).unwrap();
+        assert_eq!(
+            handler.handle(cli),
+            Ok(CommandOutput::AllocHookSet {
+                ok: true,
+                alloc: malloc,
+                free,
+            })
+        );
+        assert!(
+            handler
+                .handle(Cli::Alloc(AllocCmd::Hook {
+                    alloc: "no_such_symbol".to_string(),
+                    free: "0".to_string(),
+                    size_arg: 0,
+                    ptr_arg: 0,
+                }))
+                .is_err()
+        );
+
+        handler.handle(Cli::Continue { steps: 4 }).unwrap();
+        let Ok(CommandOutput::AllocReport { stats, allocations }) =
+            handler.handle(Cli::Alloc(AllocCmd::Report { count: 20 }))
+        else {
+            panic!("expected the allocation report");
+        };
+        assert_eq!((stats.allocs, stats.outstanding_bytes), (1, 24));
+        assert_eq!(allocations[0].0.ptr, 0x10000);
+        assert_eq!(allocations[0].0.caller, ram_config::BASE_ADDR + 8);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut board = VirtBoard::from_binary(&0x13u32.to_le_bytes()); // NOP
+        let mut handler = Handler::new(&mut board);
+        let image = Address::Phys(ram_config::BASE_ADDR);
+
+        handler.handle(Cli::Si).unwrap();
+        handler.dbg.write_memory(image, 0u32).unwrap();
+        assert_eq!(
+            handler.handle(Cli::Reset { reload: false }),
+            Ok(CommandOutput::None)
+        );
+        assert_eq!(handler.dbg.read_pc(), ram_config::BASE_ADDR);
+        assert_eq!(handler.dbg.read_memory::<u32>(image), Ok(0));
+
+        handler.handle(Cli::Reset { reload: true }).unwrap();
+        assert_eq!(handler.dbg.read_memory::<u32>(image), Ok(0x13));
+    }
+
+    #[test]
+    fn test_device_hotplug() {
+        let path = std::path::Path::new("./tmp/test_device_hotplug.img");
+        fs::create_dir_all("./tmp").unwrap();
+        fs::write(&path, [0u8; 512]).unwrap();
+
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+
+        let config = format!("virtio-block:{}", path.display());
+        let CommandOutput::DeviceAdded(info) = handler
+            .handle(Cli::Device(DeviceCmd::Add { config }))
+            .unwrap()
+        else {
+            panic!("device not added");
+        };
+        assert_eq!(
+            handler.handle(Cli::Device(DeviceCmd::Remove { id: info.slot })),
+            Ok(CommandOutput::None)
+        );
+        assert!(
+            handler
+                .handle(Cli::Device(DeviceCmd::Remove { id: info.slot }))
+                .is_err()
+        );
+        assert!(
+            handler
+                .handle(Cli::Device(DeviceCmd::Add {
+                    config: "virtio-gpu:x".to_string()
+                }))
+                .is_err()
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_gpio() {
+        assert!(
+            Handler::new(&mut create_board())
+                .handle(Cli::Gpio(GpioCmd::Show))
+                .is_err()
+        );
+
+        let mut board = RVBoardBuilder::new()
+            .gpio(GpioScript::default())
+            .build(Ram::new());
+        let mut handler = Handler::new(&mut board);
+        let CommandOutput::Gpio(state) = handler.execute("gpio set 3 high").unwrap() else {
+            panic!("no GPIO state");
+        };
+        assert_eq!(state.pins, 1 << 3);
+        assert_eq!(state.driven, 1 << 3);
+
+        handler.execute("gpio set 4 1").unwrap();
+        let CommandOutput::Gpio(state) = handler.execute("gpio release 3").unwrap() else {
+            panic!("no GPIO state");
+        };
+        assert_eq!(state.pins, 1 << 4);
+        assert_eq!(state.driven, 1 << 4);
+        assert!(handler.execute("gpio set 32 0").is_err());
+    }
+
+    #[test]
+    fn test_device_faults() {
+        let path = std::path::Path::new("./tmp/test_device_faults.img");
+        fs::create_dir_all("./tmp").unwrap();
+        fs::write(&path, [0u8; 512]).unwrap();
+
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+        let config = format!("virtio-block:{}", path.display());
+        let CommandOutput::DeviceAdded(info) = handler
+            .handle(Cli::Device(DeviceCmd::Add { config }))
+            .unwrap()
+        else {
+            panic!("device not added");
+        };
+
+        let faults = |latency, eio_every, torn_flush| {
+            Cli::Device(DeviceCmd::Faults {
+                id: info.slot,
+                latency,
+                eio_every,
+                torn_flush,
+            })
+        };
+        handler.handle(faults(Some(1000), Some(4), None)).unwrap();
+        assert_eq!(
+            handler.handle(faults(None, None, Some(true))),
+            Ok(CommandOutput::DeviceFaults {
+                slot: info.slot,
+                faults: FaultConfig {
+                    latency: 1000,
+                    eio_every: 4,
+                    torn_flush: true,
+                },
+            })
+        );
+        assert!(
+            handler
+                .handle(Cli::Device(DeviceCmd::Faults {
+                    id: info.slot + 1,
+                    latency: None,
+                    eio_every: None,
+                    torn_flush: None,
+                }))
+                .is_err()
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_info_devices() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+
+        let CommandOutput::Devices(stats) = handler
+            .handle(Cli::Info(InfoCmd::Devices { json: false }))
+            .unwrap()
+        else {
+            panic!("expected device stats");
+        };
+        let uart = stats.iter().find(|d| d.name.starts_with("uart")).unwrap();
+        assert!(uart.irq.is_some());
+
+        let CommandOutput::Json(json) = handler
+            .handle(Cli::Info(InfoCmd::Devices { json: true }))
+            .unwrap()
+        else {
+            panic!("expected JSON");
+        };
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value.as_array().unwrap().len(), stats.len());
+    }
+
+    #[test]
+    fn test_info_mtree() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+
+        let CommandOutput::Mtree(regions) = handler.execute("info mtree").unwrap() else {
+            panic!("expected memory regions");
+        };
+        assert!(
+            regions
+                .windows(2)
+                .all(|w| w[0].start + w[0].size <= w[1].start)
+        );
+        assert!(regions.iter().any(|r| r.name.starts_with("uart")));
+        let ram = regions.last().unwrap();
+        assert_eq!(
+            (ram.start, ram.kind),
+            (ram_config::BASE_ADDR, RegionKind::Ram)
+        );
+
+        let CommandOutput::Json(json) = handler.execute("info mtree --json").unwrap() else {
+            panic!("expected JSON");
+        };
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[0]["kind"], "device");
+        assert_eq!(value.as_array().unwrap().len(), regions.len());
+    }
+
+    #[test]
+    fn test_info_registers() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+
+        let CommandOutput::Registers {
+            pc,
+            privilege,
+            regs,
+            fregs,
+            csrs,
+        } = handler.execute("info registers").unwrap()
+        else {
+            panic!("expected registers");
+        };
+        assert_eq!(pc, handler.dbg.read_pc());
+        assert_eq!(privilege, PrivilegeLevel::M);
+        assert_eq!(regs.len(), REGFILE_CNT);
+        assert_eq!(fregs.len(), FLOAT_REGFILE_CNT);
+        assert_eq!(csrs.len(), SUMMARY_CSRS.len());
+        assert_eq!(csrs[0].0, "mstatus");
+        assert!(csrs.iter().all(|(_, val)| val.is_some()));
+        assert!(matches!(
+            handler.execute("info r"),
+            Ok(CommandOutput::Registers { .. })
+        ));
+    }
+
+    #[test]
+    fn test_info_vm() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+
+        let vm = |handler: &mut Handler<'_, VirtBoard>| match handler.execute("info vm") {
+            Ok(CommandOutput::Vm(vm)) => vm,
+            other => panic!("expected vm info, got {:?}", other),
+        };
+        assert_eq!(vm(&mut handler).mode, "Bare");
+        let satp = (8 << 60) | (5 << 44) | 0x80001;
+        handler.dbg.write_csr(CSR_ADDRESS["satp"], satp).unwrap();
+        let info = vm(&mut handler);
+        assert_eq!((info.mode, info.root, info.asid), ("Sv39", 0x8000_1000, 5));
+    }
+
+    #[test]
+    fn test_changed_registers() {
+        let bytes: Vec<u8> = [0x0030_0293u32, 0x0000_0013] // addi t0, zero, 3; nop
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+        let mut board = VirtBoard::from_binary(&bytes);
+        let mut handler = Handler::new(&mut board);
+        let t0 = |handler: &mut Handler<VirtBoard>| match handler.execute("print reg t0") {
+            Ok(CommandOutput::Reg(reg)) => reg,
+            other => panic!("expected a register, got {:?}", other),
+        };
+        assert!(!t0(&mut handler).changed);
+
+        handler.execute("si").unwrap();
+        assert_eq!(
+            t0(&mut handler),
+            RegValue {
+                name: "t0",
+                val: 3,
+                changed: true
+            }
+        );
+        let Ok(CommandOutput::Regs(regs)) = handler.execute("print regs") else {
+            panic!("expected registers");
+        };
+        assert_eq!(
+            regs.iter()
+                .filter(|reg| reg.changed)
+                .map(|reg| reg.name)
+                .collect::<Vec<_>>(),
+            ["t0"]
+        );
+
+        handler.execute("si").unwrap();
+        assert!(!t0(&mut handler).changed);
+    }
+
+    #[test]
+    fn test_find_compare() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+        let base = ram_config::BASE_ADDR;
+        for (i, byte) in b"hello, world".iter().enumerate() {
+            handler
+                .dbg
+                .write_memory(Address::Phys(base + 0x100 + i as u64), *byte)
+                .unwrap();
+        }
+        handler
+            .dbg
+            .write_memory(Address::Phys(base + 0x200), 0xdead_beefu32)
+            .unwrap();
+
+        let find = |handler: &mut Handler<VirtBoard>, args: &str| match handler
+            .execute(&format!("find {:#x} 0x1000 {}", base, args))
+        {
+            Ok(CommandOutput::Found { matches, truncated }) => (matches, truncated),
+            other => panic!("expected matches, got {:?}", other),
+        };
+        assert_eq!(
+            find(&mut handler, "-k ascii hello, world").0,
+            [Address::Phys(base + 0x100)]
+        );
+        assert_eq!(find(&mut handler, "6c 6c").0, [Address::Phys(base + 0x102)]);
+        assert_eq!(
+            find(&mut handler, "-k u32 0xdeadbeef").0,
+            find(&mut handler, "efbeadde").0
+        );
+        assert_eq!(
+            find(&mut handler, "-m 1 6c").0,
+            [Address::Phys(base + 0x102)]
+        );
+        assert!(find(&mut handler, "-m 1 6c").1);
+        assert!(find(&mut handler, "abcdef12").0.is_empty());
+        assert!(handler.execute("find 0x0 0x10 abc").is_err());
+
+        let compare = format!("compare {:#x} {:#x} 12", base + 0x100, base + 0x200);
+        let Ok(CommandOutput::Compare { count, diffs, .. }) = handler.execute(&compare) else {
+            panic!("expected a comparison");
+        };
+        assert_eq!(count, 12);
+        assert_eq!(
+            diffs[0],
+            debugger::MemDiff {
+                offset: 0,
+                left: Some(b'h'),
+                right: Some(0xef)
+            }
+        );
+        let same = format!("cmp {:#x} {:#x} 12", base + 0x100, base + 0x100);
+        assert!(matches!(
+            handler.execute(&same),
+            Ok(CommandOutput::Compare { count: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_dump_load_mem() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+        let base = ram_config::BASE_ADDR;
+        let path = "./tmp/test_dump_load_mem.bin";
+        fs::create_dir_all("./tmp").unwrap();
+        for (i, byte) in b"buffer".iter().enumerate() {
+            handler
+                .dbg
+                .write_memory(Address::Phys(base + i as u64), *byte)
+                .unwrap();
+        }
+
+        assert_eq!(
+            handler.execute(&format!("dump mem {:#x} 6 {}", base, path)),
+            Ok(CommandOutput::MemDumped {
+                addr: Address::Phys(base),
+                len: 6,
+                path: path.to_string(),
+            })
+        );
+        assert_eq!(fs::read(path).unwrap(), b"buffer");
+
+        assert_eq!(
+            handler.execute(&format!("load mem {} {:#x}", path, base + 0x100)),
+            Ok(CommandOutput::MemLoaded {
+                addr: Address::Phys(base + 0x100),
+                len: 6,
+            })
+        );
+        assert_eq!(
+            handler
+                .dbg
+                .read_memory::<u8>(Address::Phys(base + 0x105))
+                .unwrap(),
+            b'r'
+        );
+
+        assert!(
+            handler
+                .execute(&format!("dump mem 0x0 4 {}", path))
+                .is_err()
+        );
+        // Fails at the end of the RAM instead of allocating the whole length up front.
+        let end = base + ram_config::SIZE as u64 - 4;
+        assert!(
+            handler
+                .execute(&format!(
+                    "dump mem {:#x} {:#x} {}",
+                    end,
+                    u64::MAX >> 1,
+                    path
+                ))
+                .is_err()
+        );
+        assert!(handler.execute(&format!("load mem {} 0x0", path)).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_info_istats() {
+        let bytes: Vec<u8> = [0x00000013u32; 3] // nop
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+        let mut board = VirtBoard::from_binary(&bytes);
+        let mut handler = Handler::new(&mut board);
+        for _ in 0..3 {
+            handler.execute("si").unwrap();
+        }
+
+        let CommandOutput::Istats { stats, count } = handler.execute("info istats 1").unwrap()
+        else {
+            panic!("expected instruction stats");
+        };
+        assert_eq!(count, 1);
+        assert_eq!(stats.total, 3);
+        assert_eq!(stats.isa.iter().map(|line| line.count).sum::<u64>(), 3);
+
+        let CommandOutput::Json(json) = handler.execute("info istats --json --reset").unwrap()
+        else {
+            panic!("expected JSON");
+        };
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["total"], 3);
+        let CommandOutput::Istats { stats, .. } = handler.execute("info istat").unwrap() else {
+            panic!("expected instruction stats");
+        };
+        assert_eq!(stats.total, 0);
+    }
+
+    #[test]
+    fn test_taint() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+        assert!(handler.execute("info taint").is_err());
+
+        handler.execute("taint uart").unwrap();
+        handler.execute("taint mem 0x80001002 -l 2").unwrap();
+        assert_eq!(
+            handler.execute("info taint 0x80001000 -l 4"),
+            Ok(CommandOutput::TaintMem {
+                addr: 0x8000_1000,
+                tainted: vec![false, false, true, true],
+            })
+        );
+        assert_eq!(
+            handler.execute("info taint a0"),
+            Ok(CommandOutput::TaintReg {
+                name: "a0".to_string(),
+                tainted: false,
+            })
+        );
+        assert!(handler.execute("taint disk 0 0 512").is_err());
+
+        handler.execute("taint clear").unwrap();
+        assert_eq!(
+            handler.execute("info taint"),
+            Ok(CommandOutput::TaintSummary {
+                regs: Vec::new(),
+                bytes: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_breakpoint_ops() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+
+        const ADDR: WordType = 0x80001000;
+
+        // Set breakpoint
+        let result = handler
+            .handle(Cli::Breakpoint {
+                delete: false,
+                symbol: ADDR.to_string(),
+                virt: false,
+                hart: None,
+            })
+            .unwrap();
+
+        assert_eq!(
+            result,
+            CommandOutput::BreakpointSet {
+                ok: true,
+                addr: Address::Phys(ADDR),
+                symbol: None
+            }
+        );
+
+        // Physical breakpoint cannot be removed by virtual address
+        let result = handler
+            .handle(Cli::Breakpoint {
+                delete: true,
+                symbol: ADDR.to_string(),
+                virt: true,
+                hart: None,
+            })
+            .unwrap();
+
+        assert_eq!(
+            result,
+            CommandOutput::BreakpointCleared {
+                ok: false,
+                addr: Address::Virt(ADDR),
+                symbol: None
+            }
+        );
+
+        // Remove breakpoint
+        let result = handler
+            .handle(Cli::Breakpoint {
+                delete: true,
+                symbol: ADDR.to_string(),
+                virt: false,
+                hart: None,
+            })
+            .unwrap();
+
+        assert_eq!(
+            result,
+            CommandOutput::BreakpointCleared {
+                ok: true,
+                addr: Address::Phys(ADDR),
+                symbol: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_hart() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+
+        let harts = Ok(CommandOutput::Harts {
+            current: 0,
+            harts: vec![(ram_config::BASE_ADDR, PrivilegeLevel::M)],
+        });
+        assert_eq!(handler.execute("hart"), harts);
+        assert_eq!(handler.execute("hart 0"), harts);
+        assert!(handler.execute("hart 1").is_err());
+
+        // A breakpoint for one hart.
+        assert!(handler.execute("break 0x80001000 --hart 1").is_err());
+        handler.execute("break 0x80001000 --hart 0").unwrap();
+        let Ok(CommandOutput::Breakpoints(bps)) = handler.execute("info breakpoints") else {
+            panic!("no breakpoints");
+        };
+        assert_eq!(bps[0].hart, Some(0));
+    }
+
+    #[test]
+    fn test_ftrace_start_stop_show_and_stat() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+
+        assert_eq!(
+            handler.handle(Cli::FTrace(FTraceCmd::Stat)).unwrap(),
+            CommandOutput::FTraceStat(crate::isa::riscv::debugger::FtraceStatsSnapshot {
+                enabled: false,
+                queue_len: 0,
+                call_count: 0,
+                return_count: 0,
+                unknown_calls: 0,
+                unknown_returns: 0,
+                per_func: Vec::new(),
+            })
+        );
+
+        assert_eq!(
+            handler.handle(Cli::FTrace(FTraceCmd::Start)).unwrap(),
+            CommandOutput::FTraceStatus { enabled: true }
+        );
+        assert_eq!(
+            handler.handle(Cli::FTrace(FTraceCmd::Stop)).unwrap(),
+            CommandOutput::FTraceStatus { enabled: false }
+        );
+        assert_eq!(
+            handler
+                .handle(Cli::FTrace(FTraceCmd::Show { count: 5 }))
+                .unwrap(),
+            CommandOutput::FTraceShow(Vec::new())
+        );
+    }
+
+    fn board_with_program(instrs: &[u16]) -> VirtBoard {
+        let mut bytes = Vec::new();
+        for half in instrs {
+            bytes.extend_from_slice(&half.to_le_bytes());
+        }
+        VirtBoard::from_binary(&bytes)
+    }
+
+    #[test]
+    #[cfg(feature = "riscv64")]
+    fn test_list_advances_by_instruction_length() {
+        use crate::ram_config::BASE_ADDR;
+
+        // c.addi s0,5 (2B) | addi x2,x3,-5 (4B) | c.li a0,-3 (2B)
+        let mut board = board_with_program(&[0x0415, 0x8113, 0xffb1, 0x5575]);
+        let mut handler = Handler::new(&mut board);
+
+        let CommandOutput::CodeList(lines) = handler.handle(Cli::List).unwrap() else {
+            panic!("expected a code list");
+        };
+
+        // The listing starts at the current PC and walks forward by each
+        // instruction's real length, mixing 2- and 4-byte instructions.
+        assert_eq!(lines[0].addr, BASE_ADDR);
+        assert_eq!(lines[0].decoded.unwrap().len, 2);
+        assert!(lines[0].is_current_pc);
+
+        assert_eq!(lines[1].addr, BASE_ADDR + 2);
+        assert_eq!(lines[1].decoded.unwrap().len, 4);
+        assert!(!lines[1].is_current_pc);
+
+        assert_eq!(lines[2].addr, BASE_ADDR + 6);
+        assert_eq!(lines[2].decoded.unwrap().len, 2);
+        assert_eq!(lines[3].addr, BASE_ADDR + 8);
+    }
+
+    #[test]
+    #[cfg(feature = "riscv64")]
+    fn test_decoded_length_in_history() {
+        use crate::ram_config::BASE_ADDR;
+
+        // c.li a0,-3 (2B) then c.addi s0,5 (2B): stepping must advance PC by 2.
+        let mut board = board_with_program(&[0x5575, 0x0415]);
+        let mut handler = Handler::new(&mut board);
+
+        handler.handle(Cli::Si).unwrap();
+
+        let CommandOutput::History(history) = handler.handle(Cli::History { count: 8 }).unwrap()
+        else {
+            panic!("expected history");
+        };
+
+        // The single executed instruction was compressed; its recorded entry
+        // must decode with a 2-byte length, and the PC advanced by 2.
+        let first = history.first().expect("history should not be empty");
+        assert_eq!(first.addr, BASE_ADDR);
+        assert_eq!(first.decoded.unwrap().len, 2);
+        assert_eq!(handler.dbg.read_pc(), BASE_ADDR + 2);
+    }
+
+    #[test]
+    fn test_help() {
+        let mut board = create_board();
+        let mut handler = Handler::new(&mut board);
+        let page = |output| match output {
+            Ok(CommandOutput::Help(page)) => page,
+            other => panic!("expected help, got {:?}", other),
+        };
+
+        let list = page(handler.execute("help"));
+        assert!(
+            list.lines()
+                .any(|line| line.contains("continue, c") && line.contains("Continue running"))
+        );
+        assert!(page(handler.execute("h info istat")).contains("Clear the counters"));
+        assert!(page(handler.execute("info istats --help")).contains("--reset"));
+        assert_eq!(
+            handler.execute("help info nope"),
+            Err("unknown command: info nope".to_string())
+        );
+    }
+}
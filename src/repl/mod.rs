@@ -0,0 +1,630 @@
+//! The rvdb debugger command engine: [`Cli`] parses a command line, [`Handler`] runs it on a
+//! [`Board`](crate::board::Board) and returns a [`CommandOutput`], which [`Printer`] shows on the
+//! terminal. [`DebugREPL`] puts them together with a line editor, other front-ends can drive
+//! [`Handler::execute`] and render the outputs themselves.
+
+mod completer;
+mod editor;
+mod handler;
+mod printer;
+mod script;
+
+use crate::board::HotplugInfo;
+use crate::config::arch_config::REGFILE_CNT;
+use crate::config::arch_config::WordType;
+use crate::device::FaultConfig;
+use crate::device::MemoryRegion;
+use crate::device::sifive_gpio::GpioState;
+use crate::device::stats::DeviceStats;
+use crate::isa::riscv::RawInstr;
+use crate::isa::riscv::alloc_track::{AllocStats, Allocation};
+use crate::isa::riscv::csr_reg::PrivilegeLevel;
+use crate::isa::riscv::debugger;
+use crate::isa::riscv::instr_stats::IstatsSnapshot;
+use crate::isa::riscv::mmu::AccessType;
+use crate::isa::riscv::trap::trap_log::TrapRecord;
+use crate::isa::riscv::{debugger::Address, decoder::DecodeInstr};
+use clap::{Parser, Subcommand};
+
+pub use editor::DebugREPL;
+pub use handler::Handler;
+pub use printer::Printer;
+pub use script::{DebugScript, DebugScriptError};
+
+#[derive(clap::ValueEnum, Debug, Clone)]
+pub enum ClapAccessType {
+    Read,
+    Write,
+}
+
+impl std::fmt::Display for ClapAccessType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClapAccessType::Read => write!(f, "read"),
+            ClapAccessType::Write => write!(f, "write"),
+        }
+    }
+}
+
+/// How `find` reads its pattern.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternKind {
+    /// Hex bytes, e.g. `de ad be ef` or `deadbeef`.
+    Bytes,
+    /// Text, the words joined by single spaces.
+    Ascii,
+    /// Little-endian 32-bit words.
+    U32,
+}
+
+impl From<ClapAccessType> for AccessType {
+    fn from(value: ClapAccessType) -> Self {
+        match value {
+            ClapAccessType::Read => AccessType::Read,
+            ClapAccessType::Write => AccessType::Write,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(multicall = true, disable_help_subcommand = true)]
+pub enum Cli {
+    /// Print items such as registers, the PC, or memory.
+    #[command(alias = "p", subcommand)]
+    Print(PrintCmd),
+
+    /// Display a given item each time the program stops.
+    #[command(alias = "d", subcommand)]
+    Display(PrintCmd),
+    /// Translate a given address as a real instruction would.
+    /// This respects the current CPU state (e.g., privilege level and CSR settings)
+    /// and page table flags, based on the given access type.
+    #[command(aliases = ["t", "trans"])]
+    Translate {
+        addr: String,
+        #[arg(default_value_t = ClapAccessType::Read)]
+        access: ClapAccessType,
+    },
+
+    /// Cancel a display request.
+    #[command(subcommand)]
+    Undisplay(PrintCmd),
+
+    /// List assembly around the current position.
+    #[command(aliases = ["l", "ls"])]
+    List,
+
+    /// Search memory for a pattern, e.g. `find 0x80000000 0x1000 de ad be ef` or
+    /// `find -k ascii 0x80000000 0x1000 hello world`.
+    Find {
+        start: String,
+        len: String,
+        #[arg(required = true)]
+        pattern: Vec<String>,
+        #[arg(short, long, value_enum, default_value_t = PatternKind::Bytes)]
+        kind: PatternKind,
+        /// Whether the address is virtual or physical.
+        #[arg(short, long, default_value_t = false)]
+        virt: bool,
+        /// Stop after this many matches.
+        #[arg(short, long, default_value_t = 32)]
+        max: usize,
+    },
+
+    /// Compare two memory ranges byte by byte, e.g. a DMA buffer with its source.
+    #[command(alias = "cmp")]
+    Compare {
+        addr1: String,
+        addr2: String,
+        len: String,
+        /// Whether the addresses are virtual or physical.
+        #[arg(short, long, default_value_t = false)]
+        virt: bool,
+        /// Number of differences to show.
+        #[arg(short, long, default_value_t = 32)]
+        max: usize,
+    },
+
+    /// Save guest memory to a file, e.g. `dump mem 0x80000000 0x1000 buf.bin`.
+    #[command(subcommand)]
+    Dump(DumpCmd),
+
+    /// Write a file into guest memory, e.g. `load mem blob.bin 0x80000000`.
+    #[command(subcommand)]
+    Load(LoadCmd),
+
+    /// Show historical PC values.
+    #[command(alias = "his")]
+    History {
+        #[arg(default_value_t = 20)]
+        count: usize,
+    },
+
+    /// Show function call trace.
+    #[command(aliases = ["ft", "ftrace"], subcommand)]
+    FTrace(FTraceCmd),
+
+    /// Track guest heap allocations through allocator hooks, e.g. `alloc hook kmalloc kfree`.
+    #[command(subcommand)]
+    Alloc(AllocCmd),
+
+    /// Follow the data the guest reads from a source through registers and memory,
+    /// e.g. `taint uart`, then query it with `info taint`.
+    #[command(subcommand)]
+    Taint(TaintCmd),
+
+    /// Load an ELF symbol file.
+    #[command(aliases = ["symbol", "file"])]
+    SymbolFile { path: String },
+
+    /// Step a single instruction.
+    #[command(aliases = ["s", "step"])]
+    Si,
+
+    /// Continue running.
+    #[command(name = "continue", aliases = ["c"])]
+    Continue {
+        #[arg(default_value_t = u64::MAX)]
+        steps: u64,
+    },
+
+    /// Set or delete a breakpoint.
+    #[command(name = "break", alias = "b")]
+    Breakpoint {
+        #[arg(short = 'd', long = "delete")]
+        delete: bool,
+        /// Address or function symbol name to set/delete a breakpoint.
+        /// Address should be decimal by default, or hex if prefixed with `0x`.
+        symbol: String,
+
+        /// Whether the address is virtual or physical.
+        #[arg(short, long, default_value_t = false)]
+        virt: bool,
+
+        /// Stop only hart N, every hart stops by default.
+        #[arg(long, value_name = "N")]
+        hart: Option<usize>,
+    },
+
+    /// Show the harts, or select hart ID for the commands which show or change registers, CSRs
+    /// and memory.
+    Hart { id: Option<usize> },
+
+    /// Set an external interrupt source pending in the PLIC.
+    Irq { id: u32 },
+
+    /// Plug or remove VirtIO devices while the guest runs.
+    #[command(alias = "dev", subcommand)]
+    Device(DeviceCmd),
+
+    /// Show the GPIO pins or drive the inputs, e.g. `gpio set 3 high`.
+    #[command(subcommand)]
+    Gpio(GpioCmd),
+
+    /// Reset the board as at power-on, RAM keeps its content.
+    Reset {
+        /// Load the boot images to RAM again.
+        #[arg(short, long)]
+        reload: bool,
+    },
+
+    /// Show information such as breakpoints.
+    #[command(subcommand)]
+    Info(InfoCmd),
+
+    /// Change debugger options.
+    #[command(subcommand)]
+    Set(SetCmd),
+
+    /// List the commands, or show the arguments and options of one, e.g. `help info istats`.
+    #[command(alias = "h")]
+    Help { command: Vec<String> },
+
+    /// Quit the debugger
+    #[command(name = "quit", aliases = ["q", "exit"]) ]
+    Quit,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PrintCmd {
+    /// Program counter
+    Pc,
+    /// General-purpose register
+    Reg {
+        /// Register name
+        reg: String,
+    },
+    /// Some general-purpose registers
+    Regs {
+        /// Starting register index
+        #[arg(long, default_value_t = 0)]
+        start: u8,
+        /// Number of registers
+        #[arg(short, long, default_value_t = REGFILE_CNT as u8)]
+        len: u8,
+    },
+    /// Memory (in virtual or physical address space)
+    Mem {
+        addr: String,
+        #[arg(short, long, default_value_t = 16)]
+        len: u32,
+        /// Whether the address is virtual or physical.
+        #[arg(short, long, default_value_t = false)]
+        virt: bool,
+    },
+    /// Control and status register
+    Csr { addr: String },
+    /// Floating-point register
+    FReg { reg: String },
+    /// Vector register
+    VReg { reg: String },
+    /// Privilege level
+    Priv,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum InfoCmd {
+    /// Every integer and floating-point register, the pc, the privilege level and the main CSRs.
+    #[command(aliases = ["r", "reg", "regs"])]
+    Registers,
+    #[command(aliases = ["b", "bp", "break"])]
+    Breakpoints,
+    #[command(aliases = ["sym", "symbol"])]
+    Symbols,
+    /// The last traps taken by the hart, most recent first.
+    #[command(aliases = ["exc", "traps"])]
+    Exceptions {
+        #[arg(default_value_t = 20)]
+        count: usize,
+    },
+    /// Access and interrupt counters of the memory-mapped devices.
+    #[command(aliases = ["dev", "device"])]
+    Devices {
+        /// Print as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// The guest physical address map: the RAM and every memory-mapped device.
+    #[command(aliases = ["mem", "memmap"])]
+    Mtree {
+        /// Print as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Whether a register or memory is tainted, or every tainted register without argument.
+    Taint {
+        /// Register name or address.
+        item: Option<String>,
+        #[arg(short, long, default_value_t = 16)]
+        len: u32,
+        /// Whether the address is virtual or physical.
+        #[arg(short, long, default_value_t = false)]
+        virt: bool,
+    },
+    /// Address translation: the satp mode, the root page table, the ASID and the TLB.
+    #[command(alias = "mmu")]
+    Vm,
+    /// Executed instructions by ISA subset, class and opcode.
+    #[command(alias = "istat")]
+    Istats {
+        /// Number of opcodes to show.
+        #[arg(default_value_t = 20)]
+        count: usize,
+        /// Print as JSON, with every opcode.
+        #[arg(long)]
+        json: bool,
+        /// Clear the counters after showing them.
+        #[arg(long)]
+        reset: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SetCmd {
+    /// Leave interrupts pending while single-stepping, so `si` stays in the interrupted code.
+    /// They are taken on the next `continue`.
+    StepOverInterrupt {
+        #[arg(action = clap::ArgAction::Set, value_parser = clap::builder::BoolishValueParser::new())]
+        enabled: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DeviceCmd {
+    /// Plug a device into a free VirtIO slot, e.g. `virtio-block:disk.img`.
+    Add { config: String },
+    /// Remove the device in VirtIO slot `id`.
+    #[command(alias = "rm")]
+    Remove { id: usize },
+    /// Show or change the faults injected into the disk in VirtIO slot `id`.
+    Faults {
+        id: usize,
+        /// Guest clock ticks between a notification and the completion of the requests.
+        #[arg(long)]
+        latency: Option<u64>,
+        /// Fail every N-th disk operation with an I/O error, 0 for never.
+        #[arg(long)]
+        eio_every: Option<u32>,
+        /// Tear the last write before each flush and fail the flush.
+        #[arg(long, value_parser = clap::builder::BoolishValueParser::new())]
+        torn_flush: Option<bool>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum GpioCmd {
+    /// Show the direction and level of the pins in use.
+    Show,
+    /// Drive input PIN high or low.
+    Set {
+        pin: u32,
+        /// `0`/`low` or `1`/`high`.
+        #[arg(action = clap::ArgAction::Set, value_parser = parse_gpio_level)]
+        level: bool,
+    },
+    /// Stop driving PIN, it floats low again.
+    Release { pin: u32 },
+}
+
+fn parse_gpio_level(level: &str) -> Result<bool, String> {
+    match level {
+        "0" | "low" => Ok(false),
+        "1" | "high" => Ok(true),
+        _ => Err("expected 0, 1, low or high".to_string()),
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AllocCmd {
+    /// Record the allocations returned by ALLOC until they are passed to FREE.
+    /// Both are symbol names or addresses.
+    Hook {
+        alloc: String,
+        free: String,
+        /// Argument of ALLOC holding the size, 0 for `a0`.
+        #[arg(long, default_value_t = 0)]
+        size_arg: u8,
+        /// Argument of FREE holding the pointer, 0 for `a0`.
+        #[arg(long, default_value_t = 0)]
+        ptr_arg: u8,
+    },
+    /// Show the allocations not freed yet, oldest first.
+    Report {
+        #[arg(default_value_t = 20)]
+        count: usize,
+    },
+    /// Forget the recorded allocations, the hooks are kept.
+    Clear,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TaintCmd {
+    /// What the guest reads from the UART.
+    Uart,
+    /// What the disk in VirtIO slot `id` reads from LEN bytes at OFFSET.
+    Disk {
+        id: usize,
+        offset: String,
+        len: String,
+    },
+    /// Physical memory, e.g. a buffer the guest filled already.
+    Mem {
+        addr: String,
+        #[arg(short, long, default_value_t = 1)]
+        len: u64,
+    },
+    /// Clean the registers and memory, the sources stay tainted.
+    Clear,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DumpCmd {
+    /// LEN bytes from ADDR, which must all be readable.
+    Mem {
+        addr: String,
+        len: String,
+        file: String,
+        /// Whether the address is virtual or physical.
+        #[arg(short, long, default_value_t = false)]
+        virt: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum LoadCmd {
+    /// The whole file, from ADDR.
+    Mem {
+        file: String,
+        addr: String,
+        /// Whether the address is virtual or physical.
+        #[arg(short, long, default_value_t = false)]
+        virt: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FTraceCmd {
+    Start,
+    Stop,
+    Show {
+        #[arg(default_value_t = 20)]
+        count: usize,
+    },
+    Stat,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrintObject {
+    Pc,
+    Reg(u8),
+    Regs(u8, u8),
+    Mem(u64, u32, bool), // addr, len, is_virt
+    CSR(WordType),
+    FReg(u8),
+    VReg(u8), // index
+    Privilege,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DbgInstrLine {
+    pub addr: u64,
+    pub raw: Option<RawInstr>,
+    pub decoded: Option<DecodeInstr>,
+    pub symbol: Option<String>,
+    pub is_current_pc: bool,
+}
+
+/// An integer register, `changed` if it differs from the previous stop of the hart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegValue {
+    pub name: &'static str,
+    pub val: WordType,
+    pub changed: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandOutput {
+    None,
+    Exit,
+    /// The command list or the page of a command, as rendered by clap.
+    Help(String),
+
+    Pc(WordType),
+    Reg(RegValue),
+    Regs(Vec<RegValue>),
+    FReg {
+        name: String,
+        f32_val: f32,
+        f64_val: f64,
+    },
+    VReg {
+        name: String,
+        val: Vec<(String, Vec<WordType>)>,
+    },
+    Csr {
+        name: String,
+        val: Option<WordType>,
+    },
+    Mem {
+        addr: Address,
+        data: Vec<Option<u8>>,
+    },
+
+    /// The addresses `find` matched, `truncated` if it stopped at the maximum.
+    Found {
+        matches: Vec<Address>,
+        truncated: bool,
+    },
+    /// The result of `compare`: `count` of the `len` bytes differ, the first ones in `diffs`.
+    Compare {
+        addr1: Address,
+        addr2: Address,
+        len: u64,
+        count: u64,
+        diffs: Vec<debugger::MemDiff>,
+    },
+
+    Vm(debugger::VmInfo),
+
+    /// `len` bytes from `addr` saved to `path` by `dump mem`.
+    MemDumped {
+        addr: Address,
+        len: u64,
+        path: String,
+    },
+    /// `len` bytes written from `addr` by `load mem`.
+    MemLoaded {
+        addr: Address,
+        len: u64,
+    },
+
+    Translate {
+        virt_addr: WordType,
+        phys_addr: u64,
+    },
+
+    Privilege(PrivilegeLevel),
+    /// The pc and the privilege level of every hart, `current` is the selected one.
+    Harts {
+        current: usize,
+        harts: Vec<(WordType, PrivilegeLevel)>,
+    },
+    /// The state of the hart for `info registers`.
+    Registers {
+        pc: WordType,
+        privilege: PrivilegeLevel,
+        regs: Vec<RegValue>,
+        fregs: Vec<(&'static str, f32, f64)>,
+        csrs: Vec<(&'static str, Option<WordType>)>,
+    },
+
+    History(Vec<DbgInstrLine>),
+    CodeList(Vec<DbgInstrLine>),
+    Breakpoints(Vec<debugger::Breakpoint>),
+    Symbols(Vec<(String, WordType)>),
+    Devices(Vec<DeviceStats>),
+    Mtree(Vec<MemoryRegion>),
+    Traps(Vec<TrapRecord>),
+    /// The instruction histogram, with the `count` most executed opcodes.
+    Istats {
+        stats: IstatsSnapshot,
+        count: usize,
+    },
+    Json(String),
+    FTraceShow(Vec<debugger::FuncTrace>),
+    FTraceStat(debugger::FtraceStatsSnapshot),
+    FTraceStatus {
+        enabled: bool,
+    },
+
+    DeviceAdded(HotplugInfo),
+    Gpio(GpioState),
+    DeviceFaults {
+        slot: usize,
+        faults: FaultConfig,
+    },
+
+    AllocHookSet {
+        ok: bool,
+        alloc: String,
+        free: String,
+    },
+    AllocReport {
+        stats: AllocStats,
+        /// The oldest outstanding allocations, with the symbol of their caller.
+        allocations: Vec<(Allocation, Option<String>)>,
+    },
+
+    /// The tainted registers and the number of tainted bytes of memory.
+    TaintSummary {
+        regs: Vec<&'static str>,
+        bytes: u64,
+    },
+    TaintReg {
+        name: String,
+        tainted: bool,
+    },
+    /// Whether each byte from the physical `addr` is tainted.
+    TaintMem {
+        addr: u64,
+        tainted: Vec<bool>,
+    },
+
+    ContinueDone {
+        instr: DbgInstrLine,
+        watch_results: Vec<CommandOutput>,
+        event: debugger::DebugEvent,
+        actual_steps: u64,
+    },
+
+    BreakpointSet {
+        ok: bool,
+        addr: Address,
+        symbol: Option<String>,
+    },
+    BreakpointCleared {
+        ok: bool,
+        addr: Address,
+        symbol: Option<String>,
+    },
+}
@@ -0,0 +1,1061 @@
+use std::io::{self, Write};
+
+use super::DbgInstrLine;
+
+use super::{CommandOutput, RegValue};
+use crate::{
+    config::arch_config::{REG_NAME, WordType},
+    device::{
+        RegionKind,
+        pma::{AmoClass, Pma},
+    },
+    isa::riscv::{
+        RawInstr,
+        csr_reg::{PrivilegeLevel, csr_macro::CSR_NAME},
+        debugger::{self, Address},
+        decoder::DecodeInstr,
+        instruction::{RVInstrInfo, instr_table::RiscvInstr},
+        trap::Trap,
+    },
+};
+use crossterm::style::Stylize;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref palette: OutputPalette = OutputPalette {};
+}
+
+struct OutputPalette;
+
+impl OutputPalette {
+    fn index(&self, index: &str) -> impl std::fmt::Display {
+        index.yellow()
+    }
+
+    fn addr(&self, addr: &str) -> impl std::fmt::Display {
+        addr.blue()
+    }
+
+    fn reg(&self, reg: &str, padding: usize) -> impl std::fmt::Display {
+        format!("{:<width$}", reg, width = padding).magenta()
+    }
+
+    fn csr(&self, csr: &str) -> impl std::fmt::Display {
+        csr.dark_grey()
+    }
+
+    fn instr(&self, instr: &str) -> impl std::fmt::Display {
+        instr.green()
+    }
+
+    fn arrow(&self, ch: &str) -> impl std::fmt::Display {
+        ch.cyan()
+    }
+
+    fn data(&self, value: &str) -> impl std::fmt::Display {
+        value.yellow()
+    }
+
+    fn identifier(&self, value: &str) -> impl std::fmt::Display {
+        value.yellow()
+    }
+
+    /// A value which changed since the previous stop.
+    fn changed(&self, value: &str) -> impl std::fmt::Display {
+        value.red().bold()
+    }
+
+    fn invalid(&self, value: &str) -> impl std::fmt::Display {
+        value.red()
+    }
+
+    fn privilege(&self, value: &str) -> impl std::fmt::Display {
+        value.dark_grey()
+    }
+}
+
+pub struct Printer;
+
+impl Default for Printer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Printer {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn print(&self, output: &CommandOutput) {
+        // Nothing to do if stdout is closed.
+        let _ = self.write(output, &mut std::io::stdout().lock());
+    }
+
+    /// Write `output` without colors, e.g. to a file.
+    pub fn write_plain(&self, output: &CommandOutput, out: &mut dyn Write) -> io::Result<()> {
+        let mut colored = Vec::new();
+        self.write(output, &mut colored)?;
+        out.write_all(&strip_colors(&colored))
+    }
+
+    /// Write `output` as [`Self::print`] shows it, with colors.
+    pub fn write(&self, output: &CommandOutput, out: &mut dyn Write) -> io::Result<()> {
+        match output {
+            CommandOutput::None => {}
+            CommandOutput::Exit => {}
+            CommandOutput::Help(page) => {
+                writeln!(out, "{}", page.trim_end())?;
+            }
+
+            CommandOutput::Pc(pc) => {
+                writeln!(out, "pc = {}", format_addr(*pc))?;
+            }
+            CommandOutput::Reg(reg) => {
+                writeln!(out, "{} = {}", palette.reg(reg.name, 3), format_reg(reg, 8))?;
+            }
+            CommandOutput::Regs(regs) => {
+                for (idx, reg) in regs.iter().enumerate() {
+                    write!(out, "x{:<3} ", idx)?;
+                    writeln!(out, "{} = {}", palette.reg(reg.name, 5), format_reg(reg, 8))?;
+                }
+            }
+            CommandOutput::Registers {
+                pc,
+                privilege,
+                regs,
+                fregs,
+                csrs,
+            } => {
+                writeln!(
+                    out,
+                    "{} {}  {}",
+                    palette.reg("pc", 4),
+                    format_data_64(*pc),
+                    format_privilege(*privilege)
+                )?;
+                for row in regs.chunks(4) {
+                    for reg in row {
+                        write!(
+                            out,
+                            "{} {}  ",
+                            palette.reg(reg.name, 4),
+                            format_reg(reg, 16)
+                        )?;
+                    }
+                    writeln!(out)?;
+                }
+                for row in fregs.chunks(2) {
+                    for (name, f32_val, f64_val) in row {
+                        let views = format!("f32 {:<13e} f64 {:<13e}", f32_val, f64_val);
+                        write!(out, "{} {}  ", palette.reg(name, 4), palette.data(&views))?;
+                    }
+                    writeln!(out)?;
+                }
+                for row in csrs.chunks(4) {
+                    for (name, val) in row {
+                        let val = match val {
+                            Some(val) => format_data_64(*val).to_string(),
+                            None => palette.invalid(&format!("{:<18}", "-")).to_string(),
+                        };
+                        write!(out, "{} {}  ", palette.csr(&format!("{:>7}", name)), val)?;
+                    }
+                    writeln!(out)?;
+                }
+            }
+            CommandOutput::FReg {
+                name,
+                f32_val,
+                f64_val,
+            } => {
+                writeln!(
+                    out,
+                    "{} = {{f32: {}, f64: {}}}",
+                    palette.reg(name, 0),
+                    f32_val,
+                    f64_val,
+                )?;
+            }
+            CommandOutput::VReg { name, val } => {
+                writeln!(out, "{} = {{", palette.reg(name, 0))?;
+                for (c, data) in val {
+                    writeln!(
+                        out,
+                        "\t{} = {}, ",
+                        palette.data(c),
+                        palette.data(format!("{:?}", data).as_str())
+                    )?;
+                }
+                writeln!(out, "\n}}")?;
+            }
+            CommandOutput::Found { matches, truncated } => {
+                if matches.is_empty() {
+                    writeln!(out, "pattern not found")?;
+                }
+                for addr in matches {
+                    writeln!(out, "{}", format_address(*addr))?;
+                }
+                if *truncated {
+                    writeln!(out, "more matches not shown, raise --max")?;
+                }
+            }
+            CommandOutput::Compare {
+                addr1,
+                addr2,
+                len,
+                count,
+                diffs,
+            } => {
+                if *count == 0 {
+                    writeln!(out, "{} bytes identical", len)?;
+                } else {
+                    writeln!(
+                        out,
+                        "{} of {} bytes differ between {} and {}",
+                        count,
+                        len,
+                        format_address(*addr1),
+                        format_address(*addr2)
+                    )?;
+                }
+                let byte = |byte: Option<u8>| match byte {
+                    Some(byte) => palette.data(&format!("{:02x}", byte)).to_string(),
+                    None => palette.invalid("??").to_string(),
+                };
+                for diff in diffs {
+                    writeln!(
+                        out,
+                        "  +{}: {} != {}",
+                        palette.index(&format!("{:#x}", diff.offset)),
+                        byte(diff.left),
+                        byte(diff.right)
+                    )?;
+                }
+                if diffs.len() as u64 != *count {
+                    writeln!(out, "  ...")?;
+                }
+            }
+            CommandOutput::MemDumped { addr, len, path } => {
+                writeln!(
+                    out,
+                    "{} bytes from {} saved to {}",
+                    len,
+                    format_address(*addr),
+                    path
+                )?;
+            }
+            CommandOutput::MemLoaded { addr, len } => {
+                writeln!(out, "{} bytes loaded to {}", len, format_address(*addr))?;
+            }
+            CommandOutput::Vm(vm) => {
+                writeln!(out, "mode {}", palette.identifier(vm.mode))?;
+                writeln!(out, "root {}", format_addr(vm.root))?;
+                writeln!(out, "asid {}", palette.data(&vm.asid.to_string()))?;
+                writeln!(
+                    out,
+                    "tlb  {} translations cached for this ASID",
+                    palette.data(&vm.tlb_entries.to_string())
+                )?;
+            }
+            CommandOutput::Translate {
+                virt_addr,
+                phys_addr,
+            } => {
+                writeln!(
+                    out,
+                    "{} -> {}",
+                    format_addr(*virt_addr),
+                    format_addr(*phys_addr)
+                )?;
+            }
+            CommandOutput::Csr { name, val } => {
+                if let Some(v) = val {
+                    #[cfg(feature = "riscv64")]
+                    writeln!(out, "{} = {}", name, format_data_64(*v))?;
+                    #[cfg(feature = "riscv32")]
+                    writeln!(out, "{} = {}", name, format_data(*v))?;
+                } else {
+                    writeln!(out, "Illegal CSR.")?;
+                }
+            }
+            CommandOutput::Mem { addr, data } => {
+                const BYTE_PER_LINE: u32 = 16;
+                let mut curr_addr = *addr;
+                let mut i = 0;
+                let len = data.len() as u32;
+
+                while i < len {
+                    if i % BYTE_PER_LINE == 0 {
+                        write!(out, "{}: ", format_address(curr_addr))?;
+                    }
+
+                    if let Some(byte) = data[i as usize] {
+                        write!(out, "{:02x} ", byte)?;
+                    } else {
+                        write!(out, "?? ")?;
+                    }
+
+                    curr_addr = curr_addr + 1;
+                    i += 1;
+                    if i % BYTE_PER_LINE == 0 {
+                        writeln!(out)?;
+                    }
+                }
+                if len > 0 && !len.is_multiple_of(BYTE_PER_LINE) {
+                    writeln!(out)?;
+                }
+            }
+            CommandOutput::Privilege(privilege) => {
+                writeln!(out, "{}", format_privilege(*privilege))?;
+            }
+            CommandOutput::Harts { current, harts } => {
+                for (id, (pc, privilege)) in harts.iter().enumerate() {
+                    let marker = if id == *current { "*" } else { " " };
+                    writeln!(
+                        out,
+                        "{} hart {}: pc = {} ({})",
+                        marker,
+                        id,
+                        format_addr(*pc),
+                        format_privilege(*privilege)
+                    )?;
+                }
+            }
+
+            CommandOutput::History(history) => {
+                for (i, line) in history.iter().enumerate() {
+                    writeln!(out, "  [{}] {}", format_idx(i), format_instr(line),)?;
+                }
+            }
+            CommandOutput::CodeList(lines) => {
+                for line in lines {
+                    if line.is_current_pc {
+                        write!(out, "{} ", palette.arrow(">"))?;
+                    } else {
+                        write!(out, "  ")?;
+                    }
+
+                    writeln!(out, "{}", format_instr_detailed(line))?;
+                }
+            }
+            CommandOutput::Breakpoints(bps) => {
+                for bp in bps {
+                    write!(out, "{}: {}", format_idx(bp.id), format_address(bp.addr))?;
+                    match bp.hart {
+                        Some(hart) => writeln!(out, " (hart {})", hart)?,
+                        None => writeln!(out)?,
+                    }
+                }
+            }
+            CommandOutput::Symbols(symbols) => {
+                for (name, addr) in symbols {
+                    writeln!(out, "{}: {}", format_addr(*addr), palette.identifier(name))?;
+                }
+            }
+            CommandOutput::Devices(devices) => {
+                for dev in devices {
+                    writeln!(
+                        out,
+                        "{} @ {}: {} reads ({} B), {} writes ({} B)",
+                        palette.identifier(&dev.name),
+                        format_addr(dev.base),
+                        dev.reads,
+                        dev.bytes_read,
+                        dev.writes,
+                        dev.bytes_written,
+                    )?;
+                    if let Some(irq) = dev.irq {
+                        writeln!(out, "    irq {}: raised {} times", irq, dev.irqs_raised)?;
+                    }
+                    if let Some(depth) = dev.queue_depth {
+                        writeln!(out, "    queue depth: {}", depth)?;
+                    }
+                }
+            }
+            CommandOutput::Mtree(regions) => {
+                for region in regions {
+                    let kind = match region.kind {
+                        RegionKind::Ram => "ram",
+                        RegionKind::Device => "i/o",
+                    };
+                    writeln!(
+                        out,
+                        "{}-{} ({}): {} [{:#x}]{}",
+                        palette.addr(&format!("{:016x}", region.start)),
+                        palette.addr(&format!(
+                            "{:016x}",
+                            region.start + region.size.saturating_sub(1)
+                        )),
+                        kind,
+                        palette.identifier(&region.name),
+                        region.size,
+                        format_pma(&region.pma),
+                    )?;
+                }
+            }
+            CommandOutput::Traps(traps) => {
+                for (i, trap) in traps.iter().enumerate() {
+                    let cause = match trap.cause {
+                        Trap::Interrupt(interrupt) => format!("{:?}", interrupt),
+                        Trap::Exception(exception) => format!("{:?}", exception),
+                    };
+                    writeln!(
+                        out,
+                        "  [{}] {} at {}, tval {}, {} -> {}",
+                        format_idx(i),
+                        palette.identifier(&format!("{:<20}", cause)),
+                        format_addr(trap.pc),
+                        format_data(trap.tval),
+                        format_privilege(trap.from),
+                        format_privilege(trap.to),
+                    )?;
+                }
+            }
+            CommandOutput::Istats { stats, count } => {
+                writeln!(out, "{} instructions", stats.total)?;
+                let sections = [
+                    ("ISA", stats.isa.as_slice()),
+                    ("class", stats.class.as_slice()),
+                    ("opcode", &stats.instr[..stats.instr.len().min(*count)]),
+                ];
+                for (title, counts) in sections {
+                    writeln!(out, "by {}:", title)?;
+                    for line in counts {
+                        writeln!(
+                            out,
+                            "  {} {:>12} {:>6.2}%",
+                            palette.identifier(&format!("{:<12}", line.name)),
+                            line.count,
+                            line.percent
+                        )?;
+                    }
+                }
+            }
+            CommandOutput::Json(json) => {
+                writeln!(out, "{}", json)?;
+            }
+
+            CommandOutput::FTraceShow(traces) => {
+                for trace in traces {
+                    match trace {
+                        debugger::FuncTrace::Call { name, addr } => {
+                            let name = name.clone().unwrap_or("???".to_string());
+                            writeln!(
+                                out,
+                                "Call   -> [{}@{}]",
+                                palette.identifier(&name),
+                                format_addr(*addr)
+                            )?;
+                        }
+                        debugger::FuncTrace::Return { name, addr } => {
+                            let name = name.clone().unwrap_or("???".to_string());
+                            writeln!(
+                                out,
+                                "Return <- [{}@{}]",
+                                palette.identifier(&name),
+                                format_addr(*addr)
+                            )?;
+                        }
+                    }
+                }
+            }
+            CommandOutput::FTraceStat(stats) => {
+                writeln!(
+                    out,
+                    "ftrace: {}",
+                    if stats.enabled { "running" } else { "stopped" }
+                )?;
+                writeln!(out, "queue: {} / {}", stats.queue_len, debugger::MAX_FTRACE)?;
+                writeln!(out, "calls: {}", stats.call_count)?;
+                writeln!(out, "returns: {}", stats.return_count)?;
+                writeln!(out, "unknown calls: {}", stats.unknown_calls)?;
+                writeln!(out, "unknown returns: {}", stats.unknown_returns)?;
+
+                if !stats.per_func.is_empty() {
+                    writeln!(out, "function stats:")?;
+                    let mut per_func = stats.per_func.clone().into_iter().collect::<Vec<_>>();
+                    per_func.sort_by_key(|(_, e)| e.calls + e.returns);
+                    for (name, entry) in per_func.into_iter().rev() {
+                        writeln!(
+                            out,
+                            "{} calls={:<5} returns={:<5}",
+                            palette.identifier(&format!("{:<32}", name)),
+                            entry.calls,
+                            entry.returns,
+                        )?;
+                    }
+                }
+            }
+            CommandOutput::FTraceStatus { enabled } => {
+                writeln!(
+                    out,
+                    "ftrace {}",
+                    if *enabled { "started" } else { "stopped" }
+                )?;
+            }
+            CommandOutput::AllocHookSet { ok, alloc, free } => {
+                if *ok {
+                    writeln!(
+                        out,
+                        "tracking allocations of {} freed by {}",
+                        palette.identifier(alloc),
+                        palette.identifier(free)
+                    )?;
+                } else {
+                    writeln!(out, "{} is hooked already", palette.identifier(alloc))?;
+                }
+            }
+            CommandOutput::AllocReport { stats, allocations } => {
+                writeln!(
+                    out,
+                    "{} outstanding allocations, {} bytes",
+                    stats.outstanding, stats.outstanding_bytes
+                )?;
+                writeln!(
+                    out,
+                    "allocs: {}, frees: {}, failed allocs: {}, unknown frees: {}",
+                    stats.allocs, stats.frees, stats.failed_allocs, stats.unknown_frees
+                )?;
+                for (i, (allocation, symbol)) in allocations.iter().enumerate() {
+                    let caller = symbol.as_deref().unwrap_or("???");
+                    writeln!(
+                        out,
+                        "  [{}] {} size {:<8} cycle {:<12} from {}@{}",
+                        format_idx(i),
+                        format_addr(allocation.ptr),
+                        allocation.size,
+                        allocation.cycle,
+                        palette.identifier(caller),
+                        format_addr(allocation.caller)
+                    )?;
+                }
+                if allocations.len() < stats.outstanding {
+                    writeln!(out, "  ... {} more", stats.outstanding - allocations.len())?;
+                }
+            }
+            CommandOutput::TaintSummary { regs, bytes } => {
+                match regs.is_empty() {
+                    true => writeln!(out, "no tainted register")?,
+                    false => {
+                        write!(out, "tainted registers:")?;
+                        for name in regs {
+                            write!(out, " {}", palette.reg(name, 0))?;
+                        }
+                        writeln!(out)?;
+                    }
+                }
+                writeln!(out, "{} tainted bytes of memory", bytes)?;
+            }
+            CommandOutput::TaintReg { name, tainted } => {
+                let state = if *tainted { "tainted" } else { "clean" };
+                writeln!(out, "{} is {}", palette.reg(name, 0), state)?;
+            }
+            CommandOutput::TaintMem { addr, tainted } => {
+                // `T` for a tainted byte, `.` for a clean one.
+                for (i, line) in tainted.chunks(16).enumerate() {
+                    let flags: String = line.iter().map(|&t| if t { 'T' } else { '.' }).collect();
+                    writeln!(out, "{}: {}", format_addr(addr + 16 * i as u64), flags)?;
+                }
+            }
+            CommandOutput::DeviceAdded(info) => {
+                writeln!(
+                    out,
+                    "device plugged into slot {} at {}, irq {}",
+                    info.slot,
+                    format_addr(info.base),
+                    info.irq
+                )?;
+            }
+            CommandOutput::DeviceFaults { slot, faults } => {
+                writeln!(out, "slot {slot}: latency {} ticks", faults.latency)?;
+                match faults.eio_every {
+                    0 => writeln!(out, "    no injected errors")?,
+                    n => writeln!(out, "    I/O error every {n} operations")?,
+                }
+                if faults.torn_flush {
+                    writeln!(out, "    flushes tear the last write")?;
+                }
+            }
+
+            CommandOutput::Gpio(state) => {
+                let used = state.input_en | state.output_en | state.driven | state.pins;
+                if used == 0 {
+                    writeln!(out, "no pin in use")?;
+                }
+                for pin in (0..u32::BITS).filter(|pin| used & (1 << pin) != 0) {
+                    let bit = |mask: u32| mask & (1 << pin) != 0;
+                    let direction = match (bit(state.output_en), bit(state.input_en)) {
+                        (true, _) => "out",
+                        (false, true) => "in",
+                        (false, false) => "off",
+                    };
+                    write!(
+                        out,
+                        "{:>2}: {:<3} {}",
+                        pin,
+                        direction,
+                        if bit(state.pins) { "high" } else { "low" }
+                    )?;
+                    if bit(state.driven) {
+                        write!(out, " (driven)")?;
+                    }
+                    if bit(state.pending) {
+                        write!(out, " (irq pending)")?;
+                    }
+                    writeln!(out)?;
+                }
+            }
+
+            CommandOutput::ContinueDone {
+                instr,
+                watch_results,
+                event,
+                actual_steps: steps,
+            } => {
+                match event {
+                    debugger::DebugEvent::StepCompleted => {
+                        writeln!(out, "Completed, next: {}", format_instr(instr))?;
+                    }
+                    debugger::DebugEvent::BreakpointHit => {
+                        writeln!(
+                            out,
+                            "Breakpoint hit after {} steps: {}",
+                            steps,
+                            format_instr(instr)
+                        )?;
+                    }
+                    debugger::DebugEvent::BoardBreak(reason) => {
+                        writeln!(
+                            out,
+                            "Stopped after {} steps, {}: {}",
+                            steps,
+                            palette.invalid(reason),
+                            format_instr(instr)
+                        )?;
+                    }
+                    debugger::DebugEvent::BoardHalted => {
+                        if *steps == 0 {
+                            writeln!(out, "Board already halted")?;
+                        } else {
+                            writeln!(
+                                out,
+                                "Board halted after {} steps: {}",
+                                steps,
+                                format_instr(instr)
+                            )?;
+                        }
+                    }
+                }
+                for res in watch_results {
+                    self.write(res, out)?;
+                }
+            }
+
+            CommandOutput::BreakpointSet { ok, addr, symbol } => {
+                if *ok {
+                    if let Some(sym) = symbol {
+                        writeln!(out, "Breakpoint set at {} <{}>", sym, format_address(*addr))?;
+                    } else {
+                        writeln!(out, "Breakpoint set at {}", format_address(*addr))?;
+                    }
+                } else {
+                    writeln!(
+                        out,
+                        "Breakpoint already exists at {}",
+                        format_address(*addr)
+                    )?;
+                }
+            }
+            CommandOutput::BreakpointCleared { ok, addr, symbol } => {
+                if *ok {
+                    if let Some(sym) = symbol {
+                        writeln!(
+                            out,
+                            "Breakpoint removed at {} <{}>",
+                            sym,
+                            format_address(*addr)
+                        )?;
+                    } else {
+                        writeln!(out, "Breakpoint removed at {}", format_address(*addr))?;
+                    }
+                } else {
+                    writeln!(out, "Breakpoint not found at {}", format_address(*addr))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Remove the escape sequences setting the colors, `ESC [ <params> m`.
+fn strip_colors(text: &[u8]) -> Vec<u8> {
+    let mut plain = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] == 0x1b && text.get(i + 1) == Some(&b'[') {
+            i += 2;
+            while i < text.len() && !(0x40..=0x7e).contains(&text[i]) {
+                i += 1;
+            }
+        } else {
+            plain.push(text[i]);
+        }
+        i += 1;
+    }
+    plain
+}
+
+/// The attributes which differ from an I/O region's.
+fn format_pma(pma: &Pma) -> String {
+    let mut attrs = String::new();
+    for (set, name) in [
+        (pma.cacheable, "cacheable"),
+        (pma.idempotent, "idempotent"),
+        (pma.reservable, "lr/sc"),
+    ] {
+        if set {
+            attrs.push(' ');
+            attrs.push_str(name);
+        }
+    }
+    if pma.amo != AmoClass::None {
+        attrs.push_str(&format!(" amo-{:?}", pma.amo).to_lowercase());
+    }
+    attrs
+}
+
+fn format_idx(idx: usize) -> impl std::fmt::Display {
+    palette.index(&format!("{:2}", idx)).to_string()
+}
+
+fn format_addr(word: WordType) -> impl std::fmt::Display {
+    palette.addr(&format!("0x{:08x}", word)).to_string()
+}
+
+fn format_address(addr: Address) -> impl std::fmt::Display {
+    match addr {
+        Address::Phys(addr) => format!("paddr({})", format_addr(addr)),
+        Address::Virt(addr) => format!("vaddr({})", format_addr(addr)),
+    }
+}
+
+fn format_data(data: WordType) -> impl std::fmt::Display {
+    palette.data(&format!("0x{:08x}", data)).to_string()
+}
+
+/// `digits` hex digits at least, highlighted if it changed since the previous stop.
+fn format_reg(reg: &RegValue, digits: usize) -> impl std::fmt::Display {
+    let val = format!("0x{:0digits$x}", reg.val);
+    match reg.changed {
+        true => palette.changed(&val).to_string(),
+        false => palette.data(&val).to_string(),
+    }
+}
+
+fn format_privilege(privilege: PrivilegeLevel) -> impl std::fmt::Display {
+    palette.privilege(&format!("{:?}", privilege)).to_string()
+}
+
+fn format_data_64(data: WordType) -> impl std::fmt::Display {
+    palette.data(&format!("0x{:016x}", data)).to_string()
+}
+
+fn format_instr(instr: &DbgInstrLine) -> impl std::fmt::Display {
+    if let Some(symbol) = &instr.symbol {
+        format!(
+            "{}: {} {}",
+            format_addr(instr.addr),
+            format_asm(instr.decoded),
+            palette.identifier(symbol)
+        )
+    } else {
+        format!("{}: {}", format_addr(instr.addr), format_asm(instr.decoded))
+    }
+}
+
+fn format_instr_detailed(instr: &DbgInstrLine) -> impl std::fmt::Display {
+    if let Some(symbol) = &instr.symbol {
+        format!(
+            "{}: {} {} {}",
+            format_addr(instr.addr),
+            format_raw(instr.raw),
+            format_asm(instr.decoded),
+            palette.identifier(symbol)
+        )
+    } else {
+        format!(
+            "{}: {} {}",
+            format_addr(instr.addr),
+            format_raw(instr.raw),
+            format_asm(instr.decoded)
+        )
+    }
+}
+
+fn format_raw(raw: Option<RawInstr>) -> impl std::fmt::Display {
+    use crate::isa::InstrLen;
+    match raw {
+        Some(raw) if raw.len() == 2 => palette.data(&format!("0x{:04x}", raw.val)).to_string(),
+        Some(raw) => palette.data(&format!("0x{:08x}", raw.val)).to_string(),
+        None => palette.invalid("<invalid>").to_string(),
+    }
+}
+
+fn format_asm(decode_instr: Option<DecodeInstr>) -> impl std::fmt::Display {
+    if decode_instr.is_none() {
+        return format!("{}", palette.invalid("<invalid instruction>"));
+    }
+    let DecodeInstr { instr, info, .. } = unsafe { decode_instr.unwrap_unchecked() };
+    match info {
+        RVInstrInfo::I { rd, rs1, imm } => match instr {
+            RiscvInstr::CSRRC | RiscvInstr::CSRRS | RiscvInstr::CSRRW => {
+                format!(
+                    "{} {},{},{}",
+                    palette.instr(instr.name()),
+                    palette.reg(REG_NAME[rd as usize], 0),
+                    palette.csr(
+                        CSR_NAME
+                            .get(&imm)
+                            .unwrap_or(&format!("csr[0x{:03x}]", imm).as_str())
+                    ),
+                    palette.reg(REG_NAME[rs1 as usize], 0),
+                )
+            }
+            RiscvInstr::CSRRCI | RiscvInstr::CSRRSI | RiscvInstr::CSRRWI => {
+                format!(
+                    "{} {},{},{}",
+                    palette.instr(instr.name()),
+                    palette.reg(REG_NAME[rd as usize], 0),
+                    palette.csr(
+                        CSR_NAME
+                            .get(&imm)
+                            .unwrap_or(&format!("csr[0x{:03x}]", imm).as_str())
+                    ),
+                    palette.data(rs1.to_string().as_str()),
+                )
+            }
+            _ => {
+                format!(
+                    "{} {},{},{}",
+                    palette.instr(instr.name()),
+                    palette.reg(REG_NAME[rd as usize], 0),
+                    palette.reg(REG_NAME[rs1 as usize], 0),
+                    palette.data(imm.to_string().as_str()),
+                )
+            }
+        },
+
+        RVInstrInfo::R { rs1, rs2, rd } => {
+            format!(
+                "{} {},{},{}",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rd as usize], 0),
+                palette.reg(REG_NAME[rs1 as usize], 0),
+                palette.reg(REG_NAME[rs2 as usize], 0)
+            )
+        }
+
+        RVInstrInfo::R_rm { rs1, rs2, rd, rm } => {
+            format!(
+                "{} {},{},{} rm={}",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rd as usize], 0),
+                palette.reg(REG_NAME[rs1 as usize], 0),
+                palette.reg(REG_NAME[rs2 as usize], 0),
+                rm,
+            )
+        }
+
+        RVInstrInfo::R4_rm {
+            rs1,
+            rs2,
+            rs3,
+            rd,
+            rm,
+        } => {
+            format!(
+                "{} {},{},{},{} rm={}",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rd as usize], 0),
+                palette.reg(REG_NAME[rs1 as usize], 0),
+                palette.reg(REG_NAME[rs2 as usize], 0),
+                palette.reg(REG_NAME[rs3 as usize], 0),
+                rm
+            )
+        }
+
+        RVInstrInfo::A {
+            rs1,
+            rs2,
+            rd,
+            rl,
+            aq,
+        } => {
+            if instr.name().starts_with("amo") {
+                format!(
+                    "{} {},{},({}) rl={}, aq={}",
+                    palette.instr(instr.name()),
+                    palette.reg(REG_NAME[rd as usize], 0),
+                    palette.reg(REG_NAME[rs2 as usize], 0),
+                    palette.reg(REG_NAME[rs1 as usize], 0),
+                    rl,
+                    aq,
+                )
+            } else {
+                // lr or sc
+                format!(
+                    "{} {},({}) rl={}, aq={}",
+                    palette.instr(instr.name()),
+                    palette.reg(REG_NAME[rd as usize], 0),
+                    palette.reg(REG_NAME[rs1 as usize], 0),
+                    rl,
+                    aq,
+                )
+            }
+        }
+
+        RVInstrInfo::B { rs1, rs2, imm } => {
+            format!(
+                "{} {},{},{}",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rs1 as usize], 0),
+                palette.reg(REG_NAME[rs2 as usize], 0),
+                palette.data((imm >> 1).to_string().as_str())
+            )
+        }
+
+        RVInstrInfo::J { rd, imm } => {
+            format!(
+                "{} {},{}",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rd as usize], 0),
+                palette.data((imm >> 12).to_string().as_str())
+            )
+        }
+
+        RVInstrInfo::S { rs1, rs2, imm } => {
+            format!(
+                "{} {},{},{}",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rs1 as usize], 0),
+                palette.reg(REG_NAME[rs2 as usize], 0),
+                palette.data((imm).to_string().as_str())
+            )
+        }
+        RVInstrInfo::U { rd, imm } => {
+            format!(
+                "{} {},{}",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rd as usize], 0),
+                palette.data((imm >> 12).to_string().as_str())
+            )
+        }
+        RVInstrInfo::V {
+            rs1, rs2, rd, vm, ..
+        } => {
+            let vector_reg_name = |id: u8| -> String { "v".to_string() + &id.to_string() };
+            format!(
+                "{} {}, {}, {}, {}",
+                palette.instr(instr.name()),
+                palette.reg(vector_reg_name(rd).as_str(), 0),
+                palette.reg(vector_reg_name(rs1).as_str(), 0),
+                palette.reg(vector_reg_name(rs2).as_str(), 0),
+                palette.data(if vm { "vm" } else { "" }),
+            )
+        }
+
+        RVInstrInfo::CR { rd_rs1, rs2 } => {
+            format!(
+                "{} {},{}",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rd_rs1 as usize], 0),
+                palette.reg(REG_NAME[rs2 as usize], 0),
+            )
+        }
+
+        RVInstrInfo::CI { rd_rs1, imm } => {
+            format!(
+                "{} {},{}",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rd_rs1 as usize], 0),
+                palette.data(imm.to_string().as_str()),
+            )
+        }
+
+        RVInstrInfo::CSS { rs2, imm } => {
+            format!(
+                "{} {},{}(sp)",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rs2 as usize], 0),
+                palette.data(imm.to_string().as_str()),
+            )
+        }
+
+        RVInstrInfo::CIW { rd, imm } => {
+            format!(
+                "{} {},{}",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rd as usize], 0),
+                palette.data(imm.to_string().as_str()),
+            )
+        }
+
+        RVInstrInfo::CL { rd, rs1, imm } => {
+            format!(
+                "{} {},{}({})",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rd as usize], 0),
+                palette.data(imm.to_string().as_str()),
+                palette.reg(REG_NAME[rs1 as usize], 0),
+            )
+        }
+
+        RVInstrInfo::CS { rs1, rs2, imm } => {
+            format!(
+                "{} {},{}({})",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rs2 as usize], 0),
+                palette.data(imm.to_string().as_str()),
+                palette.reg(REG_NAME[rs1 as usize], 0),
+            )
+        }
+
+        RVInstrInfo::CA { rd_rs1, rs2 } => {
+            format!(
+                "{} {},{}",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rd_rs1 as usize], 0),
+                palette.reg(REG_NAME[rs2 as usize], 0),
+            )
+        }
+
+        RVInstrInfo::CB { rd_rs1, imm } => {
+            format!(
+                "{} {},{}",
+                palette.instr(instr.name()),
+                palette.reg(REG_NAME[rd_rs1 as usize], 0),
+                palette.data(imm.to_string().as_str()),
+            )
+        }
+
+        RVInstrInfo::CJ { target } => {
+            format!(
+                "{} {}",
+                palette.instr(instr.name()),
+                palette.data(target.to_string().as_str()),
+            )
+        }
+
+        RVInstrInfo::None => {
+            format!("{}", palette.instr(instr.name()))
+        }
+
+        RVInstrInfo::Extension { raw, .. } => {
+            format!(
+                "{} {}",
+                palette.instr(instr.name()),
+                palette.data(format!("{raw:#010x}").as_str()),
+            )
+        }
+    }
+}
@@ -0,0 +1,502 @@
+//! Debugger scripts: rvdb commands with variables, loops and conditionals, e.g. to run to a
+//! breakpoint many times collecting a value.
+//!
+//! ```text
+//! # Count the calls to my_isr with a0 == 0.
+//! let zeros = 0
+//! break my_isr
+//! repeat 100 {
+//!     continue
+//!     print reg a0
+//!     if $a0 == 0 {
+//!         let zeros = $zeros + 1
+//!     } else {
+//!         print pc
+//!     }
+//! }
+//! ```
+//!
+//! `$name` in a command or an expression is a variable set by `let`, else a register or `pc`.
+//! Expressions are 64-bit integers, decimal or `0x` hex, with `+ - * / % & | ^ << >>`,
+//! `== != < <= > >=`, `&& || !` and parentheses. `if` runs its block when the condition is not 0.
+//! Lines starting with `#` are comments.
+
+use std::collections::HashMap;
+
+use crate::{board::Board, config::arch_config::WordType};
+
+use super::{CommandOutput, Handler};
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("line {line}: {msg}")]
+pub struct DebugScriptError {
+    pub line: usize,
+    pub msg: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinOp {
+    fn parse(token: &str) -> Option<Self> {
+        Some(match token {
+            "||" => BinOp::Or,
+            "&&" => BinOp::And,
+            "|" => BinOp::BitOr,
+            "^" => BinOp::BitXor,
+            "&" => BinOp::BitAnd,
+            "==" => BinOp::Eq,
+            "!=" => BinOp::Ne,
+            "<" => BinOp::Lt,
+            "<=" => BinOp::Le,
+            ">" => BinOp::Gt,
+            ">=" => BinOp::Ge,
+            "<<" => BinOp::Shl,
+            ">>" => BinOp::Shr,
+            "+" => BinOp::Add,
+            "-" => BinOp::Sub,
+            "*" => BinOp::Mul,
+            "/" => BinOp::Div,
+            "%" => BinOp::Rem,
+            _ => return None,
+        })
+    }
+
+    /// Higher binds tighter, as in C.
+    fn precedence(self) -> u8 {
+        match self {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::BitOr => 3,
+            BinOp::BitXor => 4,
+            BinOp::BitAnd => 5,
+            BinOp::Eq | BinOp::Ne => 6,
+            BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => 7,
+            BinOp::Shl | BinOp::Shr => 8,
+            BinOp::Add | BinOp::Sub => 9,
+            BinOp::Mul | BinOp::Div | BinOp::Rem => 10,
+        }
+    }
+
+    fn apply(self, l: u64, r: u64) -> Result<u64, String> {
+        Ok(match self {
+            BinOp::Or => (l != 0 || r != 0) as u64,
+            BinOp::And => (l != 0 && r != 0) as u64,
+            BinOp::BitOr => l | r,
+            BinOp::BitXor => l ^ r,
+            BinOp::BitAnd => l & r,
+            BinOp::Eq => (l == r) as u64,
+            BinOp::Ne => (l != r) as u64,
+            BinOp::Lt => (l < r) as u64,
+            BinOp::Le => (l <= r) as u64,
+            BinOp::Gt => (l > r) as u64,
+            BinOp::Ge => (l >= r) as u64,
+            BinOp::Shl => l.wrapping_shl(r as u32),
+            BinOp::Shr => l.wrapping_shr(r as u32),
+            BinOp::Add => l.wrapping_add(r),
+            BinOp::Sub => l.wrapping_sub(r),
+            BinOp::Mul => l.wrapping_mul(r),
+            BinOp::Div => l.checked_div(r).ok_or("division by zero")?,
+            BinOp::Rem => l.checked_rem(r).ok_or("division by zero")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Num(u64),
+    Var(String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn parse(s: &str) -> Result<Self, String> {
+        let tokens = tokenize(s)?;
+        let mut pos = 0;
+        let expr = Self::parse_binary(&tokens, &mut pos, 0)?;
+        match tokens.get(pos) {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected `{}`", token)),
+        }
+    }
+
+    /// Operators binding tighter than `min`, by precedence climbing.
+    fn parse_binary(tokens: &[String], pos: &mut usize, min: u8) -> Result<Self, String> {
+        let mut lhs = Self::parse_unary(tokens, pos)?;
+        while let Some(op) = tokens.get(*pos).and_then(|token| BinOp::parse(token))
+            && op.precedence() > min
+        {
+            *pos += 1;
+            let rhs = Self::parse_binary(tokens, pos, op.precedence())?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(tokens: &[String], pos: &mut usize) -> Result<Self, String> {
+        let token = tokens.get(*pos).ok_or("missing operand")?;
+        *pos += 1;
+        match token.as_str() {
+            "!" => Ok(Expr::Not(Box::new(Self::parse_unary(tokens, pos)?))),
+            "-" => Ok(Expr::Neg(Box::new(Self::parse_unary(tokens, pos)?))),
+            "(" => {
+                let expr = Self::parse_binary(tokens, pos, 0)?;
+                match tokens.get(*pos).map(String::as_str) {
+                    Some(")") => {
+                        *pos += 1;
+                        Ok(expr)
+                    }
+                    _ => Err("missing `)`".to_string()),
+                }
+            }
+            _ => match token.strip_prefix('$') {
+                Some(name) => Ok(Expr::Var(name.to_string())),
+                None => parse_number(token).map(Expr::Num),
+            },
+        }
+    }
+
+    fn eval(&self, lookup: &dyn Fn(&str) -> Option<u64>) -> Result<u64, String> {
+        match self {
+            Expr::Num(value) => Ok(*value),
+            Expr::Var(name) => lookup(name).ok_or_else(|| format!("unknown variable `${}`", name)),
+            Expr::Not(expr) => Ok((expr.eval(lookup)? == 0) as u64),
+            Expr::Neg(expr) => Ok(expr.eval(lookup)?.wrapping_neg()),
+            Expr::Binary(op, lhs, rhs) => op.apply(lhs.eval(lookup)?, rhs.eval(lookup)?),
+        }
+    }
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn tokenize(s: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+        if c.is_whitespace() {
+            continue;
+        } else if c == '$' || is_ident(c) {
+            while let Some(&(idx, c)) = chars.peek()
+                && is_ident(c)
+            {
+                end = idx + c.len_utf8();
+                chars.next();
+            }
+        } else if let Some(&(_, next)) = chars.peek()
+            && BinOp::parse(&s[start..end + next.len_utf8()]).is_some()
+        {
+            end += next.len_utf8();
+            chars.next();
+        } else if !"!()".contains(c) && BinOp::parse(&s[start..end]).is_none() {
+            return Err(format!("unexpected `{}`", c));
+        }
+        tokens.push(s[start..end].to_string());
+    }
+    Ok(tokens)
+}
+
+fn parse_number(s: &str) -> Result<u64, String> {
+    crate::parse_u64(s).map_err(|_| format!("invalid number `{}`", s))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Stmt {
+    Command(String),
+    Let(String, Expr),
+    Repeat(Expr, Vec<(usize, Stmt)>),
+    If(Expr, Vec<(usize, Stmt)>, Vec<(usize, Stmt)>),
+}
+
+/// What ends a block.
+enum Closing {
+    End,
+    Brace,
+    Else,
+}
+
+/// A parsed debugger script, statements with their line number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugScript {
+    stmts: Vec<(usize, Stmt)>,
+}
+
+impl DebugScript {
+    pub fn parse(lines: &[String]) -> Result<Self, DebugScriptError> {
+        let mut pos = 0;
+        let (stmts, closing) = parse_block(lines, &mut pos)?;
+        match closing {
+            Closing::End => Ok(Self { stmts }),
+            _ => Err(DebugScriptError {
+                line: pos,
+                msg: "unexpected `}`".to_string(),
+            }),
+        }
+    }
+
+    /// Run the script, passing each command after substitution to `execute`, which returns
+    /// whether to exit and stop the script, see [`execute_command`] for the plain behavior.
+    /// `vars` are the variables, kept from one script to the next. Returns whether the script
+    /// exited.
+    pub fn run<'a, B: Board>(
+        &self,
+        handler: &mut Handler<'a, B>,
+        vars: &mut HashMap<String, WordType>,
+        mut execute: impl FnMut(&mut Handler<'a, B>, &str) -> bool,
+    ) -> Result<bool, DebugScriptError> {
+        run_block(&self.stmts, handler, vars, &mut execute)
+    }
+}
+
+fn parse_block(
+    lines: &[String],
+    pos: &mut usize,
+) -> Result<(Vec<(usize, Stmt)>, Closing), DebugScriptError> {
+    let mut stmts = Vec::new();
+    while let Some(line) = lines.get(*pos) {
+        *pos += 1;
+        let line_no = *pos;
+        let error = |msg: String| DebugScriptError { line: line_no, msg };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line {
+            "}" => return Ok((stmts, Closing::Brace)),
+            "} else {" => return Ok((stmts, Closing::Else)),
+            _ => {}
+        }
+
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let stmt = match keyword {
+            "let" => {
+                let (name, expr) = rest
+                    .split_once('=')
+                    .ok_or_else(|| error("expected `let <name> = <expr>`".to_string()))?;
+                let name = name.trim();
+                if name.is_empty() || !name.chars().all(is_ident) {
+                    return Err(error(format!("invalid variable name `{}`", name)));
+                }
+                Stmt::Let(name.to_string(), Expr::parse(expr).map_err(error)?)
+            }
+            "repeat" | "if" => {
+                let expr = rest
+                    .strip_suffix('{')
+                    .ok_or_else(|| error(format!("expected `{} <expr> {{`", keyword)))?;
+                let expr = Expr::parse(expr).map_err(error)?;
+                let (body, closing) = parse_block(lines, pos)?;
+                match (keyword, closing) {
+                    (_, Closing::End) => return Err(error("missing `}`".to_string())),
+                    ("repeat", Closing::Else) => {
+                        return Err(error("`else` after `repeat`".to_string()));
+                    }
+                    ("repeat", _) => Stmt::Repeat(expr, body),
+                    (_, Closing::Brace) => Stmt::If(expr, body, Vec::new()),
+                    (_, Closing::Else) => match parse_block(lines, pos)? {
+                        (otherwise, Closing::Brace) => Stmt::If(expr, body, otherwise),
+                        _ => return Err(error("missing `}` after `else`".to_string())),
+                    },
+                }
+            }
+            _ => Stmt::Command(line.to_string()),
+        };
+        stmts.push((line_no, stmt));
+    }
+    Ok((stmts, Closing::End))
+}
+
+/// Execute `command` on `handler`, returns whether it asked to exit.
+pub fn execute_command<B: Board>(handler: &mut Handler<'_, B>, command: &str) -> bool {
+    handler.execute(command) == Ok(CommandOutput::Exit)
+}
+
+fn run_block<'a, B: Board>(
+    stmts: &[(usize, Stmt)],
+    handler: &mut Handler<'a, B>,
+    vars: &mut HashMap<String, WordType>,
+    execute: &mut impl FnMut(&mut Handler<'a, B>, &str) -> bool,
+) -> Result<bool, DebugScriptError> {
+    for (line, stmt) in stmts {
+        let error = |msg: String| DebugScriptError { line: *line, msg };
+        let eval = |expr: &Expr, handler: &Handler<'_, B>, vars: &HashMap<String, WordType>| {
+            expr.eval(&|name| vars.get(name).copied().or_else(|| handler.read_value(name)))
+                .map_err(error)
+        };
+        match stmt {
+            Stmt::Command(command) => {
+                let command = substitute(command, handler, vars).map_err(error)?;
+                if execute(handler, &command) {
+                    return Ok(true);
+                }
+            }
+            Stmt::Let(name, expr) => {
+                let value = eval(expr, handler, vars)?;
+                vars.insert(name.clone(), value);
+            }
+            Stmt::Repeat(count, body) => {
+                for _ in 0..eval(count, handler, vars)? {
+                    if run_block(body, handler, vars, execute)? {
+                        return Ok(true);
+                    }
+                }
+            }
+            Stmt::If(cond, body, otherwise) => {
+                let body = match eval(cond, handler, vars)? {
+                    0 => otherwise,
+                    _ => body,
+                };
+                if run_block(body, handler, vars, execute)? {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Replace each `$name` of a command by its value in hex.
+fn substitute<B: Board>(
+    command: &str,
+    handler: &Handler<'_, B>,
+    vars: &HashMap<String, WordType>,
+) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = command;
+    while let Some(idx) = rest.find('$') {
+        result.push_str(&rest[..idx]);
+        let name_len = rest[idx + 1..]
+            .find(|c| !is_ident(c))
+            .unwrap_or(rest.len() - idx - 1);
+        let name = &rest[idx + 1..idx + 1 + name_len];
+        let value = vars
+            .get(name)
+            .copied()
+            .or_else(|| handler.read_value(name))
+            .ok_or_else(|| format!("unknown variable `${}`", name))?;
+        result.push_str(&format!("{:#x}", value));
+        rest = &rest[idx + 1 + name_len..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::board::virt::VirtBoard;
+
+    use super::*;
+
+    fn lines(script: &str) -> Vec<String> {
+        script.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_expr() {
+        let vars = |name: &str| (name == "x").then_some(6);
+        let eval = |s: &str| Expr::parse(s).and_then(|expr| expr.eval(&vars));
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("$x % 4 == 2 && !0"), Ok(1));
+        assert_eq!(eval("0x10 >> 2 | 1"), Ok(5));
+        assert_eq!(eval("-1"), Ok(u64::MAX));
+        assert_eq!(eval("$x >= 7 || $x != 6"), Ok(0));
+        assert_eq!(eval("1 / 0"), Err("division by zero".to_string()));
+        assert_eq!(eval("$y"), Err("unknown variable `$y`".to_string()));
+        assert!(eval("(1 + 2").is_err());
+        assert!(eval("1 2").is_err());
+        assert!(eval("1 @ 2").is_err());
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let error = |script: &str| DebugScript::parse(&lines(script)).unwrap_err();
+        assert_eq!(error("repeat 3 {\nsi").msg, "missing `}`");
+        assert_eq!(error("si\n}").line, 2);
+        assert_eq!(error("let = 1").msg, "invalid variable name ``");
+        assert_eq!(
+            error("repeat 3 {\n} else {\n}").msg,
+            "`else` after `repeat`"
+        );
+        assert!(error("if 1 {\n} else {\nsi").msg.contains("after `else`"));
+        assert!(DebugScript::parse(&lines("# comment\nif 1 {\n} else {\nsi\n}")).is_ok());
+    }
+
+    #[test]
+    fn test_run_script() {
+        // addi t0, t0, 1, four times.
+        let bytes: Vec<u8> = [0x0012_8293u32; 4]
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+        let mut board = VirtBoard::from_binary(&bytes);
+        let mut handler = Handler::new(&mut board);
+        let mut vars = HashMap::new();
+        let script = DebugScript::parse(&lines(
+            "
+            let sum = 0
+            repeat 3 {
+                si
+                let sum = $sum + $t0
+            }
+            if $sum == 6 {
+                let ok = 1
+            } else {
+                let ok = 0
+            }
+            print reg $t0
+            ",
+        ))
+        .unwrap();
+        let mut commands = Vec::new();
+        let exit = script
+            .run(&mut handler, &mut vars, |handler, command| {
+                commands.push(command.to_string());
+                execute_command(handler, command)
+            })
+            .unwrap();
+        assert!(!exit);
+        assert_eq!(vars["sum"], 6);
+        assert_eq!(vars["ok"], 1);
+        assert_eq!(commands, ["si", "si", "si", "print reg 0x3"]);
+
+        let script = DebugScript::parse(&lines("repeat 10 {\nsi\nquit\n}")).unwrap();
+        assert!(
+            script
+                .run(&mut handler, &mut vars, execute_command)
+                .unwrap()
+        );
+        assert_eq!(handler.read_value("t0"), Some(4));
+
+        let script = DebugScript::parse(&lines("si\nlet x = $nope")).unwrap();
+        assert_eq!(
+            script.run(&mut handler, &mut vars, execute_command),
+            Err(DebugScriptError {
+                line: 2,
+                msg: "unknown variable `$nope`".to_string()
+            })
+        );
+    }
+}
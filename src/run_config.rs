@@ -0,0 +1,217 @@
+//! `--config run.toml`: a whole invocation in a file, so it can be reproduced and shared.
+//!
+//! Every key is the long name of a command line option, `image` is the target executable and
+//! `guest-args` the arguments after `--`. Options given on the command line override the file,
+//! repeatable options (`device`, `panic-pattern`) add to it.
+//!
+//! ```toml
+//! image = "./tmp/Image"
+//! isa = "RV64IMAFDC"
+//! board = "./boards/sifive.toml"
+//! device = ["virtio-block:./tmp/rootfs.img", "virtio-block:./tmp/data.img:ro"]
+//! flash = "./tmp/RISCV_VIRT_CODE.fd:ro"
+//! flash-vars = "./tmp/RISCV_VIRT_VARS.fd"
+//! append = "console=ttyS0 root=/dev/vda"
+//! watchdog = "reset"
+//! deterministic = true
+//! mhartid = 0
+//! ```
+
+use std::{ffi::OsString, path::Path};
+
+use clap::{ArgAction, Command};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RunConfigError {
+    #[error("failed to read configuration file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid configuration file: {0}")]
+    Parse(#[from] toml::de::Error),
+
+    #[error("{0}: no such option")]
+    UnknownOption(String),
+
+    #[error("{0}: expected a string, a number, a boolean or an array of them")]
+    InvalidValue(String),
+}
+
+/// The command line equivalent of a configuration file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RunConfig {
+    /// `--option=value` arguments.
+    pub options: Vec<OsString>,
+    pub image: Option<String>,
+    pub guest_args: Vec<String>,
+}
+
+impl RunConfig {
+    /// Translate a configuration file into the arguments of `command`.
+    pub fn parse(text: &str, command: &Command) -> Result<Self, RunConfigError> {
+        let table = toml::from_str::<toml::Table>(text)?;
+        let mut config = Self::default();
+        for (key, value) in table {
+            match key.as_str() {
+                "image" => config.image = Some(scalar(&key, &value)?),
+                "guest-args" => config.guest_args = list(&key, &value)?,
+                _ => config.push_option(command, &key, &value)?,
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &Path, command: &Command) -> Result<Self, RunConfigError> {
+        Self::parse(&std::fs::read_to_string(path)?, command)
+    }
+
+    fn push_option(
+        &mut self,
+        command: &Command,
+        key: &str,
+        value: &toml::Value,
+    ) -> Result<(), RunConfigError> {
+        // A file naming another file would have to be loaded as well.
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key) && key != "config")
+            .ok_or_else(|| RunConfigError::UnknownOption(key.to_string()))?;
+
+        match value {
+            toml::Value::Boolean(true) => self.options.push(format!("--{key}").into()),
+            toml::Value::Boolean(false) => {}
+            toml::Value::Array(_) if matches!(arg.get_action(), ArgAction::Append) => {
+                for value in list(key, value)? {
+                    self.options.push(format!("--{key}={value}").into());
+                }
+            }
+            toml::Value::Array(_) => {
+                let values = list(key, value)?.join(",");
+                self.options.push(format!("--{key}={values}").into());
+            }
+            _ => {
+                let value = scalar(key, value)?;
+                self.options.push(format!("--{key}={value}").into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The command line `args` under this configuration: the options of the file come first so
+    /// that those of the command line override them. The image and the guest arguments of the
+    /// file are only used when the command line has none.
+    pub fn merge(self, args: Vec<OsString>, has_image: bool) -> Vec<OsString> {
+        let mut args = args.into_iter();
+        let mut merged: Vec<OsString> = args.next().into_iter().collect();
+        merged.extend(self.options);
+        if !has_image {
+            merged.extend(self.image.map(OsString::from));
+        }
+        let mut separated = false;
+        for arg in args {
+            separated |= arg == "--";
+            merged.push(arg);
+        }
+        if !separated && !self.guest_args.is_empty() {
+            merged.push("--".into());
+            merged.extend(self.guest_args.into_iter().map(OsString::from));
+        }
+        merged
+    }
+}
+
+fn scalar(key: &str, value: &toml::Value) -> Result<String, RunConfigError> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(n) => Ok(n.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(RunConfigError::InvalidValue(key.to_string())),
+    }
+}
+
+fn list(key: &str, value: &toml::Value) -> Result<Vec<String>, RunConfigError> {
+    match value {
+        toml::Value::Array(values) => values.iter().map(|value| scalar(key, value)).collect(),
+        value => Ok(vec![scalar(key, value)?]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, ArgAction};
+
+    use super::*;
+
+    fn command() -> Command {
+        Command::new("emu")
+            .arg(Arg::new("path"))
+            .arg(Arg::new("isa").long("isa"))
+            .arg(Arg::new("device").long("device").action(ArgAction::Append))
+            .arg(Arg::new("trace-mmio").long("trace-mmio"))
+            .arg(
+                Arg::new("deterministic")
+                    .long("deterministic")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(Arg::new("config").long("config"))
+    }
+
+    fn strings(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_parse_run_config() {
+        let text = r#"
+            image = "./kernel"
+            guest-args = ["-v", 1]
+            isa = "RV64IM"
+            device = ["virtio-block:a.img", "virtio-block:b.img:ro"]
+            trace-mmio = ["uart", "plic"]
+            deterministic = true
+        "#;
+        let config = RunConfig::parse(text, &command()).unwrap();
+        assert_eq!(config.image.as_deref(), Some("./kernel"));
+        assert_eq!(config.guest_args, ["-v", "1"]);
+        assert_eq!(
+            config.options,
+            strings(&[
+                "--deterministic",
+                "--device=virtio-block:a.img",
+                "--device=virtio-block:b.img:ro",
+                "--isa=RV64IM",
+                "--trace-mmio=uart,plic",
+            ])
+        );
+
+        assert!(matches!(
+            RunConfig::parse("memory = 1", &command()),
+            Err(RunConfigError::UnknownOption(_))
+        ));
+        assert!(matches!(
+            RunConfig::parse(r#"config = "other.toml""#, &command()),
+            Err(RunConfigError::UnknownOption(_))
+        ));
+        assert!(matches!(
+            RunConfig::parse("isa = { base = 64 }", &command()),
+            Err(RunConfigError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_merge_run_config() {
+        let config = || RunConfig {
+            options: strings(&["--isa=RV64IM"]),
+            image: Some("./kernel".into()),
+            guest_args: vec!["-v".into()],
+        };
+        assert_eq!(
+            config().merge(strings(&["emu", "--isa=RV64I"]), false),
+            strings(&["emu", "--isa=RV64IM", "./kernel", "--isa=RV64I", "--", "-v"])
+        );
+        assert_eq!(
+            config().merge(strings(&["emu", "./other", "--", "-q"]), true),
+            strings(&["emu", "--isa=RV64IM", "./other", "--", "-q"])
+        );
+    }
+}
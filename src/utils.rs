@@ -404,6 +404,21 @@ pub trait UnsignedInteger:
     type AtomicType;
     type DoubleWidenType;
 
+    /// Reverses the byte order, see [`u32::swap_bytes`].
+    fn swap_bytes(self) -> Self;
+
+    /// An atomic holding `value`, see [`std::sync::atomic::AtomicU32::new`].
+    fn atomic_new(value: Self) -> Self::AtomicType;
+
+    /// The value of an owned atomic, see [`std::sync::atomic::AtomicU32::into_inner`].
+    fn atomic_into_inner(atomic: Self::AtomicType) -> Self;
+
+    /// Loads the value of `atomic`, sequentially consistent.
+    fn atomic_load(atomic: &Self::AtomicType) -> Self;
+
+    /// Stores `new` into `atomic` if it still holds `current`, sequentially consistent.
+    fn atomic_compare_exchange(atomic: &Self::AtomicType, current: Self, new: Self) -> bool;
+
     fn mask_range(self, l: u32, r: u32) -> Self {
         debug_assert!(l <= r && (r as usize) < Self::BITS);
         self & make_mask(l as usize, r as usize).truncate_to()
@@ -421,6 +436,29 @@ impl UnsignedInteger for u8 {
     const BITS: usize = 8;
     type AtomicType = std::sync::atomic::AtomicU8;
     type DoubleWidenType = u16;
+
+    fn swap_bytes(self) -> Self {
+        u8::swap_bytes(self)
+    }
+
+    fn atomic_new(value: Self) -> Self::AtomicType {
+        Self::AtomicType::new(value)
+    }
+
+    fn atomic_into_inner(atomic: Self::AtomicType) -> Self {
+        atomic.into_inner()
+    }
+
+    fn atomic_load(atomic: &Self::AtomicType) -> Self {
+        atomic.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    fn atomic_compare_exchange(atomic: &Self::AtomicType, current: Self, new: Self) -> bool {
+        use std::sync::atomic::Ordering::SeqCst;
+        atomic
+            .compare_exchange(current, new, SeqCst, SeqCst)
+            .is_ok()
+    }
 }
 impl UnsignedInteger for u16 {
     const MAX: u16 = u16::MAX;
@@ -428,6 +466,29 @@ impl UnsignedInteger for u16 {
     const BITS: usize = 16;
     type AtomicType = std::sync::atomic::AtomicU16;
     type DoubleWidenType = u32;
+
+    fn swap_bytes(self) -> Self {
+        u16::swap_bytes(self)
+    }
+
+    fn atomic_new(value: Self) -> Self::AtomicType {
+        Self::AtomicType::new(value)
+    }
+
+    fn atomic_into_inner(atomic: Self::AtomicType) -> Self {
+        atomic.into_inner()
+    }
+
+    fn atomic_load(atomic: &Self::AtomicType) -> Self {
+        atomic.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    fn atomic_compare_exchange(atomic: &Self::AtomicType, current: Self, new: Self) -> bool {
+        use std::sync::atomic::Ordering::SeqCst;
+        atomic
+            .compare_exchange(current, new, SeqCst, SeqCst)
+            .is_ok()
+    }
 }
 impl UnsignedInteger for u32 {
     const MAX: u32 = u32::MAX;
@@ -435,6 +496,29 @@ impl UnsignedInteger for u32 {
     const BITS: usize = 32;
     type AtomicType = std::sync::atomic::AtomicU32;
     type DoubleWidenType = u64;
+
+    fn swap_bytes(self) -> Self {
+        u32::swap_bytes(self)
+    }
+
+    fn atomic_new(value: Self) -> Self::AtomicType {
+        Self::AtomicType::new(value)
+    }
+
+    fn atomic_into_inner(atomic: Self::AtomicType) -> Self {
+        atomic.into_inner()
+    }
+
+    fn atomic_load(atomic: &Self::AtomicType) -> Self {
+        atomic.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    fn atomic_compare_exchange(atomic: &Self::AtomicType, current: Self, new: Self) -> bool {
+        use std::sync::atomic::Ordering::SeqCst;
+        atomic
+            .compare_exchange(current, new, SeqCst, SeqCst)
+            .is_ok()
+    }
 }
 impl UnsignedInteger for u64 {
     const MAX: u64 = u64::MAX;
@@ -442,6 +526,29 @@ impl UnsignedInteger for u64 {
     const BITS: usize = 64;
     type AtomicType = std::sync::atomic::AtomicU64;
     type DoubleWidenType = u128;
+
+    fn swap_bytes(self) -> Self {
+        u64::swap_bytes(self)
+    }
+
+    fn atomic_new(value: Self) -> Self::AtomicType {
+        Self::AtomicType::new(value)
+    }
+
+    fn atomic_into_inner(atomic: Self::AtomicType) -> Self {
+        atomic.into_inner()
+    }
+
+    fn atomic_load(atomic: &Self::AtomicType) -> Self {
+        atomic.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    fn atomic_compare_exchange(atomic: &Self::AtomicType, current: Self, new: Self) -> bool {
+        use std::sync::atomic::Ordering::SeqCst;
+        atomic
+            .compare_exchange(current, new, SeqCst, SeqCst)
+            .is_ok()
+    }
 }
 
 pub trait SignedInteger:
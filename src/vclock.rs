@@ -1,7 +1,70 @@
-use std::{cell::Cell, rc::Rc, u64};
+use std::{
+    cell::Cell,
+    rc::Rc,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+    u64,
+};
+
+/// Guest ticks per microsecond, matches the `timebase-frequency` (10 MHz) of the device tree.
+pub const TICKS_PER_MICRO: u64 = 10;
+
+/// In deterministic mode nothing reads the host clock: device time is derived from the
+/// instruction count only. Process wide, set once before building the board.
+static DETERMINISTIC: AtomicBool = AtomicBool::new(false);
+
+/// Guest time published by the board, readable from pollers off the main thread.
+static GUEST_TIME: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_deterministic(enable: bool) {
+    DETERMINISTIC.store(enable, Ordering::Relaxed);
+}
+
+pub fn is_deterministic() -> bool {
+    DETERMINISTIC.load(Ordering::Relaxed)
+}
+
+pub fn publish_guest_time(time: u64) {
+    GUEST_TIME.store(time, Ordering::Relaxed);
+}
+
+pub fn guest_time() -> u64 {
+    GUEST_TIME.load(Ordering::Relaxed)
+}
+
+/// A point in time as seen by devices: the host monotonic clock, or the
+/// published guest time in deterministic mode.
+#[derive(Clone, Copy, Debug)]
+pub enum DeviceInstant {
+    Host(Instant),
+    Guest(u64),
+}
+
+impl DeviceInstant {
+    pub fn now() -> Self {
+        if is_deterministic() {
+            DeviceInstant::Guest(guest_time())
+        } else {
+            DeviceInstant::Host(Instant::now())
+        }
+    }
+
+    /// Time elapsed from `earlier` to `self`, zero if `earlier` is later or from another source.
+    pub fn duration_since(&self, earlier: DeviceInstant) -> Duration {
+        match (self, earlier) {
+            (DeviceInstant::Host(now), DeviceInstant::Host(earlier)) => {
+                now.saturating_duration_since(earlier)
+            }
+            (DeviceInstant::Guest(now), DeviceInstant::Guest(earlier)) => {
+                Duration::from_micros(now.saturating_sub(earlier) / TICKS_PER_MICRO)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+}
 
 /// Simple clock, clone by ref, cannot used in multi-threaded context.
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct VirtualClockRef {
     time: Rc<Cell<u64>>,
 }
@@ -30,11 +93,12 @@ impl VirtualClockRef {
 struct ScheduledTask {
     due: u64,
     seq: u64,
-    callback: Box<dyn FnMut()>,
+    /// Returns the next due time, if any.
+    callback: Box<dyn FnMut() -> Option<u64>>,
 }
 
 impl ScheduledTask {
-    fn new<F: FnMut() + 'static>(seq: u64, callback: F) -> Self {
+    fn new<F: FnMut() -> Option<u64> + 'static>(seq: u64, callback: F) -> Self {
         Self {
             due: u64::MAX,
             seq: seq,
@@ -43,9 +107,17 @@ impl ScheduledTask {
     }
 }
 
+/// Deadline scheduler for the devices: a task runs once the clock reaches its due time, instead
+/// of the device being synced on every step.
+///
+/// The board ticks the timer after every step, which costs a single comparison with the earliest
+/// due time until a task is due.
 pub struct Timer {
     seq: u64,
+    /// Sorted by due time.
     tasks: Vec<ScheduledTask>,
+    /// Due time of the first task, `u64::MAX` when none is scheduled.
+    next_due: u64,
     vclock: VirtualClockRef,
 }
 
@@ -54,15 +126,29 @@ impl Timer {
         Self {
             seq: 0,
             tasks: Vec::new(),
+            next_due: u64::MAX,
             vclock,
         }
     }
 
     /// Register a new task without setting a due, returning the sequence ID of the task.
     #[must_use]
-    pub fn register<F>(&mut self, callback: F) -> u64
+    pub fn register<F>(&mut self, mut callback: F) -> u64
     where
         F: FnMut() + 'static,
+    {
+        self.register_repeating(move || {
+            callback();
+            None
+        })
+    }
+
+    /// Register a task which schedules itself again: its callback returns the next due time, or
+    /// `None` to wait for a [`Timer::set_due`].
+    #[must_use]
+    pub fn register_repeating<F>(&mut self, callback: F) -> u64
+    where
+        F: FnMut() -> Option<u64> + 'static,
     {
         let st = ScheduledTask::new(self.seq, callback);
         self.seq += 1;
@@ -73,6 +159,7 @@ impl Timer {
 
     pub fn build(&mut self) {
         self.tasks.sort_unstable_by_key(|task| task.due);
+        self.next_due = self.tasks.first().map_or(u64::MAX, |task| task.due);
     }
 
     /// Set the due time, use [`Timer::set_delay`] for a certain delay.
@@ -97,15 +184,21 @@ impl Timer {
     }
 
     /// Run all tasks whose due time is <= the timer's clock `now()`.
+    #[inline]
     pub fn tick(&mut self) {
         let now = self.vclock.now();
+        if now >= self.next_due {
+            self.run_due(now);
+        }
+    }
 
+    #[cold]
+    fn run_due(&mut self, now: u64) {
         self.tasks
             .iter_mut()
             .take_while(|task| task.due <= now)
             .for_each(|task| {
-                (task.callback)();
-                task.due = u64::MAX;
+                task.due = (task.callback)().unwrap_or(u64::MAX);
             });
 
         self.build();
@@ -131,6 +224,14 @@ impl<'a> TimerGuard<'a> {
         self.timer.register(callback)
     }
 
+    /// See [`Timer::register_repeating`].
+    pub fn register_repeating<F>(&mut self, callback: F) -> u64
+    where
+        F: FnMut() -> Option<u64> + 'static,
+    {
+        self.timer.register_repeating(callback)
+    }
+
     /// See [`Timer::set_due`].
     pub fn set_due(&mut self, seq: u64, new_due: u64) {
         self.timer
@@ -152,3 +253,70 @@ impl<'a> Drop for TimerGuard<'a> {
         self.timer.build();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_instant_test() {
+        let earlier = DeviceInstant::Guest(100);
+        let now = DeviceInstant::Guest(100 + 25 * TICKS_PER_MICRO);
+        assert_eq!(now.duration_since(earlier), Duration::from_micros(25));
+        assert_eq!(earlier.duration_since(now), Duration::ZERO);
+        assert_eq!(
+            now.duration_since(DeviceInstant::Host(Instant::now())),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn repeating_task_test() {
+        let clock = VirtualClockRef::new();
+        let mut timer = Timer::new(clock.clone());
+        let runs = Rc::new(Cell::new(0));
+        let task = timer.register_repeating({
+            let (clock, runs) = (clock.clone(), runs.clone());
+            move || {
+                runs.set(runs.get() + 1);
+                (runs.get() < 3).then(|| clock.now() + 10)
+            }
+        });
+        timer.set_due(task, 10);
+
+        for _ in 0..50 {
+            clock.advance(1);
+            timer.tick();
+        }
+        assert_eq!(runs.get(), 3);
+        assert_eq!(timer.next_due(), Some(u64::MAX));
+    }
+
+    #[test]
+    fn tick_until_due_test() {
+        let clock = VirtualClockRef::new();
+        let mut timer = Timer::new(clock.clone());
+        let order = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut guard = timer.guard();
+        for (name, due) in [("late", 20), ("early", 5), ("idle", u64::MAX)] {
+            let order = order.clone();
+            let task = guard.register(move || order.borrow_mut().push(name));
+            guard.set_due(task, due);
+        }
+        drop(guard);
+        assert_eq!(timer.next_due(), Some(5));
+
+        clock.set(4);
+        timer.tick();
+        assert!(order.borrow().is_empty());
+
+        clock.set(5);
+        timer.tick();
+        assert_eq!(timer.next_due(), Some(20));
+
+        clock.set(100);
+        timer.tick();
+        assert_eq!(*order.borrow(), ["early", "late"]);
+        assert_eq!(timer.next_due(), Some(u64::MAX));
+    }
+}
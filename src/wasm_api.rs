@@ -1,5 +1,6 @@
 use std::panic;
 
+use js_sys::{Function, Uint8Array};
 use wasm_bindgen::prelude::*;
 
 use crate::{
@@ -11,6 +12,10 @@ use crate::{
 #[wasm_bindgen]
 pub struct WasmEmulator {
     inner: Emulator,
+    /// Asked for serial input before every run, see [`WasmEmulator::set_input_callback`].
+    on_input: Option<Function>,
+    /// Given the serial output after every run, see [`WasmEmulator::set_output_callback`].
+    on_output: Option<Function>,
 }
 
 #[wasm_bindgen(start)]
@@ -24,25 +29,25 @@ impl WasmEmulator {
     pub fn from_elf_bytes(bytes: &[u8]) -> Result<Self, JsValue> {
         let inner = Emulator::try_from_elf_bytes(bytes.to_vec())
             .map_err(|e| JsValue::from_str(&format!("ELF load failed: {e}")))?;
-        Ok(Self { inner })
+        Ok(Self::new(inner))
     }
 
     pub fn from_bin_bytes(bytes: &[u8]) -> Self {
-        Self {
-            inner: Emulator::from_binary_bytes(bytes),
-        }
+        Self::new(Emulator::from_binary_bytes(bytes))
     }
 
     pub fn step(&mut self) -> Result<(), JsValue> {
-        self.inner
-            .step()
-            .map_err(|e| JsValue::from_str(&format!("{e:?}")))
+        self.poll_input()?;
+        let result = self.inner.step();
+        self.flush_output()?;
+        result.map_err(|e| JsValue::from_str(&format!("{e:?}")))
     }
 
     pub fn continue_for_steps(&mut self, max_steps: u64) -> Result<u64, JsValue> {
-        self.inner
-            .run_steps(max_steps)
-            .map_err(|e| JsValue::from_str(&format!("{e:?}")))
+        self.poll_input()?;
+        let result = self.inner.run_steps(max_steps);
+        self.flush_output()?;
+        result.map_err(|e| JsValue::from_str(&format!("{e:?}")))
     }
 
     pub fn is_halted(&self) -> bool {
@@ -68,4 +73,55 @@ impl WasmEmulator {
     pub fn take_uart_output(&mut self) -> Vec<u8> {
         self.inner.take_uart_output_bytes()
     }
+
+    /// Call `callback` before every run to fetch serial input. It returns a `Uint8Array`, a
+    /// string, or `undefined` when there is nothing to send.
+    pub fn set_input_callback(&mut self, callback: Option<Function>) {
+        self.on_input = callback;
+    }
+
+    /// Call `callback` with a `Uint8Array` of the serial output after every run which produced
+    /// some. The output is no longer kept for [`WasmEmulator::take_uart_output`].
+    pub fn set_output_callback(&mut self, callback: Option<Function>) {
+        self.on_output = callback;
+    }
+}
+
+impl WasmEmulator {
+    fn new(inner: Emulator) -> Self {
+        Self {
+            inner,
+            on_input: None,
+            on_output: None,
+        }
+    }
+
+    fn poll_input(&mut self) -> Result<(), JsValue> {
+        let Some(callback) = &self.on_input else {
+            return Ok(());
+        };
+        let input = callback.call0(&JsValue::NULL)?;
+        if let Some(text) = input.as_string() {
+            self.inner.push_uart_input_bytes(text.as_bytes());
+        } else if input.is_instance_of::<Uint8Array>() {
+            self.inner
+                .push_uart_input_bytes(&Uint8Array::from(input).to_vec());
+        } else if !input.is_undefined() && !input.is_null() {
+            return Err(JsValue::from_str(
+                "input callback must return a Uint8Array or a string",
+            ));
+        }
+        Ok(())
+    }
+
+    fn flush_output(&mut self) -> Result<(), JsValue> {
+        let Some(callback) = &self.on_output else {
+            return Ok(());
+        };
+        let output = self.inner.take_uart_output_bytes();
+        if !output.is_empty() {
+            callback.call1(&JsValue::NULL, &Uint8Array::from(output.as_slice()))?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,230 @@
+//! Worker threads devices can offload long operations to (disk reads, network sends, ...).
+//!
+//! A device opens a [`WorkQueue::channel`], submits jobs through the [`Submitter`] and keeps the
+//! [`CompletionQueue`] for itself. The results are never applied on the worker: the board calls
+//! [`DeviceTrait::complete_work`](crate::device::DeviceTrait::complete_work) between instructions
+//! once something completed, where the device pops the results, updates guest memory and raises
+//! its IRQ. Completions ring the board's [`Doorbell`], see [`WorkQueue::with_doorbell`].
+//!
+//! - With `multithreading`, the jobs run on a small pool of worker threads.
+//! - Without it, or in deterministic mode, the jobs run inline on submission. The completions
+//!   are still delivered before the next instruction, so devices behave the same either way.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use crossbeam::channel::{self, Receiver, Sender};
+
+use crate::doorbell::Doorbell;
+
+#[cfg(feature = "multithreading")]
+use crate::vclock;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Number of worker threads of [`WorkQueue::default`].
+const DEFAULT_WORKERS: usize = 2;
+
+pub struct WorkQueue {
+    /// `None` when the jobs run inline. The workers exit once every sender is dropped.
+    job_tx: Option<Sender<Job>>,
+
+    /// Set by the workers once a completion is pushed, cleared by [`Self::take_ready`].
+    ready: Arc<AtomicBool>,
+    /// Rung along with `ready`.
+    doorbell: Doorbell,
+}
+
+impl WorkQueue {
+    /// Start `workers` worker threads, none means running the jobs inline.
+    pub fn new(workers: usize) -> Self {
+        #[cfg(feature = "multithreading")]
+        if workers > 0 && !vclock::is_deterministic() {
+            let (job_tx, job_rx) = channel::unbounded::<Job>();
+            for i in 0..workers {
+                let job_rx = job_rx.clone();
+                std::thread::Builder::new()
+                    .name(format!("work-queue-{i}"))
+                    .spawn(move || {
+                        while let Ok(job) = job_rx.recv() {
+                            job();
+                        }
+                    })
+                    .expect("failed to spawn work queue worker");
+            }
+
+            return Self {
+                job_tx: Some(job_tx),
+                ready: Arc::new(AtomicBool::new(false)),
+                doorbell: Doorbell::new(),
+            };
+        }
+
+        #[cfg(not(feature = "multithreading"))]
+        let _ = workers;
+
+        Self {
+            job_tx: None,
+            ready: Arc::new(AtomicBool::new(false)),
+            doorbell: Doorbell::new(),
+        }
+    }
+
+    /// Ring `doorbell` whenever a job completed, for the channels opened from now on.
+    pub fn with_doorbell(mut self, doorbell: Doorbell) -> Self {
+        self.doorbell = doorbell;
+        self
+    }
+
+    /// Open a channel whose jobs produce `T`s for the device holding the [`CompletionQueue`].
+    pub fn channel<T: Send + 'static>(&self) -> (Submitter<T>, CompletionQueue<T>) {
+        let (tx, rx) = channel::unbounded();
+        (
+            Submitter {
+                job_tx: self.job_tx.clone(),
+                completion_tx: tx,
+                ready: self.ready.clone(),
+                doorbell: self.doorbell.clone(),
+            },
+            CompletionQueue { rx },
+        )
+    }
+
+    /// Whether any job completed since the last call.
+    pub fn take_ready(&self) -> bool {
+        self.ready.swap(false, Ordering::AcqRel)
+    }
+}
+
+impl Default for WorkQueue {
+    fn default() -> Self {
+        Self::new(if cfg!(feature = "multithreading") {
+            DEFAULT_WORKERS
+        } else {
+            0
+        })
+    }
+}
+
+/// Submits jobs whose results end up in the paired [`CompletionQueue`].
+pub struct Submitter<T> {
+    job_tx: Option<Sender<Job>>,
+    completion_tx: Sender<T>,
+    ready: Arc<AtomicBool>,
+    doorbell: Doorbell,
+}
+
+impl<T> Clone for Submitter<T> {
+    fn clone(&self) -> Self {
+        Self {
+            job_tx: self.job_tx.clone(),
+            completion_tx: self.completion_tx.clone(),
+            ready: self.ready.clone(),
+            doorbell: self.doorbell.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> Submitter<T> {
+    /// Run `work` in the background. Jobs may complete out of submission order.
+    pub fn submit(&self, work: impl FnOnce() -> T + Send + 'static) {
+        let completion_tx = self.completion_tx.clone();
+        let ready = self.ready.clone();
+        let doorbell = self.doorbell.clone();
+        let job = move || {
+            // The device may be gone already, drop the result then.
+            if completion_tx.send(work()).is_ok() {
+                ready.store(true, Ordering::Release);
+                doorbell.ring();
+            }
+        };
+
+        match &self.job_tx {
+            Some(job_tx) => {
+                if let Err(err) = job_tx.send(Box::new(job)) {
+                    // Workers are gone, run it here instead of losing it.
+                    err.into_inner()();
+                }
+            }
+            None => job(),
+        }
+    }
+}
+
+pub struct CompletionQueue<T> {
+    rx: Receiver<T>,
+}
+
+impl<T> CompletionQueue<T> {
+    pub fn try_pop(&self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Pop every result completed so far.
+    pub fn drain(&self) -> impl Iterator<Item = T> + '_ {
+        self.rx.try_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn wait_ready(queue: &WorkQueue) {
+        let start = Instant::now();
+        while !queue.take_ready() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "job never completed"
+            );
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_work_queue() {
+        for workers in [0, 2] {
+            let queue = WorkQueue::new(workers);
+            let (submitter, completions) = queue.channel::<u32>();
+            assert!(!queue.take_ready());
+            assert_eq!(completions.try_pop(), None);
+
+            for i in 0..4 {
+                submitter.submit(move || i * 2);
+            }
+
+            let mut results = Vec::new();
+            while results.len() < 4 {
+                wait_ready(&queue);
+                results.extend(completions.drain());
+            }
+            results.sort();
+            assert_eq!(results, vec![0, 2, 4, 6]);
+        }
+    }
+
+    #[test]
+    fn test_doorbell() {
+        let doorbell = Doorbell::new();
+        let queue = WorkQueue::new(0).with_doorbell(doorbell.clone());
+        let (submitter, completions) = queue.channel::<u32>();
+        submitter.submit(|| 1);
+        assert!(doorbell.take());
+        assert!(queue.take_ready());
+        assert_eq!(completions.try_pop(), Some(1));
+    }
+
+    #[test]
+    fn test_completion_after_device_dropped() {
+        let queue = WorkQueue::new(1);
+        let (submitter, completions) = queue.channel::<u32>();
+        drop(completions);
+        submitter.submit(|| 1);
+        drop(submitter);
+        drop(queue);
+    }
+}
@@ -0,0 +1,72 @@
+#!/usr/bin/env bash
+# Fill the images directory of the boot tests (tests/boot/images, or $RVEMU_BOOT_IMAGES).
+#
+# - riscv-tests: copied from the riscv-tests submodule, build it first as described in README.
+# - test_resources: built with the test_resources Makefile.
+# - xv6: cloned from $XV6_REPO (at $XV6_REV) and built with CPUS=1, or taken from $XV6_DIR.
+# - linux: copied from $FW_PAYLOAD, the OpenSBI firmware built by `make build-opensbi`.
+#
+# Images which can not be produced are reported and skipped, boot them selectively with
+# RVEMU_BOOT_ONLY.
+
+set -euo pipefail
+
+ROOT="$(cd "$(dirname "${BASH_SOURCE[0]}")/../.." && pwd)"
+IMAGES="${RVEMU_BOOT_IMAGES:-$ROOT/tests/boot/images}"
+CROSS_COMPILE="${CROSS_COMPILE:-riscv64-unknown-elf-}"
+XV6_REPO="${XV6_REPO:-https://github.com/mit-pdos/xv6-riscv.git}"
+XV6_REV="${XV6_REV:-riscv}"
+
+skip() {
+    echo "skip $1: $2" >&2
+}
+
+mkdir -p "$IMAGES"
+
+# riscv-tests
+if [ -d "$ROOT/riscv-tests/isa" ]; then
+    mkdir -p "$IMAGES/riscv-tests"
+    for test in rv64ui-v-add rv64ua-v-amoadd_d; do
+        if [ -f "$ROOT/riscv-tests/isa/$test" ]; then
+            cp "$ROOT/riscv-tests/isa/$test" "$IMAGES/riscv-tests/"
+        else
+            skip "$test" "not built in riscv-tests/isa"
+        fi
+    done
+else
+    skip riscv-tests "the submodule is not checked out"
+fi
+
+# test_resources
+if command -v "${CROSS_COMPILE}gcc" > /dev/null; then
+    make -C "$ROOT/test_resources" build \
+        RV_CC="${CROSS_COMPILE}gcc" RV_LD="${CROSS_COMPILE}ld"
+    mkdir -p "$IMAGES/test_resources"
+    cp "$ROOT"/test_resources/bin/*.elf "$IMAGES/test_resources/"
+    truncate -s 1M "$IMAGES/test_resources/virtio_blk_test.img"
+else
+    skip test_resources "${CROSS_COMPILE}gcc not found"
+fi
+
+# xv6
+if [ -z "${XV6_DIR:-}" ] && command -v "${CROSS_COMPILE}gcc" > /dev/null; then
+    XV6_DIR="$IMAGES/xv6-src"
+    if [ ! -d "$XV6_DIR" ]; then
+        git clone --depth 1 --branch "$XV6_REV" "$XV6_REPO" "$XV6_DIR"
+    fi
+    make -C "$XV6_DIR" CPUS=1 TOOLPREFIX="$CROSS_COMPILE" kernel/kernel fs.img
+fi
+if [ -n "${XV6_DIR:-}" ] && [ -f "$XV6_DIR/kernel/kernel" ]; then
+    mkdir -p "$IMAGES/xv6"
+    cp "$XV6_DIR/kernel/kernel" "$XV6_DIR/fs.img" "$IMAGES/xv6/"
+else
+    skip xv6 "set XV6_DIR or install ${CROSS_COMPILE}gcc"
+fi
+
+# linux
+if [ -n "${FW_PAYLOAD:-}" ]; then
+    mkdir -p "$IMAGES/linux"
+    cp "$FW_PAYLOAD" "$IMAGES/linux/fw_payload.bin"
+else
+    skip linux "set FW_PAYLOAD to the fw_payload.bin built by make build-opensbi"
+fi
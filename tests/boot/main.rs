@@ -0,0 +1,251 @@
+//! Boot tests, they run the prebuilt images listed in `images.toml` with the UART captured and
+//! check the serial output for the expected markers. Need feature `boot-tests`.
+//!
+//! The images are looked up in `tests/boot/images` (or `$RVEMU_BOOT_IMAGES`), run
+//! `tests/boot/fetch.sh` to build or copy them there. The serial output of every image is
+//! written to `<target>/tmp/boot-logs/<name>.log` (or `$RVEMU_BOOT_LOGS`), set
+//! `RVEMU_BOOT_ONLY=<name>[,<name>...]` to boot a subset.
+
+#![cfg(feature = "boot-tests")]
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crossterm::style::Stylize;
+use riscv_emulator::board::virt::VirtBoard;
+use riscv_emulator::board::{Board, BoardStatus};
+use riscv_emulator::config::arch_config::WordType;
+use riscv_emulator::isa::DebugTarget;
+use riscv_emulator::isa::riscv::debugger::Address;
+use riscv_emulator::{DeviceConfig, EmulatorConfigurator};
+use serde::Deserialize;
+
+/// Cycles between two looks at the UART and `tohost`.
+const POLL_INTERVAL: u64 = 0x1000;
+
+#[derive(Deserialize)]
+struct Manifest {
+    image: Vec<Image>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    Elf,
+    Bin,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Image {
+    name: String,
+    /// Path of the image, relative to the images directory.
+    path: PathBuf,
+    format: Format,
+    /// Images attached as VirtIO block devices, the guest works on a copy.
+    #[serde(default)]
+    disks: Vec<PathBuf>,
+    max_cycles: u64,
+    /// Markers which must appear in the serial output, in this order.
+    #[serde(default)]
+    expect: Vec<String>,
+    /// Markers which fail the boot as soon as they appear.
+    #[serde(default)]
+    fail: Vec<String>,
+    /// The image reports its result through `tohost` like riscv-tests, `1` is a pass.
+    #[serde(default)]
+    tohost: bool,
+}
+
+fn manifest_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/boot")
+}
+
+fn env_dir(var: &str, default: PathBuf) -> PathBuf {
+    std::env::var_os(var).map_or(default, PathBuf::from)
+}
+
+/// What the serial output and `tohost` say about a boot so far.
+struct Progress<'a> {
+    image: &'a Image,
+    serial: String,
+    next_marker: usize,
+    log: File,
+}
+
+impl<'a> Progress<'a> {
+    fn new(image: &'a Image, log: File) -> Self {
+        Self {
+            image,
+            serial: String::new(),
+            next_marker: 0,
+            log,
+        }
+    }
+
+    fn capture(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.log.write_all(bytes).map_err(|err| err.to_string())?;
+        self.serial.push_str(&String::from_utf8_lossy(bytes));
+
+        if let Some(marker) = self
+            .image
+            .fail
+            .iter()
+            .find(|m| self.serial.contains(m.as_str()))
+        {
+            return Err(format!("found failure marker {marker:?}"));
+        }
+        // Markers are searched from the end of the previous one, so they must appear in order.
+        while let Some(marker) = self.image.expect.get(self.next_marker) {
+            let Some(offset) = self.serial.find(marker.as_str()) else {
+                break;
+            };
+            self.serial.drain(..offset + marker.len());
+            self.next_marker += 1;
+        }
+        Ok(())
+    }
+
+    fn missing_marker(&self) -> Option<&str> {
+        self.image.expect.get(self.next_marker).map(String::as_str)
+    }
+}
+
+fn load(image: &Image, images: &Path, scratch: &Path) -> Result<VirtBoard, String> {
+    let path = images.join(&image.path);
+    let bytes = fs::read(&path).map_err(|err| {
+        format!(
+            "can not read {}: {err}, run tests/boot/fetch.sh first",
+            path.display()
+        )
+    })?;
+
+    let mut config = EmulatorConfigurator::new().serial_console(false);
+    for disk in &image.disks {
+        let source = images.join(disk);
+        let copy = scratch.join(format!(
+            "{}-{}",
+            image.name,
+            disk.file_name().unwrap().to_string_lossy()
+        ));
+        fs::copy(&source, &copy)
+            .map_err(|err| format!("can not copy {}: {err}", source.display()))?;
+        let device: DeviceConfig = format!("virtio-block:{}", copy.display()).parse()?;
+        config = config.append_device(device);
+    }
+    drop(config);
+
+    match image.format {
+        Format::Elf => VirtBoard::try_from_elf(bytes),
+        Format::Bin => Ok(VirtBoard::from_binary(&bytes)),
+    }
+}
+
+fn boot(image: &Image, images: &Path, logs: &Path) -> Result<u64, String> {
+    let mut board = load(image, images, logs)?;
+    let log_path = logs.join(format!("{}.log", image.name));
+    let log = File::create(&log_path).map_err(|err| err.to_string())?;
+    let mut progress = Progress::new(image, log);
+
+    let tohost: Option<WordType> = match image.tohost {
+        true => Some(
+            board
+                .loader()
+                .and_then(|loader| loader.get_section_addr(".tohost"))
+                .ok_or("the image has no .tohost section")?,
+        ),
+        false => None,
+    };
+
+    loop {
+        match board.status() {
+            BoardStatus::Running => {}
+            BoardStatus::Resetting => board.reset(),
+            BoardStatus::Halt => break,
+        }
+        board
+            .step()
+            .map_err(|err| format!("stopped by {err:?} at cycle {}", board.clock.now()))?;
+
+        let cycle = board.clock.now();
+        if cycle % POLL_INTERVAL != 0 {
+            continue;
+        }
+        progress.capture(&board.take_uart_output())?;
+        if let Some(tohost) = tohost {
+            match board.cpu.read_memory::<u64>(Address::Phys(tohost)).unwrap() {
+                0 => {}
+                1 => return Ok(cycle),
+                code => return Err(format!("tohost reported failure {}", code >> 1)),
+            }
+        } else if progress.missing_marker().is_none() {
+            return Ok(cycle);
+        }
+        if cycle >= image.max_cycles {
+            break;
+        }
+    }
+
+    progress.capture(&board.take_uart_output())?;
+    let cycle = board.clock.now();
+    if tohost.is_some() {
+        return Err(format!("no result in tohost after {cycle} cycles"));
+    }
+    match progress.missing_marker() {
+        Some(marker) => Err(format!("{marker:?} not found after {cycle} cycles")),
+        None => Ok(cycle),
+    }
+}
+
+/// A single test, the board configuration is global so the images can not boot in parallel.
+#[test]
+fn boot_images() {
+    let manifest = fs::read_to_string(manifest_dir().join("images.toml")).unwrap();
+    let manifest: Manifest = toml::from_str(&manifest).unwrap();
+    let images = env_dir("RVEMU_BOOT_IMAGES", manifest_dir().join("images"));
+    let logs = env_dir(
+        "RVEMU_BOOT_LOGS",
+        PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("boot-logs"),
+    );
+    fs::create_dir_all(&logs).unwrap();
+
+    let only = std::env::var("RVEMU_BOOT_ONLY").ok();
+    let selected: Vec<_> = manifest
+        .image
+        .iter()
+        .filter(|image| {
+            only.as_ref()
+                .is_none_or(|only| only.split(',').any(|name| name == image.name))
+        })
+        .collect();
+    assert!(!selected.is_empty(), "no image selected");
+
+    let width = 24;
+    let mut failed = Vec::new();
+    for image in selected {
+        match boot(image, &images, &logs) {
+            Ok(cycles) => {
+                eprintln!(
+                    "Boot {:<width$}{} in {cycles} cycles",
+                    image.name,
+                    "passed".green()
+                )
+            }
+            Err(reason) => {
+                eprintln!("Boot {:<width$}{}: {reason}", image.name, "failed".red());
+                failed.push(image.name.as_str());
+            }
+        }
+    }
+
+    assert!(
+        failed.is_empty(),
+        "{} failed to boot, serial logs are in {}",
+        failed.join(", "),
+        logs.display()
+    );
+}
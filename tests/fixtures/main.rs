@@ -0,0 +1,119 @@
+//! Guest programs covering the devices and trap paths of the whole board. Need feature
+//! `fixtures`.
+//!
+//! The programs are the assembly sources in `tests/fixtures`, assembled into flat binaries under
+//! `tests/fixtures/bin` by `make -C tests/fixtures` with a RISC-V GCC (`RV_PREFIX`,
+//! `riscv64-unknown-elf-` by default). The binaries are checked in, so the tests run without a
+//! cross toolchain.
+//!
+//! A program reports through the hypercall window, a checkpoint for every step it saw, and
+//! powers the board off at the end.
+
+#![cfg(feature = "fixtures")]
+
+use std::path::PathBuf;
+
+use riscv_emulator::board::virt::{SerialDestination, VirtBoardBuilder};
+use riscv_emulator::board::{Board, BoardStatus};
+use riscv_emulator::vclock;
+
+struct Fixture {
+    name: &'static str,
+    /// Given to the UART before the first step.
+    input: &'static [u8],
+    /// Values of the checkpoints the program records, in order.
+    checkpoints: &'static [u32],
+    /// What the program writes to the UART.
+    output: &'static [u8],
+    max_cycles: u64,
+}
+
+const FIXTURES: &[Fixture] = &[
+    Fixture {
+        name: "uart",
+        input: b"",
+        checkpoints: &[],
+        output: b"hello, fixtures\n",
+        max_cycles: 10_000,
+    },
+    Fixture {
+        name: "trap",
+        input: b"",
+        // ecall from M-mode, illegal instruction, load address misaligned
+        checkpoints: &[11, 2, 4],
+        output: b"",
+        max_cycles: 10_000,
+    },
+    Fixture {
+        name: "clint_timer",
+        input: b"",
+        // machine timer, and the interrupt bit of mcause
+        checkpoints: &[7, 1],
+        output: b"",
+        max_cycles: 100_000,
+    },
+    Fixture {
+        name: "plic_uart",
+        input: b"x",
+        // machine external, and the claimed source
+        checkpoints: &[11, 10],
+        output: b"x",
+        max_cycles: 100_000,
+    },
+];
+
+fn run(fixture: &Fixture) -> Result<(), String> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/bin")
+        .join(format!("{}.bin", fixture.name));
+    let bytes = std::fs::read(&path).map_err(|err| format!("{}: {err}", path.display()))?;
+    let mut board = VirtBoardBuilder::new()
+        .serial(SerialDestination::Buffer)
+        .binary(bytes)
+        .build()?;
+    board.push_uart_input(fixture.input);
+
+    while board.status() != BoardStatus::Halt {
+        if board.clock.now() >= fixture.max_cycles {
+            return Err(format!("still running after {} cycles", fixture.max_cycles));
+        }
+        board.step().map_err(|err| format!("{err:?}"))?;
+    }
+
+    let checkpoints: Vec<u32> = board
+        .guest_checkpoints()
+        .iter()
+        .map(|checkpoint| checkpoint.value)
+        .collect();
+    if checkpoints != fixture.checkpoints {
+        return Err(format!(
+            "checkpoints {checkpoints:?}, expected {:?}",
+            fixture.checkpoints
+        ));
+    }
+    let output = board.take_uart_output();
+    if output != fixture.output {
+        return Err(format!(
+            "output {:?}, expected {:?}",
+            String::from_utf8_lossy(&output),
+            String::from_utf8_lossy(fixture.output)
+        ));
+    }
+    Ok(())
+}
+
+/// The power-off state is process wide, so the boards run one after the other.
+#[test]
+fn fixtures() {
+    vclock::set_deterministic(true);
+
+    let failures: Vec<String> = FIXTURES
+        .iter()
+        .filter_map(|fixture| {
+            run(fixture)
+                .err()
+                .map(|err| format!("{}: {err}", fixture.name))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}